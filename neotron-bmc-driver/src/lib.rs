@@ -0,0 +1,441 @@
+#![doc = include_str!("../README.md")]
+#![no_std]
+
+// ============================================================================
+// Modules and Imports
+// ============================================================================
+
+use embedded_hal::digital::{self, InputPin, OutputPin};
+use embedded_hal::spi::{self, SpiBus};
+use neotron_bmc_protocol::registers::{self, MAX_READ_LEN};
+use neotron_bmc_protocol::values::RegisterValue;
+use neotron_bmc_protocol::{
+	calculate_crc, BuildInfo, ProtocolVersion, Receivable, Request, Response, ResponseResult,
+	Width, PADDING_BYTE, SYNC_BYTE,
+};
+
+// ============================================================================
+// Constants
+// ============================================================================
+
+/// The version of the NBMC protocol this driver was written against. An NBMC
+/// must be compatible with it (see [`Bmc::check_protocol_version`]).
+pub const PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion::new(1, 0, 0);
+
+/// How many bytes we clock through, waiting for a Response to start, before
+/// we give up.
+const MAX_TURNAROUND_BYTES: usize = 64;
+
+/// The longest Response we can receive - the Response Result, up to
+/// `MAX_READ_LEN` bytes of data, and the CRC.
+const MAX_RESPONSE_LEN: usize = MAX_READ_LEN as usize + 2;
+
+/// The two values which unlock the configuration, written one after the
+/// other to the Configuration Lock register.
+const UNLOCK_KEYS: [u8; 2] = [0x4E, 0xB1];
+
+// ============================================================================
+// Enums
+// ============================================================================
+
+/// The ways talking to the NBMC can fail
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+	/// The SPI bus reported an error
+	Spi(spi::ErrorKind),
+	/// The Chip Select or IRQ pin reported an error
+	Pin(digital::ErrorKind),
+	/// We only received Padding Bytes - the NBMC never started its Response
+	NoResponse,
+	/// A Response, or the value in it, couldn't be decoded (e.g. it had a bad
+	/// CRC)
+	Protocol(neotron_bmc_protocol::Error),
+	/// The NBMC answered with something other than *OK*
+	Result(ResponseResult),
+	/// A register held something we don't understand (like a power state
+	/// this driver doesn't know)
+	BadValue,
+	/// The NBMC speaks a version of the protocol this driver doesn't
+	Incompatible(ProtocolVersion),
+}
+
+/// The main board's power state, as in the Power Control register.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PowerState {
+	/// The DC/DC supply is off
+	Off = 0x00,
+	/// The main board is powered and running
+	On = 0x01,
+	/// The DC/DC supply is on, but the main board is held in reset
+	Suspended = 0x03,
+}
+
+// ============================================================================
+// Structs
+// ============================================================================
+
+/// An NBMC on the end of an SPI bus.
+pub struct Bmc<SPI, CS, IRQ> {
+	/// The SPI bus
+	spi: SPI,
+	/// The NBMC's `nCS` line
+	cs: CS,
+	/// The NBMC's `IRQ_nHOST` line
+	irq: IRQ,
+	/// Which Request Type we used last - they should alternate, so the NBMC
+	/// can spot a repeated Request
+	use_alt: bool,
+	/// What the NBMC sends during the Turn-Around
+	padding: u8,
+	/// Does the NBMC send `SYNC_BYTE` just before each Response?
+	sync: bool,
+}
+
+// ============================================================================
+// Impls
+// ============================================================================
+
+impl core::fmt::Display for Error {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		match self {
+			Error::Spi(kind) => write!(f, "SPI error: {}", kind),
+			Error::Pin(kind) => write!(f, "pin error: {}", kind),
+			Error::NoResponse => write!(f, "no response from the NBMC"),
+			Error::Protocol(error) => write!(f, "bad response: {}", error),
+			Error::Result(result) => write!(f, "NBMC answered {}", result),
+			Error::BadValue => write!(f, "unexpected register value"),
+			Error::Incompatible(version) => {
+				let [major, minor, patch] = version.as_bytes();
+				write!(f, "NBMC speaks protocol {}.{}.{}", major, minor, patch)
+			}
+		}
+	}
+}
+
+impl TryFrom<u8> for PowerState {
+	type Error = Error;
+
+	fn try_from(value: u8) -> Result<PowerState, Error> {
+		match value {
+			0x00 => Ok(PowerState::Off),
+			0x01 => Ok(PowerState::On),
+			0x03 => Ok(PowerState::Suspended),
+			_ => Err(Error::BadValue),
+		}
+	}
+}
+
+impl<SPI, CS, IRQ> Bmc<SPI, CS, IRQ>
+where
+	SPI: SpiBus,
+	CS: OutputPin,
+	IRQ: InputPin,
+{
+	/// Talk to an NBMC on the given bus and pins, leaving it deselected.
+	pub fn new(spi: SPI, cs: CS, irq: IRQ) -> Result<Bmc<SPI, CS, IRQ>, Error> {
+		let mut bmc = Bmc {
+			spi,
+			cs,
+			irq,
+			use_alt: false,
+			padding: PADDING_BYTE,
+			sync: false,
+		};
+		bmc.cs.set_high().map_err(pin_error)?;
+		Ok(bmc)
+	}
+
+	/// Give back the bus and pins.
+	pub fn release(self) -> (SPI, CS, IRQ) {
+		(self.spi, self.cs, self.irq)
+	}
+
+	/// Is the NBMC's IRQ line asserted (i.e. low)?
+	///
+	/// See [`Bmc::read_interrupts`] for why.
+	pub fn irq_asserted(&mut self) -> Result<bool, Error> {
+		self.irq.is_low().map_err(pin_error)
+	}
+
+	/// Ask the NBMC to send `padding` during the Turn-Around, and (if `sync`
+	/// is set) `SYNC_BYTE` just before each Response.
+	///
+	/// The NBMC only changes over at the next Chip Select, so neither write
+	/// is affected.
+	pub fn set_framing(&mut self, padding: u8, sync: bool) -> Result<(), Error> {
+		self.write(registers::SPI_PADDING_BYTE.address, padding)?;
+		self.padding = padding;
+		self.write(registers::SPI_SYNC_MARKER.address, u8::from(sync))?;
+		self.sync = sync;
+		Ok(())
+	}
+
+	/// Read a register, filling `buffer`.
+	///
+	/// You get an error if `buffer` is longer than `MAX_READ_LEN`.
+	pub fn read(&mut self, register: u8, buffer: &mut [u8]) -> Result<(), Error> {
+		let length = u8::try_from(buffer.len())
+			.ok()
+			.filter(|length| *length <= MAX_READ_LEN)
+			.ok_or(Error::Protocol(neotron_bmc_protocol::Error::BufferTooSmall))?;
+		let req = Request::new_read(self.next_alt(), register, length);
+		self.transaction(&req.as_bytes(), buffer, None)
+	}
+
+	/// Write one byte to a register.
+	pub fn write(&mut self, register: u8, data: u8) -> Result<(), Error> {
+		let req = Request::new_short_write(self.next_alt(), register, data);
+		self.transaction(&req.as_bytes(), &mut [], None)
+	}
+
+	/// Write several bytes to a register, with a Long Write.
+	pub fn write_long(&mut self, register: u8, data: &[u8]) -> Result<(), Error> {
+		let length = u8::try_from(data.len())
+			.map_err(|_| Error::Protocol(neotron_bmc_protocol::Error::BadLength))?;
+		let req = Request::new_long_write(self.next_alt(), register, length);
+		self.transaction(&req.as_bytes(), &mut [], Some(data))
+	}
+
+	/// Read a register holding a single value, like a `u16le`.
+	pub fn read_value<T: RegisterValue>(&mut self, register: u8) -> Result<T, Error> {
+		let mut buffer = [0u8; 4];
+		let buffer = &mut buffer[0..T::SIZE];
+		self.read(register, buffer)?;
+		T::decode(buffer).map_err(Error::Protocol)
+	}
+
+	/// Read a run of values, like a block of `u32le` counters, with one Wide
+	/// Read.
+	///
+	/// The values start at `register`, and carry on into the registers after
+	/// it (see `registers::wide_read_pieces`). You get an error if they don't
+	/// fit in one Response, or `T` isn't 1, 2 or 4 bytes long.
+	pub fn read_values<T: RegisterValue>(
+		&mut self,
+		register: u8,
+		values: &mut [T],
+	) -> Result<(), Error> {
+		let width = Width::for_size(T::SIZE)
+			.ok_or(Error::Protocol(neotron_bmc_protocol::Error::BadLength))?;
+		let length = values.len() * T::SIZE;
+		if length > usize::from(MAX_READ_LEN) {
+			return Err(Error::Protocol(neotron_bmc_protocol::Error::BufferTooSmall));
+		}
+		let mut buffer = [0u8; MAX_READ_LEN as usize];
+		let buffer = &mut buffer[0..length];
+		let req = Request::new_wide_read(self.next_alt(), register, width, values.len() as u8);
+		self.transaction(&req.as_bytes(), buffer, None)?;
+		for (value, bytes) in values.iter_mut().zip(buffer.chunks_exact(T::SIZE)) {
+			*value = T::decode(bytes).map_err(Error::Protocol)?;
+		}
+		Ok(())
+	}
+
+	/// Read the Protocol Version register.
+	pub fn read_protocol_version(&mut self) -> Result<ProtocolVersion, Error> {
+		let mut buffer = [0u8; 3];
+		self.read(registers::PROTOCOL_VERSION.address, &mut buffer)?;
+		ProtocolVersion::from_bytes(&buffer).map_err(Error::Protocol)
+	}
+
+	/// Check the NBMC speaks a version of the protocol compatible with this
+	/// driver, and return that version.
+	pub fn check_protocol_version(&mut self) -> Result<ProtocolVersion, Error> {
+		let version = self.read_protocol_version()?;
+		if !version.is_compatible_with(&PROTOCOL_VERSION) {
+			return Err(Error::Incompatible(version));
+		}
+		Ok(version)
+	}
+
+	/// Read the Firmware Version string into `buffer`, and return it without
+	/// the null padding.
+	pub fn read_firmware_version<'b>(
+		&mut self,
+		buffer: &'b mut [u8; 32],
+	) -> Result<&'b str, Error> {
+		self.read(registers::FIRMWARE_VERSION.address, buffer)?;
+		let length = buffer.iter().position(|b| *b == 0).unwrap_or(buffer.len());
+		core::str::from_utf8(&buffer[0..length]).map_err(|_| Error::BadValue)
+	}
+
+	/// Read the Build Info register.
+	pub fn read_build_info(&mut self) -> Result<BuildInfo, Error> {
+		let mut buffer = [0u8; BuildInfo::SIZE];
+		self.read(registers::BUILD_INFO.address, &mut buffer)?;
+		BuildInfo::from_bytes(&buffer).map_err(Error::Protocol)
+	}
+
+	/// Take bytes received from the PS/2 keyboard, oldest first, and return
+	/// how many there were.
+	///
+	/// Asks for as many as fit in `buffer` (up to `MAX_READ_LEN`). The NBMC
+	/// pads the read with zeros once its FIFO runs out, so we stop counting at
+	/// the first zero.
+	pub fn read_keyboard_fifo(&mut self, buffer: &mut [u8]) -> Result<usize, Error> {
+		let length = buffer.len().min(usize::from(MAX_READ_LEN));
+		let buffer = &mut buffer[0..length];
+		self.read(registers::KEYBOARD_DATA.address, buffer)?;
+		Ok(buffer.iter().position(|b| *b == 0).unwrap_or(length))
+	}
+
+	/// Read the main board's power state.
+	pub fn power(&mut self) -> Result<PowerState, Error> {
+		let value: u8 = self.read_value(registers::POWER_CONTROL.address)?;
+		PowerState::try_from(value)
+	}
+
+	/// Power the main board on or off, or suspend it.
+	///
+	/// The NBMC acts on this shortly after the transaction completes. Power
+	/// Control is guarded, so this gives *Locked* whilst the configuration is
+	/// locked (see [`Bmc::unlock_config`]).
+	pub fn set_power(&mut self, state: PowerState) -> Result<(), Error> {
+		self.write(registers::POWER_CONTROL.address, state as u8)
+	}
+
+	/// Lock the configuration, so the guarded registers refuse writes.
+	pub fn lock_config(&mut self) -> Result<(), Error> {
+		self.write(registers::CONFIG_LOCK.address, 0x01)
+	}
+
+	/// Unlock the configuration.
+	pub fn unlock_config(&mut self) -> Result<(), Error> {
+		for key in UNLOCK_KEYS {
+			self.write(registers::CONFIG_LOCK.address, key)?;
+		}
+		Ok(())
+	}
+
+	/// Read which interrupts are active, as a bitmask of all sixteen (from
+	/// the Interrupt Status and Interrupt Status (High) registers).
+	///
+	/// The IRQ line is asserted whilst any enabled interrupt is active.
+	pub fn read_interrupts(&mut self) -> Result<u16, Error> {
+		let low: u8 = self.read_value(registers::INTERRUPT_STATUS.address)?;
+		let high: u8 = self.read_value(registers::INTERRUPT_STATUS_HIGH.address)?;
+		Ok(u16::from_le_bytes([low, high]))
+	}
+
+	/// Read which interrupt sources' conditions are true right now, as a
+	/// bitmask of all sixteen (from the Interrupt Level and Interrupt Level
+	/// (High) registers).
+	///
+	/// An interrupt which is active here, but not in
+	/// [`Bmc::read_interrupts`], was cleared whilst its condition still held,
+	/// and won't become active again until the condition goes away and comes
+	/// back.
+	pub fn read_interrupt_levels(&mut self) -> Result<u16, Error> {
+		let low: u8 = self.read_value(registers::INTERRUPT_LEVEL.address)?;
+		let high: u8 = self.read_value(registers::INTERRUPT_LEVEL_HIGH.address)?;
+		Ok(u16::from_le_bytes([low, high]))
+	}
+
+	/// Clear the given interrupts.
+	pub fn clear_interrupts(&mut self, mask: u16) -> Result<(), Error> {
+		let [low, high] = mask.to_le_bytes();
+		if low != 0 {
+			self.write(registers::INTERRUPT_STATUS.address, low)?;
+		}
+		if high != 0 {
+			self.write(registers::INTERRUPT_STATUS_HIGH.address, high)?;
+		}
+		Ok(())
+	}
+
+	/// Flip to the other Request Type.
+	fn next_alt(&mut self) -> bool {
+		self.use_alt = !self.use_alt;
+		self.use_alt
+	}
+
+	/// Send a Request (and perhaps a Long Write Payload), in one Chip Select,
+	/// and put the data from the Response in `data`.
+	fn transaction(
+		&mut self,
+		request: &[u8],
+		data: &mut [u8],
+		payload: Option<&[u8]>,
+	) -> Result<(), Error> {
+		self.cs.set_low().map_err(pin_error)?;
+		let result = self.exchange(request, data, payload);
+		let flushed = self.spi.flush().map_err(spi_error);
+		// Always deselect, even if the exchange failed part way
+		self.cs.set_high().map_err(pin_error)?;
+		result.and(flushed)
+	}
+
+	/// Send a Request and collect the Response.
+	fn exchange(
+		&mut self,
+		request: &[u8],
+		data: &mut [u8],
+		payload: Option<&[u8]>,
+	) -> Result<(), Error> {
+		self.spi.write(request).map_err(spi_error)?;
+		self.response(data)?;
+		if let Some(payload) = payload {
+			self.spi.write(payload).map_err(spi_error)?;
+			self.spi
+				.write(&[calculate_crc(payload)])
+				.map_err(spi_error)?;
+			self.response(&mut [])?;
+		}
+		Ok(())
+	}
+
+	/// Clock through the Turn-Around, then collect a Response carrying
+	/// `data.len()` bytes of data.
+	fn response(&mut self, data: &mut [u8]) -> Result<(), Error> {
+		let mut buffer = [self.padding; MAX_RESPONSE_LEN];
+		let mut turnaround = 0;
+		loop {
+			self.spi.read(&mut buffer[0..1]).map_err(spi_error)?;
+			if self.sync {
+				// The Response starts straight after the marker
+				if buffer[0] == SYNC_BYTE {
+					self.spi.read(&mut buffer[0..1]).map_err(spi_error)?;
+					break;
+				}
+			} else if buffer[0] != self.padding {
+				break;
+			}
+			turnaround += 1;
+			if turnaround == MAX_TURNAROUND_BYTES {
+				return Err(Error::NoResponse);
+			}
+		}
+		// Failures come back without any data, whatever we asked for
+		let length = if buffer[0] == ResponseResult::Ok as u8 {
+			data.len() + 2
+		} else {
+			2
+		};
+		self.spi.read(&mut buffer[1..length]).map_err(spi_error)?;
+		let response = Response::from_bytes(&buffer[0..length]).map_err(Error::Protocol)?;
+		if response.result != ResponseResult::Ok {
+			return Err(Error::Result(response.result));
+		}
+		data.copy_from_slice(response.data);
+		Ok(())
+	}
+}
+
+// ============================================================================
+// Functions
+// ============================================================================
+
+/// Wrap up an error from the SPI bus.
+fn spi_error<E: spi::Error>(error: E) -> Error {
+	Error::Spi(error.kind())
+}
+
+/// Wrap up an error from a pin.
+fn pin_error<E: digital::Error>(error: E) -> Error {
+	Error::Pin(error.kind())
+}
+
+// ============================================================================
+// End of File
+// ============================================================================
@@ -0,0 +1,449 @@
+//! Runs the driver against a fake NBMC.
+//!
+//! The fake answers from a handful of registers, the way the firmware does,
+//! and checks every Request against the register map in
+//! `neotron-bmc-protocol`. It sits behind a fake SPI bus, Chip Select pin and
+//! IRQ pin, which all share it.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::rc::Rc;
+
+use embedded_hal::digital::{self, InputPin, OutputPin};
+use embedded_hal::spi::{self, SpiBus};
+use neotron_bmc_driver::{Bmc, Error, PowerState};
+use neotron_bmc_protocol::registers;
+use neotron_bmc_protocol::{
+	calculate_crc, BuildInfo, ProtocolVersion, Receivable, Request, RequestType, ResponseResult,
+	PADDING_BYTE,
+};
+
+/// The state of the fake NBMC.
+struct Nbmc {
+	/// The Firmware Version string
+	firmware_version: &'static str,
+	/// The Build Info
+	build_info: BuildInfo,
+	/// Bytes received from the keyboard
+	keyboard: VecDeque<u8>,
+	/// The Power Control register
+	power: u8,
+	/// Is the configuration locked?
+	locked: bool,
+	/// Was the last Request the first half of the unlock sequence?
+	unlocking: bool,
+	/// The Interrupt Status registers
+	interrupts: u16,
+	/// The Interrupt Level registers
+	levels: u16,
+	/// The scratch registers
+	scratch: [u8; 32],
+	/// How many Padding Bytes to send before each Response
+	turnaround: usize,
+	/// Stop answering altogether
+	silent: bool,
+	/// Is Chip Select low?
+	selected: bool,
+	/// Bytes received in this Chip Select, not yet handled
+	rx: Vec<u8>,
+	/// Bytes waiting to be clocked out
+	tx: VecDeque<u8>,
+	/// The register and length of a Long Write waiting for its payload
+	long_write: Option<(u8, u8)>,
+	/// Every Request received
+	requests: Vec<Request>,
+}
+
+impl Nbmc {
+	fn new() -> Nbmc {
+		Nbmc {
+			firmware_version: "tags/v0.5.0",
+			build_info: BuildInfo {
+				firmware_version: [0, 5, 0],
+				protocol_version: ProtocolVersion::new(1, 0, 0),
+				build_flags: 0,
+				git_hash: [0xb2, 0x2c, 0x86, 0x80, 0x11, 0x22, 0x33, 0x44],
+				build_timestamp: 0x6000_0000,
+				feature_flags: 0,
+			},
+			keyboard: VecDeque::new(),
+			power: 0x01,
+			locked: false,
+			unlocking: false,
+			interrupts: 0,
+			levels: 0,
+			scratch: [0; 32],
+			turnaround: 3,
+			silent: false,
+			selected: false,
+			rx: Vec::new(),
+			tx: VecDeque::new(),
+			long_write: None,
+			requests: Vec::new(),
+		}
+	}
+
+	/// Queue a Response to be clocked out, after the Turn-Around.
+	fn reply(&mut self, result: ResponseResult, data: &[u8]) {
+		if self.silent {
+			return;
+		}
+		let mut bytes = vec![result as u8];
+		if result == ResponseResult::Ok {
+			bytes.extend_from_slice(data);
+		}
+		bytes.push(calculate_crc(&bytes));
+		self.tx
+			.extend(std::iter::repeat_n(PADDING_BYTE, self.turnaround));
+		self.tx.extend(bytes);
+	}
+
+	/// Handle the bytes received so far.
+	fn receive(&mut self) {
+		if let Some((register, length)) = self.long_write {
+			if self.rx.len() == usize::from(length) + 1 {
+				self.long_write = None;
+				let payload = std::mem::take(&mut self.rx);
+				let result = if calculate_crc(&payload) != 0 {
+					ResponseResult::CrcFailure
+				} else {
+					self.write_long(register, &payload[0..usize::from(length)])
+				};
+				self.reply(result, &[]);
+			}
+			return;
+		}
+		if self.rx.len() < 4 {
+			return;
+		}
+		let bytes = std::mem::take(&mut self.rx);
+		let req = match Request::from_bytes(&bytes) {
+			Ok(req) => req,
+			Err(_) => {
+				self.reply(ResponseResult::CrcFailure, &[]);
+				return;
+			}
+		};
+		self.requests.push(req.clone());
+		let unlocking = std::mem::take(&mut self.unlocking);
+		match req.request_type {
+			RequestType::Read | RequestType::ReadAlt => {
+				match self.read(req.register, req.length_or_data) {
+					Ok(data) => self.reply(ResponseResult::Ok, &data),
+					Err(result) => self.reply(result, &[]),
+				}
+			}
+			RequestType::ShortWrite | RequestType::ShortWriteAlt => {
+				let result = self.write(req.register, req.length_or_data, unlocking);
+				self.reply(result, &[]);
+			}
+			RequestType::LongWrite | RequestType::LongWriteAlt => {
+				self.long_write = Some((req.register, req.length_or_data));
+				self.reply(ResponseResult::Ok, &[]);
+			}
+			RequestType::WideRead | RequestType::WideReadAlt => match self.wide_read(&req) {
+				Ok(data) => self.reply(ResponseResult::Ok, &data),
+				Err(result) => self.reply(result, &[]),
+			},
+			_ => self.reply(ResponseResult::BadRequestType, &[]),
+		}
+	}
+
+	/// Answer a Read.
+	fn read(&mut self, address: u8, length: u8) -> Result<Vec<u8>, ResponseResult> {
+		let register = registers::find(address).ok_or(ResponseResult::BadRegister)?;
+		if !register.allows_read(address, length) {
+			return Err(ResponseResult::BadLength);
+		}
+		let length = usize::from(length);
+		let data = match address {
+			0x00 => vec![1, 0, 0],
+			0x01 => {
+				let mut data = self.firmware_version.as_bytes().to_vec();
+				data.resize(32, 0);
+				data
+			}
+			0x02 => self.build_info.as_bytes().to_vec(),
+			0x10 => vec![self.interrupts as u8],
+			0x14 => vec![(self.interrupts >> 8) as u8],
+			0x9D => vec![self.levels as u8],
+			0x9E => vec![(self.levels >> 8) as u8],
+			0x25 => vec![self.power],
+			0x40 => (0..length)
+				.map(|_| self.keyboard.pop_front().unwrap_or(0))
+				.collect(),
+			0xE0..=0xFF => self.scratch[usize::from(address - 0xE0)..].to_vec(),
+			_ => return Err(ResponseResult::BadRegister),
+		};
+		Ok(data[0..length].to_vec())
+	}
+
+	/// Answer a Wide Read, as a Read of each register in the run.
+	fn wide_read(&mut self, req: &Request) -> Result<Vec<u8>, ResponseResult> {
+		let (width, count) = req.wide_read_shape().ok_or(ResponseResult::BadLength)?;
+		let pieces = registers::wide_read_pieces(req.register, width, count, 0)
+			.collect::<Result<Vec<_>, _>>()?;
+		let mut data = Vec::new();
+		for (address, length) in pieces {
+			data.extend(self.read(address, length)?);
+		}
+		Ok(data)
+	}
+
+	/// Answer a Short Write.
+	fn write(&mut self, address: u8, data: u8, unlocking: bool) -> ResponseResult {
+		let Some(register) = registers::find(address) else {
+			return ResponseResult::BadRegister;
+		};
+		if self.locked && register.is_guarded() {
+			return ResponseResult::Locked;
+		}
+		match (address, data) {
+			(0x0D, 0x01) => self.locked = true,
+			(0x0D, 0x4E) => self.unlocking = true,
+			(0x0D, 0xB1) if unlocking => self.locked = false,
+			(0x10, _) => self.interrupts &= !u16::from(data),
+			(0x14, _) => self.interrupts &= !(u16::from(data) << 8),
+			(0x25, 0x00 | 0x01 | 0x03) => self.power = data,
+			_ => return ResponseResult::BadLength,
+		}
+		ResponseResult::Ok
+	}
+
+	/// Answer the payload of a Long Write.
+	fn write_long(&mut self, address: u8, data: &[u8]) -> ResponseResult {
+		match address {
+			0xE0..=0xFF if usize::from(address - 0xE0) + data.len() <= 32 => {
+				let start = usize::from(address - 0xE0);
+				self.scratch[start..start + data.len()].copy_from_slice(data);
+				ResponseResult::Ok
+			}
+			_ => ResponseResult::BadLength,
+		}
+	}
+}
+
+/// The fake SPI bus.
+struct FakeSpi(Rc<RefCell<Nbmc>>);
+
+/// The fake Chip Select pin.
+struct FakeCs(Rc<RefCell<Nbmc>>);
+
+/// The fake IRQ pin.
+struct FakeIrq(Rc<RefCell<Nbmc>>);
+
+impl spi::ErrorType for FakeSpi {
+	type Error = Infallible;
+}
+
+impl SpiBus for FakeSpi {
+	fn read(&mut self, words: &mut [u8]) -> Result<(), Infallible> {
+		let mut nbmc = self.0.borrow_mut();
+		assert!(nbmc.selected, "clocked whilst deselected");
+		for word in words.iter_mut() {
+			*word = nbmc.tx.pop_front().unwrap_or(PADDING_BYTE);
+		}
+		Ok(())
+	}
+
+	fn write(&mut self, words: &[u8]) -> Result<(), Infallible> {
+		let mut nbmc = self.0.borrow_mut();
+		assert!(nbmc.selected, "clocked whilst deselected");
+		for word in words {
+			nbmc.rx.push(*word);
+			nbmc.receive();
+		}
+		Ok(())
+	}
+
+	fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Infallible> {
+		self.write(write)?;
+		self.read(read)
+	}
+
+	fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Infallible> {
+		let write = words.to_vec();
+		self.transfer(words, &write)
+	}
+
+	fn flush(&mut self) -> Result<(), Infallible> {
+		Ok(())
+	}
+}
+
+impl digital::ErrorType for FakeCs {
+	type Error = Infallible;
+}
+
+impl OutputPin for FakeCs {
+	fn set_low(&mut self) -> Result<(), Infallible> {
+		self.0.borrow_mut().selected = true;
+		Ok(())
+	}
+
+	fn set_high(&mut self) -> Result<(), Infallible> {
+		// Raising Chip Select throws away anything half done
+		let mut nbmc = self.0.borrow_mut();
+		nbmc.selected = false;
+		nbmc.rx.clear();
+		nbmc.tx.clear();
+		nbmc.long_write = None;
+		Ok(())
+	}
+}
+
+impl digital::ErrorType for FakeIrq {
+	type Error = Infallible;
+}
+
+impl InputPin for FakeIrq {
+	fn is_high(&mut self) -> Result<bool, Infallible> {
+		Ok(self.0.borrow().interrupts == 0)
+	}
+
+	fn is_low(&mut self) -> Result<bool, Infallible> {
+		Ok(self.0.borrow().interrupts != 0)
+	}
+}
+
+/// A driver talking to a new fake NBMC, and the NBMC.
+fn setup() -> (Bmc<FakeSpi, FakeCs, FakeIrq>, Rc<RefCell<Nbmc>>) {
+	let nbmc = Rc::new(RefCell::new(Nbmc::new()));
+	let bmc = Bmc::new(
+		FakeSpi(nbmc.clone()),
+		FakeCs(nbmc.clone()),
+		FakeIrq(nbmc.clone()),
+	)
+	.unwrap();
+	(bmc, nbmc)
+}
+
+#[test]
+fn versions() {
+	let (mut bmc, nbmc) = setup();
+	assert_eq!(
+		bmc.check_protocol_version(),
+		Ok(ProtocolVersion::new(1, 0, 0))
+	);
+	let mut buffer = [0u8; 32];
+	assert_eq!(bmc.read_firmware_version(&mut buffer), Ok("tags/v0.5.0"));
+	assert_eq!(bmc.read_build_info(), Ok(nbmc.borrow().build_info));
+	// The Request Types alternate
+	let alternates: Vec<bool> = nbmc
+		.borrow()
+		.requests
+		.iter()
+		.map(|req| req.request_type == RequestType::ReadAlt)
+		.collect();
+	assert_eq!(alternates, vec![true, false, true]);
+}
+
+#[test]
+fn keyboard_fifo() {
+	let (mut bmc, nbmc) = setup();
+	let mut buffer = [0u8; 16];
+	assert_eq!(bmc.read_keyboard_fifo(&mut buffer), Ok(0));
+	nbmc.borrow_mut().keyboard.extend([0x1C, 0xF0, 0x1C]);
+	assert_eq!(bmc.read_keyboard_fifo(&mut buffer), Ok(3));
+	assert_eq!(&buffer[0..3], &[0x1C, 0xF0, 0x1C]);
+	// Only as many as fit
+	nbmc.borrow_mut().keyboard.extend([0x32, 0x33, 0x34]);
+	assert_eq!(bmc.read_keyboard_fifo(&mut buffer[0..2]), Ok(2));
+	assert_eq!(bmc.read_keyboard_fifo(&mut buffer), Ok(1));
+	assert_eq!(buffer[0], 0x34);
+}
+
+#[test]
+fn power_and_lock() {
+	let (mut bmc, nbmc) = setup();
+	assert_eq!(bmc.power(), Ok(PowerState::On));
+	assert_eq!(bmc.set_power(PowerState::Suspended), Ok(()));
+	assert_eq!(bmc.power(), Ok(PowerState::Suspended));
+	assert_eq!(bmc.lock_config(), Ok(()));
+	assert_eq!(
+		bmc.set_power(PowerState::Off),
+		Err(Error::Result(ResponseResult::Locked))
+	);
+	assert_eq!(bmc.unlock_config(), Ok(()));
+	assert_eq!(bmc.set_power(PowerState::Off), Ok(()));
+	assert_eq!(nbmc.borrow().power, 0x00);
+	// A power state we don't know
+	nbmc.borrow_mut().power = 0x02;
+	assert_eq!(bmc.power(), Err(Error::BadValue));
+}
+
+#[test]
+fn interrupts() {
+	let (mut bmc, nbmc) = setup();
+	assert_eq!(bmc.irq_asserted(), Ok(false));
+	nbmc.borrow_mut().interrupts = 0x4001;
+	assert_eq!(bmc.irq_asserted(), Ok(true));
+	assert_eq!(bmc.read_interrupts(), Ok(0x4001));
+	assert_eq!(bmc.clear_interrupts(0x4000), Ok(()));
+	assert_eq!(bmc.read_interrupts(), Ok(0x0001));
+	assert_eq!(bmc.clear_interrupts(0x0001), Ok(()));
+	assert_eq!(bmc.irq_asserted(), Ok(false));
+	nbmc.borrow_mut().levels = 0x1020;
+	assert_eq!(bmc.read_interrupt_levels(), Ok(0x1020));
+}
+
+#[test]
+fn wide_reads() {
+	let (mut bmc, nbmc) = setup();
+	assert_eq!(bmc.write_long(0xE0, &[1, 0, 2, 0, 3, 0, 4, 0]), Ok(()));
+	let mut values = [0u16; 4];
+	assert_eq!(bmc.read_values(0xE0, &mut values), Ok(()));
+	assert_eq!(values, [1, 2, 3, 4]);
+	assert_eq!(
+		nbmc.borrow()
+			.requests
+			.last()
+			.map(|req| req.wide_read_shape()),
+		Some(Some((neotron_bmc_protocol::Width::U16, 4)))
+	);
+	// Past the end of the map
+	let mut values = [0u32; 9];
+	assert_eq!(
+		bmc.read_values(0xE0, &mut values),
+		Err(Error::Result(ResponseResult::BadRegister))
+	);
+	// More than fit in a Response
+	let mut values = [0u32; 16];
+	assert_eq!(
+		bmc.read_values(0xE0, &mut values),
+		Err(Error::Protocol(neotron_bmc_protocol::Error::BufferTooSmall))
+	);
+}
+
+#[test]
+fn long_writes_and_errors() {
+	let (mut bmc, nbmc) = setup();
+	assert_eq!(bmc.write_long(0xE4, &[1, 2, 3]), Ok(()));
+	let mut buffer = [0u8; 5];
+	assert_eq!(bmc.read(0xE3, &mut buffer), Ok(()));
+	assert_eq!(buffer, [0, 1, 2, 3, 0]);
+	assert_eq!(
+		bmc.write_long(0xFE, &[1, 2, 3]),
+		Err(Error::Result(ResponseResult::BadLength))
+	);
+	assert_eq!(
+		bmc.read(0x7F, &mut buffer),
+		Err(Error::Result(ResponseResult::BadRegister))
+	);
+	assert_eq!(
+		bmc.read(0x00, &mut buffer),
+		Err(Error::Result(ResponseResult::BadLength))
+	);
+	// Longer than any Read can be
+	assert_eq!(
+		bmc.read(0x40, &mut [0u8; 61]),
+		Err(Error::Protocol(neotron_bmc_protocol::Error::BufferTooSmall))
+	);
+	// A slow NBMC is fine, but a silent one isn't
+	nbmc.borrow_mut().turnaround = 40;
+	assert_eq!(bmc.power(), Ok(PowerState::On));
+	nbmc.borrow_mut().silent = true;
+	assert_eq!(bmc.power(), Err(Error::NoResponse));
+	assert!(!nbmc.borrow().selected);
+}
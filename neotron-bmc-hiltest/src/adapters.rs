@@ -0,0 +1,99 @@
+//! # Test Rig Adapters
+//!
+//! Opens the hardware which connects us to the NBMC.
+//!
+//! * A Raspberry Pi (feature `raspberry-pi`) uses a `spidev` SPI port, with
+//!   chip select, IRQ and the relay on GPIO lines. The kernel's own chip
+//!   select is turned off, as we must hold `nCS` low across a Long Write.
+//! * An FT232H (feature `ft232h`) uses its MPSSE engine, with SCK on AD0,
+//!   MOSI on AD1, MISO on AD2, chip select on AD3, IRQ on AD4 and the relay
+//!   on AD5.
+
+#[cfg(any(feature = "raspberry-pi", feature = "ft232h"))]
+use crate::link::Adapter;
+
+/// Where to find the SPI port and pins
+#[derive(Debug, Clone)]
+pub struct Config {
+	/// The SPI clock, in Hz
+	pub clock_hz: u32,
+	/// The `spidev` device (Raspberry Pi only)
+	pub spi_device: String,
+	/// The GPIO character device (Raspberry Pi only)
+	pub gpio_chip: String,
+	/// The GPIO line wired to `nCS` (Raspberry Pi only)
+	pub cs_line: u32,
+	/// The GPIO line wired to `IRQ_nHOST` (Raspberry Pi only)
+	pub irq_line: u32,
+	/// The GPIO line driving the relay, if the rig has one (on an FT232H,
+	/// any value means there is a relay on AD5)
+	pub relay_line: Option<u32>,
+}
+
+impl Default for Config {
+	fn default() -> Self {
+		Config {
+			// The most this firmware reports in SPI Maximum Clock
+			clock_hz: 1_000_000,
+			spi_device: "/dev/spidev0.0".into(),
+			gpio_chip: "/dev/gpiochip0".into(),
+			cs_line: 25,
+			irq_line: 24,
+			relay_line: None,
+		}
+	}
+}
+
+/// Open the SPI port and GPIO lines on a Raspberry Pi.
+#[cfg(feature = "raspberry-pi")]
+pub fn open_raspberry_pi(config: &Config) -> Result<Box<dyn Adapter>, Box<dyn std::error::Error>> {
+	use linux_embedded_hal::gpio_cdev::{Chip, LineRequestFlags};
+	use linux_embedded_hal::spidev::{SpiModeFlags, SpidevOptions};
+	use linux_embedded_hal::{CdevPin, SpidevBus};
+
+	const CONSUMER: &str = "neotron-bmc-hiltest";
+
+	let mut spi = SpidevBus::open(&config.spi_device)?;
+	spi.configure(
+		&SpidevOptions::new()
+			.bits_per_word(8)
+			.max_speed_hz(config.clock_hz)
+			.mode(SpiModeFlags::SPI_MODE_0 | SpiModeFlags::SPI_NO_CS)
+			.build(),
+	)?;
+	let mut chip = Chip::new(&config.gpio_chip)?;
+	let cs = chip
+		.get_line(config.cs_line)?
+		.request(LineRequestFlags::OUTPUT, 1, CONSUMER)?;
+	let irq = chip
+		.get_line(config.irq_line)?
+		.request(LineRequestFlags::INPUT, 0, CONSUMER)?;
+	let relay = match config.relay_line {
+		Some(line) => Some(CdevPin::new(chip.get_line(line)?.request(
+			LineRequestFlags::OUTPUT,
+			0,
+			CONSUMER,
+		)?)?),
+		None => None,
+	};
+	let adapter = crate::link::HalAdapter::new(spi, CdevPin::new(cs)?, CdevPin::new(irq)?, relay)?;
+	Ok(Box::new(adapter))
+}
+
+/// Open the first FT232H on the USB bus.
+#[cfg(feature = "ft232h")]
+pub fn open_ft232h(config: &Config) -> Result<Box<dyn Adapter>, Box<dyn std::error::Error>> {
+	let device = ftdi::find_by_vid_pid(0x0403, 0x6014)
+		.interface(ftdi::Interface::A)
+		.open()?;
+	let hal = ftdi_embedded_hal::FtHal::init_freq(device, config.clock_hz)?;
+	let spi = hal.spi()?;
+	let cs = hal.ad3()?;
+	let irq = hal.adi4()?;
+	let relay = match config.relay_line {
+		Some(_) => Some(hal.ad5()?),
+		None => None,
+	};
+	let adapter = crate::link::HalAdapter::new(spi, cs, irq, relay)?;
+	Ok(Box::new(adapter))
+}
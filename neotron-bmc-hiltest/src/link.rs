@@ -0,0 +1,432 @@
+//! # Link to the NBMC
+//!
+//! Speaks the NBMC's SPI protocol through an [`Adapter`] - the SPI bus, chip
+//! select line, IRQ line and (optionally) the relay on the test rig.
+//!
+//! [`HalAdapter`] makes an adapter out of anything implementing the
+//! `embedded-hal` 1.0 traits, so the same code drives a Raspberry Pi (through
+//! `spidev` and the GPIO character device) and an FT232H.
+
+use embedded_hal::digital::{InputPin, OutputPin};
+use embedded_hal::spi::SpiBus;
+use neotron_bmc_protocol::{
+	calculate_crc, Receivable, Request, Response, ResponseResult, PADDING_BYTE, SYNC_BYTE,
+};
+
+/// How many Padding Bytes we clock through, waiting for a Response to start,
+/// before we give up.
+const MAX_TURNAROUND_BYTES: usize = 64;
+
+/// The ways talking to the NBMC can fail
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+	/// The adapter reported an error
+	Adapter(String),
+	/// We only received Padding Bytes - the NBMC never started its Response
+	NoResponse,
+	/// The Response couldn't be decoded (e.g. it had a bad CRC)
+	Protocol(neotron_bmc_protocol::Error),
+	/// The NBMC answered with something other than *OK*
+	Result(ResponseResult),
+	/// Something we were waiting for didn't happen in time
+	Timeout(&'static str),
+	/// The test rig has no relay
+	NoRelay,
+}
+
+impl std::fmt::Display for Error {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Error::Adapter(message) => write!(f, "adapter error: {}", message),
+			Error::NoResponse => write!(f, "no response from the NBMC"),
+			Error::Protocol(error) => write!(f, "bad response: {}", error),
+			Error::Result(result) => write!(f, "NBMC answered {}", result),
+			Error::Timeout(what) => write!(f, "timed out waiting for {}", what),
+			Error::NoRelay => write!(f, "the test rig has no relay"),
+		}
+	}
+}
+
+impl std::error::Error for Error {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			Error::Protocol(error) => Some(error),
+			_ => None,
+		}
+	}
+}
+
+/// The hardware between us and the NBMC.
+pub trait Adapter {
+	/// Drive the chip select line (`true` selects the NBMC, i.e. drives it low)
+	fn select(&mut self, selected: bool) -> Result<(), Error>;
+
+	/// Clock out some bytes, ignoring whatever comes back
+	fn write(&mut self, data: &[u8]) -> Result<(), Error>;
+
+	/// Clock in some bytes, sending dummy bytes
+	fn read(&mut self, buffer: &mut [u8]) -> Result<(), Error>;
+
+	/// Is the NBMC's IRQ line asserted (i.e. low)?
+	fn irq_asserted(&mut self) -> Result<bool, Error>;
+
+	/// Close (`true`) or open the relay across the power button
+	fn set_relay(&mut self, closed: bool) -> Result<(), Error>;
+
+	/// Does the test rig have a relay?
+	fn has_relay(&self) -> bool;
+}
+
+/// An [`Adapter`] built from `embedded-hal` parts.
+pub struct HalAdapter<SPI, CS, IRQ, RELAY> {
+	/// The SPI bus. We drive chip select ourselves, so we can hold it low
+	/// across both halves of a Long Write.
+	spi: SPI,
+	/// The NBMC's `nCS` line
+	cs: CS,
+	/// The NBMC's `IRQ_nHOST` line
+	irq: IRQ,
+	/// The relay across the power button, if the rig has one
+	relay: Option<RELAY>,
+}
+
+impl<SPI, CS, IRQ, RELAY> HalAdapter<SPI, CS, IRQ, RELAY>
+where
+	SPI: SpiBus,
+	CS: OutputPin,
+	IRQ: InputPin,
+	RELAY: OutputPin,
+{
+	/// Build an adapter, leaving the NBMC deselected and the relay open.
+	pub fn new(
+		spi: SPI,
+		cs: CS,
+		irq: IRQ,
+		relay: Option<RELAY>,
+	) -> Result<HalAdapter<SPI, CS, IRQ, RELAY>, Error> {
+		let mut adapter = HalAdapter {
+			spi,
+			cs,
+			irq,
+			relay,
+		};
+		adapter.select(false)?;
+		if adapter.has_relay() {
+			adapter.set_relay(false)?;
+		}
+		Ok(adapter)
+	}
+}
+
+impl<SPI, CS, IRQ, RELAY> Adapter for HalAdapter<SPI, CS, IRQ, RELAY>
+where
+	SPI: SpiBus,
+	CS: OutputPin,
+	IRQ: InputPin,
+	RELAY: OutputPin,
+{
+	fn select(&mut self, selected: bool) -> Result<(), Error> {
+		if selected {
+			self.cs.set_low().map_err(adapter_error)
+		} else {
+			self.cs.set_high().map_err(adapter_error)
+		}
+	}
+
+	fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+		self.spi.write(data).map_err(adapter_error)?;
+		self.spi.flush().map_err(adapter_error)
+	}
+
+	fn read(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+		self.spi.read(buffer).map_err(adapter_error)?;
+		self.spi.flush().map_err(adapter_error)
+	}
+
+	fn irq_asserted(&mut self) -> Result<bool, Error> {
+		self.irq.is_low().map_err(adapter_error)
+	}
+
+	fn set_relay(&mut self, closed: bool) -> Result<(), Error> {
+		let relay = self.relay.as_mut().ok_or(Error::NoRelay)?;
+		if closed {
+			relay.set_high().map_err(adapter_error)
+		} else {
+			relay.set_low().map_err(adapter_error)
+		}
+	}
+
+	fn has_relay(&self) -> bool {
+		self.relay.is_some()
+	}
+}
+
+/// Wrap up an error from an `embedded-hal` driver.
+fn adapter_error<E: std::fmt::Debug>(error: E) -> Error {
+	Error::Adapter(format!("{:?}", error))
+}
+
+/// An NBMC on the end of an [`Adapter`].
+pub struct Bmc {
+	/// How we reach it
+	adapter: Box<dyn Adapter>,
+	/// Which Request Type we used last - they should alternate, so the NBMC
+	/// can spot a repeated Request
+	use_alt: bool,
+	/// What the NBMC sends during the Turn-Around
+	padding: u8,
+	/// Does the NBMC send `SYNC_BYTE` just before each Response?
+	sync: bool,
+}
+
+impl Bmc {
+	/// Talk to an NBMC through the given adapter.
+	pub fn new(adapter: Box<dyn Adapter>) -> Bmc {
+		Bmc {
+			adapter,
+			use_alt: false,
+			padding: PADDING_BYTE,
+			sync: false,
+		}
+	}
+
+	/// Ask the NBMC to send `padding` during the Turn-Around, and (if `sync`
+	/// is set) `SYNC_BYTE` just before each Response.
+	///
+	/// The NBMC only changes over at the next chip select, so neither write
+	/// is affected.
+	pub fn set_framing(&mut self, padding: u8, sync: bool) -> Result<(), Error> {
+		self.write(0x1C, padding)?;
+		self.padding = padding;
+		self.write(0x1D, u8::from(sync))?;
+		self.sync = sync;
+		Ok(())
+	}
+
+	/// Get the adapter, for the IRQ line and the relay.
+	pub fn adapter(&mut self) -> &mut dyn Adapter {
+		self.adapter.as_mut()
+	}
+
+	/// Read `length` bytes from a register.
+	pub fn read(&mut self, register: u8, length: u8) -> Result<Vec<u8>, Error> {
+		let req = Request::new_read(self.next_alt(), register, length);
+		self.transaction(&req.as_bytes(), usize::from(length), None)
+	}
+
+	/// Write one byte to a register.
+	pub fn write(&mut self, register: u8, data: u8) -> Result<(), Error> {
+		let req = Request::new_short_write(self.next_alt(), register, data);
+		self.transaction(&req.as_bytes(), 0, None).map(|_| ())
+	}
+
+	/// Write several bytes to a register, with a Long Write.
+	pub fn write_long(&mut self, register: u8, data: &[u8]) -> Result<(), Error> {
+		let length =
+			u8::try_from(data.len()).map_err(|_| Error::Result(ResponseResult::BadLength))?;
+		let req = Request::new_long_write(self.next_alt(), register, length);
+		let mut payload = data.to_vec();
+		payload.push(calculate_crc(data));
+		self.transaction(&req.as_bytes(), 0, Some(&payload))
+			.map(|_| ())
+	}
+
+	/// Send `request` exactly as given, bad CRC and all, and return the raw
+	/// Response - the Response Result, then `length` bytes of data if it is
+	/// *OK*, then the CRC - without checking it.
+	pub fn raw_transaction(&mut self, request: &[u8], length: usize) -> Result<Vec<u8>, Error> {
+		self.adapter.select(true)?;
+		let result = self
+			.adapter
+			.write(request)
+			.and_then(|_| self.raw_response(length));
+		// Always deselect, even if the exchange failed part way
+		self.adapter.select(false)?;
+		result
+	}
+
+	/// Flip to the other Request Type.
+	fn next_alt(&mut self) -> bool {
+		self.use_alt = !self.use_alt;
+		self.use_alt
+	}
+
+	/// Send a Request (and perhaps a Long Write Payload), in one chip select.
+	fn transaction(
+		&mut self,
+		request: &[u8],
+		length: usize,
+		payload: Option<&[u8]>,
+	) -> Result<Vec<u8>, Error> {
+		self.adapter.select(true)?;
+		let result = self.exchange(request, length, payload);
+		// Always deselect, even if the exchange failed part way
+		self.adapter.select(false)?;
+		result
+	}
+
+	/// Send a Request and collect the Response.
+	fn exchange(
+		&mut self,
+		request: &[u8],
+		length: usize,
+		payload: Option<&[u8]>,
+	) -> Result<Vec<u8>, Error> {
+		self.adapter.write(request)?;
+		let data = self.response(length)?;
+		match payload {
+			Some(payload) => {
+				self.adapter.write(payload)?;
+				self.response(0)
+			}
+			None => Ok(data),
+		}
+	}
+
+	/// Clock through the Turn-Around, then collect a Response carrying
+	/// `length` bytes of data.
+	fn response(&mut self, length: usize) -> Result<Vec<u8>, Error> {
+		let buffer = self.raw_response(length)?;
+		let response = Response::from_bytes(&buffer).map_err(Error::Protocol)?;
+		if response.result != ResponseResult::Ok {
+			return Err(Error::Result(response.result));
+		}
+		Ok(response.data.to_vec())
+	}
+
+	/// Clock through the Turn-Around, then collect the bytes of a Response
+	/// carrying `length` bytes of data.
+	fn raw_response(&mut self, length: usize) -> Result<Vec<u8>, Error> {
+		let mut buffer = vec![self.padding; length + 2];
+		let mut turnaround = 0;
+		loop {
+			self.adapter.read(&mut buffer[0..1])?;
+			if self.sync {
+				// The Response starts straight after the marker
+				if buffer[0] == SYNC_BYTE {
+					self.adapter.read(&mut buffer[0..1])?;
+					break;
+				}
+			} else if buffer[0] != self.padding {
+				break;
+			}
+			turnaround += 1;
+			if turnaround == MAX_TURNAROUND_BYTES {
+				return Err(Error::NoResponse);
+			}
+		}
+		// Failures come back without any data, whatever we asked for
+		if buffer[0] != ResponseResult::Ok as u8 {
+			buffer.truncate(2);
+		}
+		self.adapter.read(&mut buffer[1..])?;
+		Ok(buffer)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::collections::VecDeque;
+	use std::sync::{Arc, Mutex};
+
+	/// Plays back a scripted NBMC, and records what we sent it.
+	#[derive(Default)]
+	struct Script {
+		/// Bytes the NBMC will send (after these run out, Padding Bytes)
+		replies: VecDeque<u8>,
+		/// Bytes we sent whilst selected
+		sent: Arc<Mutex<Vec<u8>>>,
+	}
+
+	impl Adapter for Script {
+		fn select(&mut self, _selected: bool) -> Result<(), Error> {
+			Ok(())
+		}
+
+		fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+			self.sent.lock().unwrap().extend_from_slice(data);
+			Ok(())
+		}
+
+		fn read(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+			for byte in buffer.iter_mut() {
+				*byte = self.replies.pop_front().unwrap_or(0xFF);
+			}
+			Ok(())
+		}
+
+		fn irq_asserted(&mut self) -> Result<bool, Error> {
+			Ok(false)
+		}
+
+		fn set_relay(&mut self, _closed: bool) -> Result<(), Error> {
+			Err(Error::NoRelay)
+		}
+
+		fn has_relay(&self) -> bool {
+			false
+		}
+	}
+
+	/// Build a Response as the NBMC would send it.
+	fn reply(result: ResponseResult, data: &[u8]) -> Vec<u8> {
+		let mut bytes = vec![result as u8];
+		bytes.extend_from_slice(data);
+		bytes.push(calculate_crc(&bytes));
+		bytes
+	}
+
+	#[test]
+	fn read_skips_turnaround() {
+		let mut script = Script::default();
+		script.replies.extend([0xFF, 0xFF, 0xFF]);
+		script
+			.replies
+			.extend(reply(ResponseResult::Ok, &[0x01, 0x00, 0x00]));
+		let sent = script.sent.clone();
+		let mut bmc = Bmc::new(Box::new(script));
+		assert_eq!(bmc.read(0x00, 3), Ok(vec![0x01, 0x00, 0x00]));
+		assert_eq!(
+			*sent.lock().unwrap(),
+			Request::new_read(true, 0x00, 3).as_bytes()
+		);
+	}
+
+	#[test]
+	fn failures_and_long_writes() {
+		let mut script = Script::default();
+		script
+			.replies
+			.extend(reply(ResponseResult::BadRegister, &[]));
+		script.replies.extend(reply(ResponseResult::Ok, &[]));
+		script.replies.extend([0xFF]);
+		script.replies.extend(reply(ResponseResult::Ok, &[]));
+		let sent = script.sent.clone();
+		let mut bmc = Bmc::new(Box::new(script));
+		assert_eq!(
+			bmc.read(0x99, 4),
+			Err(Error::Result(ResponseResult::BadRegister))
+		);
+		sent.lock().unwrap().clear();
+		assert_eq!(bmc.write_long(0xE0, &[1, 2, 3]), Ok(()));
+		let mut expected = Request::new_long_write(false, 0xE0, 3).as_bytes().to_vec();
+		expected.extend([1, 2, 3, calculate_crc(&[1, 2, 3])]);
+		assert_eq!(*sent.lock().unwrap(), expected);
+		// Nothing more from the NBMC
+		assert_eq!(bmc.write(0x25, 0x00), Err(Error::NoResponse));
+	}
+
+	#[test]
+	fn sync_marker() {
+		let mut script = Script::default();
+		script.replies.extend(reply(ResponseResult::Ok, &[]));
+		script.replies.extend(reply(ResponseResult::Ok, &[]));
+		// Stray bytes in the Turn-Around which aren't the padding
+		script.replies.extend([0x00, 0x12, SYNC_BYTE]);
+		script.replies.extend(reply(ResponseResult::Ok, &[0x34]));
+		let mut bmc = Bmc::new(Box::new(script));
+		assert_eq!(bmc.set_framing(0x00, true), Ok(()));
+		assert_eq!(bmc.read(0x40, 1), Ok(vec![0x34]));
+	}
+}
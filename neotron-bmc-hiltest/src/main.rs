@@ -0,0 +1,123 @@
+//! # Neotron BMC Hardware-in-the-Loop Tests
+//!
+//! Connects to an NBMC through a Raspberry Pi or an FT232H, runs the test
+//! suite against it, and prints a pass/fail report. The exit code is zero
+//! only if every check passed, so this can gate a firmware release.
+
+mod adapters;
+mod link;
+mod suite;
+
+use std::process::ExitCode;
+
+const USAGE: &str = "\
+Usage: neotron-bmc-hiltest [OPTIONS]
+
+Options:
+  --adapter <pi|ft232h>   How we reach the NBMC (default: pi)
+  --clock-khz <N>         SPI clock in kHz (default: 1000)
+  --spi <PATH>            spidev device (default: /dev/spidev0.0)
+  --gpio-chip <PATH>      GPIO character device (default: /dev/gpiochip0)
+  --cs-line <N>           GPIO line wired to nCS (default: 25)
+  --irq-line <N>          GPIO line wired to IRQ_nHOST (default: 24)
+  --relay-line <N>        GPIO line driving the power button relay (default: none)
+  --relay                 The FT232H has a power button relay on AD5
+  --stress <N>            Scratch register write/read iterations (default: 1000)
+  --irq-samples <N>       IRQ latency measurements (default: 100)
+  --power-cycles <N>      Power on/off cycles, with the relay (default: 3)
+  --ps2-stress <SECS>     Clock SPI flat out whilst you type (default: 0, skip)
+  --help                  Show this text
+";
+
+/// Everything from the command line
+struct Args {
+	adapter: String,
+	config: adapters::Config,
+	options: suite::Options,
+}
+
+fn main() -> ExitCode {
+	let args = match parse_args(std::env::args().skip(1)) {
+		Ok(Some(args)) => args,
+		Ok(None) => {
+			print!("{}", USAGE);
+			return ExitCode::SUCCESS;
+		}
+		Err(message) => {
+			eprintln!("{}\n\n{}", message, USAGE);
+			return ExitCode::from(2);
+		}
+	};
+
+	let adapter = match open_adapter(&args.adapter, &args.config) {
+		Ok(adapter) => adapter,
+		Err(e) => {
+			eprintln!("Failed to open the {} adapter: {}", args.adapter, e);
+			return ExitCode::from(2);
+		}
+	};
+
+	let mut bmc = link::Bmc::new(adapter);
+	let report = suite::run(&mut bmc, &args.options);
+	if let Err(e) = report.write_to(&mut std::io::stdout()) {
+		eprintln!("Failed to write the report: {}", e);
+		return ExitCode::from(2);
+	}
+	if report.passed() {
+		ExitCode::SUCCESS
+	} else {
+		ExitCode::FAILURE
+	}
+}
+
+/// Parse the command line. Returns `None` if the user asked for help.
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Option<Args>, String> {
+	let mut parsed = Args {
+		adapter: "pi".into(),
+		config: adapters::Config::default(),
+		options: suite::Options::default(),
+	};
+	while let Some(arg) = args.next() {
+		let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+		match arg.as_str() {
+			"--adapter" => parsed.adapter = value()?,
+			"--clock-khz" => parsed.config.clock_hz = number::<u32>(&value()?)? * 1000,
+			"--spi" => parsed.config.spi_device = value()?,
+			"--gpio-chip" => parsed.config.gpio_chip = value()?,
+			"--cs-line" => parsed.config.cs_line = number(&value()?)?,
+			"--irq-line" => parsed.config.irq_line = number(&value()?)?,
+			"--relay-line" => parsed.config.relay_line = Some(number(&value()?)?),
+			"--relay" => parsed.config.relay_line = Some(0),
+			"--stress" => parsed.options.stress_iterations = number(&value()?)?,
+			"--irq-samples" => parsed.options.irq_samples = number(&value()?)?,
+			"--power-cycles" => parsed.options.power_cycles = number(&value()?)?,
+			"--ps2-stress" => parsed.options.ps2_stress_secs = number(&value()?)?,
+			"--help" | "-h" => return Ok(None),
+			_ => return Err(format!("Unknown option {}", arg)),
+		}
+	}
+	Ok(Some(parsed))
+}
+
+/// Parse a number from the command line.
+fn number<T: std::str::FromStr>(text: &str) -> Result<T, String> {
+	text.parse()
+		.map_err(|_| format!("{} isn't a valid number", text))
+}
+
+/// Open whichever adapter the user asked for.
+fn open_adapter(
+	name: &str,
+	config: &adapters::Config,
+) -> Result<Box<dyn link::Adapter>, Box<dyn std::error::Error>> {
+	match name {
+		#[cfg(feature = "raspberry-pi")]
+		"pi" => adapters::open_raspberry_pi(config),
+		#[cfg(feature = "ft232h")]
+		"ft232h" => adapters::open_ft232h(config),
+		_ => {
+			let _ = config;
+			Err(format!("{} isn't supported by this build", name).into())
+		}
+	}
+}
@@ -0,0 +1,701 @@
+//! # The Test Suite
+//!
+//! Each check talks to the NBMC through a [`Bmc`], and passes, fails, or is
+//! skipped (because the firmware or the rig can't do it). The results are
+//! gathered into a [`Report`].
+//!
+//! The checks leave the NBMC as they found it, except that the main board
+//! ends up powered off if the power sequencing check ran.
+
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use neotron_bmc_protocol::registers::{self, Access, Length, MAX_READ_LEN};
+use neotron_bmc_protocol::vectors::{self, Vector};
+use neotron_bmc_protocol::{ProtocolVersion, Receivable, ResponseResult};
+
+use crate::link::{Bmc, Error};
+
+/// The protocol version these tests were written against
+const TESTED_VERSION: ProtocolVersion = ProtocolVersion::new(1, 0, 0);
+
+/// The deferred operation has finished (byte 1 of Deferred Operation)
+const DEFERRED_FINISHED: u8 = 0x02;
+
+/// Deferred Done, in the Interrupt Status (High) register
+const IRQ_HIGH_DEFERRED_DONE: u8 = 1 << 0;
+
+/// Bit 0 of BMC Status, set once the NBMC has started up
+const STATUS_READY: u8 = 1 << 0;
+
+/// How long the NBMC may take to start up
+const READY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long a deferred operation (like a self-test) may take
+const DEFERRED_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The slowest the IRQ line may be to follow the Interrupt Control register
+const IRQ_LATENCY_LIMIT: Duration = Duration::from_millis(10);
+
+/// How many pulses to ask for in the IRQ line test
+const IRQ_LINE_TEST_PULSES: u8 = 3;
+
+/// How long the IRQ line test may take (each pulse takes 20 to 30 ms)
+const IRQ_LINE_TEST_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// How long to hold the power button for a short press
+const SHORT_PRESS: Duration = Duration::from_millis(200);
+
+/// How long to hold the power button to force the power off (the firmware
+/// does this after just over a second)
+const LONG_PRESS: Duration = Duration::from_millis(1500);
+
+/// How long the main board may take to change power state
+const POWER_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Power Control reads this whilst off
+const POWER_OFF: u8 = 0x00;
+
+/// Power Control reads this whilst on
+const POWER_ON: u8 = 0x01;
+
+/// Power Control reads this whilst suspended
+const POWER_SUSPENDED: u8 = 0x03;
+
+/// The BMC Health fault flags for lost or garbled PS/2 words
+const HEALTH_PS2_FAULTS: u8 = (1 << 0) | (1 << 5);
+
+/// The BMC Health fault flags for SPI timeouts and bad requests
+const HEALTH_SPI_FAULTS: u8 = (1 << 2) | (1 << 3);
+
+/// What to run, and how hard.
+#[derive(Debug, Clone)]
+pub struct Options {
+	/// How many times to write and read back the scratch registers
+	pub stress_iterations: u32,
+	/// How many times to measure the IRQ latency
+	pub irq_samples: u32,
+	/// How many times to power the main board on and off (needs the relay)
+	pub power_cycles: u32,
+	/// How many seconds to hammer SPI whilst someone types (zero to skip)
+	pub ps2_stress_secs: u32,
+}
+
+impl Default for Options {
+	fn default() -> Self {
+		Options {
+			stress_iterations: 1000,
+			irq_samples: 100,
+			power_cycles: 3,
+			ps2_stress_secs: 0,
+		}
+	}
+}
+
+/// How a check went
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+	Pass(String),
+	Fail(String),
+	Skip(String),
+}
+
+/// The outcome of one check
+#[derive(Debug, Clone)]
+pub struct CheckResult {
+	/// What was checked
+	pub name: &'static str,
+	/// How it went
+	pub outcome: Outcome,
+	/// How long it took
+	pub duration: Duration,
+}
+
+/// The outcome of every check
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+	pub checks: Vec<CheckResult>,
+}
+
+impl Report {
+	/// Did every check which ran pass?
+	pub fn passed(&self) -> bool {
+		!self
+			.checks
+			.iter()
+			.any(|check| matches!(check.outcome, Outcome::Fail(_)))
+	}
+
+	/// Write the report out, one line per check, then the verdict.
+	pub fn write_to(&self, out: &mut dyn std::io::Write) -> std::io::Result<()> {
+		writeln!(out, "Neotron BMC hardware-in-the-loop test report")?;
+		writeln!(out)?;
+		let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+		for check in self.checks.iter() {
+			let (label, detail) = match &check.outcome {
+				Outcome::Pass(detail) => {
+					passed += 1;
+					("PASS", detail)
+				}
+				Outcome::Fail(detail) => {
+					failed += 1;
+					("FAIL", detail)
+				}
+				Outcome::Skip(detail) => {
+					skipped += 1;
+					("SKIP", detail)
+				}
+			};
+			writeln!(
+				out,
+				"{}  {:<28} {:>7} ms  {}",
+				label,
+				check.name,
+				check.duration.as_millis(),
+				detail
+			)?;
+		}
+		writeln!(out)?;
+		writeln!(
+			out,
+			"RESULT: {} ({} passed, {} failed, {} skipped)",
+			if self.passed() { "PASS" } else { "FAIL" },
+			passed,
+			failed,
+			skipped
+		)
+	}
+}
+
+/// A check. `Err` means it failed.
+type Check = fn(&mut Bmc, &Options) -> Result<Outcome, Error>;
+
+/// Run every check, in order.
+pub fn run(bmc: &mut Bmc, options: &Options) -> Report {
+	let checks: [(&'static str, Check); 10] = [
+		("Protocol version", protocol_version),
+		("Register reads", register_reads),
+		("Conformance vectors", conformance_vectors),
+		("Scratch write/read stress", scratch_stress),
+		("UART FIFO self-test", uart_fifo),
+		("BMC self-test", bmc_self_test),
+		("IRQ latency", irq_latency),
+		("IRQ line test", irq_line_test),
+		("PS/2 under SPI load", ps2_under_load),
+		("Power sequencing", power_sequencing),
+	];
+	let mut report = Report::default();
+	for (name, check) in checks {
+		let start = Instant::now();
+		let outcome = check(bmc, options).unwrap_or_else(|e| Outcome::Fail(e.to_string()));
+		report.checks.push(CheckResult {
+			name,
+			outcome,
+			duration: start.elapsed(),
+		});
+	}
+	// Last of all, make sure nothing above upset the NBMC
+	let start = Instant::now();
+	let outcome = bmc_health(bmc).unwrap_or_else(|e| Outcome::Fail(e.to_string()));
+	report.checks.push(CheckResult {
+		name: "BMC health",
+		outcome,
+		duration: start.elapsed(),
+	});
+	report
+}
+
+/// Check we can talk to the NBMC at all, and that it speaks our protocol.
+///
+/// If the NBMC is still starting up, we wait until it is ready. Then we clear
+/// the BMC Health fault flags, so the final health check only sees faults
+/// caused by these tests.
+fn protocol_version(bmc: &mut Bmc, _options: &Options) -> Result<Outcome, Error> {
+	let data = bmc.read(registers::PROTOCOL_VERSION.address, 3)?;
+	wait_for(bmc, READY_TIMEOUT, "the NBMC to start up", |bmc| {
+		Ok((bmc.read(registers::BMC_STATUS.address, 1)?[0] & STATUS_READY) != 0)
+	})?;
+	let version = ProtocolVersion::from_bytes(&data).map_err(Error::Protocol)?;
+	let firmware = bmc.read(registers::FIRMWARE_VERSION.address, 32)?;
+	let firmware = String::from_utf8_lossy(&firmware);
+	let firmware = firmware.trim_end_matches('\0');
+	bmc.write(registers::BMC_HEALTH.address, 0xFF)?;
+	let [major, minor, patch] = version.as_bytes();
+	let detail = format!(
+		"protocol {}.{}.{}, firmware {}",
+		major, minor, patch, firmware
+	);
+	if version.is_compatible_with(&TESTED_VERSION) {
+		Ok(Outcome::Pass(detail))
+	} else {
+		Ok(Outcome::Fail(detail))
+	}
+}
+
+/// Read every register this firmware build has, at its full length, then
+/// check that bad reads are refused.
+fn register_reads(bmc: &mut Bmc, _options: &Options) -> Result<Outcome, Error> {
+	let flags = feature_flags(bmc)?;
+	let mut read = 0;
+	let mut failures = Vec::new();
+	for register in registers::ALL.iter() {
+		// Reading a FIFO would throw away whatever is in it
+		if !register.is_available(flags) || register.access == Access::Fifo {
+			continue;
+		}
+		let length = match register.length {
+			Length::Exactly(n) | Length::UpTo(n) | Length::Window(n) => n.min(MAX_READ_LEN),
+			Length::Entries(_) => continue,
+		};
+		match bmc.read(register.address, length) {
+			Ok(_) => read += 1,
+			Err(e) => failures.push(format!(
+				"0x{:02X} {}: {}",
+				register.address, register.name, e
+			)),
+		}
+	}
+	// A register which doesn't exist
+	if let Some(address) = (0..=255u8).find(|address| registers::find(*address).is_none()) {
+		match bmc.read(address, 1) {
+			Err(Error::Result(ResponseResult::BadRegister)) => {}
+			other => failures.push(format!("0x{:02X} (missing) gave {:?}", address, other)),
+		}
+	}
+	// A register which does exist, read at the wrong length
+	match bmc.read(registers::PROTOCOL_VERSION.address, 4) {
+		Err(Error::Result(ResponseResult::BadLength)) => {}
+		other => failures.push(format!("0x00 at length 4 gave {:?}", other)),
+	}
+	if failures.is_empty() {
+		Ok(Outcome::Pass(format!(
+			"{} registers read (features 0x{:08X})",
+			read, flags
+		)))
+	} else {
+		Ok(Outcome::Fail(failures.join("; ")))
+	}
+}
+
+/// Send each of the protocol crate's golden exchanges, byte for byte, and
+/// check the NBMC answers exactly as the vectors say.
+///
+/// The vectors change the first scratch register and deliberately send bad
+/// Requests, so afterwards we put the scratch register back and clear the SPI
+/// faults, so they don't fail the final health check.
+fn conformance_vectors(bmc: &mut Bmc, _options: &Options) -> Result<Outcome, Error> {
+	let scratch = registers::SCRATCH.address;
+	let saved = bmc.read(scratch, 1)?;
+	let mut sent = 0;
+	let mut failures = Vec::new();
+	for (idx, vector) in vectors::parse(vectors::EXCHANGES).enumerate() {
+		let Ok(Vector::Exchange { request, response }) = vector else {
+			failures.push(format!("vector {} doesn't make sense", idx + 1));
+			continue;
+		};
+		let request: Vec<u8> = request.bytes().collect();
+		let expected: Vec<u8> = response.bytes().collect();
+		let length = expected.len().saturating_sub(2);
+		match bmc.raw_transaction(&request, length) {
+			Ok(got) if got == expected => sent += 1,
+			Ok(got) => failures.push(format!("{:02X?} gave {:02X?}", request, got)),
+			Err(e) => failures.push(format!("{:02X?} gave {}", request, e)),
+		}
+	}
+	bmc.write(scratch, saved[0])?;
+	bmc.write(registers::BMC_HEALTH.address, HEALTH_SPI_FAULTS)?;
+	if failures.is_empty() {
+		Ok(Outcome::Pass(format!("{} exchanges matched", sent)))
+	} else {
+		Ok(Outcome::Fail(failures.join("; ")))
+	}
+}
+
+/// Hammer the scratch registers with Long Writes of random lengths at random
+/// offsets, reading each back.
+fn scratch_stress(bmc: &mut Bmc, options: &Options) -> Result<Outcome, Error> {
+	let window = match registers::SCRATCH.length {
+		Length::Window(n) => n,
+		_ => return Ok(Outcome::Skip("scratch registers aren't a window".into())),
+	};
+	let base = registers::SCRATCH.address;
+	let saved = bmc.read(base, window)?;
+	let mut rng = XorShift(0x2545_F491);
+	let mut errors = 0;
+	let mut mismatches = 0;
+	let mut bytes = 0;
+	for _ in 0..options.stress_iterations {
+		let offset = rng.below(window);
+		let length = 1 + rng.below(window - offset);
+		let data: Vec<u8> = (0..length).map(|_| rng.next() as u8).collect();
+		match bmc
+			.write_long(base + offset, &data)
+			.and_then(|_| bmc.read(base + offset, length))
+		{
+			Ok(read_back) if read_back == data => bytes += data.len(),
+			Ok(_) => mismatches += 1,
+			Err(_) => errors += 1,
+		}
+	}
+	bmc.write_long(base, &saved)?;
+	let detail = format!(
+		"{} iterations, {} bytes checked, {} mismatches, {} errors",
+		options.stress_iterations, bytes, mismatches, errors
+	);
+	if mismatches == 0 && errors == 0 {
+		Ok(Outcome::Pass(detail))
+	} else {
+		Ok(Outcome::Fail(detail))
+	}
+}
+
+/// Run the longest UART self-test, which pushes 4080 bytes through the UART
+/// receive FIFO at the configured baud rate.
+fn uart_fifo(bmc: &mut Bmc, _options: &Options) -> Result<Outcome, Error> {
+	let address = registers::UART_SELF_TEST.address;
+	let result = run_deferred(bmc, address, 0xFF)?;
+	let results = bmc.read(address, 6)?;
+	let counts: Vec<u16> = results
+		.chunks(2)
+		.map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+		.collect();
+	let detail = format!("result {}, counts {:?}", result, counts);
+	if result == 0 {
+		Ok(Outcome::Pass(detail))
+	} else {
+		Ok(Outcome::Fail(detail))
+	}
+}
+
+/// Run the NBMC's own board self-test.
+fn bmc_self_test(bmc: &mut Bmc, _options: &Options) -> Result<Outcome, Error> {
+	let address = registers::BMC_SELF_TEST.address;
+	let failed = run_deferred(bmc, address, 0x01)?;
+	let results = bmc.read(address, 2)?;
+	let detail = format!("ran 0x{:02X}, failed 0x{:02X}", results[0], results[1]);
+	if failed == 0 {
+		Ok(Outcome::Pass(detail))
+	} else {
+		Ok(Outcome::Fail(detail))
+	}
+}
+
+/// Measure how long the IRQ line takes to follow the Interrupt Control
+/// registers.
+///
+/// We finish a self-test to leave the Deferred Done interrupt active, then
+/// repeatedly enable it and time how long the line takes to assert, measured
+/// from the end of the enabling write.
+fn irq_latency(bmc: &mut Bmc, options: &Options) -> Result<Outcome, Error> {
+	let control = registers::INTERRUPT_CONTROL.address;
+	let control_high = registers::INTERRUPT_CONTROL_HIGH.address;
+	let status_high = registers::INTERRUPT_STATUS_HIGH.address;
+	let saved = bmc.read(control, 1)?[0];
+	let saved_high = bmc.read(control_high, 1)?[0];
+	bmc.write(control, 0x00)?;
+	bmc.write(control_high, 0x00)?;
+	wait_for(bmc, Duration::from_millis(100), "IRQ to clear", |bmc| {
+		Ok(!bmc.adapter().irq_asserted()?)
+	})?;
+
+	let token = start_deferred(bmc, registers::BMC_SELF_TEST.address, 0x01)?;
+	wait_deferred(bmc, token)?;
+	let measured: Result<Result<Vec<Duration>, String>, Error> = (|| {
+		if (bmc.read(status_high, 1)?[0] & IRQ_HIGH_DEFERRED_DONE) == 0 {
+			return Ok(Err("Deferred Done wasn't raised".to_string()));
+		}
+		let mut samples = Vec::new();
+		for _ in 0..options.irq_samples {
+			bmc.write(control_high, IRQ_HIGH_DEFERRED_DONE)?;
+			let latency = wait_for(bmc, IRQ_LATENCY_LIMIT, "IRQ to assert", |bmc| {
+				bmc.adapter().irq_asserted()
+			})?;
+			samples.push(latency);
+			bmc.write(control_high, 0x00)?;
+			wait_for(bmc, IRQ_LATENCY_LIMIT, "IRQ to clear", |bmc| {
+				Ok(!bmc.adapter().irq_asserted()?)
+			})?;
+		}
+		Ok(Ok(samples))
+	})();
+
+	// Put everything back, even if the measurements failed
+	bmc.write(registers::DEFERRED_OPERATION.address, token)?;
+	bmc.write(status_high, IRQ_HIGH_DEFERRED_DONE)?;
+	bmc.write(control, saved)?;
+	bmc.write(control_high, saved_high)?;
+
+	let samples = match measured? {
+		Ok(samples) => samples,
+		Err(message) => return Ok(Outcome::Fail(message)),
+	};
+	if samples.is_empty() {
+		return Ok(Outcome::Skip("no samples asked for".into()));
+	}
+	let min = samples.iter().min().copied().unwrap_or_default();
+	let max = samples.iter().max().copied().unwrap_or_default();
+	let mean = samples.iter().sum::<Duration>() / samples.len() as u32;
+	Ok(Outcome::Pass(format!(
+		"{} samples, min {} us, mean {} us, max {} us",
+		samples.len(),
+		min.as_micros(),
+		mean.as_micros(),
+		max.as_micros()
+	)))
+}
+
+/// Have the NBMC pulse the IRQ line, and count the pulses, to check the line
+/// is wired up.
+///
+/// The interrupts are all disabled first, so the line is released between
+/// the pulses.
+fn irq_line_test(bmc: &mut Bmc, _options: &Options) -> Result<Outcome, Error> {
+	let control = registers::INTERRUPT_CONTROL.address;
+	let control_high = registers::INTERRUPT_CONTROL_HIGH.address;
+	let test = registers::IRQ_LINE_TEST.address;
+	let saved = bmc.read(control, 1)?[0];
+	let saved_high = bmc.read(control_high, 1)?[0];
+	bmc.write(control, 0x00)?;
+	bmc.write(control_high, 0x00)?;
+	let counted: Result<u8, Error> = (|| {
+		wait_for(bmc, IRQ_LATENCY_LIMIT, "IRQ to clear", |bmc| {
+			Ok(!bmc.adapter().irq_asserted()?)
+		})?;
+		bmc.write(test, IRQ_LINE_TEST_PULSES)?;
+		let mut pulses = 0;
+		let mut asserted = false;
+		wait_for(bmc, IRQ_LINE_TEST_TIMEOUT, "IRQ line test", |bmc| {
+			let now = bmc.adapter().irq_asserted()?;
+			if now && !asserted {
+				pulses += 1;
+			}
+			asserted = now;
+			Ok(!now && bmc.read(test, 1)?[0] == 0)
+		})?;
+		Ok(pulses)
+	})();
+
+	// Put everything back, even if the test failed
+	bmc.write(control, saved)?;
+	bmc.write(control_high, saved_high)?;
+
+	let pulses = counted?;
+	if pulses == IRQ_LINE_TEST_PULSES {
+		Ok(Outcome::Pass(format!("{} pulses", pulses)))
+	} else {
+		Ok(Outcome::Fail(format!(
+			"asked for {} pulses, saw {}",
+			IRQ_LINE_TEST_PULSES, pulses
+		)))
+	}
+}
+
+/// Clock SPI as fast as we can whilst someone types on the PS/2 keyboard, and
+/// check that no PS/2 words were lost or garbled.
+///
+/// The SPI interrupt runs below the PS/2 clock interrupts, so an SPI request
+/// may occasionally lose a byte (and fail its CRC), but a PS/2 word never
+/// should. The SPI faults this causes are cleared afterwards, so they don't
+/// fail the final health check.
+fn ps2_under_load(bmc: &mut Bmc, options: &Options) -> Result<Outcome, Error> {
+	if options.ps2_stress_secs == 0 {
+		return Ok(Outcome::Skip("not asked for (see --ps2-stress)".into()));
+	}
+	let latency = registers::PS2_SERVICE_LATENCY.address;
+	let health = registers::BMC_HEALTH.address;
+	bmc.write(latency, 0x00)?;
+	bmc.write(health, HEALTH_PS2_FAULTS)?;
+	eprintln!(
+		"Type on the PS/2 keyboard for the next {} seconds...",
+		options.ps2_stress_secs
+	);
+	let duration = Duration::from_secs(u64::from(options.ps2_stress_secs));
+	let start = Instant::now();
+	let (mut reads, mut errors) = (0u32, 0u32);
+	while start.elapsed() < duration {
+		// The longest read we can do keeps the SPI interrupt busiest
+		match bmc.read(registers::FIRMWARE_VERSION.address, 32) {
+			Ok(_) => reads += 1,
+			Err(_) => errors += 1,
+		}
+	}
+	let stats = bmc.read(latency, 8)?;
+	let faults = bmc.read(health, 4)?[2];
+	bmc.write(health, HEALTH_SPI_FAULTS)?;
+	let worst_us = u16::from_le_bytes([stats[0], stats[1]]);
+	let bytes = u32::from_le_bytes([stats[4], stats[5], stats[6], stats[7]]);
+	let detail = format!(
+		"{} reads ({} failed), {} PS/2 bytes, worst PS/2 latency {} us",
+		reads, errors, bytes, worst_us
+	);
+	if (faults & HEALTH_PS2_FAULTS) != 0 {
+		Ok(Outcome::Fail(format!(
+			"{}, fault flags 0x{:02X}",
+			detail, faults
+		)))
+	} else if bytes == 0 {
+		Ok(Outcome::Skip(format!("{} - nobody typed", detail)))
+	} else {
+		Ok(Outcome::Pass(detail))
+	}
+}
+
+/// Power the main board on and off with the relay across the power button,
+/// and suspend and resume it, watching the Power Control register.
+fn power_sequencing(bmc: &mut Bmc, options: &Options) -> Result<Outcome, Error> {
+	if !bmc.adapter().has_relay() {
+		return Ok(Outcome::Skip("no relay on this rig".into()));
+	}
+	let address = registers::POWER_CONTROL.address;
+	if bmc.read(address, 1)?[0] != POWER_OFF {
+		bmc.write(address, POWER_OFF)?;
+		wait_power(bmc, POWER_OFF)?;
+	}
+	let mut on_times = Vec::new();
+	let mut off_times = Vec::new();
+	for _ in 0..options.power_cycles {
+		press(bmc, SHORT_PRESS)?;
+		on_times.push(wait_power(bmc, POWER_ON)?);
+		// Suspend, and wake with a short press
+		bmc.write(address, POWER_SUSPENDED)?;
+		wait_power(bmc, POWER_SUSPENDED)?;
+		press(bmc, SHORT_PRESS)?;
+		wait_power(bmc, POWER_ON)?;
+		// A long press forces the power off, whatever the host says
+		press(bmc, LONG_PRESS)?;
+		off_times.push(wait_power(bmc, POWER_OFF)?);
+	}
+	let slowest = |times: &[Duration]| times.iter().max().copied().unwrap_or_default();
+	Ok(Outcome::Pass(format!(
+		"{} cycles, on within {} ms of release, off within {} ms of release",
+		options.power_cycles,
+		slowest(&on_times).as_millis(),
+		slowest(&off_times).as_millis()
+	)))
+}
+
+/// Check the NBMC is still running, and hasn't recorded any faults.
+fn bmc_health(bmc: &mut Bmc) -> Result<Outcome, Error> {
+	let address = registers::BMC_HEALTH.address;
+	let before = bmc.read(address, 4)?;
+	sleep(Duration::from_millis(250));
+	let after = bmc.read(address, 4)?;
+	let heartbeat = |data: &[u8]| u16::from_le_bytes([data[0], data[1]]);
+	if heartbeat(&before) == heartbeat(&after) {
+		return Ok(Outcome::Fail("heartbeat has stopped".into()));
+	}
+	if after[2] != 0 {
+		return Ok(Outcome::Fail(format!(
+			"fault flags 0x{:02X}, last bad result 0x{:02X}",
+			after[2], after[3]
+		)));
+	}
+	Ok(Outcome::Pass("heartbeat running, no faults".into()))
+}
+
+/// Read the Firmware Feature Flags.
+fn feature_flags(bmc: &mut Bmc) -> Result<u32, Error> {
+	let data = bmc.read(registers::FIRMWARE_FEATURE_FLAGS.address, 4)?;
+	Ok(u32::from_le_bytes([data[0], data[1], data[2], data[3]]))
+}
+
+/// Start a deferred operation, run it to the end and acknowledge it.
+///
+/// Returns the operation's result.
+fn run_deferred(bmc: &mut Bmc, register: u8, data: u8) -> Result<u8, Error> {
+	let token = start_deferred(bmc, register, data)?;
+	let result = wait_deferred(bmc, token)?;
+	bmc.write(registers::DEFERRED_OPERATION.address, token)?;
+	bmc.write(
+		registers::INTERRUPT_STATUS_HIGH.address,
+		IRQ_HIGH_DEFERRED_DONE,
+	)?;
+	Ok(result)
+}
+
+/// Write to a register which starts a deferred operation.
+///
+/// Returns the operation's token.
+fn start_deferred(bmc: &mut Bmc, register: u8, data: u8) -> Result<u8, Error> {
+	match bmc.write(register, data) {
+		Err(Error::Result(ResponseResult::Busy)) => {}
+		Ok(()) => return Err(Error::Result(ResponseResult::Ok)),
+		Err(e) => return Err(e),
+	}
+	let state = bmc.read(registers::DEFERRED_OPERATION.address, 4)?;
+	if state[2] != register {
+		// Something else was already running, so ours never started
+		return Err(Error::Result(ResponseResult::Busy));
+	}
+	Ok(state[0])
+}
+
+/// Wait for a deferred operation to finish, and return its result.
+fn wait_deferred(bmc: &mut Bmc, token: u8) -> Result<u8, Error> {
+	let mut result = 0;
+	wait_for(bmc, DEFERRED_TIMEOUT, "the deferred operation", |bmc| {
+		let state = bmc.read(registers::DEFERRED_OPERATION.address, 4)?;
+		if state[0] != token {
+			return Err(Error::Timeout("our deferred operation (token changed)"));
+		}
+		result = state[3];
+		Ok(state[1] == DEFERRED_FINISHED)
+	})?;
+	Ok(result)
+}
+
+/// Close the relay across the power button for a while.
+fn press(bmc: &mut Bmc, duration: Duration) -> Result<(), Error> {
+	bmc.adapter().set_relay(true)?;
+	sleep(duration);
+	bmc.adapter().set_relay(false)
+}
+
+/// Wait for the Power Control register to read `state`.
+fn wait_power(bmc: &mut Bmc, state: u8) -> Result<Duration, Error> {
+	wait_for(bmc, POWER_TIMEOUT, "the power state to change", |bmc| {
+		Ok(bmc.read(registers::POWER_CONTROL.address, 1)?[0] == state)
+	})
+}
+
+/// Poll `done` until it returns `true`, or `timeout` passes.
+///
+/// Returns how long it took.
+fn wait_for<F>(
+	bmc: &mut Bmc,
+	timeout: Duration,
+	what: &'static str,
+	mut done: F,
+) -> Result<Duration, Error>
+where
+	F: FnMut(&mut Bmc) -> Result<bool, Error>,
+{
+	let start = Instant::now();
+	loop {
+		if done(bmc)? {
+			return Ok(start.elapsed());
+		}
+		if start.elapsed() > timeout {
+			return Err(Error::Timeout(what));
+		}
+	}
+}
+
+/// A small random number generator, so each run hits the NBMC with the same
+/// sequence.
+struct XorShift(u32);
+
+impl XorShift {
+	/// The next number in the sequence
+	fn next(&mut self) -> u32 {
+		self.0 ^= self.0 << 13;
+		self.0 ^= self.0 >> 17;
+		self.0 ^= self.0 << 5;
+		self.0
+	}
+
+	/// A number from `0` to `limit - 1`
+	fn below(&mut self, limit: u8) -> u8 {
+		(self.next() % u32::from(limit)) as u8
+	}
+}
@@ -1,20 +1,27 @@
 /// This is the build-script for the Neotron BMC.
 ///
-/// It just copies the memory.x file somewhere Cargo can find it, then generates a version header.
+/// It just copies the memory.x file (or memory-f072.x, for the STM32F072) somewhere Cargo can find
+/// it, then generates a version header and the values for the Build Info register.
 use std::env;
 use std::fs::File;
 use std::io::Write;
 use std::path::PathBuf;
 
 fn main() {
-	// Put the linker script somewhere the linker can find it
+	// Put the linker script for our part somewhere the linker can find it
 	let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
+	let memory: &[u8] = if env::var_os("CARGO_FEATURE_STM32F072").is_some() {
+		include_bytes!("memory-f072.x")
+	} else {
+		include_bytes!("memory.x")
+	};
 	File::create(out.join("memory.x"))
 		.unwrap()
-		.write_all(include_bytes!("memory.x"))
+		.write_all(memory)
 		.unwrap();
 	println!("cargo:rustc-link-search={}", out.display());
 	println!("cargo:rerun-if-changed=memory.x");
+	println!("cargo:rerun-if-changed=memory-f072.x");
 
 	// Generate a file containing the firmware version
 	let version_output = std::process::Command::new("git")
@@ -27,7 +34,59 @@ fn main() {
 	// Remove the trailing newline
 	let mut output = version_output.stdout;
 	output.pop();
+	let is_dirty = output.ends_with(b"-dirty");
 
 	// Write the file
 	std::fs::write(out.join("version.txt"), output).expect("writing version file");
+
+	// Get the commit hash, for the Build Info register
+	let hash_output = std::process::Command::new("git")
+		.current_dir(env::var_os("CARGO_MANIFEST_DIR").unwrap())
+		.args(&["rev-parse", "HEAD"])
+		.output()
+		.expect("running git-rev-parse");
+	assert!(hash_output.status.success());
+	let hash = String::from_utf8(hash_output.stdout).expect("git hash is UTF-8");
+	let hash_bytes: Vec<String> = (0..8)
+		.map(|i| format!("0x{}", &hash[i * 2..(i * 2) + 2]))
+		.collect();
+
+	// Honour SOURCE_DATE_EPOCH for reproducible builds
+	println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+	let build_timestamp: u32 = match env::var("SOURCE_DATE_EPOCH") {
+		Ok(s) => s.parse().expect("parsing SOURCE_DATE_EPOCH"),
+		Err(_) => std::time::SystemTime::now()
+			.duration_since(std::time::UNIX_EPOCH)
+			.expect("clock is after 1970")
+			.as_secs() as u32,
+	};
+
+	// Write the file
+	let mut build_info = File::create(out.join("build_info.rs")).expect("creating build info file");
+	writeln!(
+		build_info,
+		"/// The firmware version, from Cargo.toml\nconst BUILD_FIRMWARE_VERSION: [u8; 3] = [{}, {}, {}];",
+		env::var("CARGO_PKG_VERSION_MAJOR").unwrap(),
+		env::var("CARGO_PKG_VERSION_MINOR").unwrap(),
+		env::var("CARGO_PKG_VERSION_PATCH").unwrap()
+	)
+	.unwrap();
+	writeln!(
+		build_info,
+		"/// The first eight bytes of the git commit hash\nconst BUILD_GIT_HASH: [u8; 8] = [{}];",
+		hash_bytes.join(", ")
+	)
+	.unwrap();
+	writeln!(
+		build_info,
+		"/// Did the working copy have uncommitted changes?\nconst BUILD_GIT_DIRTY: bool = {};",
+		is_dirty
+	)
+	.unwrap();
+	writeln!(
+		build_info,
+		"/// When the firmware was built, in seconds since the Unix epoch\nconst BUILD_TIMESTAMP: u32 = {};",
+		build_timestamp
+	)
+	.unwrap();
 }
@@ -0,0 +1,13 @@
+MEMORY
+{
+  /* NOTE K = KiBi = 1024 bytes */
+  /* The STM32F072 has 2K pages. The last three are reserved for the host's
+     NVRAM, the lifetime counters and the config store (see `src/config.rs`) */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 122K
+  RAM : ORIGIN = 0x20000000, LENGTH = 16K
+}
+
+/* This is where the call stack will be allocated. */
+/* The stack is of the full descending type. */
+/* NOTE Do NOT modify `_stack_start` unless you know what you are doing */
+_stack_start = ORIGIN(RAM) + LENGTH(RAM);
@@ -1,7 +1,9 @@
 MEMORY
 {
   /* NOTE K = KiBi = 1024 bytes */
-  FLASH : ORIGIN = 0x08000000, LENGTH = 32K
+  /* The last three 1K pages are reserved for the host's NVRAM, the lifetime
+     counters and the config store (see `src/config.rs`) */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 29K
   RAM : ORIGIN = 0x20000000, LENGTH = 4K
 }
 
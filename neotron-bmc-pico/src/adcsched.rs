@@ -0,0 +1,303 @@
+//! # ADC Scheduler
+//!
+//! The ADC is shared between the analog inputs and the internal temperature
+//! sensor, which want sampling at quite different rates - a rail monitor
+//! every 100 ms, say, but the temperature only once a second. So each
+//! channel has its own sample interval, which the host can change, and the
+//! [`AdcScheduler`] says which channels are due each time the analog task
+//! wakes up.
+//!
+//! Each channel can also have a filter, applied to the raw ADC counts before
+//! they're turned into a voltage or a temperature: a first-order IIR low
+//! pass, to smooth out noise, or a median of the last three samples, which
+//! throws away a single spike entirely. The filters run on top of the
+//! averaging set by the Analog Averaging register.
+
+use crate::analog::{self, ANALOG_INPUTS};
+
+/// How many channels are scheduled: the analog inputs, and then the
+/// temperature sensor
+pub const ADC_CHANNELS: usize = ANALOG_INPUTS + 1;
+
+/// The temperature sensor's channel
+pub const CHANNEL_TEMPERATURE: usize = ANALOG_INPUTS;
+
+/// A bit for each analog input's channel
+pub const INPUT_CHANNELS: u8 = (1 << ANALOG_INPUTS) - 1;
+
+/// How often each channel is sampled to begin with, in units of 10 ms - the
+/// analog inputs every 100 ms, and the temperature once a second
+pub const DEFAULT_INTERVALS: [u8; ADC_CHANNELS] =
+	[analog::DEFAULT_INTERVAL, analog::DEFAULT_INTERVAL, 100];
+
+/// How many bits each channel takes in the ADC Channel Filters register
+const FILTER_BITS: usize = 2;
+
+/// The IIR filter follows each new sample by 1/2^N of the difference
+const IIR_SHIFT: u32 = 2;
+
+/// How a channel's samples are filtered.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Filter {
+	/// Each sample is used as it is
+	None,
+	/// A first-order IIR low pass, which moves 1/4 of the way towards each
+	/// new sample
+	Iir,
+	/// The median of the last three samples
+	Median,
+}
+
+impl Filter {
+	/// Decode a filter from its two bits in the ADC Channel Filters register.
+	pub fn from_bits(bits: u8) -> Option<Filter> {
+		match bits {
+			0 => Some(Filter::None),
+			1 => Some(Filter::Iir),
+			2 => Some(Filter::Median),
+			_ => None,
+		}
+	}
+
+	/// Encode a filter as its two bits in the ADC Channel Filters register.
+	pub fn bits(self) -> u8 {
+		match self {
+			Filter::None => 0,
+			Filter::Iir => 1,
+			Filter::Median => 2,
+		}
+	}
+}
+
+/// One channel's schedule and filter.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct Channel {
+	/// How often to sample, in units of 10 ms. Zero stops sampling.
+	interval: u8,
+	/// When we last sampled, if we have
+	last_sample_ms: Option<u32>,
+	/// How the samples are filtered
+	filter: Filter,
+	/// The last three samples, newest last, for the median filter
+	recent: [u16; 3],
+	/// The IIR filter's output, times 2^`IIR_SHIFT`
+	iir: u32,
+	/// Have the filters seen a sample yet?
+	primed: bool,
+}
+
+impl Channel {
+	/// Create a channel, sampled every `interval`, with no filter.
+	const fn new(interval: u8) -> Channel {
+		Channel {
+			interval,
+			last_sample_ms: None,
+			filter: Filter::None,
+			recent: [0; 3],
+			iir: 0,
+			primed: false,
+		}
+	}
+
+	/// Is it time to take a sample?
+	fn is_due(&self, now_ms: u32) -> bool {
+		if self.interval == 0 {
+			return false;
+		}
+		match self.last_sample_ms {
+			Some(last) => now_ms.wrapping_sub(last) >= u32::from(self.interval) * 10,
+			None => true,
+		}
+	}
+
+	/// Run a sample through the filter.
+	fn filter(&mut self, counts: u16) -> u16 {
+		if !self.primed {
+			// Start from the first sample, rather than ramping up from zero
+			self.primed = true;
+			self.recent = [counts; 3];
+			self.iir = u32::from(counts) << IIR_SHIFT;
+		} else {
+			self.recent = [self.recent[1], self.recent[2], counts];
+			self.iir = self.iir - (self.iir >> IIR_SHIFT) + u32::from(counts);
+		}
+		match self.filter {
+			Filter::None => counts,
+			Filter::Iir => (self.iir >> IIR_SHIFT) as u16,
+			Filter::Median => {
+				let [a, b, c] = self.recent;
+				a.min(b).max(a.max(b).min(c))
+			}
+		}
+	}
+}
+
+/// Decides when each ADC channel is sampled, and filters the samples.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdcScheduler {
+	/// Each channel, in order
+	channels: [Channel; ADC_CHANNELS],
+	/// Channels someone wants sampled now, whatever the schedule says
+	requested: u8,
+}
+
+impl AdcScheduler {
+	/// Create the scheduler, with the default intervals and no filters.
+	pub const fn new() -> AdcScheduler {
+		AdcScheduler {
+			channels: [
+				Channel::new(DEFAULT_INTERVALS[0]),
+				Channel::new(DEFAULT_INTERVALS[1]),
+				Channel::new(DEFAULT_INTERVALS[2]),
+			],
+			requested: 0,
+		}
+	}
+
+	/// How often a channel is sampled, in units of 10 ms. Zero means never.
+	pub fn interval(&self, channel: usize) -> u8 {
+		self.channels[channel].interval
+	}
+
+	/// Set how often a channel is sampled, in units of 10 ms. Zero stops
+	/// sampling.
+	pub fn set_interval(&mut self, channel: usize, interval: u8) {
+		self.channels[channel].interval = interval;
+	}
+
+	/// Each channel's filter, two bits each, for the ADC Channel Filters
+	/// register.
+	pub fn filters(&self) -> u8 {
+		self.channels
+			.iter()
+			.enumerate()
+			.fold(0, |bits, (index, channel)| {
+				bits | (channel.filter.bits() << (index * FILTER_BITS))
+			})
+	}
+
+	/// Set each channel's filter, from the ADC Channel Filters register.
+	///
+	/// Returns `false` (and changes nothing) if any channel's bits aren't a
+	/// filter, or a reserved bit is set. A channel whose filter changes
+	/// starts again from its next sample.
+	pub fn set_filters(&mut self, bits: u8) -> bool {
+		if bits >> (ADC_CHANNELS * FILTER_BITS) != 0 {
+			return false;
+		}
+		let mut filters = [Filter::None; ADC_CHANNELS];
+		for (index, filter) in filters.iter_mut().enumerate() {
+			match Filter::from_bits((bits >> (index * FILTER_BITS)) & 0b11) {
+				Some(f) => *filter = f,
+				None => return false,
+			}
+		}
+		for (channel, filter) in self.channels.iter_mut().zip(filters.iter()) {
+			if channel.filter != *filter {
+				channel.filter = *filter;
+				channel.primed = false;
+			}
+		}
+		true
+	}
+
+	/// Sample some channels (a bitmask) as soon as possible, even if their
+	/// sampling is stopped.
+	pub fn request(&mut self, channels: u8) {
+		self.requested |= channels;
+	}
+
+	/// Which channels are due to be sampled, as a bitmask.
+	pub fn due(&self, now_ms: u32) -> u8 {
+		self.channels
+			.iter()
+			.enumerate()
+			.fold(self.requested, |due, (index, channel)| {
+				if channel.is_due(now_ms) {
+					due | (1 << index)
+				} else {
+					due
+				}
+			})
+	}
+
+	/// Record a sample of a channel, in raw ADC counts, and return it
+	/// filtered.
+	pub fn sampled(&mut self, channel: usize, now_ms: u32, counts: u16) -> u16 {
+		self.requested &= !(1 << channel);
+		let channel = &mut self.channels[channel];
+		channel.last_sample_ms = Some(now_ms);
+		channel.filter(counts)
+	}
+}
+
+impl Default for AdcScheduler {
+	fn default() -> Self {
+		AdcScheduler::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn schedule() {
+		let mut scheduler = AdcScheduler::new();
+		// Everything straight away
+		assert_eq!(scheduler.due(5), 0b111);
+		for channel in 0..ADC_CHANNELS {
+			scheduler.sampled(channel, 5, 0);
+		}
+		// Then the inputs every 100 ms, and the temperature every second
+		assert_eq!(scheduler.due(104), 0);
+		assert_eq!(scheduler.due(105), INPUT_CHANNELS);
+		scheduler.sampled(0, 105, 0);
+		scheduler.sampled(1, 105, 0);
+		assert_eq!(scheduler.due(1005), 0b111);
+		// Each channel has its own interval
+		scheduler.set_interval(1, 1);
+		assert_eq!(scheduler.interval(1), 1);
+		assert_eq!(scheduler.due(115), 0b010);
+		// Stopped, unless someone asks
+		scheduler.set_interval(0, 0);
+		assert_eq!(scheduler.due(5000) & 0b001, 0);
+		scheduler.request(0b001);
+		assert_eq!(scheduler.due(5000) & 0b001, 0b001);
+		scheduler.sampled(0, 5000, 0);
+		assert_eq!(scheduler.due(5000) & 0b001, 0);
+	}
+
+	#[test]
+	fn filters() {
+		let mut scheduler = AdcScheduler::new();
+		assert_eq!(scheduler.filters(), 0);
+		// Input 0 unfiltered, input 1 IIR, temperature median
+		assert!(scheduler.set_filters(0b10_01_00));
+		assert_eq!(scheduler.filters(), 0b10_01_00);
+		assert!(!scheduler.set_filters(0b11));
+		assert!(!scheduler.set_filters(0b0100_0000));
+		assert_eq!(scheduler.filters(), 0b10_01_00);
+		// Each filter starts from its first sample
+		for channel in 0..ADC_CHANNELS {
+			assert_eq!(scheduler.sampled(channel, 0, 1000), 1000);
+		}
+		// A spike
+		assert_eq!(scheduler.sampled(0, 10, 2000), 2000);
+		assert_eq!(scheduler.sampled(1, 10, 2000), 1250);
+		assert_eq!(scheduler.sampled(2, 10, 2000), 1000);
+		assert_eq!(scheduler.sampled(2, 20, 1000), 1000);
+		assert_eq!(scheduler.sampled(2, 25, 1000), 1000);
+		// A step gets through the median filter on the second sample
+		assert_eq!(scheduler.sampled(2, 30, 3000), 1000);
+		assert_eq!(scheduler.sampled(2, 40, 3000), 3000);
+		// The IIR filter settles
+		for now in 0..50 {
+			scheduler.sampled(1, now, 2000);
+		}
+		assert_eq!(scheduler.sampled(1, 20, 2000), 2000);
+		// Changing the filter starts it again
+		assert!(scheduler.set_filters(0b01_00_00));
+		assert_eq!(scheduler.sampled(2, 50, 500), 500);
+	}
+}
@@ -0,0 +1,493 @@
+//! # Analog Inputs
+//!
+//! The host can read the voltage on the BMC's ADC-capable pins, on a schedule
+//! it chooses, so builders can wire up a battery or a potentiometer and read
+//! it from the OS.
+//!
+//! Every pin which can reach the ADC on the Neotron Pico is already spoken
+//! for, except PA0 and PA1 - the rail monitor inputs - so those are analog
+//! inputs 0 and 1. Readings are the voltage at the pin, before any divider.
+//!
+//! Each time we sample, we also measure the internal voltage reference, which
+//! tells us our own 3.3V supply, so the readings stay accurate as the supply
+//! wanders. The `current-sense` and `fan` features need the ADC for
+//! themselves, so there are no analog inputs in those builds.
+//!
+//! The rail monitors sit behind dividers, whose resistors are only so
+//! accurate, so each input has a gain and an offset (a [`Calibration`]),
+//! kept in flash, which turn the voltage at the pin into the voltage on the
+//! rail. They start out doing nothing, so the readings are the voltage at
+//! the pin, until someone calibrates them - by hand, or by telling us the
+//! rails are at exactly 3.300V and 5.000V.
+//!
+//! As the inputs are the rail monitors, we also keep the lowest, highest and
+//! average reading of each since the main board last powered on, so a brief
+//! brown-out under load, or a marginal supply, shows up even if nobody was
+//! reading at the time.
+
+/// How many analog inputs there are
+pub const ANALOG_INPUTS: usize = 2;
+
+/// A bit for each analog input which exists in this build.
+#[cfg(not(any(feature = "current-sense", feature = "fan")))]
+pub const ANALOG_AVAILABLE: u8 = 0b0000_0011;
+/// A bit for each analog input which exists in this build.
+#[cfg(any(feature = "current-sense", feature = "fan"))]
+pub const ANALOG_AVAILABLE: u8 = 0;
+
+/// The sample interval we start with, in units of 10 ms
+pub const DEFAULT_INTERVAL: u8 = 10;
+
+/// The most samples we average, as a power of two (so 16 samples)
+pub const MAX_AVERAGING: u8 = 4;
+
+/// The supply voltage the internal reference was calibrated at, in millivolts
+const VREFINT_CAL_MV: u32 = 3300;
+
+/// The largest reading the 12-bit ADC can give
+const ADC_FULL_SCALE: u32 = 4095;
+
+/// A calibration gain of one, as gains are in units of 1/4096
+pub const CALIBRATION_UNITY: u16 = 4096;
+
+/// What a known-good board's rails read, in millivolts, for a factory
+/// calibration: 3.3V on input 0 (MON_3V3) and 5.0V on input 1 (MON_5V)
+pub const FACTORY_RAILS_MV: [u16; ANALOG_INPUTS] = [3300, 5000];
+
+/// The rolling average follows each new sample by 1/2^N of the difference,
+/// so it covers roughly the last 16 samples
+const AVERAGE_SHIFT: u32 = 4;
+
+/// The average temperature sensor slope, in ADC counts per 1000 °C (from RM0360)
+const TS_AVG_SLOPE: i32 = 5336;
+
+/// Work out the 3.3V supply voltage, in millivolts, from an ADC reading of
+/// the internal voltage reference.
+///
+/// `vrefint_cal` is the reading taken at the factory, with a 3.3V supply.
+pub fn vdda_mv(vrefint_cal: u16, vrefint: u16) -> u16 {
+	if vrefint == 0 {
+		return 0;
+	}
+	let mv = (VREFINT_CAL_MV * u32::from(vrefint_cal)) / u32::from(vrefint);
+	mv.min(u32::from(u16::MAX)) as u16
+}
+
+/// Work out the voltage at an input, in millivolts, from an ADC reading,
+/// given our 3.3V supply in millivolts.
+pub fn counts_to_mv(counts: u16, vdda_mv: u16) -> u16 {
+	((u32::from(counts) * u32::from(vdda_mv)) / ADC_FULL_SCALE) as u16
+}
+
+/// Work out the temperature, in °C, from an ADC reading of the internal
+/// temperature sensor.
+///
+/// `ts_cal1` is the reading taken at the factory, at 30 °C.
+pub fn temperature_c(ts_cal1: u16, counts: u16) -> i8 {
+	let temperature_c = ((i32::from(ts_cal1) - i32::from(counts)) * 1000) / TS_AVG_SLOPE + 30;
+	temperature_c.clamp(i32::from(i8::MIN), i32::from(i8::MAX)) as i8
+}
+
+/// Turns the voltage at an input pin into the voltage it is measuring.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub struct Calibration {
+	/// What to multiply the voltage at the pin by, in units of
+	/// 1/`CALIBRATION_UNITY`
+	pub gain: u16,
+	/// What to add afterwards, in millivolts
+	pub offset_mv: i16,
+}
+
+impl Calibration {
+	/// How many bytes a calibration takes, in flash and in the Analog
+	/// Calibration register.
+	pub const SIZE: usize = 4;
+
+	/// The calibration which leaves the voltage at the pin alone.
+	pub const NONE: Calibration = Calibration {
+		gain: CALIBRATION_UNITY,
+		offset_mv: 0,
+	};
+
+	/// Work out the calibration which makes `pin_mv` read as `expected_mv`,
+	/// with no offset.
+	///
+	/// Returns `None` if the pin reads zero, or the gain would be too big.
+	pub fn for_reading(pin_mv: u16, expected_mv: u16) -> Option<Calibration> {
+		if pin_mv == 0 {
+			return None;
+		}
+		let pin_mv = u32::from(pin_mv);
+		let gain = (u32::from(expected_mv) * u32::from(CALIBRATION_UNITY) + pin_mv / 2) / pin_mv;
+		if gain > u32::from(u16::MAX) {
+			return None;
+		}
+		Some(Calibration {
+			gain: gain as u16,
+			offset_mv: 0,
+		})
+	}
+
+	/// Correct the voltage at a pin, in millivolts.
+	pub fn apply(&self, pin_mv: u16) -> u16 {
+		let unity = u32::from(CALIBRATION_UNITY);
+		let mv = (u32::from(pin_mv) * u32::from(self.gain) + unity / 2) / unity;
+		let mv = mv as i32 + i32::from(self.offset_mv);
+		mv.clamp(0, i32::from(u16::MAX)) as u16
+	}
+
+	/// Convert to bytes: the gain and then the offset, each little-endian.
+	pub fn as_bytes(&self) -> [u8; Self::SIZE] {
+		let gain = self.gain.to_le_bytes();
+		let offset = self.offset_mv.to_le_bytes();
+		[gain[0], gain[1], offset[0], offset[1]]
+	}
+
+	/// Convert from bytes, as made by [`Calibration::as_bytes`].
+	pub fn from_bytes(bytes: &[u8; Self::SIZE]) -> Calibration {
+		Calibration {
+			gain: u16::from_le_bytes([bytes[0], bytes[1]]),
+			offset_mv: i16::from_le_bytes([bytes[2], bytes[3]]),
+		}
+	}
+}
+
+impl Default for Calibration {
+	fn default() -> Self {
+		Calibration::NONE
+	}
+}
+
+/// The lowest, highest and average readings of one input.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct InputHistory {
+	/// Have there been any readings?
+	recorded: bool,
+	/// The lowest reading, in millivolts
+	min_mv: u16,
+	/// The highest reading, in millivolts
+	max_mv: u16,
+	/// The rolling average, in millivolts, times 2^`AVERAGE_SHIFT`
+	average: u32,
+}
+
+impl InputHistory {
+	/// How many bytes one input takes in the Analog Input History register.
+	pub const SIZE: usize = 6;
+
+	/// Create an empty history.
+	pub const fn new() -> InputHistory {
+		InputHistory {
+			recorded: false,
+			min_mv: 0,
+			max_mv: 0,
+			average: 0,
+		}
+	}
+
+	/// Add a reading, in millivolts.
+	fn record(&mut self, mv: u16) {
+		if !self.recorded {
+			*self = InputHistory {
+				recorded: true,
+				min_mv: mv,
+				max_mv: mv,
+				average: u32::from(mv) << AVERAGE_SHIFT,
+			};
+			return;
+		}
+		self.min_mv = self.min_mv.min(mv);
+		self.max_mv = self.max_mv.max(mv);
+		self.average = self.average - (self.average >> AVERAGE_SHIFT) + u32::from(mv);
+	}
+
+	/// The lowest reading, in millivolts, or zero if there are none.
+	pub fn min_mv(&self) -> u16 {
+		self.min_mv
+	}
+
+	/// The highest reading, in millivolts, or zero if there are none.
+	pub fn max_mv(&self) -> u16 {
+		self.max_mv
+	}
+
+	/// The rolling average, in millivolts, or zero if there are no readings.
+	pub fn average_mv(&self) -> u16 {
+		(self.average >> AVERAGE_SHIFT) as u16
+	}
+}
+
+/// The analog input settings, and the most recent readings.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnalogInputs {
+	/// How many samples to average, as a power of two
+	averaging: u8,
+	/// When we last sampled, if we have
+	last_sample_ms: Option<u32>,
+	/// Our 3.3V supply, in millivolts, or zero if we haven't sampled yet
+	vdda_mv: u16,
+	/// The voltage at each input pin, in millivolts
+	pin_mv: [u16; ANALOG_INPUTS],
+	/// The voltage on each input, after calibration, in millivolts
+	millivolts: [u16; ANALOG_INPUTS],
+	/// The lowest, highest and average voltage on each input since
+	/// `clear_history`
+	history: [InputHistory; ANALOG_INPUTS],
+}
+
+impl AnalogInputs {
+	/// How many bytes the Analog Input Readings register holds.
+	pub const SIZE: usize = ANALOG_INPUTS * 2;
+
+	/// How many bytes the Analog Input History register holds.
+	pub const HISTORY_SIZE: usize = ANALOG_INPUTS * InputHistory::SIZE;
+
+	/// Create the analog input state, with nothing sampled yet.
+	pub const fn new() -> AnalogInputs {
+		AnalogInputs {
+			averaging: 0,
+			last_sample_ms: None,
+			vdda_mv: 0,
+			pin_mv: [0; ANALOG_INPUTS],
+			millivolts: [0; ANALOG_INPUTS],
+			history: [InputHistory::new(); ANALOG_INPUTS],
+		}
+	}
+
+	/// How many samples we average, as a power of two.
+	pub fn averaging(&self) -> u8 {
+		self.averaging
+	}
+
+	/// Set how many samples we average, as a power of two.
+	///
+	/// Returns `false` (and changes nothing) if that's more than
+	/// `MAX_AVERAGING`.
+	pub fn set_averaging(&mut self, averaging: u8) -> bool {
+		if averaging > MAX_AVERAGING {
+			return false;
+		}
+		self.averaging = averaging;
+		true
+	}
+
+	/// How many samples to take of each input, to average.
+	pub fn samples(&self) -> u16 {
+		1 << self.averaging
+	}
+
+	/// Record a sample.
+	///
+	/// `vrefint` is the (averaged) reading of the internal voltage reference,
+	/// `vrefint_cal` its factory reading, `inputs` the (averaged and
+	/// filtered) readings of each input which was sampled, and `calibration`
+	/// how to correct each input. Inputs which weren't sampled keep their
+	/// last reading.
+	pub fn record(
+		&mut self,
+		now_ms: u32,
+		vrefint_cal: u16,
+		vrefint: u16,
+		inputs: &[Option<u16>; ANALOG_INPUTS],
+		calibration: &[Calibration; ANALOG_INPUTS],
+	) {
+		self.last_sample_ms = Some(now_ms);
+		self.vdda_mv = vdda_mv(vrefint_cal, vrefint);
+		for input in 0..ANALOG_INPUTS {
+			let Some(counts) = inputs[input] else {
+				continue;
+			};
+			self.pin_mv[input] = counts_to_mv(counts, self.vdda_mv);
+			self.millivolts[input] = calibration[input].apply(self.pin_mv[input]);
+			self.history[input].record(self.millivolts[input]);
+		}
+	}
+
+	/// Work out the calibration of each input from the last sample, taken
+	/// with the rails at exactly `FACTORY_RAILS_MV`.
+	///
+	/// Returns `None` if there hasn't been a sample, or an input can't be
+	/// calibrated (see [`Calibration::for_reading`]).
+	pub fn factory_calibration(&self) -> Option<[Calibration; ANALOG_INPUTS]> {
+		self.last_sample_ms?;
+		let mut calibration = [Calibration::NONE; ANALOG_INPUTS];
+		for input in 0..ANALOG_INPUTS {
+			calibration[input] =
+				Calibration::for_reading(self.pin_mv[input], FACTORY_RAILS_MV[input])?;
+		}
+		Some(calibration)
+	}
+
+	/// The lowest, highest and average voltage on an input.
+	pub fn history(&self, input: usize) -> &InputHistory {
+		&self.history[input]
+	}
+
+	/// Forget the lowest, highest and average voltages. Call this when the
+	/// main board powers on.
+	pub fn clear_history(&mut self) {
+		self.history = [InputHistory::new(); ANALOG_INPUTS];
+	}
+
+	/// Convert to bytes for the Analog Input History register.
+	///
+	/// That's the lowest, highest and average voltage on each input in
+	/// millivolts, each as a `u16le`.
+	pub fn history_bytes(&self) -> [u8; Self::HISTORY_SIZE] {
+		let mut bytes = [0u8; Self::HISTORY_SIZE];
+		for (chunk, history) in bytes
+			.chunks_exact_mut(InputHistory::SIZE)
+			.zip(self.history.iter())
+		{
+			chunk[0..2].copy_from_slice(&history.min_mv().to_le_bytes());
+			chunk[2..4].copy_from_slice(&history.max_mv().to_le_bytes());
+			chunk[4..6].copy_from_slice(&history.average_mv().to_le_bytes());
+		}
+		bytes
+	}
+
+	/// Our 3.3V supply, in millivolts, if we have sampled it.
+	pub fn vdda_mv(&self) -> Option<u16> {
+		self.last_sample_ms.map(|_| self.vdda_mv)
+	}
+
+	/// The voltage on an input, after calibration, in millivolts.
+	pub fn millivolts(&self, input: usize) -> u16 {
+		self.millivolts[input]
+	}
+
+	/// Convert to bytes for the Analog Input Readings register.
+	///
+	/// That's the voltage on each input in millivolts, as a `u16le`.
+	pub fn as_bytes(&self) -> [u8; Self::SIZE] {
+		let mut bytes = [0u8; Self::SIZE];
+		for (chunk, mv) in bytes.chunks_exact_mut(2).zip(self.millivolts.iter()) {
+			chunk.copy_from_slice(&mv.to_le_bytes());
+		}
+		bytes
+	}
+}
+
+impl Default for AnalogInputs {
+	fn default() -> Self {
+		AnalogInputs::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Readings at the pins
+	const UNCALIBRATED: [Calibration; ANALOG_INPUTS] = [Calibration::NONE; ANALOG_INPUTS];
+
+	#[test]
+	fn supply_voltage() {
+		// A typical calibration value
+		assert_eq!(vdda_mv(1500, 1500), 3300);
+		assert_eq!(vdda_mv(1500, 1650), 3000);
+		assert_eq!(vdda_mv(1500, 0), 0);
+	}
+
+	#[test]
+	fn temperature() {
+		assert_eq!(temperature_c(1700, 1700), 30);
+		// The sensor reading falls as it warms up
+		assert_eq!(temperature_c(1700, 1487), 69);
+		assert_eq!(temperature_c(1700, 1863), 0);
+		assert_eq!(temperature_c(4095, 0), i8::MAX);
+	}
+
+	#[test]
+	fn averaging() {
+		let mut analog = AnalogInputs::new();
+		assert_eq!(analog.samples(), 1);
+		assert!(analog.set_averaging(MAX_AVERAGING));
+		assert_eq!(analog.samples(), 16);
+		assert!(!analog.set_averaging(MAX_AVERAGING + 1));
+		assert_eq!(analog.averaging(), MAX_AVERAGING);
+	}
+
+	#[test]
+	fn readings() {
+		let mut analog = AnalogInputs::new();
+		assert_eq!(analog.vdda_mv(), None);
+		// A 3.0V supply, with input 0 at half scale and input 1 at full scale
+		analog.record(0, 1500, 1650, &[Some(2048), Some(4095)], &UNCALIBRATED);
+		assert_eq!(analog.vdda_mv(), Some(3000));
+		assert_eq!(analog.millivolts(0), 1500);
+		assert_eq!(analog.millivolts(1), 3000);
+		assert_eq!(analog.as_bytes(), [0xDC, 0x05, 0xB8, 0x0B]);
+		// Only input 1 sampled
+		analog.record(10, 1500, 1500, &[None, Some(0)], &UNCALIBRATED);
+		assert_eq!(analog.millivolts(0), 1500);
+		assert_eq!(analog.millivolts(1), 0);
+	}
+
+	#[test]
+	fn history() {
+		let mut analog = AnalogInputs::new();
+		assert_eq!(analog.history_bytes(), [0; AnalogInputs::HISTORY_SIZE]);
+		// A 3.3V supply, so 4095 counts is 3300 mV
+		analog.record(0, 1500, 1500, &[Some(4095), Some(2048)], &UNCALIBRATED);
+		let history = analog.history(0);
+		assert_eq!(
+			(history.min_mv(), history.max_mv(), history.average_mv()),
+			(3300, 3300, 3300)
+		);
+		// A dip only moves the average a little way
+		analog.record(10, 1500, 1500, &[Some(2048), Some(2048)], &UNCALIBRATED);
+		let history = analog.history(0);
+		assert_eq!(
+			(history.min_mv(), history.max_mv(), history.average_mv()),
+			(1650, 3300, 3196)
+		);
+		// But it settles on a steady reading
+		for now in 0..200 {
+			analog.record(now, 1500, 1500, &[Some(4095), Some(2048)], &UNCALIBRATED);
+		}
+		assert_eq!(analog.history(0).average_mv(), 3300);
+		assert_eq!(analog.history(1).average_mv(), 1650);
+		assert_eq!(
+			analog.history_bytes(),
+			[0x72, 0x06, 0xE4, 0x0C, 0xE4, 0x0C, 0x72, 0x06, 0x72, 0x06, 0x72, 0x06]
+		);
+		analog.clear_history();
+		assert_eq!(analog.history_bytes(), [0; AnalogInputs::HISTORY_SIZE]);
+		// The latest reading stays
+		assert_eq!(analog.millivolts(0), 3300);
+	}
+
+	#[test]
+	fn calibration() {
+		let halved = Calibration {
+			gain: CALIBRATION_UNITY / 2,
+			offset_mv: -100,
+		};
+		assert_eq!(Calibration::NONE.apply(1234), 1234);
+		assert_eq!(halved.apply(3000), 1400);
+		// No negative voltages
+		assert_eq!(halved.apply(100), 0);
+		assert_eq!(Calibration::from_bytes(&halved.as_bytes()), halved);
+		assert_eq!(halved.as_bytes(), [0x00, 0x08, 0x9C, 0xFF]);
+		// A divider which gives 1.65V for 3.3V
+		assert_eq!(
+			Calibration::for_reading(1650, 3300),
+			Some(Calibration {
+				gain: 8192,
+				offset_mv: 0
+			})
+		);
+		assert_eq!(Calibration::for_reading(0, 5000), None);
+		assert_eq!(Calibration::for_reading(100, 5000), None);
+		// A factory calibration needs a sample
+		let mut analog = AnalogInputs::new();
+		assert_eq!(analog.factory_calibration(), None);
+		// 1.65V and 1.5V at the pins
+		analog.record(0, 1500, 1500, &[Some(2048), Some(1862)], &UNCALIBRATED);
+		let calibration = analog.factory_calibration().unwrap();
+		analog.record(10, 1500, 1500, &[Some(2048), Some(1862)], &calibration);
+		assert_eq!(analog.millivolts(0), 3300);
+		assert_eq!(analog.millivolts(1), 5000);
+	}
+}
@@ -0,0 +1,219 @@
+//! # Board Support
+//!
+//! Which pin does what on the Neotron Pico's BMC. [`Board::new`] puts every
+//! pin in the mode its job needs, and hands them back as named fields, so
+//! moving one pin can't shift the others.
+//!
+//! The I²C pins (PB6 and PB7) belong to whichever of the `fan`, `encoder`,
+//! `i2c-target`, `ps2-power` or `status-led` features is turned on, or are
+//! spare GPIO for the host without any of them. The rail monitor pins (PA0
+//! and PA1) are analog inputs unless the ADC is busy with something else, and
+//! the keyboard clock (PA15) is USART2 RX in `uart2` builds. In `usb` builds,
+//! the UART's flow control pins (PA11 and PA12) are the USB data lines.
+
+#[cfg(feature = "uart2")]
+use stm32f0xx_hal::gpio::gpioa::PA14;
+#[cfg(any(
+	feature = "usb",
+	not(any(
+		feature = "fan",
+		feature = "encoder",
+		feature = "i2c-target",
+		feature = "ps2-power",
+		feature = "status-led"
+	))
+))]
+use stm32f0xx_hal::gpio::Floating;
+#[cfg(feature = "status-led")]
+use stm32f0xx_hal::gpio::PullDown;
+#[cfg(any(feature = "fan", feature = "status-led"))]
+use stm32f0xx_hal::gpio::AF2;
+#[cfg(all(feature = "adc", not(any(feature = "current-sense", feature = "fan"))))]
+use stm32f0xx_hal::gpio::{
+	gpioa::{PA0, PA1},
+	Analog,
+};
+use stm32f0xx_hal::{
+	gpio::gpioa::{PA10, PA11, PA12, PA15, PA2, PA3, PA4, PA5, PA6, PA7, PA8, PA9},
+	gpio::gpiob::{PB0, PB1, PB3, PB4, PB5, PB6, PB7},
+	gpio::gpiof::{PF0, PF1},
+	gpio::{Alternate, Input, OpenDrain, Output, PullUp, PushPull, AF0, AF1},
+	pac,
+	prelude::*,
+	rcc::Rcc,
+};
+
+/// Every pin the BMC uses, set up for its job.
+pub struct Board {
+	/// UART transmit (USART1_TX)
+	pub uart_tx: PA9<Alternate<AF1>>,
+	/// UART receive (USART1_RX)
+	pub uart_rx: PA10<Alternate<AF1>>,
+	/// The Clear-To-Send line on the FTDI UART header (USART1_CTS)
+	#[cfg(not(feature = "usb"))]
+	pub uart_cts: PA11<Alternate<AF1>>,
+	/// The Ready-To-Receive line on the FTDI UART header (USART1_RTS)
+	#[cfg(not(feature = "usb"))]
+	pub uart_rts: PA12<Alternate<AF1>>,
+	/// The power LED
+	pub led_power: PB0<Output<PushPull>>,
+	/// The buzzer, or the current sense amplifier with `current-sense`
+	pub buzzer: PB1<Output<PushPull>>,
+	/// The power button (active low)
+	pub button_power: PF0<Input<PullUp>>,
+	/// The reset button (active low)
+	pub button_reset: PF1<Input<PullUp>>,
+	/// Turns on the main board's DC power
+	pub dc_on: PA3<Output<PushPull>>,
+	/// Holds the main board in reset (active low)
+	pub sys_reset: PA2<Output<PushPull>>,
+	/// The interrupt line to the host
+	pub irq: PA8<Output<PushPull>>,
+	/// The keyboard clock (open drain, so we can hold the keyboard off, and
+	/// send it commands)
+	#[cfg(not(feature = "uart2"))]
+	pub ps2_clk0: PA15<Output<OpenDrain>>,
+	/// The mouse clock (open drain, for the same reason)
+	pub ps2_clk1: PB3<Output<OpenDrain>>,
+	/// The keyboard data line
+	pub ps2_dat0: PB4<Output<OpenDrain>>,
+	/// The mouse data line
+	pub ps2_dat1: PB5<Output<OpenDrain>>,
+	/// SPI chip select, from the host
+	pub spi_cs: PA4<Input<PullUp>>,
+	/// SPI clock (SPI1_SCK)
+	pub spi_sck: PA5<Alternate<AF0>>,
+	/// SPI data to the host (SPI1_MISO)
+	pub spi_cipo: PA6<Alternate<AF0>>,
+	/// SPI data from the host (SPI1_MOSI)
+	pub spi_copi: PA7<Alternate<AF0>>,
+	/// The fan's PWM output (TIM16_CH1N) and tachometer input (pulled up, as
+	/// the fan's output is open-collector)
+	#[cfg(feature = "fan")]
+	pub fan: (PB6<Alternate<AF2>>, PB7<Input<PullUp>>),
+	/// The rotary encoder's phase A and phase B. Its contacts pull the pins
+	/// to ground.
+	#[cfg(feature = "encoder")]
+	pub encoder: (PB6<Input<PullUp>>, PB7<Input<PullUp>>),
+	/// The keyboard and mouse power switches. Each drives a P-FET gate,
+	/// pulled up to 5V, so they are open drain.
+	#[cfg(feature = "ps2-power")]
+	pub ps2_power: (PB6<Output<OpenDrain>>, PB7<Output<OpenDrain>>),
+	/// The RGB status LED's data line (TIM16_CH1N), and PB7, which is pulled
+	/// down out of the way
+	#[cfg(feature = "status-led")]
+	pub status_led: (PB6<Alternate<AF2>>, PB7<Input<PullDown>>),
+	/// I²C clock (I2C1_SCL) and data (I2C1_SDA), for the host to reach our
+	/// registers
+	#[cfg(feature = "i2c-target")]
+	pub i2c: (PB6<Alternate<AF1>>, PB7<Alternate<AF1>>),
+	/// The spare I²C pins, for the host to use as GPIO
+	#[cfg(not(any(
+		feature = "fan",
+		feature = "encoder",
+		feature = "i2c-target",
+		feature = "ps2-power",
+		feature = "status-led"
+	)))]
+	pub gpio: (PB6<Input<Floating>>, PB7<Input<Floating>>),
+	/// The rail monitor pins, as analog inputs
+	#[cfg(all(feature = "adc", not(any(feature = "current-sense", feature = "fan"))))]
+	pub analog: (PA0<Analog>, PA1<Analog>),
+	/// USART2 transmit and receive, which take over SWCLK and the keyboard
+	/// clock
+	#[cfg(feature = "uart2")]
+	pub uart2: (PA14<Alternate<AF1>>, PA15<Alternate<AF1>>),
+	/// The USB D- and D+ lines, which the USB peripheral takes over without
+	/// an alternate function
+	#[cfg(feature = "usb")]
+	pub usb: (PA11<Input<Floating>>, PA12<Input<Floating>>),
+}
+
+impl Board {
+	/// Set up every pin on GPIO ports A, B and F.
+	///
+	/// The outputs start low, so the main board is off and held in reset,
+	/// and the PS/2 lines are held low until we let go of them.
+	pub fn new(gpioa: pac::GPIOA, gpiob: pac::GPIOB, gpiof: pac::GPIOF, rcc: &mut Rcc) -> Board {
+		let gpioa = gpioa.split(rcc);
+		let gpiob = gpiob.split(rcc);
+		let gpiof = gpiof.split(rcc);
+		cortex_m::interrupt::free(|cs| Board {
+			uart_tx: gpioa.pa9.into_alternate_af1(cs),
+			uart_rx: gpioa.pa10.into_alternate_af1(cs),
+			#[cfg(not(feature = "usb"))]
+			uart_cts: gpioa.pa11.into_alternate_af1(cs),
+			#[cfg(not(feature = "usb"))]
+			uart_rts: gpioa.pa12.into_alternate_af1(cs),
+			led_power: gpiob.pb0.into_push_pull_output(cs),
+			buzzer: gpiob.pb1.into_push_pull_output(cs),
+			button_power: gpiof.pf0.into_pull_up_input(cs),
+			button_reset: gpiof.pf1.into_pull_up_input(cs),
+			dc_on: gpioa.pa3.into_push_pull_output(cs),
+			sys_reset: gpioa.pa2.into_push_pull_output(cs),
+			irq: gpioa.pa8.into_push_pull_output(cs),
+			#[cfg(not(feature = "uart2"))]
+			ps2_clk0: gpioa.pa15.into_open_drain_output(cs),
+			ps2_clk1: gpiob.pb3.into_open_drain_output(cs),
+			ps2_dat0: gpiob.pb4.into_open_drain_output(cs),
+			ps2_dat1: gpiob.pb5.into_open_drain_output(cs),
+			spi_cs: gpioa.pa4.into_pull_up_input(cs),
+			spi_sck: gpioa.pa5.into_alternate_af0(cs),
+			spi_cipo: {
+				// Force 'high speed' mode first, then go into AF0 (high
+				// speed mode is sticky in this HAL revision).
+				let pin = gpioa.pa6.into_push_pull_output_hs(cs);
+				pin.into_alternate_af0(cs)
+			},
+			spi_copi: gpioa.pa7.into_alternate_af0(cs),
+			#[cfg(feature = "fan")]
+			fan: (
+				gpiob.pb6.into_alternate_af2(cs),
+				gpiob.pb7.into_pull_up_input(cs),
+			),
+			#[cfg(feature = "encoder")]
+			encoder: (
+				gpiob.pb6.into_pull_up_input(cs),
+				gpiob.pb7.into_pull_up_input(cs),
+			),
+			#[cfg(feature = "ps2-power")]
+			ps2_power: (
+				gpiob.pb6.into_open_drain_output(cs),
+				gpiob.pb7.into_open_drain_output(cs),
+			),
+			#[cfg(feature = "status-led")]
+			status_led: (
+				gpiob.pb6.into_alternate_af2(cs),
+				gpiob.pb7.into_pull_down_input(cs),
+			),
+			#[cfg(feature = "i2c-target")]
+			i2c: (
+				gpiob.pb6.into_alternate_af1(cs).set_open_drain(cs),
+				gpiob.pb7.into_alternate_af1(cs).set_open_drain(cs),
+			),
+			#[cfg(not(any(
+				feature = "fan",
+				feature = "encoder",
+				feature = "i2c-target",
+				feature = "ps2-power",
+				feature = "status-led"
+			)))]
+			gpio: (
+				gpiob.pb6.into_floating_input(cs),
+				gpiob.pb7.into_floating_input(cs),
+			),
+			#[cfg(all(feature = "adc", not(any(feature = "current-sense", feature = "fan"))))]
+			analog: (gpioa.pa0.into_analog(cs), gpioa.pa1.into_analog(cs)),
+			#[cfg(feature = "uart2")]
+			uart2: (
+				gpioa.pa14.into_alternate_af1(cs),
+				gpioa.pa15.into_alternate_af1(cs),
+			),
+			#[cfg(feature = "usb")]
+			usb: (
+				gpioa.pa11.into_floating_input(cs),
+				gpioa.pa12.into_floating_input(cs),
+			),
+		})
+	}
+}
@@ -0,0 +1,223 @@
+//! # Bulk Reads and Writes
+//!
+//! A single read returns at most `MAX_READ_LEN` bytes, so emptying a big FIFO
+//! (like the 256 byte UART receive FIFO) takes a lot of reads. A *Bulk Read
+//! Start* takes up to 255 bytes out of a FIFO in one go and holds them here.
+//! The host then collects them with *Bulk Read Continue* requests, each
+//! giving an offset. A request which is repeated (because its response was
+//! corrupted) gets the same bytes again, so nothing is lost.
+//!
+//! Going the other way, a *Bulk Write Start* says how many bytes are coming
+//! for a FIFO, and the host sends them with *Bulk Write Continue* requests,
+//! each giving an offset and carrying a payload. They are received here, and
+//! once they have all arrived they are written to the FIFO. We remember how
+//! many have been written, so a piece which is sent again (because its
+//! response was corrupted, or because the FIFO was full) never writes a byte
+//! twice.
+//!
+//! There is only one buffer - a new *Bulk Read Start* or *Bulk Write Start*
+//! throws away anything the host hadn't collected (or we hadn't written)
+//! from the last one.
+
+/// Holds the bytes taken by the last *Bulk Read Start*, or received since
+/// the last *Bulk Write Start*.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BulkBuffer<const N: usize> {
+	/// The bytes
+	buffer: [u8; N],
+	/// How many bytes of `buffer` are used
+	len: usize,
+	/// The bulk write the bytes are for, if they are for one
+	write: Option<BulkWrite>,
+}
+
+/// Where the bytes of a bulk write go, and how far it has got.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct BulkWrite {
+	/// The register they are written to
+	register: u8,
+	/// How many bytes the host is sending
+	length: usize,
+	/// How many of them have been written to the register
+	written: usize,
+}
+
+impl<const N: usize> BulkBuffer<N> {
+	/// Create a new, empty, buffer.
+	pub const fn new() -> BulkBuffer<N> {
+		BulkBuffer {
+			buffer: [0; N],
+			len: 0,
+			write: None,
+		}
+	}
+
+	/// How many bytes are held?
+	pub fn len(&self) -> usize {
+		self.len
+	}
+
+	/// Is the buffer empty?
+	pub fn is_empty(&self) -> bool {
+		self.len == 0
+	}
+
+	/// Throw away everything in the buffer, and any bulk write.
+	pub fn clear(&mut self) {
+		self.len = 0;
+		self.write = None;
+	}
+
+	/// Add some bytes to the end of the buffer.
+	///
+	/// Returns `false`, and adds nothing, if they don't all fit.
+	pub fn extend(&mut self, data: &[u8]) -> bool {
+		match self.buffer.get_mut(self.len..self.len + data.len()) {
+			Some(space) => {
+				space.copy_from_slice(data);
+				self.len += data.len();
+				true
+			}
+			None => false,
+		}
+	}
+
+	/// Get `length` bytes, starting `offset` bytes in.
+	///
+	/// Returns `None` if that runs past the end of what is held, or if the
+	/// bytes held are for a bulk write.
+	pub fn get(&self, offset: usize, length: usize) -> Option<&[u8]> {
+		if self.write.is_some() {
+			return None;
+		}
+		self.buffer[0..self.len].get(offset..offset.checked_add(length)?)
+	}
+
+	/// Throw away everything in the buffer, and get ready to receive
+	/// `length` bytes for `register`.
+	///
+	/// Returns `false`, and holds nothing, if they wouldn't fit.
+	pub fn start_write(&mut self, register: u8, length: usize) -> bool {
+		self.clear();
+		if length > N {
+			return false;
+		}
+		self.write = Some(BulkWrite {
+			register,
+			length,
+			written: 0,
+		});
+		true
+	}
+
+	/// Could `length` bytes be received at `offset`?
+	///
+	/// They must be for the bulk write in progress, they mustn't leave a gap
+	/// after the bytes received so far, and they mustn't run past the end.
+	/// Bytes which have been received already can be received again.
+	pub fn can_receive(&self, offset: usize, length: usize) -> bool {
+		match (self.write, offset.checked_add(length)) {
+			(Some(write), Some(end)) => offset <= self.len && end <= write.length,
+			_ => false,
+		}
+	}
+
+	/// Receive some bytes for the bulk write in progress, at `offset`.
+	///
+	/// Returns `false`, and receives nothing, if [`BulkBuffer::can_receive`]
+	/// says no.
+	pub fn receive(&mut self, offset: usize, data: &[u8]) -> bool {
+		if !self.can_receive(offset, data.len()) {
+			return false;
+		}
+		let end = offset + data.len();
+		self.buffer[offset..end].copy_from_slice(data);
+		self.len = self.len.max(end);
+		true
+	}
+
+	/// Once every byte of the bulk write in progress has been received, get
+	/// the register they go to, and the bytes which haven't been written to
+	/// it yet.
+	pub fn unwritten(&self) -> Option<(u8, &[u8])> {
+		match self.write {
+			Some(write) if self.len == write.length => {
+				Some((write.register, &self.buffer[write.written..self.len]))
+			}
+			_ => None,
+		}
+	}
+
+	/// Note that `count` more bytes of the bulk write have been written.
+	pub fn mark_written(&mut self, count: usize) {
+		if let Some(write) = self.write.as_mut() {
+			write.written = (write.written + count).min(self.len);
+		}
+	}
+}
+
+impl<const N: usize> Default for BulkBuffer<N> {
+	fn default() -> Self {
+		BulkBuffer::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn fill_and_collect() {
+		let mut bulk = BulkBuffer::<8>::new();
+		assert!(bulk.is_empty());
+		assert_eq!(bulk.get(0, 0), Some(&[][..]));
+		assert_eq!(bulk.get(0, 1), None);
+		assert!(bulk.extend(&[1, 2, 3]));
+		assert!(bulk.extend(&[4, 5]));
+		assert_eq!(bulk.len(), 5);
+		assert_eq!(bulk.get(1, 3), Some(&[2, 3, 4][..]));
+		// Asking again gets the same bytes
+		assert_eq!(bulk.get(1, 3), Some(&[2, 3, 4][..]));
+		assert_eq!(bulk.get(3, 3), None);
+		assert_eq!(bulk.get(usize::MAX, 2), None);
+		// Too much to fit
+		assert!(!bulk.extend(&[6, 7, 8, 9]));
+		assert_eq!(bulk.len(), 5);
+		bulk.clear();
+		assert_eq!(bulk.get(0, 1), None);
+	}
+
+	#[test]
+	fn receive_and_write() {
+		let mut bulk = BulkBuffer::<8>::new();
+		// Not without a start
+		assert!(!bulk.receive(0, &[1]));
+		assert!(!bulk.start_write(0x30, 9));
+		assert!(bulk.start_write(0x30, 6));
+		assert_eq!(bulk.unwritten(), None);
+		// No gaps, and nothing past the end
+		assert!(!bulk.receive(1, &[2]));
+		assert!(!bulk.can_receive(4, 3));
+		assert!(!bulk.can_receive(usize::MAX, 2));
+		assert!(bulk.receive(0, &[1, 2, 3]));
+		// A piece sent again is fine
+		assert!(bulk.receive(0, &[1, 2, 3]));
+		assert_eq!(bulk.unwritten(), None);
+		// The bytes aren't there to be read back
+		assert_eq!(bulk.get(0, 3), None);
+		assert!(bulk.receive(3, &[4, 5, 6]));
+		assert_eq!(bulk.unwritten(), Some((0x30, &[1, 2, 3, 4, 5, 6][..])));
+		// The register filled up part way
+		bulk.mark_written(4);
+		assert_eq!(bulk.unwritten(), Some((0x30, &[5, 6][..])));
+		// The last piece again carries on where we stopped
+		assert!(bulk.receive(3, &[4, 5, 6]));
+		assert_eq!(bulk.unwritten(), Some((0x30, &[5, 6][..])));
+		bulk.mark_written(2);
+		assert_eq!(bulk.unwritten(), Some((0x30, &[][..])));
+		// A bulk read starts afresh
+		bulk.clear();
+		assert_eq!(bulk.unwritten(), None);
+		assert!(!bulk.receive(0, &[1]));
+	}
+}
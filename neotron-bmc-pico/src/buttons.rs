@@ -0,0 +1,433 @@
+//! # Buttons
+//!
+//! Debounces the front panel buttons, and works out what each press means.
+//!
+//! Each button in the table has a short action, which happens as soon as a
+//! press has been debounced (150 ms), and a long action, which happens if the
+//! button is still held 1.2 seconds later. By default:
+//!
+//! * A short press of the power button whilst off powers the system on (or
+//!   resumes it, whilst suspended).
+//! * A long press of the power button whilst on powers the system off.
+//! * A short press of the reset button whilst on resets the system.
+//!
+//! The host can change any of the actions, and can map spare GPIO pins as
+//! extra buttons. An action can be handled by the BMC, or forwarded to the
+//! host as a button event.
+//!
+//! This module only makes the decisions - reading and driving the pins is
+//! left to the caller.
+
+use debouncr::{Debouncer, Edge, Repeat16, Repeat2};
+
+use crate::power::{DcPowerState, PowerAction};
+
+/// How many buttons are in the table
+pub const BUTTONS: usize = 4;
+
+/// The power button
+pub const BUTTON_POWER: usize = 0;
+/// The reset button
+pub const BUTTON_RESET: usize = 1;
+/// A button wired to GPIO 0
+pub const BUTTON_GPIO0: usize = 2;
+/// A button wired to GPIO 1
+pub const BUTTON_GPIO1: usize = 3;
+
+/// Something a button press can do.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum ButtonAction {
+	/// Nothing
+	None = 0,
+	/// Power the system on if it's off, or resume it if it's suspended
+	PowerOn = 1,
+	/// Power the system off if it's on
+	PowerOff = 2,
+	/// Reset the system if it's on
+	Reset = 3,
+	/// Tell the host, if the system is running
+	Host = 4,
+}
+
+impl ButtonAction {
+	/// Convert from the number the host uses.
+	pub fn from_u8(value: u8) -> Option<ButtonAction> {
+		match value {
+			0 => Some(ButtonAction::None),
+			1 => Some(ButtonAction::PowerOn),
+			2 => Some(ButtonAction::PowerOff),
+			3 => Some(ButtonAction::Reset),
+			4 => Some(ButtonAction::Host),
+			_ => None,
+		}
+	}
+}
+
+/// What one button does.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub struct ButtonConfig {
+	/// What a short press does
+	pub short: ButtonAction,
+	/// What a long press does
+	pub long: ButtonAction,
+}
+
+impl ButtonConfig {
+	/// A button which does nothing
+	pub const NONE: ButtonConfig = ButtonConfig {
+		short: ButtonAction::None,
+		long: ButtonAction::None,
+	};
+
+	/// Convert to the byte in the Button Action registers.
+	///
+	/// The short action is in the bottom four bits, the long action above.
+	pub fn as_byte(&self) -> u8 {
+		(self.short as u8) | ((self.long as u8) << 4)
+	}
+
+	/// Convert from the byte in the Button Action registers.
+	///
+	/// Returns `None` if either action is unknown.
+	pub fn from_byte(byte: u8) -> Option<ButtonConfig> {
+		Some(ButtonConfig {
+			short: ButtonAction::from_u8(byte & 0x0F)?,
+			long: ButtonAction::from_u8(byte >> 4)?,
+		})
+	}
+}
+
+/// What the buttons do until the host says otherwise.
+pub const DEFAULT_BUTTONS: [ButtonConfig; BUTTONS] = [
+	// Power
+	ButtonConfig {
+		short: ButtonAction::PowerOn,
+		long: ButtonAction::PowerOff,
+	},
+	// Reset
+	ButtonConfig {
+		short: ButtonAction::Reset,
+		long: ButtonAction::None,
+	},
+	// GPIO 0
+	ButtonConfig::NONE,
+	// GPIO 1
+	ButtonConfig::NONE,
+];
+
+/// Everything the buttons asked us to do, in one poll.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub struct ButtonActions {
+	/// What to do with the power, if anything
+	pub power: Option<PowerAction>,
+	/// Should we pulse the reset line?
+	pub reset: bool,
+	/// Presses to pass on to the host - bit N is a short press of button N,
+	/// and bit N + 4 a long press.
+	pub host_events: u8,
+}
+
+/// The debouncers for one button.
+struct Debouncers {
+	/// 75ms x 2 = 150ms is a short press
+	short: Debouncer<u8, Repeat2>,
+	/// 75ms x 16 = 1200ms is a long press
+	long: Debouncer<u16, Repeat16>,
+}
+
+impl Debouncers {
+	/// Create debouncers for a released button.
+	fn new() -> Debouncers {
+		Debouncers {
+			short: debouncr::debounce_2(false),
+			long: debouncr::debounce_16(false),
+		}
+	}
+}
+
+/// Debounces every button in the table.
+pub struct Buttons {
+	/// One set of debouncers per button
+	debouncers: [Debouncers; BUTTONS],
+}
+
+impl Buttons {
+	/// Create a new button tracker, with every button released.
+	pub fn new() -> Buttons {
+		Buttons {
+			debouncers: [
+				Debouncers::new(),
+				Debouncers::new(),
+				Debouncers::new(),
+				Debouncers::new(),
+			],
+		}
+	}
+
+	/// Which buttons are pressed (after debouncing), as a bitmask.
+	pub fn pressed(&self) -> u8 {
+		self.debouncers
+			.iter()
+			.enumerate()
+			.filter(|(_, d)| d.short.is_high())
+			.fold(0, |acc, (idx, _)| acc | (1 << idx))
+	}
+
+	/// Feed in the button states (`true` means pressed).
+	///
+	/// Call this at a fixed interval. Returns what the buttons want us to do,
+	/// given what each button is for and that we are currently in `state`.
+	pub fn update(
+		&mut self,
+		pressed: [bool; BUTTONS],
+		config: &[ButtonConfig; BUTTONS],
+		state: DcPowerState,
+	) -> ButtonActions {
+		let mut actions = ButtonActions {
+			power: None,
+			reset: false,
+			host_events: 0,
+		};
+		let mut state = state;
+		for (idx, ((debouncers, &pressed), config)) in self
+			.debouncers
+			.iter_mut()
+			.zip(pressed.iter())
+			.zip(config.iter())
+			.enumerate()
+		{
+			let short_edge = debouncers.short.update(pressed);
+			let long_edge = debouncers.long.update(pressed);
+			if short_edge == Some(Edge::Rising) {
+				Self::act(config.short, 1 << idx, state, &mut actions);
+			}
+			if long_edge == Some(Edge::Rising) {
+				Self::act(config.long, 1 << (idx + 4), state, &mut actions);
+			}
+			if short_edge == Some(Edge::Falling)
+				&& config.short == ButtonAction::PowerOn
+				&& state == DcPowerState::Starting
+				&& actions.power.is_none()
+			{
+				actions.power = Some(PowerAction::Released);
+			}
+			state = actions.power.map(PowerAction::next_state).unwrap_or(state);
+		}
+
+		// Don't do a reset if the board is powered off.
+		actions.reset &= state == DcPowerState::On;
+
+		actions
+	}
+
+	/// Work out what one press does, given we are currently in `state`.
+	fn act(action: ButtonAction, event: u8, state: DcPowerState, actions: &mut ButtonActions) {
+		match (action, state) {
+			(ButtonAction::PowerOn, DcPowerState::Off) if actions.power.is_none() => {
+				actions.power = Some(PowerAction::PowerOn);
+			}
+			(ButtonAction::PowerOn, DcPowerState::Suspended) if actions.power.is_none() => {
+				actions.power = Some(PowerAction::Resume);
+			}
+			(ButtonAction::PowerOff, DcPowerState::On) if actions.power.is_none() => {
+				actions.power = Some(PowerAction::PowerOff);
+			}
+			(ButtonAction::Reset, _) => {
+				actions.reset = true;
+			}
+			(ButtonAction::Host, DcPowerState::Starting | DcPowerState::On) => {
+				actions.host_events |= event;
+			}
+			_ => {
+				// Nothing to do in this state
+			}
+		}
+	}
+}
+
+impl Default for Buttons {
+	fn default() -> Self {
+		Buttons::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Poll the buttons `count` times, with the default actions, and return
+	/// the last result.
+	fn poll(
+		buttons: &mut Buttons,
+		count: usize,
+		power_pressed: bool,
+		reset_pressed: bool,
+		state: DcPowerState,
+	) -> ButtonActions {
+		poll_all(
+			buttons,
+			count,
+			[power_pressed, reset_pressed, false, false],
+			&DEFAULT_BUTTONS,
+			state,
+		)
+	}
+
+	/// Poll the buttons `count` times, and return the last result.
+	fn poll_all(
+		buttons: &mut Buttons,
+		count: usize,
+		pressed: [bool; BUTTONS],
+		config: &[ButtonConfig; BUTTONS],
+		state: DcPowerState,
+	) -> ButtonActions {
+		let mut actions = ButtonActions {
+			power: None,
+			reset: false,
+			host_events: 0,
+		};
+		for _ in 0..count {
+			actions = buttons.update(pressed, config, state);
+		}
+		actions
+	}
+
+	#[test]
+	fn short_press_powers_on() {
+		let mut buttons = Buttons::new();
+		let actions = poll(&mut buttons, 1, true, false, DcPowerState::Off);
+		assert_eq!(actions.power, None);
+		let actions = poll(&mut buttons, 1, true, false, DcPowerState::Off);
+		assert_eq!(actions.power, Some(PowerAction::PowerOn));
+		assert_eq!(buttons.pressed(), 1 << BUTTON_POWER);
+		// Holding it down doesn't do anything else
+		let actions = poll(&mut buttons, 20, true, false, DcPowerState::Starting);
+		assert_eq!(actions.power, None);
+		// Releasing it finishes the power on
+		let _ = poll(&mut buttons, 1, false, false, DcPowerState::Starting);
+		let actions = poll(&mut buttons, 1, false, false, DcPowerState::Starting);
+		assert_eq!(actions.power, Some(PowerAction::Released));
+		assert_eq!(buttons.pressed(), 0);
+	}
+
+	#[test]
+	fn long_press_powers_off() {
+		let mut buttons = Buttons::new();
+		let actions = poll(&mut buttons, 15, true, false, DcPowerState::On);
+		assert_eq!(actions.power, None);
+		let actions = poll(&mut buttons, 1, true, false, DcPowerState::On);
+		assert_eq!(actions.power, Some(PowerAction::PowerOff));
+	}
+
+	#[test]
+	fn short_press_whilst_on_does_nothing() {
+		let mut buttons = Buttons::new();
+		let actions = poll(&mut buttons, 4, true, false, DcPowerState::On);
+		assert_eq!(actions.power, None);
+		let actions = poll(&mut buttons, 4, false, false, DcPowerState::On);
+		assert_eq!(actions.power, None);
+	}
+
+	#[test]
+	fn short_press_resumes() {
+		let mut buttons = Buttons::new();
+		let actions = poll(&mut buttons, 2, true, false, DcPowerState::Suspended);
+		assert_eq!(actions.power, Some(PowerAction::Resume));
+		assert_eq!(PowerAction::Resume.next_state(), DcPowerState::Starting);
+		// Holding it down doesn't power off straight after resuming
+		let actions = poll(&mut buttons, 20, true, false, DcPowerState::Starting);
+		assert_eq!(actions.power, None);
+		// Nor does the reset button do anything whilst suspended
+		let mut buttons = Buttons::new();
+		let actions = poll(&mut buttons, 2, false, true, DcPowerState::Suspended);
+		assert!(!actions.reset);
+	}
+
+	#[test]
+	fn reset_only_when_on() {
+		let mut buttons = Buttons::new();
+		let actions = poll(&mut buttons, 2, false, true, DcPowerState::Off);
+		assert!(!actions.reset);
+		let actions = poll(&mut buttons, 2, false, false, DcPowerState::Off);
+		assert!(!actions.reset);
+		let actions = poll(&mut buttons, 2, false, true, DcPowerState::Starting);
+		assert!(!actions.reset);
+		let actions = poll(&mut buttons, 2, false, false, DcPowerState::On);
+		assert!(!actions.reset);
+		let actions = poll(&mut buttons, 2, false, true, DcPowerState::On);
+		assert!(actions.reset);
+	}
+
+	#[test]
+	fn reset_as_power_button_released() {
+		let mut buttons = Buttons::new();
+		let _ = poll(&mut buttons, 2, true, false, DcPowerState::Off);
+		let _ = poll(&mut buttons, 1, false, true, DcPowerState::Starting);
+		let actions = poll(&mut buttons, 1, false, true, DcPowerState::Starting);
+		assert_eq!(actions.power, Some(PowerAction::Released));
+		assert!(actions.reset);
+	}
+
+	#[test]
+	fn remapped_buttons() {
+		let mut config = DEFAULT_BUTTONS;
+		// The power button goes to the host, and GPIO 0 takes over
+		config[BUTTON_POWER] = ButtonConfig {
+			short: ButtonAction::Host,
+			long: ButtonAction::Host,
+		};
+		config[BUTTON_GPIO0] = DEFAULT_BUTTONS[BUTTON_POWER];
+		let mut buttons = Buttons::new();
+		let actions = poll_all(
+			&mut buttons,
+			2,
+			[true, false, false, false],
+			&config,
+			DcPowerState::On,
+		);
+		assert_eq!(actions.host_events, 1 << BUTTON_POWER);
+		assert_eq!(actions.power, None);
+		let actions = poll_all(
+			&mut buttons,
+			14,
+			[true, false, false, false],
+			&config,
+			DcPowerState::On,
+		);
+		assert_eq!(actions.host_events, 1 << (BUTTON_POWER + 4));
+		assert_eq!(actions.power, None);
+		let actions = poll_all(
+			&mut buttons,
+			16,
+			[false, false, true, false],
+			&config,
+			DcPowerState::On,
+		);
+		assert_eq!(actions.power, Some(PowerAction::PowerOff));
+		// Nothing goes to the host whilst it's off
+		let mut buttons = Buttons::new();
+		let actions = poll_all(
+			&mut buttons,
+			2,
+			[true, false, false, false],
+			&config,
+			DcPowerState::Off,
+		);
+		assert_eq!(actions.host_events, 0);
+	}
+
+	#[test]
+	fn config_bytes() {
+		assert_eq!(DEFAULT_BUTTONS[BUTTON_POWER].as_byte(), 0x21);
+		assert_eq!(
+			ButtonConfig::from_byte(0x34),
+			Some(ButtonConfig {
+				short: ButtonAction::Host,
+				long: ButtonAction::Reset,
+			})
+		);
+		assert_eq!(ButtonConfig::from_byte(0x05), None);
+		assert_eq!(ButtonConfig::from_byte(0x50), None);
+	}
+}
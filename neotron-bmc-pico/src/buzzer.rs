@@ -0,0 +1,164 @@
+//! # Buzzer Melody Sequencer
+//!
+//! The host queues up notes (a frequency, a duration and a gap before the
+//! next note) and a task plays them back in the background, so the OS can
+//! play a start-up jingle without having to time each note itself.
+//!
+//! The buzzer is on PB1, which is TIM3_CH4, so we generate each tone with a
+//! 50% duty-cycle PWM output.
+
+use stm32f0xx_hal::pac;
+
+/// TIM3 counts at this rate, whatever the system clock is.
+const TIMER_TICK_HZ: u32 = 1_000_000;
+
+/// The lowest tone we can play, given `TIMER_TICK_HZ` and a 16-bit counter.
+const MIN_FREQUENCY_HZ: u16 = 16;
+
+/// A single note in the [`NoteQueue`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub struct Note {
+	/// The pitch, in Hz. Zero is a rest.
+	pub frequency_hz: u16,
+	/// How long to sound the note, in units of 10 ms
+	pub duration: u8,
+	/// How long to stay silent after the note, in units of 10 ms
+	pub gap: u8,
+}
+
+impl Note {
+	/// A rest of no length, used to fill empty queue slots.
+	pub const EMPTY: Note = Note {
+		frequency_hz: 0,
+		duration: 0,
+		gap: 0,
+	};
+
+	/// How long to sound the note, in milliseconds.
+	pub fn duration_ms(&self) -> u32 {
+		u32::from(self.duration) * 10
+	}
+
+	/// How long to stay silent after the note, in milliseconds.
+	pub fn gap_ms(&self) -> u32 {
+		u32::from(self.gap) * 10
+	}
+}
+
+/// A fixed-size queue of up to `N` notes waiting to be played.
+#[derive(Debug)]
+pub struct NoteQueue<const N: usize> {
+	notes: [Note; N],
+	/// Index of the next note to play
+	head: usize,
+	/// How many notes are queued
+	len: usize,
+}
+
+impl<const N: usize> NoteQueue<N> {
+	/// Create a new, empty, note queue.
+	pub const fn new() -> NoteQueue<N> {
+		NoteQueue {
+			notes: [Note::EMPTY; N],
+			head: 0,
+			len: 0,
+		}
+	}
+
+	/// Add a note to the end of the queue.
+	///
+	/// Gives the note back if the queue is full.
+	pub fn push(&mut self, note: Note) -> Result<(), Note> {
+		if self.len == N {
+			return Err(note);
+		}
+		let idx = (self.head + self.len) % N;
+		self.notes[idx] = note;
+		self.len += 1;
+		Ok(())
+	}
+
+	/// Take the next note to play off the front of the queue.
+	pub fn pop(&mut self) -> Option<Note> {
+		if self.len == 0 {
+			return None;
+		}
+		let note = self.notes[self.head];
+		self.head = (self.head + 1) % N;
+		self.len -= 1;
+		Some(note)
+	}
+
+	/// How many notes are waiting to be played?
+	pub fn len(&self) -> usize {
+		self.len
+	}
+
+	/// Is the queue empty?
+	pub fn is_empty(&self) -> bool {
+		self.len == 0
+	}
+
+	/// Throw away all the queued notes.
+	pub fn clear(&mut self) {
+		self.head = 0;
+		self.len = 0;
+	}
+}
+
+impl<const N: usize> Default for NoteQueue<N> {
+	fn default() -> Self {
+		NoteQueue::new()
+	}
+}
+
+/// Drives the buzzer with TIM3.
+pub struct Buzzer {
+	tim: pac::TIM3,
+}
+
+impl Buzzer {
+	/// Set up TIM3 for PWM output on channel 4, with the buzzer silent.
+	///
+	/// PB1 must already be in AF1 (TIM3_CH4) mode.
+	pub fn new(tim: pac::TIM3) -> Buzzer {
+		// Safety: we only touch the TIM3 enable bit, which nothing else
+		// modifies after init.
+		let rcc = unsafe { &*pac::RCC::ptr() };
+		rcc.apb1enr.modify(|_r, w| w.tim3en().set_bit());
+
+		// PWM mode 1 on channel 4
+		tim.ccmr2_output()
+			.write(|w| unsafe { w.oc4m().bits(0b110).oc4pe().set_bit() });
+		tim.ccer.write(|w| w.cc4e().set_bit());
+		tim.ccr4.write(|w| unsafe { w.bits(0) });
+
+		Buzzer { tim }
+	}
+
+	/// Start playing a tone, given the current timer clock speed.
+	///
+	/// A frequency of zero stops the buzzer.
+	pub fn play(&mut self, frequency_hz: u16, clock_hz: u32) {
+		if frequency_hz == 0 {
+			self.stop();
+			return;
+		}
+		let period = TIMER_TICK_HZ / u32::from(frequency_hz.max(MIN_FREQUENCY_HZ));
+		self.tim
+			.psc
+			.write(|w| unsafe { w.bits((clock_hz / TIMER_TICK_HZ) - 1) });
+		self.tim.arr.write(|w| unsafe { w.bits(period - 1) });
+		self.tim.ccr4.write(|w| unsafe { w.bits(period / 2) });
+		// Load the new prescaler and period straight away
+		self.tim.egr.write(|w| w.ug().set_bit());
+		self.tim.cr1.write(|w| w.arpe().set_bit().cen().set_bit());
+	}
+
+	/// Silence the buzzer.
+	pub fn stop(&mut self) {
+		self.tim.cr1.write(|w| w.cen().clear_bit());
+		self.tim.ccr4.write(|w| unsafe { w.bits(0) });
+		self.tim.egr.write(|w| w.ug().set_bit());
+	}
+}
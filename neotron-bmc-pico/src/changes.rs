@@ -0,0 +1,132 @@
+//! # Register Changes
+//!
+//! A host which polls half a dozen status registers every millisecond spends
+//! most of its SPI time being told that nothing has changed. Instead, we keep
+//! an eye on the registers it is likely to poll, and set a sticky bit in the
+//! Register Changes register when one of them changes. The host polls that
+//! one register, and only reads the others when their bit is set.
+//!
+//! Each bit watches one or more registers, summarised as a `u16` - the value
+//! of a status register, or how much is waiting in a FIFO. We compare the
+//! summaries with the ones we saw last every few milliseconds, and whenever
+//! the host reads the Register Changes register. When the host reads or
+//! writes a watched register, it is up to date, so that bit is cleared.
+
+/// How many bits the Register Changes register has.
+pub const WATCHED_LEN: usize = 8;
+
+/// The registers each bit of the Register Changes register watches.
+///
+/// Accessing any of them clears the bit.
+pub const WATCHED: [&[u8]; WATCHED_LEN] = [
+	// Interrupt Status, and Interrupt Status (High)
+	&[0x10, 0x14],
+	// BMC Health
+	&[0x07],
+	// Button Status, and Button Events
+	&[0x20, 0xC4],
+	// Power Control
+	&[0x25],
+	// Event Log Count, and the Event Log
+	&[0x27, 0x28],
+	// UART RX Count, and the UART FIFO
+	&[0x35, 0x30],
+	// The PS/2 Keyboard FIFO
+	&[0x40],
+	// PS/2 Mouse Report Count, and the reports
+	&[0x53, 0x54],
+];
+
+/// Tracks which of the watched registers have changed since the host last
+/// looked at them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeTracker {
+	/// The summary of each watched register when we last looked
+	seen: [u16; WATCHED_LEN],
+	/// Bit N is set if `WATCHED[N]` has changed since the host looked
+	changed: u8,
+}
+
+impl ChangeTracker {
+	/// Create a new tracker.
+	///
+	/// Every bit starts off set, so the host reads everything once.
+	pub const fn new() -> ChangeTracker {
+		ChangeTracker {
+			seen: [0; WATCHED_LEN],
+			changed: 0xFF,
+		}
+	}
+
+	/// Compare the watched registers with the last time we looked, and set
+	/// the bit for each one which differs.
+	pub fn sample(&mut self, current: &[u16; WATCHED_LEN]) {
+		for (bit, (seen, now)) in self.seen.iter_mut().zip(current.iter()).enumerate() {
+			if seen != now {
+				self.changed |= 1 << bit;
+				*seen = *now;
+			}
+		}
+	}
+
+	/// The host has just read or written `register`, so it is up to date
+	/// with whichever bit watches it.
+	///
+	/// `current` is the summary after the access, which might have changed
+	/// it (by taking bytes out of a FIFO, say).
+	pub fn accessed(&mut self, register: u8, current: &[u16; WATCHED_LEN]) {
+		self.sample(current);
+		for (bit, registers) in WATCHED.iter().enumerate() {
+			if registers.contains(&register) {
+				self.changed &= !(1 << bit);
+			}
+		}
+	}
+
+	/// Which of the watched registers have changed?
+	pub fn changed(&self) -> u8 {
+		self.changed
+	}
+
+	/// Clear the bits with a 1 in `bits`.
+	pub fn clear(&mut self, bits: u8) {
+		self.changed &= !bits;
+	}
+}
+
+impl Default for ChangeTracker {
+	fn default() -> Self {
+		ChangeTracker::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn sticky() {
+		let mut changes = ChangeTracker::new();
+		changes.clear(0xFF);
+		let mut current = [0u16; WATCHED_LEN];
+		changes.sample(&current);
+		assert_eq!(changes.changed(), 0);
+		// A button is pressed and released between two polls, but we
+		// sampled it in between
+		current[2] = 0x01;
+		changes.sample(&current);
+		current[2] = 0x00;
+		changes.sample(&current);
+		assert_eq!(changes.changed(), 1 << 2);
+		// Reading Button Events clears it
+		changes.accessed(0xC4, &current);
+		assert_eq!(changes.changed(), 0);
+		// Reading the UART FIFO empties it, which isn't news to the host
+		current[5] = 10;
+		changes.sample(&current);
+		current[5] = 0;
+		changes.accessed(0x30, &current);
+		changes.sample(&current);
+		assert_eq!(changes.changed(), 0);
+	}
+}
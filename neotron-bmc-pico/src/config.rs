@@ -0,0 +1,710 @@
+//! # Configuration Store
+//!
+//! Settings which must survive the BMC losing power are kept in the last page
+//! of flash (which `memory.x` keeps clear of the firmware). We find that page
+//! at run time (see [`FlashLayout`]), as the larger parts have more flash, and
+//! bigger pages. The
+//! page holds a single [`Config`] block, protected by a magic number, a
+//! layout version and a CRC-8. If any of those don't match, we fall back to
+//! [`Config::DEFAULT`].
+//!
+//! The rest of the page holds the power state journal (see [`crate::power`]),
+//! which we append to without erasing the page.
+//!
+//! The page before it holds the lifetime counters (see [`crate::stats`]),
+//! which work the same way, and the page before that holds the host's NVRAM
+//! (see [`crate::nvram`]).
+//!
+//! We also program the option bytes from here, for readout protection (see
+//! [`crate::readout`]).
+
+use stm32f0xx_hal::pac;
+
+use crate::analog::{Calibration, ANALOG_INPUTS};
+use crate::nvram::{self, NvramScan, NVRAM_LEN, RECORD_SIZE};
+use crate::power::{self, JournalScan};
+use crate::readout::{self, Level, OPTION_BYTES};
+use crate::rtc;
+use crate::stats::{self, Counter, Stats, StatsScan};
+
+/// Where flash starts.
+const FLASH_BASE: u32 = 0x0800_0000;
+
+/// Where the factory-programmed flash size (in KiB, as a `u16`) lives.
+const FLASH_SIZE_ADDRESS: u32 = 0x1FFF_F7CC;
+
+/// The MCU's ID code register, which holds the device ID in its bottom 12
+/// bits.
+const DBGMCU_IDCODE_ADDRESS: u32 = 0x4001_5800;
+
+/// How much room the config block has, at the start of the config page, before
+/// the power state journal.
+const CONFIG_BLOCK_LEN: u32 = 32;
+
+/// How much room the lifetime counters header has, at the start of the
+/// counters page, before the log.
+const STATS_HEADER_LEN: u32 = 32;
+
+/// Where the settings live in flash.
+///
+/// They go in the last three pages, wherever those are on the part we are
+/// running on, so one firmware image works on every part with at least as
+/// much flash as it was linked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlashLayout {
+	/// How big one erasable page is, in bytes.
+	pub page_size: u32,
+	/// Where the config page (the last one) starts.
+	pub config_address: u32,
+	/// Where the lifetime counters page (the one before it) starts.
+	pub stats_address: u32,
+	/// Where the NVRAM page (the one before that) starts.
+	pub nvram_address: u32,
+}
+
+impl FlashLayout {
+	/// Work out the layout for a part, from its device ID and how much flash
+	/// it has (in KiB).
+	pub const fn new(device_id: u16, flash_kib: u16) -> FlashLayout {
+		// The STM32F07x (0x448), and the STM32F09x and STM32F030xC (0x442),
+		// have 2 KiB pages. Every other STM32F0 has 1 KiB pages.
+		let page_size = if matches!(device_id, 0x448 | 0x442) {
+			2048
+		} else {
+			1024
+		};
+		let config_address = FLASH_BASE + (flash_kib as u32 * 1024) - page_size;
+		FlashLayout {
+			page_size,
+			config_address,
+			stats_address: config_address - page_size,
+			nvram_address: config_address - (2 * page_size),
+		}
+	}
+
+	/// Look at the part we are running on.
+	pub fn detect() -> FlashLayout {
+		// Safety: both registers are always mapped, and read-only
+		let (idcode, flash_kib) = unsafe {
+			(
+				core::ptr::read_volatile(DBGMCU_IDCODE_ADDRESS as *const u32),
+				core::ptr::read_volatile(FLASH_SIZE_ADDRESS as *const u16),
+			)
+		};
+		FlashLayout::new((idcode & 0xFFF) as u16, flash_kib)
+	}
+
+	/// Where the power state journal starts, leaving room for the config
+	/// block to grow.
+	fn journal_address(&self) -> u32 {
+		self.config_address + CONFIG_BLOCK_LEN
+	}
+
+	/// How many half-word entries the power state journal holds.
+	fn journal_len(&self) -> usize {
+		((self.page_size - CONFIG_BLOCK_LEN) / 2) as usize
+	}
+
+	/// Where the lifetime counters log starts, after the header.
+	fn stats_log_address(&self) -> u32 {
+		self.stats_address + STATS_HEADER_LEN
+	}
+
+	/// How many half-word entries the lifetime counters log holds.
+	fn stats_log_len(&self) -> usize {
+		((self.page_size - STATS_HEADER_LEN) / 2) as usize
+	}
+
+	/// How many NVRAM records fit in the NVRAM page.
+	fn nvram_records(&self) -> usize {
+		self.page_size as usize / RECORD_SIZE
+	}
+}
+
+/// Marks the start of a valid configuration block ("NB").
+const MAGIC: [u8; 2] = [0x4E, 0x42];
+
+/// Which version of the [`Config`] layout this firmware writes.
+const LAYOUT_VERSION: u8 = 12;
+
+/// The lowest 7-bit I²C address we can answer on (the ones below are
+/// reserved).
+pub const I2C_ADDRESS_MIN: u8 = 0x08;
+
+/// The highest 7-bit I²C address we can answer on (the ones above are
+/// reserved).
+pub const I2C_ADDRESS_MAX: u8 = 0x77;
+
+/// The first key to unlock the flash controller.
+const FLASH_KEY1: u32 = 0x4567_0123;
+
+/// The second key to unlock the flash controller.
+const FLASH_KEY2: u32 = 0xCDEF_89AB;
+
+/// Where the option bytes live.
+const OPTION_BYTES_ADDRESS: u32 = 0x1FFF_F800;
+
+/// Settings which persist across power cycles.
+#[derive(Debug, Clone, PartialEq, Eq, defmt::Format)]
+pub struct Config {
+	/// The main board current, in milliamps, which gives a full-scale reading
+	/// from the current sense ADC.
+	pub current_full_scale_ma: u16,
+	/// Cut the DC power if the main board draws more than this, in units of
+	/// 50 mA. Zero disables the over-current trip.
+	pub over_current_limit: u8,
+	/// The key to look for during power-on, as a bit number in the keyboard
+	/// key state bitmap. Zero disables it.
+	pub boot_key: u8,
+	/// Ask the host to shut down if the BMC's temperature sensor reaches
+	/// this, in °C. Zero disables the over-temperature shutdown.
+	pub over_temperature_limit_c: u8,
+	/// How long the host gets to shut down, in seconds, before we cut the
+	/// power ourselves.
+	pub over_temperature_timeout_s: u8,
+	/// What to do when the BMC powers up (see `power::RESTORE_OFF`, etc)
+	pub power_restore: u8,
+	/// How long to wait before powering on by ourselves, in seconds
+	pub power_on_delay_s: u8,
+	/// The 7-bit address we answer on as an I²C target (see the
+	/// `i2c-target` feature)
+	pub i2c_address: u8,
+	/// The keys which reset the main board when held down together, as bit
+	/// numbers in the keyboard key state bitmap. A zero leaves that key out,
+	/// and all zeros disables the chord.
+	pub reset_chord: [u8; 3],
+	/// How the IRQ line to the host is driven (see `irq::LINE_ACTIVE_HIGH`,
+	/// etc)
+	pub irq_line: u8,
+	/// How we tell whether there is a main board to power (see
+	/// `power::PRESENCE_OFF`, etc)
+	pub board_presence: u8,
+	/// The shortest press of the power or reset button we believe, in units
+	/// of `glitch::BUTTON_FILTER_UNIT_US`. Zero disables the filter.
+	pub button_filter: u8,
+	/// The shortest chip select pulse we believe, in microseconds. Zero
+	/// disables the filter.
+	pub cs_filter_us: u8,
+	/// What the power LED does whilst the main board is off (see
+	/// `power::LED_BREATHE`, etc)
+	pub led_mode: u8,
+	/// How bright the power LED is, in percent
+	pub led_brightness: u8,
+	/// What short and long presses of the reset button do, as in the Reset
+	/// Button Action register (see [`crate::buttons::ButtonConfig`])
+	pub reset_button: u8,
+	/// Turns the voltage at each analog input pin into the voltage on the
+	/// rail it monitors
+	pub analog_calibration: [Calibration; ANALOG_INPUTS],
+	/// Which clock the RTC runs from (see `rtc::CLOCK_LSI`, etc)
+	pub rtc_clock: u8,
+}
+
+impl Config {
+	/// How many bytes a configuration block occupies in flash.
+	///
+	/// Must be a multiple of two, as flash is written a half-word at a time.
+	pub const SIZE: usize = 32;
+
+	/// The settings we use if there is nothing valid in flash.
+	pub const DEFAULT: Config = Config {
+		current_full_scale_ma: 3300,
+		over_current_limit: 0,
+		// Delete
+		boot_key: 0xF1,
+		over_temperature_limit_c: 0,
+		over_temperature_timeout_s: 30,
+		power_restore: power::RESTORE_OFF,
+		power_on_delay_s: 0,
+		i2c_address: 0x42,
+		reset_chord: [0; 3],
+		// Active low, push-pull
+		irq_line: 0,
+		board_presence: power::PRESENCE_OFF,
+		button_filter: 0,
+		cs_filter_us: 0,
+		led_mode: power::LED_BREATHE,
+		led_brightness: 100,
+		// Reset with a short press
+		reset_button: 0x03,
+		analog_calibration: [Calibration::NONE; ANALOG_INPUTS],
+		rtc_clock: rtc::CLOCK_LSI,
+	};
+
+	/// Convert to bytes for storing in flash.
+	///
+	/// The layout is the magic number, the layout version, the full-scale
+	/// current as a `u16le`, the over-current limit, the boot key, the
+	/// over-temperature limit and timeout, the power restore policy and
+	/// delay, the I²C target address, the three reset chord keys, the IRQ
+	/// line control, the board presence setting, the button and chip select
+	/// glitch filters, the power LED mode and brightness, the reset button
+	/// action, the calibration of each analog input, the RTC clock source,
+	/// and a CRC-8 of all the preceeding bytes.
+	pub fn as_bytes(&self) -> [u8; Self::SIZE] {
+		let full_scale = self.current_full_scale_ma.to_le_bytes();
+		let mut bytes = [0u8; Self::SIZE];
+		bytes[0..22].copy_from_slice(&[
+			MAGIC[0],
+			MAGIC[1],
+			LAYOUT_VERSION,
+			full_scale[0],
+			full_scale[1],
+			self.over_current_limit,
+			self.boot_key,
+			self.over_temperature_limit_c,
+			self.over_temperature_timeout_s,
+			self.power_restore,
+			self.power_on_delay_s,
+			self.i2c_address,
+			self.reset_chord[0],
+			self.reset_chord[1],
+			self.reset_chord[2],
+			self.irq_line,
+			self.board_presence,
+			self.button_filter,
+			self.cs_filter_us,
+			self.led_mode,
+			self.led_brightness,
+			self.reset_button,
+		]);
+		for (chunk, calibration) in bytes[22..30]
+			.chunks_exact_mut(Calibration::SIZE)
+			.zip(self.analog_calibration.iter())
+		{
+			chunk.copy_from_slice(&calibration.as_bytes());
+		}
+		bytes[30] = self.rtc_clock;
+		bytes[Self::SIZE - 1] = neotron_bmc_protocol::calculate_crc(&bytes[0..Self::SIZE - 1]);
+		bytes
+	}
+
+	/// Convert from bytes read from flash.
+	///
+	/// Returns `None` if the magic number, layout version or CRC are wrong.
+	pub fn from_bytes(bytes: &[u8; Self::SIZE]) -> Option<Config> {
+		if bytes[0..2] != MAGIC || bytes[2] != LAYOUT_VERSION {
+			return None;
+		}
+		if neotron_bmc_protocol::calculate_crc(bytes) != 0 {
+			return None;
+		}
+		Some(Config {
+			current_full_scale_ma: u16::from_le_bytes([bytes[3], bytes[4]]),
+			over_current_limit: bytes[5],
+			boot_key: bytes[6],
+			over_temperature_limit_c: bytes[7],
+			over_temperature_timeout_s: bytes[8],
+			power_restore: bytes[9],
+			power_on_delay_s: bytes[10],
+			i2c_address: bytes[11],
+			reset_chord: [bytes[12], bytes[13], bytes[14]],
+			irq_line: bytes[15],
+			board_presence: bytes[16],
+			button_filter: bytes[17],
+			cs_filter_us: bytes[18],
+			led_mode: bytes[19],
+			led_brightness: bytes[20],
+			reset_button: bytes[21],
+			analog_calibration: [
+				Calibration::from_bytes(&[bytes[22], bytes[23], bytes[24], bytes[25]]),
+				Calibration::from_bytes(&[bytes[26], bytes[27], bytes[28], bytes[29]]),
+			],
+			rtc_clock: bytes[30],
+		})
+	}
+
+	/// Has a configuration for this firmware been damaged?
+	///
+	/// That's a page with our magic number and layout version, but the wrong
+	/// CRC. A blank page, or one saved by firmware with a different layout,
+	/// just means there's nothing for us to load.
+	pub fn is_corrupt(bytes: &[u8; Self::SIZE]) -> bool {
+		bytes[0..2] == MAGIC
+			&& bytes[2] == LAYOUT_VERSION
+			&& neotron_bmc_protocol::calculate_crc(bytes) != 0
+	}
+
+	/// Read the configuration from flash.
+	///
+	/// Returns `None` if there's no valid configuration stored.
+	pub fn load() -> Option<Config> {
+		Config::from_bytes(&Config::read_page())
+	}
+
+	/// Read the bytes at the start of the config page in flash, whether they
+	/// hold a valid configuration or not.
+	pub fn read_page() -> [u8; Self::SIZE] {
+		let layout = FlashLayout::detect();
+		let mut bytes = [0u8; Self::SIZE];
+		for (idx, byte) in bytes.iter_mut().enumerate() {
+			// Safety: the config page is always mapped, and is never written
+			// whilst we are reading it.
+			*byte =
+				unsafe { core::ptr::read_volatile((layout.config_address as *const u8).add(idx)) };
+		}
+		bytes
+	}
+
+	/// Write this configuration to flash.
+	///
+	/// This erases the whole config page (including the power state journal),
+	/// which stalls the CPU for tens of milliseconds, so don't call it from a
+	/// high priority task.
+	pub fn save(&self, flash: &mut pac::FLASH) -> Result<(), ()> {
+		let layout = FlashLayout::detect();
+		unlock(flash);
+		erase_page(flash, layout.config_address);
+		let mut ok = true;
+		for (idx, pair) in self.as_bytes().chunks_exact(2).enumerate() {
+			let half_word = u16::from_le_bytes([pair[0], pair[1]]);
+			ok &= program(flash, layout.config_address + (idx as u32 * 2), half_word);
+		}
+		lock(flash);
+
+		if !ok || Config::load().as_ref() != Some(self) {
+			Err(())
+		} else {
+			Ok(())
+		}
+	}
+}
+
+impl Default for Config {
+	fn default() -> Self {
+		Config::DEFAULT
+	}
+}
+
+/// Read the power state journal from flash.
+pub fn read_journal() -> JournalScan {
+	let layout = FlashLayout::detect();
+	let journal_address = layout.journal_address();
+	power::scan_journal((0..layout.journal_len()).map(|idx| {
+		// Safety: the config page is always mapped, and is never written
+		// whilst we are reading it.
+		unsafe { core::ptr::read_volatile((journal_address as *const u16).add(idx)) }
+	}))
+}
+
+/// Add an entry to the power state journal (see `power::JOURNAL_ON`, etc).
+///
+/// Usually this only programs one half-word, but when the journal is full
+/// the page has to be erased first (putting the config block back
+/// afterwards), which stalls the CPU for tens of milliseconds.
+pub fn append_journal(flash: &mut pac::FLASH, entry: u16) -> Result<(), ()> {
+	let layout = FlashLayout::detect();
+	unlock(flash);
+	let mut ok = true;
+	let next_free = match read_journal().next_free {
+		Some(idx) => idx,
+		None => {
+			let page = Config::read_page();
+			erase_page(flash, layout.config_address);
+			for (idx, pair) in page.chunks_exact(2).enumerate() {
+				let half_word = u16::from_le_bytes([pair[0], pair[1]]);
+				// Erased flash is already all ones
+				if half_word != 0xFFFF {
+					ok &= program(flash, layout.config_address + (idx as u32 * 2), half_word);
+				}
+			}
+			0
+		}
+	};
+	ok &= program(
+		flash,
+		layout.journal_address() + (next_free as u32 * 2),
+		entry,
+	);
+	lock(flash);
+	if ok {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+/// Read the lifetime counters from flash.
+pub fn read_stats() -> StatsScan {
+	let layout = FlashLayout::detect();
+	let mut header = [0u8; Stats::HEADER_SIZE];
+	for (idx, byte) in header.iter_mut().enumerate() {
+		// Safety: the counters page is always mapped, and is never written
+		// whilst we are reading it.
+		*byte = unsafe { core::ptr::read_volatile((layout.stats_address as *const u8).add(idx)) };
+	}
+	stats::scan(
+		&header,
+		(0..layout.stats_log_len()).map(|idx| {
+			// Safety: as above
+			unsafe { core::ptr::read_volatile((layout.stats_log_address() as *const u16).add(idx)) }
+		}),
+	)
+}
+
+/// Add one to a lifetime counter in flash, and return the new totals.
+///
+/// Usually this only programs one half-word, but when the log is full (or
+/// the page has never been written) the page has to be erased first, which
+/// stalls the CPU for tens of milliseconds.
+pub fn record_stat(flash: &mut pac::FLASH, counter: Counter) -> Result<Stats, ()> {
+	let layout = FlashLayout::detect();
+	let found = read_stats();
+	let mut expected = found.stats;
+	expected.record(counter);
+	unlock(flash);
+	let ok = match found.next_free {
+		Some(idx) => program(
+			flash,
+			layout.stats_log_address() + (idx as u32 * 2),
+			counter.entry(),
+		),
+		None => {
+			erase_page(flash, layout.stats_address);
+			let mut ok = true;
+			for (idx, pair) in expected.header_bytes().chunks_exact(2).enumerate() {
+				let half_word = u16::from_le_bytes([pair[0], pair[1]]);
+				ok &= program(flash, layout.stats_address + (idx as u32 * 2), half_word);
+			}
+			ok
+		}
+	};
+	lock(flash);
+	let stats = read_stats().stats;
+	if ok && stats == expected {
+		Ok(stats)
+	} else {
+		Err(())
+	}
+}
+
+/// Read the host's NVRAM from flash.
+pub fn read_nvram() -> NvramScan {
+	let layout = FlashLayout::detect();
+	nvram::scan((0..layout.nvram_records()).map(|record| {
+		let address = layout.nvram_address + (record * RECORD_SIZE) as u32;
+		let mut bytes = [0u8; RECORD_SIZE];
+		for (idx, byte) in bytes.iter_mut().enumerate() {
+			// Safety: the NVRAM page is always mapped, and is never written
+			// whilst we are reading it.
+			*byte = unsafe { core::ptr::read_volatile((address as *const u8).add(idx)) };
+		}
+		bytes
+	}))
+}
+
+/// Commit the host's NVRAM to flash, unless it already holds these bytes.
+///
+/// Usually this only programs one record, but when the page is full (or
+/// holds something else) it has to be erased first, which stalls the CPU for
+/// tens of milliseconds.
+pub fn commit_nvram(flash: &mut pac::FLASH, data: &[u8; NVRAM_LEN]) -> Result<(), ()> {
+	let layout = FlashLayout::detect();
+	let found = read_nvram();
+	if found.data.as_ref() == Some(data) {
+		return Ok(());
+	}
+	unlock(flash);
+	let next_free = match found.next_free {
+		Some(idx) => idx,
+		None => {
+			erase_page(flash, layout.nvram_address);
+			0
+		}
+	};
+	let address = layout.nvram_address + (next_free * RECORD_SIZE) as u32;
+	let mut ok = true;
+	for (idx, pair) in nvram::record(data).chunks_exact(2).enumerate() {
+		let half_word = u16::from_le_bytes([pair[0], pair[1]]);
+		ok &= program(flash, address + (idx as u32 * 2), half_word);
+	}
+	lock(flash);
+	if ok && read_nvram().data.as_ref() == Some(data) {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+/// Read the readout protection level in force now, and the one the option
+/// bytes will give after the next power-up.
+pub fn readout_levels() -> (Level, Level) {
+	// Safety: reading the option byte register has no side effects
+	let flash = unsafe { &*pac::FLASH::ptr() };
+	let now = Level::from_rdprt(flash.obr.read().rdprt().bits());
+	let next = Level::from_option_byte(read_option_bytes()[0] as u8);
+	(now, next)
+}
+
+/// Program the option bytes for readout protection level 1, keeping the
+/// other option bytes as they are.
+///
+/// The option bytes have to be erased first, so if we lose power part way
+/// through, the chip comes up at level 1 anyway (an erased RDP byte means
+/// level 1). Returns an error if the option bytes don't read back as
+/// expected.
+pub fn enable_readout_protection(flash: &mut pac::FLASH) -> Result<(), ()> {
+	let expected = readout::level_1_option_bytes(&read_option_bytes());
+	unlock(flash);
+	if flash.cr.read().optwre().bit_is_clear() {
+		flash
+			.optkeyr
+			.write(|w| unsafe { w.optkeyr().bits(FLASH_KEY1) });
+		flash
+			.optkeyr
+			.write(|w| unsafe { w.optkeyr().bits(FLASH_KEY2) });
+	}
+	flash.cr.modify(|_r, w| w.opter().set_bit());
+	flash.cr.modify(|_r, w| w.strt().set_bit());
+	while flash.sr.read().bsy().bit_is_set() {}
+	flash.cr.modify(|_r, w| w.opter().clear_bit());
+	let mut ok = true;
+	flash.cr.modify(|_r, w| w.optpg().set_bit());
+	for (idx, half_word) in expected.iter().enumerate() {
+		// Safety: the flash controller only lets us write the option bytes
+		// whilst OPTPG is set, and they are erased.
+		unsafe {
+			core::ptr::write_volatile((OPTION_BYTES_ADDRESS as *mut u16).add(idx), *half_word);
+		}
+		while flash.sr.read().bsy().bit_is_set() {}
+		let sr = flash.sr.read();
+		ok &= !(sr.pgerr().bit_is_set() || sr.wrprt().bit_is_set());
+		flash
+			.sr
+			.write(|w| w.eop().set_bit().pgerr().set_bit().wrprt().set_bit());
+	}
+	flash
+		.cr
+		.modify(|_r, w| w.optpg().clear_bit().optwre().clear_bit());
+	lock(flash);
+	let written = read_option_bytes();
+	let matches = written
+		.iter()
+		.zip(expected.iter())
+		.all(|(written, expected)| (written & 0x00FF) == *expected);
+	if ok && matches {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+/// Read the option bytes, as half-words (each byte with its complement
+/// above it).
+fn read_option_bytes() -> [u16; OPTION_BYTES] {
+	let mut option_bytes = [0u16; OPTION_BYTES];
+	for (idx, half_word) in option_bytes.iter_mut().enumerate() {
+		// Safety: the option bytes are always mapped
+		*half_word =
+			unsafe { core::ptr::read_volatile((OPTION_BYTES_ADDRESS as *const u16).add(idx)) };
+	}
+	option_bytes
+}
+
+/// Unlock the flash controller, so we can erase and program.
+fn unlock(flash: &mut pac::FLASH) {
+	if flash.cr.read().lock().bit_is_set() {
+		flash.keyr.write(|w| unsafe { w.fkeyr().bits(FLASH_KEY1) });
+		flash.keyr.write(|w| unsafe { w.fkeyr().bits(FLASH_KEY2) });
+	}
+	while flash.sr.read().bsy().bit_is_set() {}
+}
+
+/// Erase the page at `address` (the config or counters page).
+fn erase_page(flash: &mut pac::FLASH, address: u32) {
+	flash.cr.modify(|_r, w| w.per().set_bit());
+	flash.ar.write(|w| unsafe { w.far().bits(address) });
+	flash.cr.modify(|_r, w| w.strt().set_bit());
+	while flash.sr.read().bsy().bit_is_set() {}
+	flash.cr.modify(|_r, w| w.per().clear_bit());
+}
+
+/// Program one half-word in the config or counters page, which must be
+/// erased.
+///
+/// Returns `false` if the flash controller reported an error.
+fn program(flash: &mut pac::FLASH, address: u32, half_word: u16) -> bool {
+	flash.cr.modify(|_r, w| w.pg().set_bit());
+	// Safety: both pages are past the end of the firmware (see `memory.x`),
+	// and the caller has checked this half-word is erased.
+	unsafe {
+		core::ptr::write_volatile(address as *mut u16, half_word);
+	}
+	while flash.sr.read().bsy().bit_is_set() {}
+	flash.cr.modify(|_r, w| w.pg().clear_bit());
+	let sr = flash.sr.read();
+	let failed = sr.pgerr().bit_is_set() || sr.wrprt().bit_is_set();
+	// Clear the status flags (they are write-1-to-clear)
+	flash
+		.sr
+		.write(|w| w.eop().set_bit().pgerr().set_bit().wrprt().set_bit());
+	!failed
+}
+
+/// Lock the flash controller again.
+fn lock(flash: &mut pac::FLASH) {
+	flash.cr.modify(|_r, w| w.lock().set_bit());
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn round_trip() {
+		let config = Config {
+			reset_button: 0x21,
+			analog_calibration: [
+				Calibration {
+					gain: 8192,
+					offset_mv: -5,
+				},
+				Calibration::NONE,
+			],
+			rtc_clock: rtc::CLOCK_LSE,
+			..Config::DEFAULT
+		};
+		let bytes = config.as_bytes();
+		assert_eq!(bytes[30], rtc::CLOCK_LSE);
+		assert_eq!(Config::from_bytes(&bytes), Some(config));
+		let mut damaged = bytes;
+		damaged[24] ^= 0x01;
+		assert_eq!(Config::from_bytes(&damaged), None);
+		assert!(Config::is_corrupt(&damaged));
+	}
+
+	#[test]
+	fn small_parts_have_1k_pages() {
+		// STM32F030x6 and STM32F031x6
+		let layout = FlashLayout::new(0x444, 32);
+		assert_eq!(layout.page_size, 1024);
+		assert_eq!(layout.config_address, 0x0800_7C00);
+		assert_eq!(layout.stats_address, 0x0800_7800);
+		assert_eq!(layout.nvram_address, 0x0800_7400);
+		assert_eq!(layout.nvram_records(), 15);
+		assert_eq!(layout.journal_address(), 0x0800_7C20);
+		assert_eq!(layout.journal_len(), 496);
+		// STM32F042x6
+		assert_eq!(FlashLayout::new(0x445, 32), layout);
+		// STM32F030x8 has twice the flash, but the same pages
+		let layout = FlashLayout::new(0x440, 64);
+		assert_eq!(layout.page_size, 1024);
+		assert_eq!(layout.config_address, 0x0800_FC00);
+	}
+
+	#[test]
+	fn large_parts_have_2k_pages() {
+		// STM32F072xB
+		let layout = FlashLayout::new(0x448, 128);
+		assert_eq!(layout.page_size, 2048);
+		assert_eq!(layout.config_address, 0x0801_F800);
+		assert_eq!(layout.stats_address, 0x0801_F000);
+		assert_eq!(layout.nvram_address, 0x0801_E800);
+		assert_eq!(layout.nvram_records(), 30);
+		assert_eq!(layout.stats_log_address(), 0x0801_F020);
+		assert_eq!(layout.journal_len(), 1008);
+		assert_eq!(layout.stats_log_len(), 1008);
+		// STM32F072x8 has 2 KiB pages too, even with only 64 KiB of flash
+		assert_eq!(FlashLayout::new(0x448, 64).config_address, 0x0800_F800);
+	}
+}
@@ -0,0 +1,376 @@
+//! # UART Consoles
+//!
+//! A developer with only a serial cable can use the UART as the OS's
+//! console, in one of two ways (or both at once).
+//!
+//! ## Console Takeover
+//!
+//! Turns characters arriving on the UART into keypresses, so the developer
+//! can type into the OS (through the keyboard registers) before there's a
+//! PS/2 keyboard to hand.
+//!
+//! Each character becomes the make and break codes (Scan Code Set 2, US
+//! layout) for the key which types it, wrapped in Left Shift or Left Control
+//! where needed. Return, Line Feed, Tab, Backspace, Delete and Escape press
+//! the matching keys, and the other control characters press Control and a
+//! letter (so `0x03` is Ctrl+C). Anything else (including all of the top
+//! half) has no key, and is dropped.
+//!
+//! The same US layout works the other way round for the keyboard's ASCII
+//! mode, where a key press becomes the character it types (see
+//! [`char_for_key`]).
+//!
+//! ## Remote Console
+//!
+//! The host writes its text output to the UART register, and we send it out
+//! of the UART, whilst what the developer types comes back as usual. Like
+//! `ssh`, a `~` at the start of a line begins an escape, which the NBMC
+//! handles itself rather than passing on (see [`Escapes`]).
+
+use crate::uart::ByteFifo;
+
+/// The make code for Left Shift
+const CODE_LEFT_SHIFT: u8 = 0x12;
+
+/// The make code for Left Control
+const CODE_LEFT_CONTROL: u8 = 0x14;
+
+/// Goes before the make code, to make a break code
+const BREAK: u8 = 0xF0;
+
+/// The most bytes one character turns into
+pub const MAX_KEYSTROKE_LEN: usize = 6;
+
+/// The make codes for the letters `a` to `z`
+const LETTERS: [u8; 26] = [
+	0x1C, 0x32, 0x21, 0x23, 0x24, 0x2B, 0x34, 0x33, 0x43, 0x3B, 0x42, 0x4B, 0x3A, 0x31, 0x44, 0x4D,
+	0x15, 0x2D, 0x1B, 0x2C, 0x3C, 0x2A, 0x1D, 0x22, 0x35, 0x1A,
+];
+
+/// The make codes for the digits `0` to `9`
+const DIGITS: [u8; 10] = [0x45, 0x16, 0x1E, 0x26, 0x25, 0x2E, 0x36, 0x3D, 0x3E, 0x46];
+
+/// The punctuation keys - the character without Shift, the character with
+/// Shift, and the make code
+const PUNCTUATION: [(u8, u8, u8); 11] = [
+	(b'`', b'~', 0x0E),
+	(b'-', b'_', 0x4E),
+	(b'=', b'+', 0x55),
+	(b'[', b'{', 0x54),
+	(b']', b'}', 0x5B),
+	(b'\\', b'|', 0x5D),
+	(b';', b':', 0x4C),
+	(b'\'', b'"', 0x52),
+	(b',', b'<', 0x41),
+	(b'.', b'>', 0x49),
+	(b'/', b'?', 0x4A),
+];
+
+/// The shifted digit keys, `)` and `!` to `(`, in digit order
+const SHIFTED_DIGITS: &[u8; 10] = b")!@#$%^&*(";
+
+/// A key to press, and the modifier to hold down whilst pressing it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Keystroke {
+	/// The make code for the modifier key, if any
+	modifier: Option<u8>,
+	/// The make code for the key
+	code: u8,
+}
+
+impl Keystroke {
+	/// Which key types this character, if any.
+	pub fn for_char(ch: u8) -> Option<Keystroke> {
+		let plain = |code| {
+			Some(Keystroke {
+				modifier: None,
+				code,
+			})
+		};
+		let shifted = |code| {
+			Some(Keystroke {
+				modifier: Some(CODE_LEFT_SHIFT),
+				code,
+			})
+		};
+		match ch {
+			b'a'..=b'z' => plain(LETTERS[usize::from(ch - b'a')]),
+			b'A'..=b'Z' => shifted(LETTERS[usize::from(ch - b'A')]),
+			b'0'..=b'9' => plain(DIGITS[usize::from(ch - b'0')]),
+			b' ' => plain(0x29),
+			// Return and Line Feed are both Enter
+			b'\r' | b'\n' => plain(0x5A),
+			b'\t' => plain(0x0D),
+			// Backspace and Delete are both Backspace, as terminals differ
+			0x08 | 0x7F => plain(0x66),
+			0x1B => plain(0x76),
+			0x01..=0x1A => Some(Keystroke {
+				modifier: Some(CODE_LEFT_CONTROL),
+				code: LETTERS[usize::from(ch - 0x01)],
+			}),
+			_ => {
+				if let Some(digit) = SHIFTED_DIGITS.iter().position(|&c| c == ch) {
+					return shifted(DIGITS[digit]);
+				}
+				PUNCTUATION
+					.iter()
+					.find_map(|&(unshifted, with_shift, code)| {
+						if ch == unshifted {
+							plain(code)
+						} else if ch == with_shift {
+							shifted(code)
+						} else {
+							None
+						}
+					})
+			}
+		}
+	}
+
+	/// The scan codes which press and release the key (and the modifier).
+	///
+	/// Returns the codes, and how many of them there are.
+	pub fn scancodes(&self) -> ([u8; MAX_KEYSTROKE_LEN], usize) {
+		let code = self.code;
+		match self.modifier {
+			Some(modifier) => ([modifier, code, BREAK, code, BREAK, modifier], 6),
+			None => ([code, BREAK, code, 0, 0, 0], 3),
+		}
+	}
+}
+
+/// Type a character received on the UART into a keyboard FIFO.
+///
+/// The whole keystroke goes in, or none of it does (so a key is never left
+/// held down). Returns `false` if the character had to be dropped because
+/// the FIFO was full; characters with no key are dropped quietly.
+pub fn type_char<const N: usize>(ch: u8, fifo: &mut ByteFifo<N>) -> bool {
+	let keystroke = match Keystroke::for_char(ch) {
+		Some(keystroke) => keystroke,
+		None => return true,
+	};
+	let (codes, len) = keystroke.scancodes();
+	if fifo.space() < len {
+		return false;
+	}
+	for &code in &codes[0..len] {
+		fifo.push(code);
+	}
+	true
+}
+
+/// Which character a key types, with the US layout - the reverse of
+/// [`Keystroke::for_char`], for the keyboard's ASCII mode.
+///
+/// `bit` is the key's bit number in the key state bitmap (see
+/// [`crate::keyboard::KeyState`]). Caps Lock only affects the letters. With
+/// Control, the letters give the control characters `0x01` to `0x1A`, and
+/// keys which don't type a control character give nothing - as do keys which
+/// never type anything, like the function keys.
+pub fn char_for_key(bit: u8, shift: bool, ctrl: bool, caps_lock: bool) -> Option<u8> {
+	let ch = if let Some(letter) = LETTERS.iter().position(|&code| code == bit) {
+		let first = if shift != caps_lock { b'A' } else { b'a' };
+		first + letter as u8
+	} else if let Some(digit) = DIGITS.iter().position(|&code| code == bit) {
+		if shift {
+			SHIFTED_DIGITS[digit]
+		} else {
+			b'0' + digit as u8
+		}
+	} else if let Some(&(unshifted, with_shift, _)) =
+		PUNCTUATION.iter().find(|&&(_, _, code)| code == bit)
+	{
+		if shift {
+			with_shift
+		} else {
+			unshifted
+		}
+	} else {
+		match bit {
+			0x29 => b' ',
+			// Enter, and the keypad's Enter
+			0x5A | 0xDA => b'\r',
+			0x0D => b'\t',
+			0x66 => 0x08,
+			0x76 => 0x1B,
+			// Delete
+			0xF1 => 0x7F,
+			// The keypad keys which Num Lock doesn't change
+			0xCA => b'/',
+			0x7C => b'*',
+			0x7B => b'-',
+			0x79 => b'+',
+			_ => return None,
+		}
+	};
+	if !ctrl {
+		return Some(ch);
+	}
+	match ch {
+		b'a'..=b'z' | b'A'..=b'Z' => Some(ch & 0x1F),
+		0x00..=0x1F | 0x7F => Some(ch),
+		_ => None,
+	}
+}
+
+/// Starts an escape, at the start of a line
+pub const ESCAPE: u8 = b'~';
+
+/// What an escape asks the NBMC to do.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Command {
+	/// `~k` - turn console takeover on or off
+	ToggleTakeover,
+}
+
+/// Finds the escapes in what the developer types on a remote console.
+///
+/// An escape is [`ESCAPE`] at the start of a line (or as the very first
+/// character), then a command character. `~~` passes on a single `~`, and
+/// `~` followed by anything else passes on both characters, so typing a
+/// `~` in the middle of a line works as normal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Escapes {
+	/// Was the last character passed on a Return or Line Feed?
+	line_start: bool,
+	/// Have we swallowed the `~` at the start of an escape?
+	escaping: bool,
+}
+
+impl Escapes {
+	/// Create the escape finder, at the start of a line.
+	pub const fn new() -> Escapes {
+		Escapes {
+			line_start: true,
+			escaping: false,
+		}
+	}
+
+	/// Handle one character received on the UART.
+	///
+	/// Characters to pass on go to `pass`, in order. Returns the command, if
+	/// this character finished an escape.
+	pub fn receive<F>(&mut self, byte: u8, mut pass: F) -> Option<Command>
+	where
+		F: FnMut(u8),
+	{
+		if core::mem::replace(&mut self.escaping, false) {
+			self.line_start = false;
+			match byte {
+				b'k' => return Some(Command::ToggleTakeover),
+				ESCAPE => {}
+				_ => pass(ESCAPE),
+			}
+		} else if self.line_start && byte == ESCAPE {
+			self.escaping = true;
+			return None;
+		}
+		self.line_start = matches!(byte, b'\r' | b'\n');
+		pass(byte);
+		None
+	}
+}
+
+impl Default for Escapes {
+	fn default() -> Self {
+		Escapes::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn typed(ch: u8) -> Vec<u8> {
+		let mut fifo = ByteFifo::<16>::new();
+		assert!(type_char(ch, &mut fifo));
+		core::iter::from_fn(|| fifo.pop()).collect()
+	}
+
+	#[test]
+	fn characters() {
+		assert_eq!(typed(b'a'), [0x1C, 0xF0, 0x1C]);
+		assert_eq!(typed(b'Z'), [0x12, 0x1A, 0xF0, 0x1A, 0xF0, 0x12]);
+		assert_eq!(typed(b'0'), [0x45, 0xF0, 0x45]);
+		assert_eq!(typed(b'!'), [0x12, 0x16, 0xF0, 0x16, 0xF0, 0x12]);
+		assert_eq!(typed(b'/'), [0x4A, 0xF0, 0x4A]);
+		assert_eq!(typed(b'"'), [0x12, 0x52, 0xF0, 0x52, 0xF0, 0x12]);
+		assert_eq!(typed(b'\r'), [0x5A, 0xF0, 0x5A]);
+		assert_eq!(typed(0x7F), [0x66, 0xF0, 0x66]);
+		// Ctrl+C
+		assert_eq!(typed(0x03), [0x14, 0x21, 0xF0, 0x21, 0xF0, 0x14]);
+		// No key for these
+		assert!(typed(0x00).is_empty());
+		assert!(typed(0xE9).is_empty());
+	}
+
+	#[test]
+	fn every_printable_character() {
+		for ch in 0x20..=0x7E {
+			assert!(Keystroke::for_char(ch).is_some(), "0x{:02x}", ch);
+		}
+	}
+
+	#[test]
+	fn keys_to_characters() {
+		// Every character we can type comes back as itself
+		for ch in 0x20..=0x7E {
+			let keystroke = Keystroke::for_char(ch).unwrap();
+			let shift = keystroke.modifier.is_some();
+			assert_eq!(
+				char_for_key(keystroke.code, shift, false, false),
+				Some(ch),
+				"0x{:02x}",
+				ch
+			);
+		}
+		// Caps Lock only changes the letters
+		assert_eq!(char_for_key(0x1C, false, false, true), Some(b'A'));
+		assert_eq!(char_for_key(0x1C, true, false, true), Some(b'a'));
+		assert_eq!(char_for_key(0x16, false, false, true), Some(b'1'));
+		assert_eq!(char_for_key(0x5A, false, false, false), Some(b'\r'));
+		assert_eq!(char_for_key(0xF1, false, false, false), Some(0x7F));
+		// Ctrl+C, Ctrl+Enter, and Ctrl+1 (which isn't a character)
+		assert_eq!(char_for_key(0x21, false, true, false), Some(0x03));
+		assert_eq!(char_for_key(0x5A, false, true, false), Some(b'\r'));
+		assert_eq!(char_for_key(0x16, false, true, false), None);
+		// F1
+		assert_eq!(char_for_key(0x05, false, false, false), None);
+	}
+
+	#[test]
+	fn whole_keystrokes_only() {
+		let mut fifo = ByteFifo::<8>::new();
+		assert!(type_char(b'A', &mut fifo));
+		// Only two bytes of room left
+		assert!(!type_char(b'a', &mut fifo));
+		assert_eq!(fifo.len(), 6);
+	}
+
+	fn escaped(input: &[u8]) -> (Vec<u8>, Vec<Command>) {
+		let mut escapes = Escapes::new();
+		let mut passed = Vec::new();
+		let mut commands = Vec::new();
+		for &byte in input {
+			commands.extend(escapes.receive(byte, |b| passed.push(b)));
+		}
+		(passed, commands)
+	}
+
+	#[test]
+	fn escapes() {
+		assert_eq!(
+			escaped(b"~kls\r~k"),
+			(
+				b"ls\r".to_vec(),
+				vec![Command::ToggleTakeover, Command::ToggleTakeover]
+			)
+		);
+		// Not at the start of a line
+		assert_eq!(escaped(b"cd ~k"), (b"cd ~k".to_vec(), vec![]));
+		// Not a command
+		assert_eq!(escaped(b"~/bin\n~~k"), (b"~/bin\n~k".to_vec(), vec![]));
+		// Still waiting for the command
+		assert_eq!(escaped(b"\n~"), (b"\n".to_vec(), vec![]));
+	}
+}
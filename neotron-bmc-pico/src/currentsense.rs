@@ -0,0 +1,105 @@
+//! # Main Board Current Sense
+//!
+//! Some main board revisions have a current-sense amplifier on the 5V rail.
+//! The Pico has no spare ADC inputs, so the amplifier output replaces the
+//! buzzer on PB1 (ADC_IN9).
+//!
+//! The ADC converts continuously. The analog watchdog compares every
+//! conversion against the over-current limit in hardware, and fires the ADC
+//! interrupt as soon as the limit is crossed, so we can cut the power without
+//! waiting for the next time we poll the current.
+
+use stm32f0xx_hal::pac;
+
+use crate::config::Config;
+
+/// The ADC channel the current sense amplifier is wired to (PB1)
+const CHANNEL: u8 = 9;
+
+/// The largest value the 12-bit ADC can return.
+const FULL_SCALE_COUNTS: u32 = 4095;
+
+/// Drives the ADC to sample the main board current.
+pub struct CurrentSense {
+	dev: pac::ADC,
+}
+
+impl CurrentSense {
+	/// Set up the ADC to continuously sample the current sense input.
+	///
+	/// The PB1 pin must already be in analog mode.
+	pub fn new(dev: pac::ADC, config: &Config) -> CurrentSense {
+		// Safety: we only touch the ADC enable bit, which nothing else
+		// modifies after init.
+		let rcc = unsafe { &*pac::RCC::ptr() };
+		rcc.apb2enr.modify(|_r, w| w.adcen().set_bit());
+
+		// Clock the ADC from PCLK/4 (12 MHz), which is always in range
+		dev.cfgr2.write(|w| unsafe { w.ckmode().bits(0b10) });
+
+		// Calibrate (whilst the ADC is disabled)
+		dev.cr.modify(|_r, w| w.adcal().set_bit());
+		while dev.cr.read().adcal().bit_is_set() {}
+
+		dev.cfgr1.write(|w| {
+			// Convert continuously, overwriting old results
+			w.cont().set_bit();
+			w.ovrmod().set_bit();
+			// Watch just our channel with the analog watchdog
+			w.awden().set_bit();
+			w.awdsgl().set_bit();
+			unsafe { w.awdch().bits(CHANNEL) };
+			w
+		});
+		// Use the longest sample time - the amplifier is slow
+		dev.smpr.write(|w| unsafe { w.smp().bits(0b111) });
+		dev.chselr.write(|w| w.chsel9().set_bit());
+		dev.ier.write(|w| w.awdie().set_bit());
+
+		let mut current_sense = CurrentSense { dev };
+		current_sense.set_limit(config);
+
+		// Power up and start converting
+		current_sense.dev.cr.modify(|_r, w| w.aden().set_bit());
+		while current_sense.dev.isr.read().adrdy().bit_is_clear() {}
+		current_sense.dev.cr.modify(|_r, w| w.adstart().set_bit());
+
+		current_sense
+	}
+
+	/// Program the analog watchdog with the over-current limit from `config`.
+	pub fn set_limit(&mut self, config: &Config) {
+		let high = if config.over_current_limit == 0 {
+			// The watchdog can never fire
+			FULL_SCALE_COUNTS
+		} else {
+			let limit_ma = u32::from(config.over_current_limit) * 50;
+			let counts =
+				(limit_ma * FULL_SCALE_COUNTS) / u32::from(config.current_full_scale_ma.max(1));
+			counts.min(FULL_SCALE_COUNTS)
+		};
+		self.dev
+			.tr
+			.write(|w| unsafe { w.ht().bits(high as u16).lt().bits(0) });
+	}
+
+	/// Get the latest reading, in milliamps.
+	pub fn sample(&mut self, config: &Config) -> u16 {
+		let counts = u32::from(self.dev.dr.read().data().bits());
+		let current_ma = (counts * u32::from(config.current_full_scale_ma)) / FULL_SCALE_COUNTS;
+		current_ma.min(u32::from(u16::MAX)) as u16
+	}
+
+	/// Call this from the ADC interrupt.
+	///
+	/// Returns `true` if the over-current limit was crossed.
+	pub fn handle_isr(&mut self) -> bool {
+		if self.dev.isr.read().awd().bit_is_set() {
+			// Write 1 to clear
+			self.dev.isr.write(|w| w.awd().set_bit());
+			true
+		} else {
+			false
+		}
+	}
+}
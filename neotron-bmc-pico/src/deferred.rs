@@ -0,0 +1,144 @@
+//! # Deferred Operations
+//!
+//! Some register writes kick off work which takes far longer than one SPI
+//! transaction (like saving the config to flash). Those writes answer with
+//! `Busy`, and the work is tracked here until it finishes. Each operation gets
+//! a token, so the host can tell its operation apart from an earlier one.
+//!
+//! There is only one slot - a second long operation can't start until the
+//! host has collected the result of the first.
+
+/// Where a deferred operation has got to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum DeferredState {
+	/// Nothing is running, and there's no result waiting
+	Idle = 0,
+	/// The operation is still running
+	Pending = 1,
+	/// The operation has finished and the result is waiting for the host
+	Done = 2,
+}
+
+/// Tracks the one deferred operation we can have in flight.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Deferred {
+	/// Identifies the most recent operation. Zero means there hasn't been one.
+	token: u8,
+	/// Where the most recent operation has got to
+	state: DeferredState,
+	/// The register which was written to start the most recent operation
+	register: u8,
+	/// What the most recent operation produced (the meaning depends on the
+	/// register)
+	result: u8,
+}
+
+impl Deferred {
+	/// How many bytes the Deferred Operation register holds.
+	pub const SIZE: usize = 4;
+
+	/// Create a new, idle, tracker.
+	pub const fn new() -> Deferred {
+		Deferred {
+			token: 0,
+			state: DeferredState::Idle,
+			register: 0,
+			result: 0,
+		}
+	}
+
+	/// Get the token for the most recent operation.
+	pub fn token(&self) -> u8 {
+		self.token
+	}
+
+	/// Where has the most recent operation got to?
+	pub fn state(&self) -> DeferredState {
+		self.state
+	}
+
+	/// Start an operation on behalf of `register`.
+	///
+	/// Returns the new token, or `None` if an operation is already running. A
+	/// result which the host hasn't collected yet is thrown away.
+	pub fn start(&mut self, register: u8) -> Option<u8> {
+		if self.state == DeferredState::Pending {
+			return None;
+		}
+		// Skip zero when we wrap
+		self.token = self.token.checked_add(1).unwrap_or(1);
+		self.state = DeferredState::Pending;
+		self.register = register;
+		self.result = 0;
+		Some(self.token)
+	}
+
+	/// Record that the running operation has finished, producing `result`.
+	///
+	/// Returns `false` if nothing was running.
+	pub fn finish(&mut self, result: u8) -> bool {
+		if self.state != DeferredState::Pending {
+			return false;
+		}
+		self.state = DeferredState::Done;
+		self.result = result;
+		true
+	}
+
+	/// The host has collected the result for `token`, so go back to idle.
+	///
+	/// Returns `false` if `token` isn't a finished operation.
+	pub fn acknowledge(&mut self, token: u8) -> bool {
+		if self.state != DeferredState::Done || token != self.token {
+			return false;
+		}
+		self.state = DeferredState::Idle;
+		true
+	}
+
+	/// Convert to bytes for the Deferred Operation register.
+	///
+	/// That's the token, the state, the register and then the result.
+	pub fn as_bytes(&self) -> [u8; Self::SIZE] {
+		[self.token, self.state as u8, self.register, self.result]
+	}
+}
+
+impl Default for Deferred {
+	fn default() -> Self {
+		Deferred::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn one_at_a_time() {
+		let mut deferred = Deferred::new();
+		assert_eq!(deferred.as_bytes(), [0, 0, 0, 0]);
+		assert_eq!(deferred.start(0x0F), Some(1));
+		assert_eq!(deferred.start(0x0F), None);
+		assert!(!deferred.acknowledge(1));
+		assert!(deferred.finish(0x02));
+		assert!(!deferred.finish(0x03));
+		assert_eq!(deferred.as_bytes(), [1, 2, 0x0F, 0x02]);
+		assert!(!deferred.acknowledge(2));
+		assert!(deferred.acknowledge(1));
+		assert_eq!(deferred.state(), DeferredState::Idle);
+		assert_eq!(deferred.start(0x0F), Some(2));
+	}
+
+	#[test]
+	fn token_skips_zero() {
+		let mut deferred = Deferred::new();
+		for _ in 0..255 {
+			deferred.start(0x0F);
+			deferred.finish(0);
+		}
+		assert_eq!(deferred.token(), 255);
+		assert_eq!(deferred.start(0x0F), Some(1));
+	}
+}
@@ -0,0 +1,197 @@
+//! # Rotary Encoder
+//!
+//! With the `encoder` feature, a front panel knob (a quadrature rotary
+//! encoder) can be wired to the I²C pins - PB6 is phase A and PB7 is phase B,
+//! each pulled up, with the common pin to ground. The host can read how far
+//! it has been turned and how fast, so the OS can use it for volume or
+//! scrolling.
+//!
+//! The EXTI interrupt feeds every edge on either pin to a [`Quadrature`]
+//! decoder, and a task turns the total into the [`Encoder`] registers. We
+//! expect the common kind of encoder, with four edges per detent, which rests
+//! with both pins high.
+
+/// How many edges make a step, for an encoder which rests with both pins high
+const EDGES_PER_STEP: i8 = 4;
+
+/// The pin levels when the encoder is resting at a detent (both pulled up)
+const REST_STATE: u8 = 0b11;
+
+/// Which way each change of pin levels turns the encoder, indexed by the old
+/// levels and the new levels (`A << 1 | B` for each). Impossible changes,
+/// where both pins moved at once, count as nothing.
+const TRANSITIONS: [i8; 16] = [0, -1, 1, 0, 1, 0, 0, -1, -1, 0, 0, 1, 0, 1, -1, 0];
+
+/// Decodes the two phases of a rotary encoder into steps.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Quadrature {
+	/// The pin levels we last saw, as `A << 1 | B`
+	last: u8,
+	/// Edges since the encoder was last at rest, positive for clockwise
+	edges: i8,
+}
+
+impl Quadrature {
+	/// Create a decoder, with the encoder at rest.
+	pub const fn new() -> Quadrature {
+		Quadrature {
+			last: REST_STATE,
+			edges: 0,
+		}
+	}
+
+	/// Feed in the levels on the A and B pins, after either of them changes.
+	///
+	/// Returns `1` for a step clockwise (A leading B), `-1` for a step
+	/// anti-clockwise, or `0` if the encoder hasn't come to rest yet. A step
+	/// only counts once the encoder reaches the next detent, having moved at
+	/// least half way, so contact bounce and missed edges can't add up.
+	pub fn update(&mut self, a: bool, b: bool) -> i8 {
+		let state = (u8::from(a) << 1) | u8::from(b);
+		let edge = TRANSITIONS[usize::from((self.last << 2) | state)];
+		self.last = state;
+		self.edges = self
+			.edges
+			.saturating_add(edge)
+			.clamp(-EDGES_PER_STEP, EDGES_PER_STEP);
+		if state != REST_STATE {
+			return 0;
+		}
+		let edges = core::mem::replace(&mut self.edges, 0);
+		if edges >= EDGES_PER_STEP / 2 {
+			1
+		} else if edges <= -EDGES_PER_STEP / 2 {
+			-1
+		} else {
+			0
+		}
+	}
+}
+
+impl Default for Quadrature {
+	fn default() -> Self {
+		Quadrature::new()
+	}
+}
+
+/// The encoder position and speed, as the host sees them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Encoder {
+	/// The total steps from the decoder, when we last looked
+	total: i16,
+	/// The total when the host last set the position to zero
+	zero: i16,
+	/// Steps per second, over the last poll
+	velocity: i16,
+}
+
+impl Encoder {
+	/// How many bytes the Encoder Position and Encoder Velocity registers
+	/// hold.
+	pub const SIZE: usize = 2;
+
+	/// Create the encoder state, at position zero.
+	pub const fn new() -> Encoder {
+		Encoder {
+			total: 0,
+			zero: 0,
+			velocity: 0,
+		}
+	}
+
+	/// Record the total steps from the decoder, `interval_ms` after the last
+	/// time.
+	///
+	/// Returns `true` if the encoder has moved.
+	pub fn update(&mut self, total: i16, interval_ms: u32) -> bool {
+		let moved = i32::from(total.wrapping_sub(self.total));
+		self.total = total;
+		let velocity = (moved * 1000) / (interval_ms.max(1) as i32);
+		self.velocity = velocity.clamp(i32::from(i16::MIN), i32::from(i16::MAX)) as i16;
+		moved != 0
+	}
+
+	/// Steps since the host last set the position to zero, positive for
+	/// clockwise.
+	pub fn position(&self) -> i16 {
+		self.total.wrapping_sub(self.zero)
+	}
+
+	/// Set the current position to zero.
+	pub fn reset(&mut self) {
+		self.zero = self.total;
+	}
+
+	/// Steps per second, positive for clockwise.
+	pub fn velocity(&self) -> i16 {
+		self.velocity
+	}
+}
+
+impl Default for Encoder {
+	fn default() -> Self {
+		Encoder::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Turn the encoder one detent, returning the steps the decoder reports.
+	fn turn(decoder: &mut Quadrature, clockwise: bool) -> i8 {
+		let levels = if clockwise {
+			[(false, true), (false, false), (true, false), (true, true)]
+		} else {
+			[(true, false), (false, false), (false, true), (true, true)]
+		};
+		levels.iter().map(|&(a, b)| decoder.update(a, b)).sum()
+	}
+
+	#[test]
+	fn quadrature() {
+		let mut decoder = Quadrature::new();
+		assert_eq!(turn(&mut decoder, true), 1);
+		assert_eq!(turn(&mut decoder, true), 1);
+		assert_eq!(turn(&mut decoder, false), -1);
+		// Bouncing on one pin goes nowhere
+		assert_eq!(decoder.update(false, true), 0);
+		assert_eq!(decoder.update(true, true), 0);
+		assert_eq!(decoder.update(false, true), 0);
+		assert_eq!(decoder.update(true, true), 0);
+		// Nor does going a little way and coming back
+		assert_eq!(decoder.update(false, true), 0);
+		assert_eq!(decoder.update(false, false), 0);
+		assert_eq!(decoder.update(false, true), 0);
+		assert_eq!(decoder.update(true, true), 0);
+		// A missed edge still counts, once it comes to rest
+		assert_eq!(decoder.update(false, true), 0);
+		assert_eq!(decoder.update(true, false), 0);
+		assert_eq!(decoder.update(true, true), 1);
+		assert_eq!(decoder.update(true, false), 0);
+		assert_eq!(decoder.update(false, true), 0);
+		assert_eq!(decoder.update(true, true), -1);
+	}
+
+	#[test]
+	fn position_and_velocity() {
+		let mut encoder = Encoder::new();
+		assert!(!encoder.update(0, 50));
+		assert!(encoder.update(5, 50));
+		assert_eq!(encoder.position(), 5);
+		assert_eq!(encoder.velocity(), 100);
+		encoder.reset();
+		assert_eq!(encoder.position(), 0);
+		assert!(encoder.update(2, 50));
+		assert_eq!(encoder.position(), -3);
+		assert_eq!(encoder.velocity(), -60);
+		assert!(!encoder.update(2, 50));
+		assert_eq!(encoder.velocity(), 0);
+		// The total wraps around
+		let mut encoder = Encoder::new();
+		assert!(encoder.update(i16::MAX, 50_000));
+		assert!(encoder.update(i16::MIN, 50));
+		assert_eq!(encoder.position(), i16::MIN);
+		assert_eq!(encoder.velocity(), 20);
+	}
+}
@@ -0,0 +1,172 @@
+//! # Event Log
+//!
+//! A small ring-buffer of timestamped events (power transitions, resets,
+//! protocol errors, etc), which the host can read over SPI. Useful for
+//! working out why the system turned itself off overnight.
+//!
+//! When the log is full, the oldest entry is discarded.
+
+/// The kinds of event we can log.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum EventKind {
+	/// No event. Used to pad out reads when the log is empty.
+	None = 0x00,
+	/// The BMC has booted.
+	BmcStart = 0x01,
+	/// The main board was powered on. Data is a [`PowerOnSource`].
+	PowerOn = 0x02,
+	/// The main board was powered off.
+	PowerOff = 0x03,
+	/// The main board was reset. Data is a [`ResetSource`].
+	Reset = 0x04,
+	/// A bad request arrived (over SPI, I²C or the UART). Data is the protocol error code.
+	ProtocolError = 0x05,
+	/// The main board drew too much current, so we cut the power.
+	OverCurrent = 0x06,
+	/// The host didn't shut down when the BMC got too hot, so we cut the
+	/// power. Data is the temperature, in °C, as an `i8`.
+	OverTemperature = 0x07,
+	/// The host asked to be suspended.
+	Suspend = 0x08,
+	/// The main board was resumed from suspend. Data is a [`PowerOnSource`].
+	Resume = 0x09,
+	/// Our supply sagged, so we held the main board in reset.
+	LowVoltage = 0x0A,
+	/// We were asked to power on, but there is no main board. Data is a
+	/// [`PowerOnSource`].
+	NoMainBoard = 0x0B,
+	/// Someone held down the power and reset buttons, so we cut the power.
+	ForcedOff = 0x0C,
+}
+
+/// What caused the main board to be reset.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum ResetSource {
+	/// Someone pressed the reset button.
+	Button = 0x00,
+	/// Someone held down the keyboard reset chord.
+	Keyboard = 0x01,
+	/// The host didn't kick the host watchdog in time.
+	Watchdog = 0x02,
+	/// The host wrote to the Main Board Reset register.
+	Host = 0x03,
+}
+
+/// What caused the main board to be powered on.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum PowerOnSource {
+	/// Someone pressed the power button.
+	Button = 0x00,
+	/// A byte arrived from the PS/2 keyboard.
+	Keyboard = 0x01,
+	/// A byte arrived on the UART.
+	Uart = 0x02,
+	/// The BMC powered up, and the power restore policy said to power on.
+	PowerRestore = 0x03,
+	/// Someone wrote to the Power Control register (over the UART, as the
+	/// host can't do it whilst the main board is off).
+	PowerControl = 0x04,
+	/// The RTC alarm went off.
+	Alarm = 0x05,
+}
+
+/// A single entry in the [`EventLog`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub struct Event {
+	/// Milliseconds since the BMC booted (wraps after ~49 days)
+	pub timestamp_ms: u32,
+	/// What happened
+	pub kind: EventKind,
+	/// Extra information, specific to `kind`
+	pub data: u8,
+}
+
+impl Event {
+	/// How many bytes an event occupies when sent to the host.
+	pub const SIZE: usize = 6;
+
+	/// An empty event, used to pad out reads.
+	pub const EMPTY: Event = Event {
+		timestamp_ms: 0,
+		kind: EventKind::None,
+		data: 0,
+	};
+
+	/// Create a new event.
+	pub const fn new(timestamp_ms: u32, kind: EventKind, data: u8) -> Event {
+		Event {
+			timestamp_ms,
+			kind,
+			data,
+		}
+	}
+
+	/// Convert to bytes for transmission.
+	///
+	/// The timestamp is sent first as a `u32le`, then the kind, then the data.
+	pub fn as_bytes(&self) -> [u8; Self::SIZE] {
+		let ts = self.timestamp_ms.to_le_bytes();
+		[ts[0], ts[1], ts[2], ts[3], self.kind as u8, self.data]
+	}
+}
+
+/// A fixed-size log of the last `N` events.
+pub struct EventLog<const N: usize> {
+	entries: [Event; N],
+	/// Index of the oldest entry
+	head: usize,
+	/// How many entries are valid
+	len: usize,
+}
+
+impl<const N: usize> EventLog<N> {
+	/// Create a new, empty, event log.
+	pub const fn new() -> EventLog<N> {
+		EventLog {
+			entries: [Event::EMPTY; N],
+			head: 0,
+			len: 0,
+		}
+	}
+
+	/// Add an event to the log, discarding the oldest event if the log is full.
+	pub fn push(&mut self, event: Event) {
+		let idx = (self.head + self.len) % N;
+		self.entries[idx] = event;
+		if self.len == N {
+			self.head = (self.head + 1) % N;
+		} else {
+			self.len += 1;
+		}
+	}
+
+	/// Remove the oldest event from the log.
+	pub fn pop(&mut self) -> Option<Event> {
+		if self.len == 0 {
+			return None;
+		}
+		let event = self.entries[self.head];
+		self.head = (self.head + 1) % N;
+		self.len -= 1;
+		Some(event)
+	}
+
+	/// How many events are in the log?
+	pub fn len(&self) -> usize {
+		self.len
+	}
+
+	/// Is the log empty?
+	pub fn is_empty(&self) -> bool {
+		self.len == 0
+	}
+
+	/// Throw away all the events in the log.
+	pub fn clear(&mut self) {
+		self.head = 0;
+		self.len = 0;
+	}
+}
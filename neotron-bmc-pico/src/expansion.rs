@@ -0,0 +1,283 @@
+//! # Expansion Pins
+//!
+//! Some of the BMC's pins aren't used by this firmware, so the host can use
+//! them as general purpose I/O, without the BMC being reflashed for each new
+//! main board revision.
+//!
+//! On the Neotron Pico, the spare pins are the I²C pins - PB6 is GPIO 0 and
+//! PB7 is GPIO 1. The `fan`, `encoder`, `i2c-target`, `ps2-power` and
+//! `status-led` features use those pins, so there are no spare pins in those
+//! builds.
+//!
+//! Each pin has one bit in the direction, output and input bitmaps, and two
+//! bits in the pull bitmap. A task copies the settings to the pins, and the
+//! pin levels back, every few milliseconds.
+//!
+//! GPIO 0 can also be a PWM output (from TIM16), for things like case
+//! lighting or an LCD backlight.
+
+/// Pull bits for a pin with no pull-up or pull-down resistor
+pub const PULL_NONE: u8 = 0b00;
+/// Pull bits for a pin with its pull-up resistor turned on
+pub const PULL_UP: u8 = 0b01;
+/// Pull bits for a pin with its pull-down resistor turned on
+pub const PULL_DOWN: u8 = 0b10;
+
+/// How many pins the pull bitmap has room for
+const GPIO_MAX_PINS: u8 = 4;
+
+/// A bit for each GPIO pin which exists in this build.
+#[cfg(not(any(
+	feature = "fan",
+	feature = "encoder",
+	feature = "i2c-target",
+	feature = "ps2-power",
+	feature = "status-led"
+)))]
+pub const GPIO_AVAILABLE: u8 = 0b0000_0011;
+/// A bit for each GPIO pin which exists in this build.
+#[cfg(any(
+	feature = "fan",
+	feature = "encoder",
+	feature = "i2c-target",
+	feature = "ps2-power",
+	feature = "status-led"
+))]
+pub const GPIO_AVAILABLE: u8 = 0;
+
+/// Bit in the PWM Control register which turns the PWM output on
+pub const PWM_ENABLE: u8 = 1 << 0;
+
+/// The PWM frequency we start with, in Hz
+pub const PWM_DEFAULT_FREQUENCY_HZ: u16 = 1000;
+
+/// The host's settings for the GPIO pins, and their levels.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Gpio {
+	/// One bit per pin - 1 is an output
+	direction: u8,
+	/// One bit per pin - the level to drive, if it's an output
+	output: u8,
+	/// Two bits per pin (see `PULL_UP`, etc)
+	pull: u8,
+	/// One bit per pin - the level last seen on the pin
+	input: u8,
+}
+
+impl Gpio {
+	/// Create the GPIO state, with every pin a floating input.
+	pub const fn new() -> Gpio {
+		Gpio {
+			direction: 0,
+			output: 0,
+			pull: 0,
+			input: 0,
+		}
+	}
+
+	/// Which pins are outputs?
+	pub fn direction(&self) -> u8 {
+		self.direction
+	}
+
+	/// Choose which pins are outputs. Bits for pins which don't exist are
+	/// ignored.
+	pub fn set_direction(&mut self, direction: u8) {
+		self.direction = direction & GPIO_AVAILABLE;
+	}
+
+	/// What level should each output pin drive?
+	pub fn output(&self) -> u8 {
+		self.output
+	}
+
+	/// Set the level each output pin drives. Bits for pins which don't exist
+	/// are ignored.
+	pub fn set_output(&mut self, output: u8) {
+		self.output = output & GPIO_AVAILABLE;
+	}
+
+	/// Which pull resistors are turned on?
+	pub fn pull(&self) -> u8 {
+		self.pull
+	}
+
+	/// Get the pull bits for one pin (see `PULL_UP`, etc).
+	pub fn pin_pull(&self, pin: u8) -> u8 {
+		(self.pull >> (pin * 2)) & 0b11
+	}
+
+	/// Choose which pull resistors are turned on.
+	///
+	/// Returns `false` (and changes nothing) if any pin has the reserved
+	/// value `0b11`. Bits for pins which don't exist are ignored.
+	pub fn set_pull(&mut self, pull: u8) -> bool {
+		if (0..GPIO_MAX_PINS).any(|pin| ((pull >> (pin * 2)) & 0b11) == 0b11) {
+			return false;
+		}
+		let mut mask = 0;
+		for pin in 0..GPIO_MAX_PINS {
+			if (GPIO_AVAILABLE & (1 << pin)) != 0 {
+				mask |= 0b11 << (pin * 2);
+			}
+		}
+		self.pull = pull & mask;
+		true
+	}
+
+	/// Choose the pull resistor for one pin (see `PULL_UP`, etc), leaving
+	/// the others alone.
+	pub fn set_pin_pull(&mut self, pin: u8, pull: u8) -> bool {
+		let shift = pin * 2;
+		self.set_pull((self.pull & !(0b11 << shift)) | ((pull & 0b11) << shift))
+	}
+
+	/// What level was each pin at, when we last looked?
+	pub fn input(&self) -> u8 {
+		self.input
+	}
+
+	/// Record the level of each pin.
+	pub fn set_input(&mut self, input: u8) {
+		self.input = input & GPIO_AVAILABLE;
+	}
+}
+
+/// The host's settings for the PWM output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pwm {
+	/// The frequency, in Hz
+	pub frequency_hz: u16,
+	/// The duty cycle, as a percentage
+	pub duty_percent: u8,
+	/// See `PWM_ENABLE`
+	pub control: u8,
+}
+
+impl Pwm {
+	/// Create the PWM settings, with the output turned off.
+	pub const fn new() -> Pwm {
+		Pwm {
+			frequency_hz: PWM_DEFAULT_FREQUENCY_HZ,
+			duty_percent: 0,
+			control: 0,
+		}
+	}
+
+	/// Should GPIO 0 be a PWM output?
+	pub fn is_enabled(&self) -> bool {
+		(self.control & PWM_ENABLE) != 0 && self.frequency_hz != 0
+	}
+
+	/// Work out the timer settings which give this frequency and duty cycle,
+	/// when the timer counts at `clock_hz`.
+	pub fn timing(&self, clock_hz: u32) -> PwmTiming {
+		let period_ticks = clock_hz / u32::from(self.frequency_hz.max(1));
+		// Use the smallest prescaler we can, for the finest duty cycle steps
+		let prescaler = period_ticks / 0x1_0000;
+		let period = (period_ticks / (prescaler + 1)).max(1);
+		let compare = (period * u32::from(self.duty_percent.min(100))) / 100;
+		PwmTiming {
+			prescaler: prescaler.min(0xFFFF) as u16,
+			reload: (period - 1) as u16,
+			compare: compare as u16,
+		}
+	}
+}
+
+impl Default for Pwm {
+	fn default() -> Self {
+		Pwm::new()
+	}
+}
+
+/// Settings for a timer generating a PWM output.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PwmTiming {
+	/// The timer counts at its clock divided by this plus one
+	pub prescaler: u16,
+	/// The timer counts from zero up to this, then wraps
+	pub reload: u16,
+	/// The output is high while the count is below this
+	pub compare: u16,
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[cfg(not(any(
+		feature = "fan",
+		feature = "encoder",
+		feature = "i2c-target",
+		feature = "ps2-power",
+		feature = "status-led"
+	)))]
+	#[test]
+	fn gpio_settings() {
+		let mut gpio = Gpio::new();
+		gpio.set_direction(0xFF);
+		assert_eq!(gpio.direction(), 0b11);
+		gpio.set_output(0b10);
+		assert_eq!(gpio.output(), 0b10);
+		// Pins 2 and 3 don't exist
+		assert!(gpio.set_pull(0x50 | (PULL_DOWN << 2) | PULL_UP));
+		assert_eq!(gpio.pull(), (PULL_DOWN << 2) | PULL_UP);
+		assert_eq!(gpio.pin_pull(1), PULL_DOWN);
+		// Reserved values aren't allowed
+		assert!(!gpio.set_pull(0b11));
+		assert!(!gpio.set_pull(0xC0));
+		assert_eq!(gpio.pin_pull(0), PULL_UP);
+		gpio.set_input(0xFE);
+		assert_eq!(gpio.input(), 0b10);
+	}
+
+	#[test]
+	fn pwm_timing() {
+		let mut pwm = Pwm::new();
+		assert!(!pwm.is_enabled());
+		pwm.control = PWM_ENABLE;
+		pwm.duty_percent = 25;
+		assert!(pwm.is_enabled());
+		assert_eq!(
+			pwm.timing(48_000_000),
+			PwmTiming {
+				prescaler: 0,
+				reload: 47_999,
+				compare: 12_000
+			}
+		);
+		// Too slow for a 16-bit counter on its own
+		pwm.frequency_hz = 10;
+		assert_eq!(
+			pwm.timing(8_000_000),
+			PwmTiming {
+				prescaler: 12,
+				reload: 61_537,
+				compare: 15_384
+			}
+		);
+		// Duty cycles over 100% are flat out
+		pwm.duty_percent = 200;
+		let timing = pwm.timing(8_000_000);
+		assert_eq!(timing.compare, timing.reload + 1);
+		pwm.frequency_hz = 0;
+		assert!(!pwm.is_enabled());
+	}
+
+	#[cfg(any(
+		feature = "fan",
+		feature = "encoder",
+		feature = "i2c-target",
+		feature = "ps2-power",
+		feature = "status-led"
+	))]
+	#[test]
+	fn no_gpio_with_fan() {
+		let mut gpio = Gpio::new();
+		gpio.set_direction(0xFF);
+		assert_eq!(gpio.direction(), 0);
+		assert!(gpio.set_pull(PULL_UP));
+		assert_eq!(gpio.pull(), 0);
+	}
+}
@@ -0,0 +1,131 @@
+//! # Forced Power-Off
+//!
+//! Whatever the button actions say, whatever the host has asked for, and
+//! whatever the power manager thinks is going on, holding down both the
+//! power and reset buttons for `HOLD_MS` cuts the DC power. It is the front
+//! panel's last resort, for when a bad configuration (or a bug) stops the
+//! power button from working.
+//!
+//! So this is kept apart from the rest of the button handling - it looks at
+//! the button levels before they are debounced or given an action, and the
+//! power manager obeys [`crate::power::PowerRequest::ForcedOff`] in every
+//! state. It can't be turned off.
+
+/// How long both buttons must be held down, in milliseconds
+pub const HOLD_MS: u32 = 5000;
+
+/// Watches for both buttons being held down.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForcedOff {
+	/// When both buttons went down, if they still are
+	held_since_ms: Option<u32>,
+	/// We have already cut the power for this hold
+	fired: bool,
+}
+
+impl ForcedOff {
+	/// Create the watcher, with both buttons released.
+	pub const fn new() -> ForcedOff {
+		ForcedOff {
+			held_since_ms: None,
+			fired: false,
+		}
+	}
+
+	/// Feed in the button levels (`true` means pressed). Call this every
+	/// time the buttons are polled.
+	///
+	/// Returns true, once per hold, when the power must be cut.
+	pub fn update(&mut self, power_pressed: bool, reset_pressed: bool, now_ms: u32) -> bool {
+		if !(power_pressed && reset_pressed) {
+			self.held_since_ms = None;
+			self.fired = false;
+			return false;
+		}
+		let since_ms = *self.held_since_ms.get_or_insert(now_ms);
+		if self.fired || now_ms.wrapping_sub(since_ms) < HOLD_MS {
+			return false;
+		}
+		self.fired = true;
+		true
+	}
+}
+
+impl Default for ForcedOff {
+	fn default() -> Self {
+		ForcedOff::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Poll every 75 ms, like the firmware, from `start_ms` until
+	/// `end_ms`, and return when we were told to cut the power.
+	fn hold(
+		forced_off: &mut ForcedOff,
+		power_pressed: bool,
+		reset_pressed: bool,
+		start_ms: u32,
+		end_ms: u32,
+	) -> Vec<u32> {
+		let mut fired = Vec::new();
+		let mut now_ms = start_ms;
+		while now_ms.wrapping_sub(start_ms) <= end_ms.wrapping_sub(start_ms) {
+			if forced_off.update(power_pressed, reset_pressed, now_ms) {
+				fired.push(now_ms);
+			}
+			now_ms = now_ms.wrapping_add(75);
+		}
+		fired
+	}
+
+	#[test]
+	fn both_buttons() {
+		let mut forced_off = ForcedOff::new();
+		assert!(!forced_off.update(true, true, 1000));
+		assert!(!forced_off.update(true, true, 1000 + HOLD_MS - 1));
+		assert!(forced_off.update(true, true, 1000 + HOLD_MS));
+		// Only once per hold, however long it goes on
+		assert_eq!(hold(&mut forced_off, true, true, 6100, 60_000), vec![]);
+	}
+
+	#[test]
+	fn one_button() {
+		let mut forced_off = ForcedOff::new();
+		assert_eq!(hold(&mut forced_off, true, false, 0, 60_000), vec![]);
+		assert_eq!(hold(&mut forced_off, false, true, 0, 60_000), vec![]);
+		assert_eq!(hold(&mut forced_off, false, false, 0, 60_000), vec![]);
+	}
+
+	#[test]
+	fn release_starts_again() {
+		let mut forced_off = ForcedOff::new();
+		assert_eq!(hold(&mut forced_off, true, true, 0, 4950), vec![]);
+		// Letting go of either button starts the count again
+		assert!(!forced_off.update(true, false, 5025));
+		assert_eq!(hold(&mut forced_off, true, true, 5100, 10_050), vec![]);
+		assert!(!forced_off.update(false, true, 10_125));
+		let fired = hold(&mut forced_off, true, true, 10_200, 20_000);
+		assert_eq!(fired, vec![10_200 + 5025]);
+		// And after firing, a second hold fires again
+		assert!(!forced_off.update(false, false, 20_100));
+		assert!(!forced_off.update(true, true, 20_200));
+		assert!(forced_off.update(true, true, 20_200 + HOLD_MS));
+	}
+
+	#[test]
+	fn clock_wraps() {
+		let mut forced_off = ForcedOff::new();
+		let start_ms = u32::MAX - 1000;
+		let fired = hold(
+			&mut forced_off,
+			true,
+			true,
+			start_ms,
+			start_ms.wrapping_add(10_000),
+		);
+		assert_eq!(fired, vec![start_ms.wrapping_add(5025)]);
+	}
+}
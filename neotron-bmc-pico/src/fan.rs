@@ -0,0 +1,145 @@
+//! # Case Fan Control
+//!
+//! Some Neotron builds add a 4-wire case fan. The Pico has no spare timer
+//! pins, so the fan takes over the I²C pins:
+//!
+//! * PB6 (TIM16_CH1N) drives the fan's PWM input at 25 kHz
+//! * PB7 (EXTI7) counts pulses from the fan's tachometer output
+//!
+//! The duty cycle either follows a fixed curve based on the STM32's internal
+//! temperature sensor, or is set by the host.
+
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use stm32f0xx_hal::pac;
+
+/// The frequency Intel's 4-wire fan specification asks for
+const PWM_FREQUENCY_HZ: u32 = 25_000;
+
+/// The TIM16 auto-reload value which gives `PWM_FREQUENCY_HZ`.
+const PWM_PERIOD: u32 = (crate::standby::RUN_CLOCK_HZ / PWM_FREQUENCY_HZ) - 1;
+
+/// Fans give two tachometer pulses per revolution
+const PULSES_PER_REV: u32 = 2;
+
+/// Below this temperature (in °C) the fan runs at `MIN_DUTY_PERCENT`
+const CURVE_LOW_C: i8 = 30;
+
+/// Above this temperature (in °C) the fan runs flat out
+const CURVE_HIGH_C: i8 = 60;
+
+/// The slowest we run the fan in automatic mode, as a percentage
+const MIN_DUTY_PERCENT: u8 = 30;
+
+/// The temperature sensor reading at 30°C and 3.3V, measured at the factory
+const TS_CAL1_ADDRESS: *const u16 = 0x1FFF_F7B8 as *const u16;
+
+/// The ADC channel connected to the internal temperature sensor
+const TS_CHANNEL: u32 = 1 << 16;
+
+/// Tachometer pulses counted since `take_tach_pulses` was last called
+static TACH_PULSES: AtomicU16 = AtomicU16::new(0);
+
+/// Count a pulse from the tachometer.
+///
+/// Call this from the EXTI interrupt. It must not be called from anywhere
+/// else, as the increment is not atomic.
+pub fn count_tach_pulse() {
+	let pulses = TACH_PULSES.load(Ordering::Relaxed);
+	TACH_PULSES.store(pulses.wrapping_add(1), Ordering::Relaxed);
+}
+
+/// Get the number of tachometer pulses since we last asked, and start
+/// counting again.
+pub fn take_tach_pulses() -> u16 {
+	cortex_m::interrupt::free(|_cs| {
+		let pulses = TACH_PULSES.load(Ordering::Relaxed);
+		TACH_PULSES.store(0, Ordering::Relaxed);
+		pulses
+	})
+}
+
+/// Work out the duty cycle for a given temperature.
+///
+/// We run at `MIN_DUTY_PERCENT` below `CURVE_LOW_C`, 100% above
+/// `CURVE_HIGH_C`, and ramp linearly in between.
+pub fn duty_for_temperature(temperature_c: i8) -> u8 {
+	if temperature_c <= CURVE_LOW_C {
+		MIN_DUTY_PERCENT
+	} else if temperature_c >= CURVE_HIGH_C {
+		100
+	} else {
+		let above = i32::from(temperature_c - CURVE_LOW_C);
+		let span = i32::from(CURVE_HIGH_C - CURVE_LOW_C);
+		let extra = (above * i32::from(100 - MIN_DUTY_PERCENT)) / span;
+		MIN_DUTY_PERCENT + extra as u8
+	}
+}
+
+/// Work out the fan speed from tachometer pulses counted over `interval_ms`.
+pub fn rpm_from_pulses(pulses: u16, interval_ms: u32) -> u16 {
+	if interval_ms == 0 {
+		return 0;
+	}
+	let rpm = (u32::from(pulses) * 60_000) / (PULSES_PER_REV * interval_ms);
+	rpm.min(u32::from(u16::MAX)) as u16
+}
+
+/// Drives the fan PWM output and reads the temperature sensor.
+pub struct Fan {
+	tim: pac::TIM16,
+	adc: pac::ADC,
+}
+
+impl Fan {
+	/// Set up TIM16 for PWM output, and the ADC for reading the temperature
+	/// sensor.
+	///
+	/// PB6 must already be in AF2 (TIM16_CH1N) mode.
+	pub fn new(tim: pac::TIM16, adc: pac::ADC) -> Fan {
+		// Safety: we only touch the TIM16 and ADC enable bits, which nothing
+		// else modifies after init.
+		let rcc = unsafe { &*pac::RCC::ptr() };
+		rcc.apb2enr
+			.modify(|_r, w| w.tim16en().set_bit().adcen().set_bit());
+
+		// PWM mode 1 on channel 1, driven out of the complementary pin
+		tim.psc.write(|w| unsafe { w.bits(0) });
+		tim.arr.write(|w| unsafe { w.bits(PWM_PERIOD) });
+		tim.ccr1.write(|w| unsafe { w.bits(0) });
+		tim.ccmr1_output()
+			.write(|w| unsafe { w.oc1m().bits(0b110).oc1pe().set_bit() });
+		tim.ccer.write(|w| w.cc1ne().set_bit());
+		tim.bdtr.write(|w| w.moe().set_bit());
+		tim.cr1.write(|w| w.arpe().set_bit().cen().set_bit());
+
+		// Clock the ADC from PCLK/4, and calibrate it
+		adc.cfgr2.write(|w| unsafe { w.ckmode().bits(0b10) });
+		adc.cr.modify(|_r, w| w.adcal().set_bit());
+		while adc.cr.read().adcal().bit_is_set() {}
+		// The temperature sensor needs a sample time of at least 17.1us
+		adc.smpr.write(|w| unsafe { w.smp().bits(0b111) });
+		adc.chselr.write(|w| unsafe { w.bits(TS_CHANNEL) });
+		adc.ccr.modify(|_r, w| w.tsen().set_bit());
+		adc.cr.modify(|_r, w| w.aden().set_bit());
+		while adc.isr.read().adrdy().bit_is_clear() {}
+
+		Fan { tim, adc }
+	}
+
+	/// Set the PWM duty cycle, as a percentage.
+	pub fn set_duty(&mut self, duty_percent: u8) {
+		let duty = (u32::from(duty_percent.min(100)) * (PWM_PERIOD + 1)) / 100;
+		self.tim.ccr1.write(|w| unsafe { w.bits(duty) });
+	}
+
+	/// Read the internal temperature sensor, in °C.
+	pub fn read_temperature(&mut self) -> i8 {
+		self.adc.cr.modify(|_r, w| w.adstart().set_bit());
+		while self.adc.isr.read().eoc().bit_is_clear() {}
+		let counts = self.adc.dr.read().data().bits();
+		// Safety: this is a factory-programmed value in system memory
+		let cal = unsafe { core::ptr::read_volatile(TS_CAL1_ADDRESS) };
+		crate::analog::temperature_c(cal, counts)
+	}
+}
@@ -0,0 +1,158 @@
+//! # Fault Codes
+//!
+//! Some things can go wrong before the host can ask us about them - whilst we
+//! are starting up, and the SPI bus isn't being answered yet. A defmt log
+//! only helps someone with a debug probe attached, so instead we sound a code
+//! in Morse on the buzzer, and blink it on the power LED at the same time.
+//!
+//! | Fault                | Letter | Code    | What happens next                       |
+//! | -------------------- | ------ | ------- | --------------------------------------- |
+//! | [`Fault::Supply`]    | V      | `...-`  | Repeats forever, main board held off    |
+//! | [`Fault::Config`]    | C      | `-.-.`  | Sounded three times, then the defaults  |
+//!
+//! Each dot is one unit (`UNIT_MS`) long, each dash is three units, with one
+//! unit between them, and seven units of silence after the letter.
+
+/// How long one Morse unit (a dot) lasts
+pub const UNIT_MS: u32 = 150;
+
+/// The tone we sound a fault code in
+pub const TONE_HZ: u16 = 1000;
+
+/// How many units of silence follow each letter, before it repeats
+pub const LETTER_GAP_UNITS: u8 = 7;
+
+/// How many times we sound a fault which isn't fatal, before carrying on
+pub const WARNING_REPEATS: u8 = 3;
+
+/// The lowest our 3.3V supply can be, for us to trust anything we measure or
+/// drive, in millivolts
+pub const SUPPLY_MIN_MV: u16 = 3000;
+
+/// The highest our 3.3V supply can be, in millivolts. The STM32F0 is only
+/// rated to 3.6V.
+pub const SUPPLY_MAX_MV: u16 = 3600;
+
+/// Something which went wrong whilst we were starting up.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Fault {
+	/// Our own 3.3V supply is out of range, so we won't power on the main
+	/// board.
+	Supply,
+	/// The config page in flash holds a configuration for this firmware,
+	/// but its CRC is wrong. We use the defaults, which the host can then
+	/// save over it.
+	Config,
+}
+
+impl Fault {
+	/// The Morse code we sound for this fault, as dots and dashes.
+	pub fn code(self) -> &'static str {
+		match self {
+			Fault::Supply => "...-",
+			Fault::Config => "-.-.",
+		}
+	}
+
+	/// Do we stop here, and sound the code forever?
+	pub fn is_fatal(self) -> bool {
+		self == Fault::Supply
+	}
+
+	/// Sound the code once, as a series of `(on, units)` steps.
+	pub fn signal(self) -> Signal {
+		Signal {
+			code: self.code().as_bytes(),
+			idx: 0,
+			gap: false,
+		}
+	}
+}
+
+/// The steps in sounding a fault code once (see [`Fault::signal`]).
+///
+/// Each step is whether the buzzer and LED are on, and for how many units.
+#[derive(Debug, Clone)]
+pub struct Signal {
+	/// The dots and dashes
+	code: &'static [u8],
+	/// Which dot or dash we're on
+	idx: usize,
+	/// Whether the gap after it is next
+	gap: bool,
+}
+
+impl Iterator for Signal {
+	type Item = (bool, u8);
+
+	fn next(&mut self) -> Option<(bool, u8)> {
+		let symbol = *self.code.get(self.idx)?;
+		if !self.gap {
+			self.gap = true;
+			return Some((true, if symbol == b'-' { 3 } else { 1 }));
+		}
+		self.gap = false;
+		self.idx += 1;
+		if self.idx == self.code.len() {
+			Some((false, LETTER_GAP_UNITS))
+		} else {
+			Some((false, 1))
+		}
+	}
+}
+
+/// Is our 3.3V supply, in millivolts, fit to run on?
+pub fn supply_ok(vdda_mv: u16) -> bool {
+	(SUPPLY_MIN_MV..=SUPPLY_MAX_MV).contains(&vdda_mv)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::config::Config;
+
+	#[test]
+	fn signals() {
+		let steps: Vec<(bool, u8)> = Fault::Config.signal().collect();
+		assert_eq!(
+			steps,
+			vec![
+				(true, 3),
+				(false, 1),
+				(true, 1),
+				(false, 1),
+				(true, 3),
+				(false, 1),
+				(true, 1),
+				(false, LETTER_GAP_UNITS),
+			]
+		);
+		// The whole of V, in units
+		let units: u32 = Fault::Supply
+			.signal()
+			.map(|(_, units)| u32::from(units))
+			.sum();
+		assert_eq!(units, 1 + 1 + 1 + 1 + 1 + 1 + 3 + 7);
+		assert!(Fault::Supply.is_fatal());
+		assert!(!Fault::Config.is_fatal());
+	}
+
+	#[test]
+	fn supply() {
+		assert!(supply_ok(3300));
+		assert!(!supply_ok(2800));
+		assert!(!supply_ok(3700));
+	}
+
+	#[test]
+	fn corrupt_config() {
+		assert!(!Config::is_corrupt(&[0xFF; Config::SIZE]));
+		let mut bytes = Config::DEFAULT.as_bytes();
+		assert!(!Config::is_corrupt(&bytes));
+		bytes[4] ^= 0x01;
+		assert!(Config::is_corrupt(&bytes));
+		// Another firmware's layout isn't ours to worry about
+		bytes[2] = bytes[2].wrapping_sub(1);
+		assert!(!Config::is_corrupt(&bytes));
+	}
+}
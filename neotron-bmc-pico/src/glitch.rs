@@ -0,0 +1,288 @@
+//! # Glitch Filtering
+//!
+//! Near motors or fluorescent lights, the wiring to the front panel buttons
+//! and the SPI chip select line can pick up spikes, which look like a button
+//! press or a chip select edge. The buttons are debounced already, but a
+//! spike which happens to land on two polls in a row still gets through.
+//!
+//! So the host can set a minimum pulse width for each. A change only counts
+//! once the pin has held its new level for that long, and if it goes back
+//! sooner, we call it a glitch, count it, and carry on as if it hadn't
+//! happened. The counts let the host see whether the filter is earning its
+//! keep.
+//!
+//! The chip select line is watched by busy-waiting (see [`watch`]), which is
+//! why its width is kept to a few microseconds. The buttons can be filtered
+//! for milliseconds, so a [`PollFilter`] keeps track of them across polls
+//! instead, and the poll task sleeps in between.
+
+/// The glitch counter for the power button
+pub const INPUT_POWER: usize = 0;
+/// The glitch counter for the reset button
+pub const INPUT_RESET: usize = 1;
+/// The glitch counter for the SPI chip select line
+pub const INPUT_CS: usize = 2;
+
+/// How many inputs we count glitches on
+pub const INPUTS: usize = 3;
+
+/// The button filter width is in units of this many microseconds
+pub const BUTTON_FILTER_UNIT_US: u16 = 100;
+
+/// The widest button filter, in units of `BUTTON_FILTER_UNIT_US`
+pub const BUTTON_FILTER_MAX: u8 = 50;
+
+/// The widest chip select filter, in microseconds
+pub const CS_FILTER_MAX_US: u8 = 10;
+
+/// Watch some pins for `width_us` microseconds.
+///
+/// `read` gives the level of each pin, as a bitmask, and `now_us` a free
+/// running microsecond timer. Returns the levels we first saw, and a bitmask
+/// of the pins which changed whilst we were watching. A width of zero reads
+/// the pins once.
+pub fn watch<R, T>(width_us: u16, mut read: R, mut now_us: T) -> (u8, u8)
+where
+	R: FnMut() -> u8,
+	T: FnMut() -> u16,
+{
+	let start_us = now_us();
+	let levels = read();
+	let mut changed = 0;
+	while now_us().wrapping_sub(start_us) < width_us {
+		changed |= read() ^ levels;
+	}
+	(levels, changed)
+}
+
+/// Filters some pins across polls, without busy-waiting.
+///
+/// Each pin is sampled on every poll. When a pin is seen at a new level, we
+/// note when, and only believe the new level once a later poll sees it has
+/// held for the filter width. If it goes back before then, it was a glitch.
+/// Whilst a change is waiting to be believed, the poller should come back
+/// sooner than usual (see [`Filtered::wait_us`]).
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct PollFilter {
+	/// The levels we believe, as a bitmask
+	levels: u8,
+	/// The levels at the last poll, as a bitmask
+	sample: u8,
+	/// When each pin took its level in `sample`, in microseconds
+	since_us: [u16; 8],
+}
+
+/// What a [`PollFilter`] made of a poll.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct Filtered {
+	/// The levels we believe, as a bitmask
+	pub levels: u8,
+	/// The pins which went back before their change was believed
+	pub glitches: u8,
+	/// How much longer the soonest waiting change has to hold, in
+	/// microseconds, or `None` if no change is waiting
+	pub wait_us: Option<u16>,
+}
+
+impl PollFilter {
+	/// Create a filter, where every pin is low.
+	pub const fn new() -> PollFilter {
+		PollFilter {
+			levels: 0,
+			sample: 0,
+			since_us: [0; 8],
+		}
+	}
+
+	/// Poll the pins.
+	///
+	/// `sample` is the level of each pin, as a bitmask, and `now_us` a free
+	/// running microsecond timer. A width of zero believes every change
+	/// straight away.
+	pub fn poll(&mut self, sample: u8, now_us: u16, width_us: u16) -> Filtered {
+		let moved = sample ^ self.sample;
+		// A pin which moves again before its last move was believed is back
+		// where it was
+		let glitches = moved & (self.sample ^ self.levels);
+		for (pin, since_us) in self.since_us.iter_mut().enumerate() {
+			if (moved & (1 << pin)) != 0 {
+				*since_us = now_us;
+			}
+		}
+		self.sample = sample;
+		let mut wait_us = None;
+		for (pin, since_us) in self.since_us.iter().enumerate() {
+			if ((self.sample ^ self.levels) & (1 << pin)) == 0 {
+				continue;
+			}
+			let held_us = now_us.wrapping_sub(*since_us);
+			if held_us >= width_us {
+				self.levels ^= 1 << pin;
+			} else {
+				let remaining_us = width_us - held_us;
+				wait_us = Some(wait_us.map_or(remaining_us, |us: u16| us.min(remaining_us)));
+			}
+		}
+		Filtered {
+			levels: self.levels,
+			glitches,
+			wait_us,
+		}
+	}
+}
+
+/// How many glitches each input has had.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct GlitchCounters {
+	/// One count per input (see `INPUT_POWER`, etc)
+	counts: [u16; INPUTS],
+}
+
+impl GlitchCounters {
+	/// How many bytes `as_bytes` gives
+	pub const SIZE: usize = INPUTS * 2;
+
+	/// Create the counters, all at zero.
+	pub const fn new() -> GlitchCounters {
+		GlitchCounters {
+			counts: [0; INPUTS],
+		}
+	}
+
+	/// Count `glitches` more glitches on `input`. The count sticks at its
+	/// maximum.
+	pub fn add(&mut self, input: usize, glitches: u16) {
+		self.counts[input] = self.counts[input].saturating_add(glitches);
+	}
+
+	/// How many glitches has `input` had?
+	pub fn count(&self, input: usize) -> u16 {
+		self.counts[input]
+	}
+
+	/// Start counting again.
+	pub fn clear(&mut self) {
+		self.counts = [0; INPUTS];
+	}
+
+	/// Convert to the bytes in the Glitch Counters register - each count as
+	/// a `u16le`, in input order.
+	pub fn as_bytes(&self) -> [u8; Self::SIZE] {
+		let mut bytes = [0u8; Self::SIZE];
+		for (chunk, count) in bytes.chunks_exact_mut(2).zip(self.counts.iter()) {
+			chunk.copy_from_slice(&count.to_le_bytes());
+		}
+		bytes
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn watching() {
+		// A spike on pin 1, part way through
+		let mut reads = 0;
+		let mut now_us = 0u16;
+		let (levels, changed) = watch(
+			10,
+			|| {
+				reads += 1;
+				if reads == 4 {
+					0b11
+				} else {
+					0b01
+				}
+			},
+			|| {
+				now_us = now_us.wrapping_add(2);
+				now_us
+			},
+		);
+		assert_eq!(levels, 0b01);
+		assert_eq!(changed, 0b10);
+		// No filter
+		let (levels, changed) = watch(0, || 0b10, || 0);
+		assert_eq!((levels, changed), (0b10, 0));
+		// The timer wraps whilst we watch
+		let mut now_us = 0xFFFEu16;
+		let (_, changed) = watch(
+			5,
+			|| 0,
+			|| {
+				now_us = now_us.wrapping_add(1);
+				now_us
+			},
+		);
+		assert_eq!(changed, 0);
+	}
+
+	#[test]
+	fn poll_filter() {
+		let mut filter = PollFilter::new();
+		// No filter
+		assert_eq!(
+			filter.poll(0b01, 0, 0),
+			Filtered {
+				levels: 0b01,
+				glitches: 0,
+				wait_us: None,
+			}
+		);
+		assert_eq!(filter.poll(0b00, 10, 0).levels, 0b00);
+		// A press has to hold for the width
+		assert_eq!(
+			filter.poll(0b10, 100, 500),
+			Filtered {
+				levels: 0b00,
+				glitches: 0,
+				wait_us: Some(500),
+			}
+		);
+		assert_eq!(filter.poll(0b10, 400, 500).wait_us, Some(200));
+		assert_eq!(
+			filter.poll(0b10, 600, 500),
+			Filtered {
+				levels: 0b10,
+				glitches: 0,
+				wait_us: None,
+			}
+		);
+		// A spike which is gone by the next poll is a glitch
+		assert_eq!(filter.poll(0b11, 1000, 500).levels, 0b10);
+		assert_eq!(
+			filter.poll(0b10, 1200, 500),
+			Filtered {
+				levels: 0b10,
+				glitches: 0b01,
+				wait_us: None,
+			}
+		);
+		// So is a release which doesn't last, and the timer can wrap
+		assert_eq!(filter.poll(0b00, 0xFFF0, 500).wait_us, Some(500));
+		assert_eq!(
+			filter.poll(0b10, 0x0010, 500),
+			Filtered {
+				levels: 0b10,
+				glitches: 0b10,
+				wait_us: None,
+			}
+		);
+		// A release which lasts
+		assert_eq!(filter.poll(0b00, 0x1000, 500).levels, 0b10);
+		assert_eq!(filter.poll(0b00, 0x1200, 500).levels, 0b00);
+	}
+
+	#[test]
+	fn counters() {
+		let mut glitches = GlitchCounters::new();
+		glitches.add(INPUT_RESET, 2);
+		glitches.add(INPUT_CS, 0xFFFF);
+		glitches.add(INPUT_CS, 1);
+		assert_eq!(glitches.count(INPUT_RESET), 2);
+		assert_eq!(glitches.as_bytes(), [0, 0, 2, 0, 0xFF, 0xFF]);
+		glitches.clear();
+		assert_eq!(glitches.as_bytes(), [0; 6]);
+	}
+}
@@ -0,0 +1,140 @@
+//! # BMC Health
+//!
+//! A heartbeat counter and some sticky fault flags, so the host driver can
+//! tell a wedged BMC (the heartbeat stops) from broken SPI wiring (nothing
+//! sensible comes back at all), and see whether anything has been lost.
+
+/// A PS/2 word arrived when the PS/2 queue was full, or a keyboard byte when
+/// the keyboard FIFO was full, so it was dropped
+pub const PS2_QUEUE_OVERFLOW: u8 = 1 << 0;
+/// A UART byte arrived when the UART receive FIFO was full (or, in console
+/// takeover mode, the keyboard inject FIFO), so it was dropped
+pub const UART_QUEUE_OVERFLOW: u8 = 1 << 1;
+/// The host stopped clocking part-way through an SPI transaction
+pub const SPI_TIMEOUT: u8 = 1 << 2;
+/// A bad request arrived over SPI (see [`Health::last_error`])
+pub const PROTOCOL_ERROR: u8 = 1 << 3;
+/// We cut the DC power because the host didn't shut down when it got too hot
+pub const OVER_TEMPERATURE: u8 = 1 << 4;
+/// A PS/2 word arrived with a bad start, parity or stop bit (perhaps a clock
+/// edge was missed)
+pub const PS2_FRAMING_ERROR: u8 = 1 << 5;
+
+/// The heartbeat and fault flags behind the BMC Health register.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Health {
+	/// Incremented every 100 ms (wraps)
+	heartbeat: u16,
+	/// Which faults have been seen since the host last cleared them
+	flags: u8,
+	/// The Response Result sent for the most recent bad request, or zero for none
+	last_error: u8,
+}
+
+impl Health {
+	/// How many bytes the BMC Health register holds.
+	pub const SIZE: usize = 4;
+
+	/// Create a new, healthy, record.
+	pub const fn new() -> Health {
+		Health {
+			heartbeat: 0,
+			flags: 0,
+			last_error: 0,
+		}
+	}
+
+	/// Move the heartbeat on. Call this every 100 ms.
+	pub fn tick(&mut self) {
+		self.heartbeat = self.heartbeat.wrapping_add(1);
+	}
+
+	/// Get the heartbeat counter.
+	pub fn heartbeat(&self) -> u16 {
+		self.heartbeat
+	}
+
+	/// Which faults have been seen?
+	pub fn flags(&self) -> u8 {
+		self.flags
+	}
+
+	/// What Response Result did we send for the most recent bad request?
+	pub fn last_error(&self) -> u8 {
+		self.last_error
+	}
+
+	/// Record that some faults have occurred.
+	pub fn report(&mut self, flags: u8) {
+		self.flags |= flags;
+	}
+
+	/// Record a bad request, and the Response Result we sent for it.
+	pub fn report_error(&mut self, code: u8) {
+		self.flags |= PROTOCOL_ERROR;
+		self.last_error = code;
+	}
+
+	/// Clear the faults with a 1 bit in `flags`.
+	///
+	/// Clearing `PROTOCOL_ERROR` also clears the last error code.
+	pub fn clear(&mut self, flags: u8) {
+		self.flags &= !flags;
+		if (flags & PROTOCOL_ERROR) != 0 {
+			self.last_error = 0;
+		}
+	}
+
+	/// Convert to bytes for the BMC Health register.
+	///
+	/// The heartbeat is sent first as a `u16le`, then the flags, then the last
+	/// error code.
+	pub fn as_bytes(&self) -> [u8; Self::SIZE] {
+		let heartbeat = self.heartbeat.to_le_bytes();
+		[heartbeat[0], heartbeat[1], self.flags, self.last_error]
+	}
+}
+
+impl Default for Health {
+	fn default() -> Self {
+		Health::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn heartbeat_wraps() {
+		let mut health = Health::new();
+		for _ in 0..0x1_0001 {
+			health.tick();
+		}
+		assert_eq!(health.heartbeat(), 1);
+		assert_eq!(health.as_bytes(), [0x01, 0x00, 0x00, 0x00]);
+	}
+
+	#[test]
+	fn flags_are_sticky() {
+		let mut health = Health::new();
+		health.report(PS2_QUEUE_OVERFLOW);
+		health.report(SPI_TIMEOUT);
+		health.report_error(0xA1);
+		assert_eq!(
+			health.as_bytes(),
+			[
+				0x00,
+				0x00,
+				PS2_QUEUE_OVERFLOW | SPI_TIMEOUT | PROTOCOL_ERROR,
+				0xA1
+			]
+		);
+		health.clear(SPI_TIMEOUT);
+		assert_eq!(health.flags(), PS2_QUEUE_OVERFLOW | PROTOCOL_ERROR);
+		assert_eq!(health.last_error(), 0xA1);
+		health.clear(PROTOCOL_ERROR);
+		assert_eq!(health.flags(), PS2_QUEUE_OVERFLOW);
+		assert_eq!(health.last_error(), 0x00);
+	}
+}
@@ -0,0 +1,213 @@
+//! # I²C Target Driver for STM32
+//!
+//! With the `i2c-target` feature, the host can reach the register map over
+//! the I²C pins (PB6 is SCL and PB7 is SDA) as well as over SPI, for carrier
+//! boards which only route I²C to the management header.
+//!
+//! The framing is the same as over SPI - the host writes a four byte
+//! *Request* to our address, then reads the *Response* back, either after a
+//! repeated start or in a separate transfer. We work out the *Response* as
+//! soon as the last *Request* byte arrives, stretching the clock if we need
+//! to, so it is ready before the host starts reading. Anything the host reads
+//! beyond the end of the *Response*, or before there is one, is padding
+//! (`0xFF`). Where a *Request* carries a payload (and got an `Ok` result),
+//! the host writes the payload and its CRC next, and reads a second
+//! *Response*.
+//!
+//! Unlike the HAL, this implements 'Target Mode', i.e. for when the clock
+//! signal comes from another controller.
+
+use stm32f0xx_hal::{pac, prelude::*, rcc::Rcc};
+
+use crate::config::{Config, I2C_ADDRESS_MAX, I2C_ADDRESS_MIN};
+
+pub struct I2cTarget<const RXC: usize, const TXC: usize> {
+	dev: pac::I2C1,
+	/// A space for bytes received from the host
+	rx_buffer: [u8; RXC],
+	/// How many bytes have been received?
+	rx_idx: usize,
+	/// A space for data we're about to send
+	tx_buffer: [u8; TXC],
+	/// How many bytes have been played from the TX buffer
+	tx_idx: usize,
+	/// How many bytes are loaded into the TX buffer
+	tx_ready: usize,
+	/// Has the RX been processed?
+	is_done: bool,
+}
+
+impl<const RXC: usize, const TXC: usize> I2cTarget<RXC, TXC> {
+	/// Answer on the given 7-bit address.
+	///
+	/// Reserved addresses are replaced with the default address.
+	pub fn new<SCLPIN, SDAPIN>(
+		dev: pac::I2C1,
+		pins: (SCLPIN, SDAPIN),
+		address: u8,
+		rcc: &mut Rcc,
+	) -> I2cTarget<RXC, TXC>
+	where
+		SCLPIN: stm32f0xx_hal::i2c::SclPin<pac::I2C1>,
+		SDAPIN: stm32f0xx_hal::i2c::SdaPin<pac::I2C1>,
+	{
+		let address = if (I2C_ADDRESS_MIN..=I2C_ADDRESS_MAX).contains(&address) {
+			address
+		} else {
+			Config::DEFAULT.i2c_address
+		};
+		crate::info!(target: crate::logging::Subsystem::Spi, "I²C target address = 0x{:02x}", address);
+
+		// Set I²C up in Controller mode. This will cause the HAL to enable
+		// the clocks and power to the IP block. It also checks the pins are OK.
+		let i2c_controller = stm32f0xx_hal::i2c::I2c::i2c1(dev, pins, 100.khz(), rcc);
+		// Now disassemble the driver so we can set it into Target mode instead
+		let (dev, _pins) = i2c_controller.release();
+
+		// We are following DM00091010, Section 26.4.7 I2C slave mode
+
+		// 1. Disable I²C, so we can change the clock, timings and own address
+		dev.cr1.modify(|_r, w| w.pe().clear_bit());
+
+		// 2. Clock the I²C block from the HSI, so the timings don't change
+		// when SYSCLK drops to the HSI in standby.
+		// Safety: nothing else touches the I²C clock select after init.
+		let rcc_regs = unsafe { &*pac::RCC::ptr() };
+		rcc_regs.cfgr3.modify(|_r, w| w.i2c1sw().hsi());
+
+		// 3. Set the data setup and hold times. These are the Standard-mode
+		// timings for an 8 MHz clock, from the reference manual (the SCL
+		// high and low times only matter to a controller).
+		dev.timingr.write(|w| unsafe {
+			w.presc().bits(1);
+			w.scldel().bits(0x4);
+			w.sdadel().bits(0x2);
+			w.sclh().bits(0x0F);
+			w.scll().bits(0x13);
+			w
+		});
+
+		// 4. Set our 7-bit own address, and enable it
+		dev.oar1.write(|w| {
+			w.oa1mode().clear_bit();
+			unsafe { w.oa1().bits(u16::from(address) << 1) };
+			w.oa1en().set_bit();
+			w
+		});
+
+		// 5. Keep clock stretching on (NOSTRETCH = 0), so we can hold the bus
+		// whilst we work out a response, and turn on the interrupts
+		dev.cr1.modify(|_r, w| {
+			w.nostretch().clear_bit();
+			w.addrie().set_bit();
+			w.rxie().set_bit();
+			w.txie().set_bit();
+			w.stopie().set_bit();
+			w.nackie().set_bit();
+			w.pe().set_bit();
+			w
+		});
+
+		I2cTarget {
+			dev,
+			rx_buffer: [0u8; RXC],
+			rx_idx: 0,
+			tx_buffer: [0u8; TXC],
+			tx_idx: 0,
+			tx_ready: 0,
+			is_done: true,
+		}
+	}
+
+	/// Get a slice of data received so far.
+	pub fn get_received(&self) -> Option<&[u8]> {
+		if !self.is_done {
+			Some(&self.rx_buffer[0..self.rx_idx])
+		} else {
+			None
+		}
+	}
+
+	/// Mark the RX as processed, so we don't do it again (until the host
+	/// starts writing the next request).
+	pub fn mark_done(&mut self) {
+		self.is_done = true;
+	}
+
+	pub fn handle_isr(&mut self) {
+		let irq_status = self.dev.isr.read();
+		if irq_status.addr().bit_is_set() {
+			if irq_status.dir().bit_is_clear() {
+				// The host is writing, so this is a new request, and any
+				// old response is no longer wanted
+				self.rx_idx = 0;
+				self.tx_idx = 0;
+				self.tx_ready = 0;
+				self.is_done = false;
+			} else {
+				// The host is reading. Flush the transmit register, in case
+				// it holds a byte from a read the host cut short.
+				self.tx_idx = 0;
+				self.dev.isr.write(|w| w.txe().set_bit());
+			}
+			// This releases the clock
+			self.dev.icr.write(|w| w.addrcf().set_bit());
+		}
+		if irq_status.rxne().bit_is_set() {
+			self.read_isr();
+		}
+		if irq_status.txis().bit_is_set() {
+			self.tx_isr();
+		}
+		if irq_status.nackf().bit_is_set() {
+			// The host doesn't want any more bytes
+			self.dev.icr.write(|w| w.nackcf().set_bit());
+		}
+		if irq_status.stopf().bit_is_set() {
+			self.dev.icr.write(|w| w.stopcf().set_bit());
+		}
+	}
+
+	/// Take a byte from the receive register
+	fn read_isr(&mut self) {
+		let cmd = self.dev.rxdr.read().rxdata().bits();
+		if self.rx_idx < self.rx_buffer.len() {
+			self.rx_buffer[self.rx_idx] = cmd;
+			self.rx_idx += 1;
+		}
+	}
+
+	/// Load the transmit register with some data, either from `tx_buffer` or
+	/// a padding byte.
+	fn tx_isr(&mut self) {
+		let next_tx = if (self.tx_idx < self.tx_ready) && (self.tx_idx < self.tx_buffer.len()) {
+			// We have some data yet to send
+			let next_tx = self.tx_buffer[self.tx_idx];
+			self.tx_idx += 1;
+			next_tx
+		} else {
+			// No data - send padding
+			0xFF
+		};
+		self.dev.txdr.write(|w| w.txdata().bits(next_tx));
+	}
+
+	/// Render some message into the TX buffer.
+	///
+	/// You get an error if you try to load too much.
+	pub fn set_transmit_sendable(
+		&mut self,
+		message: &dyn neotron_bmc_protocol::Sendable,
+	) -> Result<(), ()> {
+		self.tx_ready = 0;
+		self.tx_idx = 0;
+
+		match message.render_to_buffer(&mut self.tx_buffer) {
+			Ok(n) => {
+				self.tx_ready = n;
+				Ok(())
+			}
+			Err(_) => Err(()),
+		}
+	}
+}
@@ -0,0 +1,403 @@
+//! # Host Interrupts
+//!
+//! Tracks which interrupts are active and enabled, and so whether the
+//! `IRQ_nHOST` line should be asserted.
+//!
+//! Each interrupt source has three bits:
+//!
+//! * Its *level* - whether the condition behind it is true right now, like
+//!   "the keyboard FIFO has data in it". Sources which are just events (like
+//!   a button changing state) have no level, so it is always zero.
+//! * Its *pending* bit, which is latched when the source's event happens, or
+//!   when its level goes from zero to one, and stays set until the host
+//!   writes a 1 to it. Clearing it while the level is still one doesn't
+//!   latch it again - the level has to fall and rise first.
+//! * Its *enabled* bit (the mask). The line is asserted whilst any source is
+//!   both pending and enabled.
+//!
+//! So when the line seems stuck, the host can see which sources are pending,
+//! and whether their conditions have actually gone away.
+//!
+//! Some sources (like UART RX) can fire very often, so they can be
+//! coalesced - the interrupt is only raised once a number of events have
+//! arrived, or once some time has passed since the first event, whichever
+//! comes first. Keyboard input and faults are always raised immediately.
+//!
+//! Host boards differ in how they want the line driven, so it can be active
+//! low or active high, and push-pull or open-drain (see the IRQ Line Control
+//! register). The host can also have us pulse the line, whatever the
+//! interrupts say, to check its wiring when it boots.
+
+/// A register watch has tripped (see the Watch Status register)
+pub const WATCH_TRIGGERED: u16 = 1 << 15;
+/// The host watchdog will reset the main board soon (see the Host Watchdog
+/// Pretimeout register)
+pub const WATCHDOG_PRETIMEOUT: u16 = 1 << 14;
+/// The rotary encoder has been turned (only with the `encoder` feature)
+pub const ENCODER_CHANGE: u16 = 1 << 13;
+/// The BMC is too hot, so the host should shut down (see the Over-temperature
+/// Limit register)
+pub const OVER_TEMPERATURE: u16 = 1 << 12;
+/// The UART2 transmit FIFO has emptied (only with the `uart2` feature)
+pub const UART2_TX_EMPTY: u16 = 1 << 11;
+/// A byte arrived on UART2 (only with the `uart2` feature)
+pub const UART2_RX_NOT_EMPTY: u16 = 1 << 10;
+/// A boot key was held during power-on (see the Boot Keys register)
+pub const BOOT_KEY: u16 = 1 << 9;
+/// A deferred operation has finished (see the Deferred Operation register)
+pub const DEFERRED_DONE: u16 = 1 << 8;
+/// An alarm on one of the voltage rails
+pub const VOLTAGE_ALARM: u16 = 1 << 7;
+/// A button changed state
+pub const BUTTON_STATE_CHANGE: u16 = 1 << 6;
+/// The UART TX buffer is empty
+pub const UART_TX_EMPTY: u16 = 1 << 5;
+/// The UART RX buffer has data in it
+pub const UART_RX_NOT_EMPTY: u16 = 1 << 4;
+/// The I²C TX buffer is empty
+pub const I2C_TX_EMPTY: u16 = 1 << 3;
+/// The I²C RX buffer has data in it
+pub const I2C_RX_NOT_EMPTY: u16 = 1 << 2;
+/// The PS/2 Mouse RX buffer has data in it
+pub const MOUSE_RX_NOT_EMPTY: u16 = 1 << 1;
+/// The PS/2 Keyboard RX buffer has data in it
+pub const KEYBOARD_RX_NOT_EMPTY: u16 = 1 << 0;
+
+/// The sources which have a level (see [`InterruptController::set_level`]).
+/// The others are only ever raised by an event.
+pub const LEVEL_SOURCES: u16 = WATCHDOG_PRETIMEOUT
+	| OVER_TEMPERATURE
+	| UART2_TX_EMPTY
+	| UART2_RX_NOT_EMPTY
+	| VOLTAGE_ALARM
+	| UART_TX_EMPTY
+	| UART_RX_NOT_EMPTY
+	| I2C_TX_EMPTY
+	| I2C_RX_NOT_EMPTY
+	| MOUSE_RX_NOT_EMPTY
+	| KEYBOARD_RX_NOT_EMPTY;
+
+/// IRQ Line Control bit: the line is high when asserted, instead of low
+pub const LINE_ACTIVE_HIGH: u8 = 1 << 0;
+/// IRQ Line Control bit: the line is open-drain, instead of push-pull
+pub const LINE_OPEN_DRAIN: u8 = 1 << 1;
+/// The IRQ Line Control bits which mean something
+pub const LINE_CONTROL_BITS: u8 = LINE_ACTIVE_HIGH | LINE_OPEN_DRAIN;
+
+/// How long the line is asserted, and then released, for each pulse of the
+/// line test
+const LINE_TEST_HALF_PERIOD_MS: u32 = 10;
+
+/// Decides when a frequently firing source should actually raise its interrupt.
+#[derive(Debug, Clone)]
+pub struct Coalescer {
+	/// Raise the interrupt once this many events are pending
+	threshold: u8,
+	/// Raise the interrupt this many milliseconds after the first pending event
+	timeout_ms: u8,
+	/// How many events have arrived since we last raised the interrupt
+	pending: u8,
+	/// When the first pending event arrived
+	first_event_ms: u32,
+}
+
+impl Coalescer {
+	/// Create a new coalescer, which raises the interrupt on every event.
+	pub const fn new() -> Coalescer {
+		Coalescer {
+			threshold: 1,
+			timeout_ms: 0,
+			pending: 0,
+			first_event_ms: 0,
+		}
+	}
+
+	/// How many events must be pending before we raise the interrupt?
+	pub fn threshold(&self) -> u8 {
+		self.threshold
+	}
+
+	/// Set how many events must be pending before we raise the interrupt.
+	///
+	/// Zero is treated as one.
+	pub fn set_threshold(&mut self, threshold: u8) {
+		self.threshold = threshold.max(1);
+	}
+
+	/// How long after the first pending event do we raise the interrupt anyway?
+	pub fn timeout_ms(&self) -> u8 {
+		self.timeout_ms
+	}
+
+	/// Set how long after the first pending event we raise the interrupt anyway.
+	pub fn set_timeout_ms(&mut self, timeout_ms: u8) {
+		self.timeout_ms = timeout_ms;
+	}
+
+	/// Record an event. Returns `true` if the interrupt should be raised now.
+	pub fn event(&mut self, now_ms: u32) -> bool {
+		self.events(now_ms, 1)
+	}
+
+	/// Record several events which arrived together. Returns `true` if the
+	/// interrupt should be raised now.
+	pub fn events(&mut self, now_ms: u32, count: usize) -> bool {
+		if count == 0 {
+			return false;
+		}
+		if self.pending == 0 {
+			self.first_event_ms = now_ms;
+		}
+		let count = count.min(usize::from(u8::MAX)) as u8;
+		self.pending = self.pending.saturating_add(count);
+		self.poll(now_ms)
+	}
+
+	/// Check for expiry. Returns `true` if the interrupt should be raised now.
+	pub fn poll(&mut self, now_ms: u32) -> bool {
+		if self.pending == 0 {
+			return false;
+		}
+		let waited_ms = now_ms.wrapping_sub(self.first_event_ms);
+		if self.pending >= self.threshold || waited_ms >= u32::from(self.timeout_ms) {
+			self.pending = 0;
+			true
+		} else {
+			false
+		}
+	}
+}
+
+impl Default for Coalescer {
+	fn default() -> Self {
+		Coalescer::new()
+	}
+}
+
+/// The Interrupt Status, Interrupt Control and Interrupt Level registers.
+///
+/// There are sixteen interrupts. The low eight bits are in registers 0x10,
+/// 0x11 and 0x9D, and the high eight bits are in registers 0x14, 0x15 and
+/// 0x9E.
+#[derive(Debug, Clone)]
+pub struct InterruptController {
+	/// Which interrupts are pending (latched until cleared)
+	pending: u16,
+	/// Which interrupts are enabled
+	enabled: u16,
+	/// Which sources' conditions were true when last sampled
+	level: u16,
+	/// Coalescing for `UART_RX_NOT_EMPTY`
+	pub uart_rx: Coalescer,
+	/// Halves of line test pulses still to go - the line is asserted when
+	/// this is even, and released when it is odd
+	line_test: u16,
+	/// When the current half of the line test pulse started, once the test
+	/// is under way
+	line_test_since_ms: Option<u32>,
+}
+
+impl InterruptController {
+	/// Create a new controller, with nothing active and nothing enabled.
+	pub const fn new() -> InterruptController {
+		InterruptController {
+			pending: 0,
+			enabled: 0,
+			level: 0,
+			uart_rx: Coalescer::new(),
+			line_test: 0,
+			line_test_since_ms: None,
+		}
+	}
+
+	/// Which interrupts are pending?
+	pub fn pending(&self) -> u16 {
+		self.pending
+	}
+
+	/// Which interrupts are enabled?
+	pub fn enabled(&self) -> u16 {
+		self.enabled
+	}
+
+	/// Set which interrupts are enabled.
+	pub fn set_enabled(&mut self, enabled: u16) {
+		self.enabled = enabled;
+	}
+
+	/// Which sources' conditions were true when last sampled?
+	pub fn level(&self) -> u16 {
+		self.level
+	}
+
+	/// Record the level of every source in [`LEVEL_SOURCES`] (the other bits
+	/// are ignored). A source whose level has gone from zero to one becomes
+	/// pending - apart from UART RX Not Empty, which the coalescer raises.
+	pub fn set_level(&mut self, level: u16) {
+		let level = level & LEVEL_SOURCES;
+		let rising = level & !self.level & !UART_RX_NOT_EMPTY;
+		self.pending |= rising;
+		self.level = level;
+	}
+
+	/// An event happened, so mark some interrupts as pending, immediately.
+	pub fn raise(&mut self, bits: u16) {
+		self.pending |= bits;
+	}
+
+	/// Clear the pending interrupts with a 1 bit in `bits`.
+	pub fn clear(&mut self, bits: u16) {
+		self.pending &= !bits;
+	}
+
+	/// Some bytes arrived on the UART.
+	pub fn uart_rx_event(&mut self, now_ms: u32, count: usize) {
+		if self.uart_rx.events(now_ms, count) {
+			self.raise(UART_RX_NOT_EMPTY);
+		}
+	}
+
+	/// Pulse the IRQ line `pulses` times, whatever the interrupts say. Zero
+	/// stops a test which is already running.
+	pub fn start_line_test(&mut self, pulses: u8) {
+		self.line_test = u16::from(pulses) * 2;
+		self.line_test_since_ms = None;
+	}
+
+	/// How many line test pulses are still to go (including the current
+	/// one)?
+	pub fn line_test_pulses(&self) -> u8 {
+		((self.line_test + 1) / 2) as u8
+	}
+
+	/// Raise any coalesced interrupts which have waited long enough, and move
+	/// the line test along.
+	pub fn poll(&mut self, now_ms: u32) {
+		if self.uart_rx.poll(now_ms) {
+			self.raise(UART_RX_NOT_EMPTY);
+		}
+		if self.line_test != 0 {
+			match self.line_test_since_ms {
+				None => self.line_test_since_ms = Some(now_ms),
+				Some(since_ms) if now_ms.wrapping_sub(since_ms) >= LINE_TEST_HALF_PERIOD_MS => {
+					self.line_test -= 1;
+					self.line_test_since_ms = Some(now_ms);
+				}
+				Some(_) => {}
+			}
+		}
+	}
+
+	/// Should the IRQ line be asserted?
+	pub fn is_asserted(&self) -> bool {
+		if self.line_test != 0 {
+			return (self.line_test % 2) == 0;
+		}
+		(self.pending & self.enabled) != 0
+	}
+}
+
+impl Default for InterruptController {
+	fn default() -> Self {
+		InterruptController::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Every source, from bit 0 up
+	const SOURCES: [u16; 16] = [
+		KEYBOARD_RX_NOT_EMPTY,
+		MOUSE_RX_NOT_EMPTY,
+		I2C_RX_NOT_EMPTY,
+		I2C_TX_EMPTY,
+		UART_RX_NOT_EMPTY,
+		UART_TX_EMPTY,
+		BUTTON_STATE_CHANGE,
+		VOLTAGE_ALARM,
+		DEFERRED_DONE,
+		BOOT_KEY,
+		UART2_RX_NOT_EMPTY,
+		UART2_TX_EMPTY,
+		OVER_TEMPERATURE,
+		ENCODER_CHANGE,
+		WATCHDOG_PRETIMEOUT,
+		WATCH_TRIGGERED,
+	];
+
+	#[test]
+	fn events_latch_until_cleared() {
+		for source in SOURCES {
+			let mut irq = InterruptController::new();
+			irq.raise(source);
+			assert_eq!(irq.pending(), source);
+			// Masked
+			assert!(!irq.is_asserted());
+			irq.set_enabled(!source);
+			assert!(!irq.is_asserted());
+			irq.set_enabled(source);
+			assert!(irq.is_asserted());
+			// Writing 1 to the other bits leaves it alone
+			irq.clear(!source);
+			assert!(irq.is_asserted());
+			irq.clear(source);
+			assert_eq!(irq.pending(), 0);
+			assert!(!irq.is_asserted());
+			// Events don't touch the level
+			assert_eq!(irq.level(), 0);
+		}
+	}
+
+	#[test]
+	fn levels_latch_on_the_rising_edge() {
+		for source in SOURCES {
+			let mut irq = InterruptController::new();
+			irq.set_enabled(0xFFFF);
+			irq.set_level(source);
+			if (source & LEVEL_SOURCES) == 0 {
+				// Just an event, with no level
+				assert_eq!(irq.level(), 0);
+				assert!(!irq.is_asserted());
+				continue;
+			}
+			assert_eq!(irq.level(), source);
+			if source == UART_RX_NOT_EMPTY {
+				// The coalescer raises this one
+				assert_eq!(irq.pending(), 0);
+				continue;
+			}
+			assert_eq!(irq.pending(), source);
+			assert!(irq.is_asserted());
+			// Cleared whilst still high, so it stays cleared
+			irq.clear(source);
+			irq.set_level(source);
+			assert!(!irq.is_asserted());
+			// Falling doesn't latch anything
+			irq.set_level(0);
+			assert_eq!(irq.pending(), 0);
+			// Rising again does
+			irq.set_level(source);
+			assert!(irq.is_asserted());
+			// And falling doesn't clear it
+			irq.set_level(0);
+			assert_eq!(irq.pending(), source);
+		}
+	}
+
+	#[test]
+	fn uart_rx_is_coalesced() {
+		let mut irq = InterruptController::new();
+		irq.uart_rx.set_threshold(4);
+		irq.uart_rx.set_timeout_ms(10);
+		irq.uart_rx_event(100, 2);
+		irq.set_level(UART_RX_NOT_EMPTY);
+		assert_eq!(irq.pending(), 0);
+		irq.poll(105);
+		assert_eq!(irq.pending(), 0);
+		irq.poll(110);
+		assert_eq!(irq.pending(), UART_RX_NOT_EMPTY);
+		assert_eq!(irq.level(), UART_RX_NOT_EMPTY);
+	}
+}
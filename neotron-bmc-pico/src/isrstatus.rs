@@ -0,0 +1,283 @@
+//! # Interrupt Status
+//!
+//! The PS/2 clock interrupts run above everything which shares the register
+//! state, and mustn't lock it, or an SPI request being answered would hold
+//! off their clock edges. So they note what went wrong in an [`IsrStatus`],
+//! which is made of atomics and lives in a `static`, and whoever holds the
+//! register state picks it up with [`IsrStatusReader::collect`] - the
+//! heartbeat task, and the request handler just before it answers each
+//! request, so the host sees a fault in the very next read of the BMC Health
+//! register, rather than up to a heartbeat later.
+//!
+//! Each count only goes up (wrapping), and the reader remembers what it saw
+//! last time, so nothing is ever reset. A Cortex-M0 can't do an atomic
+//! read-modify-write, but as only interrupts of the one priority bump the
+//! counts, a load and then a store is enough - and as nothing else writes
+//! them, a count can't be lost between the reader's load and a reset.
+//!
+//! The PS/2 clock interrupts also publish each port's bit timing here, after
+//! each word. Clearing it goes the other way: the reader raises a flag, and
+//! the port's interrupt clears its timer at the next clock edge.
+
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU8, Ordering};
+
+use crate::health;
+use crate::ps2::Ps2Port;
+use crate::ps2timing::BitTiming;
+
+/// How many PS/2 ports there are
+const PS2_PORTS: usize = 2;
+
+/// One PS/2 port's bit timing, as its clock interrupt last published it.
+#[derive(Debug)]
+struct SharedBitTiming {
+	/// See [`BitTiming::min_us`]
+	min_us: AtomicU16,
+	/// See [`BitTiming::max_us`]
+	max_us: AtomicU16,
+	/// See [`BitTiming::typical_us`]
+	typical_us: AtomicU16,
+	/// See [`BitTiming::out_of_spec`]
+	out_of_spec: AtomicU16,
+	/// The reader wants the port's timer cleared
+	clear: AtomicBool,
+}
+
+impl SharedBitTiming {
+	/// Create a port's timing, where nothing has been timed.
+	const fn new() -> SharedBitTiming {
+		SharedBitTiming {
+			min_us: AtomicU16::new(0),
+			max_us: AtomicU16::new(0),
+			typical_us: AtomicU16::new(0),
+			out_of_spec: AtomicU16::new(0),
+			clear: AtomicBool::new(false),
+		}
+	}
+
+	/// Publish the timing.
+	fn store(&self, timing: &BitTiming) {
+		self.min_us.store(timing.min_us, Ordering::Relaxed);
+		self.max_us.store(timing.max_us, Ordering::Relaxed);
+		self.typical_us.store(timing.typical_us, Ordering::Relaxed);
+		self.out_of_spec
+			.store(timing.out_of_spec, Ordering::Relaxed);
+	}
+
+	/// Read the published timing.
+	fn load(&self) -> BitTiming {
+		BitTiming {
+			min_us: self.min_us.load(Ordering::Relaxed),
+			max_us: self.max_us.load(Ordering::Relaxed),
+			typical_us: self.typical_us.load(Ordering::Relaxed),
+			out_of_spec: self.out_of_spec.load(Ordering::Relaxed),
+		}
+	}
+}
+
+/// What the interrupts which can't lock the register state have seen.
+#[derive(Debug)]
+pub struct IsrStatus {
+	/// PS/2 words dropped because the PS/2 task's queue was full
+	ps2_overflows: AtomicU8,
+	/// Chip select glitches thrown away
+	cs_glitches: AtomicU16,
+	/// Each PS/2 port's bit timing
+	ps2_timing: [SharedBitTiming; PS2_PORTS],
+}
+
+impl IsrStatus {
+	/// Create a new status, where nothing has happened.
+	pub const fn new() -> IsrStatus {
+		IsrStatus {
+			ps2_overflows: AtomicU8::new(0),
+			cs_glitches: AtomicU16::new(0),
+			ps2_timing: [SharedBitTiming::new(), SharedBitTiming::new()],
+		}
+	}
+
+	/// Note a PS/2 word we dropped because the queue was full.
+	///
+	/// Only call this from interrupts of one priority, as the increment is
+	/// not atomic.
+	pub fn ps2_overflow(&self) {
+		let count = self.ps2_overflows.load(Ordering::Relaxed);
+		self.ps2_overflows
+			.store(count.wrapping_add(1), Ordering::Relaxed);
+	}
+
+	/// Note a chip select glitch we threw away.
+	///
+	/// Only call this from interrupts of one priority, as the increment is
+	/// not atomic.
+	pub fn cs_glitch(&self) {
+		let count = self.cs_glitches.load(Ordering::Relaxed);
+		self.cs_glitches
+			.store(count.wrapping_add(1), Ordering::Relaxed);
+	}
+
+	/// Publish a PS/2 port's bit timing.
+	///
+	/// Only call this from the port's clock interrupt.
+	pub fn ps2_bit_timing(&self, port: Ps2Port, timing: &BitTiming) {
+		self.ps2_timing[port.index()].store(timing);
+	}
+
+	/// Does the reader want a PS/2 port's bit timer cleared? Only says so
+	/// once for each request, and publishes the cleared timing.
+	///
+	/// Only call this from the port's clock interrupt.
+	pub fn take_ps2_timing_clear(&self, port: Ps2Port) -> bool {
+		let shared = &self.ps2_timing[port.index()];
+		if !shared.clear.load(Ordering::Relaxed) {
+			return false;
+		}
+		shared.store(&BitTiming::default());
+		shared.clear.store(false, Ordering::Relaxed);
+		true
+	}
+}
+
+impl Default for IsrStatus {
+	fn default() -> Self {
+		IsrStatus::new()
+	}
+}
+
+/// What the interrupts saw since we last looked.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct IsrReport {
+	/// Faults for the BMC Health register
+	pub health: u8,
+	/// Chip select glitches for the Glitch Counters register
+	pub cs_glitches: u16,
+	/// Each PS/2 port's bit timing, for the PS/2 Bit Timing register
+	pub ps2_timing: [BitTiming; PS2_PORTS],
+}
+
+/// Remembers the counts in an [`IsrStatus`] we've already picked up.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct IsrStatusReader {
+	/// The PS/2 overflow count, last time we looked
+	ps2_overflows: u8,
+	/// The chip select glitch count, last time we looked
+	cs_glitches: u16,
+	/// We've been asked to clear the PS/2 bit timing, but haven't passed it
+	/// on yet
+	clear_ps2_timing: bool,
+}
+
+impl IsrStatusReader {
+	/// Create a new reader, for an [`IsrStatus`] where nothing has happened.
+	pub const fn new() -> IsrStatusReader {
+		IsrStatusReader {
+			ps2_overflows: 0,
+			cs_glitches: 0,
+			clear_ps2_timing: false,
+		}
+	}
+
+	/// Ask for the PS/2 bit timing of both ports to be cleared, the next time
+	/// we collect.
+	pub fn clear_ps2_timing(&mut self) {
+		self.clear_ps2_timing = true;
+	}
+
+	/// Find out what the interrupts saw since we last looked.
+	///
+	/// The PS/2 bit timing of a port reads as zero until its interrupt has
+	/// cleared its timer, if we asked it to.
+	pub fn collect(&mut self, status: &IsrStatus) -> IsrReport {
+		if self.clear_ps2_timing {
+			self.clear_ps2_timing = false;
+			for shared in status.ps2_timing.iter() {
+				shared.clear.store(true, Ordering::Relaxed);
+			}
+		}
+		let mut ps2_timing = [BitTiming::default(); PS2_PORTS];
+		for (timing, shared) in ps2_timing.iter_mut().zip(status.ps2_timing.iter()) {
+			if !shared.clear.load(Ordering::Relaxed) {
+				*timing = shared.load();
+			}
+		}
+		let ps2_overflows = status.ps2_overflows.load(Ordering::Relaxed);
+		let cs_glitches = status.cs_glitches.load(Ordering::Relaxed);
+		let report = IsrReport {
+			health: if ps2_overflows != self.ps2_overflows {
+				health::PS2_QUEUE_OVERFLOW
+			} else {
+				0
+			},
+			cs_glitches: cs_glitches.wrapping_sub(self.cs_glitches),
+			ps2_timing,
+		};
+		self.ps2_overflows = ps2_overflows;
+		self.cs_glitches = cs_glitches;
+		report
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn collect() {
+		let status = IsrStatus::new();
+		let mut reader = IsrStatusReader::new();
+		assert_eq!(reader.collect(&status), IsrReport::default());
+		status.ps2_overflow();
+		status.cs_glitch();
+		status.cs_glitch();
+		assert_eq!(
+			reader.collect(&status),
+			IsrReport {
+				health: health::PS2_QUEUE_OVERFLOW,
+				cs_glitches: 2,
+				ps2_timing: Default::default(),
+			}
+		);
+		// Each is only picked up once
+		assert_eq!(reader.collect(&status), IsrReport::default());
+		// The counts wrap
+		for _ in 0..0xFFFD {
+			status.cs_glitch();
+		}
+		assert_eq!(reader.collect(&status).cs_glitches, 0xFFFD);
+		for _ in 0..3 {
+			status.cs_glitch();
+		}
+		assert_eq!(reader.collect(&status).cs_glitches, 3);
+	}
+
+	#[test]
+	fn ps2_timing() {
+		let status = IsrStatus::new();
+		let mut reader = IsrStatusReader::new();
+		let timing = BitTiming {
+			min_us: 70,
+			max_us: 90,
+			typical_us: 80,
+			out_of_spec: 1,
+		};
+		status.ps2_bit_timing(Ps2Port::Mouse, &timing);
+		assert_eq!(
+			reader.collect(&status).ps2_timing,
+			[BitTiming::default(), timing]
+		);
+		// It's a snapshot, so it's there every time
+		assert_eq!(reader.collect(&status).ps2_timing[1], timing);
+		// Nothing to clear until the reader asks
+		assert!(!status.take_ps2_timing_clear(Ps2Port::Mouse));
+		reader.clear_ps2_timing();
+		assert_eq!(reader.collect(&status).ps2_timing[1], BitTiming::default());
+		// Even if the interrupt publishes again before it sees the request
+		status.ps2_bit_timing(Ps2Port::Mouse, &timing);
+		assert_eq!(reader.collect(&status).ps2_timing[1], BitTiming::default());
+		assert!(status.take_ps2_timing_clear(Ps2Port::Mouse));
+		assert!(!status.take_ps2_timing_clear(Ps2Port::Mouse));
+		assert_eq!(reader.collect(&status).ps2_timing[1], BitTiming::default());
+		status.ps2_bit_timing(Ps2Port::Mouse, &timing);
+		assert_eq!(reader.collect(&status).ps2_timing[1], timing);
+	}
+}
@@ -0,0 +1,948 @@
+//! # Keyboard Key State
+//!
+//! Follows the make and break codes coming from the PS/2 keyboard (Scan Code
+//! Set 2), and keeps a bitmap of which keys are currently held down, so the
+//! host can ask "is Ctrl held down?" without decoding the scan codes itself.
+//!
+//! Each key has one bit:
+//!
+//! * A plain key with code `0xNN` uses bit `0xNN`
+//! * An extended key (`0xE0 0xNN`) uses bit `0x80 | 0xNN`
+//! * F7, which is the one plain key above `0x7F` (`0x83`), uses bit `0x02`
+//!
+//! Pause (`0xE1 ...`) has no break code, so it is ignored, as are the "fake
+//! shifts" some keyboards send around Print Screen.
+//!
+//! We also spot each new key press, leaving out the typematic repeats of a
+//! key which is already held down, for hosts which only want to know which
+//! key was pressed (see the PS/2 Keyboard Control register).
+//!
+//! For a few seconds after power-on, we also watch for a configurable boot
+//! key (and the modifier keys) being held, so the BIOS can offer a settings
+//! menu or a recovery mode before the OS keyboard driver is running.
+//!
+//! We can also watch for a configurable chord of up to three keys, which
+//! resets the main board, so a hung OS can be rebooted from the keyboard even
+//! when its own keyboard driver is dead.
+//!
+//! For a minimal host which uses make-only mode, we can also repeat the last
+//! key pressed for as long as it is held, at a rate the host picks, and so
+//! take care of typematic repeat for it.
+//!
+//! For a host which only wants characters, we can also translate each key
+//! press into the character it types with a US layout, following Shift,
+//! Ctrl and our own idea of Caps Lock.
+//!
+//! Finally, once the BMC has started we reset the keyboard and ask for its
+//! ID, so the host knows straight away whether a keyboard is plugged in, and
+//! what kind it is.
+
+/// The make code for F7, the one plain key which doesn't fit in seven bits
+const CODE_F7: u8 = 0x83;
+
+/// Where we keep F7 in the bitmap (nothing else uses this code)
+const BIT_F7: u8 = 0x02;
+
+/// Sent by the keyboard when it has passed its self-test (BAT)
+const BAT_PASSED: u8 = 0xAA;
+
+/// Sent by the keyboard when it has failed its self-test (BAT)
+const BAT_FAILED: u8 = 0xFC;
+
+/// Also sent by some keyboards when they have failed their self-test
+const BAT_FAILED_ALT: u8 = 0xFD;
+
+/// Sent by the keyboard when it has accepted a byte from us
+const ACK: u8 = 0xFA;
+
+/// Sent by the keyboard when it wants the last byte again
+const RESEND: u8 = 0xFE;
+
+/// Command to reset the keyboard, and run its self-test
+const CMD_RESET: u8 = 0xFF;
+
+/// Command to ask for the keyboard's ID
+const CMD_READ_ID: u8 = 0xF2;
+
+/// How long the keyboard gets to finish its self-test, after a reset. The
+/// spec says 500 to 750 ms.
+const BAT_TIMEOUT_MS: u32 = 1000;
+
+/// How long the keyboard gets to send each byte of its ID
+const ID_TIMEOUT_MS: u32 = 100;
+
+/// We are still checking for a keyboard (or haven't started)
+pub const KEYBOARD_CHECKING: u8 = 0;
+
+/// A keyboard passed its self-test
+pub const KEYBOARD_PRESENT: u8 = 1;
+
+/// Nothing answered when we reset the keyboard
+pub const KEYBOARD_ABSENT: u8 = 2;
+
+/// A keyboard answered, but failed its self-test (or never finished it)
+pub const KEYBOARD_FAILED: u8 = 3;
+
+/// The next code is an extended key
+const PREFIX_EXTENDED: u8 = 0xE0;
+
+/// The next code is a key being released
+const PREFIX_BREAK: u8 = 0xF0;
+
+/// Starts the eight byte Pause sequence
+const PREFIX_PAUSE: u8 = 0xE1;
+
+/// How many more bytes follow `PREFIX_PAUSE`
+const PAUSE_LENGTH: u8 = 7;
+
+/// Sent by the keyboard when it has (re)started
+const SELF_TEST_PASSED: u8 = 0xAA;
+
+/// The fake Left Shift and Num Lock codes, which appear after `0xE0`
+const FAKE_SHIFTS: [u8; 2] = [0x12, 0x59];
+
+/// Bit in the Boot Keys register set when the configured boot key was held
+pub const BOOT_KEY: u8 = 1 << 0;
+/// Bit in the Boot Keys register set when either Shift key was held
+pub const BOOT_KEY_SHIFT: u8 = 1 << 1;
+/// Bit in the Boot Keys register set when either Ctrl key was held
+pub const BOOT_KEY_CTRL: u8 = 1 << 2;
+/// Bit in the Boot Keys register set when either Alt key was held
+pub const BOOT_KEY_ALT: u8 = 1 << 3;
+
+/// How long after power-on we watch for boot keys, in milliseconds. The
+/// keyboard takes up to a second to pass its self-test, and then a held key
+/// only repeats after its typematic delay.
+pub const BOOT_KEY_WINDOW_MS: u32 = 3000;
+
+/// The modifier keys, as key state bitmap bit numbers, and the Boot Keys bit
+/// they set.
+const BOOT_MODIFIERS: [(u8, u8); 6] = [
+	// Left and Right Shift
+	(0x12, BOOT_KEY_SHIFT),
+	(0x59, BOOT_KEY_SHIFT),
+	// Left and Right Ctrl
+	(0x14, BOOT_KEY_CTRL),
+	(0x94, BOOT_KEY_CTRL),
+	// Left and Right Alt
+	(0x11, BOOT_KEY_ALT),
+	(0x91, BOOT_KEY_ALT),
+];
+
+/// Tracks which keys are held down.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyState {
+	/// One bit per key, LSB first
+	bitmap: [u8; Self::SIZE],
+	/// We've seen `PREFIX_EXTENDED`
+	extended: bool,
+	/// We've seen `PREFIX_BREAK`
+	release: bool,
+	/// How many bytes of a Pause sequence we still need to skip
+	skip: u8,
+}
+
+impl KeyState {
+	/// How many bytes the bitmap holds.
+	pub const SIZE: usize = 32;
+
+	/// Create a new key tracker, with no keys held down.
+	pub const fn new() -> KeyState {
+		KeyState {
+			bitmap: [0; Self::SIZE],
+			extended: false,
+			release: false,
+			skip: 0,
+		}
+	}
+
+	/// Feed in a byte received from the keyboard.
+	///
+	/// If a key has just been pressed, returns its bit number. Typematic
+	/// repeats of a key which is already held down don't count.
+	pub fn update(&mut self, byte: u8) -> Option<u8> {
+		if self.skip > 0 {
+			self.skip -= 1;
+			return None;
+		}
+		let mut pressed = None;
+		match byte {
+			PREFIX_EXTENDED => self.extended = true,
+			PREFIX_BREAK => self.release = true,
+			PREFIX_PAUSE => {
+				self.skip = PAUSE_LENGTH;
+				self.extended = false;
+				self.release = false;
+			}
+			SELF_TEST_PASSED => {
+				// The keyboard was plugged in, or reset, so nothing is held
+				self.clear();
+			}
+			_ => {
+				let bit = match (self.extended, byte) {
+					(false, CODE_F7) => Some(BIT_F7),
+					(false, 0x01..=0x7F) => Some(byte),
+					(true, 0x01..=0x7F) if !FAKE_SHIFTS.contains(&byte) => Some(0x80 | byte),
+					// Acknowledgements, errors, etc
+					_ => None,
+				};
+				if let Some(bit) = bit {
+					let mask = 1 << (bit % 8);
+					let byte = &mut self.bitmap[usize::from(bit / 8)];
+					if self.release {
+						*byte &= !mask;
+					} else {
+						if (*byte & mask) == 0 {
+							pressed = Some(bit);
+						}
+						*byte |= mask;
+					}
+				}
+				self.extended = false;
+				self.release = false;
+			}
+		}
+		pressed
+	}
+
+	/// Is the given key held down?
+	///
+	/// `code` is the Scan Code Set 2 make code, without any `0xE0` prefix.
+	pub fn is_pressed(&self, extended: bool, code: u8) -> bool {
+		let bit = match (extended, code) {
+			(false, CODE_F7) => BIT_F7,
+			(false, _) => code & 0x7F,
+			(true, _) => 0x80 | code,
+		};
+		self.bit_is_set(bit)
+	}
+
+	/// Is the key with the given bit number held down?
+	pub fn bit_is_set(&self, bit: u8) -> bool {
+		(self.bitmap[usize::from(bit / 8)] & (1 << (bit % 8))) != 0
+	}
+
+	/// Forget about all the keys, and any half-received sequence.
+	pub fn clear(&mut self) {
+		*self = KeyState::new();
+	}
+
+	/// Get the bitmap for the Keyboard Key State register.
+	pub fn as_bytes(&self) -> &[u8; Self::SIZE] {
+		&self.bitmap
+	}
+}
+
+impl Default for KeyState {
+	fn default() -> Self {
+		KeyState::new()
+	}
+}
+
+/// Watches for keys held down just after power-on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootKeys {
+	/// Are we still inside the window after power-on?
+	watching: bool,
+	/// When the system was powered on
+	power_on_ms: u32,
+	/// Which keys we saw (see `BOOT_KEY`, etc)
+	held: u8,
+}
+
+impl BootKeys {
+	/// Create a new watcher, which isn't watching.
+	pub const fn new() -> BootKeys {
+		BootKeys {
+			watching: false,
+			power_on_ms: 0,
+			held: 0,
+		}
+	}
+
+	/// The system has just powered on, so start watching (and forget what we
+	/// saw last time).
+	pub fn start(&mut self, now_ms: u32) {
+		self.watching = true;
+		self.power_on_ms = now_ms;
+		self.held = 0;
+	}
+
+	/// Check the keys which are held down. Call this whenever the keyboard
+	/// sends us a byte.
+	///
+	/// `boot_key` is a bit number in the key state bitmap, or zero for none.
+	/// Returns `true` if we've just seen a key we hadn't seen before.
+	pub fn check(&mut self, keys: &KeyState, boot_key: u8, now_ms: u32) -> bool {
+		if !self.watching {
+			return false;
+		}
+		if now_ms.wrapping_sub(self.power_on_ms) > BOOT_KEY_WINDOW_MS {
+			self.watching = false;
+			return false;
+		}
+		let mut held = 0;
+		if boot_key != 0 && keys.bit_is_set(boot_key) {
+			held |= BOOT_KEY;
+		}
+		for (bit, flag) in BOOT_MODIFIERS {
+			if keys.bit_is_set(bit) {
+				held |= flag;
+			}
+		}
+		let new = held & !self.held;
+		self.held |= held;
+		new != 0
+	}
+
+	/// Which keys did we see held down after power-on?
+	pub fn held(&self) -> u8 {
+		self.held
+	}
+
+	/// Forget about the keys with a 1 bit in `bits`.
+	pub fn clear(&mut self, bits: u8) {
+		self.held &= !bits;
+	}
+}
+
+impl Default for BootKeys {
+	fn default() -> Self {
+		BootKeys::new()
+	}
+}
+
+/// The typematic rate and delay we start with, in the keyboard's own Set
+/// Typematic format: 10.9 repeats per second, after 500 ms
+pub const DEFAULT_REPEAT_RATE: u8 = 0x2B;
+
+/// The PS/2 Keyboard Repeat Rate bits which mean something
+pub const REPEAT_RATE_BITS: u8 = 0x7F;
+
+/// Goes in the keyboard FIFO before the bit number of a key we repeated, so
+/// the host can tell a repeat from a new press. No key uses bit zero.
+pub const REPEAT_PREFIX: u8 = 0x00;
+
+/// Keys which we never repeat, as key state bitmap bit numbers: Shift, Ctrl,
+/// Alt and the Windows keys, and the three lock keys
+const NO_REPEAT: [u8; 11] = [
+	0x12, 0x59, 0x14, 0x94, 0x11, 0x91, 0x9F, 0xA7, 0x58, 0x77, 0x7E,
+];
+
+/// Repeats the last key pressed, for as long as it is held down.
+///
+/// Like a keyboard, we only repeat one key - pressing another takes over,
+/// and releasing the key stops it. The modifiers and lock keys don't repeat,
+/// and don't stop another key repeating.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyRepeat {
+	/// The delay and rate, in the keyboard's Set Typematic format
+	rate: u8,
+	/// The key we're repeating, if any
+	key: Option<u8>,
+	/// When it next repeats
+	due_ms: u32,
+}
+
+impl KeyRepeat {
+	/// Create a new repeater, with the default rate and nothing to repeat.
+	pub const fn new() -> KeyRepeat {
+		KeyRepeat {
+			rate: DEFAULT_REPEAT_RATE,
+			key: None,
+			due_ms: 0,
+		}
+	}
+
+	/// The delay and rate, in the keyboard's Set Typematic format.
+	pub fn rate(&self) -> u8 {
+		self.rate
+	}
+
+	/// Set the delay and rate, in the keyboard's Set Typematic format: bits
+	/// 6 and 5 are the delay, and bits 4 to 0 the rate.
+	///
+	/// Returns `false`, and changes nothing, if bit 7 is set.
+	pub fn set_rate(&mut self, rate: u8) -> bool {
+		if (rate & !REPEAT_RATE_BITS) != 0 {
+			return false;
+		}
+		self.rate = rate;
+		true
+	}
+
+	/// How long a key is held before it first repeats: 250 ms to 1 second.
+	pub fn delay_ms(&self) -> u32 {
+		(u32::from((self.rate >> 5) & 0x03) + 1) * 250
+	}
+
+	/// How long between repeats: 33 ms (30 per second) to 500 ms (2 per
+	/// second), following the formula in the PS/2 keyboard spec.
+	pub fn period_ms(&self) -> u32 {
+		let a = u32::from(self.rate & 0x07);
+		let b = (self.rate >> 3) & 0x03;
+		((8 + a) << b) * 417 / 100
+	}
+
+	/// A key has just been pressed (see [`KeyState::update`]).
+	pub fn pressed(&mut self, bit: u8, now_ms: u32) {
+		if NO_REPEAT.contains(&bit) {
+			return;
+		}
+		self.key = Some(bit);
+		self.due_ms = now_ms.wrapping_add(self.delay_ms());
+	}
+
+	/// Check whether the key is due to repeat. Call this every few
+	/// milliseconds.
+	///
+	/// Returns the key's bit number when it repeats.
+	pub fn poll(&mut self, keys: &KeyState, now_ms: u32) -> Option<u8> {
+		let bit = self.key?;
+		if !keys.bit_is_set(bit) {
+			self.key = None;
+			return None;
+		}
+		if (now_ms.wrapping_sub(self.due_ms) as i32) < 0 {
+			return None;
+		}
+		self.due_ms = self.due_ms.wrapping_add(self.period_ms());
+		if (now_ms.wrapping_sub(self.due_ms) as i32) >= 0 {
+			// We fell behind, so don't send a burst to catch up
+			self.due_ms = now_ms.wrapping_add(self.period_ms());
+		}
+		Some(bit)
+	}
+}
+
+impl Default for KeyRepeat {
+	fn default() -> Self {
+		KeyRepeat::new()
+	}
+}
+
+/// Caps Lock's bit number in the key state bitmap
+const CAPS_LOCK: u8 = 0x58;
+
+/// Turns key presses into characters, for the keyboard's ASCII mode.
+///
+/// We keep track of Caps Lock ourselves, as the keyboard doesn't tell us.
+/// It starts off, and the keyboard's LED doesn't follow it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsciiKeys {
+	/// Is Caps Lock on?
+	caps_lock: bool,
+}
+
+impl AsciiKeys {
+	/// Create a new translator, with Caps Lock off.
+	pub const fn new() -> AsciiKeys {
+		AsciiKeys { caps_lock: false }
+	}
+
+	/// Is Caps Lock on?
+	pub fn caps_lock(&self) -> bool {
+		self.caps_lock
+	}
+
+	/// A key has just been pressed (see [`KeyState::update`]). Pressing Caps
+	/// Lock turns it on or off.
+	///
+	/// Returns the character the key types, if any.
+	pub fn pressed(&mut self, bit: u8, keys: &KeyState) -> Option<u8> {
+		if bit == CAPS_LOCK {
+			self.caps_lock = !self.caps_lock;
+			return None;
+		}
+		self.translate(bit, keys)
+	}
+
+	/// The character a key types, given the modifiers held down now. Keys
+	/// pressed with Alt don't type anything.
+	pub fn translate(&self, bit: u8, keys: &KeyState) -> Option<u8> {
+		if keys.bit_is_set(0x11) || keys.bit_is_set(0x91) {
+			return None;
+		}
+		let shift = keys.bit_is_set(0x12) || keys.bit_is_set(0x59);
+		let ctrl = keys.bit_is_set(0x14) || keys.bit_is_set(0x94);
+		crate::console::char_for_key(bit, shift, ctrl, self.caps_lock)
+	}
+}
+
+impl Default for AsciiKeys {
+	fn default() -> Self {
+		AsciiKeys::new()
+	}
+}
+
+/// Watches for the keys which reset the main board.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResetChord {
+	/// Were all the keys in the chord held down last time we checked?
+	held: bool,
+}
+
+impl ResetChord {
+	/// Create a new watcher.
+	pub const fn new() -> ResetChord {
+		ResetChord { held: false }
+	}
+
+	/// Check the keys which are held down. Call this whenever the keyboard
+	/// sends us a byte.
+	///
+	/// `chord` holds bit numbers in the key state bitmap, where zero means
+	/// "no key". Returns `true` when the last key of the chord goes down.
+	/// Holding the chord doesn't count again until a key is released.
+	pub fn check(&mut self, keys: &KeyState, chord: &[u8; 3]) -> bool {
+		let mut held = chord.iter().any(|&bit| bit != 0);
+		for &bit in chord.iter().filter(|&&bit| bit != 0) {
+			held &= keys.bit_is_set(bit);
+		}
+		let pressed = held && !self.held;
+		self.held = held;
+		pressed
+	}
+}
+
+impl Default for ResetChord {
+	fn default() -> Self {
+		ResetChord::new()
+	}
+}
+
+/// Where we are in the reset and identify sequence
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum IdStep {
+	/// Not running
+	Idle,
+	/// Waiting to send the reset command
+	SendReset,
+	/// Sent the reset command, waiting for the self-test result
+	AwaitBat,
+	/// Waiting to send the read ID command
+	SendReadId,
+	/// Sent the read ID command, waiting for this many ID bytes so far
+	AwaitId(usize),
+}
+
+/// Resets the keyboard and reads its ID.
+///
+/// We send `0xFF` (reset), and wait for `0xFA` and then the self-test result
+/// (`0xAA` for a pass). Then we send `0xF2` (read ID), and wait for `0xFA`
+/// and up to two ID bytes - an MF2 keyboard sends `0xAB 0x83`, and an old AT
+/// keyboard sends none.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyboardId {
+	/// Where we are in the sequence
+	step: IdStep,
+	/// Has the keyboard acknowledged the command we sent?
+	acked: bool,
+	/// When we last sent a command or heard from the keyboard
+	since_ms: u32,
+	/// What we found (see `KEYBOARD_PRESENT` and friends)
+	status: u8,
+	/// The self-test result the keyboard sent, or zero
+	bat: u8,
+	/// The ID bytes the keyboard sent, zero if it sent fewer than two
+	id: [u8; 2],
+}
+
+impl KeyboardId {
+	/// Create a new identifier, which hasn't started.
+	pub const fn new() -> KeyboardId {
+		KeyboardId {
+			step: IdStep::Idle,
+			acked: false,
+			since_ms: 0,
+			status: KEYBOARD_CHECKING,
+			bat: 0,
+			id: [0; 2],
+		}
+	}
+
+	/// Forget what we found, and start again.
+	pub fn start(&mut self) {
+		*self = KeyboardId::new();
+		self.step = IdStep::SendReset;
+	}
+
+	/// Are we part way through? If so, bytes from the keyboard belong to us,
+	/// not the key state.
+	pub fn is_running(&self) -> bool {
+		self.step != IdStep::Idle
+	}
+
+	/// What we found (see `KEYBOARD_PRESENT` and friends)
+	pub fn status(&self) -> u8 {
+		self.status
+	}
+
+	/// The self-test result the keyboard sent, or zero.
+	pub fn bat(&self) -> u8 {
+		self.bat
+	}
+
+	/// The ID bytes the keyboard sent, zero if it sent fewer than two.
+	pub fn id(&self) -> [u8; 2] {
+		self.id
+	}
+
+	/// Stop, with this result.
+	fn finish(&mut self, status: u8) {
+		self.status = status;
+		self.step = IdStep::Idle;
+	}
+
+	/// Get the next byte to send to the keyboard, if any.
+	pub fn next_command(&mut self, now_ms: u32) -> Option<u8> {
+		let (next, byte) = match self.step {
+			IdStep::SendReset => (IdStep::AwaitBat, CMD_RESET),
+			IdStep::SendReadId => (IdStep::AwaitId(0), CMD_READ_ID),
+			_ => return None,
+		};
+		self.step = next;
+		self.acked = false;
+		self.since_ms = now_ms;
+		Some(byte)
+	}
+
+	/// The byte from [`KeyboardId::next_command`] has been clocked out.
+	/// `acked` is the acknowledge bit from the keyboard.
+	pub fn command_sent(&mut self, acked: bool, now_ms: u32) {
+		self.since_ms = now_ms;
+		match self.step {
+			// Nobody clocked the reset in, so there's no keyboard
+			IdStep::AwaitBat if !acked => self.finish(KEYBOARD_ABSENT),
+			// It passed its self-test, so it's there, but won't say what it is
+			IdStep::AwaitId(_) if !acked => self.finish(KEYBOARD_PRESENT),
+			_ => {}
+		}
+	}
+
+	/// Handle a byte from the keyboard.
+	///
+	/// Returns `true` if the byte was part of the sequence, so isn't a key.
+	pub fn update(&mut self, byte: u8, now_ms: u32) -> bool {
+		self.since_ms = now_ms;
+		match self.step {
+			IdStep::Idle => return false,
+			IdStep::AwaitBat => match byte {
+				ACK => self.acked = true,
+				RESEND if !self.acked => self.step = IdStep::SendReset,
+				BAT_PASSED => {
+					self.bat = byte;
+					self.step = IdStep::SendReadId;
+				}
+				BAT_FAILED | BAT_FAILED_ALT => {
+					self.bat = byte;
+					self.finish(KEYBOARD_FAILED);
+				}
+				_ => {}
+			},
+			IdStep::AwaitId(_) if !self.acked => match byte {
+				ACK => self.acked = true,
+				RESEND => self.step = IdStep::SendReadId,
+				_ => {}
+			},
+			IdStep::AwaitId(count) => {
+				self.id[count] = byte;
+				if count + 1 == self.id.len() {
+					self.finish(KEYBOARD_PRESENT);
+				} else {
+					self.step = IdStep::AwaitId(count + 1);
+				}
+			}
+			// Keys pressed just as we start
+			IdStep::SendReset | IdStep::SendReadId => {}
+		}
+		true
+	}
+
+	/// Give up on answers which haven't come. Call this every so often.
+	pub fn poll(&mut self, now_ms: u32) {
+		let waited_ms = now_ms.wrapping_sub(self.since_ms);
+		match self.step {
+			IdStep::AwaitBat if waited_ms > BAT_TIMEOUT_MS => self.finish(if self.acked {
+				KEYBOARD_FAILED
+			} else {
+				KEYBOARD_ABSENT
+			}),
+			// An AT keyboard sends no ID bytes at all
+			IdStep::AwaitId(_) if waited_ms > ID_TIMEOUT_MS => self.finish(KEYBOARD_PRESENT),
+			_ => {}
+		}
+	}
+}
+
+impl Default for KeyboardId {
+	fn default() -> Self {
+		KeyboardId::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Left Control
+	const LCTRL: u8 = 0x14;
+	/// Right Control is `0xE0 0x14`
+	const RCTRL: u8 = 0x14;
+	/// Delete is `0xE0 0x71`
+	const DELETE: u8 = 0x71;
+
+	fn feed(keys: &mut KeyState, bytes: &[u8]) {
+		for &b in bytes {
+			keys.update(b);
+		}
+	}
+
+	#[test]
+	fn make_and_break() {
+		let mut keys = KeyState::new();
+		feed(&mut keys, &[LCTRL]);
+		assert!(keys.is_pressed(false, LCTRL));
+		assert!(!keys.is_pressed(true, RCTRL));
+		assert_eq!(keys.as_bytes()[2], 0x10);
+		feed(&mut keys, &[0xE0, RCTRL, 0xE0, DELETE]);
+		assert!(keys.is_pressed(true, RCTRL));
+		assert!(keys.is_pressed(true, DELETE));
+		feed(&mut keys, &[0xF0, LCTRL, 0xE0, 0xF0, DELETE]);
+		assert!(!keys.is_pressed(false, LCTRL));
+		assert!(keys.is_pressed(true, RCTRL));
+		assert!(!keys.is_pressed(true, DELETE));
+	}
+
+	#[test]
+	fn presses() {
+		let mut keys = KeyState::new();
+		let pressed: Vec<u8> = [
+			LCTRL, LCTRL, LCTRL, 0xE0, DELETE, 0xF0, LCTRL, 0xE0, 0xF0, DELETE, LCTRL,
+		]
+		.iter()
+		.filter_map(|&b| keys.update(b))
+		.collect();
+		// The repeats and the releases are left out
+		assert_eq!(pressed, [LCTRL, 0x80 | DELETE, LCTRL]);
+	}
+
+	#[test]
+	fn awkward_keys() {
+		let mut keys = KeyState::new();
+		feed(&mut keys, &[CODE_F7]);
+		assert!(keys.is_pressed(false, CODE_F7));
+		assert_eq!(keys.as_bytes()[0], 1 << BIT_F7);
+		feed(&mut keys, &[0xF0, CODE_F7]);
+		// Pause, then Print Screen with its fake shift
+		feed(&mut keys, &[0xE1, 0x14, 0x77, 0xE1, 0xF0, 0x14, 0xF0, 0x77]);
+		feed(&mut keys, &[0xE0, 0x12, 0xE0, 0x7C]);
+		assert!(keys.is_pressed(true, 0x7C));
+		assert!(!keys.is_pressed(true, 0x12));
+		assert!(!keys.is_pressed(false, 0x14));
+		assert!(!keys.is_pressed(false, 0x77));
+		// An ACK changes nothing, and a self-test clears everything
+		feed(&mut keys, &[0xFA]);
+		assert!(keys.is_pressed(true, 0x7C));
+		feed(&mut keys, &[0xAA]);
+		assert_eq!(keys.as_bytes(), &[0u8; KeyState::SIZE]);
+	}
+
+	#[test]
+	fn boot_keys() {
+		let mut keys = KeyState::new();
+		let mut boot = BootKeys::new();
+		feed(&mut keys, &[0xE0, DELETE]);
+		// Not watching until we power on
+		assert!(!boot.check(&keys, 0x80 | DELETE, 100));
+		boot.start(1000);
+		assert!(boot.check(&keys, 0x80 | DELETE, 1500));
+		// Only new keys count
+		assert!(!boot.check(&keys, 0x80 | DELETE, 1600));
+		feed(&mut keys, &[LCTRL]);
+		assert!(boot.check(&keys, 0x80 | DELETE, 1700));
+		assert_eq!(boot.held(), BOOT_KEY | BOOT_KEY_CTRL);
+		boot.clear(BOOT_KEY);
+		assert_eq!(boot.held(), BOOT_KEY_CTRL);
+		// Too late
+		feed(&mut keys, &[0x12]);
+		assert!(!boot.check(&keys, 0x80 | DELETE, 1000 + BOOT_KEY_WINDOW_MS + 1));
+		assert_eq!(boot.held(), BOOT_KEY_CTRL);
+		// Powering on again starts afresh, and zero disables the boot key
+		boot.start(10_000);
+		assert!(boot.check(&keys, 0, 10_001));
+		assert_eq!(boot.held(), BOOT_KEY_SHIFT | BOOT_KEY_CTRL);
+	}
+
+	#[test]
+	fn key_repeat() {
+		let mut keys = KeyState::new();
+		let mut repeat = KeyRepeat::new();
+		assert_eq!(repeat.delay_ms(), 500);
+		assert_eq!(repeat.period_ms(), 91);
+		assert!(!repeat.set_rate(0x80));
+		assert!(repeat.set_rate(0x00));
+		assert_eq!((repeat.delay_ms(), repeat.period_ms()), (250, 33));
+		assert!(repeat.set_rate(0x7F));
+		assert_eq!((repeat.delay_ms(), repeat.period_ms()), (1000, 500));
+		assert!(repeat.set_rate(0x20));
+		// Nothing held
+		assert_eq!(repeat.poll(&keys, 0), None);
+		// Hold 'A'
+		let bit = keys.update(0x1C).unwrap();
+		repeat.pressed(bit, 1000);
+		assert_eq!(repeat.poll(&keys, 1499), None);
+		assert_eq!(repeat.poll(&keys, 1500), Some(0x1C));
+		assert_eq!(repeat.poll(&keys, 1532), None);
+		assert_eq!(repeat.poll(&keys, 1533), Some(0x1C));
+		// Shift doesn't take over
+		let shift = keys.update(0x12).unwrap();
+		repeat.pressed(shift, 1540);
+		assert_eq!(repeat.poll(&keys, 1566), Some(0x1C));
+		// 'B' does
+		let bit = keys.update(0x32).unwrap();
+		repeat.pressed(bit, 1570);
+		assert_eq!(repeat.poll(&keys, 1600), None);
+		assert_eq!(repeat.poll(&keys, 2070), Some(0x32));
+		// Falling behind doesn't bring a burst
+		assert_eq!(repeat.poll(&keys, 2200), Some(0x32));
+		assert_eq!(repeat.poll(&keys, 2210), None);
+		// Letting go stops it, for good
+		keys.update(0xF0);
+		keys.update(0x32);
+		assert_eq!(repeat.poll(&keys, 2300), None);
+		keys.update(0x32);
+		assert_eq!(repeat.poll(&keys, 2400), None);
+	}
+
+	#[test]
+	fn ascii_keys() {
+		let mut keys = KeyState::new();
+		let mut ascii = AsciiKeys::new();
+		let mut press = |keys: &mut KeyState, code| {
+			let bit = keys.update(code).unwrap();
+			ascii.pressed(bit, keys)
+		};
+		// 'a', then Shift + 'a'
+		assert_eq!(press(&mut keys, 0x1C), Some(b'a'));
+		feed(&mut keys, &[0xF0, 0x1C]);
+		assert_eq!(press(&mut keys, 0x12), None);
+		assert_eq!(press(&mut keys, 0x1C), Some(b'A'));
+		feed(&mut keys, &[0xF0, 0x1C, 0xF0, 0x12]);
+		// Caps Lock, then 'a' and '1'
+		assert_eq!(press(&mut keys, 0x58), None);
+		feed(&mut keys, &[0xF0, 0x58]);
+		assert_eq!(press(&mut keys, 0x1C), Some(b'A'));
+		feed(&mut keys, &[0xF0, 0x1C]);
+		assert_eq!(press(&mut keys, 0x16), Some(b'1'));
+		feed(&mut keys, &[0xF0, 0x16]);
+		// Right Ctrl + 'c'
+		feed(&mut keys, &[0xE0, RCTRL]);
+		assert_eq!(press(&mut keys, 0x21), Some(0x03));
+		feed(&mut keys, &[0xF0, 0x21, 0xE0, 0xF0, RCTRL]);
+		// Alt + 'a'
+		feed(&mut keys, &[0x11]);
+		assert_eq!(press(&mut keys, 0x1C), None);
+		feed(&mut keys, &[0xF0, 0x1C, 0xF0, 0x11]);
+		// Caps Lock off again
+		assert_eq!(press(&mut keys, 0x58), None);
+		assert_eq!(press(&mut keys, 0x1C), Some(b'a'));
+		assert!(!ascii.caps_lock());
+	}
+
+	#[test]
+	fn reset_chord() {
+		/// Left Alt
+		const LALT: u8 = 0x11;
+		let chord = [LCTRL, LALT, 0x80 | DELETE];
+		let mut keys = KeyState::new();
+		let mut reset = ResetChord::new();
+		feed(&mut keys, &[LCTRL, LALT]);
+		assert!(!reset.check(&keys, &chord));
+		feed(&mut keys, &[0xE0, DELETE]);
+		assert!(reset.check(&keys, &chord));
+		// Holding it down (or typematic repeat) doesn't count again
+		feed(&mut keys, &[0xE0, DELETE]);
+		assert!(!reset.check(&keys, &chord));
+		// Until a key is released and pressed again
+		feed(&mut keys, &[0xE0, 0xF0, DELETE]);
+		assert!(!reset.check(&keys, &chord));
+		feed(&mut keys, &[0xE0, DELETE]);
+		assert!(reset.check(&keys, &chord));
+		// Just one key
+		feed(&mut keys, &[0xF0, LALT]);
+		assert!(!reset.check(&keys, &[LALT, 0, 0]));
+		feed(&mut keys, &[LALT]);
+		assert!(reset.check(&keys, &[LALT, 0, 0]));
+		// Turned off
+		assert!(!reset.check(&keys, &[0, 0, 0]));
+		assert!(!reset.check(&keys, &[0, 0, 0]));
+	}
+
+	#[test]
+	fn keyboard_id() {
+		let mut id = KeyboardId::new();
+		assert!(!id.is_running());
+		assert_eq!(id.next_command(0), None);
+		assert!(!id.update(0x1C, 0));
+		// An MF2 keyboard, which asks for the reset again first
+		id.start();
+		assert_eq!(id.status(), KEYBOARD_CHECKING);
+		assert_eq!(id.next_command(10), Some(CMD_RESET));
+		assert!(id.update(RESEND, 12));
+		assert_eq!(id.next_command(12), Some(CMD_RESET));
+		id.command_sent(true, 30);
+		assert!(id.update(ACK, 31));
+		assert_eq!(id.next_command(40), None);
+		assert!(id.update(BAT_PASSED, 600));
+		assert_eq!(id.next_command(610), Some(CMD_READ_ID));
+		id.command_sent(true, 630);
+		assert!(id.update(ACK, 631));
+		assert!(id.update(0xAB, 632));
+		assert!(id.update(0x83, 633));
+		assert!(!id.is_running());
+		assert_eq!(id.status(), KEYBOARD_PRESENT);
+		assert_eq!(id.bat(), BAT_PASSED);
+		assert_eq!(id.id(), [0xAB, 0x83]);
+		// Key presses are keys again
+		assert!(!id.update(0x1C, 700));
+		// An AT keyboard, which sends no ID
+		id.start();
+		assert_eq!(id.id(), [0, 0]);
+		assert_eq!(id.next_command(0), Some(CMD_RESET));
+		id.command_sent(true, 20);
+		id.update(ACK, 21);
+		id.update(BAT_PASSED, 500);
+		assert_eq!(id.next_command(510), Some(CMD_READ_ID));
+		id.command_sent(true, 530);
+		id.update(ACK, 531);
+		id.poll(600);
+		assert!(id.is_running());
+		id.poll(700);
+		assert_eq!(id.status(), KEYBOARD_PRESENT);
+		assert_eq!(id.id(), [0, 0]);
+		// Nothing plugged in
+		id.start();
+		assert_eq!(id.next_command(0), Some(CMD_RESET));
+		id.command_sent(false, 20);
+		assert_eq!(id.status(), KEYBOARD_ABSENT);
+		// A keyboard which fails its self-test
+		id.start();
+		assert_eq!(id.next_command(0), Some(CMD_RESET));
+		id.command_sent(true, 20);
+		id.update(ACK, 21);
+		id.update(BAT_FAILED, 500);
+		assert_eq!(id.status(), KEYBOARD_FAILED);
+		assert_eq!(id.bat(), BAT_FAILED);
+		// A keyboard which never finishes its self-test
+		id.start();
+		assert_eq!(id.next_command(0), Some(CMD_RESET));
+		id.command_sent(true, 20);
+		id.update(ACK, 21);
+		id.poll(1000);
+		assert!(id.is_running());
+		id.poll(1100);
+		assert_eq!(id.status(), KEYBOARD_FAILED);
+		assert_eq!(id.bat(), 0);
+	}
+}
@@ -0,0 +1,207 @@
+//! # Latency Measurement
+//!
+//! To see how changes to the firmware affect its real-time behaviour, we
+//! time two things:
+//!
+//! * SPI service - from the host pulling chip select low, to the response
+//!   being ready to clock out.
+//! * PS/2 service - from the last bit of a PS/2 word arriving, to the byte
+//!   being visible to the host (in the key state or the mouse report FIFO).
+//!
+//! TIM14 runs freely at 1 MHz as a stopwatch. It is only 16 bits wide, so
+//! each [`Stamp`] also carries the monotonic timer's millisecond count, for
+//! anything which takes longer than the stopwatch can measure.
+
+use stm32f0xx_hal::pac;
+
+/// How fast the stopwatch ticks
+pub const STOPWATCH_HZ: u32 = 1_000_000;
+
+/// Up to this many milliseconds apart, the stopwatch can't have wrapped
+/// (allowing for the millisecond count only moving every few milliseconds).
+const MAX_STOPWATCH_MS: u32 = 50;
+
+/// When something happened.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Stamp {
+	/// The monotonic timer, in milliseconds
+	ms: u32,
+	/// The stopwatch, in microseconds (wraps every 65.536 ms)
+	us: u16,
+}
+
+impl Stamp {
+	/// Make a stamp from the two clocks.
+	pub const fn new(ms: u32, us: u16) -> Stamp {
+		Stamp { ms, us }
+	}
+
+	/// The monotonic timer, in milliseconds.
+	pub fn ms(&self) -> u32 {
+		self.ms
+	}
+
+	/// The stopwatch, in microseconds.
+	pub fn us(&self) -> u16 {
+		self.us
+	}
+
+	/// How many microseconds passed between `earlier` and this stamp.
+	///
+	/// Long gaps are only as accurate as the millisecond count.
+	pub fn micros_since(&self, earlier: Stamp) -> u32 {
+		let ms = self.ms.wrapping_sub(earlier.ms);
+		if ms > MAX_STOPWATCH_MS {
+			ms.saturating_mul(1000)
+		} else {
+			u32::from(self.us.wrapping_sub(earlier.us))
+		}
+	}
+}
+
+/// The worst and average of a set of latency measurements.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LatencyStats {
+	/// The longest measurement, in microseconds
+	worst_us: u32,
+	/// All the measurements added up, in microseconds
+	total_us: u64,
+	/// How many measurements there have been
+	count: u32,
+}
+
+impl LatencyStats {
+	/// How many bytes the latency registers hold.
+	pub const SIZE: usize = 8;
+
+	/// Create an empty set of measurements.
+	pub const fn new() -> LatencyStats {
+		LatencyStats {
+			worst_us: 0,
+			total_us: 0,
+			count: 0,
+		}
+	}
+
+	/// Add a measurement, in microseconds.
+	pub fn record(&mut self, us: u32) {
+		self.worst_us = self.worst_us.max(us);
+		self.total_us = self.total_us.saturating_add(u64::from(us));
+		self.count = self.count.saturating_add(1);
+	}
+
+	/// The longest measurement, in microseconds.
+	pub fn worst_us(&self) -> u32 {
+		self.worst_us
+	}
+
+	/// The average measurement, in microseconds, or zero if there are none.
+	pub fn mean_us(&self) -> u32 {
+		if self.count == 0 {
+			0
+		} else {
+			(self.total_us / u64::from(self.count)) as u32
+		}
+	}
+
+	/// How many measurements there have been.
+	pub fn count(&self) -> u32 {
+		self.count
+	}
+
+	/// Throw away all the measurements.
+	pub fn clear(&mut self) {
+		*self = LatencyStats::new();
+	}
+
+	/// The register contents: the worst and the average (each in
+	/// microseconds, as a `u16le` which sticks at 65535), then the count as a
+	/// `u32le`.
+	pub fn as_bytes(&self) -> [u8; Self::SIZE] {
+		let saturate = |us: u32| us.min(u32::from(u16::MAX)) as u16;
+		let worst = saturate(self.worst_us).to_le_bytes();
+		let mean = saturate(self.mean_us()).to_le_bytes();
+		let count = self.count.to_le_bytes();
+		[
+			worst[0], worst[1], mean[0], mean[1], count[0], count[1], count[2], count[3],
+		]
+	}
+}
+
+impl Default for LatencyStats {
+	fn default() -> Self {
+		LatencyStats::new()
+	}
+}
+
+/// Runs TIM14 as a free-running 1 MHz stopwatch.
+pub struct Stopwatch {
+	tim: pac::TIM14,
+}
+
+impl Stopwatch {
+	/// Start TIM14 counting, given the current timer clock speed.
+	pub fn new(tim: pac::TIM14, clock_hz: u32) -> Stopwatch {
+		// Safety: we only touch the TIM14 enable bit, which nothing else
+		// modifies after init.
+		let rcc = unsafe { &*pac::RCC::ptr() };
+		rcc.apb1enr.modify(|_r, w| w.tim14en().set_bit());
+		tim.arr.write(|w| unsafe { w.bits(0xFFFF) });
+		let mut stopwatch = Stopwatch { tim };
+		stopwatch.set_clock(clock_hz);
+		stopwatch.tim.cr1.write(|w| w.cen().set_bit());
+		stopwatch
+	}
+
+	/// Keep ticking at 1 MHz after the timer clock changes speed.
+	pub fn set_clock(&mut self, clock_hz: u32) {
+		self.tim
+			.psc
+			.write(|w| unsafe { w.bits((clock_hz / STOPWATCH_HZ) - 1) });
+		// Load the new prescaler straight away
+		self.tim.egr.write(|w| w.ug().set_bit());
+	}
+
+	/// Read the stopwatch, in microseconds.
+	///
+	/// This only reads the counter, so it is safe to call from any priority.
+	pub fn now_us() -> u16 {
+		// Safety: reading the counter has no side effects
+		let tim = unsafe { &*pac::TIM14::ptr() };
+		tim.cnt.read().bits() as u16
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn stamps() {
+		let start = Stamp::new(100, 65_000);
+		// The stopwatch has wrapped
+		assert_eq!(Stamp::new(105, 1_000).micros_since(start), 1_536);
+		// Too long for the stopwatch, so we fall back to milliseconds
+		assert_eq!(Stamp::new(200, 0).micros_since(start), 100_000);
+	}
+
+	#[test]
+	fn stats() {
+		let mut stats = LatencyStats::new();
+		assert_eq!(stats.mean_us(), 0);
+		assert_eq!(stats.as_bytes(), [0; LatencyStats::SIZE]);
+		stats.record(10);
+		stats.record(30);
+		stats.record(100_000);
+		assert_eq!(stats.worst_us(), 100_000);
+		assert_eq!(stats.mean_us(), 33_346);
+		assert_eq!(stats.count(), 3);
+		// The worst case sticks at the top of a u16
+		assert_eq!(
+			stats.as_bytes(),
+			[0xFF, 0xFF, 0x42, 0x82, 0x03, 0x00, 0x00, 0x00]
+		);
+		stats.clear();
+		assert_eq!(stats.count(), 0);
+	}
+}
@@ -1,22 +1,142 @@
-#![no_std]
+//! The parts of the Neotron BMC firmware which aren't RTIC tasks.
+//!
+//! The pure logic modules (like [`power`], [`registers`] and [`health`]) have unit tests,
+//! which run on the host with:
+//!
+//! ```console
+//! $ cargo test --lib --target x86_64-unknown-linux-gnu
+//! ```
 
+#![cfg_attr(not(test), no_std)]
+
+#[cfg(not(test))]
 use core::sync::atomic::{AtomicUsize, Ordering};
 
+#[cfg(not(test))]
 use defmt_rtt as _; // global logger
+#[cfg(not(test))]
 use panic_probe as _;
 use stm32f0xx_hal as _; // memory layout // panic handler
 
+#[cfg(all(feature = "current-sense", feature = "fan"))]
+compile_error!(
+	"The `current-sense` and `fan` features both need the ADC, so can't be enabled together"
+);
+
+#[cfg(all(feature = "encoder", feature = "fan"))]
+compile_error!(
+	"The `encoder` and `fan` features both need the I²C pins, so can't be enabled together"
+);
+
+#[cfg(all(feature = "i2c-target", any(feature = "fan", feature = "encoder")))]
+compile_error!(
+	"The `i2c-target` feature needs the I²C pins, so can't be enabled with `fan` or `encoder`"
+);
+
+#[cfg(all(
+	feature = "ps2-power",
+	any(feature = "fan", feature = "encoder", feature = "i2c-target")
+))]
+compile_error!(
+	"The `ps2-power` feature needs the I²C pins, so can't be enabled with `fan`, `encoder` or `i2c-target`"
+);
+
+#[cfg(all(
+	feature = "status-led",
+	any(
+		feature = "fan",
+		feature = "encoder",
+		feature = "i2c-target",
+		feature = "ps2-power"
+	)
+))]
+compile_error!(
+	"The `status-led` feature needs the I²C pins, so can't be enabled with `fan`, `encoder`, `i2c-target` or `ps2-power`"
+);
+
+#[cfg(any(
+	all(feature = "stm32f030x6", feature = "stm32f031"),
+	all(feature = "stm32f030x6", feature = "stm32f042"),
+	all(feature = "stm32f030x6", feature = "stm32f072"),
+	all(feature = "stm32f031", feature = "stm32f042"),
+	all(feature = "stm32f031", feature = "stm32f072"),
+	all(feature = "stm32f042", feature = "stm32f072")
+))]
+compile_error!(
+	"Only one of the `stm32f030x6`, `stm32f031`, `stm32f042` and `stm32f072` features can be enabled (the first is on by default, so use `--no-default-features` for the others)"
+);
+
+#[cfg(all(
+	feature = "usb",
+	not(any(feature = "stm32f042", feature = "stm32f072"))
+))]
+compile_error!("The `usb` feature needs a part with USB, so enable `stm32f042` or `stm32f072` too");
+
+pub mod adcsched;
+pub mod analog;
+pub mod board;
+pub mod bulk;
+pub mod buttons;
+pub mod buzzer;
+pub mod changes;
+pub mod config;
+pub mod console;
+#[cfg(feature = "current-sense")]
+pub mod currentsense;
+pub mod deferred;
+pub mod encoder;
+pub mod eventlog;
+pub mod expansion;
+pub mod failsafe;
+#[cfg(feature = "fan")]
+pub mod fan;
+pub mod faults;
+pub mod glitch;
+pub mod health;
+#[cfg(feature = "i2c-target")]
+pub mod i2ctarget;
+pub mod irq;
+pub mod isrstatus;
+pub mod keyboard;
+pub mod latency;
+pub mod lock;
+pub mod logging;
+pub mod loopback;
+pub mod mouse;
+pub mod nvram;
+pub mod power;
 pub mod ps2;
+pub mod ps2power;
+pub mod ps2timing;
+pub mod readout;
+pub mod registers;
+pub mod risetime;
+pub mod rtc;
+pub mod selftest;
 pub mod spi;
+pub mod spitrace;
+pub mod standby;
+pub mod stats;
+pub mod statusled;
+pub mod thermal;
+pub mod uart;
+pub mod uartlink;
+#[cfg(feature = "usb")]
+pub mod usb;
+pub mod watch;
+pub mod watchdog;
 
 // same panicking *behavior* as `panic-probe` but doesn't print a panic message
 // this prevents the panic message being printed *twice* when `defmt::panic` is invoked
+#[cfg(not(test))]
 #[defmt::panic_handler]
 fn panic() -> ! {
 	cortex_m::asm::udf()
 }
 
+#[cfg(not(test))]
 static COUNT: AtomicUsize = AtomicUsize::new(0);
+#[cfg(not(test))]
 defmt::timestamp!("{=usize}", {
 	// NOTE(no-CAS) `timestamps` runs with interrupts disabled
 	let n = COUNT.load(Ordering::Relaxed);
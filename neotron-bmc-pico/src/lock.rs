@@ -0,0 +1,110 @@
+//! # Configuration Lock
+//!
+//! A crashed OS can scribble anything over the SPI bus. Once the host has set
+//! things up, it can lock the configuration, and from then on writes to the
+//! registers which could brick or power-cycle the machine (the `GUARDED`
+//! registers in the protocol crate's register map) are refused with a
+//! `Locked` result.
+//!
+//! To unlock, the host writes two keys to the Configuration Lock register,
+//! one straight after the other. Any other request in between starts the
+//! sequence again, so stray writes are very unlikely to unlock it.
+
+/// Write this to the Configuration Lock register to lock the configuration
+pub const LOCK_COMMAND_LOCK: u8 = 0x01;
+
+/// Write this to the Configuration Lock register to start unlocking
+pub const UNLOCK_KEY_1: u8 = 0x4E;
+
+/// Write this to the Configuration Lock register, straight after
+/// `UNLOCK_KEY_1`, to unlock
+pub const UNLOCK_KEY_2: u8 = 0xB1;
+
+/// Whether the configuration is locked, and how far through unlocking it the
+/// host has got.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigLock {
+	/// Are writes to the guarded registers refused?
+	locked: bool,
+	/// Was the last request the first unlock key?
+	key_seen: bool,
+}
+
+impl ConfigLock {
+	/// Create the lock, unlocked.
+	pub const fn new() -> ConfigLock {
+		ConfigLock {
+			locked: false,
+			key_seen: false,
+		}
+	}
+
+	/// Are writes to the guarded registers refused?
+	pub fn is_locked(&self) -> bool {
+		self.locked
+	}
+
+	/// Handle a write to the Configuration Lock register.
+	///
+	/// Returns `false` if `byte` isn't a command, or is the second unlock key
+	/// without the first.
+	pub fn write(&mut self, byte: u8) -> bool {
+		let key_seen = core::mem::replace(&mut self.key_seen, false);
+		match byte {
+			LOCK_COMMAND_LOCK => {
+				self.locked = true;
+				true
+			}
+			UNLOCK_KEY_1 => {
+				self.key_seen = true;
+				true
+			}
+			UNLOCK_KEY_2 if key_seen => {
+				self.locked = false;
+				true
+			}
+			_ => false,
+		}
+	}
+
+	/// Some other request arrived, so an unlock has to start again.
+	pub fn interrupt(&mut self) {
+		self.key_seen = false;
+	}
+}
+
+impl Default for ConfigLock {
+	fn default() -> Self {
+		ConfigLock::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn lock_and_unlock() {
+		let mut lock = ConfigLock::new();
+		assert!(!lock.is_locked());
+		assert!(lock.write(LOCK_COMMAND_LOCK));
+		assert!(lock.is_locked());
+		// The second key on its own does nothing
+		assert!(!lock.write(UNLOCK_KEY_2));
+		assert!(lock.is_locked());
+		// Something else in between
+		assert!(lock.write(UNLOCK_KEY_1));
+		lock.interrupt();
+		assert!(!lock.write(UNLOCK_KEY_2));
+		assert!(lock.is_locked());
+		// A bad value in between
+		assert!(lock.write(UNLOCK_KEY_1));
+		assert!(!lock.write(0x00));
+		assert!(!lock.write(UNLOCK_KEY_2));
+		assert!(lock.is_locked());
+		// The keys back to back
+		assert!(lock.write(UNLOCK_KEY_1));
+		assert!(lock.write(UNLOCK_KEY_2));
+		assert!(!lock.is_locked());
+	}
+}
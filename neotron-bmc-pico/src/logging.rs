@@ -0,0 +1,178 @@
+//! # Logging
+//!
+//! All the firmware's logging goes through the macros here, rather than
+//! calling `defmt` directly, so a production build can leave it out.
+//!
+//! Normally each macro is the `defmt` macro of the same name, and the
+//! `DEFMT_LOG` environment variable picks the level at compile time, as
+//! usual. With the `silent` feature, no logging code is generated at all
+//! (the arguments are still type-checked, so a silent build can't break a
+//! logging one), which saves flash and keeps logging from ever affecting
+//! timing. Panic messages are still sent, as there's nothing more to lose.
+//!
+//! Anything which can happen once per byte (like a PS/2 byte arriving)
+//! should log at `trace` level, so it only costs time when asked for.
+//!
+//! The host can also turn logging down (or back up) at run-time, with the
+//! Log Level and Log Subsystems registers, so a unit in the field can be made
+//! more verbose without reflashing. The run-time level can't log anything
+//! `DEFMT_LOG` left out at compile time. Messages are tagged with the
+//! [`Subsystem`] they come from, like this:
+//!
+//! ```rust,ignore
+//! info!(target: Subsystem::Ps2, "< KB 0x{:x}", byte);
+//! ```
+//!
+//! Untagged messages count as [`Subsystem::System`].
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+/// The run-time level which logs everything down to `debug`, but not the
+/// per-byte `trace` messages
+pub const DEFAULT_LEVEL: u8 = Level::Debug as u8;
+
+/// The subsystems we log messages from at start-up (all of them)
+pub const DEFAULT_SUBSYSTEMS: u8 = 0b1111;
+
+/// How important a message is.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+	/// Something went wrong
+	Error = 1,
+	/// Something might be about to go wrong
+	Warn = 2,
+	/// Something of interest
+	Info = 3,
+	/// Something for debugging
+	Debug = 4,
+	/// Something which happens very often
+	Trace = 5,
+}
+
+/// Where a message comes from, as a bit in the Log Subsystems register.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Subsystem {
+	/// Requests from the host, over SPI, I²C, the UART command channel or USB
+	Spi = 1 << 0,
+	/// The PS/2 keyboard and mouse
+	Ps2 = 1 << 1,
+	/// The main board power, the buttons, and the power and thermal faults
+	Power = 1 << 2,
+	/// Everything else
+	System = 1 << 3,
+}
+
+/// The least important level we log, or zero to log nothing
+static LEVEL: AtomicU8 = AtomicU8::new(DEFAULT_LEVEL);
+
+/// Which subsystems we log, as a bitmask of [`Subsystem`]s
+static SUBSYSTEMS: AtomicU8 = AtomicU8::new(DEFAULT_SUBSYSTEMS);
+
+/// Set the run-time filter, from the Log Level and Log Subsystems
+/// registers.
+///
+/// This is just two stores, so it is safe to call from any priority.
+pub fn set_filter(level: u8, subsystems: u8) {
+	LEVEL.store(level, Ordering::Relaxed);
+	SUBSYSTEMS.store(subsystems, Ordering::Relaxed);
+}
+
+/// Should a message at this level, from this subsystem, be logged?
+pub fn enabled(level: Level, subsystem: Subsystem) -> bool {
+	(level as u8) <= LEVEL.load(Ordering::Relaxed)
+		&& (SUBSYSTEMS.load(Ordering::Relaxed) & subsystem as u8) != 0
+}
+
+/// Log an error (see [`defmt::error`]).
+#[macro_export]
+macro_rules! error {
+	(target: $subsystem:expr, $($arg:tt)*) => {{
+		if !cfg!(feature = "silent")
+			&& $crate::logging::enabled($crate::logging::Level::Error, $subsystem)
+		{
+			defmt::error!($($arg)*);
+		}
+	}};
+	($($arg:tt)*) => {
+		$crate::error!(target: $crate::logging::Subsystem::System, $($arg)*)
+	};
+}
+
+/// Log a warning (see [`defmt::warn`]).
+#[macro_export]
+macro_rules! warn {
+	(target: $subsystem:expr, $($arg:tt)*) => {{
+		if !cfg!(feature = "silent")
+			&& $crate::logging::enabled($crate::logging::Level::Warn, $subsystem)
+		{
+			defmt::warn!($($arg)*);
+		}
+	}};
+	($($arg:tt)*) => {
+		$crate::warn!(target: $crate::logging::Subsystem::System, $($arg)*)
+	};
+}
+
+/// Log something of interest (see [`defmt::info`]).
+#[macro_export]
+macro_rules! info {
+	(target: $subsystem:expr, $($arg:tt)*) => {{
+		if !cfg!(feature = "silent")
+			&& $crate::logging::enabled($crate::logging::Level::Info, $subsystem)
+		{
+			defmt::info!($($arg)*);
+		}
+	}};
+	($($arg:tt)*) => {
+		$crate::info!(target: $crate::logging::Subsystem::System, $($arg)*)
+	};
+}
+
+/// Log something for debugging (see [`defmt::debug`]).
+#[macro_export]
+macro_rules! debug {
+	(target: $subsystem:expr, $($arg:tt)*) => {{
+		if !cfg!(feature = "silent")
+			&& $crate::logging::enabled($crate::logging::Level::Debug, $subsystem)
+		{
+			defmt::debug!($($arg)*);
+		}
+	}};
+	($($arg:tt)*) => {
+		$crate::debug!(target: $crate::logging::Subsystem::System, $($arg)*)
+	};
+}
+
+/// Log something which happens very often (see [`defmt::trace`]).
+#[macro_export]
+macro_rules! trace {
+	(target: $subsystem:expr, $($arg:tt)*) => {{
+		if !cfg!(feature = "silent")
+			&& $crate::logging::enabled($crate::logging::Level::Trace, $subsystem)
+		{
+			defmt::trace!($($arg)*);
+		}
+	}};
+	($($arg:tt)*) => {
+		$crate::trace!(target: $crate::logging::Subsystem::System, $($arg)*)
+	};
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn filter() {
+		assert!(enabled(Level::Debug, Subsystem::Ps2));
+		assert!(!enabled(Level::Trace, Subsystem::Ps2));
+		set_filter(Level::Trace as u8, Subsystem::Ps2 as u8);
+		assert!(enabled(Level::Trace, Subsystem::Ps2));
+		assert!(!enabled(Level::Error, Subsystem::Power));
+		set_filter(0, DEFAULT_SUBSYSTEMS);
+		assert!(!enabled(Level::Error, Subsystem::System));
+		set_filter(DEFAULT_LEVEL, DEFAULT_SUBSYSTEMS);
+	}
+}
@@ -0,0 +1,151 @@
+//! # Protocol Loopback
+//!
+//! We can check the register dispatch without a host attached, by feeding
+//! the conformance exchanges (see `neotron_bmc_protocol::vectors`) through
+//! our own request handler, exactly as if they had arrived over SPI, and
+//! comparing each *Response* with the one the vector expects.
+//!
+//! The exchanges are written for a newly started NBMC, but they only read
+//! registers every NBMC has, and write the scratch registers, so they run on
+//! the live register state. The scratch registers are put back afterwards.
+//! Nothing is logged, and the *BMC Health* register doesn't count the
+//! errors the exchanges provoke, so running the loopback doesn't upset the
+//! SPI check in the production self-test.
+
+use neotron_bmc_protocol as proto;
+use proto::vectors::{self, Vector};
+use proto::{Receivable, Sendable};
+
+use crate::eventlog::EventLog;
+use crate::irq::InterruptController;
+use crate::registers::{self, RegisterState, READ_BUFFER_LEN};
+
+/// The longest *Request* in the exchanges
+const MAX_REQUEST_LEN: usize = 8;
+
+/// The longest *Response*: the result, the data and the CRC
+const MAX_RESPONSE_LEN: usize = READ_BUFFER_LEN + 2;
+
+/// How a loopback run went.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct LoopbackResults {
+	/// How many exchanges ran
+	pub exchanges: u8,
+	/// How many of those got the wrong *Response*
+	pub failures: u8,
+	/// The first exchange which failed, counting from one
+	pub first_failure: Option<u8>,
+}
+
+impl LoopbackResults {
+	/// Did every exchange get the *Response* it expected?
+	pub fn passed(&self) -> bool {
+		self.exchanges > 0 && self.failures == 0
+	}
+
+	/// Count an exchange, and whether it got the right *Response*.
+	fn record(&mut self, ok: bool) {
+		self.exchanges = self.exchanges.saturating_add(1);
+		if !ok {
+			self.failures = self.failures.saturating_add(1);
+			self.first_failure.get_or_insert(self.exchanges);
+		}
+	}
+}
+
+/// Run every exchange through `registers::handle_request`, and say how many
+/// got the *Response* they expected.
+///
+/// A vector which can't be read counts as a failure.
+pub fn run<const N: usize>(
+	register_state: &mut RegisterState,
+	event_log: &mut EventLog<N>,
+	irq: &mut InterruptController,
+	read_buffer: &mut [u8; READ_BUFFER_LEN],
+) -> LoopbackResults {
+	let scratch = register_state.scratch;
+	let mut results = LoopbackResults::default();
+	for vector in vectors::parse(vectors::EXCHANGES) {
+		let Ok(Vector::Exchange { request, response }) = vector else {
+			results.record(false);
+			continue;
+		};
+		let mut request_bytes = [0u8; MAX_REQUEST_LEN];
+		let Ok(request) = request.copy_into(&mut request_bytes) else {
+			results.record(false);
+			continue;
+		};
+		let mut sent = [0u8; MAX_RESPONSE_LEN];
+		let len = match proto::Request::from_bytes(request) {
+			Ok(req) => registers::handle_request(&req, register_state, event_log, irq, read_buffer)
+				.render_to_buffer(&mut sent),
+			Err(e) => proto::Response::new_without_data(registers::bad_request_result(e))
+				.render_to_buffer(&mut sent),
+		};
+		let ok = match len {
+			Ok(len) => sent[..len].iter().copied().eq(response.bytes()),
+			Err(_) => false,
+		};
+		results.record(ok);
+	}
+	register_state.scratch = scratch;
+	results
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::config::Config;
+	use crate::registers::CONFIG_STATUS_DEFAULTS;
+
+	fn new_state() -> RegisterState {
+		let build_info = proto::BuildInfo {
+			firmware_version: [0, 4, 0],
+			protocol_version: proto::ProtocolVersion::new(1, 0, 0),
+			build_flags: 0,
+			git_hash: [0; 8],
+			build_timestamp: 0,
+			feature_flags: 0,
+		};
+		let mut state = RegisterState::new(
+			"v0.4.0",
+			&build_info,
+			Config::DEFAULT,
+			CONFIG_STATUS_DEFAULTS,
+		);
+		state.ready = true;
+		state
+	}
+
+	#[test]
+	fn passes_and_leaves_the_scratch_registers() {
+		let mut state = new_state();
+		state.scratch[0] = 0x12;
+		let results = run(
+			&mut state,
+			&mut EventLog::<4>::new(),
+			&mut InterruptController::new(),
+			&mut [0u8; READ_BUFFER_LEN],
+		);
+		assert!(results.passed(), "{:?}", results);
+		assert_eq!(results.first_failure, None);
+		assert_eq!(state.scratch[0], 0x12);
+		assert_eq!(state.health.flags(), 0);
+	}
+
+	#[test]
+	fn spots_a_wrong_response() {
+		let mut state = new_state();
+		state.protocol_version = [1, 0, 1];
+		let results = run(
+			&mut state,
+			&mut EventLog::<4>::new(),
+			&mut InterruptController::new(),
+			&mut [0u8; READ_BUFFER_LEN],
+		);
+		assert!(!results.passed());
+		// Both reads of the Protocol Version
+		assert_eq!(results.failures, 2);
+		assert_eq!(results.first_failure, Some(1));
+	}
+}
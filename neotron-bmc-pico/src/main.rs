@@ -11,633 +11,4525 @@
 #![no_main]
 #![no_std]
 
-use heapless::spsc::{Consumer, Producer, Queue};
-use rtic::app;
+#[cfg(feature = "encoder")]
+use core::sync::atomic::AtomicI16;
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU8, Ordering};
+
+use rtic_monotonics::systick::prelude::*;
+#[cfg(not(feature = "usb"))]
+use stm32f0xx_hal::gpio::gpioa::{PA11, PA12};
+#[cfg(not(any(
+	feature = "fan",
+	feature = "encoder",
+	feature = "i2c-target",
+	feature = "ps2-power",
+	feature = "status-led"
+)))]
+use stm32f0xx_hal::gpio::Floating;
+#[cfg(feature = "status-led")]
+use stm32f0xx_hal::gpio::{PullDown, AF2};
+#[cfg(feature = "usb")]
+use stm32f0xx_hal::usb::{Peripheral, UsbBus, UsbBusType};
 use stm32f0xx_hal::{
-	gpio::gpioa::{PA10, PA11, PA12, PA15, PA2, PA3, PA4, PA9},
-	gpio::gpiob::{PB0, PB1, PB3, PB4, PB5},
+	gpio::gpioa::{PA0, PA1, PA10, PA15, PA2, PA3, PA4, PA8, PA9},
+	gpio::gpiob::{PB0, PB3, PB4, PB5, PB6, PB7},
 	gpio::gpiof::{PF0, PF1},
-	gpio::{Alternate, Floating, Input, Output, PullUp, PushPull, AF1},
+	gpio::{Alternate, Analog, Input, OpenDrain, Output, PullUp, PushPull, AF1},
 	pac,
 	prelude::*,
 	serial,
 };
+#[cfg(feature = "usb")]
+use usb_device::{
+	bus::UsbBusAllocator,
+	device::{StringDescriptors, UsbDevice, UsbDeviceBuilder},
+};
+#[cfg(feature = "usb")]
+use usbd_serial::SerialPort;
 
 use neotron_bmc_pico as _;
+#[cfg(all(feature = "adc", not(any(feature = "current-sense", feature = "fan"))))]
+use neotron_bmc_pico::adcsched::{CHANNEL_TEMPERATURE, INPUT_CHANNELS};
+#[cfg(all(feature = "adc", not(any(feature = "current-sense", feature = "fan"))))]
+use neotron_bmc_pico::analog::{self, ANALOG_INPUTS};
+use neotron_bmc_pico::board::Board;
+use neotron_bmc_pico::buttons::{
+	Buttons, BUTTONS, BUTTON_GPIO0, BUTTON_GPIO1, BUTTON_POWER, BUTTON_RESET,
+};
+#[cfg(all(feature = "speaker", not(feature = "current-sense")))]
+use neotron_bmc_pico::buzzer::Buzzer;
+use neotron_bmc_pico::config::{
+	append_journal, commit_nvram, enable_readout_protection, read_journal, read_nvram, read_stats,
+	readout_levels, record_stat, Config,
+};
+use neotron_bmc_pico::console::{self, Escapes};
+#[cfg(feature = "current-sense")]
+use neotron_bmc_pico::currentsense::CurrentSense;
+#[cfg(feature = "encoder")]
+use neotron_bmc_pico::encoder::Quadrature;
+use neotron_bmc_pico::eventlog::{Event, EventKind, EventLog, PowerOnSource, ResetSource};
+use neotron_bmc_pico::expansion::GPIO_AVAILABLE;
+#[cfg(not(any(
+	feature = "fan",
+	feature = "encoder",
+	feature = "i2c-target",
+	feature = "ps2-power",
+	feature = "status-led"
+)))]
+use neotron_bmc_pico::expansion::{Gpio, Pwm, PwmTiming};
+use neotron_bmc_pico::failsafe::ForcedOff;
+#[cfg(feature = "fan")]
+use neotron_bmc_pico::fan::{self, Fan};
+use neotron_bmc_pico::faults::{self, Fault};
+use neotron_bmc_pico::glitch::{self, INPUT_POWER, INPUT_RESET};
+use neotron_bmc_pico::health;
+#[cfg(feature = "i2c-target")]
+use neotron_bmc_pico::i2ctarget::I2cTarget;
+use neotron_bmc_pico::irq::{self, InterruptController};
+use neotron_bmc_pico::isrstatus::IsrStatus;
+#[cfg(feature = "ps2-keyboard")]
+use neotron_bmc_pico::keyboard::REPEAT_PREFIX;
+use neotron_bmc_pico::latency::{Stamp, Stopwatch};
+use neotron_bmc_pico::logging::{self, Subsystem};
+use neotron_bmc_pico::loopback::{self, LoopbackResults};
+use neotron_bmc_pico::nvram::{self, Nvram};
+use neotron_bmc_pico::power::{
+	board_present, boot_mode_entry, led_duty, restore_power, DcPowerState, PowerManager,
+	PowerRequest, BOOT_MODE_NORMAL, JOURNAL_OFF, JOURNAL_ON, RESET_DURATION_MS, RESTORE_LAST,
+};
+#[cfg(any(feature = "ps2-keyboard", feature = "ps2-mouse"))]
+use neotron_bmc_pico::ps2::Ps2Decoder;
+use neotron_bmc_pico::ps2::{Ps2Arbiter, Ps2Edge, Ps2Link, Ps2Port};
+#[cfg(feature = "ps2-power")]
+use neotron_bmc_pico::ps2power::{PORT_KEYBOARD, PORT_MOUSE};
+use neotron_bmc_pico::ps2timing::BitTimer;
+use neotron_bmc_pico::readout;
+use neotron_bmc_pico::registers::{
+	self, RegisterState, CONFIG_STATUS_DEFAULTS, CONFIG_STATUS_LOADED, CONFIG_STATUS_SAVED,
+	CONFIG_STATUS_SAVE_FAILED, FEATURE_FLAGS, WAKE_ON_ALARM, WAKE_ON_UART,
+};
+#[cfg(feature = "ps2-keyboard")]
+use neotron_bmc_pico::registers::{
+	KEYBOARD_CONTROL_ASCII, KEYBOARD_CONTROL_MAKE_ONLY, KEYBOARD_CONTROL_REPEAT, WAKE_ON_KEYBOARD,
+};
+#[cfg(all(feature = "adc", not(any(feature = "current-sense", feature = "fan"))))]
+use neotron_bmc_pico::risetime::{self, RiseTimer, RiseTimes};
+use neotron_bmc_pico::rtc::{self, Rtc};
+use neotron_bmc_pico::selftest::{self, PinSamples, SelfTestReport};
+#[cfg(feature = "spi-trace")]
+use neotron_bmc_pico::spitrace::{self, TraceEntry};
+use neotron_bmc_pico::standby::Standby;
+use neotron_bmc_pico::stats::Counter;
+#[cfg(feature = "status-led")]
+use neotron_bmc_pico::statusled::{self, BitTiming};
+#[cfg(all(feature = "adc", not(feature = "current-sense")))]
+use neotron_bmc_pico::thermal::{ThermalAction, ThermalGuard};
+use neotron_bmc_pico::uart::{
+	DmaRing, SelfTest, SelfTestResults, SELF_TEST_FAILED, SELF_TEST_PASSED,
+};
+use neotron_bmc_pico::uartlink::UartLink;
+#[cfg(feature = "usb")]
+use neotron_bmc_pico::usb::{self, RegisterClass};
+use neotron_bmc_pico::watchdog::{self, WatchdogAction};
+use neotron_bmc_pico::{debug, info, trace, warn};
 use neotron_bmc_protocol as proto;
 
 /// Version string auto-generated by git.
 static VERSION: &'static str = include_str!(concat!(env!("OUT_DIR"), "/version.txt"));
 
-/// At what rate do we blink the status LED when we're running?
-const LED_PERIOD_MS: u64 = 1000;
+// Constants for the Build Info register, generated by `build.rs`.
+include!(concat!(env!("OUT_DIR"), "/build_info.rs"));
+
+/// The version of the NBMC protocol (and register map) we implement.
+const PROTOCOL_VERSION: proto::ProtocolVersion = proto::ProtocolVersion::new(1, 0, 0);
 
 /// How often we poll the power and reset buttons in milliseconds.
-const DEBOUNCE_POLL_INTERVAL_MS: u64 = 75;
-
-/// Length of a reset pulse, in milliseconds
-const RESET_DURATION_MS: u64 = 250;
-
-/// The states we can be in controlling the DC power
-#[derive(Copy, Clone, PartialEq, Eq)]
-#[repr(u8)]
-pub enum DcPowerState {
-	/// We've just enabled the DC power (so ignore any incoming long presses!)
-	Starting = 1,
-	/// We are now fully on. Look for a long press to turn off.
-	On = 2,
-	/// We are fully off.
-	Off = 0,
-}
+const DEBOUNCE_POLL_INTERVAL_MS: u32 = 75;
 
-/// This is our system state, as accessible via SPI reads and writes.
-#[derive(Debug)]
-pub struct RegisterState {
-	firmware_version: [u8; 32],
-}
+/// How long we wait before carrying out a power request from the host, so it
+/// can finish reading our response first
+const POWER_REQUEST_DELAY_MS: u32 = 20;
 
-#[app(device = crate::pac, peripherals = true, dispatchers = [USB, USART3_4_5_6, TIM14, TIM15, TIM16, TIM17, PVD])]
-mod app {
-	use super::*;
-	use systick_monotonic::*; // Implements the `Monotonic` trait
-
-	pub enum Message {
-		/// Word from PS/2 port 0
-		Ps2Data0(u16),
-		/// Word from PS/2 port 1
-		Ps2Data1(u16),
-		/// Message from SPI bus
-		SpiRequest(neotron_bmc_protocol::Request),
-		/// The power button was given a tap
-		PowerButtonShortPress,
-		/// The power button was held down
-		PowerButtonLongPress,
-		/// The reset button was given a tap
-		ResetButtonShortPress,
-		/// The UART got some data
-		UartByte(u8),
-	}
+/// How fast the SysTick monotonic timer ticks, in Hz
+const SYSTICK_HZ: u32 = 200;
 
-	#[shared]
-	struct Shared {
-		/// The power LED (D1101)
-		#[lock_free]
-		led_power: PB0<Output<PushPull>>,
-		/// The status LED (D1102)
-		#[lock_free]
-		_buzzer_pwm: PB1<Output<PushPull>>,
-		/// The FTDI UART header (J105)
-		#[lock_free]
-		serial: serial::Serial<pac::USART1, PA9<Alternate<AF1>>, PA10<Alternate<AF1>>>,
-		/// The Clear-To-Send line on the FTDI UART header (which the serial object can't handle)
-		#[lock_free]
-		_pin_uart_cts: PA11<Alternate<AF1>>,
-		/// The Ready-To-Receive line on the FTDI UART header (which the serial object can't handle)
-		#[lock_free]
-		_pin_uart_rts: PA12<Alternate<AF1>>,
-		/// The power button
-		#[lock_free]
-		button_power: PF0<Input<PullUp>>,
-		/// The reset button
-		#[lock_free]
-		button_reset: PF1<Input<PullUp>>,
-		/// Tracks DC power state
-		#[lock_free]
-		state_dc_power_enabled: DcPowerState,
-		/// Controls the DC-DC PSU
-		#[lock_free]
-		pin_dc_on: PA3<Output<PushPull>>,
-		/// Controls the Reset signal across the main board, putting all the
-		/// chips (except this BMC!) in reset when pulled low.
-		#[lock_free]
-		pin_sys_reset: PA2<Output<PushPull>>,
-		/// Clock pin for PS/2 Keyboard port
-		#[lock_free]
-		ps2_clk0: PA15<Input<Floating>>,
-		/// Clock pin for PS/2 Mouse port
-		#[lock_free]
-		_ps2_clk1: PB3<Input<Floating>>,
-		/// Data pin for PS/2 Keyboard port
-		#[lock_free]
-		ps2_dat0: PB4<Input<Floating>>,
-		/// Data pin for PS/2 Mouse port
-		#[lock_free]
-		_ps2_dat1: PB5<Input<Floating>>,
-		/// The external interrupt peripheral
-		#[lock_free]
-		exti: pac::EXTI,
-		/// Our register state
-		#[lock_free]
-		register_state: RegisterState,
-		/// Read messages here
-		#[lock_free]
-		msg_q_out: Consumer<'static, Message, 8>,
-		/// Write messages here
-		msg_q_in: Producer<'static, Message, 8>,
-		/// SPI Peripheral
-		spi: neotron_bmc_pico::spi::SpiPeripheral<5, 64>,
-		/// CS pin
-		pin_cs: PA4<Input<PullUp>>,
-	}
+/// The baud rate for the FTDI UART header (J105)
+const UART_BAUD: u32 = 115_200;
 
-	#[local]
-	struct Local {
-		/// Tracks power button state for short presses. 75ms x 2 = 150ms is a short press
-		press_button_power_short: debouncr::Debouncer<u8, debouncr::Repeat2>,
-		/// Tracks power button state for long presses. 75ms x 16 = 1200ms is a long press
-		press_button_power_long: debouncr::Debouncer<u16, debouncr::Repeat16>,
-		/// Tracks reset button state for short presses. 75ms x 2 = 150ms is a long press
-		press_button_reset_short: debouncr::Debouncer<u8, debouncr::Repeat2>,
-		/// Keyboard PS/2 decoder
-		kb_decoder: neotron_bmc_pico::ps2::Ps2Decoder,
-	}
+/// How big the circular buffer USART1 receives into is. At 460,800 baud it
+/// fills up in under 3 ms, and we empty it every time it is half full.
+const UART_DMA_LEN: usize = 128;
 
-	#[monotonic(binds = SysTick, default = true)]
-	type MyMono = Systick<200>; // 200 Hz (= 5ms) timer tick
+/// How often we check on a UART self-test
+const UART_SELF_TEST_POLL_MS: u32 = 10;
 
-	/// The entry point to our application.
-	///
-	/// Sets up the hardware and spawns the regular tasks.
-	///
-	/// * Task `led_power_blink` - blinks the LED
-	/// * Task `button_poll` - checks the power and reset buttons
-	#[init(local = [ queue: Queue<Message, 8> = Queue::new()])]
-	fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
-		defmt::info!("Neotron BMC version {:?} booting", VERSION);
+/// How long the last byte of a UART self-test gets to come back, after it has
+/// been handed to the transmitter
+const UART_SELF_TEST_SETTLE_MS: u32 = 10;
 
-		let dp: pac::Peripherals = ctx.device;
-		let cp: cortex_m::Peripherals = ctx.core;
+/// How many entries we keep in the event log
+const EVENT_LOG_LEN: usize = 16;
 
-		let mut flash = dp.FLASH;
-		let mut rcc = dp
-			.RCC
-			.configure()
-			.hclk(48.mhz())
-			.pclk(48.mhz())
-			.sysclk(48.mhz())
-			.freeze(&mut flash);
+/// How often the heartbeat in the BMC Health register ticks
+const HEARTBEAT_INTERVAL_MS: u32 = 100;
 
-		defmt::info!("Configuring SysTick...");
-		// Initialize the monotonic timer using the Cortex-M SysTick peripheral
-		let mono = Systick::new(cp.SYST, rcc.clocks.sysclk().0);
-
-		defmt::info!("Creating pins...");
-		let gpioa = dp.GPIOA.split(&mut rcc);
-		let gpiob = dp.GPIOB.split(&mut rcc);
-		let gpiof = dp.GPIOF.split(&mut rcc);
-		// We have to have the closure return a tuple of all our configured
-		// pins because by taking fields from `gpioa`, `gpiob`, etc, we leave
-		// them as partial structures. This prevents us from having a call to
-		// `disable_interrupts` for each pin. We can't simply do the `let foo
-		// = ` inside the closure either, as the pins would be dropped when
-		// the closure ended. So, we have this slightly awkward syntax
-		// instead. Do ensure the pins and the variables line-up correctly;
-		// order is important!
-		let (
-			uart_tx,
-			uart_rx,
-			_pin_uart_cts,
-			_pin_uart_rts,
-			mut led_power,
-			mut _buzzer_pwm,
-			button_power,
-			button_reset,
-			mut pin_dc_on,
-			mut pin_sys_reset,
-			ps2_clk0,
-			_ps2_clk1,
-			ps2_dat0,
-			_ps2_dat1,
-			pin_cs,
-			pin_sck,
-			pin_cipo,
-			pin_copi,
-		) = cortex_m::interrupt::free(|cs| {
-			(
-				// uart_tx,
-				gpioa.pa9.into_alternate_af1(cs),
-				// uart_rx,
-				gpioa.pa10.into_alternate_af1(cs),
-				// _pin_uart_cts,
-				gpioa.pa11.into_alternate_af1(cs),
-				// _pin_uart_rts,
-				gpioa.pa12.into_alternate_af1(cs),
-				// led_power,
-				gpiob.pb0.into_push_pull_output(cs),
-				// _buzzer_pwm,
-				gpiob.pb1.into_push_pull_output(cs),
-				// button_power,
-				gpiof.pf0.into_pull_up_input(cs),
-				// button_reset,
-				gpiof.pf1.into_pull_up_input(cs),
-				// pin_dc_on,
-				gpioa.pa3.into_push_pull_output(cs),
-				// pin_sys_reset,
-				gpioa.pa2.into_push_pull_output(cs),
-				// ps2_clk0,
-				gpioa.pa15.into_floating_input(cs),
-				// _ps2_clk1,
-				gpiob.pb3.into_floating_input(cs),
-				// ps2_dat0,
-				gpiob.pb4.into_floating_input(cs),
-				// _ps2_dat1,
-				gpiob.pb5.into_floating_input(cs),
-				// pin_cs,
-				gpioa.pa4.into_pull_up_input(cs),
-				// pin_sck,
-				gpioa.pa5.into_alternate_af0(cs),
-				// pin_cipo,
-				{
-					// Force 'high speed' mode first, then go into AF0 (high
-					// speed mode is sticky in this HAL revision).
-					let pin = gpioa.pa6.into_push_pull_output_hs(cs);
-					pin.into_alternate_af0(cs)
-				},
-				// pin_copi,
-				gpioa.pa7.into_alternate_af0(cs),
-			)
-		});
+/// How often we check whether a coalesced interrupt has waited long enough
+const IRQ_POLL_INTERVAL_MS: u32 = 5;
 
-		pin_sys_reset.set_low().unwrap();
-		pin_dc_on.set_low().unwrap();
+/// How often we pass disk activity from the host on to the power manager
+const ACTIVITY_POLL_MS: u32 = 10;
 
-		defmt::info!("Creating UART...");
+/// How often we read the RTC. The alarm matches for a whole second, so this
+/// must be shorter than that.
+const RTC_POLL_INTERVAL_MS: u32 = 250;
 
-		let mut serial =
-			serial::Serial::usart1(dp.USART1, (uart_tx, uart_rx), 115_200.bps(), &mut rcc);
+/// How often we look for new notes when the buzzer is idle
+#[cfg(all(feature = "speaker", not(feature = "current-sense")))]
+const BUZZER_IDLE_POLL_MS: u32 = 10;
 
-		serial.listen(serial::Event::Rxne);
+/// How often we sample the main board current
+#[cfg(feature = "current-sense")]
+const CURRENT_SENSE_INTERVAL_MS: u32 = 10;
 
-		// Put SPI into Peripheral mode (i.e. CLK is an input) and enable the RX interrupt.
-		let spi = neotron_bmc_pico::spi::SpiPeripheral::new(
-			dp.SPI1,
-			(pin_sck, pin_cipo, pin_copi),
-			8_000_000,
-			&mut rcc,
-		);
+/// How often we update the fan speed, duty cycle and temperature
+#[cfg(feature = "fan")]
+const FAN_POLL_INTERVAL_MS: u32 = 1000;
 
-		led_power.set_low().unwrap();
-		_buzzer_pwm.set_low().unwrap();
+/// How often we look for commands to send to the PS/2 devices
+const PS2_POLL_INTERVAL_MS: u32 = 10;
 
-		// Set EXTI15 to use PORT A (PA15) - button input
-		dp.SYSCFG.exticr4.modify(|_r, w| w.exti15().pa15());
+/// How often we check whether a held key is due to repeat. The fastest rate
+/// repeats every 33 ms.
+#[cfg(feature = "ps2-keyboard")]
+const KEY_REPEAT_POLL_MS: u32 = 5;
 
-		// Enable EXTI15 interrupt as external falling edge
-		dp.EXTI.imr.modify(|_r, w| w.mr15().set_bit());
-		dp.EXTI.emr.modify(|_r, w| w.mr15().set_bit());
-		dp.EXTI.ftsr.modify(|_r, w| w.tr15().set_bit());
+/// How long a PS/2 device gets to clock in a command byte. It must start
+/// within 15 ms, and the byte itself takes about 1 ms.
+const PS2_TX_TIMEOUT_MS: u32 = 20;
 
-		// Set EXTI4 to use PORT A (PA4) - SPI CS
-		dp.SYSCFG.exticr2.modify(|_r, w| w.exti4().pa4());
+/// How long the mouse gets to send `0xFA` after clocking in a command byte
+const MOUSE_ACK_TIMEOUT_MS: u32 = 25;
 
-		// Enable EXTI4 interrupt as external falling/rising edge
-		dp.EXTI.imr.modify(|_r, w| w.mr4().set_bit());
-		dp.EXTI.emr.modify(|_r, w| w.mr4().set_bit());
-		dp.EXTI.ftsr.modify(|_r, w| w.tr4().set_bit());
-		dp.EXTI.rtsr.modify(|_r, w| w.tr4().set_bit());
+/// How long after we start before we reset the keyboard. A keyboard runs
+/// its own self-test at power-on, which takes up to about 750 ms.
+const KEYBOARD_POWER_UP_MS: u32 = 1000;
 
-		// Spawn the tasks that run all the time
-		led_power_blink::spawn().unwrap();
-		button_poll::spawn().unwrap();
+/// How many bytes can wait to be sent on each PS/2 port
+const PS2_TX_QUEUE_LEN: usize = 4;
 
-		defmt::info!("Init complete!");
+/// How long we wait for a PS/2 device to finish sending a word before we
+/// decide it glitched, and send anyway. A word takes about 1 ms.
+const PS2_RX_TIMEOUT_MS: u32 = 5;
 
-		let (msg_q_in, msg_q_out) = ctx.local.queue.split();
+/// How often we pick up changes to the UART2 settings, and look for bytes
+/// to send
+#[cfg(feature = "uart2")]
+const UART2_POLL_INTERVAL_MS: u32 = 10;
 
-		let shared_resources = Shared {
-			serial,
-			_pin_uart_cts,
-			_pin_uart_rts,
-			led_power,
-			_buzzer_pwm,
-			button_power,
-			button_reset,
-			state_dc_power_enabled: DcPowerState::Off,
-			pin_dc_on,
-			pin_sys_reset,
-			ps2_clk0,
-			_ps2_clk1,
-			ps2_dat0,
-			_ps2_dat1,
-			exti: dp.EXTI,
-			register_state: RegisterState {
-				firmware_version:
-					*b"Neotron BMC v0.3.1\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00",
-			},
-			msg_q_out,
-			msg_q_in,
-			spi,
-			pin_cs,
+/// How many times we look at the PS/2 lines during a self-test
+#[cfg(any(feature = "ps2-keyboard", feature = "ps2-mouse"))]
+const SELF_TEST_PS2_SAMPLES: u32 = 8;
+
+/// How long we wait between looks at the PS/2 lines during a self-test. A
+/// device which is busy talking won't hold a line low for this long.
+#[cfg(any(feature = "ps2-keyboard", feature = "ps2-mouse"))]
+const SELF_TEST_PS2_INTERVAL_MS: u32 = 5;
+
+/// The note the self-test plays on the buzzer
+#[cfg(all(feature = "speaker", not(feature = "current-sense")))]
+const SELF_TEST_NOTE: neotron_bmc_pico::buzzer::Note = neotron_bmc_pico::buzzer::Note {
+	frequency_hz: 2000,
+	duration: 10,
+	gap: 0,
+};
+
+/// How long the buzzer task gets to start playing `SELF_TEST_NOTE`
+#[cfg(all(feature = "speaker", not(feature = "current-sense")))]
+const SELF_TEST_BUZZER_WAIT_MS: u32 = 30;
+
+/// The factory reading of the internal voltage reference, with a 3.3V supply
+#[cfg(all(feature = "adc", not(any(feature = "current-sense", feature = "fan"))))]
+const VREFINT_CAL_ADDRESS: *const u16 = 0x1FFF_F7BA as *const u16;
+
+/// The programmable voltage detector threshold, as a PLS setting. Level 7
+/// trips as our supply falls through about 2.8V, which is well below where
+/// the 3.3V rail should be, but leaves time to hold the main board in reset
+/// before its rails collapse.
+#[cfg(any(feature = "stm32f031", feature = "stm32f042", feature = "stm32f072"))]
+const PVD_LEVEL: u8 = 7;
+
+/// The ADC channel connected to the internal voltage reference
+#[cfg(all(feature = "adc", not(any(feature = "current-sense", feature = "fan"))))]
+const VREFINT_CHANNEL: u32 = 1 << 17;
+
+/// How often the boot-time protocol loopback checks whether we're ready
+#[cfg(feature = "loopback-test")]
+const BOOT_LOOPBACK_POLL_MS: u32 = 10;
+
+/// How long the self-test waits for the analog inputs to be sampled
+#[cfg(all(feature = "adc", not(any(feature = "current-sense", feature = "fan"))))]
+const SELF_TEST_ANALOG_WAIT_MS: u32 = 30;
+
+/// How often we check whether any ADC channel is due to be sampled
+#[cfg(all(feature = "adc", not(any(feature = "current-sense", feature = "fan"))))]
+const ANALOG_POLL_INTERVAL_MS: u32 = 10;
+
+/// The temperature sensor reading at 30°C and 3.3V, measured at the factory
+#[cfg(all(feature = "adc", not(any(feature = "current-sense", feature = "fan"))))]
+const TS_CAL1_ADDRESS: *const u16 = 0x1FFF_F7B8 as *const u16;
+
+/// The ADC channel connected to the internal temperature sensor
+#[cfg(all(feature = "adc", not(any(feature = "current-sense", feature = "fan"))))]
+const TS_CHANNEL: u32 = 1 << 16;
+
+/// How often we check the temperature against the over-temperature limit
+#[cfg(all(feature = "adc", not(feature = "current-sense")))]
+const THERMAL_POLL_INTERVAL_MS: u32 = 1000;
+
+/// How often we apply the GPIO and PWM settings, and read the pins
+#[cfg(not(any(
+	feature = "fan",
+	feature = "encoder",
+	feature = "i2c-target",
+	feature = "ps2-power",
+	feature = "status-led"
+)))]
+const EXPANSION_POLL_INTERVAL_MS: u32 = 10;
+
+/// How often we check whether the power state journal needs a new entry
+const POWER_JOURNAL_INTERVAL_MS: u32 = 500;
+
+/// How often we update the rotary encoder registers
+#[cfg(feature = "encoder")]
+const ENCODER_POLL_INTERVAL_MS: u32 = 50;
+
+/// How often we apply the PS/2 port power settings
+#[cfg(feature = "ps2-power")]
+const PS2_POWER_INTERVAL_MS: u16 = 10;
+
+/// How often we update the RGB status LED
+#[cfg(feature = "status-led")]
+const STATUS_LED_INTERVAL_MS: u32 = 20;
+
+/// How many items can be waiting in each of our message channels
+const MSG_Q_LEN: usize = 8;
+
+// A 200 Hz (= 5ms) timer tick, using the Cortex-M SysTick peripheral
+systick_monotonic!(Mono, SYSTICK_HZ);
+
+/// Faults and glitches noted by the EXTI interrupts, which can't lock the
+/// register state without holding off PS/2 clock edges. Whoever holds the
+/// register state picks them up with `registers::collect_isr_status`.
+static ISR_STATUS: IsrStatus = IsrStatus::new();
+
+/// Set by the EXTI interrupt when chip select falls, so the SPI interrupt
+/// can time how long the first response of the transaction takes. The time
+/// is in `CS_FALL_MS` and `CS_FALL_US`.
+static CS_FALL_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// When chip select last fell, in milliseconds (see `CS_FALL_PENDING`)
+static CS_FALL_MS: AtomicU32 = AtomicU32::new(0);
+
+/// When chip select last fell, on the stopwatch (see `CS_FALL_PENDING`)
+static CS_FALL_US: AtomicU16 = AtomicU16::new(0);
+
+/// Whether chip select was low at its last edge. The EXTI interrupt sets
+/// this and pends the SPI interrupt, which turns the SPI engine on or off to
+/// match - the EXTI interrupt mustn't lock the SPI engine, or every SPI
+/// interrupt would hold off the PS/2 clock edges.
+static CS_ASSERTED: AtomicBool = AtomicBool::new(false);
+
+/// Counts chip select edges (wrapping), so the SPI interrupt can tell a
+/// quick high-then-low blip, which starts a new transaction, from no change.
+static CS_EDGES: AtomicU8 = AtomicU8::new(0);
+
+/// The shortest chip select pulse the EXTI interrupt believes, in
+/// microseconds. A copy of the setting in the config, so the interrupt
+/// doesn't have to lock the register state.
+static CS_FILTER_US: AtomicU8 = AtomicU8::new(0);
+
+/// A copy of the DC power state, as a `DcPowerState`. Only the
+/// `power_manager` task changes the power state - everyone else reads it
+/// here, and sends the power manager a `PowerRequest` to change it.
+static DC_POWER_STATE: AtomicU8 = AtomicU8::new(DcPowerState::Off as u8);
+
+/// A copy of the system clock speed, which changes as the `power_manager`
+/// task enters and leaves standby.
+static CLOCK_HZ: AtomicU32 = AtomicU32::new(neotron_bmc_pico::standby::RUN_CLOCK_HZ);
+
+/// Whether a terminal has the USB console open, in which case the host's
+/// output goes there instead of the UART. Only the USB interrupt writes it.
+#[cfg(feature = "usb")]
+static USB_CONSOLE: AtomicBool = AtomicBool::new(false);
+
+/// The total steps the rotary encoder has turned, counted by the EXTI
+/// interrupt and picked up by `encoder_poll`. Only the interrupt writes it.
+#[cfg(feature = "encoder")]
+static ENCODER_TOTAL: AtomicI16 = AtomicI16::new(0);
+
+/// Everything involved in turning the main board on and off. The
+/// `power_manager` task owns it, and drives it to match its `PowerManager`.
+pub struct PowerControl {
+	/// The power LED (D1101)
+	led_power: PowerLed,
+	/// Controls the DC-DC PSU
+	pin_dc_on: PA3<Output<PushPull>>,
+	/// Controls the Reset signal across the main board, putting all the
+	/// chips (except this BMC!) in reset when pulled low.
+	pin_sys_reset: PA2<Output<PushPull>>,
+	/// Controls low-power standby mode
+	standby: Standby,
+	/// Times SPI and PS/2 service, so it has to follow the clock speed
+	stopwatch: Stopwatch,
+}
+
+impl PowerControl {
+	/// Drive the pins, and standby mode, to match the power manager, and keep
+	/// `DC_POWER_STATE` and `CLOCK_HZ` in step.
+	fn update(&mut self, manager: &PowerManager, now_ms: u32) {
+		if manager.in_reset() {
+			self.pin_sys_reset.set_low().unwrap();
+		}
+		if manager.dc_on() {
+			// Get back up to full speed before the host starts talking to us
+			self.standby.exit();
+			self.pin_dc_on.set_high().unwrap();
+		} else {
+			self.pin_dc_on.set_low().unwrap();
+			self.standby.enter();
+		}
+		// TODO: Start monitoring 3.3V and 5.0V rails here
+		// TODO: Take system out of reset when 3.3V and 5.0V are good
+		if !manager.in_reset() {
+			self.pin_sys_reset.set_high().unwrap();
+		}
+		DC_POWER_STATE.store(manager.state() as u8, Ordering::Relaxed);
+		let clock_hz = self.standby.clock_hz();
+		if clock_hz != CLOCK_HZ.load(Ordering::Relaxed) {
+			self.stopwatch.set_clock(clock_hz);
+			self.led_power.set_clock(clock_hz);
+			CLOCK_HZ.store(clock_hz, Ordering::Relaxed);
+		}
+		self.led_power.set_level(manager.led_level(now_ms));
+	}
+}
+
+/// The power LED, dimmed by PWM from TIM1 (PB0 is TIM1_CH2N).
+///
+/// Fully lit and fully dark are driven as a plain output, which the
+/// self-test can flip. The timer only takes the pin over in between.
+pub struct PowerLed {
+	/// We own the pin, but change its mode behind the HAL's back
+	_pin: PB0<Output<PushPull>>,
+	/// Generates the PWM
+	tim: pac::TIM1,
+	/// The brightness we last set, in percent
+	level: Option<u8>,
+}
+
+impl PowerLed {
+	/// The GPIOB pin number of the LED
+	const PIN: u8 = 0;
+
+	/// TIM1 counts at this rate, whatever the system clock is
+	const TICK_HZ: u32 = 1_000_000;
+
+	/// How many ticks in each PWM period (so the LED flickers at 1 kHz)
+	const PERIOD: u16 = 1000;
+
+	/// Take the pin, dark, and set up TIM1 for PWM output on channel 2.
+	fn new(mut pin: PB0<Output<PushPull>>, tim: pac::TIM1, clock_hz: u32) -> PowerLed {
+		pin.set_low().unwrap();
+		// Safety: we only touch the TIM1 enable bit, which nothing else
+		// modifies after init.
+		let rcc = unsafe { &*pac::RCC::ptr() };
+		rcc.apb2enr.modify(|_r, w| w.tim1en().set_bit());
+		tim.arr
+			.write(|w| unsafe { w.bits(u32::from(Self::PERIOD - 1)) });
+		// PWM mode 1 on channel 2, driven out of the complementary pin
+		tim.ccmr1_output()
+			.write(|w| unsafe { w.oc2m().bits(0b110).oc2pe().set_bit() });
+		tim.ccer.write(|w| w.cc2ne().set_bit());
+		tim.bdtr.write(|w| w.moe().set_bit());
+		tim.cr1.write(|w| w.arpe().set_bit().cen().set_bit());
+		// TIM1_CH2N is AF2 on PB0. This only matters once the pin is in
+		// alternate function mode.
+		let regs = gpio_registers(b'B');
+		cortex_m::interrupt::free(|_cs| {
+			regs.afrl
+				.modify(|r, w| unsafe { w.bits((r.bits() & !0xF) | 2) });
+		});
+		let mut led = PowerLed {
+			_pin: pin,
+			tim,
+			level: Some(0),
 		};
-		let local_resources = Local {
-			press_button_power_short: debouncr::debounce_2(false),
-			press_button_power_long: debouncr::debounce_16(false),
-			press_button_reset_short: debouncr::debounce_2(false),
-			kb_decoder: neotron_bmc_pico::ps2::Ps2Decoder::new(),
+		led.set_clock(clock_hz);
+		led
+	}
+
+	/// Keep the PWM frequency the same when the timer clock changes.
+	fn set_clock(&mut self, clock_hz: u32) {
+		let prescaler = (clock_hz / Self::TICK_HZ).max(1) - 1;
+		self.tim.psc.write(|w| unsafe { w.bits(prescaler) });
+		// Load the new prescaler straight away
+		self.tim.egr.write(|w| w.ug().set_bit());
+	}
+
+	/// Set the brightness, in percent.
+	fn set_level(&mut self, level: u8) {
+		if self.level == Some(level) {
+			return;
+		}
+		self.level = Some(level);
+		let regs = gpio_registers(b'B');
+		let shift = u32::from(Self::PIN) * 2;
+		// 0b10 is alternate function, and 0b01 is a general purpose output
+		let mode = if level == 0 {
+			// The upper half of BSRR resets pins, the lower half sets them
+			regs.bsrr
+				.write(|w| unsafe { w.bits(1 << (Self::PIN + 16)) });
+			0b01
+		} else if level >= 100 {
+			regs.bsrr.write(|w| unsafe { w.bits(1 << Self::PIN) });
+			0b01
+		} else {
+			let duty = led_duty(level, Self::PERIOD);
+			self.tim.ccr2.write(|w| unsafe { w.bits(u32::from(duty)) });
+			0b10
 		};
-		let init = init::Monotonics(mono);
-		(shared_resources, local_resources, init)
+		cortex_m::interrupt::free(|_cs| {
+			regs.moder
+				.modify(|r, w| unsafe { w.bits((r.bits() & !(0b11 << shift)) | (mode << shift)) });
+		});
 	}
+}
 
-	/// Our idle task.
-	///
-	/// This task is called when there is nothing else to do.
-	#[idle(shared = [msg_q_out, msg_q_in, spi, register_state])]
-	fn idle(mut ctx: idle::Context) -> ! {
-		defmt::info!("Idle is running...");
-		loop {
-			match ctx.shared.msg_q_out.dequeue() {
-				Some(Message::Ps2Data0(word)) => {
-					if let Some(byte) = neotron_bmc_pico::ps2::Ps2Decoder::check_word(word) {
-						defmt::info!("< KB 0x{:x}", byte);
-					} else {
-						defmt::warn!("< Bad KB 0x{:x}", word);
-					}
-				}
-				Some(Message::Ps2Data1(word)) => {
-					if let Some(byte) = neotron_bmc_pico::ps2::Ps2Decoder::check_word(word) {
-						defmt::info!("< MS 0x{:x}", byte);
-					} else {
-						defmt::warn!("< Bad MS 0x{:x}", word);
-					}
-				}
-				Some(Message::PowerButtonLongPress) => {}
-				Some(Message::PowerButtonShortPress) => {}
-				Some(Message::ResetButtonShortPress) => {}
-				Some(Message::SpiRequest(req)) => match req.request_type {
-					proto::RequestType::Read | proto::RequestType::ReadAlt => {
-						let rsp = match req.register {
-							0x00 => {
-								let length = req.length_or_data as usize;
-								if length > ctx.shared.register_state.firmware_version.len() {
-									proto::Response::new_without_data(
-										proto::ResponseResult::BadLength,
-									)
-								} else {
-									let bytes = &ctx.shared.register_state.firmware_version;
-									proto::Response::new_ok_with_data(&bytes[0..length])
-								}
-							}
-							_ => proto::Response::new_without_data(
-								proto::ResponseResult::BadRegister,
-							),
-						};
-						ctx.shared.spi.lock(|spi| {
-							spi.set_transmit_sendable(&rsp).unwrap();
-						});
-					}
-					_ => {
-						let rsp =
-							proto::Response::new_without_data(proto::ResponseResult::BadLength);
-						ctx.shared.spi.lock(|spi| {
-							spi.set_transmit_sendable(&rsp).unwrap();
-						});
-					}
-				},
-				Some(Message::UartByte(rx_byte)) => {
-					defmt::info!("UART RX {:?}", rx_byte);
-					// TODO: Copy byte to software buffer and turn UART RX
-					// interrupt off if buffer is full
-				}
-				None => {
-					// No messages
-				}
-			}
+/// The interrupt registers, and the line to the host they control.
+pub struct HostIrq {
+	/// Interrupt status, enables and coalescing
+	controller: InterruptController,
+	/// The IRQ line to the host. The HAL thinks it is push-pull, but we
+	/// change the output type behind its back if the host wants open-drain.
+	pin_irq: PA8<Output<PushPull>>,
+	/// How the line is driven (see `irq::LINE_ACTIVE_HIGH`, etc)
+	line_control: u8,
+}
 
-			// Look for something in the SPI bytes received buffer:
-			let mut req = None;
-			ctx.shared.spi.lock(|spi| {
-				let mut mark_done = false;
-				if let Some(data) = spi.get_received() {
-					use proto::Receivable;
-					match proto::Request::from_bytes(data) {
-						Ok(inner_req) => {
-							mark_done = true;
-							req = Some(inner_req);
-						}
-						Err(proto::Error::BadLength) => {
-							// Need more data
-						}
-						Err(e) => {
-							defmt::warn!("Bad Req ({:02x})", e as u8);
-							mark_done = true;
-						}
-					}
-				}
-				if mark_done {
-					// Couldn't do this whilst holding the `data` ref.
-					spi.mark_done();
-				}
-			});
+impl HostIrq {
+	/// Bit number of the IRQ line in the GPIOA registers
+	const PIN: u8 = 8;
 
-			// If we got a valid message, queue it so we can look at it next time around
-			if let Some(req) = req {
-				if ctx
-					.shared
-					.msg_q_in
-					.lock(|q| q.enqueue(Message::SpiRequest(req)))
-					.is_err()
-				{
-					panic!("Q full!");
-				}
-			}
-			// TODO: Read ADC for 3.3V and 5.0V rails and check good
+	/// Drive the IRQ line the way the host board wants, if that has changed.
+	fn set_line_control(&mut self, line_control: u8) {
+		if line_control == self.line_control {
+			return;
 		}
+		self.line_control = line_control;
+		// Set the level first, so the line doesn't glitch
+		self.update();
+		let open_drain = u32::from((line_control & irq::LINE_OPEN_DRAIN) != 0);
+		let regs = gpio_registers(b'A');
+		cortex_m::interrupt::free(|_cs| {
+			regs.otyper.modify(|r, w| unsafe {
+				w.bits((r.bits() & !(1 << Self::PIN)) | (open_drain << Self::PIN))
+			});
+		});
 	}
 
-	/// This is the external GPIO interrupt task.
-	///
-	/// It handles PS/2 clock edges, and SPI chip select edges.
-	///
-	/// It is very high priority, as we can't afford to miss a PS/2 clock edge.
-	#[task(
-		binds = EXTI4_15,
-		priority = 4,
-		shared = [ps2_clk0, msg_q_in, ps2_dat0, exti, spi, pin_cs],
-		local = [kb_decoder]
-	)]
-	fn exti4_15_interrupt(mut ctx: exti4_15_interrupt::Context) {
-		let pr = ctx.shared.exti.pr.read();
-		// Is this EXT15 (PS/2 Port 0 clock input)
-		if pr.pr15().bit_is_set() {
-			let data_bit = ctx.shared.ps2_dat0.is_high().unwrap();
-			// Do we have a complete word?
-			if let Some(data) = ctx.local.kb_decoder.add_bit(data_bit) {
-				// Don't dump in the ISR - we're busy. Add it to this nice lockless queue instead.
-				if ctx
-					.shared
-					.msg_q_in
-					.lock(|q| q.enqueue(Message::Ps2Data0(data)))
-					.is_err()
-				{
-					panic!("queue full");
-				};
-			}
-			// Clear the pending flag for this pin
-			ctx.shared.exti.pr.write(|w| w.pr15().set_bit());
+	/// Drive the IRQ line to match the interrupt registers.
+	fn update(&mut self) {
+		let active_high = (self.line_control & irq::LINE_ACTIVE_HIGH) != 0;
+		if self.controller.is_asserted() == active_high {
+			self.pin_irq.set_high().unwrap();
+		} else {
+			self.pin_irq.set_low().unwrap();
 		}
+	}
+}
 
-		if pr.pr4().bit_is_set() {
-			if ctx.shared.pin_cs.lock(|pin| pin.is_low().unwrap()) {
-				// If incoming Chip Select is low, turn on the SPI engine
-				ctx.shared.spi.lock(|s| s.enable());
-			} else {
-				// If incoming Chip Select is high, turn off the SPI engine
-				ctx.shared.spi.lock(|s| s.disable());
-			}
-			// Clear the pending flag for this pin
-			ctx.shared.exti.pr.write(|w| w.pr4().set_bit());
-		}
+/// The pins of a PS/2 port, and the link state behind them.
+///
+/// Both ports work the same way - only the pins differ.
+trait Ps2Pins {
+	/// Drive the clock line low, or let go of it
+	fn set_clk(&mut self, high: bool);
+
+	/// Drive the data line low, or let go of it
+	fn set_dat(&mut self, high: bool);
+
+	/// Read the data line
+	fn dat_is_high(&self) -> bool;
+
+	/// The link state
+	fn link(&mut self) -> &mut Ps2Link;
+
+	/// Times the clock edges
+	fn bit_timer(&mut self) -> &mut BitTimer;
+
+	/// Hold the clock low, which stops the device sending, and tells it we
+	/// may want to send something.
+	fn inhibit(&mut self) {
+		self.link().inhibit();
+		self.set_clk(false);
 	}
 
-	/// This is the USART1 task.
-	///
-	/// It fires whenever there is new data received on USART1. We should flag to the host
-	/// that data is available.
-	#[task(binds = USART1, shared = [serial, msg_q_in])]
-	fn usart1_interrupt(mut ctx: usart1_interrupt::Context) {
-		// Reading the register clears the RX-Not-Empty-Interrupt flag.
-		match ctx.shared.serial.read() {
-			Ok(b) => {
-				let _ = ctx
-					.shared
-					.msg_q_in
-					.lock(|q| q.enqueue(Message::UartByte(b)));
-			}
-			_ => {}
-		}
+	/// Let go of the clock, without sending anything.
+	fn release(&mut self) {
+		self.link().release();
+		self.set_clk(true);
 	}
 
-	/// This is the SPI1 task.
-	///
-	/// It fires whenever there is new data received on SPI1. We should flag to the host
-	/// that data is available.
-	#[task(binds = SPI1, shared = [spi])]
-	fn spi1_interrupt(mut ctx: spi1_interrupt::Context) {
-		ctx.shared.spi.lock(|spi| {
-			spi.handle_isr();
-		});
+	/// Start sending a byte. Call this after holding the clock low for at
+	/// least 100 µs.
+	fn start_send(&mut self, byte: u8) {
+		self.link().start_send(byte);
+		// The start bit, then let the device generate the clock
+		self.set_dat(false);
+		self.set_clk(true);
 	}
 
-	/// This is the LED blink task.
-	///
-	/// This task is called periodically. We check whether the status LED is currently on or off,
-	/// and set it to the opposite. This makes the LED blink.
-	#[task(shared = [led_power, state_dc_power_enabled], local = [ led_state: bool = false ])]
-	fn led_power_blink(ctx: led_power_blink::Context) {
-		if *ctx.shared.state_dc_power_enabled == DcPowerState::Off {
-			if *ctx.local.led_state {
-				ctx.shared.led_power.set_low().unwrap();
-				*ctx.local.led_state = false;
-			} else {
-				ctx.shared.led_power.set_high().unwrap();
-				*ctx.local.led_state = true;
+	/// Stop sending, and report whether the device took the byte.
+	fn finish_send(&mut self) -> bool {
+		let acked = self.link().finish_send();
+		self.set_dat(true);
+		acked
+	}
+
+	/// Handle a falling edge on the clock line, which came at `now_us` on
+	/// the stopwatch. Returns a word if the device has sent us one.
+	fn clock_edge(&mut self, now_us: u16) -> Option<u16> {
+		let mid_word = self.link().is_receiving();
+		self.bit_timer().edge(now_us, mid_word);
+		let data_bit = self.dat_is_high();
+		match self.link().clock_edge(data_bit) {
+			Ps2Edge::Word(word) => Some(word),
+			Ps2Edge::Drive(level) => {
+				self.set_dat(level);
+				None
 			}
-			led_power_blink::spawn_after(LED_PERIOD_MS.millis()).unwrap();
+			Ps2Edge::Idle => None,
 		}
 	}
 
-	/// This task polls our power and reset buttons.
-	///
-	/// We poll them rather than setting up an interrupt as we need to debounce
-	/// them, which involves waiting a short period and checking them again.
-	/// Given that we have to do that, we might as well not bother with the
-	/// interrupt.
-	#[task(
-		shared = [
-			led_power, button_power, button_reset,
-			state_dc_power_enabled, pin_sys_reset, pin_dc_on
-		],
-		local = [ press_button_power_short, press_button_power_long, press_button_reset_short ]
-	)]
-	fn button_poll(ctx: button_poll::Context) {
-		// Poll buttons
-		let pwr_pressed: bool = ctx.shared.button_power.is_low().unwrap();
-		let rst_pressed: bool = ctx.shared.button_reset.is_low().unwrap();
-
-		// Update state
-		let pwr_short_edge = ctx.local.press_button_power_short.update(pwr_pressed);
-		let pwr_long_edge = ctx.local.press_button_power_long.update(pwr_pressed);
-		let rst_long_edge = ctx.local.press_button_reset_short.update(rst_pressed);
-
-		defmt::trace!(
-			"pwr/rst {}/{} {}",
-			pwr_pressed,
-			rst_pressed,
-			match rst_long_edge {
-				Some(debouncr::Edge::Rising) => "rising",
-				Some(debouncr::Edge::Falling) => "falling",
-				None => "-",
-			}
-		);
+	/// Is the device part way through sending us a word?
+	fn is_receiving(&mut self) -> bool {
+		self.link().is_receiving()
+	}
+}
+
+/// The PS/2 mouse port, which we both listen to and send commands on.
+pub struct MousePort {
+	/// Clock pin (open drain, so we can hold it low to get the mouse's
+	/// attention)
+	clk: PB3<Output<OpenDrain>>,
+	/// Data pin (open drain, so we can clock bits out to the mouse)
+	dat: PB5<Output<OpenDrain>>,
+	/// Collects bits from, or sends bits to, the mouse
+	link: Ps2Link,
+	/// Times the mouse's clock edges
+	bit_timer: BitTimer,
+}
+
+impl Ps2Pins for MousePort {
+	fn set_clk(&mut self, high: bool) {
+		if high {
+			self.clk.set_high().unwrap();
+		} else {
+			self.clk.set_low().unwrap();
+		}
+	}
 
-		// Dispatch event
-		match (
-			pwr_long_edge,
-			pwr_short_edge,
-			*ctx.shared.state_dc_power_enabled,
-		) {
-			(None, Some(debouncr::Edge::Rising), DcPowerState::Off) => {
-				defmt::info!("Power button pressed whilst off.");
-				// Button pressed - power on system
-				*ctx.shared.state_dc_power_enabled = DcPowerState::Starting;
-				ctx.shared.led_power.set_high().unwrap();
-				defmt::info!("Power on!");
-				ctx.shared.pin_dc_on.set_high().unwrap();
-				// TODO: Start monitoring 3.3V and 5.0V rails here
-				// TODO: Take system out of reset when 3.3V and 5.0V are good
-				ctx.shared.pin_sys_reset.set_high().unwrap();
-			}
-			(None, Some(debouncr::Edge::Falling), DcPowerState::Starting) => {
-				defmt::info!("Power button released.");
-				// Button released after power on
-				*ctx.shared.state_dc_power_enabled = DcPowerState::On;
-			}
-			(Some(debouncr::Edge::Rising), None, DcPowerState::On) => {
-				defmt::info!("Power button held whilst on.");
-				*ctx.shared.state_dc_power_enabled = DcPowerState::Off;
-				ctx.shared.led_power.set_low().unwrap();
-				defmt::info!("Power off!");
-				ctx.shared.pin_sys_reset.set_low().unwrap();
-				ctx.shared.pin_dc_on.set_low().unwrap();
-				// Start LED blinking again
-				led_power_blink::spawn().unwrap();
-			}
-			_ => {
-				// Do nothing
-			}
-		}
-
-		// Did reset get a long press?
-		if let Some(debouncr::Edge::Rising) = rst_long_edge {
-			// Is the board powered on? Don't do a reset if it's powered off.
-			if *ctx.shared.state_dc_power_enabled == DcPowerState::On {
-				defmt::info!("Reset!");
-				ctx.shared.pin_sys_reset.set_low().unwrap();
-				// Returns an error if it's already scheduled
-				let _ = exit_reset::spawn_after(RESET_DURATION_MS.millis());
-			}
-		}
-
-		// Re-schedule the timer interrupt
-		button_poll::spawn_after(DEBOUNCE_POLL_INTERVAL_MS.millis()).unwrap();
-	}
-
-	/// Return the reset line high (inactive), but only if we're still powered on.
-	#[task(shared = [pin_sys_reset, state_dc_power_enabled])]
-	fn exit_reset(ctx: exit_reset::Context) {
-		defmt::debug!("End reset");
-		if *ctx.shared.state_dc_power_enabled == DcPowerState::On {
-			ctx.shared.pin_sys_reset.set_high().unwrap();
+	fn set_dat(&mut self, high: bool) {
+		if high {
+			self.dat.set_high().unwrap();
+		} else {
+			self.dat.set_low().unwrap();
 		}
 	}
+
+	fn dat_is_high(&self) -> bool {
+		self.dat.is_high().unwrap()
+	}
+
+	fn link(&mut self) -> &mut Ps2Link {
+		&mut self.link
+	}
+
+	fn bit_timer(&mut self) -> &mut BitTimer {
+		&mut self.bit_timer
+	}
 }
 
-// TODO: Pins we haven't used yet
-// SPI pins
-// spi_clk: gpioa.pa5.into_alternate_af0(cs),
-// spi_cipo: gpioa.pa6.into_alternate_af0(cs),
-// spi_copi: gpioa.pa7.into_alternate_af0(cs),
-// I²C pins
-// i2c_scl: gpiob.pb6.into_alternate_af4(cs),
-// i2c_sda: gpiob.pb7.into_alternate_af4(cs),
+/// The PS/2 keyboard port, which we both listen to and send commands on.
+///
+/// With the `uart2` feature the clock pin belongs to USART2, so there is no
+/// keyboard, and nothing we send is ever clocked out.
+pub struct KeyboardPort {
+	/// Clock pin (open drain, so we can hold it low to get the keyboard's
+	/// attention)
+	#[cfg(not(feature = "uart2"))]
+	clk: PA15<Output<OpenDrain>>,
+	/// Data pin (open drain, so we can clock bits out to the keyboard)
+	dat: PB4<Output<OpenDrain>>,
+	/// Collects bits from, or sends bits to, the keyboard
+	link: Ps2Link,
+	/// Times the keyboard's clock edges
+	bit_timer: BitTimer,
+}
+
+impl Ps2Pins for KeyboardPort {
+	fn set_clk(&mut self, high: bool) {
+		#[cfg(not(feature = "uart2"))]
+		if high {
+			self.clk.set_high().unwrap();
+		} else {
+			self.clk.set_low().unwrap();
+		}
+		#[cfg(feature = "uart2")]
+		let _ = high;
+	}
+
+	fn set_dat(&mut self, high: bool) {
+		if high {
+			self.dat.set_high().unwrap();
+		} else {
+			self.dat.set_low().unwrap();
+		}
+	}
+
+	fn dat_is_high(&self) -> bool {
+		self.dat.is_high().unwrap()
+	}
+
+	fn link(&mut self) -> &mut Ps2Link {
+		&mut self.link
+	}
+
+	fn bit_timer(&mut self) -> &mut BitTimer {
+		&mut self.bit_timer
+	}
+}
+
+/// Empties the circular buffer which USART1 receives into by DMA.
+pub struct UartRx {
+	/// The DMA controller (channel 3 is wired to USART1 RX)
+	dma: pac::DMA1,
+	/// The buffer the DMA controller writes into
+	buffer: &'static mut [u8; UART_DMA_LEN],
+	/// How far through `buffer` we've got
+	ring: DmaRing<UART_DMA_LEN>,
+	/// The self-test in progress, if any. Received bytes go here instead of
+	/// to the host.
+	self_test: Option<SelfTest>,
+	/// The command channel, for register access over the UART. Whilst it is
+	/// open, received bytes go here instead of to the host.
+	link: UartLink,
+	/// Finds the escapes typed on the remote console
+	escapes: Escapes,
+}
+
+impl UartRx {
+	/// Start USART1 receiving into `buffer` by DMA, and interrupting when the
+	/// line goes idle or a break arrives.
+	///
+	/// Call this after USART1 has been set up.
+	fn new(dma: pac::DMA1, buffer: &'static mut [u8; UART_DMA_LEN]) -> UartRx {
+		// Safety: we only touch the DMA enable bit, which nothing else
+		// modifies after init.
+		let rcc = unsafe { &*pac::RCC::ptr() };
+		rcc.ahbenr.modify(|_r, w| w.dmaen().set_bit());
+		// Safety: the serial object doesn't use the DMA or idle line bits.
+		let usart1 = unsafe { &*pac::USART1::ptr() };
+		let rdr_address = &usart1.rdr as *const _ as u32;
+		dma.ch3.par.write(|w| unsafe { w.pa().bits(rdr_address) });
+		dma.ch3
+			.mar
+			.write(|w| unsafe { w.ma().bits(buffer.as_mut_ptr() as u32) });
+		dma.ch3
+			.ndtr
+			.write(|w| unsafe { w.ndt().bits(UART_DMA_LEN as u16) });
+		dma.ch3.cr.write(|w| {
+			// From the peripheral to memory, a byte at a time, round and round
+			w.minc().set_bit();
+			w.circ().set_bit();
+			// Interrupt when half full, and when we wrap around
+			w.htie().set_bit();
+			w.tcie().set_bit();
+			w.en().set_bit();
+			w
+		});
+		// A break shows up as a framing error
+		usart1
+			.cr3
+			.modify(|_r, w| w.dmar().set_bit().eie().set_bit());
+		usart1.cr1.modify(|_r, w| w.idleie().set_bit());
+		UartRx {
+			dma,
+			buffer,
+			ring: DmaRing::new(),
+			self_test: None,
+			link: UartLink::new(),
+			escapes: Escapes::new(),
+		}
+	}
+
+	/// Loop the transmitter back to the receiver, and start sending `length`
+	/// bytes of test pattern.
+	///
+	/// Anything the host hasn't collected yet is moved to the host's FIFO
+	/// first.
+	fn start_self_test(&mut self, length: u16, register_state: &mut RegisterState) {
+		self.drain(register_state);
+		self.self_test = Some(SelfTest::new(length));
+		// Safety: the serial object doesn't use the half-duplex or transmit
+		// interrupt bits, and the half-duplex bit can only change while the
+		// USART is disabled.
+		let usart1 = unsafe { &*pac::USART1::ptr() };
+		usart1.cr1.modify(|_r, w| w.ue().clear_bit());
+		usart1.cr3.modify(|_r, w| w.hdsel().set_bit());
+		usart1.cr1.modify(|_r, w| w.ue().set_bit());
+		usart1.cr1.modify(|_r, w| w.txeie().set_bit());
+	}
+
+	/// Feed the transmitter the next byte of test pattern (or of a response
+	/// on the command channel, or of the host's output), if it wants one.
+	///
+	/// The host's output waits until the self-test has finished and the
+	/// command channel is closed.
+	fn service_tx(&mut self, register_state: &mut RegisterState) {
+		// Safety: as `start_self_test`
+		let usart1 = unsafe { &*pac::USART1::ptr() };
+		if usart1.cr1.read().txeie().bit_is_clear() || usart1.isr.read().txe().bit_is_clear() {
+			return;
+		}
+		let next_tx = match self.self_test.as_mut() {
+			Some(test) => test.next_tx(),
+			None if self.link.is_active() => self.link.next_tx(),
+			#[cfg(feature = "usb")]
+			None if USB_CONSOLE.load(Ordering::Relaxed) => None,
+			None => register_state.uart_tx.pop(),
+		};
+		match next_tx {
+			Some(byte) => usart1
+				.tdr
+				.write(|w| unsafe { w.tdr().bits(u16::from(byte)) }),
+			None => usart1.cr1.modify(|_r, w| w.txeie().clear_bit()),
+		}
+	}
+
+	/// Has the self-test sent all of its pattern?
+	fn self_test_sent(&self) -> bool {
+		self.self_test.as_ref().map_or(true, |test| test.is_sent())
+	}
+
+	/// Check whatever came back, go back to normal full-duplex operation, and
+	/// return the results.
+	fn finish_self_test(&mut self, register_state: &mut RegisterState) -> SelfTestResults {
+		self.drain(register_state);
+		// Safety: as `start_self_test`
+		let usart1 = unsafe { &*pac::USART1::ptr() };
+		usart1.cr1.modify(|_r, w| w.txeie().clear_bit());
+		usart1.cr1.modify(|_r, w| w.ue().clear_bit());
+		usart1.cr3.modify(|_r, w| w.hdsel().clear_bit());
+		usart1.cr1.modify(|_r, w| w.ue().set_bit());
+		self.self_test
+			.take()
+			.map(|test| test.results())
+			.unwrap_or_default()
+	}
+
+	/// A break arrived, so open or close the command channel.
+	///
+	/// Anything which arrived before the break goes where it would have gone
+	/// before.
+	fn toggle_link(&mut self, register_state: &mut RegisterState) -> usize {
+		let count = self.drain(register_state);
+		if self.link.toggle() {
+			info!(target: Subsystem::Spi, "UART command channel open");
+		} else {
+			info!(target: Subsystem::Spi, "UART command channel closed");
+			// The host's output may have been waiting for it to close
+			self.start_tx();
+		}
+		count
+	}
+
+	/// Send a response on the command channel.
+	fn send_response(&mut self, message: &dyn proto::Sendable) {
+		if !self.link.send(message) {
+			warn!(target: Subsystem::Spi, "UART response dropped");
+			return;
+		}
+		self.start_tx();
+	}
+
+	/// Interrupt when the transmitter wants a byte, so `service_tx` can feed
+	/// it.
+	fn start_tx(&mut self) {
+		// Safety: as `start_self_test`
+		let usart1 = unsafe { &*pac::USART1::ptr() };
+		usart1.cr1.modify(|_r, w| w.txeie().set_bit());
+	}
+
+	/// Acknowledge the half-full or wrapped-around interrupt.
+	fn clear_dma_interrupt(&mut self) {
+		self.dma.ifcr.write(|w| w.cgif3().set_bit());
+	}
+
+	/// Copy any new bytes into the host's FIFO (or check them, during a
+	/// self-test, or pass them to the command channel, or type them on the
+	/// keyboard, in console takeover mode), and return how many went into
+	/// the FIFO.
+	///
+	/// On a remote console, escapes are picked out first.
+	fn drain(&mut self, register_state: &mut RegisterState) -> usize {
+		let remaining = usize::from(self.dma.ch3.ndtr.read().ndt().bits());
+		let (first, second) = self.ring.advance(remaining);
+		let mut count = 0;
+		for idx in first.chain(second) {
+			// Safety: the DMA controller writes to the buffer behind our
+			// back, so we mustn't let the compiler cache what it holds.
+			let byte = unsafe { core::ptr::read_volatile(self.buffer.as_ptr().add(idx)) };
+			if let Some(test) = self.self_test.as_mut() {
+				test.check_rx(byte);
+				continue;
+			}
+			if self.link.is_active() {
+				self.link.receive(byte);
+				continue;
+			}
+			count += Self::console_byte(&mut self.escapes, byte, register_state);
+		}
+		count
+	}
+
+	/// Pass on a byte typed on the console (see `deliver`), picking out the
+	/// escapes first on a remote console, and return how many bytes went in
+	/// the host's FIFO.
+	fn console_byte(escapes: &mut Escapes, byte: u8, register_state: &mut RegisterState) -> usize {
+		if !register_state.remote_console {
+			return Self::deliver(byte, register_state);
+		}
+		let mut count = 0;
+		let command = escapes.receive(byte, |b| count += Self::deliver(b, register_state));
+		if let Some(console::Command::ToggleTakeover) = command {
+			register_state.console_takeover = !register_state.console_takeover;
+			info!(
+				target: Subsystem::Spi,
+				"Console takeover {}",
+				register_state.console_takeover
+			);
+		}
+		count
+	}
+
+	/// Put a received byte in the host's FIFO (or type it on the keyboard,
+	/// in console takeover mode), and return how many bytes went in the
+	/// FIFO.
+	fn deliver(byte: u8, register_state: &mut RegisterState) -> usize {
+		if register_state.console_takeover {
+			if !console::type_char(byte, &mut register_state.kb_inject) {
+				register_state.health.report(health::UART_QUEUE_OVERFLOW);
+			}
+			return 0;
+		}
+		if !register_state.uart_rx.push(byte) {
+			register_state.health.report(health::UART_QUEUE_OVERFLOW);
+		}
+		1
+	}
+}
+
+/// The USB console and register interface (see `neotron_bmc_pico::usb`).
+#[cfg(feature = "usb")]
+pub struct UsbConsole {
+	/// The USB device, which answers the USB host's control requests
+	device: UsbDevice<'static, UsbBusType>,
+	/// The CDC-ACM serial port, carrying the console
+	serial: SerialPort<'static, UsbBusType>,
+	/// The vendor interface, for register access
+	registers: RegisterClass<'static, UsbBusType>,
+	/// Finds the escapes typed on the remote console
+	escapes: Escapes,
+}
+
+#[cfg(feature = "usb")]
+impl UsbConsole {
+	/// Set up the USB device on the given bus, and connect to the USB host.
+	fn new(bus: &'static UsbBusAllocator<UsbBusType>) -> UsbConsole {
+		let serial = SerialPort::new(bus);
+		let registers = RegisterClass::new(bus);
+		let device = UsbDeviceBuilder::new(bus, usb::VID_PID)
+			.strings(&[StringDescriptors::default()
+				.manufacturer(usb::MANUFACTURER)
+				.product(usb::PRODUCT)])
+			.unwrap()
+			.composite_with_iads()
+			.build();
+		UsbConsole {
+			device,
+			serial,
+			registers,
+			escapes: Escapes::new(),
+		}
+	}
+
+	/// Answer the USB host, and return whether a terminal has the console
+	/// open.
+	fn poll(&mut self) -> bool {
+		self.device
+			.poll(&mut [&mut self.serial, &mut self.registers]);
+		self.serial.dtr()
+	}
+
+	/// Pass on whatever was typed on the console (as `UartRx::drain` does),
+	/// and return how many bytes went in the host's FIFO.
+	fn drain(&mut self, register_state: &mut RegisterState) -> usize {
+		let mut typed = [0u8; usb::MAX_PACKET_LEN as usize];
+		let len = self.serial.read(&mut typed).unwrap_or(0);
+		let mut count = 0;
+		for &byte in &typed[..len] {
+			count += UartRx::console_byte(&mut self.escapes, byte, register_state);
+		}
+		count
+	}
+
+	/// Send the host's output to the console, until the serial port's buffer
+	/// is full. The rest goes when the USB host has collected some.
+	fn service_tx(&mut self, register_state: &mut RegisterState) {
+		while let Some(byte) = register_state.uart_tx.peek() {
+			if self.serial.write(&[byte]).is_err() {
+				break;
+			}
+			register_state.uart_tx.pop();
+		}
+	}
+
+	/// Send a response on the register interface.
+	fn send_response(&mut self, message: &dyn proto::Sendable) {
+		if !self.registers.send_response(message) {
+			warn!(target: Subsystem::Spi, "USB response dropped");
+		}
+	}
+}
+
+/// The second serial port, which the host uses through the UART2 registers.
+#[cfg(feature = "uart2")]
+pub struct Uart2 {
+	/// The USART peripheral
+	dev: pac::USART2,
+	/// The clock, baud rate and enable it was last set up with
+	settings: Option<(u32, u32, bool)>,
+}
+
+#[cfg(feature = "uart2")]
+impl Uart2 {
+	/// Take the USART, but leave it turned off.
+	///
+	/// The pins must already be in their USART2 alternate function.
+	fn new(dev: pac::USART2) -> Uart2 {
+		// Safety: we only touch the USART2 enable bit, which nothing else
+		// modifies after init.
+		let rcc = unsafe { &*pac::RCC::ptr() };
+		rcc.apb1enr.modify(|_r, w| w.usart2en().set_bit());
+		Uart2 {
+			dev,
+			settings: None,
+		}
+	}
+
+	/// Set the USART up, if anything has changed since last time.
+	///
+	/// USART2 is clocked from PCLK, which changes speed when we enter and
+	/// leave standby, so `clock_hz` has to be kept up to date.
+	fn configure(&mut self, clock_hz: u32, baud: u32, enabled: bool) {
+		let settings = (clock_hz, baud, enabled);
+		if self.settings == Some(settings) {
+			return;
+		}
+		self.settings = Some(settings);
+		self.dev.cr1.reset();
+		if enabled {
+			// The divider is only 16 bits, which limits how slow we can go
+			let divider = (clock_hz / baud).min(0xFFFF);
+			self.dev.brr.write(|w| unsafe { w.bits(divider) });
+			self.dev.cr1.write(|w| {
+				w.te().set_bit();
+				w.re().set_bit();
+				w.rxneie().set_bit();
+				w.ue().set_bit();
+				w
+			});
+		}
+	}
+
+	/// Start sending whatever is in the transmit FIFO.
+	fn start_tx(&mut self) {
+		if self.dev.cr1.read().ue().bit_is_set() {
+			self.dev.cr1.modify(|_r, w| w.txeie().set_bit());
+		}
+	}
+
+	/// Move bytes between the USART and the FIFOs. Returns the interrupts to
+	/// raise.
+	fn service(&mut self, register_state: &mut RegisterState) -> u16 {
+		let mut events = 0;
+		let isr = self.dev.isr.read();
+		if isr.ore().bit_is_set() {
+			// We were too slow, and a byte was lost
+			self.dev.icr.write(|w| w.orecf().set_bit());
+			register_state.health.report(health::UART_QUEUE_OVERFLOW);
+		}
+		if isr.rxne().bit_is_set() {
+			// Reading the data register clears the flag
+			let byte = self.dev.rdr.read().rdr().bits() as u8;
+			if register_state.uart2.rx.push(byte) {
+				events |= irq::UART2_RX_NOT_EMPTY;
+			} else {
+				register_state.health.report(health::UART_QUEUE_OVERFLOW);
+			}
+		}
+		if isr.txe().bit_is_set() && self.dev.cr1.read().txeie().bit_is_set() {
+			match register_state.uart2.tx.pop() {
+				Some(byte) => {
+					self.dev
+						.tdr
+						.write(|w| unsafe { w.tdr().bits(u16::from(byte)) });
+				}
+				None => {
+					self.dev.cr1.modify(|_r, w| w.txeie().clear_bit());
+					events |= irq::UART2_TX_EMPTY;
+				}
+			}
+		}
+		events
+	}
+}
+
+/// The hardware checks run by the production self-test.
+///
+/// Other resources own the pins we look at, so we go straight to the GPIO
+/// registers (see `gpio_registers`). The ADC check uses the analog input
+/// readings (see `AnalogSampler`).
+pub struct BoardTest;
+
+impl BoardTest {
+	/// The PS/2 lines, as (port, pin), for the ports this build has. The
+	/// keyboard clock is USART2 RX in `uart2` builds.
+	#[cfg(any(feature = "ps2-keyboard", feature = "ps2-mouse"))]
+	const PS2_LINES: &[(u8, u8)] = &[
+		#[cfg(all(feature = "ps2-keyboard", not(feature = "uart2")))]
+		(b'A', 15),
+		#[cfg(feature = "ps2-mouse")]
+		(b'B', 3),
+		#[cfg(feature = "ps2-keyboard")]
+		(b'B', 4),
+		#[cfg(feature = "ps2-mouse")]
+		(b'B', 5),
+	];
+
+	/// The power LED pin, on GPIOB
+	const LED_PIN: u8 = 0;
+
+	/// The buzzer pin, on GPIOB
+	#[cfg(all(feature = "speaker", not(feature = "current-sense")))]
+	const BUZZER_PIN: u8 = 1;
+
+	/// How long a pin gets to settle, in CPU cycles (at least 10us, even when
+	/// we're running from the HSI)
+	const SETTLE_CYCLES: u32 = 480;
+
+	/// Look at each PS/2 line, with our internal pull-down turned on. A line
+	/// with its external pull-up fitted still reads high.
+	#[cfg(any(feature = "ps2-keyboard", feature = "ps2-mouse"))]
+	fn sample_ps2_lines(&mut self, lines: &mut [PinSamples]) {
+		for (samples, &(port, pin)) in lines.iter_mut().zip(Self::PS2_LINES.iter()) {
+			let gpio = gpio_registers(port);
+			let shift = u32::from(pin) * 2;
+			cortex_m::interrupt::free(|_cs| {
+				let pupdr = gpio.pupdr.read().bits();
+				// 0b10 is pull-down
+				gpio.pupdr
+					.write(|w| unsafe { w.bits((pupdr & !(0b11 << shift)) | (0b10 << shift)) });
+				cortex_m::asm::delay(Self::SETTLE_CYCLES);
+				samples.sample((gpio.idr.read().bits() & (1 << pin)) != 0);
+				gpio.pupdr.write(|w| unsafe { w.bits(pupdr) });
+			});
+		}
+	}
+
+	/// Drive the power LED the other way, check the pin follows, and put it
+	/// back.
+	///
+	/// A dimmed LED is driven by TIM1, so we make it a plain output for the
+	/// check.
+	fn check_led(&mut self) -> bool {
+		let gpio = gpio_registers(b'B');
+		let mask = 1 << Self::LED_PIN;
+		let shift = u32::from(Self::LED_PIN) * 2;
+		cortex_m::interrupt::free(|_cs| {
+			let moder = gpio.moder.read().bits();
+			gpio.moder
+				.write(|w| unsafe { w.bits((moder & !(0b11 << shift)) | (0b01 << shift)) });
+			let was_on = (gpio.odr.read().bits() & mask) != 0;
+			// The upper half of BSRR resets pins, the lower half sets them
+			let (flip, restore) = if was_on {
+				(mask << 16, mask)
+			} else {
+				(mask, mask << 16)
+			};
+			gpio.bsrr.write(|w| unsafe { w.bits(flip) });
+			cortex_m::asm::delay(Self::SETTLE_CYCLES);
+			let flipped = ((gpio.idr.read().bits() & mask) != 0) != was_on;
+			gpio.bsrr.write(|w| unsafe { w.bits(restore) });
+			cortex_m::asm::delay(Self::SETTLE_CYCLES);
+			let restored = ((gpio.idr.read().bits() & mask) != 0) == was_on;
+			gpio.moder.write(|w| unsafe { w.bits(moder) });
+			flipped && restored
+		})
+	}
+
+	/// Check the buzzer pin is toggling (so call this while a note plays).
+	#[cfg(all(feature = "speaker", not(feature = "current-sense")))]
+	fn check_buzzer(&mut self) -> bool {
+		let gpio = gpio_registers(b'B');
+		let mut samples = PinSamples::new();
+		// A couple of periods of `SELF_TEST_NOTE`, even at 48 MHz
+		for _ in 0..200 {
+			samples.sample((gpio.idr.read().bits() & (1 << Self::BUZZER_PIN)) != 0);
+			cortex_m::asm::delay(Self::SETTLE_CYCLES);
+		}
+		samples.toggled()
+	}
+}
+
+/// Sound a fault code once on the buzzer, and blink it on the power LED at
+/// the same time, by busy-waiting.
+///
+/// This is only for `init`, before we answer the host, so nothing else needs
+/// the CPU.
+fn signal_fault(
+	fault: Fault,
+	led: &mut PowerLed,
+	#[cfg(all(feature = "speaker", not(feature = "current-sense")))] buzzer: &mut Buzzer,
+) {
+	let clock_hz = CLOCK_HZ.load(Ordering::Relaxed);
+	info!("Sounding fault {}", fault);
+	for (on, units) in fault.signal() {
+		if on {
+			led.set_level(100);
+			#[cfg(all(feature = "speaker", not(feature = "current-sense")))]
+			buzzer.play(faults::TONE_HZ, clock_hz);
+		} else {
+			led.set_level(0);
+			#[cfg(all(feature = "speaker", not(feature = "current-sense")))]
+			buzzer.stop();
+		}
+		cortex_m::asm::delay((clock_hz / 1000) * faults::UNIT_MS * u32::from(units));
+	}
+}
+
+/// Log how the protocol loopback went.
+fn log_loopback(results: &LoopbackResults) {
+	if results.passed() {
+		info!("Loopback: {} exchanges passed", results.exchanges);
+	} else {
+		warn!(
+			"Loopback: {} of {} exchanges failed, first #{}",
+			results.failures,
+			results.exchanges,
+			results.first_failure.unwrap_or(0)
+		);
+	}
+}
+
+/// Get the registers for GPIOA or GPIOB, for code which changes a pin's mode
+/// at run time.
+///
+/// Callers must only touch their own pins' bits, and do any read-modify-write
+/// inside a critical section.
+fn gpio_registers(port: u8) -> &'static pac::gpioa::RegisterBlock {
+	// Safety: see above
+	unsafe {
+		if port == b'A' {
+			&*pac::GPIOA::ptr()
+		} else {
+			// GPIOB has the same layout, as far as we're concerned
+			&*(pac::GPIOB::ptr() as *const pac::gpioa::RegisterBlock)
+		}
+	}
+}
+
+/// The spare pins the host uses through the GPIO and PWM registers.
+///
+/// The pins change mode at run time, so we drive them through the GPIOB
+/// registers directly (see `gpio_registers`).
+#[cfg(not(any(
+	feature = "fan",
+	feature = "encoder",
+	feature = "i2c-target",
+	feature = "ps2-power",
+	feature = "status-led"
+)))]
+pub struct ExpansionPins {
+	/// GPIO 0 and 1 - we own them, but don't use the HAL types
+	_pins: (PB6<Input<Floating>>, PB7<Input<Floating>>),
+	/// Generates the PWM output on GPIO 0 (PB6 is TIM16_CH1N)
+	pwm_timer: pac::TIM16,
+	/// The direction, output and pull settings (and whether GPIO 0 is a PWM
+	/// output) we last applied
+	settings: Option<(u8, u8, u8, bool)>,
+	/// The PWM timer settings we last applied
+	pwm_timing: Option<PwmTiming>,
+}
+
+#[cfg(not(any(
+	feature = "fan",
+	feature = "encoder",
+	feature = "i2c-target",
+	feature = "ps2-power",
+	feature = "status-led"
+)))]
+impl ExpansionPins {
+	/// The GPIOB pin number for each GPIO pin
+	const PINS: [u8; 2] = [6, 7];
+
+	/// Take the pins, and set up TIM16 for PWM output (but leave GPIO 0 as
+	/// an input for now).
+	fn new(pins: (PB6<Input<Floating>>, PB7<Input<Floating>>), tim: pac::TIM16) -> ExpansionPins {
+		// Safety: we only touch the TIM16 enable bit, which nothing else
+		// modifies after init.
+		let rcc = unsafe { &*pac::RCC::ptr() };
+		rcc.apb2enr.modify(|_r, w| w.tim16en().set_bit());
+		// PWM mode 1 on channel 1, driven out of the complementary pin
+		tim.ccmr1_output()
+			.write(|w| unsafe { w.oc1m().bits(0b110).oc1pe().set_bit() });
+		tim.ccer.write(|w| w.cc1ne().set_bit());
+		tim.bdtr.write(|w| w.moe().set_bit());
+		tim.cr1.write(|w| w.arpe().set_bit().cen().set_bit());
+		// TIM16_CH1N is AF2 on PB6. This only matters once the pin is in
+		// alternate function mode.
+		let regs = gpio_registers(b'B');
+		cortex_m::interrupt::free(|_cs| {
+			regs.afrl
+				.modify(|r, w| unsafe { w.bits((r.bits() & !(0xF << 24)) | (2 << 24)) });
+		});
+		ExpansionPins {
+			_pins: pins,
+			pwm_timer: tim,
+			settings: None,
+			pwm_timing: None,
+		}
+	}
+
+	/// Apply the host's settings, if they have changed.
+	///
+	/// The timer clock changes as we enter and leave standby, so `clock_hz`
+	/// has to be kept up to date.
+	fn configure(&mut self, gpio: &Gpio, pwm: &Pwm, clock_hz: u32) {
+		if pwm.is_enabled() {
+			let timing = pwm.timing(clock_hz);
+			if self.pwm_timing != Some(timing) {
+				self.pwm_timing = Some(timing);
+				self.pwm_timer
+					.psc
+					.write(|w| unsafe { w.bits(u32::from(timing.prescaler)) });
+				self.pwm_timer
+					.arr
+					.write(|w| unsafe { w.bits(u32::from(timing.reload)) });
+				self.pwm_timer
+					.ccr1
+					.write(|w| unsafe { w.bits(u32::from(timing.compare)) });
+				// Load the new prescaler straight away
+				self.pwm_timer.egr.write(|w| w.ug().set_bit());
+			}
+		}
+		let settings = (
+			gpio.direction(),
+			gpio.output(),
+			gpio.pull(),
+			pwm.is_enabled(),
+		);
+		if self.settings == Some(settings) {
+			return;
+		}
+		self.settings = Some(settings);
+		let regs = gpio_registers(b'B');
+		for (bit, &pin) in Self::PINS.iter().enumerate() {
+			let mask = 1 << bit;
+			let shift = u32::from(pin) * 2;
+			// 0b10 is alternate function, 0b01 is a general purpose output,
+			// and 0b00 is an input
+			let mode = if bit == 0 && pwm.is_enabled() {
+				0b10
+			} else {
+				u32::from((gpio.direction() & mask) != 0)
+			};
+			let pull = u32::from(gpio.pin_pull(bit as u8));
+			cortex_m::interrupt::free(|_cs| {
+				// Set the level first, so an output doesn't glitch
+				let level = if (gpio.output() & mask) != 0 {
+					1 << pin
+				} else {
+					1 << (pin + 16)
+				};
+				regs.bsrr.write(|w| unsafe { w.bits(level) });
+				regs.pupdr.modify(|r, w| unsafe {
+					w.bits((r.bits() & !(0b11 << shift)) | (pull << shift))
+				});
+				regs.moder.modify(|r, w| unsafe {
+					w.bits((r.bits() & !(0b11 << shift)) | (mode << shift))
+				});
+			});
+		}
+	}
+
+	/// Read the level of each pin.
+	fn read(&self) -> u8 {
+		let idr = gpio_registers(b'B').idr.read().bits();
+		let mut input = 0;
+		for (bit, &pin) in Self::PINS.iter().enumerate() {
+			if (idr & (1 << pin)) != 0 {
+				input |= 1 << bit;
+			}
+		}
+		input
+	}
+}
+
+/// The RGB status LED, a WS2812 on PB6 (TIM16_CH1N).
+///
+/// TIM16 runs at the WS2812 bit rate, and DMA channel 4 loads the next pulse
+/// width into its compare register at every update. USART1 RX has channel 3
+/// (where the TIM16 requests usually go), so `init` remaps them.
+#[cfg(feature = "status-led")]
+pub struct StatusLedDriver {
+	/// The data pin, and PB7, which is left spare
+	_pins: (PB6<Alternate<AF2>>, PB7<Input<PullDown>>),
+	/// Generates the pulses
+	tim: pac::TIM16,
+	/// The pulse widths the DMA controller is sending. The driver lives in a
+	/// task's local resource, so this doesn't move whilst it is being sent.
+	frame: [u16; statusled::FRAME_LEN],
+	/// The colour we last sent, and the timer clock we sent it with
+	sent: Option<([u8; 3], u32)>,
+}
+
+#[cfg(feature = "status-led")]
+impl StatusLedDriver {
+	/// Take the pin, and set up TIM16 to send frames. The LED is left alone
+	/// until the first call to `show`.
+	fn new(pins: (PB6<Alternate<AF2>>, PB7<Input<PullDown>>), tim: pac::TIM16) -> StatusLedDriver {
+		// Safety: we only touch the TIM16 enable bit, which nothing else
+		// modifies after init.
+		let rcc = unsafe { &*pac::RCC::ptr() };
+		rcc.apb2enr.modify(|_r, w| w.tim16en().set_bit());
+		// PWM mode 1 on channel 1, driven out of the complementary pin. The
+		// compare value is zero, so the line idles low.
+		tim.ccmr1_output()
+			.write(|w| unsafe { w.oc1m().bits(0b110).oc1pe().set_bit() });
+		tim.ccer.write(|w| w.cc1ne().set_bit());
+		tim.bdtr.write(|w| w.moe().set_bit());
+		// Ask for a DMA transfer at every update
+		tim.dier.write(|w| w.ude().set_bit());
+		tim.cr1.write(|w| w.arpe().set_bit().cen().set_bit());
+		StatusLedDriver {
+			_pins: pins,
+			tim,
+			frame: [0; statusled::FRAME_LEN],
+			sent: None,
+		}
+	}
+
+	/// Send `colour` (red, green and blue) to the LED, if it has changed.
+	///
+	/// The timer clock changes as we enter and leave standby, so `clock_hz`
+	/// has to be kept up to date.
+	fn show(&mut self, colour: [u8; 3], clock_hz: u32) {
+		if self.sent == Some((colour, clock_hz)) {
+			return;
+		}
+		// Safety: we only touch channel 4, which nothing else uses, and the
+		// interrupt flag clear register is write-only.
+		let dma = unsafe { &*pac::DMA1::ptr() };
+		if dma.ch4.ndtr.read().ndt().bits() != 0 {
+			// Still sending the last frame - try again next time
+			return;
+		}
+		self.sent = Some((colour, clock_hz));
+		let timing = BitTiming::new(clock_hz);
+		self.frame = statusled::encode(colour, timing);
+		self.tim
+			.arr
+			.write(|w| unsafe { w.bits(u32::from(timing.reload)) });
+		dma.ch4.cr.write(|w| w);
+		dma.ifcr.write(|w| w.cgif4().set_bit());
+		let ccr1_address = &self.tim.ccr1 as *const _ as u32;
+		dma.ch4.par.write(|w| unsafe { w.pa().bits(ccr1_address) });
+		dma.ch4
+			.mar
+			.write(|w| unsafe { w.ma().bits(self.frame.as_ptr() as u32) });
+		dma.ch4
+			.ndtr
+			.write(|w| unsafe { w.ndt().bits(statusled::FRAME_LEN as u16) });
+		// The frame must be in memory before the DMA controller reads it
+		core::sync::atomic::compiler_fence(Ordering::Release);
+		dma.ch4.cr.write(|w| {
+			// From memory to the timer, a half-word at a time, once
+			w.dir().set_bit();
+			w.minc().set_bit();
+			unsafe { w.msize().bits(0b01).psize().bits(0b01) };
+			w.en().set_bit();
+			w
+		});
+	}
+}
+
+/// The front panel rotary encoder, on the I²C pins.
+///
+/// This is empty without the `encoder` feature, so the EXTI interrupt can
+/// always own it.
+pub struct EncoderInput {
+	/// Phase A (PB6) and phase B (PB7), pulled up
+	#[cfg(feature = "encoder")]
+	pins: (PB6<Input<PullUp>>, PB7<Input<PullUp>>),
+	/// Turns the edges into steps
+	#[cfg(feature = "encoder")]
+	decoder: Quadrature,
+}
+
+#[cfg(feature = "encoder")]
+impl EncoderInput {
+	/// Look at the pins after an edge on either of them, and count any step.
+	fn edge(&mut self) {
+		let a = self.pins.0.is_high().unwrap();
+		let b = self.pins.1.is_high().unwrap();
+		let step = self.decoder.update(a, b);
+		if step != 0 {
+			let total = ENCODER_TOTAL.load(Ordering::Relaxed);
+			ENCODER_TOTAL.store(total.wrapping_add(i16::from(step)), Ordering::Relaxed);
+		}
+	}
+}
+
+/// Samples the analog inputs, for the Analog Input registers.
+///
+/// The ADC is only powered up while we take a sample.
+#[cfg(all(feature = "adc", not(any(feature = "current-sense", feature = "fan"))))]
+pub struct AnalogSampler {
+	/// The ADC (nothing else uses it in this build)
+	adc: pac::ADC,
+	/// Analog inputs 0 and 1 - the rail monitor pins
+	_pins: (PA0<Analog>, PA1<Analog>),
+}
+
+#[cfg(all(feature = "adc", not(any(feature = "current-sense", feature = "fan"))))]
+impl AnalogSampler {
+	/// The ADC channel for each analog input
+	const CHANNELS: [u32; ANALOG_INPUTS] = [1 << 0, 1 << 1];
+
+	/// Take the ADC and the analog input pins.
+	fn new(adc: pac::ADC, pins: (PA0<Analog>, PA1<Analog>)) -> AnalogSampler {
+		AnalogSampler { adc, _pins: pins }
+	}
+
+	/// Read the internal voltage reference, and then each input, `samples`
+	/// times, and return the average of each.
+	fn sample(&mut self, samples: u16) -> (u16, [u16; ANALOG_INPUTS]) {
+		self.power_up();
+		let vrefint = self.convert(VREFINT_CHANNEL, samples);
+		let mut inputs = [0u16; ANALOG_INPUTS];
+		for (reading, &channel) in inputs.iter_mut().zip(Self::CHANNELS.iter()) {
+			*reading = self.convert(channel, samples);
+		}
+		self.power_down();
+		(vrefint, inputs)
+	}
+
+	/// Read the internal temperature sensor, in ADC counts.
+	fn read_temperature(&mut self) -> u16 {
+		self.power_up();
+		let counts = self.convert(TS_CHANNEL, 1);
+		self.power_down();
+		counts
+	}
+
+	/// Turn on and calibrate the ADC, with the internal reference and the
+	/// temperature sensor.
+	fn power_up(&mut self) {
+		let adc = &mut self.adc;
+		// Safety: nothing else uses the ADC in this build, so nothing else
+		// touches its enable bit.
+		let rcc = unsafe { &*pac::RCC::ptr() };
+		rcc.apb2enr.modify(|_r, w| w.adcen().set_bit());
+		// Start the reference and the temperature sensor first, so they have
+		// settled by the time we convert
+		adc.ccr
+			.modify(|_r, w| w.vrefen().set_bit().tsen().set_bit());
+		// Clock the ADC from PCLK/4, and calibrate it
+		adc.cfgr2.write(|w| unsafe { w.ckmode().bits(0b10) });
+		adc.cr.modify(|_r, w| w.adcal().set_bit());
+		while adc.cr.read().adcal().bit_is_set() {}
+		// They need a sample time of at least 17.1us, and the longest sample
+		// time suits a high impedance source (like a divider) too
+		adc.smpr.write(|w| unsafe { w.smp().bits(0b111) });
+		adc.cr.modify(|_r, w| w.aden().set_bit());
+		while adc.isr.read().adrdy().bit_is_clear() {}
+	}
+
+	/// Take `samples` readings of an ADC channel, and return the average.
+	fn convert(&mut self, channel: u32, samples: u16) -> u16 {
+		let adc = &mut self.adc;
+		adc.chselr.write(|w| unsafe { w.bits(channel) });
+		let mut total = 0u32;
+		for _ in 0..samples {
+			adc.cr.modify(|_r, w| w.adstart().set_bit());
+			while adc.isr.read().eoc().bit_is_clear() {}
+			total += u32::from(adc.dr.read().data().bits());
+		}
+		(total / u32::from(samples.max(1))) as u16
+	}
+
+	/// Turn the ADC off again.
+	fn power_down(&mut self) {
+		let adc = &mut self.adc;
+		adc.ccr
+			.modify(|_r, w| w.vrefen().clear_bit().tsen().clear_bit());
+		adc.cr.modify(|_r, w| w.addis().set_bit());
+		while adc.cr.read().aden().bit_is_set() {}
+		// Safety: see `power_up`
+		let rcc = unsafe { &*pac::RCC::ptr() };
+		rcc.apb2enr.modify(|_r, w| w.adcen().clear_bit());
+	}
+
+	/// The factory reading of the internal voltage reference.
+	fn vrefint_cal() -> u16 {
+		// Safety: this is a factory-programmed value in system memory
+		unsafe { core::ptr::read_volatile(VREFINT_CAL_ADDRESS) }
+	}
+
+	/// The factory reading of the temperature sensor, at 30°C.
+	fn ts_cal1() -> u16 {
+		// Safety: this is a factory-programmed value in system memory
+		unsafe { core::ptr::read_volatile(TS_CAL1_ADDRESS) }
+	}
+}
+
+/// A word captured from one of the PS/2 ports
+pub enum Ps2Data {
+	/// Word from PS/2 port 0, and when its last bit arrived
+	#[cfg(feature = "ps2-keyboard")]
+	Port0(u16, Stamp),
+	/// Word from PS/2 port 1, and when its last bit arrived
+	#[cfg(feature = "ps2-mouse")]
+	Port1(u16, Stamp),
+	/// A scancode the host injected, as if it came from the keyboard
+	#[cfg(feature = "ps2-keyboard")]
+	Injected(u8),
+}
+
+// The dispatchers are interrupts we don't otherwise use, which every
+// supported part has (under the same name). USB isn't one, as the `usb`
+// feature needs it.
+#[rtic::app(device = crate::pac, peripherals = true, dispatchers = [FLASH, TIM14, TIM15, TIM16, TIM17])]
+mod app {
+	use super::*;
+	use rtic::mutex_prelude::*;
+	use rtic_sync::{
+		channel::{Receiver, Sender},
+		make_channel,
+	};
+
+	#[shared]
+	struct Shared {
+		/// The Clear-To-Send line on the FTDI UART header (which the serial object can't handle)
+		#[cfg(not(feature = "usb"))]
+		#[lock_free]
+		_pin_uart_cts: PA11<Alternate<AF1>>,
+		/// The Ready-To-Receive line on the FTDI UART header (which the serial object can't handle)
+		#[cfg(not(feature = "usb"))]
+		#[lock_free]
+		_pin_uart_rts: PA12<Alternate<AF1>>,
+		/// Our register state
+		register_state: RegisterState,
+		/// SPI Peripheral
+		spi: neotron_bmc_pico::spi::SpiPeripheral<{ registers::PAYLOAD_BUFFER_LEN }, 64>,
+		/// A log of interesting things that have happened
+		event_log: EventLog<EVENT_LOG_LEN>,
+		/// Interrupts to the host
+		host_irq: HostIrq,
+		/// The PS/2 keyboard port
+		kb_port: KeyboardPort,
+		/// The PS/2 mouse port
+		mouse_port: MousePort,
+		/// Decides which PS/2 port may send next
+		ps2_arbiter: Ps2Arbiter<PS2_TX_QUEUE_LEN>,
+		/// Bytes received on the FTDI UART header
+		uart_rx: UartRx,
+		/// The second serial port
+		#[cfg(feature = "uart2")]
+		uart2: Uart2,
+		/// Samples the main board current
+		#[cfg(feature = "current-sense")]
+		current_sense: CurrentSense,
+		/// Drives the case fan
+		#[cfg(feature = "fan")]
+		fan: Fan,
+		/// I²C Target, for register access over I²C
+		#[cfg(feature = "i2c-target")]
+		i2c_target: I2cTarget<{ registers::PAYLOAD_BUFFER_LEN }, 64>,
+		/// The flash controller, for saving the config and the power state
+		/// journal
+		flash: pac::FLASH,
+	}
+
+	#[local]
+	struct Local {
+		/// The FTDI UART header (J105). Received bytes arrive by DMA (see
+		/// `UartRx`).
+		_serial: serial::Serial<pac::USART1, PA9<Alternate<AF1>>, PA10<Alternate<AF1>>>,
+		/// The power button
+		button_power: PF0<Input<PullUp>>,
+		/// The reset button
+		button_reset: PF1<Input<PullUp>>,
+		/// The external interrupt peripheral
+		exti: pac::EXTI,
+		/// The calendar in the backup domain
+		rtc: Rtc,
+		/// The rotary encoder (only used with the `encoder` feature)
+		encoder_input: EncoderInput,
+		/// The PS/2 power switches - keyboard, then mouse - low for on
+		#[cfg(feature = "ps2-power")]
+		ps2_power_pins: (PB6<Output<OpenDrain>>, PB7<Output<OpenDrain>>),
+		/// Sends colours to the RGB status LED
+		#[cfg(feature = "status-led")]
+		status_led_driver: StatusLedDriver,
+		/// The hardware checks for the production self-test
+		board_test: BoardTest,
+		/// Samples the analog inputs
+		#[cfg(all(feature = "adc", not(any(feature = "current-sense", feature = "fan"))))]
+		analog_sampler: AnalogSampler,
+		/// The spare pins the host can use as GPIO, or for PWM
+		#[cfg(not(any(
+			feature = "fan",
+			feature = "encoder",
+			feature = "i2c-target",
+			feature = "ps2-power",
+			feature = "status-led"
+		)))]
+		expansion_pins: ExpansionPins,
+		/// CS pin
+		pin_cs: PA4<Input<PullUp>>,
+		/// The buzzer
+		#[cfg(all(feature = "speaker", not(feature = "current-sense")))]
+		buzzer: Buzzer,
+		/// Debounces the power, reset and GPIO buttons
+		buttons: Buttons,
+		/// Write captured PS/2 keyboard words here
+		ps2_q_in: Sender<'static, Ps2Data, MSG_Q_LEN>,
+		/// Write captured PS/2 mouse words here (the same queue as `ps2_q_in`)
+		#[cfg(feature = "ps2-mouse")]
+		ps2_q_in_mouse: Sender<'static, Ps2Data, MSG_Q_LEN>,
+		/// Write injected keyboard scancodes here (the same queue as
+		/// `ps2_q_in`)
+		#[cfg(feature = "ps2-keyboard")]
+		ps2_q_in_inject: Sender<'static, Ps2Data, MSG_Q_LEN>,
+		/// Read captured PS/2 words here
+		ps2_q_out: Receiver<'static, Ps2Data, MSG_Q_LEN>,
+		/// Write how many UART bytes have arrived here
+		uart_q_in: Sender<'static, usize, MSG_Q_LEN>,
+		/// Write how many UART bytes have arrived here (the same queue as
+		/// `uart_q_in`)
+		uart_q_in_dma: Sender<'static, usize, MSG_Q_LEN>,
+		/// Write how many bytes were typed on the USB console here (the same
+		/// queue as `uart_q_in`)
+		#[cfg(feature = "usb")]
+		uart_q_in_usb: Sender<'static, usize, MSG_Q_LEN>,
+		/// The USB console and register interface
+		#[cfg(feature = "usb")]
+		usb_console: UsbConsole,
+		/// Read how many UART bytes have arrived here
+		uart_q_out: Receiver<'static, usize, MSG_Q_LEN>,
+		/// DC power, reset and the power LED
+		power: PowerControl,
+		/// Send wake-ups to the power manager here
+		power_q_in: Sender<'static, PowerRequest, MSG_Q_LEN>,
+		/// Send button presses to the power manager here (the same queue as
+		/// `power_q_in`, as are the rest)
+		power_q_in_buttons: Sender<'static, PowerRequest, MSG_Q_LEN>,
+		/// Send keyboard reset chords to the power manager here
+		power_q_in_keyboard: Sender<'static, PowerRequest, MSG_Q_LEN>,
+		/// Send the host's power requests to the power manager here
+		power_q_in_host: Sender<'static, PowerRequest, MSG_Q_LEN>,
+		/// Send disk activity to the power manager here
+		power_q_in_activity: Sender<'static, PowerRequest, MSG_Q_LEN>,
+		/// Send over-temperature power cuts to the power manager here
+		#[cfg(all(feature = "adc", not(feature = "current-sense")))]
+		power_q_in_thermal: Sender<'static, PowerRequest, MSG_Q_LEN>,
+		/// Send host watchdog resets to the power manager here
+		power_q_in_watchdog: Sender<'static, PowerRequest, MSG_Q_LEN>,
+		/// Send over-current power cuts to the power manager here
+		#[cfg(feature = "current-sense")]
+		power_q_in_current: Sender<'static, PowerRequest, MSG_Q_LEN>,
+		/// Send supply voltage warnings to the power manager here
+		#[cfg(feature = "stm32f031")]
+		power_q_in_pvd: Sender<'static, PowerRequest, MSG_Q_LEN>,
+		/// Send supply voltage warnings to the power manager here (the
+		/// STM32F042 and STM32F072 share the vector with the VDDIO2 monitor)
+		#[cfg(any(feature = "stm32f042", feature = "stm32f072"))]
+		power_q_in_pvd_vddio2: Sender<'static, PowerRequest, MSG_Q_LEN>,
+		/// Read requests for the power manager here
+		power_q_out: Receiver<'static, PowerRequest, MSG_Q_LEN>,
+		/// Send events for the lifetime counters here
+		stats_q_in: Sender<'static, Counter, MSG_Q_LEN>,
+		/// Read events for the lifetime counters here
+		stats_q_out: Receiver<'static, Counter, MSG_Q_LEN>,
+		/// Send the time the DC power came on here, to time the rails
+		#[cfg(all(feature = "adc", not(any(feature = "current-sense", feature = "fan"))))]
+		rise_q_in: Sender<'static, u32, MSG_Q_LEN>,
+		/// Read the times the DC power came on here
+		#[cfg(all(feature = "adc", not(any(feature = "current-sense", feature = "fan"))))]
+		rise_q_out: Receiver<'static, u32, MSG_Q_LEN>,
+	}
+
+	/// The entry point to our application.
+	///
+	/// Sets up the hardware and spawns the async tasks.
+	///
+	/// * Task `power_manager` - moves the main board between power states
+	/// * Task `button_poll` - checks the power, reset and GPIO buttons
+	/// * Task `ps2_task` - handles words captured from the PS/2 ports
+	/// * Task `uart_task` - handles bytes received on the UART
+	/// * Task `spi_watchdog` - aborts stuck SPI transactions
+	/// * Task `irq_poll` - raises coalesced interrupts once they time out, and
+	///   looks for changes in the registers the host polls
+	/// * Task `heartbeat` - ticks the heartbeat in the BMC Health register
+	/// * Task `key_repeat` - repeats a held key, for hosts in make-only or ASCII mode (optional)
+	/// * Task `current_sense_poll` - samples the main board current (optional)
+	/// * Task `fan_poll` - controls the case fan (optional)
+	/// * Task `uart2_poll` - applies the UART2 settings (optional)
+	/// * Task `expansion_poll` - applies the GPIO and PWM settings (optional)
+	/// * Task `analog_poll` - samples the analog inputs (optional)
+	/// * Task `thermal_poll` - shuts down the main board if it gets too hot (optional)
+	/// * Task `watchdog_poll` - resets the main board if the host stops kicking its watchdog
+	/// * Task `power_journal` - restores the power after a power cut, and records the power state
+	/// * Task `power_request` - powers off or suspends when the host asks
+	/// * Task `encoder_poll` - updates the rotary encoder registers (optional)
+	/// * Task `ps2_power_poll` - switches the PS/2 ports' power (optional)
+	/// * Task `status_led_poll` - updates the RGB status LED (optional)
+	/// * Task `buzzer_play` - plays queued notes on the buzzer
+	/// * Task `activity_led` - passes disk activity and the power LED settings to the power manager
+	/// * Task `ps2_tx` - sends commands to the PS/2 keyboard and mouse
+	/// * Task `stats_task` - keeps the lifetime counters in flash
+	/// * Task `boot_loopback` - checks the register dispatch at boot (optional)
+	/// * Task `rtc_poll` - starts and sets the RTC, and sounds its alarm
+	#[init(local = [uart_dma_buffer: [u8; UART_DMA_LEN] = [0; UART_DMA_LEN]])]
+	fn init(ctx: init::Context) -> (Shared, Local) {
+		info!("Neotron BMC version {:?} booting", VERSION);
+
+		let dp: pac::Peripherals = ctx.device;
+		let cp: cortex_m::Peripherals = ctx.core;
+
+		// Find out why we reset, before the HAL takes the RCC
+		let csr = dp.RCC.csr.read();
+		let watchdog_reset = csr.iwdgrstf().bit_is_set() || csr.wwdgrstf().bit_is_set();
+		dp.RCC.csr.modify(|_r, w| w.rmvf().set_bit());
+
+		let mut flash = dp.FLASH;
+		let mut rcc = dp
+			.RCC
+			.configure()
+			.hclk(48.mhz())
+			.pclk(48.mhz())
+			.sysclk(48.mhz())
+			.freeze(&mut flash);
+
+		info!("Configuring SysTick...");
+		// Initialize the monotonic timer using the Cortex-M SysTick peripheral
+		Mono::start(cp.SYST, rcc.clocks.sysclk().0);
+
+		info!("Creating pins...");
+		let mut board = Board::new(dp.GPIOA, dp.GPIOB, dp.GPIOF, &mut rcc);
+
+		board.sys_reset.set_low().unwrap();
+		board.dc_on.set_low().unwrap();
+		// The IRQ line is active low, until we've loaded the config
+		board.irq.set_high().unwrap();
+		// Let go of the mouse lines, so the mouse can talk to us
+		board.ps2_clk1.set_high().unwrap();
+		board.ps2_dat1.set_high().unwrap();
+		// And the keyboard lines, for the keyboard
+		board.ps2_dat0.set_high().unwrap();
+		let kb_port = KeyboardPort {
+			#[cfg(not(feature = "uart2"))]
+			clk: {
+				let mut clk = board.ps2_clk0;
+				clk.set_high().unwrap();
+				clk
+			},
+			dat: board.ps2_dat0,
+			link: Ps2Link::new(),
+			bit_timer: BitTimer::new(),
+		};
+
+		info!("Creating UART...");
+
+		let serial = serial::Serial::usart1(
+			dp.USART1,
+			(board.uart_tx, board.uart_rx),
+			UART_BAUD.bps(),
+			&mut rcc,
+		);
+
+		// The main board starts off powered down, so drop into standby. This
+		// moves the UART clock to the HSI, so it must come after the UART is set up.
+		info!("Entering standby...");
+		let mut standby = Standby::new(SYSTICK_HZ, UART_BAUD);
+		standby.enter();
+		CLOCK_HZ.store(standby.clock_hz(), Ordering::Relaxed);
+		let stopwatch = Stopwatch::new(dp.TIM14, standby.clock_hz());
+		let mut led_power = PowerLed::new(board.led_power, dp.TIM1, standby.clock_hz());
+
+		let uart_rx = UartRx::new(dp.DMA1, ctx.local.uart_dma_buffer);
+
+		#[cfg(feature = "usb")]
+		let usb_console = {
+			info!("Starting USB...");
+			usb::start_clock(&dp.CRS);
+			let bus = cortex_m::singleton!(: UsbBusAllocator<UsbBusType> = UsbBus::new(Peripheral {
+				usb: dp.USB,
+				pin_dm: board.usb.0,
+				pin_dp: board.usb.1,
+			}))
+			.unwrap();
+			UsbConsole::new(bus)
+		};
+
+		// Put SPI into Peripheral mode (i.e. CLK is an input) and enable the RX interrupt.
+		let spi = neotron_bmc_pico::spi::SpiPeripheral::new(
+			dp.SPI1,
+			(board.spi_sck, board.spi_cipo, board.spi_copi),
+			8_000_000,
+			&mut rcc,
+		);
+
+		let (config, config_status) = match Config::load() {
+			Some(config) => (config, CONFIG_STATUS_LOADED),
+			None => (Config::DEFAULT, CONFIG_STATUS_DEFAULTS),
+		};
+		info!("Config: {}", config);
+
+		// Drive the IRQ line the way the host board wants
+		let mut host_irq = HostIrq {
+			controller: InterruptController::new(),
+			pin_irq: board.irq,
+			line_control: Config::DEFAULT.irq_line,
+		};
+		host_irq.set_line_control(config.irq_line);
+		CS_FILTER_US.store(config.cs_filter_us, Ordering::Relaxed);
+
+		// Answer on the I²C bus too, at the address in the config
+		#[cfg(feature = "i2c-target")]
+		let i2c_target = I2cTarget::new(dp.I2C1, board.i2c, config.i2c_address, &mut rcc);
+
+		// Should we power on by ourselves, now the BMC has powered up?
+		let journal = read_journal();
+		let restore_after_s = if restore_power(config.power_restore, journal.was_on) {
+			Some(config.power_on_delay_s)
+		} else {
+			None
+		};
+
+		#[cfg(all(feature = "speaker", not(feature = "current-sense")))]
+		let mut buzzer = {
+			// The pin keeps its mode when it is dropped
+			cortex_m::interrupt::free(|cs| board.buzzer.into_alternate_af1(cs));
+			Buzzer::new(dp.TIM3)
+		};
+
+		// The host can't ask us about these yet, so sound them instead
+		if config_status == CONFIG_STATUS_DEFAULTS && Config::is_corrupt(&Config::read_page()) {
+			warn!("Config is corrupt - using the defaults");
+			for _ in 0..faults::WARNING_REPEATS {
+				signal_fault(
+					Fault::Config,
+					&mut led_power,
+					#[cfg(all(feature = "speaker", not(feature = "current-sense")))]
+					&mut buzzer,
+				);
+			}
+		}
+		#[cfg(all(feature = "adc", not(any(feature = "current-sense", feature = "fan"))))]
+		let analog_sampler = {
+			let mut sampler = AnalogSampler::new(dp.ADC, board.analog);
+			let (vrefint, _) = sampler.sample(4);
+			let vdda_mv = analog::vdda_mv(AnalogSampler::vrefint_cal(), vrefint);
+			if !faults::supply_ok(vdda_mv) {
+				// The main board is off, and held in reset, and stays that way
+				warn!("Supply is {} mV - stopping", vdda_mv);
+				loop {
+					signal_fault(
+						Fault::Supply,
+						&mut led_power,
+						#[cfg(all(feature = "speaker", not(feature = "current-sense")))]
+						&mut buzzer,
+					);
+				}
+			}
+			sampler
+		};
+
+		// The current sense amplifier replaces the buzzer
+		#[cfg(feature = "current-sense")]
+		let current_sense = {
+			cortex_m::interrupt::free(|cs| board.buzzer.into_analog(cs));
+			CurrentSense::new(dp.ADC, &config)
+		};
+		// Without either, the pin stays a push-pull output, driven low
+		#[cfg(not(any(feature = "speaker", feature = "current-sense")))]
+		let _ = board.buzzer;
+
+		#[cfg(all(feature = "ps2-keyboard", not(feature = "uart2")))]
+		{
+			// Set EXTI15 to use PORT A (PA15) - keyboard clock input
+			dp.SYSCFG.exticr4.modify(|_r, w| w.exti15().pa15());
+
+			// Enable EXTI15 interrupt as external falling edge
+			dp.EXTI.imr.modify(|_r, w| w.mr15().set_bit());
+			dp.EXTI.emr.modify(|_r, w| w.mr15().set_bit());
+			dp.EXTI.ftsr.modify(|_r, w| w.tr15().set_bit());
+		}
+
+		#[cfg(feature = "ps2-mouse")]
+		{
+			// Set EXTI3 to use PORT B (PB3) - mouse clock input
+			dp.SYSCFG.exticr1.modify(|_r, w| w.exti3().pb3());
+
+			// Enable EXTI3 interrupt as external falling edge
+			dp.EXTI.imr.modify(|_r, w| w.mr3().set_bit());
+			dp.EXTI.emr.modify(|_r, w| w.mr3().set_bit());
+			dp.EXTI.ftsr.modify(|_r, w| w.tr3().set_bit());
+		}
+
+		// Set EXTI4 to use PORT A (PA4) - SPI CS
+		dp.SYSCFG.exticr2.modify(|_r, w| w.exti4().pa4());
+
+		// Enable EXTI4 interrupt as external falling/rising edge
+		dp.EXTI.imr.modify(|_r, w| w.mr4().set_bit());
+		dp.EXTI.emr.modify(|_r, w| w.mr4().set_bit());
+		dp.EXTI.ftsr.modify(|_r, w| w.tr4().set_bit());
+		dp.EXTI.rtsr.modify(|_r, w| w.tr4().set_bit());
+
+		// Every part but the STM32F030 can warn us when our supply sags (the
+		// STM32F030 has no voltage detector)
+		#[cfg(any(feature = "stm32f031", feature = "stm32f042", feature = "stm32f072"))]
+		{
+			// Safety: we only touch the PWR enable bit, which nothing else
+			// modifies after init.
+			let rcc_regs = unsafe { &*pac::RCC::ptr() };
+			rcc_regs.apb1enr.modify(|_r, w| w.pwren().set_bit());
+			dp.PWR
+				.cr
+				.modify(|_r, w| unsafe { w.pls().bits(PVD_LEVEL) }.pvde().set_bit());
+			// EXTI16 is the voltage detector output - interrupt on both edges
+			dp.EXTI.imr.modify(|_r, w| w.mr16().set_bit());
+			dp.EXTI.ftsr.modify(|_r, w| w.tr16().set_bit());
+			dp.EXTI.rtsr.modify(|_r, w| w.tr16().set_bit());
+			// In case the supply is already low, check it once we're running
+			#[cfg(feature = "stm32f031")]
+			rtic::pend(pac::Interrupt::PVD);
+			#[cfg(any(feature = "stm32f042", feature = "stm32f072"))]
+			rtic::pend(pac::Interrupt::PVD_VDDIO2);
+		}
+
+		#[cfg(feature = "fan")]
+		let fan = {
+			// The pins keep their modes when they are dropped
+			let _ = board.fan;
+			// Set EXTI7 to use PORT B (PB7) - fan tachometer
+			dp.SYSCFG.exticr2.modify(|_r, w| w.exti7().pb7());
+			// Enable EXTI7 interrupt as external falling edge
+			dp.EXTI.imr.modify(|_r, w| w.mr7().set_bit());
+			dp.EXTI.ftsr.modify(|_r, w| w.tr7().set_bit());
+			Fan::new(dp.TIM16, dp.ADC)
+		};
+
+		#[cfg(feature = "status-led")]
+		let status_led_driver = {
+			// USART1 RX has DMA channel 3, so move the TIM16 requests to
+			// channel 4
+			dp.SYSCFG.cfgr1.modify(|_r, w| w.tim16_dma_rmp().set_bit());
+			StatusLedDriver::new(board.status_led, dp.TIM16)
+		};
+
+		#[cfg(feature = "encoder")]
+		{
+			// Set EXTI6 and EXTI7 to use PORT B (PB6 and PB7) - rotary encoder
+			dp.SYSCFG
+				.exticr2
+				.modify(|_r, w| w.exti6().pb6().exti7().pb7());
+			// Enable EXTI6 and EXTI7 interrupts on both edges
+			dp.EXTI
+				.imr
+				.modify(|_r, w| w.mr6().set_bit().mr7().set_bit());
+			dp.EXTI
+				.ftsr
+				.modify(|_r, w| w.tr6().set_bit().tr7().set_bit());
+			dp.EXTI
+				.rtsr
+				.modify(|_r, w| w.tr6().set_bit().tr7().set_bit());
+		}
+
+		#[cfg(feature = "uart2")]
+		let uart2 = {
+			// The pins keep their modes when they are dropped
+			let _ = board.uart2;
+			Uart2::new(dp.USART2)
+		};
+
+		let (ps2_q_in, ps2_q_out) = make_channel!(Ps2Data, MSG_Q_LEN);
+		let (uart_q_in, uart_q_out) = make_channel!(usize, MSG_Q_LEN);
+		let (power_q_in, power_q_out) = make_channel!(PowerRequest, MSG_Q_LEN);
+		let (stats_q_in, stats_q_out) = make_channel!(Counter, MSG_Q_LEN);
+		#[cfg(all(feature = "adc", not(any(feature = "current-sense", feature = "fan"))))]
+		let (rise_q_in, rise_q_out) = make_channel!(u32, MSG_Q_LEN);
+
+		// Spawn the tasks that run all the time
+		power_manager::spawn().unwrap();
+		ps2_tx::spawn().unwrap();
+		button_poll::spawn().unwrap();
+		ps2_task::spawn().unwrap();
+		uart_task::spawn().unwrap();
+		spi_watchdog::spawn().unwrap();
+		irq_poll::spawn().unwrap();
+		heartbeat::spawn().unwrap();
+		#[cfg(feature = "ps2-keyboard")]
+		key_repeat::spawn().unwrap();
+		#[cfg(feature = "current-sense")]
+		current_sense_poll::spawn().unwrap();
+		#[cfg(feature = "fan")]
+		fan_poll::spawn().unwrap();
+		#[cfg(feature = "uart2")]
+		uart2_poll::spawn().unwrap();
+		#[cfg(not(any(
+			feature = "fan",
+			feature = "encoder",
+			feature = "i2c-target",
+			feature = "ps2-power",
+			feature = "status-led"
+		)))]
+		expansion_poll::spawn().unwrap();
+		#[cfg(all(feature = "adc", not(any(feature = "current-sense", feature = "fan"))))]
+		analog_poll::spawn().unwrap();
+		#[cfg(all(feature = "adc", not(feature = "current-sense")))]
+		thermal_poll::spawn().unwrap();
+		watchdog_poll::spawn().unwrap();
+		#[cfg(feature = "encoder")]
+		encoder_poll::spawn().unwrap();
+		#[cfg(feature = "ps2-power")]
+		ps2_power_poll::spawn().unwrap();
+		#[cfg(feature = "status-led")]
+		status_led_poll::spawn().unwrap();
+		power_journal::spawn(restore_after_s).unwrap();
+		#[cfg(all(feature = "speaker", not(feature = "current-sense")))]
+		buzzer_play::spawn().unwrap();
+		activity_led::spawn().unwrap();
+		stats_task::spawn(watchdog_reset).unwrap();
+		rtc_poll::spawn().unwrap();
+		#[cfg(feature = "loopback-test")]
+		boot_loopback::spawn().unwrap();
+
+		// Forget any chip select edge from whilst we were setting up. We'd only
+		// catch the end of that transaction, so it's better the host sees no
+		// response at all, and tries again.
+		dp.EXTI.pr.write(|w| w.pr4().set_bit());
+
+		info!("Init complete!");
+
+		let mut event_log = EventLog::new();
+		event_log.push(Event::new(0, EventKind::BmcStart, 0));
+
+		let shared_resources = Shared {
+			#[cfg(not(feature = "usb"))]
+			_pin_uart_cts: board.uart_cts,
+			#[cfg(not(feature = "usb"))]
+			_pin_uart_rts: board.uart_rts,
+			register_state: RegisterState {
+				// Kept from before the BMC lost power
+				boot_mode: journal.boot_mode.unwrap_or(BOOT_MODE_NORMAL),
+				nvram: Nvram::load(read_nvram().data),
+				..RegisterState::new(
+					VERSION,
+					&proto::BuildInfo {
+						firmware_version: BUILD_FIRMWARE_VERSION,
+						protocol_version: PROTOCOL_VERSION,
+						build_flags: {
+							let mut flags = 0;
+							if BUILD_GIT_DIRTY {
+								flags |= proto::BuildInfo::FLAG_DIRTY;
+							}
+							if cfg!(debug_assertions) {
+								flags |= proto::BuildInfo::FLAG_DEBUG;
+							}
+							flags
+						},
+						git_hash: BUILD_GIT_HASH,
+						build_timestamp: BUILD_TIMESTAMP,
+						feature_flags: FEATURE_FLAGS,
+					},
+					config,
+					config_status,
+				)
+			},
+			spi,
+			event_log,
+			host_irq,
+			kb_port,
+			mouse_port: MousePort {
+				clk: board.ps2_clk1,
+				dat: board.ps2_dat1,
+				link: Ps2Link::new(),
+				bit_timer: BitTimer::new(),
+			},
+			ps2_arbiter: Ps2Arbiter::new(),
+			uart_rx,
+			#[cfg(feature = "uart2")]
+			uart2,
+			#[cfg(feature = "current-sense")]
+			current_sense,
+			#[cfg(feature = "fan")]
+			fan,
+			#[cfg(feature = "i2c-target")]
+			i2c_target,
+			flash,
+		};
+		let local_resources = Local {
+			_serial: serial,
+			button_power: board.button_power,
+			button_reset: board.button_reset,
+			exti: dp.EXTI,
+			rtc: Rtc::new(dp.RTC),
+			encoder_input: EncoderInput {
+				#[cfg(feature = "encoder")]
+				pins: board.encoder,
+				#[cfg(feature = "encoder")]
+				decoder: Quadrature::new(),
+			},
+			#[cfg(feature = "ps2-power")]
+			ps2_power_pins: board.ps2_power,
+			#[cfg(feature = "status-led")]
+			status_led_driver,
+			board_test: BoardTest,
+			#[cfg(all(feature = "adc", not(any(feature = "current-sense", feature = "fan"))))]
+			analog_sampler,
+			#[cfg(not(any(
+				feature = "fan",
+				feature = "encoder",
+				feature = "i2c-target",
+				feature = "ps2-power",
+				feature = "status-led"
+			)))]
+			expansion_pins: ExpansionPins::new(board.gpio, dp.TIM16),
+			pin_cs: board.spi_cs,
+			#[cfg(all(feature = "speaker", not(feature = "current-sense")))]
+			buzzer,
+			buttons: Buttons::new(),
+			#[cfg(feature = "ps2-mouse")]
+			ps2_q_in_mouse: ps2_q_in.clone(),
+			#[cfg(feature = "ps2-keyboard")]
+			ps2_q_in_inject: ps2_q_in.clone(),
+			ps2_q_in,
+			ps2_q_out,
+			uart_q_in_dma: uart_q_in.clone(),
+			#[cfg(feature = "usb")]
+			uart_q_in_usb: uart_q_in.clone(),
+			#[cfg(feature = "usb")]
+			usb_console,
+			uart_q_in,
+			uart_q_out,
+			power: PowerControl {
+				led_power,
+				pin_dc_on: board.dc_on,
+				pin_sys_reset: board.sys_reset,
+				standby,
+				stopwatch,
+			},
+			power_q_in_buttons: power_q_in.clone(),
+			power_q_in_keyboard: power_q_in.clone(),
+			power_q_in_host: power_q_in.clone(),
+			power_q_in_activity: power_q_in.clone(),
+			#[cfg(all(feature = "adc", not(feature = "current-sense")))]
+			power_q_in_thermal: power_q_in.clone(),
+			power_q_in_watchdog: power_q_in.clone(),
+			#[cfg(feature = "current-sense")]
+			power_q_in_current: power_q_in.clone(),
+			#[cfg(feature = "stm32f031")]
+			power_q_in_pvd: power_q_in.clone(),
+			#[cfg(any(feature = "stm32f042", feature = "stm32f072"))]
+			power_q_in_pvd_vddio2: power_q_in.clone(),
+			power_q_in,
+			power_q_out,
+			stats_q_in,
+			stats_q_out,
+			#[cfg(all(feature = "adc", not(any(feature = "current-sense", feature = "fan"))))]
+			rise_q_in,
+			#[cfg(all(feature = "adc", not(any(feature = "current-sense", feature = "fan"))))]
+			rise_q_out,
+		};
+		(shared_resources, local_resources)
+	}
+
+	/// Our idle task.
+	///
+	/// Everything happens in interrupts and async tasks, so this task just
+	/// sleeps until the next interrupt. We only get here once every task has
+	/// started, so this is when we tell the host we are ready.
+	#[idle(shared = [register_state])]
+	fn idle(mut ctx: idle::Context) -> ! {
+		info!("Idle is running...");
+		ctx.shared.register_state.lock(|r| {
+			r.readout_levels = readout_levels();
+			r.ready = true;
+		});
+		loop {
+			cortex_m::asm::wfi();
+		}
+	}
+
+	/// This is the external GPIO interrupt task.
+	///
+	/// It handles PS/2 keyboard clock edges, SPI chip select edges, and (with
+	/// the right features) fan tachometer pulses or rotary encoder edges.
+	///
+	/// It is very high priority, as we can't afford to miss a PS/2 clock edge.
+	/// Edges on the keyboard clock either clock out a byte we are sending to
+	/// the keyboard, or clock in a bit from it; those whilst we are holding
+	/// the clock low are ignored. The other edges share this interrupt, so
+	/// they only note what happened and leave the work to lower priorities -
+	/// in particular, nothing here locks a resource shared with the SPI
+	/// interrupt.
+	#[task(
+		binds = EXTI4_15,
+		priority = 4,
+		shared = [ps2_arbiter, kb_port],
+		local = [exti, pin_cs, ps2_q_in, encoder_input]
+	)]
+	fn exti4_15_interrupt(mut ctx: exti4_15_interrupt::Context) {
+		let pr = ctx.local.exti.pr.read();
+		// Is this EXT15 (PS/2 Port 0 clock input)
+		#[cfg(feature = "ps2-keyboard")]
+		if pr.pr15().bit_is_set() {
+			let now_us = Stopwatch::now_us();
+			let word =
+				(&mut ctx.shared.kb_port, &mut ctx.shared.ps2_arbiter).lock(|port, arbiter| {
+					if ISR_STATUS.take_ps2_timing_clear(Ps2Port::Keyboard) {
+						port.bit_timer().clear();
+					}
+					let word = port.clock_edge(now_us);
+					arbiter.set_receiving(Ps2Port::Keyboard, port.is_receiving());
+					if word.is_some() {
+						ISR_STATUS.ps2_bit_timing(Ps2Port::Keyboard, &port.bit_timer().timing());
+					}
+					word
+				});
+			// Do we have a complete word?
+			if let Some(data) = word {
+				// Don't dump in the ISR - we're busy. Send it to the PS/2 task instead.
+				if ctx
+					.local
+					.ps2_q_in
+					.try_send(Ps2Data::Port0(data, stamp()))
+					.is_err()
+				{
+					ISR_STATUS.ps2_overflow();
+				}
+			}
+			// Clear the pending flag for this pin
+			ctx.local.exti.pr.write(|w| w.pr15().set_bit());
+		}
+
+		// Is this EXTI7 (the fan tachometer)?
+		#[cfg(feature = "fan")]
+		if pr.pr7().bit_is_set() {
+			fan::count_tach_pulse();
+			// Clear the pending flag for this pin
+			ctx.local.exti.pr.write(|w| w.pr7().set_bit());
+		}
+
+		// Is this EXTI6 or EXTI7 (the rotary encoder)?
+		#[cfg(feature = "encoder")]
+		if pr.pr6().bit_is_set() || pr.pr7().bit_is_set() {
+			// Clear the pending flags first, so we don't miss the next edge
+			ctx.local
+				.exti
+				.pr
+				.write(|w| w.pr6().set_bit().pr7().set_bit());
+			ctx.local.encoder_input.edge();
+		}
+
+		if pr.pr4().bit_is_set() {
+			// Clear the pending flag for this pin first, so an edge whilst
+			// we watch the pin brings us back here
+			ctx.local.exti.pr.write(|w| w.pr4().set_bit());
+			let width_us = u16::from(CS_FILTER_US.load(Ordering::Relaxed));
+			let pin_cs = &ctx.local.pin_cs;
+			let (level, changed) = glitch::watch(
+				width_us,
+				|| u8::from(pin_cs.is_low().unwrap()),
+				Stopwatch::now_us,
+			);
+			let asserted = level != 0;
+			let glitch =
+				width_us != 0 && (changed != 0 || asserted == CS_ASSERTED.load(Ordering::Relaxed));
+			if glitch {
+				// A pulse too short to believe, or one which was over before
+				// we could look
+				ISR_STATUS.cs_glitch();
+			} else {
+				if asserted {
+					let now = stamp();
+					CS_FALL_MS.store(now.ms(), Ordering::Relaxed);
+					CS_FALL_US.store(now.us(), Ordering::Relaxed);
+					CS_FALL_PENDING.store(true, Ordering::Relaxed);
+				}
+				// Only we write these, so load-then-store is fine
+				CS_ASSERTED.store(asserted, Ordering::Relaxed);
+				CS_EDGES.store(
+					CS_EDGES.load(Ordering::Relaxed).wrapping_add(1),
+					Ordering::Relaxed,
+				);
+				// The SPI interrupt turns the SPI engine on or off
+				rtic::pend(pac::Interrupt::SPI1);
+			}
+		}
+	}
+
+	/// This is the external GPIO interrupt task for the PS/2 mouse clock.
+	///
+	/// It is as high priority as the keyboard clock, for the same reason. The
+	/// mouse generates the clock whichever way the bits are going, so this
+	/// also clocks out any command `ps2_tx` has started sending.
+	#[cfg(feature = "ps2-mouse")]
+	#[task(
+		binds = EXTI2_3,
+		priority = 4,
+		shared = [mouse_port, ps2_arbiter],
+		local = [ps2_q_in_mouse]
+	)]
+	fn exti2_3_interrupt(mut ctx: exti2_3_interrupt::Context) {
+		// Safety: the pending register is write-1-to-clear, so we can't
+		// disturb the bits belonging to `exti4_15_interrupt`.
+		let exti = unsafe { &*pac::EXTI::ptr() };
+		// Is this EXTI3 (PS/2 Port 1 clock input)
+		if exti.pr.read().pr3().bit_is_set() {
+			let now_us = Stopwatch::now_us();
+			let word =
+				(&mut ctx.shared.mouse_port, &mut ctx.shared.ps2_arbiter).lock(|port, arbiter| {
+					if ISR_STATUS.take_ps2_timing_clear(Ps2Port::Mouse) {
+						port.bit_timer().clear();
+					}
+					let word = port.clock_edge(now_us);
+					arbiter.set_receiving(Ps2Port::Mouse, port.is_receiving());
+					if word.is_some() {
+						ISR_STATUS.ps2_bit_timing(Ps2Port::Mouse, &port.bit_timer().timing());
+					}
+					word
+				});
+			// Do we have a complete word?
+			if let Some(data) = word {
+				if ctx
+					.local
+					.ps2_q_in_mouse
+					.try_send(Ps2Data::Port1(data, stamp()))
+					.is_err()
+				{
+					ISR_STATUS.ps2_overflow();
+				}
+			}
+			// Clear the pending flag for this pin
+			exti.pr.write(|w| w.pr3().set_bit());
+		}
+	}
+
+	/// This is the USART1 task.
+	///
+	/// Received bytes are copied into memory by DMA, so this only fires when
+	/// the line goes idle at the end of a burst, or when a break arrives. We
+	/// copy the new bytes into the host's FIFO, and tell `uart_task` how many
+	/// there were - or, whilst the command channel is open, answer the request
+	/// which arrived. It also fires whenever the transmitter wants another
+	/// byte, during a self-test or whilst sending a response.
+	#[task(
+		binds = USART1,
+		priority = 2,
+		shared = [uart_rx, register_state, event_log, host_irq],
+		local = [
+			uart_q_in,
+			read_buffer: [u8; registers::READ_BUFFER_LEN] = [0u8; registers::READ_BUFFER_LEN]
+		]
+	)]
+	fn usart1_interrupt(ctx: usart1_interrupt::Context) {
+		// Space for rendering register contents that aren't stored as bytes
+		let read_buffer = ctx.local.read_buffer;
+		let mut uart_rx = ctx.shared.uart_rx;
+		let mut register_state = ctx.shared.register_state;
+		let mut event_log = ctx.shared.event_log;
+		let mut host_irq = ctx.shared.host_irq;
+		// Safety: the interrupt clear register is write-1-to-clear, so we
+		// only touch the idle line and receive error flags.
+		let usart1 = unsafe { &*pac::USART1::ptr() };
+		let is_break = usart1.isr.read().fe().bit_is_set();
+		usart1.icr.write(|w| {
+			w.idlecf().set_bit();
+			w.fecf().set_bit();
+			w.ncf().set_bit();
+			w.orecf().set_bit();
+			w
+		});
+		let (count, typed, request) =
+			(&mut uart_rx, &mut register_state).lock(|uart_rx, register_state| {
+				uart_rx.service_tx(register_state);
+				let count = if is_break {
+					uart_rx.toggle_link(register_state)
+				} else {
+					uart_rx.drain(register_state)
+				};
+				(
+					count,
+					!register_state.kb_inject.is_empty(),
+					uart_rx.link.take_request(),
+				)
+			});
+		if count > 0 {
+			// If this fails, `uart_task` is behind, and the bytes are in the
+			// FIFO anyway
+			let _ = ctx.local.uart_q_in.try_send(count);
+		}
+		#[cfg(feature = "ps2-keyboard")]
+		if typed {
+			// If this fails, the last lot are still being passed on, and
+			// these go too
+			let _ = keyboard_inject::spawn();
+		}
+		// Nothing can be injected without a keyboard
+		#[cfg(not(feature = "ps2-keyboard"))]
+		let _ = typed;
+		if let Some(request) = request {
+			(
+				&mut uart_rx,
+				&mut register_state,
+				&mut event_log,
+				&mut host_irq,
+			)
+				.lock(
+					|uart_rx, register_state, event_log, host_irq| match request {
+						Ok((req, payload)) => {
+							let wants_payload = handle_host_request(
+								&req,
+								payload.as_ref().map(|payload| payload.as_bytes()),
+								register_state,
+								event_log,
+								host_irq,
+								read_buffer,
+								|rsp| uart_rx.send_response(rsp),
+							);
+							if wants_payload {
+								uart_rx.link.expect_payload(&req);
+							}
+						}
+						Err(e) => {
+							let result = handle_bad_request(e, register_state, event_log);
+							uart_rx.send_response(&proto::Response::new_without_data(result));
+						}
+					},
+				);
+		}
+	}
+
+	/// Starts sending the host's output on the UART.
+	///
+	/// The host writes to the FIFO from the SPI interrupt, which doesn't own
+	/// the UART, so we have to do this from here.
+	#[task(shared = [uart_rx])]
+	async fn uart_transmit(mut ctx: uart_transmit::Context) {
+		ctx.shared.uart_rx.lock(|uart_rx| uart_rx.start_tx());
+		// Or on the USB console, if a terminal has it open
+		#[cfg(feature = "usb")]
+		rtic::pend(pac::Interrupt::USB);
+	}
+
+	/// This is the USB task.
+	///
+	/// It fires whenever the USB host wants something, and whenever the host
+	/// has new output for the console (see `uart_transmit`). Once the USB
+	/// host is answered, it is the same as `usart1_interrupt` - we pass on
+	/// whatever was typed on the console, and answer any request which
+	/// arrived on the register interface - and it sends the host's output
+	/// whilst a terminal has the console open.
+	#[cfg(feature = "usb")]
+	#[task(
+		binds = USB,
+		priority = 2,
+		shared = [register_state, event_log, host_irq],
+		local = [
+			usb_console,
+			uart_q_in_usb,
+			read_buffer: [u8; registers::READ_BUFFER_LEN] = [0u8; registers::READ_BUFFER_LEN]
+		]
+	)]
+	fn usb_interrupt(ctx: usb_interrupt::Context) {
+		// Space for rendering register contents that aren't stored as bytes
+		let read_buffer = ctx.local.read_buffer;
+		let usb_console = ctx.local.usb_console;
+		let mut register_state = ctx.shared.register_state;
+		let mut event_log = ctx.shared.event_log;
+		let mut host_irq = ctx.shared.host_irq;
+		let open = usb_console.poll();
+		// A Cortex-M0 has no atomic swap, but only this interrupt writes it
+		let was_open = USB_CONSOLE.load(Ordering::Relaxed);
+		USB_CONSOLE.store(open, Ordering::Relaxed);
+		if was_open && !open {
+			// The terminal has gone, so the host's output goes back to the
+			// UART. If this fails, it is already pending.
+			let _ = uart_transmit::spawn();
+		}
+		let (count, typed) = register_state.lock(|register_state| {
+			let count = usb_console.drain(register_state);
+			if open {
+				usb_console.service_tx(register_state);
+			}
+			(count, !register_state.kb_inject.is_empty())
+		});
+		if count > 0 {
+			let _ = ctx.local.uart_q_in_usb.try_send(count);
+		}
+		#[cfg(feature = "ps2-keyboard")]
+		if typed {
+			let _ = keyboard_inject::spawn();
+		}
+		#[cfg(not(feature = "ps2-keyboard"))]
+		let _ = typed;
+		if let Some(request) = usb_console.registers.take_request() {
+			(&mut register_state, &mut event_log, &mut host_irq).lock(
+				|register_state, event_log, host_irq| match request {
+					Ok((req, payload)) => {
+						let wants_payload = handle_host_request(
+							&req,
+							payload.as_ref().map(|payload| payload.as_bytes()),
+							register_state,
+							event_log,
+							host_irq,
+							read_buffer,
+							|rsp| usb_console.send_response(rsp),
+						);
+						if wants_payload {
+							usb_console.registers.expect_payload(&req);
+						}
+					}
+					Err(e) => {
+						let result = handle_bad_request(e, register_state, event_log);
+						usb_console.send_response(&proto::Response::new_without_data(result));
+					}
+				},
+			);
+		}
+	}
+
+	/// This is the DMA channel 2 and 3 task.
+	///
+	/// Channel 3 fires when the UART receive buffer is half full, and when it
+	/// wraps around, so we can empty it before the DMA controller comes round
+	/// again. Otherwise, this is the same as `usart1_interrupt`.
+	#[task(
+		binds = DMA1_CH2_3,
+		priority = 2,
+		shared = [uart_rx, register_state],
+		local = [uart_q_in_dma]
+	)]
+	fn dma1_ch2_3_interrupt(mut ctx: dma1_ch2_3_interrupt::Context) {
+		let (count, typed) = (&mut ctx.shared.uart_rx, &mut ctx.shared.register_state).lock(
+			|uart_rx, register_state| {
+				uart_rx.clear_dma_interrupt();
+				let count = uart_rx.drain(register_state);
+				(count, !register_state.kb_inject.is_empty())
+			},
+		);
+		if count > 0 {
+			let _ = ctx.local.uart_q_in_dma.try_send(count);
+		}
+		#[cfg(feature = "ps2-keyboard")]
+		if typed {
+			let _ = keyboard_inject::spawn();
+		}
+		#[cfg(not(feature = "ps2-keyboard"))]
+		let _ = typed;
+	}
+
+	/// This is the USART2 task.
+	///
+	/// It fires for every byte received on, or sent from, the second serial
+	/// port.
+	#[cfg(feature = "uart2")]
+	#[task(
+		binds = USART2,
+		priority = 2,
+		shared = [uart2, register_state, host_irq]
+	)]
+	fn usart2_interrupt(mut ctx: usart2_interrupt::Context) {
+		let events = (&mut ctx.shared.uart2, &mut ctx.shared.register_state)
+			.lock(|uart2, register_state| uart2.service(register_state));
+		if events != 0 {
+			ctx.shared.host_irq.lock(|host_irq| {
+				host_irq.controller.raise(events);
+				host_irq.update();
+			});
+		}
+	}
+
+	/// This is the SPI1 task.
+	///
+	/// It fires whenever there is new data received on SPI1, and whenever
+	/// `exti4_15_interrupt` sees chip select move. Once we have a complete
+	/// request, we work out the response straight away and load it into the
+	/// SPI peripheral, so the host can clock it out before it releases chip
+	/// select.
+	///
+	/// It runs below the PS/2 clock interrupts, so however fast the host
+	/// clocks, it can't make us miss a PS/2 clock edge (although a PS/2 edge
+	/// may make us miss an SPI byte, which the CRC will catch).
+	#[task(
+		binds = SPI1,
+		priority = 3,
+		shared = [spi, register_state, event_log, host_irq],
+		local = [
+			read_buffer: [u8; registers::READ_BUFFER_LEN] = [0u8; registers::READ_BUFFER_LEN],
+			cs_edges: u8 = 0,
+			payload_for: Option<proto::Request> = None,
+		]
+	)]
+	fn spi1_interrupt(ctx: spi1_interrupt::Context) {
+		// Space for rendering register contents that aren't stored as bytes
+		let read_buffer = ctx.local.read_buffer;
+		let cs_edges = ctx.local.cs_edges;
+		// The request whose payload we are receiving, if there is one
+		let payload_for = ctx.local.payload_for;
+		let mut register_state = ctx.shared.register_state;
+		let mut event_log = ctx.shared.event_log;
+		let mut host_irq = ctx.shared.host_irq;
+		let mut spi = ctx.shared.spi;
+		spi.lock(|spi| {
+			use proto::Receivable;
+			let edges = CS_EDGES.load(Ordering::Relaxed);
+			if edges != *cs_edges {
+				*cs_edges = edges;
+				// Chip Select has risen since we last looked, so whatever
+				// the last frame held, that's all of it
+				if let Some(frame) = spi.end_frame() {
+					// The host gave up before we answered, so only the log
+					// and the BMC Health register hear about it. Even a
+					// complete Request has missed its chance.
+					let e = match proto::Request::from_bytes(frame) {
+						// Including a payload cut short
+						_ if payload_for.is_some() => proto::Error::BadLength,
+						Err(e) => e,
+						Ok(_) => proto::Error::BadLength,
+					};
+					#[cfg(feature = "spi-trace")]
+					let trace = TraceEntry::new(now_ms(), frame);
+					(&mut register_state, &mut event_log).lock(|register_state, event_log| {
+						#[cfg(feature = "spi-trace")]
+						register_state.spi_trace.push(trace);
+						handle_bad_request(e, register_state, event_log)
+					});
+				}
+				// A payload only follows its request in the same transaction
+				*payload_for = None;
+				if CS_ASSERTED.load(Ordering::Relaxed) {
+					// Chip Select is low (perhaps again), so start afresh,
+					// with whatever framing the host last asked for
+					let (padding, sync) = register_state.lock(|r| (r.spi_padding, r.spi_sync));
+					spi.set_framing(padding, sync);
+					spi.enable();
+				}
+			}
+			spi.handle_isr();
+			if let Some(req) = payload_for.take() {
+				// Until the payload (and its CRC) have all arrived, that's
+				// all we are waiting for
+				let len = req.payload_len().unwrap_or(0);
+				let payload = spi
+					.get_received()
+					.filter(|received| received.len() >= len)
+					.and_then(|received| registers::PayloadBuffer::new(&received[0..len]));
+				match payload {
+					None => *payload_for = Some(req),
+					Some(payload) => {
+						spi.mark_done();
+						(&mut register_state, &mut event_log, &mut host_irq).lock(
+							|register_state, event_log, host_irq| {
+								handle_host_request(
+									&req,
+									Some(payload.as_bytes()),
+									register_state,
+									event_log,
+									host_irq,
+									read_buffer,
+									|rsp| spi.set_transmit_sendable(rsp).unwrap(),
+								);
+							},
+						);
+					}
+				}
+				return;
+			}
+			// Look for something in the SPI bytes received buffer:
+			match spi.get_received().map(proto::Request::from_bytes) {
+				None | Some(Err(proto::Error::BadLength)) => {
+					// Need more data
+				}
+				Some(Ok(req)) => {
+					spi.mark_done();
+					// Reading the trace back isn't worth tracing
+					#[cfg(feature = "spi-trace")]
+					let mut trace = spitrace::is_traced(&req).then(|| TraceEntry::new(now_ms(), &req.as_bytes()));
+					let wants_payload = (&mut register_state, &mut event_log, &mut host_irq).lock(
+						|register_state, event_log, host_irq| {
+							let wants_payload = handle_host_request(
+								&req,
+								None,
+								register_state,
+								event_log,
+								host_irq,
+								read_buffer,
+								|rsp| {
+									#[cfg(feature = "spi-trace")]
+									if let Some(entry) = trace.as_mut() {
+										entry.set_response(rsp);
+									}
+									spi.set_transmit_sendable(rsp).unwrap()
+								},
+							);
+							#[cfg(feature = "spi-trace")]
+							if let Some(entry) = trace {
+								register_state.spi_trace.push(entry);
+							}
+							record_spi_latency(register_state);
+							wants_payload
+						},
+					);
+					if wants_payload {
+						spi.expect_payload();
+						*payload_for = Some(req);
+					}
+				}
+				Some(Err(e)) => {
+					#[cfg(feature = "spi-trace")]
+					let mut trace = TraceEntry::new(now_ms(), spi.get_received().unwrap_or(&[]));
+					spi.mark_done();
+					let result =
+						(&mut register_state, &mut event_log).lock(|register_state, event_log| {
+							handle_bad_request(e, register_state, event_log)
+						});
+					let rsp = proto::Response::new_without_data(result);
+					spi.set_transmit_sendable(&rsp).unwrap();
+					#[cfg(feature = "spi-trace")]
+					{
+						trace.set_response(&rsp);
+						register_state.lock(|r| r.spi_trace.push(trace));
+					}
+					register_state.lock(record_spi_latency);
+				}
+			}
+		});
+	}
+
+	/// This is the I²C1 task.
+	///
+	/// It fires on every I²C event addressed to us. Once we have a complete
+	/// request, we work out the response straight away (as the SPI1 task
+	/// does), so it is ready by the time the host reads it back.
+	#[cfg(feature = "i2c-target")]
+	#[task(
+		binds = I2C1,
+		priority = 3,
+		shared = [i2c_target, register_state, event_log, host_irq],
+		local = [
+			read_buffer: [u8; registers::READ_BUFFER_LEN] = [0u8; registers::READ_BUFFER_LEN],
+			payload_for: Option<proto::Request> = None,
+		]
+	)]
+	fn i2c1_interrupt(ctx: i2c1_interrupt::Context) {
+		// Space for rendering register contents that aren't stored as bytes
+		let read_buffer = ctx.local.read_buffer;
+		// The request whose payload is the next write, if there is one
+		let payload_for = ctx.local.payload_for;
+		let mut register_state = ctx.shared.register_state;
+		let mut event_log = ctx.shared.event_log;
+		let mut host_irq = ctx.shared.host_irq;
+		let mut i2c_target = ctx.shared.i2c_target;
+		i2c_target.lock(|i2c| {
+			i2c.handle_isr();
+			if let Some(req) = payload_for.take() {
+				// As over SPI, but the payload is a write of its own
+				let len = req.payload_len().unwrap_or(0);
+				let payload = i2c
+					.get_received()
+					.filter(|received| received.len() >= len)
+					.and_then(|received| registers::PayloadBuffer::new(&received[0..len]));
+				match payload {
+					None => *payload_for = Some(req),
+					Some(payload) => {
+						i2c.mark_done();
+						(&mut register_state, &mut event_log, &mut host_irq).lock(
+							|register_state, event_log, host_irq| {
+								handle_host_request(
+									&req,
+									Some(payload.as_bytes()),
+									register_state,
+									event_log,
+									host_irq,
+									read_buffer,
+									|rsp| i2c.set_transmit_sendable(rsp).unwrap(),
+								);
+							},
+						);
+					}
+				}
+				return;
+			}
+			// Look for something in the I²C bytes received buffer:
+			use proto::Receivable;
+			match i2c.get_received().map(proto::Request::from_bytes) {
+				None | Some(Err(proto::Error::BadLength)) => {
+					// Need more data
+				}
+				Some(Ok(req)) => {
+					i2c.mark_done();
+					let wants_payload = (&mut register_state, &mut event_log, &mut host_irq).lock(
+						|register_state, event_log, host_irq| {
+							handle_host_request(
+								&req,
+								None,
+								register_state,
+								event_log,
+								host_irq,
+								read_buffer,
+								|rsp| i2c.set_transmit_sendable(rsp).unwrap(),
+							)
+						},
+					);
+					if wants_payload {
+						*payload_for = Some(req);
+					}
+				}
+				Some(Err(e)) => {
+					i2c.mark_done();
+					let result =
+						(&mut register_state, &mut event_log).lock(|register_state, event_log| {
+							handle_bad_request(e, register_state, event_log)
+						});
+					i2c.set_transmit_sendable(&proto::Response::new_without_data(result))
+						.unwrap();
+				}
+			}
+		});
+	}
+
+	/// Handles words captured from the PS/2 ports, and scancodes the host
+	/// injected as if they came from the keyboard.
+	#[task(shared = [register_state, host_irq], local = [ps2_q_out, power_q_in_keyboard])]
+	async fn ps2_task(mut ctx: ps2_task::Context) {
+		while let Ok(msg) = ctx.local.ps2_q_out.recv().await {
+			match msg {
+				#[cfg(feature = "ps2-keyboard")]
+				Ps2Data::Port0(word, received) => match Ps2Decoder::check_word(word) {
+					Ok(byte) => {
+						trace!(target: Subsystem::Ps2, "< KB 0x{:x}", byte);
+						keyboard_byte(&mut ctx, byte, Some(received)).await;
+					}
+					Err(e) => {
+						warn!(target: Subsystem::Ps2, "< Bad KB 0x{:x} ({})", word, e);
+						ctx.shared.register_state.lock(|r| {
+							r.health.report(health::PS2_FRAMING_ERROR);
+							r.ps2_errors.bad_word(Ps2Port::Keyboard, e);
+						});
+					}
+				},
+				#[cfg(feature = "ps2-keyboard")]
+				Ps2Data::Injected(byte) => {
+					trace!(target: Subsystem::Ps2, "< KB 0x{:x} (injected)", byte);
+					keyboard_byte(&mut ctx, byte, None).await;
+				}
+				#[cfg(feature = "ps2-mouse")]
+				Ps2Data::Port1(word, received) => match Ps2Decoder::check_word(word) {
+					Ok(byte) => {
+						trace!(target: Subsystem::Ps2, "< MS 0x{:x}", byte);
+						let report = ctx.shared.register_state.lock(|r| {
+							r.ps2_errors.good_word(Ps2Port::Mouse);
+							let report = r.mouse.update(byte);
+							if report.is_some() {
+								r.ps2_latency.record(stamp().micros_since(received));
+							}
+							report
+						});
+						if report.is_some() {
+							ctx.shared.host_irq.lock(|host_irq| {
+								host_irq.controller.raise(irq::MOUSE_RX_NOT_EMPTY);
+								host_irq.update();
+							});
+						}
+					}
+					Err(e) => {
+						warn!(target: Subsystem::Ps2, "< Bad MS 0x{:x} ({})", word, e);
+						ctx.shared.register_state.lock(|r| {
+							r.health.report(health::PS2_FRAMING_ERROR);
+							r.ps2_errors.bad_word(Ps2Port::Mouse, e);
+						});
+					}
+				},
+			}
+		}
+	}
+
+	/// Handles a byte from the keyboard (or one the host injected, when
+	/// `received` is `None`) for `ps2_task`.
+	#[cfg(feature = "ps2-keyboard")]
+	async fn keyboard_byte(ctx: &mut ps2_task::Context<'_>, byte: u8, received: Option<Stamp>) {
+		let keys = ctx.shared.register_state.lock(|r| {
+			if received.is_some() {
+				r.ps2_errors.good_word(Ps2Port::Keyboard);
+			}
+			// Answers to our reset and read ID commands aren't keys (and
+			// injected scancodes are never answers)
+			if received.is_some() && r.keyboard_id.update(byte, now_ms()) {
+				return None;
+			}
+			let pressed = r.key_state.update(byte);
+			let mut typed = None;
+			if let Some(bit) = pressed {
+				r.key_repeat.pressed(bit, now_ms());
+				// Caps Lock is followed even outside ASCII mode
+				typed = r.ascii_keys.pressed(bit, &r.key_state);
+			}
+			let ascii = (r.kb_control & KEYBOARD_CONTROL_ASCII) != 0;
+			// In make-only mode, the host only hears about new key presses
+			let deliver = if (r.kb_control & KEYBOARD_CONTROL_MAKE_ONLY) != 0 {
+				pressed
+			} else {
+				Some(byte)
+			};
+			if let Some(byte) = deliver {
+				// A host using ASCII mode may never empty the keyboard FIFO,
+				// so it filling up isn't a fault
+				if !r.kb_rx.push(byte) && !ascii {
+					r.health.report(health::PS2_QUEUE_OVERFLOW);
+				}
+			}
+			let typed = typed.filter(|_| ascii);
+			if let Some(ch) = typed {
+				if !r.kb_ascii.push(ch) {
+					r.health.report(health::PS2_QUEUE_OVERFLOW);
+				}
+			}
+			if let Some(received) = received {
+				r.ps2_latency.record(stamp().micros_since(received));
+			}
+			let boot_key = r.boot_keys.check(&r.key_state, r.config.boot_key, now_ms());
+			let reset_chord = r.reset_chord.check(&r.key_state, &r.config.reset_chord);
+			let delivered = deliver.is_some() || typed.is_some();
+			Some((r.wake_control, delivered, boot_key, reset_chord))
+		});
+		let (wake_control, delivered, boot_key, reset_chord) = match keys {
+			Some(keys) => keys,
+			None => return,
+		};
+		// Keyboard input is never coalesced
+		ctx.shared.host_irq.lock(|host_irq| {
+			if delivered {
+				host_irq.controller.raise(irq::KEYBOARD_RX_NOT_EMPTY);
+			}
+			if boot_key {
+				host_irq.controller.raise(irq::BOOT_KEY);
+			}
+			host_irq.update();
+		});
+		if (wake_control & WAKE_ON_KEYBOARD) != 0 {
+			// Fails if a wake-up is already pending, which is fine
+			let _ = wake_up::spawn(PowerOnSource::Keyboard);
+		}
+		if reset_chord && power_state() == DcPowerState::On {
+			info!(target: Subsystem::Ps2, "Keyboard reset chord pressed.");
+			let _ = ctx
+				.local
+				.power_q_in_keyboard
+				.send(PowerRequest::Reset(
+					ResetSource::Keyboard,
+					RESET_DURATION_MS,
+				))
+				.await;
+		}
+	}
+
+	/// Repeats the last key pressed, for as long as it is held, when the host
+	/// has turned on key repeat in make-only or ASCII mode. In make-only mode,
+	/// each repeat goes in the keyboard FIFO as `REPEAT_PREFIX` and then the
+	/// key's bit number. In ASCII mode, the character goes in the ASCII FIFO
+	/// again.
+	#[cfg(feature = "ps2-keyboard")]
+	#[task(shared = [register_state, host_irq])]
+	async fn key_repeat(mut ctx: key_repeat::Context) {
+		loop {
+			let delivered = ctx.shared.register_state.lock(|r| {
+				let bit = match r.key_repeat.poll(&r.key_state, now_ms()) {
+					Some(bit) if (r.kb_control & KEYBOARD_CONTROL_REPEAT) != 0 => bit,
+					_ => return false,
+				};
+				let mut delivered = false;
+				if (r.kb_control & KEYBOARD_CONTROL_MAKE_ONLY) != 0 {
+					if r.kb_rx.space() >= 2 {
+						r.kb_rx.push(REPEAT_PREFIX);
+						r.kb_rx.push(bit);
+						delivered = true;
+					} else if (r.kb_control & KEYBOARD_CONTROL_ASCII) == 0 {
+						r.health.report(health::PS2_QUEUE_OVERFLOW);
+					}
+				}
+				if (r.kb_control & KEYBOARD_CONTROL_ASCII) != 0 {
+					if let Some(ch) = r.ascii_keys.translate(bit, &r.key_state) {
+						if r.kb_ascii.push(ch) {
+							delivered = true;
+						} else {
+							r.health.report(health::PS2_QUEUE_OVERFLOW);
+						}
+					}
+				}
+				delivered
+			});
+			if delivered {
+				ctx.shared.host_irq.lock(|host_irq| {
+					host_irq.controller.raise(irq::KEYBOARD_RX_NOT_EMPTY);
+					host_irq.update();
+				});
+			}
+			Mono::delay(KEY_REPEAT_POLL_MS.millis()).await;
+		}
+	}
+
+	/// Passes the scancodes the host injected to `ps2_task`, which handles
+	/// them just like bytes from the keyboard.
+	///
+	/// If more arrive whilst we're still running, the spawn fails, but we
+	/// pick them up before we finish (or, if they arrive just as we finish,
+	/// with the next injection).
+	#[cfg(feature = "ps2-keyboard")]
+	#[task(shared = [register_state], local = [ps2_q_in_inject])]
+	async fn keyboard_inject(mut ctx: keyboard_inject::Context) {
+		while let Some(byte) = ctx.shared.register_state.lock(|r| r.kb_inject.pop()) {
+			let _ = ctx
+				.local
+				.ps2_q_in_inject
+				.send(Ps2Data::Injected(byte))
+				.await;
+		}
+	}
+
+	/// Sends queued commands to the PS/2 devices, one byte at a time.
+	///
+	/// The mouse gets the commands the host asked for, and the keyboard gets
+	/// the reset and read ID commands we send at start-up (or when the host
+	/// asks for another self-test). Either device gets a Resend command, in
+	/// place of its next command, after it sends us a bad word. Each byte waits its turn with the PS/2
+	/// arbiter, and the other port is held off whilst it is sent. The devices
+	/// answer through `ps2_task`. When a whole batch of mouse commands for the
+	/// host has finished, the result goes in the Deferred Operation register.
+	#[task(shared = [register_state, mouse_port, kb_port, ps2_arbiter, host_irq])]
+	async fn ps2_tx(mut ctx: ps2_tx::Context) {
+		// Give the keyboard time to finish its own power-on self-test
+		Mono::delay(KEYBOARD_POWER_UP_MS.millis()).await;
+		#[cfg(feature = "ps2-keyboard")]
+		ctx.shared.register_state.lock(|r| r.keyboard_id.start());
+		let mut waited_ms = 0;
+		loop {
+			let now = now_ms();
+			let (mouse_command, kb_command) = ctx.shared.register_state.lock(|r| {
+				r.keyboard_id.poll(now);
+				(
+					r.ps2_errors
+						.resend(Ps2Port::Mouse)
+						.or_else(|| r.mouse.next_command()),
+					r.ps2_errors
+						.resend(Ps2Port::Keyboard)
+						.or_else(|| r.keyboard_id.next_command(now)),
+				)
+			});
+			// We only ask each device for one byte at a time, so there is
+			// always space
+			let next = ctx.shared.ps2_arbiter.lock(|arbiter| {
+				if let Some(byte) = mouse_command {
+					let _ = arbiter.queue(Ps2Port::Mouse, byte);
+				}
+				if let Some(byte) = kb_command {
+					let _ = arbiter.queue(Ps2Port::Keyboard, byte);
+				}
+				arbiter.start()
+			});
+			if let Some((port, byte)) = next {
+				waited_ms = 0;
+				debug!(
+					"> {} 0x{:x}",
+					if port == Ps2Port::Mouse { "MS" } else { "KB" },
+					byte
+				);
+				(&mut ctx.shared.mouse_port, &mut ctx.shared.kb_port).lock(|mouse, kb| {
+					mouse.inhibit();
+					kb.inhibit();
+				});
+				// The device needs the clock held low for at least 100 µs
+				Mono::delay(1.millis()).await;
+				(&mut ctx.shared.mouse_port, &mut ctx.shared.kb_port).lock(
+					|mouse, kb| match port {
+						Ps2Port::Mouse => mouse.start_send(byte),
+						Ps2Port::Keyboard => kb.start_send(byte),
+					},
+				);
+				Mono::delay(PS2_TX_TIMEOUT_MS.millis()).await;
+				let acked = (
+					&mut ctx.shared.mouse_port,
+					&mut ctx.shared.kb_port,
+					&mut ctx.shared.ps2_arbiter,
+				)
+					.lock(|mouse, kb, arbiter| {
+						let acked = match port {
+							Ps2Port::Mouse => mouse.finish_send(),
+							Ps2Port::Keyboard => kb.finish_send(),
+						};
+						// Stop ignoring the other device before we let it talk again
+						arbiter.finish();
+						match port {
+							Ps2Port::Mouse => kb.release(),
+							Ps2Port::Keyboard => mouse.release(),
+						}
+						acked
+					});
+				match port {
+					Ps2Port::Mouse => {
+						ctx.shared
+							.register_state
+							.lock(|r| r.mouse.command_sent(acked));
+						if acked {
+							Mono::delay(MOUSE_ACK_TIMEOUT_MS.millis()).await;
+							ctx.shared
+								.register_state
+								.lock(|r| r.mouse.command_timeout());
+						}
+					}
+					Ps2Port::Keyboard => {
+						let now = now_ms();
+						ctx.shared
+							.register_state
+							.lock(|r| r.keyboard_id.command_sent(acked, now));
+					}
+				}
+			}
+			let finished = ctx
+				.shared
+				.register_state
+				.lock(|r| match r.mouse.take_result() {
+					Some(result) => r.deferred.finish(result),
+					None => false,
+				});
+			if finished {
+				ctx.shared.host_irq.lock(|host_irq| {
+					host_irq.controller.raise(irq::DEFERRED_DONE);
+					host_irq.update();
+				});
+			}
+			if next.is_some() {
+				continue;
+			}
+			let queued = ctx
+				.shared
+				.ps2_arbiter
+				.lock(|arbiter| arbiter.queued(Ps2Port::Mouse) + arbiter.queued(Ps2Port::Keyboard));
+			if queued == 0 {
+				waited_ms = 0;
+				Mono::delay(PS2_POLL_INTERVAL_MS.millis()).await;
+			} else {
+				// Wait until neither device is part way through a word
+				if waited_ms == PS2_RX_TIMEOUT_MS {
+					ctx.shared
+						.ps2_arbiter
+						.lock(|arbiter| arbiter.receive_timeout());
+				}
+				waited_ms += 1;
+				Mono::delay(1.millis()).await;
+			}
+		}
+	}
+
+	/// Handles bytes received on the UART.
+	///
+	/// The bytes are already in the host's FIFO - we just hear how many
+	/// arrived, so we can interrupt the host or wake the system.
+	#[task(shared = [register_state, host_irq], local = [uart_q_out])]
+	async fn uart_task(mut ctx: uart_task::Context) {
+		while let Ok(count) = ctx.local.uart_q_out.recv().await {
+			debug!("UART RX {} bytes", count);
+			ctx.shared.host_irq.lock(|host_irq| {
+				host_irq.controller.uart_rx_event(now_ms(), count);
+				host_irq.update();
+			});
+			let wake_control = ctx.shared.register_state.lock(|r| r.wake_control);
+			if (wake_control & WAKE_ON_UART) != 0 {
+				// Fails if a wake-up is already pending, which is fine
+				let _ = wake_up::spawn(PowerOnSource::Uart);
+			}
+		}
+	}
+
+	/// Aborts SPI transactions where the host has stopped clocking.
+	///
+	/// If the host crashes with CS held low, the SPI engine would otherwise
+	/// sit waiting for the rest of the request forever.
+	#[task(shared = [spi, register_state])]
+	async fn spi_watchdog(mut ctx: spi_watchdog::Context) {
+		loop {
+			if ctx.shared.spi.lock(|spi| spi.watchdog()) {
+				warn!(target: Subsystem::Spi, "SPI timeout");
+				ctx.shared
+					.register_state
+					.lock(|r| r.health.report(health::SPI_TIMEOUT));
+			}
+			// Slow hosts get longer to finish each transaction
+			let timeout_ms = ctx.shared.register_state.lock(|r| r.spi_watchdog_ms());
+			Mono::delay(timeout_ms.millis()).await;
+		}
+	}
+
+	/// Ticks the heartbeat, and picks up faults flagged by the interrupts,
+	/// even if the host isn't sending any requests.
+	#[task(shared = [register_state])]
+	async fn heartbeat(mut ctx: heartbeat::Context) {
+		loop {
+			ctx.shared.register_state.lock(|r| {
+				r.health.tick();
+				registers::collect_isr_status(r, &ISR_STATUS);
+			});
+			Mono::delay(HEARTBEAT_INTERVAL_MS.millis()).await;
+		}
+	}
+
+	/// Checks the register watches, raises coalesced interrupts which have
+	/// waited long enough, samples the level of each interrupt source, and
+	/// looks for changes in the registers the host polls.
+	#[task(shared = [register_state, event_log, host_irq])]
+	async fn irq_poll(mut ctx: irq_poll::Context) {
+		loop {
+			(
+				&mut ctx.shared.register_state,
+				&mut ctx.shared.event_log,
+				&mut ctx.shared.host_irq,
+			)
+				.lock(|register_state, event_log, host_irq| {
+					registers::sample_watches(register_state, event_log, &mut host_irq.controller);
+				});
+			let level = ctx
+				.shared
+				.register_state
+				.lock(|r| registers::interrupt_level(r));
+			ctx.shared.host_irq.lock(|host_irq| {
+				host_irq.controller.poll(now_ms());
+				host_irq.controller.set_level(level);
+				host_irq.update();
+			});
+			(
+				&mut ctx.shared.register_state,
+				&mut ctx.shared.event_log,
+				&mut ctx.shared.host_irq,
+			)
+				.lock(|register_state, event_log, host_irq| {
+					registers::sample_changes(register_state, event_log, &host_irq.controller);
+				});
+			Mono::delay(IRQ_POLL_INTERVAL_MS.millis()).await;
+		}
+	}
+
+	/// Writes the config to flash.
+	///
+	/// Erasing flash stalls the CPU, so we do this at the lowest priority. The
+	/// host was told `Busy`, so we finish the deferred operation and raise
+	/// the Deferred Done interrupt when we're done.
+	#[task(shared = [register_state, host_irq, flash])]
+	async fn config_save(mut ctx: config_save::Context) {
+		let config = ctx.shared.register_state.lock(|r| r.config.clone());
+		let status = match ctx.shared.flash.lock(|flash| config.save(flash)) {
+			Ok(()) => CONFIG_STATUS_SAVED,
+			Err(()) => {
+				warn!("Config save failed");
+				CONFIG_STATUS_SAVE_FAILED
+			}
+		};
+		(ctx.shared.register_state, ctx.shared.host_irq).lock(|r, host_irq| {
+			r.config_status = status;
+			r.deferred.finish(status);
+			host_irq.controller.raise(irq::DEFERRED_DONE);
+			host_irq.update();
+		});
+	}
+
+	/// Commits the host's NVRAM to flash.
+	///
+	/// Like saving the config, this can erase a page of flash, so we do it at
+	/// the lowest priority and report the result as a deferred operation.
+	#[task(shared = [register_state, host_irq, flash])]
+	async fn nvram_commit(mut ctx: nvram_commit::Context) {
+		let data = ctx.shared.register_state.lock(|r| *r.nvram.data());
+		let ok = ctx
+			.shared
+			.flash
+			.lock(|flash| commit_nvram(flash, &data))
+			.is_ok();
+		if !ok {
+			warn!("NVRAM commit failed");
+		}
+		(ctx.shared.register_state, ctx.shared.host_irq).lock(|r, host_irq| {
+			r.nvram.committed(&data, ok);
+			r.deferred.finish(if ok {
+				nvram::STATUS_COMMITTED
+			} else {
+				nvram::STATUS_COMMIT_FAILED
+			});
+			host_irq.controller.raise(irq::DEFERRED_DONE);
+			host_irq.update();
+		});
+	}
+
+	/// Programs the option bytes for readout protection level 1.
+	///
+	/// This erases the option bytes, which stalls the CPU, so like saving the
+	/// config we do it at the lowest priority and report the result as a
+	/// deferred operation. The new level takes effect when the NBMC next
+	/// powers up.
+	#[task(shared = [register_state, host_irq, flash])]
+	async fn readout_protect(mut ctx: readout_protect::Context) {
+		warn!("Enabling readout protection");
+		let result = match ctx.shared.flash.lock(enable_readout_protection) {
+			Ok(()) => readout::RESULT_PROGRAMMED,
+			Err(()) => {
+				warn!("Programming option bytes failed");
+				readout::RESULT_FAILED
+			}
+		};
+		(ctx.shared.register_state, ctx.shared.host_irq).lock(|r, host_irq| {
+			r.readout_levels = readout_levels();
+			r.deferred.finish(result);
+			host_irq.controller.raise(irq::DEFERRED_DONE);
+			host_irq.update();
+		});
+	}
+
+	/// Runs the production self-test, or with `selftest::COMMAND_PROTOCOL`,
+	/// just the protocol loopback.
+	///
+	/// Each check records a pass or fail in the report. The host was told
+	/// `Busy`, so we finish the deferred operation (with the checks which
+	/// failed as the result) and raise the Deferred Done interrupt when we're
+	/// done.
+	#[task(
+		shared = [register_state, event_log, host_irq],
+		local = [
+			board_test,
+			read_buffer: [u8; registers::READ_BUFFER_LEN] = [0u8; registers::READ_BUFFER_LEN]
+		]
+	)]
+	async fn board_self_test(mut ctx: board_self_test::Context, command: u8) {
+		let board_test = ctx.local.board_test;
+		let read_buffer = ctx.local.read_buffer;
+		let mut report = SelfTestReport::new();
+
+		if command == selftest::COMMAND_RUN {
+			// The host got this far, so the link works - but it shouldn't have
+			// needed any retries
+			let flags = ctx.shared.register_state.lock(|r| r.health.flags());
+			report.record(
+				selftest::SPI,
+				(flags & (health::SPI_TIMEOUT | health::PROTOCOL_ERROR)) == 0,
+			);
+
+			#[cfg(all(feature = "adc", not(any(feature = "current-sense", feature = "fan"))))]
+			{
+				ctx.shared
+					.register_state
+					.lock(|r| r.adc_schedule.request(INPUT_CHANNELS));
+				Mono::delay(SELF_TEST_ANALOG_WAIT_MS.millis()).await;
+				if let Some(vdda_mv) = ctx.shared.register_state.lock(|r| r.analog.vdda_mv()) {
+					info!("Self-test: VDDA is {} mV", vdda_mv);
+					report.record(selftest::ADC, selftest::vdda_ok(vdda_mv));
+				}
+			}
+
+			#[cfg(any(feature = "ps2-keyboard", feature = "ps2-mouse"))]
+			{
+				let mut ps2_lines = [PinSamples::new(); BoardTest::PS2_LINES.len()];
+				for _ in 0..SELF_TEST_PS2_SAMPLES {
+					board_test.sample_ps2_lines(&mut ps2_lines);
+					Mono::delay(SELF_TEST_PS2_INTERVAL_MS.millis()).await;
+				}
+				report.record(selftest::PS2, ps2_lines.iter().all(|line| line.seen_high()));
+			}
+
+			#[cfg(all(feature = "speaker", not(feature = "current-sense")))]
+			{
+				// If the queue is full, the buzzer is busy anyway
+				let _ = ctx
+					.shared
+					.register_state
+					.lock(|r| r.buzzer_queue.push(SELF_TEST_NOTE));
+				Mono::delay(SELF_TEST_BUZZER_WAIT_MS.millis()).await;
+				report.record(selftest::BUZZER, board_test.check_buzzer());
+			}
+
+			report.record(selftest::LED, board_test.check_led());
+			report.record(
+				selftest::FLASH,
+				selftest::config_page_ok(&Config::read_page()),
+			);
+		}
+
+		let results = (
+			&mut ctx.shared.register_state,
+			&mut ctx.shared.event_log,
+			&mut ctx.shared.host_irq,
+		)
+			.lock(|r, event_log, host_irq| {
+				loopback::run(r, event_log, &mut host_irq.controller, read_buffer)
+			});
+		log_loopback(&results);
+		report.record(selftest::PROTOCOL, results.passed());
+
+		info!(
+			"Self-test: tested {=u8:02x}, failed {=u8:02x}",
+			report.tested(),
+			report.failed()
+		);
+		(ctx.shared.register_state, ctx.shared.host_irq).lock(|r, host_irq| {
+			r.self_test_report = report;
+			r.deferred.finish(report.failed());
+			host_irq.controller.raise(irq::DEFERRED_DONE);
+			host_irq.update();
+		});
+	}
+
+	/// Runs the protocol loopback once we're ready, in `loopback-test` builds,
+	/// so a broken register dispatch shows up without a host attached. The
+	/// result goes in the BMC Self-Test register.
+	#[cfg(feature = "loopback-test")]
+	#[task(
+		shared = [register_state, event_log, host_irq],
+		local = [read_buffer: [u8; registers::READ_BUFFER_LEN] = [0u8; registers::READ_BUFFER_LEN]]
+	)]
+	async fn boot_loopback(mut ctx: boot_loopback::Context) {
+		// The idle task says when every task has started
+		while !ctx.shared.register_state.lock(|r| r.ready) {
+			Mono::delay(BOOT_LOOPBACK_POLL_MS.millis()).await;
+		}
+		let read_buffer = ctx.local.read_buffer;
+		let results = (
+			&mut ctx.shared.register_state,
+			&mut ctx.shared.event_log,
+			&mut ctx.shared.host_irq,
+		)
+			.lock(|r, event_log, host_irq| {
+				let results = loopback::run(r, event_log, &mut host_irq.controller, read_buffer);
+				r.self_test_report = SelfTestReport::new();
+				r.self_test_report
+					.record(selftest::PROTOCOL, results.passed());
+				results
+			});
+		log_loopback(&results);
+	}
+
+	/// Runs a UART self-test.
+	///
+	/// USART1 is switched to half-duplex, which connects its transmitter to
+	/// its receiver, and sends `length` bytes of test pattern. The host was
+	/// told `Busy`, so we finish the deferred operation and raise the Deferred
+	/// Done interrupt when we're done.
+	#[task(shared = [uart_rx, register_state, host_irq])]
+	async fn uart_self_test(mut ctx: uart_self_test::Context, length: u16) {
+		(&mut ctx.shared.uart_rx, &mut ctx.shared.register_state).lock(
+			|uart_rx, register_state| {
+				uart_rx.start_self_test(length, register_state);
+			},
+		);
+		// Ten bits per byte, plus some slack in case we're interrupted
+		let expected_ms = (u32::from(length) * 10 * 1000) / UART_BAUD;
+		let mut polls_left = expected_ms / UART_SELF_TEST_POLL_MS + 2;
+		while !ctx.shared.uart_rx.lock(|uart_rx| uart_rx.self_test_sent()) {
+			if polls_left == 0 {
+				warn!("UART self-test timed out");
+				break;
+			}
+			polls_left -= 1;
+			Mono::delay(UART_SELF_TEST_POLL_MS.millis()).await;
+		}
+		Mono::delay(UART_SELF_TEST_SETTLE_MS.millis()).await;
+		let results = (&mut ctx.shared.uart_rx, &mut ctx.shared.register_state)
+			.lock(|uart_rx, register_state| uart_rx.finish_self_test(register_state));
+		info!(
+			"UART self-test: sent {}, received {}, {} bit errors",
+			results.sent, results.received, results.bit_errors
+		);
+		(ctx.shared.register_state, ctx.shared.host_irq).lock(|r, host_irq| {
+			r.uart_self_test_results = results;
+			r.deferred.finish(if results.passed() {
+				SELF_TEST_PASSED
+			} else {
+				SELF_TEST_FAILED
+			});
+			host_irq.controller.raise(irq::DEFERRED_DONE);
+			host_irq.update();
+		});
+	}
+
+	/// Samples the main board current, and keeps the over-current limit up to
+	/// date.
+	#[cfg(feature = "current-sense")]
+	#[task(shared = [register_state, current_sense])]
+	async fn current_sense_poll(mut ctx: current_sense_poll::Context) {
+		loop {
+			(
+				&mut ctx.shared.register_state,
+				&mut ctx.shared.current_sense,
+			)
+				.lock(|register_state, current_sense| {
+					current_sense.set_limit(&register_state.config);
+					let current_ma = current_sense.sample(&register_state.config);
+					register_state.main_current_ma = current_ma;
+					register_state.peak_current_ma = register_state.peak_current_ma.max(current_ma);
+				});
+			Mono::delay(CURRENT_SENSE_INTERVAL_MS.millis()).await;
+		}
+	}
+
+	/// Reads the temperature and the fan speed, and sets the fan duty cycle.
+	#[cfg(feature = "fan")]
+	#[task(shared = [register_state, fan])]
+	async fn fan_poll(mut ctx: fan_poll::Context) {
+		loop {
+			let pulses = fan::take_tach_pulses();
+			(&mut ctx.shared.register_state, &mut ctx.shared.fan).lock(|register_state, fan| {
+				register_state.temperature_c = fan.read_temperature();
+				register_state.fan_rpm = fan::rpm_from_pulses(pulses, FAN_POLL_INTERVAL_MS);
+				if (register_state.fan_control & registers::FAN_AUTO) != 0 {
+					register_state.fan_duty =
+						fan::duty_for_temperature(register_state.temperature_c);
+				}
+				fan.set_duty(register_state.fan_duty);
+			});
+			Mono::delay(FAN_POLL_INTERVAL_MS.millis()).await;
+		}
+	}
+
+	/// Turns the steps counted by the EXTI interrupt into the rotary encoder
+	/// registers, and interrupts the host when the encoder moves.
+	#[cfg(feature = "encoder")]
+	#[task(shared = [register_state, host_irq])]
+	async fn encoder_poll(mut ctx: encoder_poll::Context) {
+		loop {
+			let total = ENCODER_TOTAL.load(Ordering::Relaxed);
+			let moved = ctx
+				.shared
+				.register_state
+				.lock(|r| r.encoder.update(total, ENCODER_POLL_INTERVAL_MS));
+			if moved {
+				ctx.shared.host_irq.lock(|host_irq| {
+					host_irq.controller.raise(irq::ENCODER_CHANGE);
+					host_irq.update();
+				});
+			}
+			Mono::delay(ENCODER_POLL_INTERVAL_MS.millis()).await;
+		}
+	}
+
+	/// Switches the 5V supply to each PS/2 port, as the host has asked, and
+	/// times any power cycles.
+	#[cfg(feature = "ps2-power")]
+	#[task(shared = [register_state], local = [ps2_power_pins])]
+	async fn ps2_power_poll(mut ctx: ps2_power_poll::Context) {
+		let (kb_power, ms_power) = ctx.local.ps2_power_pins;
+		loop {
+			let powered = ctx.shared.register_state.lock(|r| {
+				r.ps2_power.tick(PS2_POWER_INTERVAL_MS);
+				r.ps2_power.powered()
+			});
+			// Low turns the P-FET on
+			if (powered & PORT_KEYBOARD) != 0 {
+				kb_power.set_low().unwrap();
+			} else {
+				kb_power.set_high().unwrap();
+			}
+			if (powered & PORT_MOUSE) != 0 {
+				ms_power.set_low().unwrap();
+			} else {
+				ms_power.set_high().unwrap();
+			}
+			Mono::delay(u32::from(PS2_POWER_INTERVAL_MS).millis()).await;
+		}
+	}
+
+	/// Shows the host's colour and pattern on the RGB status LED, or the
+	/// fault which cut the power.
+	#[cfg(feature = "status-led")]
+	#[task(shared = [register_state], local = [status_led_driver])]
+	async fn status_led_poll(mut ctx: status_led_poll::Context) {
+		loop {
+			let now = now_ms();
+			let colour = ctx
+				.shared
+				.register_state
+				.lock(|r| r.status_led.current(now));
+			ctx.local
+				.status_led_driver
+				.show(colour, CLOCK_HZ.load(Ordering::Relaxed));
+			Mono::delay(STATUS_LED_INTERVAL_MS.millis()).await;
+		}
+	}
+
+	/// Applies changes to the UART2 settings, and starts sending when the
+	/// host has queued bytes.
+	///
+	/// This also catches the system clock changing as we enter and leave
+	/// standby, which would otherwise throw the baud rate out.
+	#[cfg(feature = "uart2")]
+	#[task(shared = [register_state, uart2])]
+	async fn uart2_poll(mut ctx: uart2_poll::Context) {
+		loop {
+			let clock_hz = CLOCK_HZ.load(Ordering::Relaxed);
+			let (baud, enabled, sending) = ctx
+				.shared
+				.register_state
+				.lock(|r| (r.uart2.baud(), r.uart2.is_enabled(), !r.uart2.tx.is_empty()));
+			ctx.shared.uart2.lock(|uart2| {
+				uart2.configure(clock_hz, baud, enabled);
+				if sending {
+					uart2.start_tx();
+				}
+			});
+			Mono::delay(UART2_POLL_INTERVAL_MS.millis()).await;
+		}
+	}
+
+	/// Applies the host's GPIO and PWM settings to the spare pins, and reads
+	/// their levels back.
+	#[cfg(not(any(
+		feature = "fan",
+		feature = "encoder",
+		feature = "i2c-target",
+		feature = "ps2-power",
+		feature = "status-led"
+	)))]
+	#[task(shared = [register_state], local = [expansion_pins])]
+	async fn expansion_poll(mut ctx: expansion_poll::Context) {
+		let pins = ctx.local.expansion_pins;
+		loop {
+			let clock_hz = CLOCK_HZ.load(Ordering::Relaxed);
+			ctx.shared.register_state.lock(|r| {
+				pins.configure(&r.gpio, &r.pwm, clock_hz);
+				r.gpio.set_input(pins.read());
+			});
+			Mono::delay(EXPANSION_POLL_INTERVAL_MS.millis()).await;
+		}
+	}
+
+	/// Samples the analog inputs and the temperature sensor, each on the
+	/// schedule the host set (see `AdcScheduler`).
+	///
+	/// When the power manager turns the DC power on, we time the rails and
+	/// the reset line instead, for the Rise Times register.
+	#[cfg(all(feature = "adc", not(any(feature = "current-sense", feature = "fan"))))]
+	#[task(shared = [register_state], local = [analog_sampler, rise_q_out])]
+	async fn analog_poll(mut ctx: analog_poll::Context) {
+		let sampler = ctx.local.analog_sampler;
+		loop {
+			let now = now_ms();
+			let (due, samples) = ctx
+				.shared
+				.register_state
+				.lock(|r| (r.adc_schedule.due(now), r.analog.samples()));
+			if (due & (1 << CHANNEL_TEMPERATURE)) != 0 {
+				let counts = sampler.read_temperature();
+				ctx.shared.register_state.lock(|r| {
+					let counts = r.adc_schedule.sampled(CHANNEL_TEMPERATURE, now, counts);
+					r.temperature_c = analog::temperature_c(AnalogSampler::ts_cal1(), counts);
+				});
+			}
+			if (due & INPUT_CHANNELS) != 0 {
+				// Converting every input is quick - it's powering up the ADC
+				// which takes the time - but only the due ones are recorded
+				let (vrefint, counts) = sampler.sample(samples);
+				ctx.shared.register_state.lock(|r| {
+					let mut inputs = [None; ANALOG_INPUTS];
+					for (input, reading) in inputs.iter_mut().enumerate() {
+						if (due & (1 << input)) != 0 {
+							*reading = Some(r.adc_schedule.sampled(input, now, counts[input]));
+						}
+					}
+					r.analog.record(
+						now,
+						AnalogSampler::vrefint_cal(),
+						vrefint,
+						&inputs,
+						&r.config.analog_calibration,
+					)
+				});
+			}
+			// Timing out just means it's time to check the schedule again
+			let dc_on_ms = Mono::timeout_after(
+				ANALOG_POLL_INTERVAL_MS.millis(),
+				ctx.local.rise_q_out.recv(),
+			)
+			.await
+			.ok()
+			.and_then(|dc_on_ms| dc_on_ms.ok());
+			if let Some(dc_on_ms) = dc_on_ms {
+				if let Some(times) = time_rise(sampler, dc_on_ms).await {
+					if times.faults() != 0 {
+						warn!(target: Subsystem::Power, "Slow rails or reset: {:02x}", times.faults());
+					}
+					ctx.shared.register_state.lock(|r| r.rise_times = times);
+				}
+			}
+		}
+	}
+
+	/// Time the rails and the reset line, after the DC power came on at
+	/// `dc_on_ms`.
+	///
+	/// Returns `None` if the power goes off again before we're done.
+	#[cfg(all(feature = "adc", not(any(feature = "current-sense", feature = "fan"))))]
+	async fn time_rise(sampler: &mut AnalogSampler, dc_on_ms: u32) -> Option<RiseTimes> {
+		let mut timer = RiseTimer::new(dc_on_ms);
+		loop {
+			if power_state() == DcPowerState::Off {
+				return None;
+			}
+			let (vrefint, inputs) = sampler.sample(1);
+			let vdda_mv = analog::vdda_mv(AnalogSampler::vrefint_cal(), vrefint);
+			let rails_mv = [
+				analog::counts_to_mv(inputs[0], vdda_mv),
+				analog::counts_to_mv(inputs[1], vdda_mv),
+			];
+			// nSYS_RESET is PA2
+			let reset_released = (gpio_registers(b'A').idr.read().bits() & (1 << 2)) != 0;
+			if let Some(times) = timer.sample(now_ms(), &rails_mv, reset_released) {
+				return Some(times);
+			}
+			Mono::delay(risetime::SAMPLE_INTERVAL_MS.millis()).await;
+		}
+	}
+
+	/// Checks the temperature against the over-temperature limit.
+	///
+	/// If it is too hot, we raise the Over-Temperature interrupt to ask the
+	/// host to shut down, and cut the power ourselves if it hasn't done so
+	/// by the end of the timeout.
+	#[cfg(all(feature = "adc", not(feature = "current-sense")))]
+	#[task(
+		shared = [register_state, host_irq],
+		local = [power_q_in_thermal, thermal_guard: ThermalGuard = ThermalGuard::new()]
+	)]
+	async fn thermal_poll(mut ctx: thermal_poll::Context) {
+		loop {
+			let (temperature_c, limit_c, timeout_s) = ctx.shared.register_state.lock(|r| {
+				(
+					r.temperature_c,
+					r.config.over_temperature_limit_c,
+					r.config.over_temperature_timeout_s,
+				)
+			});
+			let powered_on = power_state() != DcPowerState::Off;
+			match ctx.local.thermal_guard.update(
+				now_ms(),
+				temperature_c,
+				limit_c,
+				timeout_s,
+				powered_on,
+			) {
+				ThermalAction::None => {}
+				ThermalAction::RequestShutdown => {
+					warn!(
+						"Over-temperature ({} C)! Asking the host to shut down.",
+						temperature_c
+					);
+					ctx.shared.host_irq.lock(|host_irq| {
+						host_irq.controller.raise(irq::OVER_TEMPERATURE);
+						host_irq.update();
+					});
+				}
+				ThermalAction::CutPower => {
+					warn!(target: Subsystem::Power, "Over-temperature ({} C)! Cutting the power.", temperature_c);
+					ctx.shared
+						.register_state
+						.lock(|r| r.health.report(health::OVER_TEMPERATURE));
+					let _ = ctx
+						.local
+						.power_q_in_thermal
+						.send(PowerRequest::OverTemperature(temperature_c))
+						.await;
+				}
+			}
+			Mono::delay(THERMAL_POLL_INTERVAL_MS.millis()).await;
+		}
+	}
+
+	/// Watches the host, once it has started its watchdog.
+	///
+	/// We raise the Watchdog Pretimeout interrupt when the host is about to
+	/// run out of time, and reset the main board if it does.
+	#[task(
+		shared = [register_state, host_irq],
+		local = [power_q_in_watchdog]
+	)]
+	async fn watchdog_poll(mut ctx: watchdog_poll::Context) {
+		loop {
+			let powered_on = power_state() != DcPowerState::Off;
+			let action = ctx
+				.shared
+				.register_state
+				.lock(|r| r.watchdog.update(now_ms(), powered_on));
+			match action {
+				WatchdogAction::None => {}
+				WatchdogAction::Pretimeout => {
+					warn!("Host watchdog about to fire!");
+					ctx.shared.host_irq.lock(|host_irq| {
+						host_irq.controller.raise(irq::WATCHDOG_PRETIMEOUT);
+						host_irq.update();
+					});
+				}
+				WatchdogAction::Reset => {
+					warn!(target: Subsystem::Power, "Host watchdog fired! Resetting the main board.");
+					let _ = ctx
+						.local
+						.power_q_in_watchdog
+						.send(PowerRequest::Reset(
+							ResetSource::Watchdog,
+							RESET_DURATION_MS,
+						))
+						.await;
+				}
+			}
+			Mono::delay(watchdog::POLL_INTERVAL_MS.millis()).await;
+		}
+	}
+
+	/// Plays the notes queued up by the host.
+	///
+	/// We poll for new notes when the queue is empty, so that a note queued
+	/// whilst this task is sleeping is never missed.
+	#[cfg(all(feature = "speaker", not(feature = "current-sense")))]
+	#[task(shared = [register_state], local = [buzzer])]
+	async fn buzzer_play(mut ctx: buzzer_play::Context) {
+		loop {
+			match ctx.shared.register_state.lock(|r| r.buzzer_queue.pop()) {
+				Some(note) => {
+					let clock_hz = CLOCK_HZ.load(Ordering::Relaxed);
+					ctx.local.buzzer.play(note.frequency_hz, clock_hz);
+					Mono::delay(note.duration_ms().millis()).await;
+					ctx.local.buzzer.stop();
+					Mono::delay(note.gap_ms().millis()).await;
+				}
+				None => {
+					Mono::delay(BUZZER_IDLE_POLL_MS.millis()).await;
+				}
+			}
+		}
+	}
+
+	/// Passes disk activity reported by the host on to the power manager,
+	/// which flickers the power LED, along with any change to the power LED
+	/// settings.
+	#[task(shared = [register_state], local = [power_q_in_activity])]
+	async fn activity_led(mut ctx: activity_led::Context) {
+		let mut led_settings = None;
+		loop {
+			let (pending, settings) = ctx.shared.register_state.lock(|r| {
+				(
+					core::mem::replace(&mut r.activity_pending, false),
+					(r.config.led_mode, r.config.led_brightness),
+				)
+			});
+			if led_settings != Some(settings) {
+				led_settings = Some(settings);
+				let _ = ctx
+					.local
+					.power_q_in_activity
+					.send(PowerRequest::Led(settings.0, settings.1))
+					.await;
+			}
+			if pending {
+				let _ = ctx
+					.local
+					.power_q_in_activity
+					.send(PowerRequest::Activity)
+					.await;
+			}
+			Mono::delay(ACTIVITY_POLL_MS.millis()).await;
+		}
+	}
+
+	/// This is the ADC task.
+	///
+	/// It fires when the analog watchdog sees the main board current go over
+	/// the limit, and has the power manager cut the DC power straight away.
+	#[cfg(feature = "current-sense")]
+	#[task(binds = ADC, priority = 3, shared = [current_sense], local = [power_q_in_current])]
+	fn adc_interrupt(mut ctx: adc_interrupt::Context) {
+		let over_current = ctx
+			.shared
+			.current_sense
+			.lock(|current_sense| current_sense.handle_isr());
+		if over_current && power_state() != DcPowerState::Off {
+			warn!(target: Subsystem::Power, "Over-current!");
+			// The power manager runs next, as soon as we return
+			if ctx
+				.local
+				.power_q_in_current
+				.try_send(PowerRequest::OverCurrent)
+				.is_err()
+			{
+				warn!(target: Subsystem::Power, "Power manager queue full");
+			}
+		}
+	}
+
+	/// This is the programmable voltage detector task (STM32F031).
+	///
+	/// It fires when our supply falls through the threshold, and again when
+	/// it recovers. Whilst the supply is low, the power manager holds the
+	/// main board in reset, so it stops cleanly before its rails collapse,
+	/// rather than running on with a bad supply.
+	#[cfg(feature = "stm32f031")]
+	#[task(binds = PVD, priority = 3, local = [power_q_in_pvd])]
+	fn pvd_interrupt(ctx: pvd_interrupt::Context) {
+		supply_changed(ctx.local.power_q_in_pvd);
+	}
+
+	/// This is the programmable voltage detector task (STM32F042 and
+	/// STM32F072), which works like `pvd_interrupt`.
+	///
+	/// These parts share the vector with the VDDIO2 monitor, which we don't
+	/// turn on.
+	#[cfg(any(feature = "stm32f042", feature = "stm32f072"))]
+	#[task(binds = PVD_VDDIO2, priority = 3, local = [power_q_in_pvd_vddio2])]
+	fn pvd_vddio2_interrupt(ctx: pvd_vddio2_interrupt::Context) {
+		supply_changed(ctx.local.power_q_in_pvd_vddio2);
+	}
+
+	/// This task polls our power and reset buttons, and any GPIO pins the
+	/// host has set up as buttons.
+	///
+	/// We poll them rather than setting up an interrupt as we need to debounce
+	/// them, which involves waiting a short period and checking them again.
+	/// Given that we have to do that, we might as well not bother with the
+	/// interrupt.
+	///
+	/// Holding both buttons down cuts the power, whatever the button actions
+	/// say (see [`neotron_bmc_pico::failsafe`]).
+	#[task(
+		shared = [register_state, host_irq],
+		local = [
+			button_power,
+			button_reset,
+			buttons,
+			power_q_in_buttons,
+			forced_off: ForcedOff = ForcedOff::new(),
+			button_filter: glitch::PollFilter = glitch::PollFilter::new()
+		]
+	)]
+	async fn button_poll(mut ctx: button_poll::Context) {
+		loop {
+			// Poll buttons, through the glitch filter
+			let width_us = ctx
+				.shared
+				.register_state
+				.lock(|r| u16::from(r.config.button_filter) * glitch::BUTTON_FILTER_UNIT_US);
+			let sample = (u8::from(ctx.local.button_power.is_low().unwrap()) << INPUT_POWER)
+				| (u8::from(ctx.local.button_reset.is_low().unwrap()) << INPUT_RESET);
+			let filtered = ctx
+				.local
+				.button_filter
+				.poll(sample, Stopwatch::now_us(), width_us);
+			let levels = filtered.levels;
+			let pwr_pressed = (levels & (1 << INPUT_POWER)) != 0;
+			let rst_pressed = (levels & (1 << INPUT_RESET)) != 0;
+
+			trace!(target: Subsystem::Power, "pwr/rst {}/{}", pwr_pressed, rst_pressed);
+
+			// The failsafe goes first, before anything which might refuse
+			if ctx
+				.local
+				.forced_off
+				.update(pwr_pressed, rst_pressed, now_ms())
+			{
+				warn!(target: Subsystem::Power, "Both buttons held! Forcing the power off.");
+				let _ = ctx
+					.local
+					.power_q_in_buttons
+					.send(PowerRequest::ForcedOff)
+					.await;
+			}
+
+			// Dispatch event
+			let buttons = &mut *ctx.local.buttons;
+			let actions = (&mut ctx.shared.register_state, &mut ctx.shared.host_irq).lock(
+				|register_state, host_irq| {
+					// GPIO buttons pull their input low, like ours
+					let gpio = &register_state.gpio;
+					let gpio_pressed = !gpio.input() & !gpio.direction() & GPIO_AVAILABLE;
+					let mut pressed = [false; BUTTONS];
+					pressed[BUTTON_POWER] = pwr_pressed;
+					pressed[BUTTON_RESET] = rst_pressed;
+					pressed[BUTTON_GPIO0] = (gpio_pressed & (1 << 0)) != 0;
+					pressed[BUTTON_GPIO1] = (gpio_pressed & (1 << 1)) != 0;
+					let actions = buttons.update(pressed, &register_state.buttons, power_state());
+					for input in [INPUT_POWER, INPUT_RESET] {
+						if (filtered.glitches & (1 << input)) != 0 {
+							register_state.glitches.add(input, 1);
+						}
+					}
+					register_state.button_status = buttons.pressed();
+					if actions.host_events != 0 {
+						register_state.button_events |= actions.host_events;
+						host_irq.controller.raise(irq::BUTTON_STATE_CHANGE);
+						host_irq.update();
+					}
+					actions
+				},
+			);
+
+			if let Some(action) = actions.power {
+				info!(target: Subsystem::Power, "Power button: {}", action);
+				let _ = ctx
+					.local
+					.power_q_in_buttons
+					.send(PowerRequest::Button(action))
+					.await;
+			}
+			if actions.reset {
+				info!(target: Subsystem::Power, "Reset button pressed.");
+				let _ = ctx
+					.local
+					.power_q_in_buttons
+					.send(PowerRequest::Reset(ResetSource::Button, RESET_DURATION_MS))
+					.await;
+			}
+
+			// Come back sooner if the glitch filter is waiting to see whether
+			// a change lasts - but never sooner than one tick, or we'd spin
+			let delay_ms = match filtered.wait_us {
+				Some(wait_us) => u32::from(wait_us)
+					.div_ceil(1000)
+					.clamp(1000 / SYSTICK_HZ, DEBOUNCE_POLL_INTERVAL_MS),
+				None => DEBOUNCE_POLL_INTERVAL_MS,
+			};
+			Mono::delay(delay_ms.millis()).await;
+		}
+	}
+
+	/// Keeps the power state journal in flash up to date, so we can restore
+	/// the power state and the boot mode after the BMC loses power.
+	///
+	/// If the power restore policy says so, we first power on by ourselves,
+	/// `restore_after_s` seconds after the BMC powered up. We only write the
+	/// power state to the journal when the policy needs it, and the boot
+	/// mode when it changes, to save wearing out the flash.
+	#[task(shared = [register_state, flash])]
+	async fn power_journal(mut ctx: power_journal::Context, restore_after_s: Option<u8>) {
+		if let Some(delay_s) = restore_after_s {
+			Mono::delay((u32::from(delay_s) * 1000).millis()).await;
+			// Fails if a wake-up is already pending, which is fine
+			let _ = wake_up::spawn(PowerOnSource::PowerRestore);
+		}
+		loop {
+			Mono::delay(POWER_JOURNAL_INTERVAL_MS.millis()).await;
+			let (policy, boot_mode) = ctx
+				.shared
+				.register_state
+				.lock(|r| (r.config.power_restore, r.boot_mode));
+			let journal = read_journal();
+			let is_on = power_state() != DcPowerState::Off;
+			// One entry at a time - anything else waits for the next pass
+			let entry = if journal.boot_mode.unwrap_or(BOOT_MODE_NORMAL) != boot_mode {
+				boot_mode_entry(boot_mode)
+			} else if policy == RESTORE_LAST && journal.was_on != Some(is_on) {
+				if is_on {
+					JOURNAL_ON
+				} else {
+					JOURNAL_OFF
+				}
+			} else {
+				continue;
+			};
+			if ctx
+				.shared
+				.flash
+				.lock(|flash| append_journal(flash, entry))
+				.is_err()
+			{
+				warn!(target: Subsystem::Power, "Power state journal write failed");
+			}
+		}
+	}
+
+	/// Keeps the lifetime counters in flash up to date.
+	///
+	/// We count this boot (and the watchdog reset, if that's why we booted)
+	/// first, then whatever the power manager sends us. Programming flash
+	/// stalls the CPU, so we do this at the lowest priority.
+	#[task(shared = [register_state, flash], local = [stats_q_out])]
+	async fn stats_task(mut ctx: stats_task::Context, watchdog_reset: bool) {
+		let stats = read_stats().stats;
+		ctx.shared.register_state.lock(|r| r.stats = stats);
+		if watchdog_reset {
+			warn!("Reset by a watchdog");
+			record(&mut ctx, Counter::WatchdogReset);
+		}
+		record(&mut ctx, Counter::BmcBoot);
+		while let Ok(counter) = ctx.local.stats_q_out.recv().await {
+			record(&mut ctx, counter);
+		}
+
+		fn record(ctx: &mut stats_task::Context, counter: Counter) {
+			match ctx.shared.flash.lock(|flash| record_stat(flash, counter)) {
+				Ok(stats) => ctx.shared.register_state.lock(|r| r.stats = stats),
+				Err(()) => warn!("Lifetime counter write failed"),
+			}
+		}
+	}
+
+	/// Powers on, powers off or suspends the system, because someone wrote to
+	/// the Power Control register, or resets the main board, because someone
+	/// wrote to the Main Board Reset register.
+	///
+	/// We wait a moment first, so the host can finish reading our response.
+	#[task(local = [power_q_in_host])]
+	async fn power_request(ctx: power_request::Context, request: PowerRequest) {
+		Mono::delay(POWER_REQUEST_DELAY_MS.millis()).await;
+		let _ = ctx.local.power_q_in_host.send(request).await;
+	}
+
+	/// Starts the RTC, sets it when the host asks, and keeps the RTC registers
+	/// up to date. Wakes the system when the alarm goes off, if the host has
+	/// armed that.
+	///
+	/// If the RTC kept running whilst the BMC was reset, from the clock the
+	/// host chose, we leave it alone. Otherwise, or if the host chooses
+	/// another clock, we start it again and the time is lost.
+	#[task(shared = [register_state], local = [rtc])]
+	async fn rtc_poll(mut ctx: rtc_poll::Context) {
+		// The clock the host chose when we last started the RTC
+		let mut started = None;
+		loop {
+			let clock = ctx.shared.register_state.lock(|r| r.config.rtc_clock);
+			if started != Some(clock) {
+				if started.is_none() && ctx.local.rtc.is_running_from(clock) {
+					info!("RTC kept running");
+				} else {
+					info!("Starting RTC from clock {}", clock);
+					ctx.local.rtc.reset(clock);
+					let mut waited_ms = 0;
+					while clock == rtc::CLOCK_LSE
+						&& !ctx.local.rtc.lse_ready()
+						&& waited_ms < rtc::LSE_STARTUP_MS
+					{
+						Mono::delay(RTC_POLL_INTERVAL_MS.millis()).await;
+						waited_ms += RTC_POLL_INTERVAL_MS;
+					}
+					ctx.local.rtc.start(clock);
+				}
+				started = Some(clock);
+			}
+			if let Some(now) = ctx.shared.register_state.lock(|r| r.rtc.take_set_request()) {
+				ctx.local.rtc.set(&now);
+			}
+			let (now, status) = ctx.local.rtc.now();
+			let wake = ctx
+				.shared
+				.register_state
+				.lock(|r| r.rtc.update(now, status) && (r.wake_control & WAKE_ON_ALARM) != 0);
+			if wake {
+				// Fails if a wake-up is already pending, which is fine
+				let _ = wake_up::spawn(PowerOnSource::Alarm);
+			}
+			Mono::delay(RTC_POLL_INTERVAL_MS.millis()).await;
+		}
+	}
+
+	/// Power on the system (or resume it) because an armed wake source fired,
+	/// or power it on because the power restore policy says so.
+	///
+	/// The power manager does nothing if the system is already on.
+	#[task(local = [power_q_in])]
+	async fn wake_up(ctx: wake_up::Context, source: PowerOnSource) {
+		let _ = ctx.local.power_q_in.send(PowerRequest::Wake(source)).await;
+	}
+
+	/// Moves the main board between power states.
+	///
+	/// This task owns the DC power, reset and power LED pins, and is the only
+	/// place the power state changes. Everyone else sends it a
+	/// `PowerRequest`. In between requests it wakes up to end reset pulses
+	/// and to blink the power LED.
+	#[task(
+		priority = 2,
+		shared = [register_state, event_log],
+		local = [
+			power,
+			power_q_out,
+			stats_q_in,
+			rise_q_in,
+			manager: PowerManager = PowerManager::new(),
+			dc_was_on: bool = false
+		]
+	)]
+	async fn power_manager(mut ctx: power_manager::Context) {
+		loop {
+			let now = now_ms();
+			ctx.local.manager.poll(now);
+			ctx.local.power.update(ctx.local.manager, now);
+			let dc_on = ctx.local.manager.dc_on();
+			if dc_on && !*ctx.local.dc_was_on {
+				// Time the rails coming up. If the analog task is still busy
+				// with the last power-on, we skip this one.
+				#[cfg(all(feature = "adc", not(any(feature = "current-sense", feature = "fan"))))]
+				let _ = ctx.local.rise_q_in.try_send(now);
+			}
+			*ctx.local.dc_was_on = dc_on;
+			let request = match ctx.local.manager.next_change_ms(now) {
+				Some(wait_ms) => {
+					// Timing out just means it's time to update the pins
+					Mono::timeout_after(wait_ms.millis(), ctx.local.power_q_out.recv())
+						.await
+						.ok()
+						.and_then(|request| request.ok())
+				}
+				None => ctx.local.power_q_out.recv().await.ok(),
+			};
+			if let Some(request) = request {
+				let now = now_ms();
+				let present = ctx
+					.shared
+					.register_state
+					.lock(|r| board_present(r.config.board_presence, r.gpio.input()));
+				ctx.local.manager.set_board_present(present);
+				let outcome = ctx.local.manager.handle(request, now);
+				if let Some((kind, data)) = outcome.event {
+					info!(target: Subsystem::Power, "{} -> {}", request, ctx.local.manager.state());
+					ctx.shared
+						.event_log
+						.lock(|event_log| event_log.push(Event::new(now, kind, data)));
+					#[cfg(feature = "status-led")]
+					ctx.shared
+						.register_state
+						.lock(|r| r.status_led.power_event(kind));
+					if let Some(counter) = Counter::for_event(kind) {
+						// If the queue is full, we'd rather lose a count than wait
+						let _ = ctx.local.stats_q_in.try_send(counter);
+					}
+				}
+				if outcome.started {
+					ctx.shared.register_state.lock(|r| {
+						r.boot_keys.start(now);
+						// The rail history covers this power-on
+						r.analog.clear_history();
+					});
+				}
+				if let Some((EventKind::PowerOn | EventKind::Reset, _)) = outcome.event {
+					// The BIOS starts again, and turns make-only mode back on
+					// if it wants it
+					ctx.shared.register_state.lock(|r| r.kb_control = 0);
+				}
+			}
+		}
+	}
+
+	/// Tell the power manager whether our supply is low, after the voltage
+	/// detector output changes.
+	#[cfg(any(feature = "stm32f031", feature = "stm32f042", feature = "stm32f072"))]
+	fn supply_changed(power_q_in: &mut Sender<'static, PowerRequest, MSG_Q_LEN>) {
+		// Safety: the pending register is write-1-to-clear, and only the
+		// voltage detector task reads the voltage detector output
+		let exti = unsafe { &*pac::EXTI::ptr() };
+		exti.pr.write(|w| w.pr16().set_bit());
+		let pwr = unsafe { &*pac::PWR::ptr() };
+		let request = if pwr.csr.read().pvdo().bit_is_set() {
+			warn!(target: Subsystem::Power, "Supply voltage low!");
+			PowerRequest::LowVoltage
+		} else {
+			PowerRequest::VoltageRestored
+		};
+		// The power manager runs next, as soon as we return
+		if power_q_in.try_send(request).is_err() {
+			warn!(target: Subsystem::Power, "Power manager queue full");
+		}
+	}
+
+	/// Answers a request from the host, whichever bus it arrived on - or,
+	/// with `payload`, the payload which followed it.
+	///
+	/// The response goes to `send` as soon as it is ready. Then we start
+	/// anything the request asked for which needs the hardware, or takes too
+	/// long to do in an interrupt.
+	///
+	/// Returns `true` if the host will now send the request's payload (with
+	/// its CRC), which should come back here.
+	fn handle_host_request(
+		req: &proto::Request,
+		payload: Option<&[u8]>,
+		register_state: &mut RegisterState,
+		event_log: &mut EventLog<EVENT_LOG_LEN>,
+		host_irq: &mut HostIrq,
+		read_buffer: &mut [u8; registers::READ_BUFFER_LEN],
+		send: impl FnOnce(&proto::Response),
+	) -> bool {
+		use proto::Receivable;
+		register_state.power_state = power_state();
+		registers::collect_isr_status(register_state, &ISR_STATUS);
+		let rsp = match payload.map(proto::Payload::from_bytes) {
+			None => registers::handle_request(
+				req,
+				register_state,
+				event_log,
+				&mut host_irq.controller,
+				read_buffer,
+			),
+			Some(Ok(payload)) => registers::handle_payload(
+				req,
+				payload.data,
+				register_state,
+				event_log,
+				&mut host_irq.controller,
+				read_buffer,
+			),
+			Some(Err(e)) => {
+				proto::Response::new_without_data(handle_bad_request(e, register_state, event_log))
+			}
+		};
+		let wants_payload = payload.is_none()
+			&& rsp.result == proto::ResponseResult::Ok
+			&& req.payload_len().is_some();
+		send(&rsp);
+		if payload.is_none() {
+			registers::note_access(req, register_state, event_log, &host_irq.controller);
+		}
+		host_irq.set_line_control(register_state.config.irq_line);
+		host_irq.update();
+		CS_FILTER_US.store(register_state.config.cs_filter_us, Ordering::Relaxed);
+		logging::set_filter(register_state.log_level, register_state.log_subsystems);
+		if core::mem::replace(&mut register_state.save_config, false) {
+			// The deferred operation stops a second save starting, but the
+			// last one might not quite have returned yet.
+			if config_save::spawn().is_err() {
+				register_state.config_status = CONFIG_STATUS_SAVE_FAILED;
+				register_state.deferred.finish(CONFIG_STATUS_SAVE_FAILED);
+				host_irq.controller.raise(irq::DEFERRED_DONE);
+				host_irq.update();
+			}
+		}
+		if core::mem::replace(&mut register_state.commit_nvram, false)
+			&& nvram_commit::spawn().is_err()
+		{
+			let data = *register_state.nvram.data();
+			register_state.nvram.committed(&data, false);
+			register_state.deferred.finish(nvram::STATUS_COMMIT_FAILED);
+			host_irq.controller.raise(irq::DEFERRED_DONE);
+			host_irq.update();
+		}
+		if core::mem::replace(&mut register_state.enable_readout_protection, false)
+			&& readout_protect::spawn().is_err()
+		{
+			register_state.deferred.finish(readout::RESULT_FAILED);
+			host_irq.controller.raise(irq::DEFERRED_DONE);
+			host_irq.update();
+		}
+		if let Some(command) = register_state.run_self_test.take() {
+			if board_self_test::spawn(command).is_err() {
+				register_state.deferred.finish(selftest::ALL);
+				host_irq.controller.raise(irq::DEFERRED_DONE);
+				host_irq.update();
+			}
+		}
+		if let Some(action) = register_state.power_request.take() {
+			// Fails if a request is already pending, which the host will
+			// have to try again
+			let _ = power_request::spawn(PowerRequest::Host(action));
+		}
+		if let Some(duration_ms) = register_state.reset_request.take() {
+			let _ = power_request::spawn(PowerRequest::Reset(ResetSource::Host, duration_ms));
+		}
+		#[cfg(feature = "ps2-keyboard")]
+		if !register_state.kb_inject.is_empty() {
+			// Fails if the last lot are still being passed on, in which case
+			// these go too
+			let _ = keyboard_inject::spawn();
+		}
+		if !register_state.uart_tx.is_empty() {
+			// Fails if it is already pending, which is fine
+			let _ = uart_transmit::spawn();
+		}
+		if let Some(length) = register_state.uart_self_test.take() {
+			if uart_self_test::spawn(length).is_err() {
+				register_state.deferred.finish(SELF_TEST_FAILED);
+				host_irq.controller.raise(irq::DEFERRED_DONE);
+				host_irq.update();
+			}
+		}
+		wants_payload
+	}
+
+	/// Records a request from the host we couldn't decode, and says which
+	/// result to send back.
+	fn handle_bad_request(
+		e: proto::Error,
+		register_state: &mut RegisterState,
+		event_log: &mut EventLog<EVENT_LOG_LEN>,
+	) -> proto::ResponseResult {
+		warn!(target: Subsystem::Spi, "Bad Req ({:02x})", e as u8);
+		let result = registers::bad_request_result(e);
+		event_log.push(Event::new(now_ms(), EventKind::ProtocolError, e as u8));
+		register_state.health.report_error(result as u8);
+		result
+	}
+
+	/// Record how long the first response since chip select fell took to
+	/// get ready, in the SPI Service Latency register.
+	fn record_spi_latency(register_state: &mut RegisterState) {
+		if CS_FALL_PENDING.load(Ordering::Relaxed) {
+			CS_FALL_PENDING.store(false, Ordering::Relaxed);
+			let selected = Stamp::new(
+				CS_FALL_MS.load(Ordering::Relaxed),
+				CS_FALL_US.load(Ordering::Relaxed),
+			);
+			register_state
+				.spi_latency
+				.record(stamp().micros_since(selected));
+		}
+	}
+
+	/// Milliseconds since the BMC booted, for timestamping events.
+	fn now_ms() -> u32 {
+		Mono::now().duration_since_epoch().to_millis()
+	}
+
+	/// The time right now, to the microsecond, for measuring latency.
+	fn stamp() -> Stamp {
+		Stamp::new(now_ms(), Stopwatch::now_us())
+	}
+
+	/// The current DC power state (see `DC_POWER_STATE`)
+	fn power_state() -> DcPowerState {
+		DcPowerState::from_u8(DC_POWER_STATE.load(Ordering::Relaxed)).unwrap_or(DcPowerState::Off)
+	}
+}
@@ -0,0 +1,591 @@
+//! # Mouse Packet Assembly
+//!
+//! A PS/2 mouse sends its movement as packets of three bytes (or four, once a
+//! wheel mouse has been switched into IntelliMouse mode):
+//!
+//! * Flags - the buttons (bits 0 to 2), an always-one sync bit (bit 3), the X
+//!   and Y sign bits (bits 4 and 5) and the X and Y overflow bits (bits 6
+//!   and 7)
+//! * The bottom eight bits of the X movement
+//! * The bottom eight bits of the Y movement (positive is up)
+//! * The wheel movement, as an `i8` (four byte packets only)
+//!
+//! We put the packets back together, checking the sync bit so we can find our
+//! place again if a byte goes missing, and keep the complete packets in a
+//! FIFO for the host. We also add up all the movement, for hosts which only
+//! poll now and again.
+//!
+//! We also keep the commands we want to send to the mouse (to set the sample
+//! rate and resolution, and to turn on reporting whenever it is plugged in),
+//! and check the mouse acknowledges each byte.
+
+/// The always-one bit in the first byte of every packet
+const SYNC_BIT: u8 = 1 << 3;
+
+/// The buttons in the first byte of every packet
+const BUTTONS: u8 = 0b0000_0111;
+
+/// The X sign bit (the ninth bit of the X movement)
+const X_SIGN: u8 = 1 << 4;
+
+/// The Y sign bit (the ninth bit of the Y movement)
+const Y_SIGN: u8 = 1 << 5;
+
+/// Either overflow bit, which means the movement is meaningless
+const OVERFLOW: u8 = 0b1100_0000;
+
+/// Sent by the mouse when it has (re)started, followed by its ID
+const SELF_TEST_PASSED: u8 = 0xAA;
+
+/// Sent by the mouse when it has accepted a byte from us
+const ACK: u8 = 0xFA;
+
+/// Sent by the mouse when it wants the last byte again
+const RESEND: u8 = 0xFE;
+
+/// Command to set the resolution (followed by the resolution)
+const CMD_SET_RESOLUTION: u8 = 0xE8;
+
+/// Command to set the sample rate (followed by the rate)
+const CMD_SET_SAMPLE_RATE: u8 = 0xF3;
+
+/// Command to start sending movement packets
+const CMD_ENABLE_REPORTING: u8 = 0xF4;
+
+/// The sample rates (in packets per second) a PS/2 mouse supports
+pub const SAMPLE_RATES: [u8; 7] = [10, 20, 40, 60, 80, 100, 200];
+
+/// The highest resolution setting - 3 is 8 counts/mm
+pub const MAX_RESOLUTION: u8 = 3;
+
+/// The mouse accepted all of the host's commands
+pub const COMMAND_OK: u8 = 0;
+
+/// The mouse didn't accept one of the host's commands (or isn't there)
+pub const COMMAND_FAILED: u8 = 1;
+
+/// How many command bytes we can have waiting to go to the mouse. Enough for
+/// everything we send when the mouse starts, plus one setting from the host.
+const COMMAND_QUEUE_LEN: usize = 8;
+
+/// A complete movement packet from the mouse.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub struct MouseReport {
+	/// The first byte of the packet (buttons, signs and overflows)
+	pub flags: u8,
+	/// The bottom eight bits of the X movement
+	pub x: u8,
+	/// The bottom eight bits of the Y movement
+	pub y: u8,
+	/// The wheel movement (zero for a three byte packet)
+	pub wheel: i8,
+}
+
+impl MouseReport {
+	/// How many bytes a report occupies when sent to the host.
+	pub const SIZE: usize = 4;
+
+	/// An empty report, used to pad out reads.
+	pub const EMPTY: MouseReport = MouseReport {
+		flags: 0,
+		x: 0,
+		y: 0,
+		wheel: 0,
+	};
+
+	/// The X movement, with its sign bit.
+	pub fn dx(&self) -> i16 {
+		if (self.flags & X_SIGN) != 0 {
+			i16::from(self.x) - 256
+		} else {
+			i16::from(self.x)
+		}
+	}
+
+	/// The Y movement, with its sign bit (positive is up).
+	pub fn dy(&self) -> i16 {
+		if (self.flags & Y_SIGN) != 0 {
+			i16::from(self.y) - 256
+		} else {
+			i16::from(self.y)
+		}
+	}
+
+	/// Convert to bytes for transmission.
+	///
+	/// That's the flags, X, Y and then the wheel - the same as a four byte
+	/// PS/2 packet.
+	pub fn as_bytes(&self) -> [u8; Self::SIZE] {
+		[self.flags, self.x, self.y, self.wheel as u8]
+	}
+}
+
+/// All the movement since the host last asked.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub struct MouseMovement {
+	/// The buttons, as of the most recent report
+	pub buttons: u8,
+	/// Total X movement (saturates)
+	pub dx: i16,
+	/// Total Y movement, positive is up (saturates)
+	pub dy: i16,
+	/// Total wheel movement (saturates)
+	pub wheel: i8,
+}
+
+impl MouseMovement {
+	/// How many bytes the movement occupies when sent to the host.
+	pub const SIZE: usize = 6;
+
+	/// No movement, and no buttons.
+	pub const EMPTY: MouseMovement = MouseMovement {
+		buttons: 0,
+		dx: 0,
+		dy: 0,
+		wheel: 0,
+	};
+
+	/// Convert to bytes for transmission.
+	///
+	/// That's the buttons, X as an `i16le`, Y as an `i16le` and then the
+	/// wheel.
+	pub fn as_bytes(&self) -> [u8; Self::SIZE] {
+		let dx = self.dx.to_le_bytes();
+		let dy = self.dy.to_le_bytes();
+		[self.buttons, dx[0], dx[1], dy[0], dy[1], self.wheel as u8]
+	}
+}
+
+/// Assembles packets from the mouse, and keeps the last `N` of them.
+#[derive(Debug)]
+pub struct Mouse<const N: usize> {
+	/// The packet we are putting together
+	packet: [u8; 4],
+	/// How many bytes of `packet` we have
+	received: usize,
+	/// Three, or four for a wheel mouse
+	packet_size: usize,
+	/// Complete reports waiting for the host
+	reports: [MouseReport; N],
+	/// Index of the oldest report
+	head: usize,
+	/// How many reports are valid
+	len: usize,
+	/// Movement since the host last asked
+	movement: MouseMovement,
+	/// The sample rate, in packets per second
+	sample_rate: u8,
+	/// The resolution (0 is 1 count/mm, up to 3 which is 8 counts/mm)
+	resolution: u8,
+	/// Bytes to send to the mouse
+	commands: [u8; COMMAND_QUEUE_LEN],
+	/// How many bytes of `commands` are valid
+	commands_len: usize,
+	/// How many bytes of `commands` we've sent
+	commands_sent: usize,
+	/// We've sent a byte, and the mouse hasn't acknowledged it yet
+	awaiting_ack: bool,
+	/// Something in the current batch of commands went wrong
+	failed: bool,
+	/// The host is waiting to hear how the current batch of commands went
+	report_result: bool,
+}
+
+impl<const N: usize> Mouse<N> {
+	/// Create a new mouse, expecting three byte packets.
+	pub const fn new() -> Mouse<N> {
+		Mouse {
+			packet: [0; 4],
+			received: 0,
+			packet_size: 3,
+			reports: [MouseReport::EMPTY; N],
+			head: 0,
+			len: 0,
+			movement: MouseMovement::EMPTY,
+			sample_rate: 100,
+			resolution: 2,
+			commands: [0; COMMAND_QUEUE_LEN],
+			commands_len: 0,
+			commands_sent: 0,
+			awaiting_ack: false,
+			failed: false,
+			report_result: false,
+		}
+	}
+
+	/// Set whether the mouse sends four byte packets (with wheel movement).
+	pub fn set_wheel(&mut self, wheel: bool) {
+		self.packet_size = if wheel { 4 } else { 3 };
+		self.received = 0;
+	}
+
+	/// Does the mouse send four byte packets?
+	pub fn wheel(&self) -> bool {
+		self.packet_size == 4
+	}
+
+	/// Feed in a byte received from the mouse.
+	///
+	/// Returns the report if that byte completed a packet.
+	pub fn update(&mut self, byte: u8) -> Option<MouseReport> {
+		if self.awaiting_ack {
+			self.awaiting_ack = false;
+			match byte {
+				ACK => {}
+				RESEND => self.commands_sent -= 1,
+				_ => self.fail(),
+			}
+			return None;
+		}
+		if self.received == 0 && (byte & SYNC_BIT) == 0 {
+			// We've lost our place - wait for a byte which could be the start
+			// of a packet
+			return None;
+		}
+		if self.received == 1 && self.packet[0] == SELF_TEST_PASSED && byte == 0x00 {
+			// The mouse has been plugged in, or reset, which also turns the
+			// wheel off again. Set it up the way the host wants.
+			self.set_wheel(false);
+			self.awaiting_ack = false;
+			self.commands_len = 0;
+			self.commands_sent = 0;
+			self.queue(&[
+				CMD_SET_SAMPLE_RATE,
+				self.sample_rate,
+				CMD_SET_RESOLUTION,
+				self.resolution,
+				CMD_ENABLE_REPORTING,
+			]);
+			return None;
+		}
+		self.packet[self.received] = byte;
+		self.received += 1;
+		if self.received < self.packet_size {
+			return None;
+		}
+		self.received = 0;
+		let report = MouseReport {
+			flags: self.packet[0],
+			x: self.packet[1],
+			y: self.packet[2],
+			wheel: if self.packet_size == 4 {
+				self.packet[3] as i8
+			} else {
+				0
+			},
+		};
+		self.push(report);
+		Some(report)
+	}
+
+	/// Add a report to the FIFO (discarding the oldest if it is full), and
+	/// to the total movement.
+	fn push(&mut self, report: MouseReport) {
+		let idx = (self.head + self.len) % N;
+		self.reports[idx] = report;
+		if self.len == N {
+			self.head = (self.head + 1) % N;
+		} else {
+			self.len += 1;
+		}
+		self.movement.buttons = report.flags & BUTTONS;
+		if (report.flags & OVERFLOW) == 0 {
+			self.movement.dx = self.movement.dx.saturating_add(report.dx());
+			self.movement.dy = self.movement.dy.saturating_add(report.dy());
+		}
+		self.movement.wheel = self.movement.wheel.saturating_add(report.wheel);
+	}
+
+	/// Remove the oldest report from the FIFO.
+	pub fn pop(&mut self) -> Option<MouseReport> {
+		if self.len == 0 {
+			return None;
+		}
+		let report = self.reports[self.head];
+		self.head = (self.head + 1) % N;
+		self.len -= 1;
+		Some(report)
+	}
+
+	/// How many reports are in the FIFO?
+	pub fn len(&self) -> usize {
+		self.len
+	}
+
+	/// Is the FIFO empty?
+	pub fn is_empty(&self) -> bool {
+		self.len == 0
+	}
+
+	/// Throw away all the reports in the FIFO.
+	pub fn clear(&mut self) {
+		self.head = 0;
+		self.len = 0;
+	}
+
+	/// Get the movement since we were last asked, and start again from zero.
+	///
+	/// The buttons are left as they are.
+	pub fn take_movement(&mut self) -> MouseMovement {
+		let movement = self.movement;
+		self.movement = MouseMovement {
+			buttons: movement.buttons,
+			..MouseMovement::EMPTY
+		};
+		movement
+	}
+
+	/// Get the sample rate, in packets per second.
+	pub fn sample_rate(&self) -> u8 {
+		self.sample_rate
+	}
+
+	/// Set the sample rate, in packets per second, and queue the commands to
+	/// tell the mouse.
+	///
+	/// Returns `false` if the rate isn't one of `SAMPLE_RATES`, or the
+	/// commands don't fit in the queue.
+	pub fn set_sample_rate(&mut self, sample_rate: u8) -> bool {
+		if !SAMPLE_RATES.contains(&sample_rate) {
+			return false;
+		}
+		self.sample_rate = sample_rate;
+		self.queue_for_host(&[CMD_SET_SAMPLE_RATE, sample_rate])
+	}
+
+	/// Get the resolution (0 is 1 count/mm, up to 3 which is 8 counts/mm).
+	pub fn resolution(&self) -> u8 {
+		self.resolution
+	}
+
+	/// Set the resolution, and queue the commands to tell the mouse.
+	///
+	/// Returns `false` if the resolution is above `MAX_RESOLUTION`, or the
+	/// commands don't fit in the queue.
+	pub fn set_resolution(&mut self, resolution: u8) -> bool {
+		if resolution > MAX_RESOLUTION {
+			return false;
+		}
+		self.resolution = resolution;
+		self.queue_for_host(&[CMD_SET_RESOLUTION, resolution])
+	}
+
+	/// Queue some commands on behalf of the host, who will want to know how
+	/// they went (see [`Mouse::take_result`]).
+	fn queue_for_host(&mut self, bytes: &[u8]) -> bool {
+		if self.is_idle() {
+			self.failed = false;
+		}
+		let queued = self.queue(bytes);
+		self.report_result |= queued;
+		queued
+	}
+
+	/// Add some bytes to the command queue.
+	fn queue(&mut self, bytes: &[u8]) -> bool {
+		if self.is_idle() {
+			self.commands_len = 0;
+			self.commands_sent = 0;
+		}
+		let end = self.commands_len + bytes.len();
+		if end > COMMAND_QUEUE_LEN {
+			return false;
+		}
+		self.commands[self.commands_len..end].copy_from_slice(bytes);
+		self.commands_len = end;
+		true
+	}
+
+	/// Throw away the rest of the current batch of commands.
+	fn fail(&mut self) {
+		self.failed = true;
+		self.awaiting_ack = false;
+		self.commands_len = 0;
+		self.commands_sent = 0;
+	}
+
+	/// Have we sent all the commands, and had them all acknowledged?
+	pub fn is_idle(&self) -> bool {
+		!self.awaiting_ack && self.commands_sent == self.commands_len
+	}
+
+	/// Get the next byte to send to the mouse, if we aren't still waiting
+	/// for the last one to be acknowledged.
+	pub fn next_command(&mut self) -> Option<u8> {
+		if self.is_idle() || self.awaiting_ack {
+			return None;
+		}
+		let byte = self.commands[self.commands_sent];
+		self.commands_sent += 1;
+		self.awaiting_ack = true;
+		// The mouse throws away any packet it was half-way through sending
+		self.received = 0;
+		Some(byte)
+	}
+
+	/// The byte from [`Mouse::next_command`] has been clocked out. `acked` is
+	/// the acknowledge bit from the mouse.
+	pub fn command_sent(&mut self, acked: bool) {
+		if !acked {
+			self.fail();
+		}
+	}
+
+	/// We gave up waiting for the mouse to acknowledge the last command.
+	pub fn command_timeout(&mut self) {
+		if self.awaiting_ack {
+			self.fail();
+		}
+	}
+
+	/// Once the host's commands have all been sent, find out how they went.
+	///
+	/// Returns `COMMAND_OK` or `COMMAND_FAILED`, once only.
+	pub fn take_result(&mut self) -> Option<u8> {
+		if !self.report_result || !self.is_idle() {
+			return None;
+		}
+		self.report_result = false;
+		Some(if self.failed {
+			COMMAND_FAILED
+		} else {
+			COMMAND_OK
+		})
+	}
+}
+
+impl<const N: usize> Default for Mouse<N> {
+	fn default() -> Self {
+		Mouse::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn feed<const N: usize>(mouse: &mut Mouse<N>, bytes: &[u8]) -> Option<MouseReport> {
+		let mut report = None;
+		for &b in bytes {
+			report = mouse.update(b);
+		}
+		report
+	}
+
+	#[test]
+	fn three_byte_packets() {
+		let mut mouse: Mouse<4> = Mouse::new();
+		// Left button, 5 right, 3 down
+		let report = feed(&mut mouse, &[0x29, 0x05, 0xFD]).unwrap();
+		assert_eq!(report.dx(), 5);
+		assert_eq!(report.dy(), -3);
+		assert_eq!(report.as_bytes(), [0x29, 0x05, 0xFD, 0x00]);
+		// 256 left
+		feed(&mut mouse, &[0x18, 0x00, 0x00]);
+		assert_eq!(mouse.len(), 2);
+		assert_eq!(mouse.pop(), Some(report));
+		assert_eq!(
+			mouse.take_movement(),
+			MouseMovement {
+				buttons: 0,
+				dx: -251,
+				dy: -3,
+				wheel: 0
+			}
+		);
+		assert_eq!(mouse.take_movement().dx, 0);
+	}
+
+	#[test]
+	fn resync() {
+		let mut mouse: Mouse<4> = Mouse::new();
+		// A stray byte without the sync bit is skipped
+		assert_eq!(feed(&mut mouse, &[0x05, 0x08, 0x01, 0x01]).unwrap().dx(), 1);
+		// Plugging in a mouse sends 0xAA 0x00, which isn't a packet
+		mouse.set_wheel(true);
+		assert_eq!(feed(&mut mouse, &[0xAA, 0x00]), None);
+		assert!(!mouse.wheel());
+		assert!(feed(&mut mouse, &[0x08, 0x01, 0x01]).is_some());
+	}
+
+	/// Pretend to be a mouse which accepts everything.
+	fn send_all<const N: usize>(mouse: &mut Mouse<N>) -> Vec<u8> {
+		let mut sent = Vec::new();
+		while let Some(byte) = mouse.next_command() {
+			sent.push(byte);
+			mouse.command_sent(true);
+			assert_eq!(mouse.update(ACK), None);
+		}
+		sent
+	}
+
+	#[test]
+	fn settings() {
+		let mut mouse: Mouse<4> = Mouse::new();
+		assert!(mouse.is_idle());
+		assert!(!mouse.set_sample_rate(50));
+		assert!(!mouse.set_resolution(4));
+		assert!(mouse.set_sample_rate(200));
+		assert!(mouse.set_resolution(3));
+		// Not finished yet
+		assert_eq!(mouse.take_result(), None);
+		assert_eq!(send_all(&mut mouse), vec![0xF3, 200, 0xE8, 3]);
+		assert_eq!(mouse.take_result(), Some(COMMAND_OK));
+		assert_eq!(mouse.take_result(), None);
+		// When the mouse is plugged in, it gets our settings and is enabled
+		feed(&mut mouse, &[0xAA, 0x00]);
+		assert_eq!(send_all(&mut mouse), vec![0xF3, 200, 0xE8, 3, 0xF4]);
+		assert_eq!(mouse.take_result(), None);
+		assert!(feed(&mut mouse, &[0x08, 0x00, 0x00]).is_some());
+	}
+
+	#[test]
+	fn command_failures() {
+		let mut mouse: Mouse<4> = Mouse::new();
+		assert!(mouse.set_sample_rate(10));
+		// A resend sends the same byte again
+		assert_eq!(mouse.next_command(), Some(0xF3));
+		assert_eq!(mouse.next_command(), None);
+		mouse.command_sent(true);
+		mouse.update(RESEND);
+		assert_eq!(mouse.next_command(), Some(0xF3));
+		mouse.command_sent(true);
+		// No ACK, so give up on the rest
+		mouse.command_timeout();
+		assert!(mouse.is_idle());
+		assert_eq!(mouse.take_result(), Some(COMMAND_FAILED));
+		// No mouse at all
+		assert!(mouse.set_resolution(0));
+		assert_eq!(mouse.next_command(), Some(0xE8));
+		mouse.command_sent(false);
+		assert_eq!(mouse.next_command(), None);
+		assert_eq!(mouse.take_result(), Some(COMMAND_FAILED));
+		// And it works again next time
+		assert!(mouse.set_resolution(1));
+		send_all(&mut mouse);
+		assert_eq!(mouse.take_result(), Some(COMMAND_OK));
+	}
+
+	#[test]
+	fn wheel_and_overflow() {
+		let mut mouse: Mouse<2> = Mouse::new();
+		mouse.set_wheel(true);
+		let report = feed(&mut mouse, &[0x08, 0x00, 0x00, 0xFF]).unwrap();
+		assert_eq!(report.wheel, -1);
+		// Overflowed movement is ignored
+		feed(&mut mouse, &[0x48, 0x7F, 0x00, 0x00]);
+		// The FIFO drops the oldest
+		feed(&mut mouse, &[0x0A, 0x01, 0x00, 0x00]);
+		assert_eq!(mouse.len(), 2);
+		assert_eq!(mouse.pop().unwrap().flags, 0x48);
+		assert_eq!(
+			mouse.take_movement(),
+			MouseMovement {
+				buttons: 0x02,
+				dx: 1,
+				dy: 0,
+				wheel: -1
+			}
+		);
+	}
+}
@@ -0,0 +1,328 @@
+//! # Emulated CMOS NVRAM
+//!
+//! A PC keeps its BIOS settings in the battery-backed CMOS RAM next to the
+//! real-time clock. The main board has nothing like that, so we give the
+//! host [`NVRAM_LEN`] bytes which survive it losing power, without it needing
+//! its own flash driver or an EEPROM. The host reads and writes them here, in
+//! our RAM, and asks us to *commit* them to flash when it is done.
+//!
+//! They are kept in their own page of flash (see [`crate::config`]), as a
+//! series of records, each holding every byte along with a magic number, a
+//! layout version and a CRC-8. A commit appends a new record after the last
+//! one, and the last good record is the one we load. Only when the page is
+//! full do we erase it and start again at the front, so a 1 KiB page takes
+//! fifteen commits per erase. A commit which changes nothing doesn't write
+//! anything. If we lose power part way through writing a record, its CRC is
+//! wrong and we go back to the one before - but if we lose it whilst erasing,
+//! the NVRAM is lost, and reads as all zeros.
+
+/// How many bytes of NVRAM the host gets
+pub const NVRAM_LEN: usize = 64;
+
+/// How many bytes the NVRAM Window register shows at once
+pub const PAGE_LEN: usize = 16;
+
+/// How many pages the NVRAM Page register can choose from
+pub const PAGES: usize = NVRAM_LEN / PAGE_LEN;
+
+/// Nothing has been committed, so the NVRAM is all zeros
+pub const STATUS_BLANK: u8 = 0;
+
+/// The NVRAM holds what was last committed to flash
+pub const STATUS_LOADED: u8 = 1;
+
+/// The host has changed the NVRAM since it was loaded or committed
+pub const STATUS_CHANGED: u8 = 2;
+
+/// The last commit succeeded
+pub const STATUS_COMMITTED: u8 = 3;
+
+/// The last commit failed
+pub const STATUS_COMMIT_FAILED: u8 = 4;
+
+/// Write this to the NVRAM Control register to commit the NVRAM to flash
+pub const COMMAND_COMMIT: u8 = 1;
+
+/// Write this to the NVRAM Control register to set the NVRAM to all zeros
+/// (which isn't committed until the host asks)
+pub const COMMAND_CLEAR: u8 = 2;
+
+/// Marks the start of a valid record ("NV").
+const MAGIC: [u8; 2] = [0x4E, 0x56];
+
+/// Which version of the record layout this firmware writes.
+const LAYOUT_VERSION: u8 = 1;
+
+/// Where the layout version goes in a record, after the data
+const VERSION_OFFSET: usize = MAGIC.len() + NVRAM_LEN;
+
+/// Where the CRC goes in a record, at the end
+const CRC_OFFSET: usize = RECORD_SIZE - 1;
+
+/// How many bytes one record takes in flash - the magic number, the data,
+/// the layout version and the CRC.
+pub const RECORD_SIZE: usize = MAGIC.len() + NVRAM_LEN + 2;
+
+/// Convert the NVRAM to a record, for writing to flash.
+pub fn record(data: &[u8; NVRAM_LEN]) -> [u8; RECORD_SIZE] {
+	let mut bytes = [0u8; RECORD_SIZE];
+	bytes[0..MAGIC.len()].copy_from_slice(&MAGIC);
+	bytes[MAGIC.len()..VERSION_OFFSET].copy_from_slice(data);
+	bytes[VERSION_OFFSET] = LAYOUT_VERSION;
+	bytes[CRC_OFFSET] = neotron_bmc_protocol::calculate_crc(&bytes[0..CRC_OFFSET]);
+	bytes
+}
+
+/// Get the NVRAM back out of a record, if it is a good one.
+fn from_record(bytes: &[u8; RECORD_SIZE]) -> Option<[u8; NVRAM_LEN]> {
+	if bytes[0..MAGIC.len()] != MAGIC
+		|| bytes[VERSION_OFFSET] != LAYOUT_VERSION
+		|| neotron_bmc_protocol::calculate_crc(bytes) != 0
+	{
+		return None;
+	}
+	let mut data = [0u8; NVRAM_LEN];
+	data.copy_from_slice(&bytes[MAGIC.len()..VERSION_OFFSET]);
+	Some(data)
+}
+
+/// Is this record slot still erased flash?
+fn is_blank(bytes: &[u8; RECORD_SIZE]) -> bool {
+	bytes.iter().all(|&byte| byte == 0xFF)
+}
+
+/// What we found in the NVRAM page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NvramScan {
+	/// The last good record, if there is one
+	pub data: Option<[u8; NVRAM_LEN]>,
+	/// The first unused record slot, or `None` if the page is full (or holds
+	/// something which isn't records at all) and has to be erased
+	pub next_free: Option<usize>,
+}
+
+/// Work out what the NVRAM holds from the record slots in its page, in
+/// order.
+///
+/// Slots which start with the magic number but aren't good records (like one
+/// we lost power part way through writing) are skipped. A slot which doesn't
+/// start with it means the page holds something which isn't records at all
+/// (like the end of an older, larger firmware), so we stop there, and the
+/// page has to be erased.
+pub fn scan<I>(slots: I) -> NvramScan
+where
+	I: IntoIterator<Item = [u8; RECORD_SIZE]>,
+{
+	let mut data = None;
+	for (idx, slot) in slots.into_iter().enumerate() {
+		if is_blank(&slot) {
+			return NvramScan {
+				data,
+				next_free: Some(idx),
+			};
+		}
+		if slot[0..MAGIC.len()] != MAGIC {
+			return NvramScan {
+				data,
+				next_free: None,
+			};
+		}
+		if let Some(found) = from_record(&slot) {
+			data = Some(found);
+		}
+	}
+	NvramScan {
+		data,
+		next_free: None,
+	}
+}
+
+/// The host's copy of the NVRAM, and which page of it the NVRAM Window
+/// register shows.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Nvram {
+	/// The bytes
+	data: [u8; NVRAM_LEN],
+	/// The page the NVRAM Window register shows
+	page: usize,
+	/// See `STATUS_LOADED`, etc
+	status: u8,
+}
+
+impl Nvram {
+	/// Create a blank NVRAM.
+	pub const fn new() -> Nvram {
+		Nvram {
+			data: [0u8; NVRAM_LEN],
+			page: 0,
+			status: STATUS_BLANK,
+		}
+	}
+
+	/// Create the NVRAM from what we found in flash, if anything.
+	pub fn load(data: Option<[u8; NVRAM_LEN]>) -> Nvram {
+		match data {
+			Some(data) => Nvram {
+				data,
+				status: STATUS_LOADED,
+				..Nvram::new()
+			},
+			None => Nvram::new(),
+		}
+	}
+
+	/// All the bytes, for committing to flash.
+	pub fn data(&self) -> &[u8; NVRAM_LEN] {
+		&self.data
+	}
+
+	/// The NVRAM Control register (see `STATUS_LOADED`, etc).
+	pub fn status(&self) -> u8 {
+		self.status
+	}
+
+	/// Which page the NVRAM Window register shows.
+	pub fn page(&self) -> usize {
+		self.page
+	}
+
+	/// Choose which page the NVRAM Window register shows. Returns `false`
+	/// (and changes nothing) if there's no such page.
+	pub fn select(&mut self, page: usize) -> bool {
+		if page >= PAGES {
+			return false;
+		}
+		self.page = page;
+		true
+	}
+
+	/// The bytes the NVRAM Window register shows.
+	pub fn window(&self) -> &[u8] {
+		let start = self.page * PAGE_LEN;
+		&self.data[start..start + PAGE_LEN]
+	}
+
+	/// Change one byte of the page the NVRAM Window register shows.
+	pub fn write(&mut self, offset: usize, byte: u8) {
+		self.data[self.page * PAGE_LEN + offset] = byte;
+		self.status = STATUS_CHANGED;
+	}
+
+	/// Set every byte to zero.
+	pub fn clear(&mut self) {
+		self.data = [0u8; NVRAM_LEN];
+		self.status = STATUS_CHANGED;
+	}
+
+	/// A commit of `committed` has finished. If the host changed the NVRAM
+	/// again whilst it was being written, it still counts as changed.
+	pub fn committed(&mut self, committed: &[u8; NVRAM_LEN], ok: bool) {
+		if !ok {
+			self.status = STATUS_COMMIT_FAILED;
+		} else if self.data == *committed {
+			self.status = STATUS_COMMITTED;
+		}
+	}
+}
+
+impl Default for Nvram {
+	fn default() -> Self {
+		Nvram::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const BLANK: [u8; RECORD_SIZE] = [0xFF; RECORD_SIZE];
+
+	#[test]
+	fn records() {
+		let mut data = [0u8; NVRAM_LEN];
+		data[0] = 0x12;
+		data[NVRAM_LEN - 1] = 0x34;
+		let good = record(&data);
+		assert_eq!(good.len() % 2, 0);
+		assert_eq!(from_record(&good), Some(data));
+		let mut damaged = good;
+		damaged[10] ^= 0x01;
+		assert_eq!(from_record(&damaged), None);
+		assert_eq!(from_record(&BLANK), None);
+
+		// A blank page
+		assert_eq!(
+			scan([BLANK; 15]),
+			NvramScan {
+				data: None,
+				next_free: Some(0),
+			}
+		);
+		// The last good record wins, even after a damaged one
+		let mut newer = data;
+		newer[5] = 0x56;
+		assert_eq!(
+			scan([good, record(&newer), damaged, BLANK, BLANK]),
+			NvramScan {
+				data: Some(newer),
+				next_free: Some(3),
+			}
+		);
+		// A full page has to be erased
+		assert_eq!(
+			scan([good, good, record(&newer)]),
+			NvramScan {
+				data: Some(newer),
+				next_free: None,
+			}
+		);
+		// As does one holding something else entirely, even with blank slots
+		// after it
+		assert_eq!(
+			scan([[0u8; RECORD_SIZE], BLANK]),
+			NvramScan {
+				data: None,
+				next_free: None,
+			}
+		);
+		assert_eq!(
+			scan([good, [0u8; RECORD_SIZE], BLANK]),
+			NvramScan {
+				data: Some(data),
+				next_free: None,
+			}
+		);
+	}
+
+	#[test]
+	fn pages() {
+		let mut nvram = Nvram::load(None);
+		assert_eq!(nvram.status(), STATUS_BLANK);
+		assert!(nvram.select(2));
+		assert!(!nvram.select(PAGES));
+		assert_eq!(nvram.page(), 2);
+		nvram.write(3, 0xAA);
+		assert_eq!(nvram.status(), STATUS_CHANGED);
+		assert_eq!(nvram.data()[2 * PAGE_LEN + 3], 0xAA);
+		assert_eq!(nvram.window()[3], 0xAA);
+		assert!(nvram.select(0));
+		assert_eq!(nvram.window(), &[0u8; PAGE_LEN]);
+
+		// The host changes it again during the commit
+		let committed = *nvram.data();
+		nvram.write(0, 1);
+		nvram.committed(&committed, true);
+		assert_eq!(nvram.status(), STATUS_CHANGED);
+		let committed = *nvram.data();
+		nvram.committed(&committed, true);
+		assert_eq!(nvram.status(), STATUS_COMMITTED);
+		nvram.committed(&committed, false);
+		assert_eq!(nvram.status(), STATUS_COMMIT_FAILED);
+
+		let loaded = Nvram::load(Some(committed));
+		assert_eq!(loaded.status(), STATUS_LOADED);
+		assert_eq!(loaded.window()[0], 1);
+		nvram.clear();
+		assert_eq!(nvram.data(), &[0u8; NVRAM_LEN]);
+	}
+}
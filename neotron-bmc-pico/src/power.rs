@@ -0,0 +1,837 @@
+//! # Main Board Power
+//!
+//! The states the main board power can be in, and the things which move it
+//! between them (see [`crate::buttons`] for what the buttons do).
+//!
+//! The host can ask to be suspended, which keeps the DC power on but holds the
+//! main board in reset, so it can come back much faster than from off.
+//!
+//! This module only makes the decisions - driving the pins is left to the
+//! caller. The firmware's `power_manager` task owns a [`PowerManager`], and
+//! every other task asks it for changes by sending it a [`PowerRequest`], so
+//! only one place ever moves the main board between states.
+//!
+//! It also decides whether to power on by ourselves when the BMC powers up
+//! (after a mains outage, say), which can depend on the last power state. That
+//! is kept in a journal in flash - a list of half-word entries, each written
+//! once, so we don't wear out the flash by erasing it on every power change.
+//! The journal also holds the boot mode, which the OS sets to tell the BIOS
+//! what to do the next time the main board boots (see [`JOURNAL_BOOT_MODE`]).
+//!
+//! A BMC on the bench, without a main board, shouldn't turn on a DC supply
+//! with nothing on the other end. If the main board ties one of the GPIO
+//! pins to ground, we can be told to look for that strap, and refuse to power
+//! on without it.
+//!
+//! The power LED is dimmed by PWM. Whilst the main board is off it slowly
+//! breathes in and out (or blinks, as older firmware did, or stays dark), and
+//! whilst it is running it is lit at a brightness the host chooses.
+
+use crate::eventlog::{EventKind, PowerOnSource, ResetSource};
+
+/// The states we can be in controlling the DC power
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum DcPowerState {
+	/// We've just enabled the DC power (so ignore any incoming long presses!)
+	Starting = 1,
+	/// We are now fully on. Look for a long press to turn off.
+	On = 2,
+	/// We are fully off, and the BMC is in low-power standby mode.
+	Off = 0,
+	/// The DC power is on, but the main board is held in reset until the
+	/// power button or a wake source resumes it.
+	Suspended = 3,
+}
+
+impl DcPowerState {
+	/// Convert from the number we store it as.
+	pub fn from_u8(value: u8) -> Option<DcPowerState> {
+		match value {
+			0 => Some(DcPowerState::Off),
+			1 => Some(DcPowerState::Starting),
+			2 => Some(DcPowerState::On),
+			3 => Some(DcPowerState::Suspended),
+			_ => None,
+		}
+	}
+}
+
+/// Stay off when the BMC powers up, until something powers us on (the default)
+pub const RESTORE_OFF: u8 = 0;
+/// Always power on when the BMC powers up
+pub const RESTORE_ON: u8 = 1;
+/// Power on when the BMC powers up if the main board was on when power was lost
+pub const RESTORE_LAST: u8 = 2;
+
+/// The longest power-on delay, in seconds
+pub const MAX_POWER_ON_DELAY_S: u8 = 63;
+
+/// Don't look for a main board - assume there is one (the default)
+pub const PRESENCE_OFF: u8 = 0;
+/// The main board is there if it pulls GPIO 0 low
+pub const PRESENCE_GPIO0: u8 = 1;
+/// The main board is there if it pulls GPIO 1 low
+pub const PRESENCE_GPIO1: u8 = 2;
+/// Set in the Main Board Presence register when we think there is a main
+/// board
+pub const PRESENCE_DETECTED: u8 = 1 << 7;
+
+/// A power state journal entry meaning the main board was on ("ON")
+pub const JOURNAL_ON: u16 = 0x4E4F;
+/// A power state journal entry meaning the main board was off
+pub const JOURNAL_OFF: u16 = 0x0000;
+/// An unused power state journal entry (erased flash)
+pub const JOURNAL_BLANK: u16 = 0xFFFF;
+/// A journal entry with this in its top byte holds the boot mode in its
+/// bottom byte, and says nothing about the power state
+pub const JOURNAL_BOOT_MODE: u16 = 0xB000;
+/// The top byte of a journal entry, which says what kind it is
+const JOURNAL_KIND_MASK: u16 = 0xFF00;
+
+/// The main board boots as usual (the default)
+pub const BOOT_MODE_NORMAL: u8 = 0;
+/// The main board boots into the BIOS settings
+pub const BOOT_MODE_SETTINGS: u8 = 1;
+/// The main board boots into recovery
+pub const BOOT_MODE_RECOVERY: u8 = 2;
+
+/// The journal entry which records a boot mode.
+pub fn boot_mode_entry(mode: u8) -> u16 {
+	JOURNAL_BOOT_MODE | u16::from(mode)
+}
+
+/// Should we power on when the BMC powers up?
+///
+/// `policy` is one of `RESTORE_OFF`, `RESTORE_ON` or `RESTORE_LAST`, and
+/// `was_on` is the last state in the power state journal, if there is one.
+pub fn restore_power(policy: u8, was_on: Option<bool>) -> bool {
+	match policy {
+		RESTORE_ON => true,
+		RESTORE_LAST => was_on == Some(true),
+		_ => false,
+	}
+}
+
+/// What we found in the power state journal.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct JournalScan {
+	/// Was the main board on, according to the last power state entry?
+	/// `None` if there are none.
+	pub was_on: Option<bool>,
+	/// The boot mode in the last boot mode entry, or `None` if there are
+	/// none
+	pub boot_mode: Option<u8>,
+	/// The index of the first unused entry, or `None` if the journal is full
+	pub next_free: Option<usize>,
+}
+
+/// Look through the power state journal entries, in order.
+///
+/// Entries are written in order, so the first blank entry marks the end.
+/// Anything we don't recognise counts as off.
+pub fn scan_journal<I>(entries: I) -> JournalScan
+where
+	I: IntoIterator<Item = u16>,
+{
+	let mut was_on = None;
+	let mut boot_mode = None;
+	for (idx, entry) in entries.into_iter().enumerate() {
+		if entry == JOURNAL_BLANK {
+			return JournalScan {
+				was_on,
+				boot_mode,
+				next_free: Some(idx),
+			};
+		}
+		if (entry & JOURNAL_KIND_MASK) == JOURNAL_BOOT_MODE {
+			boot_mode = Some(entry as u8);
+		} else {
+			was_on = Some(entry == JOURNAL_ON);
+		}
+	}
+	JournalScan {
+		was_on,
+		boot_mode,
+		next_free: None,
+	}
+}
+
+/// Which GPIO pin has the presence strap on it, if we are looking for one.
+pub fn presence_pin(presence: u8) -> Option<u8> {
+	match presence {
+		PRESENCE_GPIO0 => Some(0),
+		PRESENCE_GPIO1 => Some(1),
+		_ => None,
+	}
+}
+
+/// Is there a main board to power?
+///
+/// `presence` is one of `PRESENCE_OFF`, `PRESENCE_GPIO0` or `PRESENCE_GPIO1`,
+/// and `gpio_input` is the level on each GPIO pin. In a build without GPIO
+/// pins the levels all read low, so we assume there is a main board.
+pub fn board_present(presence: u8, gpio_input: u8) -> bool {
+	match presence_pin(presence) {
+		Some(pin) => (gpio_input & (1 << pin)) == 0,
+		None => true,
+	}
+}
+
+/// The PWM compare value which makes the power LED look `level` percent
+/// bright, from a timer which counts to `period`.
+///
+/// Our eyes notice changes in dim light much more than in bright light, so
+/// the duty cycle goes up as the square of the level.
+pub fn led_duty(level: u8, period: u16) -> u16 {
+	let level = u32::from(level.min(100));
+	(u32::from(period) * level * level / 10_000) as u16
+}
+
+/// Something the power button (or the host) asked us to do.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum PowerAction {
+	/// Turn the DC power on, and go to [`DcPowerState::Starting`].
+	PowerOn,
+	/// The power button was released after powering on, so go to
+	/// [`DcPowerState::On`].
+	Released,
+	/// Turn the DC power off, and go to [`DcPowerState::Off`].
+	PowerOff,
+	/// Put the main board in reset, and go to [`DcPowerState::Suspended`].
+	Suspend,
+	/// Take the main board out of reset, and go to
+	/// [`DcPowerState::Starting`] until the power button is released.
+	Resume,
+}
+
+impl PowerAction {
+	/// The state we are in once this action has been carried out.
+	pub fn next_state(self) -> DcPowerState {
+		match self {
+			PowerAction::PowerOn => DcPowerState::Starting,
+			PowerAction::Released => DcPowerState::On,
+			PowerAction::PowerOff => DcPowerState::Off,
+			PowerAction::Suspend => DcPowerState::Suspended,
+			PowerAction::Resume => DcPowerState::Starting,
+		}
+	}
+}
+
+/// Length of a reset pulse, in milliseconds, unless the host asks for another
+pub const RESET_DURATION_MS: u32 = 250;
+
+/// The shortest reset pulse the host can ask for, in milliseconds
+pub const MIN_RESET_DURATION_MS: u32 = 50;
+
+/// The longest reset pulse the host can ask for, in milliseconds
+pub const MAX_RESET_DURATION_MS: u32 = 2550;
+
+/// How long the power LED is lit, and then dark, when blinking whilst off
+pub const LED_PERIOD_MS: u32 = 1000;
+
+/// How long one breath of the power LED takes, whilst off
+pub const BREATHE_PERIOD_MS: u32 = 4000;
+
+/// How often the power LED changes brightness whilst breathing
+pub const BREATHE_STEP_MS: u32 = 40;
+
+/// The power LED breathes whilst the main board is off (the default)
+pub const LED_BREATHE: u8 = 0;
+/// The power LED blinks once a second whilst the main board is off
+pub const LED_BLINK: u8 = 1;
+/// The power LED stays dark whilst the main board is off
+pub const LED_DARK: u8 = 2;
+
+/// How long the power LED goes out for when the host reports disk activity,
+/// and how long it then stays lit before it can go out again.
+pub const ACTIVITY_FLICKER_MS: u32 = 40;
+
+/// Something we want the power manager to do.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum PowerRequest {
+	/// The buttons want this done
+	Button(PowerAction),
+	/// The host wrote this to the Power Control register
+	Host(PowerAction),
+	/// Power on (or resume), because of this wake source. Does nothing if
+	/// the main board is already running.
+	Wake(PowerOnSource),
+	/// Pulse the main board reset line for this many milliseconds. Does
+	/// nothing if a pulse is already running.
+	Reset(ResetSource, u32),
+	/// The main board drew too much current, so cut the power.
+	OverCurrent,
+	/// The host didn't shut down in time when it got too hot (this many
+	/// °C), so cut the power.
+	OverTemperature(i8),
+	/// The host reported disk activity, so flicker the power LED.
+	Activity,
+	/// Our supply is sagging, so hold the main board in reset before its
+	/// rails collapse.
+	LowVoltage,
+	/// Our supply has recovered, so let the main board out of reset.
+	VoltageRestored,
+	/// Change the power LED settings - what it does whilst off (see
+	/// `LED_BREATHE`, etc), and how bright it is, in percent.
+	Led(u8, u8),
+	/// Both buttons were held down (see [`crate::failsafe`]), so cut the
+	/// power, whatever state we are in.
+	ForcedOff,
+}
+
+/// What happened when the power manager handled a request.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct PowerOutcome {
+	/// Something to put in the event log, as a kind and its data
+	pub event: Option<(EventKind, u8)>,
+	/// The main board has just started running (so look for boot keys)
+	pub started: bool,
+}
+
+/// The main board power state machine.
+///
+/// Feed it [`PowerRequest`]s, and drive the pins as it says - the DC power
+/// from [`PowerManager::dc_on`], the reset line from
+/// [`PowerManager::in_reset`], the power LED from [`PowerManager::led_on`],
+/// and low-power standby whenever the DC power is off.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PowerManager {
+	/// Where we are
+	state: DcPowerState,
+	/// When the reset pulse we are sending started, if we are sending one
+	reset_since_ms: Option<u32>,
+	/// How long the reset pulse we are sending lasts, in milliseconds
+	reset_ms: u32,
+	/// When the power LED last went out for disk activity, if it still
+	/// matters
+	flicker_since_ms: Option<u32>,
+	/// More disk activity arrived whilst the LED was flickering
+	flicker_again: bool,
+	/// When we powered off, so the LED blinks from then on
+	off_since_ms: u32,
+	/// Is our supply too low to trust the main board?
+	low_voltage: bool,
+	/// Is there a main board to power?
+	board_present: bool,
+	/// What the power LED does whilst off (see `LED_BREATHE`, etc)
+	led_mode: u8,
+	/// How bright the power LED is when lit, in percent
+	led_brightness: u8,
+}
+
+impl PowerManager {
+	/// Create the state machine, with the main board off.
+	pub const fn new() -> PowerManager {
+		PowerManager {
+			state: DcPowerState::Off,
+			reset_since_ms: None,
+			reset_ms: RESET_DURATION_MS,
+			flicker_since_ms: None,
+			flicker_again: false,
+			off_since_ms: 0,
+			low_voltage: false,
+			board_present: true,
+			led_mode: LED_BREATHE,
+			led_brightness: 100,
+		}
+	}
+
+	/// Where we are.
+	pub fn state(&self) -> DcPowerState {
+		self.state
+	}
+
+	/// Should the DC power be on?
+	pub fn dc_on(&self) -> bool {
+		self.state != DcPowerState::Off
+	}
+
+	/// Should the main board be held in reset?
+	pub fn in_reset(&self) -> bool {
+		matches!(self.state, DcPowerState::Off | DcPowerState::Suspended)
+			|| self.reset_since_ms.is_some()
+			|| self.low_voltage
+	}
+
+	/// How bright should the power LED be, in percent?
+	///
+	/// It breathes (or blinks) whilst off, is dark whilst suspended, and is
+	/// lit whilst running - except when flickering for disk activity.
+	pub fn led_level(&self, now_ms: u32) -> u8 {
+		let lit = match self.state {
+			DcPowerState::Off => {
+				let off_ms = now_ms.wrapping_sub(self.off_since_ms);
+				match self.led_mode {
+					LED_BLINK => {
+						if (off_ms / LED_PERIOD_MS) % 2 == 1 {
+							100
+						} else {
+							0
+						}
+					}
+					LED_DARK => 0,
+					_ => {
+						// Up and down again, starting dark
+						let phase = (off_ms % BREATHE_PERIOD_MS) * 200 / BREATHE_PERIOD_MS;
+						if phase < 100 {
+							phase
+						} else {
+							200 - phase
+						}
+					}
+				}
+			}
+			DcPowerState::Suspended => 0,
+			DcPowerState::Starting | DcPowerState::On => match self.flicker_since_ms {
+				Some(since_ms) if now_ms.wrapping_sub(since_ms) < ACTIVITY_FLICKER_MS => 0,
+				_ => 100,
+			},
+		};
+		(lit * u32::from(self.led_brightness) / 100) as u8
+	}
+
+	/// Should the power LED be lit at all?
+	pub fn led_on(&self, now_ms: u32) -> bool {
+		self.led_level(now_ms) != 0
+	}
+
+	/// How long until one of the outputs might change by itself, in ms, or
+	/// `None` if they only change when a request arrives.
+	pub fn next_change_ms(&self, now_ms: u32) -> Option<u32> {
+		let mut next_ms = None;
+		let mut wait_for = |since_ms: u32, period_ms: u32| {
+			let left_ms = period_ms - (now_ms.wrapping_sub(since_ms) % period_ms);
+			next_ms = Some(next_ms.map_or(left_ms, |next_ms: u32| next_ms.min(left_ms)));
+		};
+		if let Some(since_ms) = self.reset_since_ms {
+			wait_for(since_ms, self.reset_ms);
+		}
+		if let Some(since_ms) = self.flicker_since_ms {
+			wait_for(since_ms, ACTIVITY_FLICKER_MS);
+		}
+		if self.state == DcPowerState::Off {
+			match self.led_mode {
+				LED_BLINK => wait_for(self.off_since_ms, LED_PERIOD_MS),
+				LED_DARK => {}
+				_ => wait_for(self.off_since_ms, BREATHE_STEP_MS),
+			}
+		}
+		next_ms
+	}
+
+	/// Say whether there is a main board. Without one, we refuse to turn the
+	/// DC power on (but leave it on if it already is).
+	pub fn set_board_present(&mut self, present: bool) {
+		self.board_present = present;
+	}
+
+	/// Finish any reset pulse or LED flicker which has run its course. Call
+	/// this before reading the outputs.
+	pub fn poll(&mut self, now_ms: u32) {
+		if let Some(since_ms) = self.reset_since_ms {
+			if now_ms.wrapping_sub(since_ms) >= self.reset_ms {
+				self.reset_since_ms = None;
+			}
+		}
+		if let Some(since_ms) = self.flicker_since_ms {
+			if now_ms.wrapping_sub(since_ms) >= 2 * ACTIVITY_FLICKER_MS {
+				// However often the host reports activity, the LED stays lit
+				// for a while between flickers, so it is a visible flicker.
+				self.flicker_since_ms = if core::mem::replace(&mut self.flicker_again, false) {
+					Some(now_ms)
+				} else {
+					None
+				};
+			}
+		}
+	}
+
+	/// Handle a request, and say what happened.
+	pub fn handle(&mut self, request: PowerRequest, now_ms: u32) -> PowerOutcome {
+		self.poll(now_ms);
+		let mut outcome = PowerOutcome::default();
+		match (request, self.state) {
+			// This comes first, so nothing can get in its way
+			(PowerRequest::ForcedOff, state) => {
+				self.reset_since_ms = None;
+				if state != DcPowerState::Off {
+					self.power_off(now_ms);
+					outcome.event = Some((EventKind::ForcedOff, 0));
+				}
+			}
+			(PowerRequest::Button(PowerAction::PowerOn), DcPowerState::Off)
+				if !self.board_present =>
+			{
+				outcome.event = Some((EventKind::NoMainBoard, PowerOnSource::Button as u8));
+			}
+			(PowerRequest::Wake(source), DcPowerState::Off) if !self.board_present => {
+				outcome.event = Some((EventKind::NoMainBoard, source as u8));
+			}
+			(PowerRequest::Button(PowerAction::PowerOn), DcPowerState::Off) => {
+				self.state = DcPowerState::Starting;
+				outcome.event = Some((EventKind::PowerOn, PowerOnSource::Button as u8));
+				outcome.started = true;
+			}
+			(PowerRequest::Button(PowerAction::Released), DcPowerState::Starting) => {
+				self.state = DcPowerState::On;
+			}
+			(PowerRequest::Button(PowerAction::Resume), DcPowerState::Suspended) => {
+				self.state = DcPowerState::Starting;
+				outcome.event = Some((EventKind::Resume, PowerOnSource::Button as u8));
+				outcome.started = true;
+			}
+			(PowerRequest::Button(PowerAction::PowerOff), DcPowerState::On)
+			| (PowerRequest::Host(PowerAction::PowerOff), DcPowerState::Starting)
+			| (PowerRequest::Host(PowerAction::PowerOff), DcPowerState::On)
+			| (PowerRequest::Host(PowerAction::PowerOff), DcPowerState::Suspended) => {
+				self.power_off(now_ms);
+				outcome.event = Some((EventKind::PowerOff, 0));
+			}
+			// We only suspend a system which is fully on
+			(PowerRequest::Host(PowerAction::Suspend), DcPowerState::On) => {
+				self.state = DcPowerState::Suspended;
+				outcome.event = Some((EventKind::Suspend, 0));
+			}
+			(PowerRequest::Host(PowerAction::PowerOn), _) => {
+				return self.handle(PowerRequest::Wake(PowerOnSource::PowerControl), now_ms);
+			}
+			// There's no button to release, so go straight to On.
+			(PowerRequest::Wake(source), DcPowerState::Off) => {
+				self.state = DcPowerState::On;
+				outcome.event = Some((EventKind::PowerOn, source as u8));
+				outcome.started = true;
+			}
+			(PowerRequest::Wake(source), DcPowerState::Suspended) => {
+				self.state = DcPowerState::On;
+				outcome.event = Some((EventKind::Resume, source as u8));
+				outcome.started = true;
+			}
+			(PowerRequest::Reset(source, duration_ms), _) if self.reset_since_ms.is_none() => {
+				self.reset_since_ms = Some(now_ms);
+				self.reset_ms = duration_ms.clamp(MIN_RESET_DURATION_MS, MAX_RESET_DURATION_MS);
+				outcome.event = Some((EventKind::Reset, source as u8));
+			}
+			(PowerRequest::OverCurrent, state) if state != DcPowerState::Off => {
+				self.power_off(now_ms);
+				outcome.event = Some((EventKind::OverCurrent, 0));
+			}
+			(PowerRequest::OverTemperature(temperature_c), state) if state != DcPowerState::Off => {
+				self.power_off(now_ms);
+				outcome.event = Some((EventKind::OverTemperature, temperature_c as u8));
+			}
+			(PowerRequest::LowVoltage, _) if !self.low_voltage => {
+				self.low_voltage = true;
+				outcome.event = Some((EventKind::LowVoltage, 0));
+			}
+			(PowerRequest::VoltageRestored, _) => {
+				self.low_voltage = false;
+			}
+			(PowerRequest::Led(mode, brightness), _) => {
+				self.led_mode = mode;
+				self.led_brightness = brightness.min(100);
+			}
+			(PowerRequest::Activity, DcPowerState::Starting | DcPowerState::On) => {
+				if self.flicker_since_ms.is_none() {
+					self.flicker_since_ms = Some(now_ms);
+				} else {
+					self.flicker_again = true;
+				}
+			}
+			_ => {
+				// Nothing to do in this state
+			}
+		}
+		outcome
+	}
+
+	/// Go to [`DcPowerState::Off`].
+	fn power_off(&mut self, now_ms: u32) {
+		self.state = DcPowerState::Off;
+		self.off_since_ms = now_ms;
+		self.flicker_since_ms = None;
+		self.flicker_again = false;
+	}
+}
+
+impl Default for PowerManager {
+	fn default() -> Self {
+		PowerManager::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn power_restore() {
+		assert!(!restore_power(RESTORE_OFF, Some(true)));
+		assert!(restore_power(RESTORE_ON, None));
+		assert!(restore_power(RESTORE_LAST, Some(true)));
+		assert!(!restore_power(RESTORE_LAST, Some(false)));
+		assert!(!restore_power(RESTORE_LAST, None));
+		assert!(!restore_power(3, Some(true)));
+	}
+
+	#[test]
+	fn journal() {
+		assert_eq!(
+			scan_journal([JOURNAL_BLANK; 4].iter().copied()),
+			JournalScan {
+				was_on: None,
+				boot_mode: None,
+				next_free: Some(0)
+			}
+		);
+		let entries = [JOURNAL_ON, JOURNAL_OFF, JOURNAL_ON, JOURNAL_BLANK];
+		assert_eq!(
+			scan_journal(entries.iter().copied()),
+			JournalScan {
+				was_on: Some(true),
+				boot_mode: None,
+				next_free: Some(3)
+			}
+		);
+		let entries = [JOURNAL_ON, JOURNAL_OFF, JOURNAL_ON, 0x1234];
+		assert_eq!(
+			scan_journal(entries.iter().copied()),
+			JournalScan {
+				was_on: Some(false),
+				boot_mode: None,
+				next_free: None
+			}
+		);
+		// Boot mode entries don't change the power state
+		let entries = [
+			JOURNAL_ON,
+			boot_mode_entry(BOOT_MODE_SETTINGS),
+			boot_mode_entry(BOOT_MODE_NORMAL),
+			JOURNAL_BLANK,
+		];
+		assert_eq!(
+			scan_journal(entries.iter().copied()),
+			JournalScan {
+				was_on: Some(true),
+				boot_mode: Some(BOOT_MODE_NORMAL),
+				next_free: Some(3)
+			}
+		);
+		let entries = [boot_mode_entry(BOOT_MODE_RECOVERY), JOURNAL_OFF];
+		assert_eq!(
+			scan_journal(entries.iter().copied()).boot_mode,
+			Some(BOOT_MODE_RECOVERY)
+		);
+	}
+
+	#[test]
+	fn power_manager() {
+		let mut power = PowerManager::new();
+		// Blink whilst off, like older firmware
+		power.handle(PowerRequest::Led(LED_BLINK, 100), 0);
+		assert!(!power.dc_on());
+		assert!(power.in_reset());
+		assert!(!power.led_on(0));
+		assert!(power.led_on(LED_PERIOD_MS));
+		assert_eq!(power.next_change_ms(10), Some(LED_PERIOD_MS - 10));
+		// Things which don't apply whilst off
+		assert_eq!(
+			power.handle(PowerRequest::Host(PowerAction::Suspend), 0),
+			PowerOutcome::default()
+		);
+		assert_eq!(
+			power.handle(PowerRequest::OverCurrent, 0),
+			PowerOutcome::default()
+		);
+		// The power button
+		let outcome = power.handle(PowerRequest::Button(PowerAction::PowerOn), 100);
+		assert_eq!(outcome.event, Some((EventKind::PowerOn, 0)));
+		assert!(outcome.started);
+		assert_eq!(power.state(), DcPowerState::Starting);
+		assert!(power.dc_on());
+		assert!(!power.in_reset());
+		assert_eq!(power.next_change_ms(100), None);
+		power.handle(PowerRequest::Button(PowerAction::Released), 200);
+		assert_eq!(power.state(), DcPowerState::On);
+		// Waking when already on does nothing
+		assert_eq!(
+			power.handle(PowerRequest::Wake(PowerOnSource::Uart), 300),
+			PowerOutcome::default()
+		);
+		// A reset pulse, and a second one whilst the first is running
+		let outcome = power.handle(
+			PowerRequest::Reset(ResetSource::Keyboard, RESET_DURATION_MS),
+			1000,
+		);
+		assert_eq!(outcome.event, Some((EventKind::Reset, 1)));
+		assert!(power.in_reset());
+		assert_eq!(
+			power.handle(
+				PowerRequest::Reset(ResetSource::Button, RESET_DURATION_MS),
+				1100
+			),
+			PowerOutcome::default()
+		);
+		assert_eq!(power.next_change_ms(1100), Some(150));
+		power.poll(1000 + RESET_DURATION_MS);
+		assert!(!power.in_reset());
+		// The host can ask for a longer pulse, within limits
+		let outcome = power.handle(PowerRequest::Reset(ResetSource::Host, 500), 1300);
+		assert_eq!(outcome.event, Some((EventKind::Reset, 3)));
+		assert_eq!(power.next_change_ms(1300), Some(500));
+		power.poll(1799);
+		assert!(power.in_reset());
+		power.poll(1800);
+		assert!(!power.in_reset());
+		power.handle(PowerRequest::Reset(ResetSource::Host, 1), 1850);
+		assert_eq!(power.next_change_ms(1850), Some(MIN_RESET_DURATION_MS));
+		power.poll(1850 + MIN_RESET_DURATION_MS);
+		assert!(!power.in_reset());
+		// Disk activity
+		power.handle(PowerRequest::Activity, 2000);
+		assert!(!power.led_on(2000));
+		assert_eq!(power.next_change_ms(2000), Some(ACTIVITY_FLICKER_MS));
+		// More activity waits until the LED has been lit for a while
+		power.handle(PowerRequest::Activity, 2000 + ACTIVITY_FLICKER_MS);
+		assert!(power.led_on(2000 + ACTIVITY_FLICKER_MS));
+		power.poll(2000 + 2 * ACTIVITY_FLICKER_MS);
+		assert!(!power.led_on(2000 + 2 * ACTIVITY_FLICKER_MS));
+		power.poll(2000 + 4 * ACTIVITY_FLICKER_MS);
+		assert!(power.led_on(2000 + 4 * ACTIVITY_FLICKER_MS));
+		assert_eq!(power.next_change_ms(2000 + 4 * ACTIVITY_FLICKER_MS), None);
+		// Suspend, and wake
+		let outcome = power.handle(PowerRequest::Host(PowerAction::Suspend), 3000);
+		assert_eq!(outcome.event, Some((EventKind::Suspend, 0)));
+		assert!(power.dc_on());
+		assert!(power.in_reset());
+		assert!(!power.led_on(3000));
+		let outcome = power.handle(PowerRequest::Wake(PowerOnSource::Keyboard), 4000);
+		assert_eq!(outcome.event, Some((EventKind::Resume, 1)));
+		assert!(outcome.started);
+		assert_eq!(power.state(), DcPowerState::On);
+		// Faults
+		let outcome = power.handle(PowerRequest::OverTemperature(-2), 5000);
+		assert_eq!(outcome.event, Some((EventKind::OverTemperature, 0xFE)));
+		assert!(!power.dc_on());
+		// The host powers on, which counts as a wake
+		let outcome = power.handle(PowerRequest::Host(PowerAction::PowerOn), 6000);
+		assert_eq!(outcome.event, Some((EventKind::PowerOn, 4)));
+		assert_eq!(power.state(), DcPowerState::On);
+		// A sagging supply holds the main board in reset, but leaves the
+		// power on, and is only logged once
+		let outcome = power.handle(PowerRequest::LowVoltage, 7000);
+		assert_eq!(outcome.event, Some((EventKind::LowVoltage, 0)));
+		assert!(power.dc_on());
+		assert!(power.in_reset());
+		assert_eq!(
+			power.handle(PowerRequest::LowVoltage, 7100),
+			PowerOutcome::default()
+		);
+		power.poll(7000 + RESET_DURATION_MS);
+		assert!(power.in_reset());
+		power.handle(PowerRequest::VoltageRestored, 8000);
+		assert!(!power.in_reset());
+		assert_eq!(power.state(), DcPowerState::On);
+	}
+
+	#[test]
+	fn forced_off() {
+		// Whilst off, there's nothing to do
+		let mut power = PowerManager::new();
+		assert_eq!(
+			power.handle(PowerRequest::ForcedOff, 0),
+			PowerOutcome::default()
+		);
+		assert!(!power.dc_on());
+		// Whilst the power button is still held after powering on, when the
+		// power button itself can't power off
+		power.handle(PowerRequest::Button(PowerAction::PowerOn), 100);
+		assert_eq!(power.state(), DcPowerState::Starting);
+		assert_eq!(
+			power.handle(PowerRequest::Button(PowerAction::PowerOff), 200),
+			PowerOutcome::default()
+		);
+		let outcome = power.handle(PowerRequest::ForcedOff, 300);
+		assert_eq!(outcome.event, Some((EventKind::ForcedOff, 0)));
+		assert!(!outcome.started);
+		assert_eq!(power.state(), DcPowerState::Off);
+		assert!(!power.dc_on());
+		// Whilst running, in the middle of a reset pulse
+		power.handle(PowerRequest::Wake(PowerOnSource::Uart), 1000);
+		power.handle(
+			PowerRequest::Reset(ResetSource::Button, RESET_DURATION_MS),
+			1100,
+		);
+		let outcome = power.handle(PowerRequest::ForcedOff, 1200);
+		assert_eq!(outcome.event, Some((EventKind::ForcedOff, 0)));
+		assert!(!power.dc_on());
+		// The reset pulse doesn't carry on into the next power-on
+		power.handle(PowerRequest::Wake(PowerOnSource::Uart), 1250);
+		assert!(!power.in_reset());
+		// Whilst suspended
+		power.handle(PowerRequest::Host(PowerAction::Suspend), 2000);
+		assert_eq!(power.state(), DcPowerState::Suspended);
+		power.handle(PowerRequest::ForcedOff, 2100);
+		assert!(!power.dc_on());
+		// Whilst our supply is low, and the main board has gone missing
+		power.handle(PowerRequest::Wake(PowerOnSource::Uart), 3000);
+		power.handle(PowerRequest::LowVoltage, 3100);
+		power.set_board_present(false);
+		let outcome = power.handle(PowerRequest::ForcedOff, 3200);
+		assert_eq!(outcome.event, Some((EventKind::ForcedOff, 0)));
+		assert!(!power.dc_on());
+	}
+
+	#[test]
+	fn power_led() {
+		let mut power = PowerManager::new();
+		// Breathing, starting dark
+		assert_eq!(power.led_level(0), 0);
+		assert_eq!(power.led_level(BREATHE_PERIOD_MS / 4), 50);
+		assert_eq!(power.led_level(BREATHE_PERIOD_MS / 2), 100);
+		assert_eq!(power.led_level(BREATHE_PERIOD_MS * 3 / 4), 50);
+		assert_eq!(power.next_change_ms(10), Some(BREATHE_STEP_MS - 10));
+		// Dimmer
+		power.handle(PowerRequest::Led(LED_BREATHE, 40), 0);
+		assert_eq!(power.led_level(BREATHE_PERIOD_MS / 2), 40);
+		// Dark whilst off, so there's nothing to wake up for
+		power.handle(PowerRequest::Led(LED_DARK, 40), 0);
+		assert_eq!(power.led_level(BREATHE_PERIOD_MS / 2), 0);
+		assert_eq!(power.next_change_ms(10), None);
+		// The brightness applies whilst running too
+		power.handle(PowerRequest::Wake(PowerOnSource::Uart), 100);
+		assert_eq!(power.led_level(100), 40);
+		power.handle(PowerRequest::Led(LED_DARK, 250), 200);
+		assert_eq!(power.led_level(200), 100);
+		// Squared, for our eyes
+		assert_eq!(led_duty(0, 1000), 0);
+		assert_eq!(led_duty(50, 1000), 250);
+		assert_eq!(led_duty(100, 1000), 1000);
+		assert_eq!(led_duty(200, 1000), 1000);
+	}
+
+	#[test]
+	fn board_presence() {
+		assert!(board_present(PRESENCE_OFF, 0b11));
+		assert!(board_present(PRESENCE_GPIO0, 0b10));
+		assert!(!board_present(PRESENCE_GPIO0, 0b01));
+		assert!(!board_present(PRESENCE_GPIO1, 0b10));
+		assert!(board_present(3, 0b11));
+		let mut power = PowerManager::new();
+		power.set_board_present(false);
+		let outcome = power.handle(PowerRequest::Button(PowerAction::PowerOn), 0);
+		assert_eq!(outcome.event, Some((EventKind::NoMainBoard, 0)));
+		assert!(!outcome.started);
+		let outcome = power.handle(PowerRequest::Host(PowerAction::PowerOn), 100);
+		assert_eq!(outcome.event, Some((EventKind::NoMainBoard, 4)));
+		assert!(!power.dc_on());
+		// A board which goes missing once powered is left alone
+		power.set_board_present(true);
+		power.handle(PowerRequest::Wake(PowerOnSource::Uart), 200);
+		power.set_board_present(false);
+		power.handle(PowerRequest::Host(PowerAction::Suspend), 300);
+		let outcome = power.handle(PowerRequest::Wake(PowerOnSource::Keyboard), 400);
+		assert_eq!(outcome.event, Some((EventKind::Resume, 1)));
+		assert!(power.dc_on());
+	}
+}
@@ -2,6 +2,16 @@
 //!
 //! Like the one in 'pc_keyboard' but simpler. Designed for use when you want to
 //! collect the bits but not decode the bytes.
+//!
+//! There's also an encoder, for sending commands to a PS/2 device, a link,
+//! which does whichever of the two each clock edge needs, and an arbiter,
+//! which makes sure only one port sends at a time.
+//!
+//! A word which arrives damaged is counted (see [`Ps2Errors`]) by what was
+//! wrong with it, and we ask the device to send it again.
+
+/// The command which asks a PS/2 device to send its last byte again
+pub const RESEND: u8 = 0xFE;
 
 /// Handles decoding incoming PS/2 packets
 ///
@@ -32,6 +42,11 @@ impl Ps2Decoder {
 		self.collector = 0;
 	}
 
+	/// Are we between words (i.e. not part way through collecting one)?
+	pub fn is_idle(&self) -> bool {
+		self.bit_mask == 1
+	}
+
 	/// Add a bit, and if we have enough, return the 11-bit PS/2 word.
 	pub fn add_bit(&mut self, bit: bool) -> Option<u16> {
 		if bit {
@@ -51,28 +66,675 @@ impl Ps2Decoder {
 	/// Check 11-bit word has 1 start bit, 1 stop bit and an odd parity bit.
 	///
 	/// If so, you get back the 8 bit data within the word. Otherwise you get
-	/// None.
-	pub fn check_word(word: u16) -> Option<u8> {
+	/// the first thing which was wrong with it.
+	pub fn check_word(word: u16) -> Result<u8, WordError> {
 		let start_bit = (word & 0b000_0000_0001) != 0;
 		let parity_bit = (word & 0b010_0000_0000) != 0;
 		let stop_bit = (word & 0b100_0000_0000) != 0;
 		let data = ((word >> 1) & 0xFF) as u8;
 
 		if start_bit {
-			return None;
+			return Err(WordError::StartBit);
 		}
 
 		if !stop_bit {
-			return None;
+			return Err(WordError::StopBit);
 		}
 
 		let need_parity = (data.count_ones() % 2) == 0;
 
 		// Check we have the correct parity bit
 		if need_parity != parity_bit {
+			return Err(WordError::Parity);
+		}
+
+		Ok(data)
+	}
+}
+
+/// What was wrong with a PS/2 word.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum WordError {
+	/// The start bit wasn't low
+	StartBit,
+	/// The parity bit didn't make the parity odd
+	Parity,
+	/// The stop bit wasn't high
+	StopBit,
+}
+
+impl WordError {
+	/// How many kinds of error there are
+	pub const COUNT: usize = 3;
+
+	/// The error's position in per-error arrays
+	const fn index(self) -> usize {
+		match self {
+			WordError::StartBit => 0,
+			WordError::Parity => 1,
+			WordError::StopBit => 2,
+		}
+	}
+}
+
+/// What to do on a falling clock edge, when sending to a PS/2 device.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Ps2TxEdge {
+	/// Set the data line to this level
+	Send(bool),
+	/// Release the data line, and read the device's acknowledge bit (low
+	/// means it got the byte)
+	Ack,
+}
+
+/// Handles sending a byte to a PS/2 device
+///
+/// The host pulls the clock low for at least 100us, pulls the data line low
+/// (the start bit) and releases the clock. The device then generates the
+/// clock, and the host changes the data line on each falling edge:
+///
+/// * 8 Data Bits (LSB first)
+/// * Parity Bit
+/// * Stop Bit (the data line released)
+/// * Acknowledge Bit (driven by the device)
+#[derive(Debug)]
+pub struct Ps2Encoder {
+	/// The bits to send, LSB first
+	frame: u16,
+	/// How many falling edges we've seen
+	edge: u8,
+}
+
+impl Ps2Encoder {
+	/// Create a new encoder to send `byte`
+	pub const fn new(byte: u8) -> Ps2Encoder {
+		let parity_bit = (byte.count_ones() % 2) == 0;
+		Ps2Encoder {
+			frame: (byte as u16) | ((parity_bit as u16) << 8) | (1 << 9),
+			edge: 0,
+		}
+	}
+
+	/// Call on each falling clock edge.
+	///
+	/// Returns `None` once the whole byte has been sent.
+	pub fn next_edge(&mut self) -> Option<Ps2TxEdge> {
+		let edge = self.edge;
+		self.edge = self.edge.saturating_add(1);
+		match edge {
+			0..=9 => Some(Ps2TxEdge::Send((self.frame & (1 << edge)) != 0)),
+			10 => Some(Ps2TxEdge::Ack),
+			_ => None,
+		}
+	}
+}
+
+/// What to do after a falling clock edge on a [`Ps2Link`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Ps2Edge {
+	/// Nothing
+	Idle,
+	/// The device has sent us this 11-bit word
+	Word(u16),
+	/// Set the data line to this level
+	Drive(bool),
+}
+
+/// The state of one PS/2 port, whichever way the bits are going.
+///
+/// The device generates the clock in both directions, so on each falling edge
+/// we either take a bit from the data line or put the next one on it. This
+/// doesn't touch the pins - the caller drives them as it is told.
+#[derive(Debug)]
+pub struct Ps2Link {
+	/// Collects bits from the device
+	decoder: Ps2Decoder,
+	/// The byte we are sending, if any
+	encoder: Option<Ps2Encoder>,
+	/// We are holding the clock low, so ignore the edges we see
+	inhibited: bool,
+	/// Whether the device acknowledged the last byte we sent
+	acked: Option<bool>,
+}
+
+impl Ps2Link {
+	/// Create a new link, listening to the device.
+	pub const fn new() -> Ps2Link {
+		Ps2Link {
+			decoder: Ps2Decoder::new(),
+			encoder: None,
+			inhibited: false,
+			acked: None,
+		}
+	}
+
+	/// Call this just before holding the clock low. Edges are ignored (and
+	/// pulling the clock low makes one) until you call [`Ps2Link::release`]
+	/// or [`Ps2Link::start_send`].
+	pub fn inhibit(&mut self) {
+		self.inhibited = true;
+	}
+
+	/// Call this just before letting go of the clock, without sending
+	/// anything. The device then picks up where it left off.
+	pub fn release(&mut self) {
+		self.decoder.reset();
+		self.inhibited = false;
+	}
+
+	/// Start sending a byte. Call this after holding the clock low for at
+	/// least 100 µs, then pull the data line low (the start bit) and let go
+	/// of the clock.
+	pub fn start_send(&mut self, byte: u8) {
+		self.decoder.reset();
+		self.encoder = Some(Ps2Encoder::new(byte));
+		self.acked = None;
+		self.inhibited = false;
+	}
+
+	/// Stop sending, and report whether the device took the byte. Let go of
+	/// the data line afterwards, in case the device never clocked the whole
+	/// byte in.
+	pub fn finish_send(&mut self) -> bool {
+		if self.encoder.take().is_some() {
+			self.decoder.reset();
+		}
+		self.acked.take().unwrap_or(false)
+	}
+
+	/// Handle a falling edge on the clock line, given the level on the data
+	/// line.
+	pub fn clock_edge(&mut self, data_bit: bool) -> Ps2Edge {
+		if self.inhibited {
+			return Ps2Edge::Idle;
+		}
+		if let Some(encoder) = &mut self.encoder {
+			match encoder.next_edge() {
+				Some(Ps2TxEdge::Send(level)) => Ps2Edge::Drive(level),
+				Some(Ps2TxEdge::Ack) => {
+					// The device pulls data low to acknowledge
+					self.acked = Some(!data_bit);
+					self.encoder = None;
+					Ps2Edge::Idle
+				}
+				None => {
+					self.encoder = None;
+					Ps2Edge::Idle
+				}
+			}
+		} else {
+			match self.decoder.add_bit(data_bit) {
+				Some(word) => Ps2Edge::Word(word),
+				None => Ps2Edge::Idle,
+			}
+		}
+	}
+
+	/// Is the device part way through sending us a word?
+	pub fn is_receiving(&self) -> bool {
+		!self.inhibited && self.encoder.is_none() && !self.decoder.is_idle()
+	}
+}
+
+impl Default for Ps2Link {
+	fn default() -> Self {
+		Ps2Link::new()
+	}
+}
+
+/// One of our two PS/2 ports
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Ps2Port {
+	/// Port 0, the keyboard
+	Keyboard,
+	/// Port 1, the mouse
+	Mouse,
+}
+
+impl Ps2Port {
+	/// The port's position in per-port arrays
+	pub const fn index(self) -> usize {
+		match self {
+			Ps2Port::Keyboard => 0,
+			Ps2Port::Mouse => 1,
+		}
+	}
+
+	/// The other port
+	pub const fn other(self) -> Ps2Port {
+		match self {
+			Ps2Port::Keyboard => Ps2Port::Mouse,
+			Ps2Port::Mouse => Ps2Port::Keyboard,
+		}
+	}
+}
+
+/// Decides which PS/2 port may send next.
+///
+/// Both clock lines interrupt us, and a device generates the clock whichever
+/// way the bits are going. So whilst we send to one port, we hold the other
+/// port's clock low (inhibit it), which makes that device wait, and we ignore
+/// the edges it makes. We only start sending once neither device is part way
+/// through sending us a word - a device inhibited before the last bit sends
+/// the whole word again, but we'd rather not make it.
+///
+/// Each port has its own queue of bytes to send, and when both have bytes
+/// waiting they take turns.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ps2Arbiter<const N: usize> {
+	/// The bytes waiting to be sent on each port, oldest first
+	queues: [[u8; N]; 2],
+	/// How many bytes are in each queue
+	queued: [usize; 2],
+	/// Is each port part way through receiving a word?
+	receiving: [bool; 2],
+	/// The port we are sending on, if any
+	sending: Option<Ps2Port>,
+	/// The port which sent most recently
+	last: Ps2Port,
+}
+
+impl<const N: usize> Ps2Arbiter<N> {
+	/// Create an arbiter, with nothing to send.
+	pub const fn new() -> Ps2Arbiter<N> {
+		Ps2Arbiter {
+			queues: [[0; N]; 2],
+			queued: [0; 2],
+			receiving: [false; 2],
+			sending: None,
+			last: Ps2Port::Mouse,
+		}
+	}
+
+	/// Queue a byte to send on a port.
+	///
+	/// Returns `false`, and queues nothing, if that port's queue is full.
+	pub fn queue(&mut self, port: Ps2Port, byte: u8) -> bool {
+		let idx = port.index();
+		match self.queues[idx].get_mut(self.queued[idx]) {
+			Some(slot) => {
+				*slot = byte;
+				self.queued[idx] += 1;
+				true
+			}
+			None => false,
+		}
+	}
+
+	/// How many bytes are waiting to be sent on a port?
+	pub fn queued(&self, port: Ps2Port) -> usize {
+		self.queued[port.index()]
+	}
+
+	/// Record whether a port is part way through receiving a word. Call this
+	/// after each clock edge the port's device makes.
+	pub fn set_receiving(&mut self, port: Ps2Port, receiving: bool) {
+		self.receiving[port.index()] = receiving;
+	}
+
+	/// A port has been part way through a word for too long, so it must have
+	/// seen a glitch. Stop waiting for it.
+	pub fn receive_timeout(&mut self) {
+		self.receiving = [false; 2];
+	}
+
+	/// Pick the next byte to send, if we can send now.
+	///
+	/// You get `None` if we are already sending, if a port is part way
+	/// through receiving, or if there is nothing to send. Otherwise, inhibit
+	/// the other port, send the byte, and call [`Ps2Arbiter::finish`].
+	pub fn start(&mut self) -> Option<(Ps2Port, u8)> {
+		if self.sending.is_some() || self.receiving.iter().any(|r| *r) {
+			return None;
+		}
+		let port = [self.last.other(), self.last]
+			.iter()
+			.copied()
+			.find(|port| self.queued[port.index()] != 0)?;
+		let idx = port.index();
+		let byte = self.queues[idx][0];
+		self.queues[idx].copy_within(1..self.queued[idx], 0);
+		self.queued[idx] -= 1;
+		self.sending = Some(port);
+		self.last = port;
+		Some((port, byte))
+	}
+
+	/// We've finished sending, so let go of the other port.
+	pub fn finish(&mut self) {
+		self.sending = None;
+		self.receiving = [false; 2];
+	}
+
+	/// The port we are sending on, if any.
+	pub fn sending(&self) -> Option<Ps2Port> {
+		self.sending
+	}
+
+	/// Is this port inhibited, because we are sending on the other one?
+	///
+	/// Ignore any clock edges on an inhibited port - including the one we
+	/// make pulling its clock low.
+	pub fn is_inhibited(&self, port: Ps2Port) -> bool {
+		self.sending == Some(port.other())
+	}
+}
+
+impl<const N: usize> Default for Ps2Arbiter<N> {
+	fn default() -> Self {
+		Ps2Arbiter::new()
+	}
+}
+
+/// Counts the bad words from each PS/2 port, and decides when to ask a
+/// device to send one again.
+///
+/// We ask for a resend after each bad word, unless the word was itself a
+/// resend - a device which can't get a word through twice in a row is
+/// unlikely to manage a third time, and we don't want to keep it busy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ps2Errors {
+	/// How many of each error, for each port (each sticks at the maximum)
+	counts: [[u16; WordError::COUNT]; 2],
+	/// How many resends we asked each port for (sticks at the maximum)
+	resends: [u16; 2],
+	/// Ports which we need to ask for a resend
+	resend_due: [bool; 2],
+	/// Ports whose next word is a resend
+	retrying: [bool; 2],
+}
+
+impl Ps2Errors {
+	/// How many bytes the PS/2 Errors register holds: for each port, each
+	/// error count and then the resend count, as `u16le`s.
+	pub const SIZE: usize = 2 * (WordError::COUNT + 1) * 2;
+
+	/// Create the counters, with no errors.
+	pub const fn new() -> Ps2Errors {
+		Ps2Errors {
+			counts: [[0; WordError::COUNT]; 2],
+			resends: [0; 2],
+			resend_due: [false; 2],
+			retrying: [false; 2],
+		}
+	}
+
+	/// Count a bad word from a port, and ask for it again if we can.
+	pub fn bad_word(&mut self, port: Ps2Port, error: WordError) {
+		let index = port.index();
+		let count = &mut self.counts[index][error.index()];
+		*count = count.saturating_add(1);
+		if self.retrying[index] {
+			// That was the resend, so give up on it
+			self.retrying[index] = false;
+		} else {
+			self.resend_due[index] = true;
+		}
+	}
+
+	/// Note a good word from a port.
+	pub fn good_word(&mut self, port: Ps2Port) {
+		self.retrying[port.index()] = false;
+	}
+
+	/// The command to send a port, if we need to ask it for a resend.
+	pub fn resend(&mut self, port: Ps2Port) -> Option<u8> {
+		let index = port.index();
+		if !self.resend_due[index] {
 			return None;
 		}
+		self.resend_due[index] = false;
+		self.retrying[index] = true;
+		self.resends[index] = self.resends[index].saturating_add(1);
+		Some(RESEND)
+	}
+
+	/// How many words of a port had an error.
+	pub fn count(&self, port: Ps2Port, error: WordError) -> u16 {
+		self.counts[port.index()][error.index()]
+	}
+
+	/// How many resends we asked a port for.
+	pub fn resends(&self, port: Ps2Port) -> u16 {
+		self.resends[port.index()]
+	}
+
+	/// Start counting again.
+	pub fn clear(&mut self) {
+		self.counts = [[0; WordError::COUNT]; 2];
+		self.resends = [0; 2];
+	}
+
+	/// Convert to bytes for the PS/2 Errors register.
+	pub fn as_bytes(&self) -> [u8; Self::SIZE] {
+		let mut bytes = [0u8; Self::SIZE];
+		let mut values = self
+			.counts
+			.iter()
+			.zip(self.resends.iter())
+			.flat_map(|(counts, resends)| counts.iter().chain(core::iter::once(resends)));
+		for (chunk, value) in bytes.chunks_exact_mut(2).zip(&mut values) {
+			chunk.copy_from_slice(&value.to_le_bytes());
+		}
+		bytes
+	}
+}
+
+impl Default for Ps2Errors {
+	fn default() -> Self {
+		Ps2Errors::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn encoder_matches_decoder() {
+		for byte in [0x00, 0xF3, 0xE8, 0xFF] {
+			let mut encoder = Ps2Encoder::new(byte);
+			let mut decoder = Ps2Decoder::new();
+			// The start bit is sent before the first edge
+			assert_eq!(decoder.add_bit(false), None);
+			let mut word = None;
+			while let Some(Ps2TxEdge::Send(bit)) = encoder.next_edge() {
+				word = decoder.add_bit(bit);
+			}
+			assert_eq!(Ps2Decoder::check_word(word.unwrap()), Ok(byte));
+			assert_eq!(encoder.next_edge(), None);
+		}
+	}
+
+	#[test]
+	fn link_sends_and_receives() {
+		let mut link = Ps2Link::new();
+		// Part of a word from the device
+		assert_eq!(link.clock_edge(false), Ps2Edge::Idle);
+		assert!(link.is_receiving());
+		// We hold the clock low, which makes an edge, and send a byte
+		link.inhibit();
+		assert_eq!(link.clock_edge(true), Ps2Edge::Idle);
+		assert!(!link.is_receiving());
+		link.start_send(0xF4);
+		let mut encoder = Ps2Encoder::new(0xF4);
+		while let Some(Ps2TxEdge::Send(level)) = encoder.next_edge() {
+			assert_eq!(link.clock_edge(true), Ps2Edge::Drive(level));
+		}
+		// The device pulls data low to acknowledge
+		assert_eq!(link.clock_edge(false), Ps2Edge::Idle);
+		assert!(link.finish_send());
+		// The half word from before was thrown away
+		let mut word = Ps2Edge::Idle;
+		for bit in [
+			false, false, true, false, true, true, true, true, true, true, true,
+		] {
+			word = link.clock_edge(bit);
+		}
+		assert_eq!(word, Ps2Edge::Word(0b111_1111_0100));
+		// A device which never clocks the byte in
+		link.inhibit();
+		link.start_send(0xFF);
+		assert_eq!(link.clock_edge(true), Ps2Edge::Drive(true));
+		assert!(!link.finish_send());
+		assert!(!link.is_receiving());
+	}
+
+	#[test]
+	fn arbiter_takes_turns() {
+		let mut arbiter = Ps2Arbiter::<2>::new();
+		assert_eq!(arbiter.start(), None);
+		assert!(arbiter.queue(Ps2Port::Keyboard, 0xED));
+		assert!(arbiter.queue(Ps2Port::Keyboard, 0x02));
+		assert!(!arbiter.queue(Ps2Port::Keyboard, 0xFF));
+		assert!(arbiter.queue(Ps2Port::Mouse, 0xF4));
+		// Not whilst the mouse is part way through a word
+		arbiter.set_receiving(Ps2Port::Mouse, true);
+		assert_eq!(arbiter.start(), None);
+		arbiter.set_receiving(Ps2Port::Mouse, false);
+		assert_eq!(arbiter.start(), Some((Ps2Port::Keyboard, 0xED)));
+		assert!(arbiter.is_inhibited(Ps2Port::Mouse));
+		assert!(!arbiter.is_inhibited(Ps2Port::Keyboard));
+		// Only one at a time
+		assert_eq!(arbiter.start(), None);
+		arbiter.finish();
+		assert!(!arbiter.is_inhibited(Ps2Port::Mouse));
+		// The mouse gets its turn before the rest of the keyboard's bytes
+		assert_eq!(arbiter.start(), Some((Ps2Port::Mouse, 0xF4)));
+		assert!(arbiter.is_inhibited(Ps2Port::Keyboard));
+		arbiter.finish();
+		// A port stuck part way through a word is given up on
+		arbiter.set_receiving(Ps2Port::Keyboard, true);
+		assert_eq!(arbiter.start(), None);
+		arbiter.receive_timeout();
+		assert_eq!(arbiter.start(), Some((Ps2Port::Keyboard, 0x02)));
+		arbiter.finish();
+		assert_eq!(arbiter.queued(Ps2Port::Keyboard), 0);
+		assert_eq!(arbiter.start(), None);
+	}
+
+	#[test]
+	fn interleaved_traffic() {
+		/// Clock edges it takes us to send a byte
+		const SEND_EDGES: usize = 11;
+		// The mouse streams packets, whilst we send keyboard LED updates and
+		// a mouse command
+		let packets = [0x08, 0x10, 0xF0, 0x28, 0x01, 0xFF, 0x09, 0x00, 0x00];
+		let mut to_keyboard = vec![0xED, 0x02, 0xED, 0x07];
+		let mut arbiter = Ps2Arbiter::<4>::new();
+		let mut mouse_decoder = Ps2Decoder::new();
+		// The bits of the word the mouse is sending, and how far through it
+		// is
+		let mut mouse_word: Option<([bool; 11], usize)> = None;
+		let mut mouse_next = 0;
+		let mut received = Vec::new();
+		let mut sent = Vec::new();
+		let mut sending_edges = 0;
+		for tick in 0..1000 {
+			// Queue the LED updates part way through the packets
+			if tick % 25 == 7 && !to_keyboard.is_empty() {
+				assert!(arbiter.queue(Ps2Port::Keyboard, to_keyboard.remove(0)));
+			}
+			if tick == 30 {
+				assert!(arbiter.queue(Ps2Port::Mouse, 0xF4));
+			}
+			// Us
+			if let Some((port, byte)) = arbiter.start() {
+				sent.push((port, byte));
+				sending_edges = SEND_EDGES;
+			}
+			if arbiter.sending().is_some() {
+				sending_edges -= 1;
+				if sending_edges == 0 {
+					arbiter.finish();
+				}
+			}
+			// The mouse, which waits whilst its clock is held low
+			if arbiter.is_inhibited(Ps2Port::Mouse) || arbiter.sending() == Some(Ps2Port::Mouse) {
+				assert!(mouse_decoder.is_idle());
+				continue;
+			}
+			if mouse_word.is_none() && mouse_next < packets.len() {
+				let mut bits = [false; 11];
+				let mut encoder = Ps2Encoder::new(packets[mouse_next]);
+				for bit in bits.iter_mut().skip(1) {
+					if let Some(Ps2TxEdge::Send(level)) = encoder.next_edge() {
+						*bit = level;
+					}
+				}
+				mouse_word = Some((bits, 0));
+				mouse_next += 1;
+			}
+			if let Some((bits, edge)) = &mut mouse_word {
+				if let Some(word) = mouse_decoder.add_bit(bits[*edge]) {
+					received.push(Ps2Decoder::check_word(word).unwrap());
+				}
+				*edge += 1;
+				if *edge == bits.len() {
+					mouse_word = None;
+				}
+			}
+			arbiter.set_receiving(Ps2Port::Mouse, !mouse_decoder.is_idle());
+		}
+		assert_eq!(received, packets);
+		assert_eq!(
+			sent,
+			[
+				(Ps2Port::Keyboard, 0xED),
+				(Ps2Port::Mouse, 0xF4),
+				(Ps2Port::Keyboard, 0x02),
+				(Ps2Port::Keyboard, 0xED),
+				(Ps2Port::Keyboard, 0x07),
+			]
+		);
+	}
+
+	#[test]
+	fn bad_words() {
+		// Start bit set
+		assert_eq!(
+			Ps2Decoder::check_word(0b110_0000_0001),
+			Err(WordError::StartBit)
+		);
+		// Stop bit clear
+		assert_eq!(
+			Ps2Decoder::check_word(0b010_0000_0000),
+			Err(WordError::StopBit)
+		);
+		// Wrong parity
+		assert_eq!(
+			Ps2Decoder::check_word(0b100_0000_0000),
+			Err(WordError::Parity)
+		);
+		assert_eq!(Ps2Decoder::check_word(0b110_0000_0000), Ok(0x00));
+	}
 
-		Some(data)
+	#[test]
+	fn errors() {
+		let mut errors = Ps2Errors::new();
+		assert_eq!(errors.resend(Ps2Port::Keyboard), None);
+		errors.bad_word(Ps2Port::Keyboard, WordError::Parity);
+		assert_eq!(errors.count(Ps2Port::Keyboard, WordError::Parity), 1);
+		assert_eq!(errors.count(Ps2Port::Mouse, WordError::Parity), 0);
+		// Asked for once
+		assert_eq!(errors.resend(Ps2Port::Mouse), None);
+		assert_eq!(errors.resend(Ps2Port::Keyboard), Some(RESEND));
+		assert_eq!(errors.resend(Ps2Port::Keyboard), None);
+		// The resend was bad too, so we give up on it
+		errors.bad_word(Ps2Port::Keyboard, WordError::StopBit);
+		assert_eq!(errors.resend(Ps2Port::Keyboard), None);
+		// But the next bad word gets another go
+		errors.bad_word(Ps2Port::Keyboard, WordError::Parity);
+		assert_eq!(errors.resend(Ps2Port::Keyboard), Some(RESEND));
+		errors.good_word(Ps2Port::Keyboard);
+		errors.bad_word(Ps2Port::Mouse, WordError::StartBit);
+		assert_eq!(errors.resend(Ps2Port::Mouse), Some(RESEND));
+		assert_eq!(errors.resends(Ps2Port::Keyboard), 2);
+		assert_eq!(
+			errors.as_bytes(),
+			[0, 0, 2, 0, 1, 0, 2, 0, 1, 0, 0, 0, 0, 0, 1, 0]
+		);
+		errors.clear();
+		assert_eq!(errors.as_bytes(), [0; Ps2Errors::SIZE]);
 	}
 }
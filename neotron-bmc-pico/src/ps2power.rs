@@ -0,0 +1,124 @@
+//! # PS/2 Port Power
+//!
+//! With the `ps2-power` feature, the 5V supply to each PS/2 port goes through
+//! its own P-FET, switched from the I²C pins - PB6 for the keyboard and PB7
+//! for the mouse, each low to turn the port on. A keyboard or mouse which has
+//! wedged can then be recovered by the host, without anyone unplugging it.
+//!
+//! The host can turn each port off and on, or ask for a *power cycle*, where
+//! we turn the port off, wait `POWER_CYCLE_OFF_MS`, and turn it back on. A
+//! task calls [`Ps2Power::tick`] and drives the pins to match
+//! [`Ps2Power::powered`].
+
+/// Bit for the keyboard port, in the PS/2 Port Power and PS/2 Power Cycle
+/// registers
+pub const PORT_KEYBOARD: u8 = 1 << 0;
+
+/// Bit for the mouse port, in the PS/2 Port Power and PS/2 Power Cycle
+/// registers
+pub const PORT_MOUSE: u8 = 1 << 1;
+
+/// All the port bits
+pub const PORTS: u8 = PORT_KEYBOARD | PORT_MOUSE;
+
+/// How long a power cycle keeps a port off. Long enough for the device's
+/// supply capacitors to drain, so it resets.
+pub const POWER_CYCLE_OFF_MS: u16 = 500;
+
+/// Which PS/2 ports the host wants powered, and any power cycles in progress.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ps2Power {
+	/// The ports the host wants on (see `PORT_KEYBOARD` and `PORT_MOUSE`)
+	enabled: u8,
+	/// How much longer each port stays off for a power cycle, in ms
+	cycle_ms: [u16; 2],
+}
+
+impl Ps2Power {
+	/// Create the state, with both ports on.
+	pub const fn new() -> Ps2Power {
+		Ps2Power {
+			enabled: PORTS,
+			cycle_ms: [0; 2],
+		}
+	}
+
+	/// The ports the host wants on.
+	pub fn enabled(&self) -> u8 {
+		self.enabled
+	}
+
+	/// Choose the ports which are on. Bits for ports we don't have are
+	/// ignored.
+	pub fn set_enabled(&mut self, ports: u8) {
+		self.enabled = ports & PORTS;
+	}
+
+	/// Start a power cycle on the given ports. Bits for ports we don't have
+	/// are ignored, and a port already being cycled starts its wait again.
+	pub fn power_cycle(&mut self, ports: u8) {
+		for (idx, cycle_ms) in self.cycle_ms.iter_mut().enumerate() {
+			if (ports & (1 << idx)) != 0 {
+				*cycle_ms = POWER_CYCLE_OFF_MS;
+			}
+		}
+	}
+
+	/// The ports part way through a power cycle.
+	pub fn cycling(&self) -> u8 {
+		self.cycle_ms
+			.iter()
+			.enumerate()
+			.filter(|(_, cycle_ms)| **cycle_ms != 0)
+			.fold(0, |ports, (idx, _)| ports | (1 << idx))
+	}
+
+	/// Call this every so often, with how long it has been since the last
+	/// call.
+	pub fn tick(&mut self, elapsed_ms: u16) {
+		for cycle_ms in self.cycle_ms.iter_mut() {
+			*cycle_ms = cycle_ms.saturating_sub(elapsed_ms);
+		}
+	}
+
+	/// The ports which should have power right now.
+	pub fn powered(&self) -> u8 {
+		self.enabled & !self.cycling()
+	}
+}
+
+impl Default for Ps2Power {
+	fn default() -> Self {
+		Ps2Power::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn power_cycle() {
+		let mut power = Ps2Power::new();
+		assert_eq!(power.powered(), PORTS);
+		power.power_cycle(PORT_KEYBOARD | 0x80);
+		assert_eq!(power.cycling(), PORT_KEYBOARD);
+		assert_eq!(power.powered(), PORT_MOUSE);
+		assert_eq!(power.enabled(), PORTS);
+		power.tick(300);
+		// Starting the mouse later doesn't hurry the keyboard along
+		power.power_cycle(PORT_MOUSE);
+		assert_eq!(power.powered(), 0);
+		power.tick(200);
+		assert_eq!(power.powered(), PORT_KEYBOARD);
+		power.tick(300);
+		assert_eq!(power.cycling(), 0);
+		assert_eq!(power.powered(), PORTS);
+		// A port the host has turned off stays off after a cycle
+		power.set_enabled(PORT_MOUSE | 0x80);
+		assert_eq!(power.enabled(), PORT_MOUSE);
+		power.power_cycle(PORTS);
+		power.tick(POWER_CYCLE_OFF_MS);
+		assert_eq!(power.powered(), PORT_MOUSE);
+	}
+}
@@ -0,0 +1,177 @@
+//! # PS/2 Bit Timing
+//!
+//! A PS/2 device generates the clock, at 10 kHz to 16.7 kHz, so each bit it
+//! sends us should take 60 µs to 100 µs. Some keyboards are out of spec, and
+//! a slow one can trip our receive timeout, so we time the falling clock
+//! edges within each word against the TIM14 stopwatch (see
+//! [`crate::latency::Stopwatch`]), and keep the shortest, longest and
+//! typical bit period of each port.
+//!
+//! The edges are timed when the clock interrupt reads the stopwatch, so the
+//! periods carry a little interrupt latency jitter - a few microseconds,
+//! unless something held off the interrupt. The gaps between words aren't
+//! bit periods, so they aren't counted, and nor are the edges whilst we
+//! send, as then we hold the data line and the device only clocks it.
+
+/// The shortest bit period the PS/2 spec allows, in microseconds (16.7 kHz)
+pub const MIN_BIT_US: u16 = 60;
+
+/// The longest bit period the PS/2 spec allows, in microseconds (10 kHz)
+pub const MAX_BIT_US: u16 = 100;
+
+/// The typical period follows each new period by 1/2^N of the difference
+const TYPICAL_SHIFT: u32 = 4;
+
+/// The bit periods seen on one PS/2 port.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct BitTiming {
+	/// The shortest bit period, in microseconds, or zero if there are none
+	pub min_us: u16,
+	/// The longest bit period, in microseconds, or zero if there are none
+	pub max_us: u16,
+	/// A rolling average of the bit period, in microseconds
+	pub typical_us: u16,
+	/// How many bit periods were outside `MIN_BIT_US` to `MAX_BIT_US`
+	/// (sticks at the maximum)
+	pub out_of_spec: u16,
+}
+
+impl BitTiming {
+	/// How many bytes one port takes in the PS/2 Bit Timing register.
+	pub const SIZE: usize = 8;
+
+	/// Convert to bytes for the PS/2 Bit Timing register.
+	///
+	/// That's the shortest, longest and typical period, and the out of spec
+	/// count, each as a `u16le`.
+	pub fn as_bytes(&self) -> [u8; Self::SIZE] {
+		let mut bytes = [0u8; Self::SIZE];
+		bytes[0..2].copy_from_slice(&self.min_us.to_le_bytes());
+		bytes[2..4].copy_from_slice(&self.max_us.to_le_bytes());
+		bytes[4..6].copy_from_slice(&self.typical_us.to_le_bytes());
+		bytes[6..8].copy_from_slice(&self.out_of_spec.to_le_bytes());
+		bytes
+	}
+}
+
+/// Times the falling clock edges on one PS/2 port.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BitTimer {
+	/// When the last edge came, on the stopwatch
+	last_edge_us: u16,
+	/// Have we timed any bits yet?
+	recorded: bool,
+	/// The typical period, in microseconds, times 2^`TYPICAL_SHIFT`
+	typical: u32,
+	/// The periods so far
+	timing: BitTiming,
+}
+
+impl BitTimer {
+	/// Create a timer which hasn't seen any edges.
+	pub const fn new() -> BitTimer {
+		BitTimer {
+			last_edge_us: 0,
+			recorded: false,
+			typical: 0,
+			timing: BitTiming {
+				min_us: 0,
+				max_us: 0,
+				typical_us: 0,
+				out_of_spec: 0,
+			},
+		}
+	}
+
+	/// Note a falling clock edge at `now_us` on the stopwatch.
+	///
+	/// `mid_word` says whether the device was part way through sending us a
+	/// word before this edge - if so, the time since the last edge was one
+	/// bit.
+	pub fn edge(&mut self, now_us: u16, mid_word: bool) {
+		if mid_word {
+			self.record(now_us.wrapping_sub(self.last_edge_us));
+		}
+		self.last_edge_us = now_us;
+	}
+
+	/// Add a bit period, in microseconds.
+	fn record(&mut self, period_us: u16) {
+		if !(MIN_BIT_US..=MAX_BIT_US).contains(&period_us) {
+			self.timing.out_of_spec = self.timing.out_of_spec.saturating_add(1);
+		}
+		if !self.recorded {
+			self.recorded = true;
+			self.timing.min_us = period_us;
+			self.timing.max_us = period_us;
+			self.typical = u32::from(period_us) << TYPICAL_SHIFT;
+		} else {
+			self.timing.min_us = self.timing.min_us.min(period_us);
+			self.timing.max_us = self.timing.max_us.max(period_us);
+			self.typical = self.typical - (self.typical >> TYPICAL_SHIFT) + u32::from(period_us);
+		}
+		self.timing.typical_us = (self.typical >> TYPICAL_SHIFT) as u16;
+	}
+
+	/// The bit periods so far.
+	pub fn timing(&self) -> BitTiming {
+		self.timing
+	}
+
+	/// Forget the bit periods so far.
+	pub fn clear(&mut self) {
+		*self = BitTimer {
+			last_edge_us: self.last_edge_us,
+			..BitTimer::new()
+		};
+	}
+}
+
+impl Default for BitTimer {
+	fn default() -> Self {
+		BitTimer::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Clock in one word, with each bit `period_us` long, starting at
+	/// `start_us`.
+	fn word(timer: &mut BitTimer, start_us: u16, period_us: u16) {
+		for bit in 0..11 {
+			timer.edge(start_us.wrapping_add(bit * period_us), bit != 0);
+		}
+	}
+
+	#[test]
+	fn timing() {
+		let mut timer = BitTimer::new();
+		assert_eq!(timer.timing(), BitTiming::default());
+		// The gap before the start bit doesn't count
+		word(&mut timer, 5000, 80);
+		assert_eq!(
+			timer.timing(),
+			BitTiming {
+				min_us: 80,
+				max_us: 80,
+				typical_us: 80,
+				out_of_spec: 0,
+			}
+		);
+		// A slow keyboard, across the stopwatch wrapping
+		word(&mut timer, 65000, 110);
+		let timing = timer.timing();
+		assert_eq!((timing.min_us, timing.max_us), (80, 110));
+		assert_eq!(timing.out_of_spec, 10);
+		assert!(timing.typical_us > 80 && timing.typical_us < 110);
+		assert_eq!(timing.as_bytes()[0..4], [0x50, 0x00, 0x6E, 0x00]);
+		timer.clear();
+		assert_eq!(timer.timing(), BitTiming::default());
+		word(&mut timer, 0, 60);
+		assert_eq!(timer.timing().min_us, 60);
+		assert_eq!(timer.timing().typical_us, 60);
+		assert_eq!(timer.timing().out_of_spec, 0);
+	}
+}
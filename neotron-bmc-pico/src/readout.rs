@@ -0,0 +1,191 @@
+//! # Readout Protection
+//!
+//! Kit builders shipping finished units may want to stop anyone reading the
+//! firmware and settings back out over SWD. The STM32 does this with its
+//! readout protection (RDP) option byte:
+//!
+//! * Level 0 - no protection (how the chips come from the factory).
+//! * Level 1 - the debugger can't read the flash. Going back to level 0 is
+//!   possible, but erases the whole flash, firmware and settings included.
+//! * Level 2 - the debug port is turned off for good. We never set this, as
+//!   nothing could ever update the firmware again.
+//!
+//! Setting level 1 is a guarded write (see [`crate::lock`]) which also needs
+//! its own sequence of keys, so it can't happen by accident. The new level
+//! is only loaded from the option bytes when the NBMC next powers up -
+//! reloading it straight away would reset the NBMC, and cut the main board's
+//! power.
+
+/// Write this to the Readout Protection register to start arming it
+pub const ARM_KEY_1: u8 = 0x52;
+
+/// Write this to the Readout Protection register, straight after
+/// `ARM_KEY_1`
+pub const ARM_KEY_2: u8 = 0xD9;
+
+/// Write this to the Readout Protection register, straight after
+/// `ARM_KEY_2`, to set level 1
+pub const COMMAND_ENABLE: u8 = 0x01;
+
+/// The deferred operation result when the option bytes were programmed
+pub const RESULT_PROGRAMMED: u8 = 0x00;
+
+/// The deferred operation result when programming the option bytes failed
+pub const RESULT_FAILED: u8 = 0x01;
+
+/// The RDP option byte value for level 0
+const RDP_LEVEL_0: u8 = 0xAA;
+
+/// The RDP option byte value for level 2 (any other value is level 1)
+const RDP_LEVEL_2: u8 = 0xCC;
+
+/// The RDP option byte value we program for level 1 (the one ST's tools use)
+const RDP_LEVEL_1: u8 = 0xBB;
+
+/// How many option byte half-words there are (RDP, USER, DATA0, DATA1, WRP0
+/// and WRP1)
+pub const OPTION_BYTES: usize = 6;
+
+/// How well the flash is protected.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum Level {
+	/// No protection
+	Level0 = 0,
+	/// The debugger can't read the flash
+	Level1 = 1,
+	/// The debug port is turned off for good
+	Level2 = 2,
+}
+
+impl Level {
+	/// Decode the RDPRT bits of the flash controller's `OBR` register, which
+	/// give the level in force now.
+	pub fn from_rdprt(bits: u8) -> Level {
+		match bits & 0b11 {
+			0b00 => Level::Level0,
+			0b01 => Level::Level1,
+			_ => Level::Level2,
+		}
+	}
+
+	/// Decode the RDP option byte, which gives the level we'll have after
+	/// the next power-up.
+	pub fn from_option_byte(rdp: u8) -> Level {
+		match rdp {
+			RDP_LEVEL_0 => Level::Level0,
+			RDP_LEVEL_2 => Level::Level2,
+			_ => Level::Level1,
+		}
+	}
+}
+
+/// The option bytes to program for level 1, given the ones there now (each
+/// half-word as read from the option byte area).
+///
+/// Only the low byte of each half-word is programmed - the flash controller
+/// fills in the complement. Everything but RDP keeps its current value.
+pub fn level_1_option_bytes(current: &[u16; OPTION_BYTES]) -> [u16; OPTION_BYTES] {
+	let mut new = [0u16; OPTION_BYTES];
+	for (dest, src) in new.iter_mut().zip(current.iter()) {
+		*dest = *src & 0x00FF;
+	}
+	new[0] = u16::from(RDP_LEVEL_1);
+	new
+}
+
+/// What a write to the Readout Protection register asks for.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Command {
+	/// One of the keys, in the right place in the sequence
+	Key,
+	/// Set level 1 - the keys were all written first
+	Enable,
+	/// Anything else
+	Invalid,
+}
+
+/// How far through the arming sequence the host has got.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Arming {
+	/// How many keys the host has written, one straight after the other
+	keys_seen: u8,
+}
+
+impl Arming {
+	/// Create the sequence, with no keys seen.
+	pub const fn new() -> Arming {
+		Arming { keys_seen: 0 }
+	}
+
+	/// Handle a write to the Readout Protection register.
+	///
+	/// Anything out of sequence starts it again.
+	pub fn write(&mut self, byte: u8) -> Command {
+		let keys_seen = core::mem::replace(&mut self.keys_seen, 0);
+		match (keys_seen, byte) {
+			(0, ARM_KEY_1) => {
+				self.keys_seen = 1;
+				Command::Key
+			}
+			(1, ARM_KEY_2) => {
+				self.keys_seen = 2;
+				Command::Key
+			}
+			(2, COMMAND_ENABLE) => Command::Enable,
+			_ => Command::Invalid,
+		}
+	}
+
+	/// Some other request arrived, so arming has to start again.
+	pub fn interrupt(&mut self) {
+		self.keys_seen = 0;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn arming() {
+		let mut arming = Arming::new();
+		// The command on its own does nothing
+		assert_eq!(arming.write(COMMAND_ENABLE), Command::Invalid);
+		// The keys in the wrong order
+		assert_eq!(arming.write(ARM_KEY_2), Command::Invalid);
+		assert_eq!(arming.write(ARM_KEY_1), Command::Key);
+		assert_eq!(arming.write(ARM_KEY_1), Command::Invalid);
+		// Something else in between
+		assert_eq!(arming.write(ARM_KEY_1), Command::Key);
+		assert_eq!(arming.write(ARM_KEY_2), Command::Key);
+		arming.interrupt();
+		assert_eq!(arming.write(COMMAND_ENABLE), Command::Invalid);
+		// The whole sequence, back to back - and it doesn't stay armed
+		assert_eq!(arming.write(ARM_KEY_1), Command::Key);
+		assert_eq!(arming.write(ARM_KEY_2), Command::Key);
+		assert_eq!(arming.write(COMMAND_ENABLE), Command::Enable);
+		assert_eq!(arming.write(COMMAND_ENABLE), Command::Invalid);
+	}
+
+	#[test]
+	fn levels() {
+		assert_eq!(Level::from_rdprt(0b00), Level::Level0);
+		assert_eq!(Level::from_rdprt(0b01), Level::Level1);
+		assert_eq!(Level::from_rdprt(0b11), Level::Level2);
+		assert_eq!(Level::from_option_byte(0xAA), Level::Level0);
+		assert_eq!(Level::from_option_byte(0xBB), Level::Level1);
+		assert_eq!(Level::from_option_byte(0xFF), Level::Level1);
+		assert_eq!(Level::from_option_byte(0xCC), Level::Level2);
+	}
+
+	#[test]
+	fn option_bytes() {
+		// Factory defaults, with a write-protected first sector
+		let current = [0x55AA, 0x807F, 0x00FF, 0x00FF, 0xFE01, 0x00FF];
+		assert_eq!(
+			level_1_option_bytes(&current),
+			[0x00BB, 0x007F, 0x00FF, 0x00FF, 0x0001, 0x00FF]
+		);
+	}
+}
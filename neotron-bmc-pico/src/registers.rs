@@ -0,0 +1,5148 @@
+//! # Register Map
+//!
+//! The registers the host can read and write over SPI (or I²C, or the UART
+//! command channel), and the code
+//! which answers each request. See the top-level `README.md` for what each
+//! register does.
+//!
+//! Nothing here touches the hardware. Anything which needs the hardware (like
+//! saving the config to flash) is flagged in the [`RegisterState`] for the
+//! caller to pick up. If that takes longer than one SPI transaction, the write
+//! answers `Busy` and the result turns up later in the [`Deferred`] tracker.
+
+use neotron_bmc_protocol as proto;
+use proto::values::RegisterValue;
+
+use crate::adcsched::AdcScheduler;
+#[cfg(feature = "adc")]
+use crate::adcsched::ADC_CHANNELS;
+use crate::analog::AnalogInputs;
+#[cfg(feature = "adc")]
+use crate::analog::{self, Calibration};
+use crate::bulk::BulkBuffer;
+use crate::buttons::{ButtonConfig, BUTTONS, BUTTON_RESET, DEFAULT_BUTTONS};
+use crate::buzzer::{Note, NoteQueue};
+use crate::changes::{ChangeTracker, WATCHED_LEN};
+use crate::config::Config;
+#[cfg(feature = "i2c-target")]
+use crate::config::{I2C_ADDRESS_MAX, I2C_ADDRESS_MIN};
+use crate::deferred::Deferred;
+use crate::encoder::Encoder;
+use crate::eventlog::{Event, EventLog};
+use crate::expansion::{self, Gpio, Pwm};
+use crate::glitch::{self, GlitchCounters};
+use crate::health::Health;
+use crate::irq::{self, InterruptController};
+use crate::isrstatus::{IsrStatus, IsrStatusReader};
+use crate::keyboard::{AsciiKeys, BootKeys, KeyRepeat, KeyState, KeyboardId, ResetChord};
+use crate::latency::LatencyStats;
+use crate::lock::ConfigLock;
+use crate::logging::{self, Level as LogLevel};
+#[cfg(feature = "ps2-mouse")]
+use crate::mouse::{self, MouseMovement};
+use crate::mouse::{Mouse, MouseReport};
+use crate::nvram::{self, Nvram};
+use crate::power::{
+	self, DcPowerState, PowerAction, MAX_POWER_ON_DELAY_S, MAX_RESET_DURATION_MS,
+	MIN_RESET_DURATION_MS, RESET_DURATION_MS,
+};
+use crate::ps2::Ps2Errors;
+use crate::ps2power::Ps2Power;
+use crate::ps2timing::BitTiming;
+use crate::readout::{self, Arming, Level};
+use crate::risetime::RiseTimes;
+use crate::rtc::{self, Alarm, DateTime, RtcState};
+use crate::selftest::{self, SelfTestReport};
+#[cfg(feature = "spi-trace")]
+use crate::spitrace::{SpiTrace, TraceEntry};
+use crate::stats::Stats;
+use crate::statusled::StatusLed;
+use crate::uart::{ByteFifo, SelfTestResults, SerialPort, SELF_TEST_BLOCK_LEN};
+use crate::watch::{Comparison, Watch, Watches, WATCHES};
+use crate::watchdog::HostWatchdog;
+
+/// Bit in the Power Control register which keeps the DC power on. Clear it to
+/// power off.
+pub const POWER_CONTROL_ON: u8 = 1 << 0;
+
+/// Bit in the Power Control register which suspends the system. The DC power
+/// stays on, but the main board is held in reset.
+pub const POWER_CONTROL_SUSPEND: u8 = 1 << 1;
+
+/// Bit in the Wake Control register which powers on the system when the PS/2
+/// keyboard sends us a byte.
+pub const WAKE_ON_KEYBOARD: u8 = 1 << 0;
+
+/// Bit in the Wake Control register which powers on the system when a byte
+/// arrives on the UART.
+pub const WAKE_ON_UART: u8 = 1 << 1;
+
+/// Bit in the Wake Control register which powers on the system when the RTC
+/// alarm goes off.
+pub const WAKE_ON_ALARM: u8 = 1 << 2;
+
+/// Bit in the PS/2 Keyboard Control register which puts only new key presses
+/// in the keyboard FIFO, as key state bitmap bit numbers, instead of the raw
+/// scancodes.
+pub const KEYBOARD_CONTROL_MAKE_ONLY: u8 = 1 << 0;
+
+/// Bit in the PS/2 Keyboard Control register which has us repeat a held key
+/// in make-only mode (see [`crate::keyboard::KeyRepeat`])
+pub const KEYBOARD_CONTROL_REPEAT: u8 = 1 << 1;
+
+/// Bit in the PS/2 Keyboard Control register which puts the character each
+/// key press types in the keyboard ASCII FIFO (see
+/// [`crate::keyboard::AsciiKeys`])
+pub const KEYBOARD_CONTROL_ASCII: u8 = 1 << 2;
+
+/// The fastest SPI clock we can keep up with, in units of 100 kHz. We handle
+/// every byte in an interrupt, so we can't go much faster than this without
+/// DMA.
+pub const SPI_MAX_CLOCK: u8 = 10;
+
+/// The SPI clock we assume until the host tells us otherwise, in units of
+/// 100 kHz.
+pub const SPI_DEFAULT_CLOCK: u8 = 10;
+
+/// The SPI modes we support, as a bitmask (bit 0 is mode 0, etc)
+pub const SPI_MODES: u8 = 1 << 0;
+
+/// The shortest time the host can stop clocking in the middle of an SPI
+/// transaction before we give up on it, in milliseconds.
+pub const SPI_WATCHDOG_MIN_MS: u32 = 10;
+
+/// How many bits the SPI watchdog gives the host time to send - four times
+/// the longest request and response.
+const SPI_WATCHDOG_BITS: u32 = 4 * 64 * 8;
+
+/// How many notes the host can queue up for the buzzer
+pub const BUZZER_QUEUE_LEN: usize = 16;
+
+/// Write this to the Buzzer Queue register to throw away the queued notes
+pub const BUZZER_COMMAND_FLUSH: u8 = 0;
+
+/// Write this to the Buzzer Queue register to queue the staged note
+pub const BUZZER_COMMAND_QUEUE: u8 = 1;
+
+/// How many bytes received on the UART we keep for the host. That's a few
+/// milliseconds' worth at the highest baud rates.
+pub const UART_RX_FIFO_LEN: usize = 256;
+
+/// How many bytes the host can queue up to send on the UART
+pub const UART_TX_FIFO_LEN: usize = 32;
+
+/// How many bytes received on UART2 we keep for the host
+pub const UART2_RX_FIFO_LEN: usize = 64;
+
+/// How many bytes the host can queue up to send on UART2
+pub const UART2_TX_FIFO_LEN: usize = 32;
+
+/// How many bytes received from the PS/2 keyboard we keep for the host
+pub const KEYBOARD_RX_FIFO_LEN: usize = 16;
+
+/// How many characters typed on the PS/2 keyboard we keep for the host, in
+/// ASCII mode
+pub const KEYBOARD_ASCII_FIFO_LEN: usize = 16;
+
+#[cfg(feature = "adc")]
+/// How many bytes the Analog Calibration registers hold
+pub const ANALOG_CALIBRATION_LEN: usize = analog::ANALOG_INPUTS * Calibration::SIZE;
+
+#[cfg(feature = "adc")]
+/// Write this to the Analog Calibrate register to calibrate every analog
+/// input from the last sample, with the rails at exactly 3.3V and 5.0V
+pub const ANALOG_CALIBRATE_FACTORY: u8 = 0x01;
+
+#[cfg(feature = "adc")]
+/// Write this to the Analog Calibrate register to go back to the voltage at
+/// the pins
+pub const ANALOG_CALIBRATE_CLEAR: u8 = 0x02;
+
+/// How many bytes the PS/2 Bit Timing register holds
+pub const PS2_TIMING_LEN: usize = 2 * BitTiming::SIZE;
+
+/// How many injected scancodes can wait to be handled
+pub const KEYBOARD_INJECT_FIFO_LEN: usize = 16;
+
+/// How many SPI transactions we keep in the trace (only with the `spi-trace`
+/// feature)
+pub const SPI_TRACE_LEN: usize = 16;
+
+/// How many complete packets we keep from the PS/2 mouse. That's as many as
+/// fit in one read.
+pub const MOUSE_QUEUE_LEN: usize = READ_BUFFER_LEN / MouseReport::SIZE;
+
+/// Bit in the Fan Control register which sets the duty cycle from the
+/// temperature automatically.
+pub const FAN_AUTO: u8 = 1 << 0;
+
+/// Bit in the BMC Status register which is set once the firmware has
+/// finished starting up
+pub const BMC_STATUS_READY: u8 = 1 << 0;
+
+/// Value of the Config Store register when nothing valid was found in flash
+pub const CONFIG_STATUS_DEFAULTS: u8 = 0;
+
+/// Value of the Config Store register when the config was loaded from flash
+pub const CONFIG_STATUS_LOADED: u8 = 1;
+
+/// Value of the Config Store register when the config was saved to flash
+pub const CONFIG_STATUS_SAVED: u8 = 2;
+
+/// Value of the Config Store register when saving the config failed
+pub const CONFIG_STATUS_SAVE_FAILED: u8 = 3;
+
+/// Write this to the Config Store register to save the config to flash
+pub const CONFIG_COMMAND_SAVE: u8 = 1;
+
+/// Write this to the Config Store register to go back to the default config
+pub const CONFIG_COMMAND_DEFAULTS: u8 = 2;
+
+/// How big a buffer [`handle_request`] needs for rendering register contents.
+pub const READ_BUFFER_LEN: usize = proto::registers::MAX_READ_LEN as usize;
+
+/// How many bytes a *Bulk Read Start* can take - as many as its length byte
+/// can ask for.
+pub const BULK_BUFFER_LEN: usize = u8::MAX as usize;
+
+/// How big a buffer the host links need for the payload of a *Bulk Write
+/// Continue* - as many bytes as a read can return, plus the CRC.
+pub const PAYLOAD_BUFFER_LEN: usize = READ_BUFFER_LEN + 1;
+
+/// Optional features compiled into this firmware, as reported in the Build
+/// Info register.
+pub const FEATURE_FLAGS: u32 = {
+	use proto::registers::*;
+	let mut flags = 0;
+	if cfg!(feature = "current-sense") {
+		flags |= FEATURE_CURRENT_SENSE;
+	}
+	if cfg!(feature = "fan") {
+		flags |= FEATURE_FAN;
+	}
+	if cfg!(feature = "uart2") {
+		flags |= FEATURE_UART2;
+	}
+	if cfg!(feature = "encoder") {
+		flags |= FEATURE_ENCODER;
+	}
+	if cfg!(feature = "i2c-target") {
+		flags |= FEATURE_I2C_TARGET;
+	}
+	if cfg!(feature = "ps2-power") {
+		flags |= FEATURE_PS2_POWER;
+	}
+	if cfg!(feature = "spi-trace") {
+		flags |= FEATURE_SPI_TRACE;
+	}
+	if cfg!(feature = "status-led") {
+		flags |= FEATURE_STATUS_LED;
+	}
+	if cfg!(feature = "ps2-keyboard") {
+		flags |= FEATURE_PS2_KEYBOARD;
+	}
+	if cfg!(feature = "ps2-mouse") {
+		flags |= FEATURE_PS2_MOUSE;
+	}
+	if cfg!(feature = "speaker") {
+		flags |= FEATURE_SPEAKER;
+	}
+	if cfg!(feature = "adc") {
+		flags |= FEATURE_ADC;
+	}
+	if cfg!(feature = "usb") {
+		flags |= FEATURE_USB;
+	}
+	flags
+};
+
+/// This is our system state, as accessible via SPI reads and writes.
+#[derive(Debug)]
+pub struct RegisterState {
+	/// The protocol version, as bytes
+	pub protocol_version: [u8; 3],
+	/// The `git describe` output, null-padded
+	pub firmware_version: [u8; 32],
+	/// Information about this build, as a [`proto::BuildInfo`] block.
+	pub build_info: [u8; proto::BuildInfo::SIZE],
+	/// The heartbeat and fault flags
+	pub health: Health,
+	/// What we've already picked up from the interrupts which can't lock the
+	/// register state (see [`collect_isr_status`])
+	pub isr_status: IsrStatusReader,
+	/// Which of the registers the host is likely to poll have changed. The
+	/// caller should check for changes every few milliseconds, and after
+	/// each request (see [`sample_changes`] and [`note_access`]).
+	pub changes: ChangeTracker,
+	/// Set by the caller once the firmware has finished starting up. Until
+	/// then, only reads of the early registers are answered.
+	pub ready: bool,
+	/// The long-running operation the host has started, if any
+	pub deferred: Deferred,
+	/// The SPI clock the host says it uses, in units of 100 kHz
+	pub spi_clock: u8,
+	/// How long SPI responses take to get ready, timed by the caller
+	pub spi_latency: LatencyStats,
+	/// How long PS/2 bytes take to reach the host, timed by the caller
+	pub ps2_latency: LatencyStats,
+	/// Each PS/2 port's bit timing, picked up from the clock interrupts (see
+	/// [`collect_isr_status`])
+	pub ps2_timing: [BitTiming; 2],
+	/// The bad words from each PS/2 port, and the resends we owe them
+	pub ps2_errors: Ps2Errors,
+	/// The lifetime counters, which the caller keeps up to date
+	pub stats: Stats,
+	/// Set when the host asks to power on, power off or suspend. The caller
+	/// should clear it and carry it out.
+	pub power_request: Option<PowerAction>,
+	/// Set when the host asks to reset the main board, to the length of the
+	/// pulse in milliseconds. The caller should clear it and carry it out.
+	pub reset_request: Option<u32>,
+	/// The length of the last reset pulse the host asked for, in units of
+	/// 10 ms, or zero if it hasn't asked for one
+	pub host_reset: u8,
+	/// What the main board should do when it next boots (see
+	/// `power::BOOT_MODE_NORMAL`, etc). The caller keeps it in the power
+	/// state journal, so it survives the BMC losing power.
+	pub boot_mode: u8,
+	/// The host's NVRAM, which the caller loads from flash
+	pub nvram: Nvram,
+	/// Set when the host asks for `nvram` to be committed to flash. The
+	/// caller should clear it and start the commit.
+	pub commit_nvram: bool,
+	/// The date, time and alarm, which the caller keeps up to date from the
+	/// RTC
+	pub rtc: RtcState,
+	/// The DC power state, which the caller keeps up to date
+	pub power_state: DcPowerState,
+	/// Which events will power on the system (see `WAKE_ON_KEYBOARD`, etc)
+	pub wake_control: u8,
+	/// What each button does
+	pub buttons: [ButtonConfig; BUTTONS],
+	/// Which buttons are pressed, as a bitmask
+	pub button_status: u8,
+	/// Button presses waiting for the host - bit N is a short press of
+	/// button N, and bit N + 4 a long press
+	pub button_events: u8,
+	/// How many glitches the glitch filters have thrown away
+	pub glitches: GlitchCounters,
+	/// General purpose storage for the host. This lives in our RAM, so it
+	/// survives the main board being reset or powered off.
+	pub scratch: [u8; 32],
+	/// Settings which are kept in flash
+	pub config: Config,
+	/// Where `config` came from (see `CONFIG_STATUS_LOADED`, etc)
+	pub config_status: u8,
+	/// Set when the host asks for `config` to be saved to flash. The caller
+	/// should clear it and start the save.
+	pub save_config: bool,
+	/// Set to the command (`selftest::COMMAND_RUN` or
+	/// `selftest::COMMAND_PROTOCOL`) when the host asks for a self-test. The
+	/// caller should clear it and start the test.
+	pub run_self_test: Option<u8>,
+	/// The outcome of the most recent self-test
+	pub self_test_report: SelfTestReport,
+	/// The most recent main board current reading, in milliamps
+	pub main_current_ma: u16,
+	/// The highest main board current reading, in milliamps
+	pub peak_current_ma: u16,
+	/// The internal temperature sensor reading, in °C
+	pub temperature_c: i8,
+	/// How the fan is controlled (see `FAN_AUTO`)
+	pub fan_control: u8,
+	/// The fan PWM duty cycle, as a percentage
+	pub fan_duty: u8,
+	/// The fan speed, in RPM
+	pub fan_rpm: u16,
+	/// Has the host reported disk activity since the LED last flickered?
+	pub activity_pending: bool,
+	/// Bytes received on the UART, waiting for the host
+	pub uart_rx: ByteFifo<UART_RX_FIFO_LEN>,
+	/// Bytes the host wants sent on the UART. The caller should start the
+	/// transmitter.
+	pub uart_tx: ByteFifo<UART_TX_FIFO_LEN>,
+	/// Set to the number of bytes to send when the host asks for a UART
+	/// self-test. The caller should clear it and start the test.
+	pub uart_self_test: Option<u16>,
+	/// The outcome of the most recent UART self-test
+	pub uart_self_test_results: SelfTestResults,
+	/// The second serial port (only used with the `uart2` feature)
+	pub uart2: SerialPort<UART2_RX_FIFO_LEN, UART2_TX_FIFO_LEN>,
+	/// Bytes received from the PS/2 keyboard (or injected), waiting for the
+	/// host
+	pub kb_rx: ByteFifo<KEYBOARD_RX_FIFO_LEN>,
+	/// Characters typed on the PS/2 keyboard, in ASCII mode
+	pub kb_ascii: ByteFifo<KEYBOARD_ASCII_FIFO_LEN>,
+	/// Scancodes the host injected. The caller should pass them on to be
+	/// handled as if they came from the keyboard.
+	pub kb_inject: ByteFifo<KEYBOARD_INJECT_FIFO_LEN>,
+	/// How keyboard input reaches the host (see `KEYBOARD_CONTROL_MAKE_ONLY`).
+	/// The caller follows it, and clears it when the main board starts
+	/// afresh.
+	pub kb_control: u8,
+	/// Which keys are held down on the PS/2 keyboard
+	pub key_state: KeyState,
+	/// Which keys were held down just after power-on
+	pub boot_keys: BootKeys,
+	/// Watches for the keys which reset the main board
+	pub reset_chord: ResetChord,
+	/// Repeats the last key pressed, for hosts which want us to
+	pub key_repeat: KeyRepeat,
+	/// Turns key presses into characters, for ASCII mode
+	pub ascii_keys: AsciiKeys,
+	/// The result of resetting and identifying the keyboard
+	pub keyboard_id: KeyboardId,
+	/// Packets from the PS/2 mouse, and the total movement
+	pub mouse: Mouse<MOUSE_QUEUE_LEN>,
+	/// The spare pins the host can use as GPIO
+	pub gpio: Gpio,
+	/// The PWM output on GPIO 0 (not used with the `fan` feature)
+	pub pwm: Pwm,
+	/// The analog input settings, and the latest readings
+	pub analog: AnalogInputs,
+	/// When each ADC channel is sampled, and how it is filtered
+	pub adc_schedule: AdcScheduler,
+	/// The rotary encoder position (only used with the `encoder` feature)
+	pub encoder: Encoder,
+	/// Which PS/2 ports have power (only used with the `ps2-power` feature)
+	pub ps2_power: Ps2Power,
+	/// The RGB status LED's colour and pattern (only used with the
+	/// `status-led` feature)
+	pub status_led: StatusLed,
+	/// The host's watchdog, and how long it has left
+	pub watchdog: HostWatchdog,
+	/// How long the rails and the reset line took at the last power-on
+	pub rise_times: RiseTimes,
+	/// The register watches (see [`crate::watch`])
+	pub watches: Watches,
+	/// The note the host is setting up, ready to be queued
+	pub buzzer_note: Note,
+	/// Notes waiting to be played on the buzzer
+	pub buzzer_queue: NoteQueue<BUZZER_QUEUE_LEN>,
+	/// Bytes taken by the last *Bulk Read Start*, waiting for the host
+	pub bulk: BulkBuffer<BULK_BUFFER_LEN>,
+	/// Whether writes to the guarded registers are refused
+	pub config_lock: ConfigLock,
+	/// The readout protection level in force now, and the one we'll have
+	/// after the next power-up, which the caller keeps up to date
+	pub readout_levels: (Level, Level),
+	/// How far through arming readout protection the host has got
+	pub readout_arming: Arming,
+	/// Set when the host asks for readout protection. The caller should clear
+	/// it and program the option bytes.
+	pub enable_readout_protection: bool,
+	/// The least important log messages to send (see [`logging`]), which the
+	/// caller passes on after each request
+	pub log_level: u8,
+	/// Which subsystems to send log messages from (see [`logging`])
+	pub log_subsystems: u8,
+	/// What we send over SPI when we have nothing else to send. The caller
+	/// passes it on to the SPI driver at the start of each transaction.
+	pub spi_padding: u8,
+	/// Do we send `SYNC_BYTE` before each SPI response? The caller passes it
+	/// on with `spi_padding`.
+	pub spi_sync: bool,
+	/// Do characters received on the UART type on the keyboard (see
+	/// [`crate::console`]), instead of going in `uart_rx`?
+	pub console_takeover: bool,
+	/// Is the host using the UART as its console, so we should look for
+	/// escapes in what arrives (see [`crate::console`])?
+	pub remote_console: bool,
+	/// The last few SPI transactions, which the caller records
+	#[cfg(feature = "spi-trace")]
+	pub spi_trace: SpiTrace<SPI_TRACE_LEN>,
+}
+
+impl RegisterState {
+	/// Create the register state for this firmware.
+	///
+	/// `firmware_version` is truncated to 31 bytes, so there is always at least
+	/// one null on the end.
+	pub fn new(
+		firmware_version: &str,
+		build_info: &proto::BuildInfo,
+		config: Config,
+		config_status: u8,
+	) -> RegisterState {
+		let mut version_buffer = [0u8; 32];
+		for (dest, src) in version_buffer[0..31]
+			.iter_mut()
+			.zip(firmware_version.bytes())
+		{
+			*dest = src;
+		}
+		let mut gpio = Gpio::new();
+		if let Some(pin) = power::presence_pin(config.board_presence) {
+			// As if the presence strap had just been chosen
+			gpio.set_pin_pull(pin, expansion::PULL_UP);
+		}
+		let mut buttons = DEFAULT_BUTTONS;
+		if let Some(reset) = ButtonConfig::from_byte(config.reset_button) {
+			buttons[BUTTON_RESET] = reset;
+		}
+		RegisterState {
+			protocol_version: build_info.protocol_version.as_bytes(),
+			firmware_version: version_buffer,
+			build_info: build_info.as_bytes(),
+			health: Health::new(),
+			isr_status: IsrStatusReader::new(),
+			changes: ChangeTracker::new(),
+			ready: false,
+			deferred: Deferred::new(),
+			spi_clock: SPI_DEFAULT_CLOCK,
+			spi_latency: LatencyStats::new(),
+			ps2_latency: LatencyStats::new(),
+			ps2_timing: [BitTiming::default(); 2],
+			ps2_errors: Ps2Errors::new(),
+			stats: Stats::new(),
+			power_request: None,
+			reset_request: None,
+			host_reset: 0,
+			boot_mode: power::BOOT_MODE_NORMAL,
+			nvram: Nvram::new(),
+			commit_nvram: false,
+			rtc: RtcState::new(),
+			power_state: DcPowerState::Off,
+			wake_control: 0,
+			buttons,
+			button_status: 0,
+			button_events: 0,
+			glitches: GlitchCounters::new(),
+			scratch: [0u8; 32],
+			config,
+			config_status,
+			save_config: false,
+			run_self_test: None,
+			self_test_report: SelfTestReport::new(),
+			main_current_ma: 0,
+			peak_current_ma: 0,
+			temperature_c: 0,
+			fan_control: FAN_AUTO,
+			fan_duty: 100,
+			fan_rpm: 0,
+			activity_pending: false,
+			uart_rx: ByteFifo::new(),
+			uart_tx: ByteFifo::new(),
+			uart_self_test: None,
+			uart_self_test_results: SelfTestResults::default(),
+			uart2: SerialPort::new(),
+			kb_rx: ByteFifo::new(),
+			kb_ascii: ByteFifo::new(),
+			kb_inject: ByteFifo::new(),
+			kb_control: 0,
+			key_state: KeyState::new(),
+			boot_keys: BootKeys::new(),
+			reset_chord: ResetChord::new(),
+			key_repeat: KeyRepeat::new(),
+			ascii_keys: AsciiKeys::new(),
+			keyboard_id: KeyboardId::new(),
+			mouse: Mouse::new(),
+			gpio,
+			pwm: Pwm::new(),
+			analog: AnalogInputs::new(),
+			adc_schedule: AdcScheduler::new(),
+			encoder: Encoder::new(),
+			ps2_power: Ps2Power::new(),
+			status_led: StatusLed::new(),
+			watchdog: HostWatchdog::new(),
+			rise_times: RiseTimes::new(),
+			watches: Watches::new(),
+			buzzer_note: Note::EMPTY,
+			buzzer_queue: NoteQueue::new(),
+			bulk: BulkBuffer::new(),
+			config_lock: ConfigLock::new(),
+			readout_levels: (Level::Level0, Level::Level0),
+			readout_arming: Arming::new(),
+			enable_readout_protection: false,
+			log_level: logging::DEFAULT_LEVEL,
+			log_subsystems: logging::DEFAULT_SUBSYSTEMS,
+			spi_padding: proto::PADDING_BYTE,
+			spi_sync: false,
+			console_takeover: false,
+			remote_console: false,
+			#[cfg(feature = "spi-trace")]
+			spi_trace: SpiTrace::new(),
+		}
+	}
+
+	/// How long the host can stop clocking in the middle of an SPI
+	/// transaction before we give up on it, in milliseconds.
+	///
+	/// This depends on the SPI clock the host said it would use, but is never
+	/// less than `SPI_WATCHDOG_MIN_MS`.
+	pub fn spi_watchdog_ms(&self) -> u32 {
+		let bits_per_ms = u32::from(self.spi_clock.max(1)) * 100;
+		(SPI_WATCHDOG_BITS / bits_per_ms).max(SPI_WATCHDOG_MIN_MS)
+	}
+}
+
+/// Summarise each of the registers the Register Changes register watches
+/// (see [`crate::changes::WATCHED`]).
+fn watched_values<const N: usize>(
+	register_state: &RegisterState,
+	event_log: &EventLog<N>,
+	irq: &InterruptController,
+) -> [u16; WATCHED_LEN] {
+	let power = match register_state.power_state {
+		DcPowerState::Off => 0,
+		DcPowerState::Suspended => POWER_CONTROL_ON | POWER_CONTROL_SUSPEND,
+		DcPowerState::Starting | DcPowerState::On => POWER_CONTROL_ON,
+	};
+	[
+		irq.pending(),
+		u16::from(register_state.health.flags()),
+		u16::from_le_bytes([register_state.button_status, register_state.button_events]),
+		u16::from(power),
+		event_log.len() as u16,
+		register_state.uart_rx.len() as u16,
+		register_state.kb_rx.len() as u16,
+		register_state.mouse.len() as u16,
+	]
+}
+
+/// Pick up the faults and glitches noted by the interrupts which can't lock
+/// the register state. The caller should do this before answering each
+/// request, and every so often, so the host always sees them.
+pub fn collect_isr_status(register_state: &mut RegisterState, status: &IsrStatus) {
+	let report = register_state.isr_status.collect(status);
+	register_state.health.report(report.health);
+	register_state
+		.glitches
+		.add(glitch::INPUT_CS, report.cs_glitches);
+	register_state.ps2_timing = report.ps2_timing;
+}
+
+/// Look for changes in the registers the Register Changes register watches.
+pub fn sample_changes<const N: usize>(
+	register_state: &mut RegisterState,
+	event_log: &EventLog<N>,
+	irq: &InterruptController,
+) {
+	let current = watched_values(register_state, event_log, irq);
+	register_state.changes.sample(&current);
+}
+
+/// How many bytes we read from a watch's register, or `None` if it can't be
+/// watched.
+///
+/// The register must be there, and have a set length (so it can't be a
+/// FIFO), and the field must fit in it. Reading the PS/2 Mouse Movement
+/// register takes the movement, so that can't be watched either.
+fn watch_read_len(watch: &Watch) -> Option<u8> {
+	let register = proto::registers::find(watch.register)?;
+	if !register.is_available(FEATURE_FLAGS)
+		|| register.access == proto::registers::Access::Fifo
+		|| register.address == proto::registers::MOUSE_MOVEMENT.address
+	{
+		return None;
+	}
+	let length = register.fixed_len(watch.register)?;
+	if watch.fits(usize::from(length)) {
+		Some(length)
+	} else {
+		None
+	}
+}
+
+/// Read each watched register, and raise the Watch Triggered interrupt if
+/// any watch has tripped (see [`crate::watch`]).
+///
+/// The reads don't count as the host's, so they don't get in the way of an
+/// unlock sequence, or clear any Register Changes bits.
+pub fn sample_watches<const N: usize>(
+	register_state: &mut RegisterState,
+	event_log: &mut EventLog<N>,
+	irq: &mut InterruptController,
+) {
+	let mut read_buffer = [0u8; READ_BUFFER_LEN];
+	let mut tripped = false;
+	for index in 0..WATCHES {
+		let watch = register_state.watches.get(index);
+		let value = match watch_read_len(&watch) {
+			Some(length) if watch.comparison != Comparison::Off => {
+				let read = proto::Request::new_read(false, watch.register, length);
+				let rsp = answer(&read, register_state, event_log, irq, &mut read_buffer);
+				if rsp.result == proto::ResponseResult::Ok {
+					watch.value(rsp.data)
+				} else {
+					None
+				}
+			}
+			_ => None,
+		};
+		tripped |= register_state.watches.sample(index, value);
+	}
+	if tripped {
+		irq.raise(irq::WATCH_TRIGGERED);
+	}
+}
+
+/// Work out the level of each interrupt source which has one (see
+/// [`irq::LEVEL_SOURCES`]), for [`InterruptController::set_level`].
+pub fn interrupt_level(register_state: &RegisterState) -> u16 {
+	let limit_c = register_state.config.over_temperature_limit_c;
+	let sources = [
+		(
+			irq::KEYBOARD_RX_NOT_EMPTY,
+			!register_state.kb_rx.is_empty() || !register_state.kb_ascii.is_empty(),
+		),
+		(irq::MOUSE_RX_NOT_EMPTY, !register_state.mouse.is_empty()),
+		(irq::UART_RX_NOT_EMPTY, !register_state.uart_rx.is_empty()),
+		(irq::UART_TX_EMPTY, register_state.uart_tx.is_empty()),
+		(
+			irq::UART2_RX_NOT_EMPTY,
+			register_state.uart2.is_enabled() && !register_state.uart2.rx.is_empty(),
+		),
+		(
+			irq::UART2_TX_EMPTY,
+			register_state.uart2.is_enabled() && register_state.uart2.tx.is_empty(),
+		),
+		(
+			irq::OVER_TEMPERATURE,
+			limit_c != 0 && i16::from(register_state.temperature_c) >= i16::from(limit_c),
+		),
+		(
+			irq::WATCHDOG_PRETIMEOUT,
+			register_state.watchdog.in_pretimeout(),
+		),
+	];
+	sources
+		.iter()
+		.filter(|(_, active)| *active)
+		.fold(0, |level, (bit, _)| level | bit)
+}
+
+/// The host has read or written the register in `req`, so it is up to date
+/// with it. Call this once the response has gone.
+pub fn note_access<const N: usize>(
+	req: &proto::Request,
+	register_state: &mut RegisterState,
+	event_log: &EventLog<N>,
+	irq: &InterruptController,
+) {
+	// The register field holds an offset in these
+	if matches!(
+		req.request_type,
+		proto::RequestType::BulkReadContinue
+			| proto::RequestType::BulkReadContinueAlt
+			| proto::RequestType::BulkWriteContinue
+			| proto::RequestType::BulkWriteContinueAlt
+	) {
+		return;
+	}
+	let current = watched_values(register_state, event_log, irq);
+	if matches!(
+		req.request_type,
+		proto::RequestType::WideRead | proto::RequestType::WideReadAlt
+	) {
+		// Every register in the run was read
+		if let Some((width, count)) = req.wide_read_shape() {
+			for (address, _) in
+				proto::registers::wide_read_pieces(req.register, width, count, FEATURE_FLAGS)
+					.flatten()
+			{
+				register_state.changes.accessed(address, &current);
+			}
+		}
+		return;
+	}
+	register_state.changes.accessed(req.register, &current);
+}
+
+/// Say which result to send back for a request from the host we couldn't
+/// decode.
+pub fn bad_request_result(e: proto::Error) -> proto::ResponseResult {
+	match e {
+		proto::Error::BadCrc => proto::ResponseResult::CrcFailure,
+		// Only a frame cut short by chip select rising
+		proto::Error::BadLength => proto::ResponseResult::BadLength,
+		_ => proto::ResponseResult::BadRequestType,
+	}
+}
+
+/// Work out the response to a request from the host.
+///
+/// Register contents that aren't stored as bytes are rendered into
+/// `read_buffer`. Writes to the interrupt registers change `irq`, so the
+/// caller should update the IRQ line afterwards.
+///
+/// *Set Bits* and *Clear Bits* requests are a read and a write of the
+/// register, back to back. A *Bulk Read Start* is a run of reads, with the
+/// bytes kept in the [`BulkBuffer`] for *Bulk Read Continue* requests. A
+/// *Bulk Write Continue* is only checked here - its payload is dealt with by
+/// [`handle_payload`]. A *Wide Read* is a read of each register in a run, all
+/// checked before any is made.
+///
+/// Whilst the [`ConfigLock`] is locked, any request which writes to a guarded
+/// register gets a `Locked` result, and nothing changes.
+///
+/// Until the firmware has finished starting up, anything but a read of an
+/// early register gets a `NotReady` result, and nothing changes.
+pub fn handle_request<'a, const N: usize>(
+	req: &proto::Request,
+	register_state: &'a mut RegisterState,
+	event_log: &mut EventLog<N>,
+	irq: &mut InterruptController,
+	read_buffer: &'a mut [u8; READ_BUFFER_LEN],
+) -> proto::Response<'a> {
+	if !register_state.ready {
+		let is_read = matches!(
+			req.request_type,
+			proto::RequestType::Read | proto::RequestType::ReadAlt
+		);
+		let is_early = proto::registers::find(req.register)
+			.map(|register| register.is_early())
+			.unwrap_or(false);
+		if !(is_read && is_early) {
+			return proto::Response::new_without_data(proto::ResponseResult::NotReady);
+		}
+	}
+	let is_write = req.request_type.is_write();
+	// Anything but a write to the lock itself breaks an unlock sequence
+	if !(is_write && req.register == proto::registers::CONFIG_LOCK.address) {
+		register_state.config_lock.interrupt();
+	}
+	if !(is_write && req.register == proto::registers::READOUT_PROTECTION.address) {
+		register_state.readout_arming.interrupt();
+	}
+	if is_write && register_state.config_lock.is_locked() {
+		let is_guarded = proto::registers::find(req.register)
+			.map(|register| register.is_guarded() && register.is_available(FEATURE_FLAGS))
+			.unwrap_or(false);
+		if is_guarded {
+			register_state
+				.health
+				.report_error(proto::ResponseResult::Locked as u8);
+			return proto::Response::new_without_data(proto::ResponseResult::Locked);
+		}
+	}
+	answer(req, register_state, event_log, irq, read_buffer)
+}
+
+/// The payload of a request, with its CRC, as it arrived from the host - for
+/// the links which collect a whole frame before it is answered.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PayloadBuffer {
+	/// The bytes
+	bytes: [u8; PAYLOAD_BUFFER_LEN],
+	/// How many bytes of `bytes` are used
+	len: usize,
+}
+
+impl PayloadBuffer {
+	/// Copy a payload.
+	///
+	/// Returns `None` if it is too long to be one we accept.
+	pub fn new(data: &[u8]) -> Option<PayloadBuffer> {
+		let mut bytes = [0u8; PAYLOAD_BUFFER_LEN];
+		bytes.get_mut(0..data.len())?.copy_from_slice(data);
+		Some(PayloadBuffer {
+			bytes,
+			len: data.len(),
+		})
+	}
+
+	/// Get the bytes, as they arrived.
+	pub fn as_bytes(&self) -> &[u8] {
+		&self.bytes[0..self.len]
+	}
+}
+
+/// Work out the second response to a request which carries a payload, once
+/// the payload has arrived.
+///
+/// `data` is the payload, with its CRC checked and taken off. Only a *Bulk
+/// Write Continue* carries a payload we accept, and [`handle_request`] must
+/// have given it an `Ok` result first. Once every piece of a bulk write has
+/// arrived, the bytes are written to the register as if by *Short Write*
+/// requests, stopping at the first which fails - the rest are kept, so the
+/// host can send the last piece again later to carry on.
+pub fn handle_payload<'a, const N: usize>(
+	req: &proto::Request,
+	data: &[u8],
+	register_state: &'a mut RegisterState,
+	event_log: &mut EventLog<N>,
+	irq: &mut InterruptController,
+	read_buffer: &'a mut [u8; READ_BUFFER_LEN],
+) -> proto::Response<'a> {
+	if !matches!(
+		req.request_type,
+		proto::RequestType::BulkWriteContinue | proto::RequestType::BulkWriteContinueAlt
+	) {
+		return proto::Response::new_without_data(proto::ResponseResult::BadRequestType);
+	}
+	// The offset is where the register usually goes
+	let offset = req.register as usize;
+	if data.len() != req.length_or_data as usize || !register_state.bulk.receive(offset, data) {
+		return proto::Response::new_without_data(proto::ResponseResult::BadLength);
+	}
+	// Moved out whilst we write from it, as the writes borrow the register
+	// state
+	let mut bulk = core::mem::take(&mut register_state.bulk);
+	let mut result = proto::ResponseResult::Ok;
+	if let Some((register, bytes)) = bulk.unwritten() {
+		let mut written = 0;
+		for &byte in bytes {
+			let write = proto::Request::new_short_write(false, register, byte);
+			let rsp = handle_request(
+				&write,
+				&mut *register_state,
+				&mut *event_log,
+				&mut *irq,
+				&mut *read_buffer,
+			);
+			if rsp.result != proto::ResponseResult::Ok {
+				result = rsp.result;
+				break;
+			}
+			written += 1;
+		}
+		bulk.mark_written(written);
+	}
+	register_state.bulk = bulk;
+	proto::Response::new_without_data(result)
+}
+
+/// Work out the response to a request, once [`handle_request`] has checked
+/// that we can answer it.
+fn answer<'a, const N: usize>(
+	req: &proto::Request,
+	register_state: &'a mut RegisterState,
+	event_log: &mut EventLog<N>,
+	irq: &mut InterruptController,
+	read_buffer: &'a mut [u8; READ_BUFFER_LEN],
+) -> proto::Response<'a> {
+	match req.request_type {
+		proto::RequestType::Read | proto::RequestType::ReadAlt => {
+			match req.register {
+				0x00 => {
+					if req.length_or_data != 3 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						proto::Response::new_ok_with_data(&register_state.protocol_version)
+					}
+				}
+				0x01 => {
+					let length = req.length_or_data as usize;
+					if length > register_state.firmware_version.len() {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						let bytes = &register_state.firmware_version;
+						proto::Response::new_ok_with_data(&bytes[0..length])
+					}
+				}
+				0x02 => {
+					// The whole block, or just the start of it
+					let length = req.length_or_data as usize;
+					if length > register_state.build_info.len() {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						let bytes = &register_state.build_info;
+						proto::Response::new_ok_with_data(&bytes[0..length])
+					}
+				}
+				0x03..=0x06 => {
+					// Accessors for individual fields within the Build Info block
+					let (offset, field_length) = match req.register {
+						0x03 => (1, 3),
+						0x04 => (8, 8),
+						0x05 => (16, 4),
+						_ => (20, 4),
+					};
+					if req.length_or_data as usize != field_length {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						let bytes = &register_state.build_info;
+						proto::Response::new_ok_with_data(&bytes[offset..offset + field_length])
+					}
+				}
+				0x07 => {
+					if req.length_or_data as usize != Health::SIZE {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0..Health::SIZE]
+							.copy_from_slice(&register_state.health.as_bytes());
+						proto::Response::new_ok_with_data(&read_buffer[0..Health::SIZE])
+					}
+				}
+				0x08..=0x0A => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0] = match req.register {
+							0x08 => SPI_MAX_CLOCK,
+							0x09 => SPI_MODES,
+							_ => register_state.spi_clock,
+						};
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				0x0B => {
+					if req.length_or_data as usize != Deferred::SIZE {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0..Deferred::SIZE]
+							.copy_from_slice(&register_state.deferred.as_bytes());
+						proto::Response::new_ok_with_data(&read_buffer[0..Deferred::SIZE])
+					}
+				}
+				0x0C => {
+					if req.length_or_data as usize != SelfTestReport::SIZE {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0..SelfTestReport::SIZE]
+							.copy_from_slice(&register_state.self_test_report.as_bytes());
+						proto::Response::new_ok_with_data(&read_buffer[0..SelfTestReport::SIZE])
+					}
+				}
+				0x0D => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0] = register_state.config_lock.is_locked() as u8;
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				0x0E => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0] = if register_state.ready {
+							BMC_STATUS_READY
+						} else {
+							0
+						};
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				0x0F => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						proto::Response::new_ok_with_data(core::slice::from_ref(
+							&register_state.config_status,
+						))
+					}
+				}
+				0x10..=0x15 => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0] = match req.register {
+							0x10 => irq.pending() as u8,
+							0x11 => irq.enabled() as u8,
+							0x12 => irq.uart_rx.threshold(),
+							0x13 => irq.uart_rx.timeout_ms(),
+							0x14 => (irq.pending() >> 8) as u8,
+							_ => (irq.enabled() >> 8) as u8,
+						};
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				0x16 | 0x17 => {
+					if req.length_or_data as usize != LatencyStats::SIZE {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						let stats = if req.register == 0x16 {
+							&register_state.spi_latency
+						} else {
+							&register_state.ps2_latency
+						};
+						read_buffer[0..LatencyStats::SIZE].copy_from_slice(&stats.as_bytes());
+						proto::Response::new_ok_with_data(&read_buffer[0..LatencyStats::SIZE])
+					}
+				}
+				0x18 => {
+					if req.length_or_data as usize != Stats::SIZE {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0..Stats::SIZE]
+							.copy_from_slice(&register_state.stats.as_bytes());
+						proto::Response::new_ok_with_data(&read_buffer[0..Stats::SIZE])
+					}
+				}
+				0x19 => {
+					if req.length_or_data != 2 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						let (now, next) = register_state.readout_levels;
+						read_buffer[0] = now as u8;
+						read_buffer[1] = next as u8;
+						proto::Response::new_ok_with_data(&read_buffer[0..2])
+					}
+				}
+				0x1A | 0x1B => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0] = if req.register == 0x1A {
+							register_state.log_level
+						} else {
+							register_state.log_subsystems
+						};
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				0x1C | 0x1D => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0] = if req.register == 0x1C {
+							register_state.spi_padding
+						} else {
+							u8::from(register_state.spi_sync)
+						};
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				#[cfg(feature = "spi-trace")]
+				0x1E => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0] = register_state.spi_trace.len() as u8;
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				#[cfg(feature = "spi-trace")]
+				0x1F => {
+					let length = req.length_or_data as usize;
+					if length > read_buffer.len() || (length % TraceEntry::SIZE) != 0 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						for chunk in read_buffer[0..length].chunks_exact_mut(TraceEntry::SIZE) {
+							let entry = register_state.spi_trace.pop().unwrap_or(TraceEntry::EMPTY);
+							chunk.copy_from_slice(&entry.as_bytes());
+						}
+						proto::Response::new_ok_with_data(&read_buffer[0..length])
+					}
+				}
+				0x25 => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0] = match register_state.power_state {
+							DcPowerState::Off => 0,
+							DcPowerState::Suspended => POWER_CONTROL_ON | POWER_CONTROL_SUSPEND,
+							DcPowerState::Starting | DcPowerState::On => POWER_CONTROL_ON,
+						};
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				0x26 => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						proto::Response::new_ok_with_data(core::slice::from_ref(
+							&register_state.wake_control,
+						))
+					}
+				}
+				0x27 => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0] = event_log.len() as u8;
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				0x28 => {
+					let length = req.length_or_data as usize;
+					if length > read_buffer.len() || (length % Event::SIZE) != 0 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						for chunk in read_buffer[0..length].chunks_exact_mut(Event::SIZE) {
+							let event = event_log.pop().unwrap_or(Event::EMPTY);
+							chunk.copy_from_slice(&event.as_bytes());
+						}
+						proto::Response::new_ok_with_data(&read_buffer[0..length])
+					}
+				}
+				0x20 => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0] = register_state.button_status;
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				#[cfg(all(feature = "adc", not(feature = "current-sense")))]
+				0x21 => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0] = register_state.temperature_c as u8;
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				#[cfg(feature = "current-sense")]
+				0x29 | 0x2A | 0x2C => {
+					let value = match req.register {
+						0x29 => register_state.main_current_ma,
+						0x2A => register_state.peak_current_ma,
+						_ => register_state.config.current_full_scale_ma,
+					};
+					if req.length_or_data != 2 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0..2].copy_from_slice(&value.to_le_bytes());
+						proto::Response::new_ok_with_data(&read_buffer[0..2])
+					}
+				}
+				#[cfg(feature = "current-sense")]
+				0x2B => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						proto::Response::new_ok_with_data(core::slice::from_ref(
+							&register_state.config.over_current_limit,
+						))
+					}
+				}
+				0x2E => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0] = register_state.activity_pending as u8;
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				0x2F => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						// The policy is in the bottom two bits, the delay above
+						read_buffer[0] = (register_state.config.power_restore & 0b11)
+							| (register_state.config.power_on_delay_s << 2);
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				0x30 => {
+					// Padded with zeros if the host reads more than we have
+					let length = req.length_or_data as usize;
+					if length > read_buffer.len() {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						for byte in read_buffer[0..length].iter_mut() {
+							*byte = register_state.uart_rx.pop().unwrap_or(0);
+						}
+						proto::Response::new_ok_with_data(&read_buffer[0..length])
+					}
+				}
+				0x35 => {
+					if req.length_or_data != 2 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						let count = register_state.uart_rx.len() as u16;
+						read_buffer[0..2].copy_from_slice(&count.to_le_bytes());
+						proto::Response::new_ok_with_data(&read_buffer[0..2])
+					}
+				}
+				0x36 => {
+					if req.length_or_data as usize != SelfTestResults::SIZE {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0..SelfTestResults::SIZE]
+							.copy_from_slice(&register_state.uart_self_test_results.as_bytes());
+						proto::Response::new_ok_with_data(&read_buffer[0..SelfTestResults::SIZE])
+					}
+				}
+				#[cfg(feature = "ps2-keyboard")]
+				0x37 => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0] = u8::from(register_state.console_takeover);
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				#[cfg(feature = "uart2")]
+				0x38 => {
+					// Padded with zeros if the host reads more than we have
+					let length = req.length_or_data as usize;
+					if length > read_buffer.len() {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						for byte in read_buffer[0..length].iter_mut() {
+							*byte = register_state.uart2.rx.pop().unwrap_or(0);
+						}
+						proto::Response::new_ok_with_data(&read_buffer[0..length])
+					}
+				}
+				#[cfg(feature = "uart2")]
+				0x39 | 0x3B => {
+					if req.length_or_data != 2 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						let value = if req.register == 0x39 {
+							register_state.uart2.rx.len() as u16
+						} else {
+							register_state.uart2.baud_hundreds
+						};
+						read_buffer[0..2].copy_from_slice(&value.to_le_bytes());
+						proto::Response::new_ok_with_data(&read_buffer[0..2])
+					}
+				}
+				#[cfg(feature = "uart2")]
+				0x3A | 0x3D => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0] = if req.register == 0x3A {
+							register_state.uart2.tx.space() as u8
+						} else {
+							register_state.uart2.control
+						};
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				0x3E | 0x3F => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0] = if req.register == 0x3E {
+							u8::from(register_state.remote_console)
+						} else {
+							register_state.uart_tx.space() as u8
+						};
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				#[cfg(feature = "ps2-keyboard")]
+				0x40 => {
+					// Padded with zeros if the host reads more than we have
+					let length = req.length_or_data as usize;
+					if length > read_buffer.len() {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						for byte in read_buffer[0..length].iter_mut() {
+							*byte = register_state.kb_rx.pop().unwrap_or(0);
+						}
+						proto::Response::new_ok_with_data(&read_buffer[0..length])
+					}
+				}
+				#[cfg(feature = "ps2-keyboard")]
+				0x4F => {
+					// Padded with zeros, like the keyboard FIFO
+					let length = req.length_or_data as usize;
+					if length > read_buffer.len() {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						for byte in read_buffer[0..length].iter_mut() {
+							*byte = register_state.kb_ascii.pop().unwrap_or(0);
+						}
+						proto::Response::new_ok_with_data(&read_buffer[0..length])
+					}
+				}
+				#[cfg(feature = "ps2-keyboard")]
+				0x41 => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						proto::Response::new_ok_with_data(core::slice::from_ref(
+							&register_state.kb_control,
+						))
+					}
+				}
+				#[cfg(feature = "ps2-keyboard")]
+				0x43 => {
+					// The whole bitmap, or just the start of it
+					let length = req.length_or_data as usize;
+					if length > KeyState::SIZE {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						let bytes = register_state.key_state.as_bytes();
+						proto::Response::new_ok_with_data(&bytes[0..length])
+					}
+				}
+				#[cfg(feature = "ps2-keyboard")]
+				0x44 | 0x45 => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0] = match req.register {
+							0x44 => register_state.config.boot_key,
+							_ => register_state.boot_keys.held(),
+						};
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				#[cfg(feature = "ps2-keyboard")]
+				0x46..=0x48 => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						let index = usize::from(req.register - 0x46);
+						proto::Response::new_ok_with_data(core::slice::from_ref(
+							&register_state.config.reset_chord[index],
+						))
+					}
+				}
+				#[cfg(feature = "ps2-keyboard")]
+				0x4B | 0x4C => {
+					if req.length_or_data != 2 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						let id = &register_state.keyboard_id;
+						read_buffer[0..2].copy_from_slice(&if req.register == 0x4B {
+							[id.status(), id.bat()]
+						} else {
+							id.id()
+						});
+						proto::Response::new_ok_with_data(&read_buffer[0..2])
+					}
+				}
+				#[cfg(feature = "ps2-keyboard")]
+				0x4D | 0x4E => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0] = if req.register == 0x4D {
+							register_state.kb_inject.len() as u8
+						} else {
+							register_state.key_repeat.rate()
+						};
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				#[cfg(feature = "ps2-power")]
+				0x49 | 0x4A => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0] = if req.register == 0x49 {
+							register_state.ps2_power.enabled()
+						} else {
+							register_state.ps2_power.cycling()
+						};
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				#[cfg(feature = "ps2-mouse")]
+				0x53 => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0] = register_state.mouse.len() as u8;
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				#[cfg(feature = "ps2-mouse")]
+				0x54 => {
+					let length = req.length_or_data as usize;
+					if length > read_buffer.len() || (length % MouseReport::SIZE) != 0 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						for chunk in read_buffer[0..length].chunks_exact_mut(MouseReport::SIZE) {
+							let report = register_state.mouse.pop().unwrap_or(MouseReport::EMPTY);
+							chunk.copy_from_slice(&report.as_bytes());
+						}
+						proto::Response::new_ok_with_data(&read_buffer[0..length])
+					}
+				}
+				#[cfg(feature = "ps2-mouse")]
+				0x55 => {
+					if req.length_or_data as usize != MouseMovement::SIZE {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						let movement = register_state.mouse.take_movement();
+						read_buffer[0..MouseMovement::SIZE].copy_from_slice(&movement.as_bytes());
+						proto::Response::new_ok_with_data(&read_buffer[0..MouseMovement::SIZE])
+					}
+				}
+				#[cfg(feature = "ps2-mouse")]
+				0x56 | 0x57 => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0] = match req.register {
+							0x56 => register_state.mouse.sample_rate(),
+							_ => register_state.mouse.resolution(),
+						};
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				0x58 => {
+					if req.length_or_data as usize != PS2_TIMING_LEN {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						for (chunk, timing) in read_buffer[0..PS2_TIMING_LEN]
+							.chunks_exact_mut(BitTiming::SIZE)
+							.zip(register_state.ps2_timing.iter())
+						{
+							chunk.copy_from_slice(&timing.as_bytes());
+						}
+						proto::Response::new_ok_with_data(&read_buffer[0..PS2_TIMING_LEN])
+					}
+				}
+				0x59 => {
+					if req.length_or_data as usize != Ps2Errors::SIZE {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0..Ps2Errors::SIZE]
+							.copy_from_slice(&register_state.ps2_errors.as_bytes());
+						proto::Response::new_ok_with_data(&read_buffer[0..Ps2Errors::SIZE])
+					}
+				}
+				#[cfg(all(feature = "speaker", not(feature = "current-sense")))]
+				0x80 => {
+					if req.length_or_data != 2 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0..2].copy_from_slice(
+							&register_state.buzzer_note.frequency_hz.to_le_bytes(),
+						);
+						proto::Response::new_ok_with_data(&read_buffer[0..2])
+					}
+				}
+				#[cfg(all(feature = "speaker", not(feature = "current-sense")))]
+				0x82 | 0x83 | 0x84 => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0] = match req.register {
+							0x82 => register_state.buzzer_note.duration,
+							0x83 => register_state.buzzer_note.gap,
+							_ => register_state.buzzer_queue.len() as u8,
+						};
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				0x85 => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0] = register_state.watches.selected() as u8;
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				0x86..=0x8C => {
+					// You can read from any byte up to the end of the block
+					let offset = usize::from(req.register - 0x86);
+					let length = req.length_or_data as usize;
+					let watches = &register_state.watches;
+					let bytes = watches.get(watches.selected()).as_bytes();
+					if offset + length > bytes.len() {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0..length].copy_from_slice(&bytes[offset..offset + length]);
+						proto::Response::new_ok_with_data(&read_buffer[0..length])
+					}
+				}
+				0x8D => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0] = register_state.watches.status();
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				0x90 | 0x91 => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0] = if req.register == 0x90 {
+							register_state.config.irq_line
+						} else {
+							irq.line_test_pulses()
+						};
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				0x92 => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						sample_changes(register_state, event_log, irq);
+						read_buffer[0] = register_state.changes.changed();
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				0x93 => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0] = register_state.config.board_presence;
+						if power::board_present(
+							register_state.config.board_presence,
+							register_state.gpio.input(),
+						) {
+							read_buffer[0] |= power::PRESENCE_DETECTED;
+						}
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				0x94 | 0x95 => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0] = if req.register == 0x94 {
+							register_state.config.led_mode
+						} else {
+							register_state.config.led_brightness
+						};
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				#[cfg(feature = "status-led")]
+				0x96..=0x9A => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0] = match req.register {
+							0x99 => register_state.status_led.pattern(),
+							0x9A => register_state
+								.status_led
+								.fault()
+								.map_or(0, |kind| kind as u8),
+							_ => {
+								register_state.status_led.colour()[usize::from(req.register - 0x96)]
+							}
+						};
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				0x9B => {
+					if req.length_or_data as usize != RiseTimes::SIZE {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0..RiseTimes::SIZE]
+							.copy_from_slice(&register_state.rise_times.as_bytes());
+						proto::Response::new_ok_with_data(&read_buffer[0..RiseTimes::SIZE])
+					}
+				}
+				0x9C => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0] = register_state.rise_times.faults();
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				0x9D | 0x9E => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						let [low, high] = irq.level().to_le_bytes();
+						read_buffer[0] = if req.register == 0x9D { low } else { high };
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				0xA0..=0xA4 => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0] = match req.register {
+							0xA0 => expansion::GPIO_AVAILABLE,
+							0xA1 => register_state.gpio.direction(),
+							0xA2 => register_state.gpio.output(),
+							0xA3 => register_state.gpio.input(),
+							_ => register_state.gpio.pull(),
+						};
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				#[cfg(not(any(
+					feature = "fan",
+					feature = "encoder",
+					feature = "i2c-target",
+					feature = "ps2-power",
+					feature = "status-led"
+				)))]
+				0xA8 => {
+					if req.length_or_data != 2 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0..2]
+							.copy_from_slice(&register_state.pwm.frequency_hz.to_le_bytes());
+						proto::Response::new_ok_with_data(&read_buffer[0..2])
+					}
+				}
+				#[cfg(not(any(
+					feature = "fan",
+					feature = "encoder",
+					feature = "i2c-target",
+					feature = "ps2-power",
+					feature = "status-led"
+				)))]
+				0xAA | 0xAB => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0] = if req.register == 0xAA {
+							register_state.pwm.duty_percent
+						} else {
+							register_state.pwm.control
+						};
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				#[cfg(feature = "adc")]
+				0xAC..=0xAE => {
+					// You can read from any byte up to the end of the block
+					let offset = usize::from(req.register - 0xAC);
+					let length = req.length_or_data as usize;
+					if offset + length > ADC_CHANNELS {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						for (index, byte) in read_buffer[0..length].iter_mut().enumerate() {
+							*byte = register_state.adc_schedule.interval(offset + index);
+						}
+						proto::Response::new_ok_with_data(&read_buffer[0..length])
+					}
+				}
+				#[cfg(feature = "adc")]
+				0xB0..=0xB2 => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0] = match req.register {
+							0xB0 => analog::ANALOG_AVAILABLE,
+							// Analog input 0 speaks for all of them
+							0xB1 => register_state.adc_schedule.interval(0),
+							_ => register_state.analog.averaging(),
+						};
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				#[cfg(feature = "adc")]
+				0xB3 => {
+					if usize::from(req.length_or_data) != AnalogInputs::SIZE {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0..AnalogInputs::SIZE]
+							.copy_from_slice(&register_state.analog.as_bytes());
+						proto::Response::new_ok_with_data(&read_buffer[0..AnalogInputs::SIZE])
+					}
+				}
+				#[cfg(feature = "adc")]
+				0xB4 => {
+					if req.length_or_data != 2 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						let vdda_mv = register_state.analog.vdda_mv().unwrap_or(0);
+						read_buffer[0..2].copy_from_slice(&vdda_mv.to_le_bytes());
+						proto::Response::new_ok_with_data(&read_buffer[0..2])
+					}
+				}
+				#[cfg(feature = "adc")]
+				0xB5 => {
+					let size = AnalogInputs::HISTORY_SIZE;
+					if usize::from(req.length_or_data) != size {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0..size]
+							.copy_from_slice(&register_state.analog.history_bytes());
+						proto::Response::new_ok_with_data(&read_buffer[0..size])
+					}
+				}
+				#[cfg(feature = "adc")]
+				0xB6..=0xBD => {
+					// You can read from any byte up to the end of the block
+					let offset = usize::from(req.register - 0xB6);
+					let length = req.length_or_data as usize;
+					let mut bytes = [0u8; ANALOG_CALIBRATION_LEN];
+					for (chunk, calibration) in bytes
+						.chunks_exact_mut(Calibration::SIZE)
+						.zip(register_state.config.analog_calibration.iter())
+					{
+						chunk.copy_from_slice(&calibration.as_bytes());
+					}
+					if offset + length > bytes.len() {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0..length].copy_from_slice(&bytes[offset..offset + length]);
+						proto::Response::new_ok_with_data(&read_buffer[0..length])
+					}
+				}
+				#[cfg(feature = "adc")]
+				0xBE => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						// Which inputs have been calibrated
+						read_buffer[0] = 0;
+						for (input, calibration) in
+							register_state.config.analog_calibration.iter().enumerate()
+						{
+							if *calibration != Calibration::NONE {
+								read_buffer[0] |= 1 << input;
+							}
+						}
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				#[cfg(feature = "adc")]
+				0xBF => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0] = register_state.adc_schedule.filters();
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				0xC0..=0xC6 => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0] = match req.register {
+							0xC4 => register_state.button_events,
+							0xC5 => register_state.config.button_filter,
+							0xC6 => register_state.config.cs_filter_us,
+							_ => register_state.buttons[usize::from(req.register - 0xC0)].as_byte(),
+						};
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				0xC7 => {
+					if req.length_or_data as usize != GlitchCounters::SIZE {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0..GlitchCounters::SIZE]
+							.copy_from_slice(&register_state.glitches.as_bytes());
+						proto::Response::new_ok_with_data(&read_buffer[0..GlitchCounters::SIZE])
+					}
+				}
+				#[cfg(feature = "encoder")]
+				0xC8 | 0xC9 => {
+					if req.length_or_data as usize != Encoder::SIZE {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						let value = match req.register {
+							0xC8 => register_state.encoder.position(),
+							_ => register_state.encoder.velocity(),
+						};
+						read_buffer[0..Encoder::SIZE].copy_from_slice(&value.to_le_bytes());
+						proto::Response::new_ok_with_data(&read_buffer[0..Encoder::SIZE])
+					}
+				}
+				0xCA | 0xCB => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0] = if req.register == 0xCA {
+							register_state.host_reset
+						} else {
+							register_state.boot_mode
+						};
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				0xCC | 0xCD => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0] = if req.register == 0xCC {
+							register_state.nvram.page() as u8
+						} else {
+							register_state.nvram.status()
+						};
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				0xD0..=0xDF => {
+					// You can read from any byte up to the end of the page
+					let offset = usize::from(req.register - 0xD0);
+					let length = req.length_or_data as usize;
+					let window = register_state.nvram.window();
+					if offset + length > window.len() {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0..length].copy_from_slice(&window[offset..offset + length]);
+						proto::Response::new_ok_with_data(&read_buffer[0..length])
+					}
+				}
+				0x5A..=0x5E | 0x77..=0x7D => {
+					// You can read from any byte up to the end of the block
+					let mut bytes = [0u8; DateTime::SIZE];
+					let (offset, size) = if req.register <= 0x5E {
+						bytes[0..Alarm::SIZE]
+							.copy_from_slice(&register_state.rtc.alarm().as_bytes());
+						(req.register - 0x5A, Alarm::SIZE)
+					} else {
+						bytes.copy_from_slice(&register_state.rtc.now().as_bytes());
+						(req.register - 0x77, DateTime::SIZE)
+					};
+					let offset = usize::from(offset);
+					let length = req.length_or_data as usize;
+					if offset + length > size {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0..length].copy_from_slice(&bytes[offset..offset + length]);
+						proto::Response::new_ok_with_data(&read_buffer[0..length])
+					}
+				}
+				0x75 | 0x76 => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0] = if req.register == 0x75 {
+							register_state.rtc.status()
+						} else {
+							register_state.config.rtc_clock
+						};
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				#[cfg(feature = "i2c-target")]
+				0x65 => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						proto::Response::new_ok_with_data(core::slice::from_ref(
+							&register_state.config.i2c_address,
+						))
+					}
+				}
+				0x66..=0x68 => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0] = match req.register {
+							0x66 => register_state.watchdog.timeout_s(),
+							0x67 => register_state.watchdog.pretimeout_s(),
+							_ => register_state.watchdog.remaining_s(),
+						};
+						proto::Response::new_ok_with_data(&read_buffer[0..1])
+					}
+				}
+				#[cfg(feature = "fan")]
+				0x70 | 0x71 => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						let value = if req.register == 0x70 {
+							&register_state.fan_control
+						} else {
+							&register_state.fan_duty
+						};
+						proto::Response::new_ok_with_data(core::slice::from_ref(value))
+					}
+				}
+				#[cfg(feature = "fan")]
+				0x72 => {
+					if req.length_or_data != 2 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						read_buffer[0..2].copy_from_slice(&register_state.fan_rpm.to_le_bytes());
+						proto::Response::new_ok_with_data(&read_buffer[0..2])
+					}
+				}
+				#[cfg(all(feature = "adc", not(feature = "current-sense")))]
+				0x73 | 0x74 => {
+					if req.length_or_data != 1 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						let value = if req.register == 0x73 {
+							&register_state.config.over_temperature_limit_c
+						} else {
+							&register_state.config.over_temperature_timeout_s
+						};
+						proto::Response::new_ok_with_data(core::slice::from_ref(value))
+					}
+				}
+				0xE0..=0xFF => {
+					// You can read from any scratch register up to the end of the block
+					let offset = usize::from(req.register - 0xE0);
+					let length = req.length_or_data as usize;
+					let scratch = &register_state.scratch;
+					if offset + length > scratch.len() {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						proto::Response::new_ok_with_data(&scratch[offset..offset + length])
+					}
+				}
+				_ => proto::Response::new_without_data(proto::ResponseResult::BadRegister),
+			}
+		}
+		proto::RequestType::ShortWrite | proto::RequestType::ShortWriteAlt => {
+			match req.register {
+				0x07 => {
+					// Write 1 to clear
+					register_state.health.clear(req.length_or_data);
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				0x0A => {
+					if req.length_or_data == 0 || req.length_or_data > SPI_MAX_CLOCK {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						register_state.spi_clock = req.length_or_data;
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					}
+				}
+				0x0B => {
+					if register_state.deferred.acknowledge(req.length_or_data) {
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					} else if register_state.deferred.token() == req.length_or_data {
+						// It hasn't finished yet
+						proto::Response::new_without_data(proto::ResponseResult::Busy)
+					} else {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					}
+				}
+				0x0C => {
+					if !matches!(
+						req.length_or_data,
+						selftest::COMMAND_RUN | selftest::COMMAND_PROTOCOL
+					) {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						// If another operation is still running, we don't start
+						// this one, and the host will see the old token.
+						if register_state.deferred.start(req.register).is_some() {
+							register_state.run_self_test = Some(req.length_or_data);
+						}
+						proto::Response::new_without_data(proto::ResponseResult::Busy)
+					}
+				}
+				0x0D => {
+					if register_state.config_lock.write(req.length_or_data) {
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					} else {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					}
+				}
+				0x0F => match req.length_or_data {
+					CONFIG_COMMAND_SAVE => {
+						// If another operation is still running, we don't start
+						// this one, and the host will see the old token.
+						if register_state.deferred.start(req.register).is_some() {
+							register_state.save_config = true;
+						}
+						proto::Response::new_without_data(proto::ResponseResult::Busy)
+					}
+					CONFIG_COMMAND_DEFAULTS => {
+						register_state.config = Config::DEFAULT;
+						register_state.buttons[BUTTON_RESET] = DEFAULT_BUTTONS[BUTTON_RESET];
+						register_state.config_status = CONFIG_STATUS_DEFAULTS;
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					}
+					_ => proto::Response::new_without_data(proto::ResponseResult::BadLength),
+				},
+				0x10 => {
+					// Write 1 to clear
+					irq.clear(u16::from(req.length_or_data));
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				0x11 => {
+					let enabled = (irq.enabled() & 0xFF00) | u16::from(req.length_or_data);
+					irq.set_enabled(enabled);
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				0x12 => {
+					irq.uart_rx.set_threshold(req.length_or_data);
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				0x13 => {
+					irq.uart_rx.set_timeout_ms(req.length_or_data);
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				0x14 => {
+					// Write 1 to clear
+					irq.clear(u16::from(req.length_or_data) << 8);
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				0x15 => {
+					let enabled = (irq.enabled() & 0x00FF) | (u16::from(req.length_or_data) << 8);
+					irq.set_enabled(enabled);
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				0x16 => {
+					// Any write starts the measurements again
+					register_state.spi_latency.clear();
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				0x17 => {
+					register_state.ps2_latency.clear();
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				0x19 => match register_state.readout_arming.write(req.length_or_data) {
+					readout::Command::Key => {
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					}
+					readout::Command::Enable => {
+						if register_state.readout_levels.1 != Level::Level0 {
+							// Already programmed, so there's nothing to do
+							proto::Response::new_without_data(proto::ResponseResult::Ok)
+						} else {
+							// If another operation is still running, we don't
+							// start this one, and the host will see the old
+							// token.
+							if register_state.deferred.start(req.register).is_some() {
+								register_state.enable_readout_protection = true;
+							}
+							proto::Response::new_without_data(proto::ResponseResult::Busy)
+						}
+					}
+					readout::Command::Invalid => {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					}
+				},
+				0x1A => {
+					if req.length_or_data > LogLevel::Trace as u8 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						register_state.log_level = req.length_or_data;
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					}
+				}
+				0x1B => {
+					register_state.log_subsystems =
+						req.length_or_data & logging::DEFAULT_SUBSYSTEMS;
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				0x1C => {
+					// It mustn't look like the start of a response
+					if proto::is_valid_padding(req.length_or_data) {
+						register_state.spi_padding = req.length_or_data;
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					} else {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					}
+				}
+				0x1D => match req.length_or_data {
+					0 | 1 => {
+						register_state.spi_sync = req.length_or_data == 1;
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					}
+					_ => proto::Response::new_without_data(proto::ResponseResult::BadLength),
+				},
+				#[cfg(feature = "spi-trace")]
+				0x1E => {
+					// Any write clears the trace
+					register_state.spi_trace.clear();
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				0x25 => match req.length_or_data {
+					0 => {
+						register_state.power_request = Some(PowerAction::PowerOff);
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					}
+					POWER_CONTROL_ON => {
+						// Does nothing if we're already on
+						register_state.power_request = Some(PowerAction::PowerOn);
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					}
+					x if x == POWER_CONTROL_ON | POWER_CONTROL_SUSPEND => {
+						register_state.power_request = Some(PowerAction::Suspend);
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					}
+					_ => proto::Response::new_without_data(proto::ResponseResult::BadLength),
+				},
+				0x26 => {
+					register_state.wake_control =
+						req.length_or_data & (WAKE_ON_KEYBOARD | WAKE_ON_UART | WAKE_ON_ALARM);
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				0x27 => {
+					// Any write clears the log
+					event_log.clear();
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				#[cfg(feature = "current-sense")]
+				0x2A => {
+					// Any write resets the peak
+					register_state.peak_current_ma = register_state.main_current_ma;
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				#[cfg(feature = "current-sense")]
+				0x2B => {
+					register_state.config.over_current_limit = req.length_or_data;
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				#[cfg(feature = "current-sense")]
+				0x2C | 0x2D => {
+					// Written a byte at a time - 0x2C is the low byte
+					register_state.config.current_full_scale_ma = register_state
+						.config
+						.current_full_scale_ma
+						.with_byte(usize::from(req.register - 0x2C), req.length_or_data);
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				0x30 => {
+					if register_state.uart_tx.push(req.length_or_data) {
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					} else {
+						// FIFO is full - the host should try again later
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					}
+				}
+				0x35 => {
+					// Any write empties the FIFO
+					register_state.uart_rx.clear();
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				0x36 => {
+					if req.length_or_data == 0 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						// The test takes a while. If another operation is still
+						// running, we don't start this one, and the host will
+						// see the old token.
+						if register_state.deferred.start(req.register).is_some() {
+							register_state.uart_self_test =
+								Some(u16::from(req.length_or_data) * SELF_TEST_BLOCK_LEN);
+						}
+						proto::Response::new_without_data(proto::ResponseResult::Busy)
+					}
+				}
+				#[cfg(feature = "ps2-keyboard")]
+				0x37 => match req.length_or_data {
+					0 | 1 => {
+						register_state.console_takeover = req.length_or_data == 1;
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					}
+					_ => proto::Response::new_without_data(proto::ResponseResult::BadLength),
+				},
+				#[cfg(feature = "uart2")]
+				0x38 => {
+					if register_state.uart2.tx.push(req.length_or_data) {
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					} else {
+						// FIFO is full - the host should try again later
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					}
+				}
+				#[cfg(feature = "uart2")]
+				0x39 => {
+					// Any write empties the FIFO
+					register_state.uart2.rx.clear();
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				#[cfg(feature = "uart2")]
+				0x3B | 0x3C => {
+					// Written a byte at a time - 0x3B is the low byte
+					register_state.uart2.baud_hundreds = register_state
+						.uart2
+						.baud_hundreds
+						.with_byte(usize::from(req.register - 0x3B), req.length_or_data);
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				#[cfg(feature = "uart2")]
+				0x3D => {
+					register_state.uart2.control = req.length_or_data & crate::uart::UART2_ENABLE;
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				0x3E => match req.length_or_data {
+					0 | 1 => {
+						register_state.remote_console = req.length_or_data == 1;
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					}
+					_ => proto::Response::new_without_data(proto::ResponseResult::BadLength),
+				},
+				#[cfg(feature = "ps2-keyboard")]
+				0x41 => {
+					register_state.kb_control = req.length_or_data
+						& (KEYBOARD_CONTROL_MAKE_ONLY
+							| KEYBOARD_CONTROL_REPEAT
+							| KEYBOARD_CONTROL_ASCII);
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				#[cfg(feature = "ps2-keyboard")]
+				0x44 => {
+					register_state.config.boot_key = req.length_or_data;
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				#[cfg(feature = "ps2-keyboard")]
+				0x45 => {
+					// Write 1 to clear
+					register_state.boot_keys.clear(req.length_or_data);
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				#[cfg(feature = "ps2-keyboard")]
+				0x46..=0x48 => {
+					let index = usize::from(req.register - 0x46);
+					register_state.config.reset_chord[index] = req.length_or_data;
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				#[cfg(feature = "ps2-power")]
+				0x49 => {
+					register_state.ps2_power.set_enabled(req.length_or_data);
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				#[cfg(feature = "ps2-power")]
+				0x4A => {
+					register_state.ps2_power.power_cycle(req.length_or_data);
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				#[cfg(feature = "ps2-keyboard")]
+				0x4B => {
+					// Any write checks for the keyboard again
+					register_state.keyboard_id.start();
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				#[cfg(feature = "ps2-keyboard")]
+				0x4D => {
+					if register_state.kb_inject.push(req.length_or_data) {
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					} else {
+						// FIFO is full - the host should try again later
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					}
+				}
+				#[cfg(feature = "ps2-keyboard")]
+				0x4E => {
+					if register_state.key_repeat.set_rate(req.length_or_data) {
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					} else {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					}
+				}
+				#[cfg(feature = "ps2-mouse")]
+				0x53 => {
+					// Any write empties the FIFO
+					register_state.mouse.clear();
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				#[cfg(feature = "ps2-mouse")]
+				0x56 | 0x57 => {
+					let valid = if req.register == 0x56 {
+						mouse::SAMPLE_RATES.contains(&req.length_or_data)
+					} else {
+						req.length_or_data <= mouse::MAX_RESOLUTION
+					};
+					if !valid {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						// Sending the commands to the mouse takes a while. If
+						// another operation is still running, we don't start
+						// this one, and the host will see the old token.
+						if register_state.deferred.start(req.register).is_some() {
+							let queued = if req.register == 0x56 {
+								register_state.mouse.set_sample_rate(req.length_or_data)
+							} else {
+								register_state.mouse.set_resolution(req.length_or_data)
+							};
+							if !queued {
+								register_state.deferred.finish(mouse::COMMAND_FAILED);
+							}
+						}
+						proto::Response::new_without_data(proto::ResponseResult::Busy)
+					}
+				}
+				0x58 => {
+					// Any write starts the timing again
+					register_state.isr_status.clear_ps2_timing();
+					register_state.ps2_timing = [BitTiming::default(); 2];
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				0x59 => {
+					// Any write starts counting again
+					register_state.ps2_errors.clear();
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				#[cfg(feature = "i2c-target")]
+				0x65 => {
+					if !(I2C_ADDRESS_MIN..=I2C_ADDRESS_MAX).contains(&req.length_or_data) {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						// Takes effect when the BMC next starts up
+						register_state.config.i2c_address = req.length_or_data;
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					}
+				}
+				0x66 => {
+					// Zero stops the watchdog, anything else (re)starts it
+					if register_state.watchdog.set_timeout(req.length_or_data) {
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					} else {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					}
+				}
+				0x67 => {
+					if register_state.watchdog.set_pretimeout(req.length_or_data) {
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					} else {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					}
+				}
+				0x68 => {
+					// Any write will do
+					register_state.watchdog.kick();
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				#[cfg(feature = "fan")]
+				0x70 => {
+					register_state.fan_control = req.length_or_data & FAN_AUTO;
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				#[cfg(feature = "fan")]
+				0x71 => {
+					if req.length_or_data > 100 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						// This is overwritten on the next update if the fan is in automatic mode
+						register_state.fan_duty = req.length_or_data;
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					}
+				}
+				#[cfg(all(feature = "adc", not(feature = "current-sense")))]
+				0x73 => {
+					// The temperature is an `i8`, so higher limits could never trip
+					if req.length_or_data > i8::MAX as u8 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						register_state.config.over_temperature_limit_c = req.length_or_data;
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					}
+				}
+				#[cfg(all(feature = "adc", not(feature = "current-sense")))]
+				0x74 => {
+					register_state.config.over_temperature_timeout_s = req.length_or_data;
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				0x2E => {
+					// Any write is an activity strobe
+					register_state.activity_pending = true;
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				0x2F => {
+					let policy = req.length_or_data & 0b11;
+					if policy > power::RESTORE_LAST {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						register_state.config.power_restore = policy;
+						register_state.config.power_on_delay_s =
+							(req.length_or_data >> 2).min(MAX_POWER_ON_DELAY_S);
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					}
+				}
+				#[cfg(all(feature = "speaker", not(feature = "current-sense")))]
+				0x80 | 0x81 => {
+					// Written a byte at a time - 0x80 is the low byte
+					register_state.buzzer_note.frequency_hz = register_state
+						.buzzer_note
+						.frequency_hz
+						.with_byte(usize::from(req.register - 0x80), req.length_or_data);
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				#[cfg(all(feature = "speaker", not(feature = "current-sense")))]
+				0x82 => {
+					register_state.buzzer_note.duration = req.length_or_data;
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				#[cfg(all(feature = "speaker", not(feature = "current-sense")))]
+				0x83 => {
+					register_state.buzzer_note.gap = req.length_or_data;
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				#[cfg(all(feature = "speaker", not(feature = "current-sense")))]
+				0x84 => match req.length_or_data {
+					BUZZER_COMMAND_FLUSH => {
+						// The note that's playing now still finishes
+						register_state.buzzer_queue.clear();
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					}
+					BUZZER_COMMAND_QUEUE => {
+						let note = register_state.buzzer_note;
+						match register_state.buzzer_queue.push(note) {
+							Ok(()) => proto::Response::new_without_data(proto::ResponseResult::Ok),
+							// Queue is full - the host should try again later
+							Err(_) => {
+								proto::Response::new_without_data(proto::ResponseResult::BadLength)
+							}
+						}
+					}
+					_ => proto::Response::new_without_data(proto::ResponseResult::BadLength),
+				},
+				0x85 => {
+					if register_state.watches.select(req.length_or_data as usize) {
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					} else {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					}
+				}
+				0x86..=0x8C => {
+					// Written a byte at a time. Anything but the comparison
+					// turns the watch off, and it's only turned on if we can
+					// watch what it says.
+					let offset = usize::from(req.register - 0x86);
+					let index = register_state.watches.selected();
+					let watch = register_state
+						.watches
+						.get(index)
+						.with_byte(offset, req.length_or_data)
+						.filter(|watch| {
+							watch.comparison == Comparison::Off || watch_read_len(watch).is_some()
+						});
+					match watch {
+						Some(watch) => {
+							register_state.watches.set(index, watch);
+							proto::Response::new_without_data(proto::ResponseResult::Ok)
+						}
+						None => proto::Response::new_without_data(proto::ResponseResult::BadLength),
+					}
+				}
+				0x8D => {
+					// Write 1 to clear the tripped bits
+					register_state.watches.clear_tripped(req.length_or_data);
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				0x90 => {
+					if (req.length_or_data & !irq::LINE_CONTROL_BITS) != 0 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						// The caller drives the line the new way
+						register_state.config.irq_line = req.length_or_data;
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					}
+				}
+				0x91 => {
+					irq.start_line_test(req.length_or_data);
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				0x92 => {
+					// Write 1 to clear
+					register_state.changes.clear(req.length_or_data);
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				0x93 => {
+					let pin = power::presence_pin(req.length_or_data);
+					let available =
+						pin.map_or(true, |pin| (expansion::GPIO_AVAILABLE & (1 << pin)) != 0);
+					if req.length_or_data > power::PRESENCE_GPIO1 || !available {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						register_state.config.board_presence = req.length_or_data;
+						if let Some(pin) = pin {
+							// A missing main board mustn't read as a strap
+							// pulled low
+							register_state.gpio.set_pin_pull(pin, expansion::PULL_UP);
+						}
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					}
+				}
+				0x94 => {
+					if req.length_or_data > power::LED_DARK {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						// The power manager picks this up
+						register_state.config.led_mode = req.length_or_data;
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					}
+				}
+				0x95 => {
+					if req.length_or_data > 100 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						register_state.config.led_brightness = req.length_or_data;
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					}
+				}
+				#[cfg(feature = "status-led")]
+				0x96..=0x98 => {
+					let channel = usize::from(req.register - 0x96);
+					register_state
+						.status_led
+						.set_channel(channel, req.length_or_data);
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				#[cfg(feature = "status-led")]
+				0x99 => {
+					if register_state.status_led.set_pattern(req.length_or_data) {
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					} else {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					}
+				}
+				#[cfg(feature = "status-led")]
+				0x9A => {
+					// Any write goes back to the host's colour
+					register_state.status_led.clear_fault();
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				0xA1 => {
+					register_state.gpio.set_direction(req.length_or_data);
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				0xA2 => {
+					register_state.gpio.set_output(req.length_or_data);
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				0xA4 => {
+					if register_state.gpio.set_pull(req.length_or_data) {
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					} else {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					}
+				}
+				#[cfg(not(any(
+					feature = "fan",
+					feature = "encoder",
+					feature = "i2c-target",
+					feature = "ps2-power",
+					feature = "status-led"
+				)))]
+				0xA8 | 0xA9 => {
+					// Written a byte at a time - 0xA8 is the low byte
+					register_state.pwm.frequency_hz = register_state
+						.pwm
+						.frequency_hz
+						.with_byte(usize::from(req.register - 0xA8), req.length_or_data);
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				#[cfg(not(any(
+					feature = "fan",
+					feature = "encoder",
+					feature = "i2c-target",
+					feature = "ps2-power",
+					feature = "status-led"
+				)))]
+				0xAA => {
+					if req.length_or_data > 100 {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						register_state.pwm.duty_percent = req.length_or_data;
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					}
+				}
+				#[cfg(not(any(
+					feature = "fan",
+					feature = "encoder",
+					feature = "i2c-target",
+					feature = "ps2-power",
+					feature = "status-led"
+				)))]
+				0xAB => {
+					register_state.pwm.control = req.length_or_data & expansion::PWM_ENABLE;
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				#[cfg(feature = "adc")]
+				0xAC..=0xAE => {
+					let channel = usize::from(req.register - 0xAC);
+					register_state
+						.adc_schedule
+						.set_interval(channel, req.length_or_data);
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				#[cfg(feature = "adc")]
+				0xB1 => {
+					// Sets every analog input's interval at once
+					for channel in 0..analog::ANALOG_INPUTS {
+						register_state
+							.adc_schedule
+							.set_interval(channel, req.length_or_data);
+					}
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				#[cfg(feature = "adc")]
+				0xB2 => {
+					if register_state.analog.set_averaging(req.length_or_data) {
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					} else {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					}
+				}
+				#[cfg(feature = "adc")]
+				0xB5 => {
+					// Any write starts the history again
+					register_state.analog.clear_history();
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				#[cfg(feature = "adc")]
+				0xB6..=0xBD => {
+					// Written a byte at a time - each input's gain and then
+					// its offset, low byte first
+					let offset = usize::from(req.register - 0xB6);
+					let calibration =
+						&mut register_state.config.analog_calibration[offset / Calibration::SIZE];
+					let mut bytes = calibration.as_bytes();
+					bytes[offset % Calibration::SIZE] = req.length_or_data;
+					*calibration = Calibration::from_bytes(&bytes);
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				#[cfg(feature = "adc")]
+				0xBE => match req.length_or_data {
+					ANALOG_CALIBRATE_FACTORY => match register_state.analog.factory_calibration() {
+						Some(calibration) => {
+							register_state.config.analog_calibration = calibration;
+							proto::Response::new_without_data(proto::ResponseResult::Ok)
+						}
+						None => proto::Response::new_without_data(proto::ResponseResult::BadLength),
+					},
+					ANALOG_CALIBRATE_CLEAR => {
+						register_state.config.analog_calibration =
+							[Calibration::NONE; analog::ANALOG_INPUTS];
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					}
+					_ => proto::Response::new_without_data(proto::ResponseResult::BadLength),
+				},
+				#[cfg(feature = "adc")]
+				0xBF => {
+					if register_state.adc_schedule.set_filters(req.length_or_data) {
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					} else {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					}
+				}
+				0xC0..=0xC3 => match ButtonConfig::from_byte(req.length_or_data) {
+					Some(config) => {
+						register_state.buttons[usize::from(req.register - 0xC0)] = config;
+						if req.register == 0xC1 {
+							// The integrator's reset button policy is kept in flash
+							register_state.config.reset_button = req.length_or_data;
+						}
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					}
+					None => proto::Response::new_without_data(proto::ResponseResult::BadLength),
+				},
+				0xC4 => {
+					// Write 1 to clear
+					register_state.button_events &= !req.length_or_data;
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				0xC5 => {
+					if req.length_or_data > glitch::BUTTON_FILTER_MAX {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						register_state.config.button_filter = req.length_or_data;
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					}
+				}
+				0xC6 => {
+					if req.length_or_data > glitch::CS_FILTER_MAX_US {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						// The caller passes this on to the EXTI interrupt
+						register_state.config.cs_filter_us = req.length_or_data;
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					}
+				}
+				0xC7 => {
+					// Any write starts the counts again
+					register_state.glitches.clear();
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				#[cfg(feature = "encoder")]
+				0xC8 => {
+					// Any write sets the position to zero
+					register_state.encoder.reset();
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				0xCA => {
+					// In units of 10 ms, or zero for the usual pulse
+					let duration_ms = match req.length_or_data {
+						0 => RESET_DURATION_MS,
+						units => u32::from(units) * 10,
+					};
+					if (MIN_RESET_DURATION_MS..=MAX_RESET_DURATION_MS).contains(&duration_ms) {
+						register_state.reset_request = Some(duration_ms);
+						register_state.host_reset = (duration_ms / 10) as u8;
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					} else {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					}
+				}
+				0xCB => {
+					// The caller writes it to flash
+					register_state.boot_mode = req.length_or_data;
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				0xCC => {
+					if register_state.nvram.select(usize::from(req.length_or_data)) {
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					} else {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					}
+				}
+				0xCD => match req.length_or_data {
+					nvram::COMMAND_COMMIT => {
+						// If another operation is still running, we don't start
+						// this one, and the host will see the old token.
+						if register_state.deferred.start(req.register).is_some() {
+							register_state.commit_nvram = true;
+						}
+						proto::Response::new_without_data(proto::ResponseResult::Busy)
+					}
+					nvram::COMMAND_CLEAR => {
+						register_state.nvram.clear();
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					}
+					_ => proto::Response::new_without_data(proto::ResponseResult::BadLength),
+				},
+				0xD0..=0xDF => {
+					let offset = usize::from(req.register - 0xD0);
+					register_state.nvram.write(offset, req.length_or_data);
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				0x5A..=0x5E => {
+					// Written a byte at a time. Anything but the enable turns
+					// the alarm off.
+					let offset = usize::from(req.register - 0x5A);
+					match register_state
+						.rtc
+						.alarm()
+						.with_byte(offset, req.length_or_data)
+					{
+						Some(alarm) => {
+							register_state.rtc.set_alarm(alarm);
+							proto::Response::new_without_data(proto::ResponseResult::Ok)
+						}
+						None => proto::Response::new_without_data(proto::ResponseResult::BadLength),
+					}
+				}
+				0x75 => {
+					// Write 1 to clear the alarm bit
+					register_state.rtc.clear(req.length_or_data);
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				0x76 => {
+					if req.length_or_data > rtc::CLOCK_LSE {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					} else {
+						// The RTC task starts the RTC again from the new clock
+						register_state.config.rtc_clock = req.length_or_data;
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					}
+				}
+				0x77..=0x7D => {
+					// Written a byte at a time. The year sets the clock.
+					let offset = usize::from(req.register - 0x77);
+					if register_state.rtc.write(offset, req.length_or_data) {
+						proto::Response::new_without_data(proto::ResponseResult::Ok)
+					} else {
+						proto::Response::new_without_data(proto::ResponseResult::BadLength)
+					}
+				}
+				0xE0..=0xFF => {
+					let offset = usize::from(req.register - 0xE0);
+					register_state.scratch[offset] = req.length_or_data;
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				_ => proto::Response::new_without_data(proto::ResponseResult::BadRegister),
+			}
+		}
+		proto::RequestType::SetBits
+		| proto::RequestType::SetBitsAlt
+		| proto::RequestType::ClearBits
+		| proto::RequestType::ClearBitsAlt => {
+			let is_bitmask = proto::registers::find(req.register)
+				.map(|register| {
+					register.access == proto::registers::Access::Bitmask
+						&& register.is_available(FEATURE_FLAGS)
+				})
+				.unwrap_or(false);
+			if !is_bitmask {
+				return proto::Response::new_without_data(proto::ResponseResult::BadRegister);
+			}
+			// The caller holds the register state throughout, so nothing else
+			// can change the register between this read and the write.
+			let read = proto::Request::new_read(false, req.register, 1);
+			let rsp = handle_request(
+				&read,
+				&mut *register_state,
+				&mut *event_log,
+				&mut *irq,
+				&mut *read_buffer,
+			);
+			if rsp.result != proto::ResponseResult::Ok {
+				return proto::Response::new_without_data(rsp.result);
+			}
+			let value = match req.request_type {
+				proto::RequestType::SetBits | proto::RequestType::SetBitsAlt => {
+					rsp.data[0] | req.length_or_data
+				}
+				_ => rsp.data[0] & !req.length_or_data,
+			};
+			let write = proto::Request::new_short_write(false, req.register, value);
+			handle_request(&write, register_state, event_log, irq, read_buffer)
+		}
+		proto::RequestType::BulkReadStart | proto::RequestType::BulkReadStartAlt => {
+			let register = match proto::registers::find(req.register) {
+				Some(register)
+					if register.access == proto::registers::Access::Fifo
+						&& register.is_available(FEATURE_FLAGS) =>
+				{
+					register
+				}
+				_ => return proto::Response::new_without_data(proto::ResponseResult::BadRegister),
+			};
+			// Check every piece before we take anything out of the FIFO
+			if !register.allows_bulk_read(req.length_or_data) {
+				return proto::Response::new_without_data(proto::ResponseResult::BadLength);
+			}
+			// Moved out whilst we read into it, as the reads borrow the
+			// register state
+			let mut bulk = core::mem::take(&mut register_state.bulk);
+			bulk.clear();
+			let mut result = proto::ResponseResult::Ok;
+			let mut remaining = req.length_or_data;
+			while remaining > 0 {
+				let length = remaining.min(proto::registers::MAX_READ_LEN);
+				let read = proto::Request::new_read(false, req.register, length);
+				let rsp = handle_request(
+					&read,
+					&mut *register_state,
+					&mut *event_log,
+					&mut *irq,
+					&mut *read_buffer,
+				);
+				if rsp.result != proto::ResponseResult::Ok || !bulk.extend(rsp.data) {
+					result = proto::ResponseResult::BadLength;
+					break;
+				}
+				remaining -= length;
+			}
+			register_state.bulk = bulk;
+			proto::Response::new_without_data(result)
+		}
+		proto::RequestType::BulkReadContinue | proto::RequestType::BulkReadContinueAlt => {
+			// The offset is where the register usually goes
+			let offset = req.register as usize;
+			let length = req.length_or_data as usize;
+			match register_state.bulk.get(offset, length) {
+				Some(bytes) if length <= READ_BUFFER_LEN => {
+					proto::Response::new_ok_with_data(bytes)
+				}
+				_ => proto::Response::new_without_data(proto::ResponseResult::BadLength),
+			}
+		}
+		proto::RequestType::BulkWriteStart | proto::RequestType::BulkWriteStartAlt => {
+			let register = match proto::registers::find(req.register) {
+				Some(register)
+					if register.allows_bulk_write(1) && register.is_available(FEATURE_FLAGS) =>
+				{
+					register
+				}
+				_ => return proto::Response::new_without_data(proto::ResponseResult::BadRegister),
+			};
+			let length = usize::from(req.length_or_data);
+			if !register.allows_bulk_write(req.length_or_data)
+				|| !register_state.bulk.start_write(register.address, length)
+			{
+				register_state.bulk.clear();
+				return proto::Response::new_without_data(proto::ResponseResult::BadLength);
+			}
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		proto::RequestType::BulkWriteContinue | proto::RequestType::BulkWriteContinueAlt => {
+			// Nothing happens until the payload arrives (see
+			// `handle_payload`). The offset is where the register usually
+			// goes.
+			let offset = req.register as usize;
+			let length = req.length_or_data as usize;
+			if (1..=READ_BUFFER_LEN).contains(&length)
+				&& register_state.bulk.can_receive(offset, length)
+			{
+				proto::Response::new_without_data(proto::ResponseResult::Ok)
+			} else {
+				proto::Response::new_without_data(proto::ResponseResult::BadLength)
+			}
+		}
+		proto::RequestType::WideRead | proto::RequestType::WideReadAlt => {
+			let (width, count) = match req.wide_read_shape() {
+				Some(shape) => shape,
+				None => return proto::Response::new_without_data(proto::ResponseResult::BadLength),
+			};
+			let pieces =
+				|| proto::registers::wide_read_pieces(req.register, width, count, FEATURE_FLAGS);
+			// Check every piece before we read anything
+			if let Some(Err(result)) = pieces().find(|piece| piece.is_err()) {
+				return proto::Response::new_without_data(result);
+			}
+			// Collected apart from the read buffer, as each read uses it
+			let mut values = [0u8; READ_BUFFER_LEN];
+			let mut len = 0;
+			for (address, length) in pieces().flatten() {
+				let read = proto::Request::new_read(false, address, length);
+				let rsp = handle_request(
+					&read,
+					&mut *register_state,
+					&mut *event_log,
+					&mut *irq,
+					&mut *read_buffer,
+				);
+				if rsp.result != proto::ResponseResult::Ok {
+					return proto::Response::new_without_data(rsp.result);
+				}
+				values[len..len + rsp.data.len()].copy_from_slice(rsp.data);
+				len += rsp.data.len();
+			}
+			read_buffer[0..len].copy_from_slice(&values[0..len]);
+			proto::Response::new_ok_with_data(&read_buffer[0..len])
+		}
+		_ => proto::Response::new_without_data(proto::ResponseResult::BadLength),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::eventlog::EventKind;
+	use crate::ps2::{Ps2Port, WordError};
+	#[cfg(feature = "ps2-power")]
+	use crate::ps2power::{PORT_KEYBOARD, PORT_MOUSE, POWER_CYCLE_OFF_MS};
+
+	/// Everything `handle_request` needs, and ways to poke at it.
+	struct Harness {
+		state: RegisterState,
+		event_log: EventLog<4>,
+		irq: InterruptController,
+		read_buffer: [u8; READ_BUFFER_LEN],
+	}
+
+	impl Harness {
+		fn new() -> Harness {
+			let build_info = proto::BuildInfo {
+				firmware_version: [0, 4, 0],
+				protocol_version: proto::ProtocolVersion::new(1, 0, 0),
+				build_flags: 0,
+				git_hash: [0x11; 8],
+				build_timestamp: 0x6000_0000,
+				feature_flags: 0,
+			};
+			let mut state = RegisterState::new(
+				"v0.4.0",
+				&build_info,
+				Config::DEFAULT,
+				CONFIG_STATUS_DEFAULTS,
+			);
+			state.ready = true;
+			Harness {
+				state,
+				event_log: EventLog::new(),
+				irq: InterruptController::new(),
+				read_buffer: [0u8; READ_BUFFER_LEN],
+			}
+		}
+
+		fn read(&mut self, register: u8, length: u8) -> (proto::ResponseResult, Vec<u8>) {
+			let req = proto::Request::new_read(false, register, length);
+			let rsp = handle_request(
+				&req,
+				&mut self.state,
+				&mut self.event_log,
+				&mut self.irq,
+				&mut self.read_buffer,
+			);
+			let answer = (rsp.result, rsp.data.to_vec());
+			// As the firmware does, once the response has gone
+			note_access(&req, &mut self.state, &self.event_log, &self.irq);
+			answer
+		}
+
+		fn write(&mut self, register: u8, data: u8) -> proto::ResponseResult {
+			self.send(&proto::Request::new_short_write(false, register, data))
+		}
+
+		fn send(&mut self, req: &proto::Request) -> proto::ResponseResult {
+			let rsp = handle_request(
+				req,
+				&mut self.state,
+				&mut self.event_log,
+				&mut self.irq,
+				&mut self.read_buffer,
+			);
+			let result = rsp.result;
+			note_access(req, &mut self.state, &self.event_log, &self.irq);
+			result
+		}
+
+		/// Send a request, and then its payload if it gets an `Ok` result.
+		/// Returns the last result.
+		fn send_with_payload(
+			&mut self,
+			req: &proto::Request,
+			data: &[u8],
+		) -> proto::ResponseResult {
+			let result = self.send(req);
+			if result != proto::ResponseResult::Ok {
+				return result;
+			}
+			handle_payload(
+				req,
+				data,
+				&mut self.state,
+				&mut self.event_log,
+				&mut self.irq,
+				&mut self.read_buffer,
+			)
+			.result
+		}
+	}
+
+	#[test]
+	fn protocol_version() {
+		let mut h = Harness::new();
+		assert_eq!(h.read(0x00, 3), (proto::ResponseResult::Ok, vec![1, 0, 0]));
+		assert_eq!(h.read(0x00, 4).0, proto::ResponseResult::BadLength);
+	}
+
+	#[test]
+	fn conformance_exchanges() {
+		use proto::vectors::{self, Vector};
+		use proto::{Receivable, Sendable};
+		let mut h = Harness::new();
+		let mut sent = [0u8; 64];
+		for vector in vectors::parse(vectors::EXCHANGES) {
+			let Ok(Vector::Exchange { request, response }) = vector else {
+				panic!("{:?} in the exchange vectors", vector);
+			};
+			let request: Vec<u8> = request.bytes().collect();
+			let len = match proto::Request::from_bytes(&request) {
+				Ok(req) => {
+					let len = handle_request(
+						&req,
+						&mut h.state,
+						&mut h.event_log,
+						&mut h.irq,
+						&mut h.read_buffer,
+					)
+					.render_to_buffer(&mut sent)
+					.unwrap();
+					note_access(&req, &mut h.state, &h.event_log, &h.irq);
+					len
+				}
+				Err(e) => proto::Response::new_without_data(bad_request_result(e))
+					.render_to_buffer(&mut sent)
+					.unwrap(),
+			};
+			let expected: Vec<u8> = response.bytes().collect();
+			assert_eq!(&sent[0..len], &expected[..], "{:02X?}", request);
+		}
+	}
+
+	#[test]
+	fn reset_button_from_flash() {
+		let build_info = proto::BuildInfo {
+			firmware_version: [0, 4, 0],
+			protocol_version: proto::ProtocolVersion::new(1, 0, 0),
+			build_flags: 0,
+			git_hash: [0; 8],
+			build_timestamp: 0,
+			feature_flags: 0,
+		};
+		let config = Config {
+			reset_button: 0x34,
+			..Config::DEFAULT
+		};
+		let state = RegisterState::new("", &build_info, config, CONFIG_STATUS_LOADED);
+		assert_eq!(state.buttons[BUTTON_RESET].as_byte(), 0x34);
+		// A setting we don't understand leaves the default
+		let config = Config {
+			reset_button: 0x07,
+			..Config::DEFAULT
+		};
+		let state = RegisterState::new("", &build_info, config, CONFIG_STATUS_LOADED);
+		assert_eq!(state.buttons[BUTTON_RESET], DEFAULT_BUTTONS[BUTTON_RESET]);
+	}
+
+	#[test]
+	fn firmware_version_always_null_terminated() {
+		let build_info = proto::BuildInfo {
+			firmware_version: [0, 4, 0],
+			protocol_version: proto::ProtocolVersion::new(1, 0, 0),
+			build_flags: 0,
+			git_hash: [0; 8],
+			build_timestamp: 0,
+			feature_flags: 0,
+		};
+		let long_version = "v0.4.0-123-gabcdef0-dirty-and-then-some";
+		let state = RegisterState::new(long_version, &build_info, Config::DEFAULT, 0);
+		assert_eq!(
+			&state.firmware_version[0..31],
+			&long_version.as_bytes()[0..31]
+		);
+		assert_eq!(state.firmware_version[31], 0);
+	}
+
+	#[test]
+	fn build_info_fields() {
+		let mut h = Harness::new();
+		assert_eq!(h.read(0x03, 3), (proto::ResponseResult::Ok, vec![0, 4, 0]));
+		assert_eq!(h.read(0x04, 8), (proto::ResponseResult::Ok, vec![0x11; 8]));
+		assert_eq!(
+			h.read(0x05, 4),
+			(proto::ResponseResult::Ok, vec![0x00, 0x00, 0x00, 0x60])
+		);
+		assert_eq!(h.read(0x05, 2).0, proto::ResponseResult::BadLength);
+	}
+
+	#[test]
+	fn unknown_register() {
+		let mut h = Harness::new();
+		assert_eq!(h.read(0x7F, 1).0, proto::ResponseResult::BadRegister);
+		assert_eq!(h.write(0x7F, 1), proto::ResponseResult::BadRegister);
+		// Read-only registers can't be written
+		assert_eq!(h.write(0x00, 1), proto::ResponseResult::BadRegister);
+	}
+
+	#[test]
+	fn health_register() {
+		let mut h = Harness::new();
+		h.state.health.tick();
+		h.state.health.report(crate::health::SPI_TIMEOUT);
+		h.state.health.report_error(0x03);
+		let flags = crate::health::SPI_TIMEOUT | crate::health::PROTOCOL_ERROR;
+		assert_eq!(
+			h.read(0x07, 4),
+			(proto::ResponseResult::Ok, vec![0x01, 0x00, flags, 0x03])
+		);
+		assert_eq!(h.read(0x07, 1).0, proto::ResponseResult::BadLength);
+		assert_eq!(h.write(0x07, flags), proto::ResponseResult::Ok);
+		assert_eq!(
+			h.read(0x07, 4),
+			(proto::ResponseResult::Ok, vec![0x01, 0x00, 0x00, 0x00])
+		);
+	}
+
+	#[test]
+	fn isr_status() {
+		let mut h = Harness::new();
+		let status = IsrStatus::new();
+		status.ps2_overflow();
+		status.cs_glitch();
+		collect_isr_status(&mut h.state, &status);
+		let flags = crate::health::PS2_QUEUE_OVERFLOW;
+		assert_eq!(h.read(0x07, 4).1[2], flags);
+		assert_eq!(h.state.glitches.count(glitch::INPUT_CS), 1);
+		// Once cleared, the fault stays cleared until it happens again
+		assert_eq!(h.write(0x07, flags), proto::ResponseResult::Ok);
+		collect_isr_status(&mut h.state, &status);
+		assert_eq!(h.read(0x07, 4).1[2], 0);
+		assert_eq!(h.state.glitches.count(glitch::INPUT_CS), 1);
+		status.ps2_overflow();
+		collect_isr_status(&mut h.state, &status);
+		assert_eq!(h.read(0x07, 4).1[2], flags);
+	}
+
+	#[test]
+	fn ps2_bit_timing() {
+		let mut h = Harness::new();
+		let status = IsrStatus::new();
+		assert_eq!(h.read(0x58, 16), (proto::ResponseResult::Ok, vec![0; 16]));
+		let timing = BitTiming {
+			min_us: 70,
+			max_us: 120,
+			typical_us: 80,
+			out_of_spec: 2,
+		};
+		status.ps2_bit_timing(Ps2Port::Keyboard, &timing);
+		collect_isr_status(&mut h.state, &status);
+		let mut expected = vec![70, 0, 120, 0, 80, 0, 2, 0];
+		expected.extend_from_slice(&[0; 8]);
+		assert_eq!(h.read(0x58, 16), (proto::ResponseResult::Ok, expected));
+		assert_eq!(h.read(0x58, 8).0, proto::ResponseResult::BadLength);
+		// Clearing is passed on to the interrupt
+		assert_eq!(h.write(0x58, 0), proto::ResponseResult::Ok);
+		assert_eq!(h.read(0x58, 16), (proto::ResponseResult::Ok, vec![0; 16]));
+		collect_isr_status(&mut h.state, &status);
+		assert_eq!(h.read(0x58, 16), (proto::ResponseResult::Ok, vec![0; 16]));
+		assert!(status.take_ps2_timing_clear(Ps2Port::Keyboard));
+		assert!(status.take_ps2_timing_clear(Ps2Port::Mouse));
+	}
+
+	#[test]
+	fn ps2_errors() {
+		let mut h = Harness::new();
+		assert_eq!(h.read(0x59, 16), (proto::ResponseResult::Ok, vec![0; 16]));
+		h.state
+			.ps2_errors
+			.bad_word(Ps2Port::Mouse, WordError::Parity);
+		assert_eq!(h.state.ps2_errors.resend(Ps2Port::Mouse), Some(0xFE));
+		let mut expected = vec![0; 8];
+		expected.extend_from_slice(&[0, 0, 1, 0, 0, 0, 1, 0]);
+		assert_eq!(h.read(0x59, 16), (proto::ResponseResult::Ok, expected));
+		assert_eq!(h.read(0x59, 1).0, proto::ResponseResult::BadLength);
+		assert_eq!(h.write(0x59, 0), proto::ResponseResult::Ok);
+		assert_eq!(h.read(0x59, 16), (proto::ResponseResult::Ok, vec![0; 16]));
+	}
+
+	#[test]
+	fn spi_clock_negotiation() {
+		let mut h = Harness::new();
+		assert_eq!(
+			h.read(0x08, 1),
+			(proto::ResponseResult::Ok, vec![SPI_MAX_CLOCK])
+		);
+		assert_eq!(h.read(0x09, 1), (proto::ResponseResult::Ok, vec![0x01]));
+		assert_eq!(
+			h.read(0x0A, 1),
+			(proto::ResponseResult::Ok, vec![SPI_DEFAULT_CLOCK])
+		);
+		assert_eq!(h.state.spi_watchdog_ms(), SPI_WATCHDOG_MIN_MS);
+		// Too fast, or zero, is refused
+		assert_eq!(
+			h.write(0x0A, SPI_MAX_CLOCK + 1),
+			proto::ResponseResult::BadLength
+		);
+		assert_eq!(h.write(0x0A, 0), proto::ResponseResult::BadLength);
+		// A slow host gets longer to finish each transaction
+		assert_eq!(h.write(0x0A, 1), proto::ResponseResult::Ok);
+		assert_eq!(h.read(0x0A, 1), (proto::ResponseResult::Ok, vec![1]));
+		assert_eq!(h.state.spi_watchdog_ms(), 20);
+		// Registers 0x08 and 0x09 are read-only
+		assert_eq!(h.write(0x08, 1), proto::ResponseResult::BadRegister);
+	}
+
+	#[test]
+	fn scratch_registers() {
+		let mut h = Harness::new();
+		assert_eq!(h.write(0xE5, 0x42), proto::ResponseResult::Ok);
+		let (result, data) = h.read(0xE0, 32);
+		assert_eq!(result, proto::ResponseResult::Ok);
+		assert_eq!(data[5], 0x42);
+		assert_eq!(h.read(0xE5, 1), (proto::ResponseResult::Ok, vec![0x42]));
+		// Can't read off the end
+		assert_eq!(h.read(0xF0, 17).0, proto::ResponseResult::BadLength);
+	}
+
+	#[test]
+	fn power_control_register() {
+		let mut h = Harness::new();
+		assert_eq!(h.read(0x25, 1), (proto::ResponseResult::Ok, vec![0]));
+		h.state.power_state = DcPowerState::On;
+		assert_eq!(
+			h.read(0x25, 1),
+			(proto::ResponseResult::Ok, vec![POWER_CONTROL_ON])
+		);
+		assert_eq!(
+			h.write(0x25, POWER_CONTROL_ON | POWER_CONTROL_SUSPEND),
+			proto::ResponseResult::Ok
+		);
+		assert_eq!(h.state.power_request, Some(PowerAction::Suspend));
+		assert_eq!(h.write(0x25, 0), proto::ResponseResult::Ok);
+		assert_eq!(h.state.power_request, Some(PowerAction::PowerOff));
+		assert_eq!(h.write(0x25, POWER_CONTROL_ON), proto::ResponseResult::Ok);
+		assert_eq!(h.state.power_request, Some(PowerAction::PowerOn));
+		h.state.power_state = DcPowerState::Suspended;
+		assert_eq!(
+			h.read(0x25, 1),
+			(
+				proto::ResponseResult::Ok,
+				vec![POWER_CONTROL_ON | POWER_CONTROL_SUSPEND]
+			)
+		);
+		// Can't suspend with the power off
+		assert_eq!(
+			h.write(0x25, POWER_CONTROL_SUSPEND),
+			proto::ResponseResult::BadLength
+		);
+	}
+
+	#[test]
+	fn main_board_reset_register() {
+		let mut h = Harness::new();
+		assert_eq!(h.read(0xCA, 1), (proto::ResponseResult::Ok, vec![0]));
+		// The usual pulse
+		assert_eq!(h.write(0xCA, 0), proto::ResponseResult::Ok);
+		assert_eq!(h.state.reset_request, Some(RESET_DURATION_MS));
+		assert_eq!(h.read(0xCA, 1).1, [25]);
+		// A longer one, for a slow expansion card
+		assert_eq!(h.write(0xCA, 200), proto::ResponseResult::Ok);
+		assert_eq!(h.state.reset_request, Some(2000));
+		assert_eq!(h.read(0xCA, 1).1, [200]);
+		// Too short to be sure of resetting anything
+		h.state.reset_request = None;
+		assert_eq!(h.write(0xCA, 4), proto::ResponseResult::BadLength);
+		assert_eq!(h.state.reset_request, None);
+		assert_eq!(h.write(0xCA, 5), proto::ResponseResult::Ok);
+		// It's guarded
+		assert_eq!(h.write(0x0D, 0x01), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0xCA, 0), proto::ResponseResult::Locked);
+	}
+
+	#[test]
+	fn boot_mode_register() {
+		let mut h = Harness::new();
+		assert_eq!(
+			h.read(0xCB, 1),
+			(proto::ResponseResult::Ok, vec![power::BOOT_MODE_NORMAL])
+		);
+		assert_eq!(
+			h.write(0xCB, power::BOOT_MODE_SETTINGS),
+			proto::ResponseResult::Ok
+		);
+		assert_eq!(h.state.boot_mode, power::BOOT_MODE_SETTINGS);
+		// Any value is kept, for the BIOS and OS to agree on
+		assert_eq!(h.write(0xCB, 0x80), proto::ResponseResult::Ok);
+		assert_eq!(h.read(0xCB, 1).1, [0x80]);
+		// It's guarded, as it is written to flash
+		assert_eq!(h.write(0x0D, 0x01), proto::ResponseResult::Ok);
+		assert_eq!(
+			h.write(0xCB, power::BOOT_MODE_RECOVERY),
+			proto::ResponseResult::Locked
+		);
+		assert_eq!(h.state.boot_mode, 0x80);
+	}
+
+	#[test]
+	fn nvram_registers() {
+		let mut h = Harness::new();
+		assert_eq!(
+			h.read(0xCD, 1),
+			(proto::ResponseResult::Ok, vec![nvram::STATUS_BLANK])
+		);
+		// The last page, a byte at a time
+		assert_eq!(h.write(0xCC, 3), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0xCC, 4), proto::ResponseResult::BadLength);
+		assert_eq!(h.read(0xCC, 1).1, [3]);
+		assert_eq!(h.write(0xDE, 0x12), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0xDF, 0x34), proto::ResponseResult::Ok);
+		assert_eq!(
+			h.read(0xDE, 2),
+			(proto::ResponseResult::Ok, vec![0x12, 0x34])
+		);
+		assert_eq!(h.read(0xDE, 3).0, proto::ResponseResult::BadLength);
+		assert_eq!(h.state.nvram.data()[62..], [0x12, 0x34]);
+		assert_eq!(h.read(0xCD, 1).1, [nvram::STATUS_CHANGED]);
+		// The other pages are untouched
+		assert_eq!(h.write(0xCC, 0), proto::ResponseResult::Ok);
+		assert_eq!(h.read(0xD0, 16).1, [0u8; 16]);
+		// Committing is a deferred operation
+		assert_eq!(
+			h.write(0xCD, nvram::COMMAND_COMMIT),
+			proto::ResponseResult::Busy
+		);
+		assert!(h.state.commit_nvram);
+		assert_eq!(h.read(0x0B, 4).1, vec![1, 1, 0xCD, 0]);
+		h.state.commit_nvram = false;
+		let committed = *h.state.nvram.data();
+		h.state.nvram.committed(&committed, true);
+		h.state.deferred.finish(nvram::STATUS_COMMITTED);
+		assert_eq!(h.read(0xCD, 1).1, [nvram::STATUS_COMMITTED]);
+		assert_eq!(
+			h.write(0xCD, nvram::COMMAND_CLEAR),
+			proto::ResponseResult::Ok
+		);
+		assert_eq!(h.state.nvram.data(), &[0u8; nvram::NVRAM_LEN]);
+		assert_eq!(h.write(0xCD, 0x99), proto::ResponseResult::BadLength);
+		// The BIOS can still change it with the configuration locked, but
+		// committing it is guarded, as it is written to flash
+		assert_eq!(h.write(0x0D, 0x01), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0xD5, 0x56), proto::ResponseResult::Ok);
+		assert_eq!(
+			h.write(0xCD, nvram::COMMAND_COMMIT),
+			proto::ResponseResult::Locked
+		);
+		assert_eq!(h.read(0xCD, 1), (proto::ResponseResult::Ok, vec![2]));
+	}
+
+	#[test]
+	fn rtc_registers() {
+		let mut h = Harness::new();
+		assert_eq!(h.read(0x75, 1), (proto::ResponseResult::Ok, vec![0]));
+		assert_eq!(h.read(0x77, 7).1, DateTime::START.as_bytes());
+		// Friday 16th October 2026, 13:45:30 - the weekday is worked out
+		for (register, byte) in [(0x77, 30), (0x78, 45), (0x79, 13), (0x7B, 16), (0x7C, 10)] {
+			assert_eq!(h.write(register, byte), proto::ResponseResult::Ok);
+		}
+		assert_eq!(h.state.rtc.take_set_request(), None);
+		assert_eq!(h.write(0x7D, 26), proto::ResponseResult::Ok);
+		assert_eq!(h.read(0x77, 7).1, [30, 45, 13, 5, 16, 10, 26]);
+		assert_eq!(h.read(0x7B, 3).1, [16, 10, 26]);
+		assert_eq!(h.read(0x7B, 4).0, proto::ResponseResult::BadLength);
+		assert_eq!(h.read(0x75, 1).1, [rtc::STATUS_TIME_VALID]);
+		assert!(h.state.rtc.take_set_request().is_some());
+		// 31st October is fine, but not 31st November
+		assert_eq!(h.write(0x7B, 31), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0x7D, 26), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0x7C, 11), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0x7D, 26), proto::ResponseResult::BadLength);
+
+		// An alarm at 07:30:00 every day, which can wake the system
+		for (register, byte) in [(0x5B, 30), (0x5C, 7), (0x5E, 1)] {
+			assert_eq!(h.write(register, byte), proto::ResponseResult::Ok);
+		}
+		assert_eq!(h.read(0x5A, 5).1, [0, 30, 7, 0, 1]);
+		assert_eq!(h.write(0x26, WAKE_ON_ALARM), proto::ResponseResult::Ok);
+		let now = DateTime::from_bytes(&[0, 30, 7, 0, 1, 11, 26]).unwrap();
+		h.state.rtc.take_set_request();
+		assert!(h.state.rtc.update(now, rtc::STATUS_TIME_VALID));
+		assert_eq!(
+			h.read(0x75, 1).1,
+			[rtc::STATUS_TIME_VALID | rtc::STATUS_ALARM]
+		);
+		assert_eq!(h.write(0x75, rtc::STATUS_ALARM), proto::ResponseResult::Ok);
+		assert_eq!(h.read(0x75, 1).1, [rtc::STATUS_TIME_VALID]);
+		// Changing the time turns it off, and it can't be impossible
+		assert_eq!(h.write(0x5C, 24), proto::ResponseResult::Ok);
+		assert_eq!(h.read(0x5E, 1).1, [0]);
+		assert_eq!(h.write(0x5E, 1), proto::ResponseResult::BadLength);
+
+		// The clock source is kept with the other settings
+		assert_eq!(h.read(0x76, 1).1, [rtc::CLOCK_LSI]);
+		assert_eq!(h.write(0x76, rtc::CLOCK_LSE), proto::ResponseResult::Ok);
+		assert_eq!(h.state.config.rtc_clock, rtc::CLOCK_LSE);
+		assert_eq!(h.write(0x76, 2), proto::ResponseResult::BadLength);
+	}
+
+	#[test]
+	fn wake_control_masked() {
+		let mut h = Harness::new();
+		assert_eq!(h.write(0x26, 0xFF), proto::ResponseResult::Ok);
+		assert_eq!(
+			h.read(0x26, 1),
+			(
+				proto::ResponseResult::Ok,
+				vec![WAKE_ON_KEYBOARD | WAKE_ON_UART | WAKE_ON_ALARM]
+			)
+		);
+	}
+
+	#[test]
+	fn config_commands() {
+		let mut h = Harness::new();
+		assert_eq!(
+			h.write(0x0F, CONFIG_COMMAND_SAVE),
+			proto::ResponseResult::Busy
+		);
+		assert!(h.state.save_config);
+		h.state.config.over_current_limit = 10;
+		h.state.config_status = CONFIG_STATUS_SAVED;
+		assert_eq!(
+			h.write(0x0F, CONFIG_COMMAND_DEFAULTS),
+			proto::ResponseResult::Ok
+		);
+		assert_eq!(h.state.config, Config::DEFAULT);
+		assert_eq!(
+			h.read(0x0F, 1),
+			(proto::ResponseResult::Ok, vec![CONFIG_STATUS_DEFAULTS])
+		);
+		assert_eq!(h.write(0x0F, 0x99), proto::ResponseResult::BadLength);
+	}
+
+	#[test]
+	fn self_test_register() {
+		let mut h = Harness::new();
+		assert_eq!(h.read(0x0C, 2), (proto::ResponseResult::Ok, vec![0, 0]));
+		assert_eq!(h.write(0x0C, 0), proto::ResponseResult::BadLength);
+		assert_eq!(
+			h.write(0x0C, selftest::COMMAND_RUN),
+			proto::ResponseResult::Busy
+		);
+		assert_eq!(h.state.run_self_test, Some(selftest::COMMAND_RUN));
+		assert_eq!(h.read(0x0B, 4).1, vec![1, 1, 0x0C, 0]);
+		h.state.run_self_test = None;
+		h.state.self_test_report.record(selftest::SPI, true);
+		h.state.self_test_report.record(selftest::LED, false);
+		h.state.deferred.finish(h.state.self_test_report.failed());
+		assert_eq!(
+			h.read(0x0C, 2),
+			(
+				proto::ResponseResult::Ok,
+				vec![selftest::SPI | selftest::LED, selftest::LED]
+			)
+		);
+		assert_eq!(h.read(0x0B, 4).1, vec![1, 2, 0x0C, selftest::LED]);
+		// Just the protocol loopback
+		assert_eq!(h.write(0x0C, 3), proto::ResponseResult::BadLength);
+		assert_eq!(
+			h.write(0x0C, selftest::COMMAND_PROTOCOL),
+			proto::ResponseResult::Busy
+		);
+		assert_eq!(h.state.run_self_test, Some(selftest::COMMAND_PROTOCOL));
+	}
+
+	#[test]
+	fn deferred_config_save() {
+		let mut h = Harness::new();
+		assert_eq!(
+			h.write(0x0F, CONFIG_COMMAND_SAVE),
+			proto::ResponseResult::Busy
+		);
+		assert_eq!(
+			h.read(0x0B, 4),
+			(proto::ResponseResult::Ok, vec![1, 1, 0x0F, 0])
+		);
+		// Can't collect it until it has finished
+		assert_eq!(h.write(0x0B, 1), proto::ResponseResult::Busy);
+		// A second save doesn't start until the first is collected
+		h.state.save_config = false;
+		assert_eq!(
+			h.write(0x0F, CONFIG_COMMAND_SAVE),
+			proto::ResponseResult::Busy
+		);
+		assert!(!h.state.save_config);
+		h.state.deferred.finish(CONFIG_STATUS_SAVED);
+		assert_eq!(
+			h.read(0x0B, 4),
+			(
+				proto::ResponseResult::Ok,
+				vec![1, 2, 0x0F, CONFIG_STATUS_SAVED]
+			)
+		);
+		assert_eq!(h.write(0x0B, 2), proto::ResponseResult::BadLength);
+		assert_eq!(h.write(0x0B, 1), proto::ResponseResult::Ok);
+		assert_eq!(
+			h.read(0x0B, 4),
+			(
+				proto::ResponseResult::Ok,
+				vec![1, 0, 0x0F, CONFIG_STATUS_SAVED]
+			)
+		);
+		assert_eq!(
+			h.write(0x0F, CONFIG_COMMAND_SAVE),
+			proto::ResponseResult::Busy
+		);
+		assert_eq!(h.read(0x0B, 4).1[0], 2);
+	}
+
+	#[test]
+	fn interrupt_registers_high_byte() {
+		let mut h = Harness::new();
+		h.irq.raise(crate::irq::DEFERRED_DONE | 0x01);
+		assert_eq!(h.read(0x14, 1), (proto::ResponseResult::Ok, vec![0x01]));
+		assert_eq!(h.write(0x11, 0x01), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0x15, 0x01), proto::ResponseResult::Ok);
+		assert_eq!(h.read(0x11, 1), (proto::ResponseResult::Ok, vec![0x01]));
+		assert_eq!(h.read(0x15, 1), (proto::ResponseResult::Ok, vec![0x01]));
+		assert_eq!(h.write(0x10, 0x01), proto::ResponseResult::Ok);
+		assert!(h.irq.is_asserted());
+		// Write 1 to clear
+		assert_eq!(h.write(0x14, 0x01), proto::ResponseResult::Ok);
+		assert_eq!(h.read(0x14, 1), (proto::ResponseResult::Ok, vec![0x00]));
+		assert!(!h.irq.is_asserted());
+	}
+
+	#[test]
+	fn latency_registers() {
+		let mut h = Harness::new();
+		h.state.spi_latency.record(20);
+		h.state.spi_latency.record(40);
+		h.state.ps2_latency.record(300);
+		assert_eq!(
+			h.read(0x16, 8),
+			(proto::ResponseResult::Ok, vec![40, 0, 30, 0, 2, 0, 0, 0])
+		);
+		assert_eq!(
+			h.read(0x17, 8),
+			(
+				proto::ResponseResult::Ok,
+				vec![0x2C, 0x01, 0x2C, 0x01, 1, 0, 0, 0]
+			)
+		);
+		assert_eq!(h.read(0x16, 4).0, proto::ResponseResult::BadLength);
+		// Any write clears just that register
+		assert_eq!(h.write(0x16, 0x55), proto::ResponseResult::Ok);
+		assert_eq!(h.state.spi_latency.count(), 0);
+		assert_eq!(h.state.ps2_latency.count(), 1);
+		assert_eq!(h.write(0x17, 0x00), proto::ResponseResult::Ok);
+		assert_eq!(h.state.ps2_latency.count(), 0);
+	}
+
+	#[test]
+	fn lifetime_counters() {
+		use crate::stats::Counter;
+		let mut h = Harness::new();
+		h.state.stats.record(Counter::BmcBoot);
+		h.state.stats.record(Counter::PowerOn);
+		h.state.stats.record(Counter::PowerOn);
+		let (result, data) = h.read(0x18, 16);
+		assert_eq!(result, proto::ResponseResult::Ok);
+		assert_eq!(&data[0..8], &[1, 0, 0, 0, 2, 0, 0, 0]);
+		assert_eq!(h.read(0x18, 4).0, proto::ResponseResult::BadLength);
+		// Only the firmware can change them
+		assert_eq!(h.write(0x18, 0), proto::ResponseResult::BadRegister);
+	}
+
+	#[test]
+	fn log_control() {
+		let mut h = Harness::new();
+		assert_eq!(
+			h.read(0x1A, 1),
+			(proto::ResponseResult::Ok, vec![logging::DEFAULT_LEVEL])
+		);
+		assert_eq!(h.read(0x1B, 1), (proto::ResponseResult::Ok, vec![0x0F]));
+		assert_eq!(
+			h.write(0x1A, LogLevel::Trace as u8),
+			proto::ResponseResult::Ok
+		);
+		assert_eq!(h.state.log_level, 5);
+		assert_eq!(h.write(0x1A, 6), proto::ResponseResult::BadLength);
+		assert_eq!(h.state.log_level, 5);
+		// Zero turns logging off
+		assert_eq!(h.write(0x1A, 0), proto::ResponseResult::Ok);
+		// Bits for subsystems we don't have are ignored
+		assert_eq!(h.write(0x1B, 0xF2), proto::ResponseResult::Ok);
+		assert_eq!(h.read(0x1B, 1), (proto::ResponseResult::Ok, vec![0x02]));
+	}
+
+	#[cfg(feature = "spi-trace")]
+	#[test]
+	fn spi_trace() {
+		let mut h = Harness::new();
+		assert_eq!(h.read(0x1E, 1), (proto::ResponseResult::Ok, vec![0]));
+		let mut entry = TraceEntry::new(0x1234, &[0xC2, 0x11]);
+		entry.set_response(&proto::Response::new_without_data(
+			proto::ResponseResult::CrcFailure,
+		));
+		h.state.spi_trace.push(entry);
+		assert_eq!(h.read(0x1E, 1), (proto::ResponseResult::Ok, vec![1]));
+		// Whole entries only
+		assert_eq!(h.read(0x1F, 13).0, proto::ResponseResult::BadLength);
+		let (result, data) = h.read(0x1F, 24);
+		assert_eq!(result, proto::ResponseResult::Ok);
+		assert_eq!(
+			data[0..12],
+			[0x34, 0x12, 0, 0, 0xC2, 0x11, 0, 0, 2, 0xA1, 0x6E, 2]
+		);
+		// Padded with empty entries
+		assert_eq!(data[12..24], [0; 12]);
+		h.state.spi_trace.push(entry);
+		assert_eq!(h.write(0x1E, 0), proto::ResponseResult::Ok);
+		assert_eq!(h.read(0x1E, 1), (proto::ResponseResult::Ok, vec![0]));
+	}
+
+	#[test]
+	fn uart_transmit() {
+		let mut h = Harness::new();
+		assert_eq!(h.read(0x3F, 1), (proto::ResponseResult::Ok, vec![32]));
+		assert_eq!(h.write(0x30, b'H'), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0x30, b'i'), proto::ResponseResult::Ok);
+		assert_eq!(h.read(0x3F, 1), (proto::ResponseResult::Ok, vec![30]));
+		for _ in 2..UART_TX_FIFO_LEN {
+			assert_eq!(h.write(0x30, b'!'), proto::ResponseResult::Ok);
+		}
+		// Full
+		assert_eq!(h.write(0x30, b'!'), proto::ResponseResult::BadLength);
+		assert_eq!(h.state.uart_tx.pop(), Some(b'H'));
+		// Writing doesn't touch what was received
+		assert!(h.state.uart_rx.is_empty());
+		// The remote console
+		assert_eq!(h.read(0x3E, 1), (proto::ResponseResult::Ok, vec![0]));
+		assert_eq!(h.write(0x3E, 1), proto::ResponseResult::Ok);
+		assert!(h.state.remote_console);
+		assert_eq!(h.write(0x3E, 2), proto::ResponseResult::BadLength);
+		assert_eq!(h.read(0x3E, 1), (proto::ResponseResult::Ok, vec![1]));
+	}
+
+	#[cfg(feature = "ps2-keyboard")]
+	#[test]
+	fn console_takeover() {
+		let mut h = Harness::new();
+		assert_eq!(h.read(0x37, 1), (proto::ResponseResult::Ok, vec![0]));
+		assert_eq!(h.write(0x37, 1), proto::ResponseResult::Ok);
+		assert!(h.state.console_takeover);
+		assert_eq!(h.read(0x37, 1), (proto::ResponseResult::Ok, vec![1]));
+		assert_eq!(h.write(0x37, 2), proto::ResponseResult::BadLength);
+		assert_eq!(h.write(0x37, 0), proto::ResponseResult::Ok);
+		assert!(!h.state.console_takeover);
+	}
+
+	#[test]
+	fn spi_framing() {
+		let mut h = Harness::new();
+		assert_eq!(h.read(0x1C, 1), (proto::ResponseResult::Ok, vec![0xFF]));
+		assert_eq!(h.read(0x1D, 1), (proto::ResponseResult::Ok, vec![0]));
+		assert_eq!(h.write(0x1C, 0x00), proto::ResponseResult::Ok);
+		assert_eq!(h.state.spi_padding, 0x00);
+		// Nothing which could start a response
+		assert_eq!(h.write(0x1C, 0xA0), proto::ResponseResult::BadLength);
+		assert_eq!(
+			h.write(0x1C, proto::SYNC_BYTE),
+			proto::ResponseResult::BadLength
+		);
+		assert_eq!(h.state.spi_padding, 0x00);
+		assert_eq!(h.write(0x1D, 1), proto::ResponseResult::Ok);
+		assert!(h.state.spi_sync);
+		assert_eq!(h.read(0x1D, 1), (proto::ResponseResult::Ok, vec![1]));
+		assert_eq!(h.write(0x1D, 2), proto::ResponseResult::BadLength);
+		assert_eq!(h.write(0x1D, 0), proto::ResponseResult::Ok);
+		assert!(!h.state.spi_sync);
+	}
+
+	#[test]
+	fn readout_protection() {
+		use crate::lock::LOCK_COMMAND_LOCK;
+		use crate::readout::{ARM_KEY_1, ARM_KEY_2, COMMAND_ENABLE};
+		let mut h = Harness::new();
+		assert_eq!(h.read(0x19, 2), (proto::ResponseResult::Ok, vec![0, 0]));
+		// The command on its own is refused
+		assert_eq!(
+			h.write(0x19, COMMAND_ENABLE),
+			proto::ResponseResult::BadLength
+		);
+		// So is the sequence with a read in between
+		assert_eq!(h.write(0x19, ARM_KEY_1), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0x19, ARM_KEY_2), proto::ResponseResult::Ok);
+		assert_eq!(h.read(0x19, 2).0, proto::ResponseResult::Ok);
+		assert_eq!(
+			h.write(0x19, COMMAND_ENABLE),
+			proto::ResponseResult::BadLength
+		);
+		assert!(!h.state.enable_readout_protection);
+		// Or with the configuration locked
+		assert_eq!(h.write(0x0D, LOCK_COMMAND_LOCK), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0x19, ARM_KEY_1), proto::ResponseResult::Locked);
+		h.state.config_lock = ConfigLock::new();
+		// The whole sequence starts the deferred operation
+		assert_eq!(h.write(0x19, ARM_KEY_1), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0x19, ARM_KEY_2), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0x19, COMMAND_ENABLE), proto::ResponseResult::Busy);
+		assert!(h.state.enable_readout_protection);
+		assert_eq!(h.read(0x0B, 4).1[2], 0x19);
+		// Once it's programmed, there's nothing to do
+		h.state.enable_readout_protection = false;
+		h.state.deferred.finish(readout::RESULT_PROGRAMMED);
+		h.state.readout_levels = (Level::Level0, Level::Level1);
+		assert_eq!(h.read(0x19, 2), (proto::ResponseResult::Ok, vec![0, 1]));
+		assert_eq!(h.write(0x19, ARM_KEY_1), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0x19, ARM_KEY_2), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0x19, COMMAND_ENABLE), proto::ResponseResult::Ok);
+		assert!(!h.state.enable_readout_protection);
+		assert_eq!(h.read(0x19, 1).0, proto::ResponseResult::BadLength);
+	}
+
+	#[test]
+	fn uart_registers() {
+		let mut h = Harness::new();
+		for byte in b"Hello" {
+			h.state.uart_rx.push(*byte);
+		}
+		assert_eq!(h.read(0x35, 2), (proto::ResponseResult::Ok, vec![5, 0]));
+		assert_eq!(
+			h.read(0x30, 3),
+			(proto::ResponseResult::Ok, b"Hel".to_vec())
+		);
+		// Padded when empty
+		assert_eq!(
+			h.read(0x30, 4),
+			(proto::ResponseResult::Ok, vec![b'l', b'o', 0, 0])
+		);
+		assert_eq!(h.read(0x35, 2), (proto::ResponseResult::Ok, vec![0, 0]));
+		assert_eq!(h.read(0x35, 1).0, proto::ResponseResult::BadLength);
+		// Any write empties the FIFO
+		h.state.uart_rx.push(b'!');
+		assert_eq!(h.write(0x35, 0), proto::ResponseResult::Ok);
+		assert!(h.state.uart_rx.is_empty());
+	}
+
+	#[test]
+	fn uart_self_test_register() {
+		let mut h = Harness::new();
+		assert_eq!(h.write(0x36, 0), proto::ResponseResult::BadLength);
+		assert_eq!(h.write(0x36, 4), proto::ResponseResult::Busy);
+		assert_eq!(h.state.uart_self_test, Some(64));
+		// Only one at a time
+		h.state.uart_self_test = None;
+		assert_eq!(h.write(0x36, 4), proto::ResponseResult::Busy);
+		assert_eq!(h.state.uart_self_test, None);
+		h.state.uart_self_test_results = SelfTestResults {
+			sent: 64,
+			received: 63,
+			bit_errors: 3,
+		};
+		assert_eq!(
+			h.read(0x36, 6),
+			(proto::ResponseResult::Ok, vec![64, 0, 63, 0, 3, 0])
+		);
+		assert_eq!(h.read(0x36, 2).0, proto::ResponseResult::BadLength);
+	}
+
+	#[cfg(feature = "uart2")]
+	#[test]
+	fn uart2_registers() {
+		let mut h = Harness::new();
+		assert_eq!(h.read(0x3D, 1), (proto::ResponseResult::Ok, vec![0]));
+		assert_eq!(
+			h.read(0x3B, 2),
+			(proto::ResponseResult::Ok, vec![0x80, 0x04])
+		);
+		// 9600 baud, then turn it on
+		assert_eq!(h.write(0x3B, 96), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0x3C, 0), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0x3D, 0xFF), proto::ResponseResult::Ok);
+		assert_eq!(h.state.uart2.baud(), 9600);
+		assert!(h.state.uart2.is_enabled());
+		assert_eq!(h.read(0x3D, 1), (proto::ResponseResult::Ok, vec![0x01]));
+		// Queue bytes to send until the FIFO is full
+		for _ in 0..UART2_TX_FIFO_LEN {
+			assert_eq!(h.write(0x38, b'x'), proto::ResponseResult::Ok);
+		}
+		assert_eq!(h.read(0x3A, 1), (proto::ResponseResult::Ok, vec![0]));
+		assert_eq!(h.write(0x38, b'x'), proto::ResponseResult::BadLength);
+		// Received bytes come out of the same register
+		h.state.uart2.rx.push(b'A');
+		assert_eq!(h.read(0x39, 2), (proto::ResponseResult::Ok, vec![1, 0]));
+		assert_eq!(h.read(0x38, 2), (proto::ResponseResult::Ok, vec![b'A', 0]));
+		h.state.uart2.rx.push(b'B');
+		assert_eq!(h.write(0x39, 0), proto::ResponseResult::Ok);
+		assert!(h.state.uart2.rx.is_empty());
+	}
+
+	#[cfg(feature = "ps2-keyboard")]
+	#[test]
+	fn key_state_register() {
+		let mut h = Harness::new();
+		// Left Control, then Delete
+		for byte in [0x14, 0xE0, 0x71] {
+			h.state.key_state.update(byte);
+		}
+		let (result, data) = h.read(0x43, 32);
+		assert_eq!(result, proto::ResponseResult::Ok);
+		assert_eq!(data[0x14 / 8], 1 << (0x14 % 8));
+		assert_eq!(data[0xF1 / 8], 1 << (0xF1 % 8));
+		assert_eq!(
+			h.read(0x43, 3),
+			(proto::ResponseResult::Ok, vec![0, 0, 0x10])
+		);
+		assert_eq!(h.read(0x43, 33).0, proto::ResponseResult::BadLength);
+		assert_eq!(h.write(0x43, 0), proto::ResponseResult::BadRegister);
+	}
+
+	#[cfg(feature = "ps2-keyboard")]
+	#[test]
+	fn keyboard_control() {
+		let mut h = Harness::new();
+		assert_eq!(h.read(0x41, 1), (proto::ResponseResult::Ok, vec![0]));
+		// Only the make-only, repeat and ASCII bits exist
+		assert_eq!(h.write(0x41, 0xFF), proto::ResponseResult::Ok);
+		assert_eq!(h.read(0x41, 1), (proto::ResponseResult::Ok, vec![0x07]));
+		assert_eq!(
+			h.send(&proto::Request::new_clear_bits(false, 0x41, 0x01)),
+			proto::ResponseResult::Ok
+		);
+		assert_eq!(
+			h.state.kb_control,
+			KEYBOARD_CONTROL_REPEAT | KEYBOARD_CONTROL_ASCII
+		);
+	}
+
+	#[cfg(feature = "ps2-keyboard")]
+	#[test]
+	fn keyboard_repeat_rate() {
+		let mut h = Harness::new();
+		assert_eq!(h.read(0x4E, 1), (proto::ResponseResult::Ok, vec![0x2B]));
+		assert_eq!(h.write(0x4E, 0x00), proto::ResponseResult::Ok);
+		assert_eq!(h.read(0x4E, 1), (proto::ResponseResult::Ok, vec![0x00]));
+		assert_eq!(h.state.key_repeat.period_ms(), 33);
+		// Bit 7 doesn't exist
+		assert_eq!(h.write(0x4E, 0x80), proto::ResponseResult::BadLength);
+		assert_eq!(h.read(0x4E, 1), (proto::ResponseResult::Ok, vec![0x00]));
+		assert_eq!(h.read(0x4E, 2).0, proto::ResponseResult::BadLength);
+	}
+
+	#[cfg(feature = "ps2-keyboard")]
+	#[test]
+	fn boot_key_registers() {
+		let mut h = Harness::new();
+		assert_eq!(h.read(0x44, 1), (proto::ResponseResult::Ok, vec![0xF1]));
+		assert_eq!(h.write(0x44, 0x76), proto::ResponseResult::Ok);
+		assert_eq!(h.state.config.boot_key, 0x76);
+		// Escape held at power-on
+		h.state.boot_keys.start(0);
+		h.state.key_state.update(0x76);
+		assert!(h.state.boot_keys.check(&h.state.key_state, 0x76, 10));
+		assert_eq!(h.read(0x45, 1), (proto::ResponseResult::Ok, vec![0x01]));
+		// Write 1 to clear
+		assert_eq!(h.write(0x45, 0x01), proto::ResponseResult::Ok);
+		assert_eq!(h.read(0x45, 1), (proto::ResponseResult::Ok, vec![0x00]));
+		// The reset chord is off until it is set
+		assert_eq!(h.read(0x46, 1), (proto::ResponseResult::Ok, vec![0x00]));
+		assert_eq!(h.write(0x48, 0xF1), proto::ResponseResult::Ok);
+		assert_eq!(h.state.config.reset_chord, [0x00, 0x00, 0xF1]);
+		assert_eq!(h.read(0x48, 1), (proto::ResponseResult::Ok, vec![0xF1]));
+	}
+
+	#[cfg(feature = "ps2-keyboard")]
+	#[test]
+	fn keyboard_id_registers() {
+		let mut h = Harness::new();
+		assert_eq!(h.read(0x4B, 2), (proto::ResponseResult::Ok, vec![0, 0]));
+		assert_eq!(h.read(0x4C, 2), (proto::ResponseResult::Ok, vec![0, 0]));
+		assert_eq!(h.write(0x4B, 0), proto::ResponseResult::Ok);
+		assert!(h.state.keyboard_id.is_running());
+		assert_eq!(h.state.keyboard_id.next_command(0), Some(0xFF));
+		h.state.keyboard_id.command_sent(true, 20);
+		for byte in [0xFA, 0xAA] {
+			h.state.keyboard_id.update(byte, 500);
+		}
+		assert_eq!(h.state.keyboard_id.next_command(510), Some(0xF2));
+		h.state.keyboard_id.command_sent(true, 530);
+		for byte in [0xFA, 0xAB, 0x83] {
+			h.state.keyboard_id.update(byte, 540);
+		}
+		assert_eq!(h.read(0x4B, 2), (proto::ResponseResult::Ok, vec![1, 0xAA]));
+		assert_eq!(
+			h.read(0x4C, 2),
+			(proto::ResponseResult::Ok, vec![0xAB, 0x83])
+		);
+		assert_eq!(h.read(0x4C, 1).0, proto::ResponseResult::BadLength);
+		assert_eq!(h.write(0x4C, 0), proto::ResponseResult::BadRegister);
+	}
+
+	#[cfg(feature = "ps2-keyboard")]
+	#[test]
+	fn keyboard_fifo_registers() {
+		let mut h = Harness::new();
+		// Left Control pressed, then released
+		for byte in [0x14, 0xF0, 0x14] {
+			assert!(h.state.kb_rx.push(byte));
+		}
+		assert_eq!(
+			h.read(0x40, 2),
+			(proto::ResponseResult::Ok, vec![0x14, 0xF0])
+		);
+		// Padded with zeros
+		assert_eq!(
+			h.read(0x40, 3),
+			(proto::ResponseResult::Ok, vec![0x14, 0x00, 0x00])
+		);
+		// Injected scancodes wait for the caller to pass them on
+		assert_eq!(h.read(0x4D, 1), (proto::ResponseResult::Ok, vec![0]));
+		for _ in 0..KEYBOARD_INJECT_FIFO_LEN {
+			assert_eq!(h.write(0x4D, 0x1C), proto::ResponseResult::Ok);
+		}
+		assert_eq!(h.write(0x4D, 0x1C), proto::ResponseResult::BadLength);
+		assert_eq!(h.read(0x4D, 1), (proto::ResponseResult::Ok, vec![16]));
+		assert_eq!(h.state.kb_inject.pop(), Some(0x1C));
+		assert_eq!(h.read(0x4D, 2).0, proto::ResponseResult::BadLength);
+		// Characters, in ASCII mode
+		for byte in *b"Hi\r" {
+			assert!(h.state.kb_ascii.push(byte));
+		}
+		assert_eq!(
+			h.read(0x4F, 4),
+			(proto::ResponseResult::Ok, vec![b'H', b'i', b'\r', 0x00])
+		);
+		assert_eq!(h.write(0x4F, 0), proto::ResponseResult::BadRegister);
+	}
+
+	#[cfg(feature = "ps2-mouse")]
+	#[test]
+	fn mouse_registers() {
+		let mut h = Harness::new();
+		for byte in [0x09, 0x02, 0x03, 0x08, 0xFF, 0x00] {
+			h.state.mouse.update(byte);
+		}
+		assert_eq!(h.read(0x53, 1), (proto::ResponseResult::Ok, vec![2]));
+		assert_eq!(
+			h.read(0x54, 8),
+			(
+				proto::ResponseResult::Ok,
+				vec![0x09, 0x02, 0x03, 0x00, 0x08, 0xFF, 0x00, 0x00]
+			)
+		);
+		// Padded when empty
+		assert_eq!(h.read(0x54, 4), (proto::ResponseResult::Ok, vec![0; 4]));
+		assert_eq!(h.read(0x54, 5).0, proto::ResponseResult::BadLength);
+		// Total movement is 2 + 255 (no sign bit) right, 3 up
+		assert_eq!(
+			h.read(0x55, 6),
+			(
+				proto::ResponseResult::Ok,
+				vec![0x00, 0x01, 0x01, 0x03, 0x00, 0x00]
+			)
+		);
+		assert_eq!(h.read(0x55, 6).1, vec![0; 6]);
+		h.state.mouse.update(0x09);
+		h.state.mouse.update(0x00);
+		h.state.mouse.update(0x00);
+		assert_eq!(h.write(0x53, 0), proto::ResponseResult::Ok);
+		assert_eq!(h.read(0x53, 1), (proto::ResponseResult::Ok, vec![0]));
+	}
+
+	#[cfg(feature = "ps2-mouse")]
+	#[test]
+	fn mouse_settings() {
+		let mut h = Harness::new();
+		assert_eq!(h.read(0x56, 1), (proto::ResponseResult::Ok, vec![100]));
+		assert_eq!(h.read(0x57, 1), (proto::ResponseResult::Ok, vec![2]));
+		assert_eq!(h.write(0x56, 50), proto::ResponseResult::BadLength);
+		assert_eq!(h.write(0x57, 4), proto::ResponseResult::BadLength);
+		assert_eq!(h.write(0x56, 40), proto::ResponseResult::Busy);
+		assert_eq!(h.read(0x56, 1), (proto::ResponseResult::Ok, vec![40]));
+		assert_eq!(
+			h.read(0x0B, 4),
+			(proto::ResponseResult::Ok, vec![1, 1, 0x56, 0])
+		);
+		assert_eq!(h.state.mouse.next_command(), Some(0xF3));
+		// Can't change the resolution until the sample rate is done
+		assert_eq!(h.write(0x57, 0), proto::ResponseResult::Busy);
+		assert_eq!(h.read(0x57, 1), (proto::ResponseResult::Ok, vec![2]));
+	}
+
+	#[test]
+	fn interrupt_registers() {
+		let mut h = Harness::new();
+		h.irq.raise(0x11);
+		assert_eq!(h.read(0x10, 1), (proto::ResponseResult::Ok, vec![0x11]));
+		// Write 1 to clear
+		assert_eq!(h.write(0x10, 0x01), proto::ResponseResult::Ok);
+		assert_eq!(h.read(0x10, 1), (proto::ResponseResult::Ok, vec![0x10]));
+		assert!(!h.irq.is_asserted());
+		assert_eq!(h.write(0x11, 0x10), proto::ResponseResult::Ok);
+		assert!(h.irq.is_asserted());
+		assert_eq!(h.write(0x12, 0), proto::ResponseResult::Ok);
+		assert_eq!(h.read(0x12, 1), (proto::ResponseResult::Ok, vec![1]));
+	}
+
+	#[test]
+	fn interrupt_level_registers() {
+		let mut h = Harness::new();
+		// Only the UART TX FIFO starts out in its active state (empty)
+		let level = interrupt_level(&h.state);
+		assert_eq!(level, irq::UART_TX_EMPTY);
+		h.irq.set_level(level);
+		assert_eq!(h.read(0x9D, 1), (proto::ResponseResult::Ok, vec![0x20]));
+		assert_eq!(h.read(0x9E, 1), (proto::ResponseResult::Ok, vec![0x00]));
+		assert_eq!(h.read(0x10, 1), (proto::ResponseResult::Ok, vec![0x20]));
+		// Clearing the latch leaves the level alone
+		assert_eq!(h.write(0x10, 0x20), proto::ResponseResult::Ok);
+		assert_eq!(h.read(0x9D, 1), (proto::ResponseResult::Ok, vec![0x20]));
+		assert_eq!(h.read(0x10, 1), (proto::ResponseResult::Ok, vec![0x00]));
+		h.state.uart_tx.push(b'A');
+		assert_eq!(interrupt_level(&h.state), 0);
+		// Each source in turn
+		h.state.kb_rx.push(0x1C);
+		assert_eq!(interrupt_level(&h.state), irq::KEYBOARD_RX_NOT_EMPTY);
+		h.state.kb_rx.clear();
+		h.state.kb_ascii.push(b'a');
+		assert_eq!(interrupt_level(&h.state), irq::KEYBOARD_RX_NOT_EMPTY);
+		h.state.kb_ascii.clear();
+		for byte in [0x08, 0x01, 0x01] {
+			h.state.mouse.update(byte);
+		}
+		assert_eq!(interrupt_level(&h.state), irq::MOUSE_RX_NOT_EMPTY);
+		h.state.mouse.pop();
+		h.state.uart_rx.push(b'B');
+		assert_eq!(interrupt_level(&h.state), irq::UART_RX_NOT_EMPTY);
+		h.state.uart_rx.clear();
+		// UART2 only counts once it is turned on
+		h.state.uart2.rx.push(b'C');
+		assert_eq!(interrupt_level(&h.state), 0);
+		h.state.uart2.control = crate::uart::UART2_ENABLE;
+		assert_eq!(
+			interrupt_level(&h.state),
+			irq::UART2_RX_NOT_EMPTY | irq::UART2_TX_EMPTY
+		);
+		h.state.uart2.control = 0;
+		h.state.config.over_temperature_limit_c = 70;
+		h.state.temperature_c = 69;
+		assert_eq!(interrupt_level(&h.state), 0);
+		h.state.temperature_c = 70;
+		assert_eq!(interrupt_level(&h.state), irq::OVER_TEMPERATURE);
+		h.state.temperature_c = 25;
+		assert!(h.state.watchdog.set_timeout(2));
+		assert!(h.state.watchdog.set_pretimeout(1));
+		h.state.watchdog.update(0, true);
+		h.state.watchdog.update(1000, true);
+		assert_eq!(interrupt_level(&h.state), irq::WATCHDOG_PRETIMEOUT);
+		h.irq.set_level(interrupt_level(&h.state));
+		assert_eq!(h.read(0x9E, 1), (proto::ResponseResult::Ok, vec![0x40]));
+		assert_eq!(h.read(0x14, 1), (proto::ResponseResult::Ok, vec![0x40]));
+		// Read only, one byte at a time
+		assert_eq!(h.write(0x9D, 0), proto::ResponseResult::BadRegister);
+		assert_eq!(h.read(0x9D, 2).0, proto::ResponseResult::BadLength);
+	}
+
+	#[test]
+	fn irq_line() {
+		let mut h = Harness::new();
+		assert_eq!(h.read(0x90, 1), (proto::ResponseResult::Ok, vec![0]));
+		assert_eq!(
+			h.write(0x90, irq::LINE_ACTIVE_HIGH | irq::LINE_OPEN_DRAIN),
+			proto::ResponseResult::Ok
+		);
+		assert_eq!(h.state.config.irq_line, 0x03);
+		// Reserved bits
+		assert_eq!(h.write(0x90, 0x04), proto::ResponseResult::BadLength);
+		assert_eq!(h.read(0x90, 1), (proto::ResponseResult::Ok, vec![3]));
+		// Two pulses, asserted then released, whatever the interrupts say
+		assert_eq!(h.write(0x91, 2), proto::ResponseResult::Ok);
+		assert_eq!(h.read(0x91, 1), (proto::ResponseResult::Ok, vec![2]));
+		let mut levels = vec![];
+		for now_ms in (0..=50).step_by(5) {
+			h.irq.poll(now_ms);
+			levels.push(h.irq.is_asserted());
+		}
+		assert_eq!(
+			levels,
+			[true, true, false, false, true, true, false, false, false, false, false]
+		);
+		assert_eq!(h.read(0x91, 1), (proto::ResponseResult::Ok, vec![0]));
+	}
+
+	#[test]
+	fn register_changes() {
+		let mut h = Harness::new();
+		// Everything has changed, as far as a host which just started knows
+		assert_eq!(h.read(0x92, 1), (proto::ResponseResult::Ok, vec![0xFF]));
+		assert_eq!(h.write(0x92, 0xFF), proto::ResponseResult::Ok);
+		assert_eq!(h.read(0x92, 1), (proto::ResponseResult::Ok, vec![0x00]));
+		// Two keys arrive, and the host takes one of them
+		h.state.kb_rx.push(0x1C);
+		h.state.kb_rx.push(0x32);
+		assert_eq!(h.read(0x92, 1), (proto::ResponseResult::Ok, vec![1 << 6]));
+		h.read(0x40, 1);
+		assert_eq!(h.read(0x92, 1), (proto::ResponseResult::Ok, vec![0x00]));
+		// A button is pressed and released between polls, but we looked in
+		// between
+		h.state.button_status = 0x01;
+		sample_changes(&mut h.state, &h.event_log, &h.irq);
+		h.state.button_status = 0x00;
+		assert_eq!(h.read(0x92, 1), (proto::ResponseResult::Ok, vec![1 << 2]));
+		h.read(0x20, 1);
+		assert_eq!(h.read(0x92, 1), (proto::ResponseResult::Ok, vec![0x00]));
+	}
+
+	#[test]
+	fn event_log_registers() {
+		let mut h = Harness::new();
+		h.event_log
+			.push(Event::new(0x0102_0304, EventKind::PowerOn, 0x01));
+		assert_eq!(h.read(0x27, 1), (proto::ResponseResult::Ok, vec![1]));
+		assert_eq!(h.read(0x28, 5).0, proto::ResponseResult::BadLength);
+		let (result, data) = h.read(0x28, 12);
+		assert_eq!(result, proto::ResponseResult::Ok);
+		assert_eq!(data[0..6], [0x04, 0x03, 0x02, 0x01, 0x02, 0x01]);
+		// Padded out with empty events
+		assert_eq!(data[6..12], Event::EMPTY.as_bytes());
+		assert_eq!(h.read(0x27, 1), (proto::ResponseResult::Ok, vec![0]));
+	}
+
+	#[test]
+	fn board_presence() {
+		let mut h = Harness::new();
+		// We assume there's a main board until told to look for one
+		assert_eq!(h.read(0x93, 1), (proto::ResponseResult::Ok, vec![0x80]));
+		assert_eq!(h.write(0x93, 3), proto::ResponseResult::BadLength);
+		if expansion::GPIO_AVAILABLE == 0 {
+			assert_eq!(
+				h.write(0x93, power::PRESENCE_GPIO1),
+				proto::ResponseResult::BadLength
+			);
+			return;
+		}
+		assert_eq!(
+			h.write(0x93, power::PRESENCE_GPIO1),
+			proto::ResponseResult::Ok
+		);
+		assert_eq!(h.state.config.board_presence, power::PRESENCE_GPIO1);
+		assert_eq!(h.state.gpio.pin_pull(1), expansion::PULL_UP);
+		h.state.gpio.set_input(0b10);
+		assert_eq!(h.read(0x93, 1), (proto::ResponseResult::Ok, vec![0x02]));
+		h.state.gpio.set_input(0b01);
+		assert_eq!(h.read(0x93, 1), (proto::ResponseResult::Ok, vec![0x82]));
+	}
+
+	#[test]
+	fn power_led() {
+		let mut h = Harness::new();
+		assert_eq!(
+			h.read(0x94, 1),
+			(proto::ResponseResult::Ok, vec![power::LED_BREATHE])
+		);
+		assert_eq!(h.read(0x95, 1), (proto::ResponseResult::Ok, vec![100]));
+		assert_eq!(h.write(0x94, power::LED_BLINK), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0x94, 3), proto::ResponseResult::BadLength);
+		assert_eq!(h.write(0x95, 30), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0x95, 101), proto::ResponseResult::BadLength);
+		assert_eq!(h.state.config.led_mode, power::LED_BLINK);
+		assert_eq!(h.read(0x95, 1), (proto::ResponseResult::Ok, vec![30]));
+	}
+
+	#[cfg(feature = "status-led")]
+	#[test]
+	fn status_led() {
+		use crate::statusled::PATTERN_BLINK;
+		let mut h = Harness::new();
+		assert_eq!(h.read(0x99, 1), (proto::ResponseResult::Ok, vec![0]));
+		assert_eq!(h.write(0x96, 0x20), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0x98, 0x40), proto::ResponseResult::Ok);
+		assert_eq!(h.state.status_led.colour(), [0x20, 0x00, 0x40]);
+		assert_eq!(h.read(0x98, 1), (proto::ResponseResult::Ok, vec![0x40]));
+		assert_eq!(h.write(0x99, PATTERN_BLINK), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0x99, 4), proto::ResponseResult::BadLength);
+		assert_eq!(
+			h.read(0x99, 1),
+			(proto::ResponseResult::Ok, vec![PATTERN_BLINK])
+		);
+		// A fault which cut the power shows until the host clears it
+		h.state.status_led.power_event(EventKind::OverCurrent);
+		assert_eq!(
+			h.read(0x9A, 1),
+			(
+				proto::ResponseResult::Ok,
+				vec![EventKind::OverCurrent as u8]
+			)
+		);
+		assert_eq!(h.write(0x9A, 0), proto::ResponseResult::Ok);
+		assert_eq!(h.read(0x9A, 1), (proto::ResponseResult::Ok, vec![0]));
+		assert_eq!(h.read(0x96, 2).0, proto::ResponseResult::BadLength);
+	}
+
+	#[test]
+	fn host_watchdog() {
+		use crate::watchdog::WatchdogAction;
+		let mut h = Harness::new();
+		assert_eq!(h.read(0x66, 1), (proto::ResponseResult::Ok, vec![0]));
+		assert_eq!(h.write(0x67, 2), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0x66, 2), proto::ResponseResult::BadLength);
+		assert_eq!(h.write(0x66, 10), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0x67, 10), proto::ResponseResult::BadLength);
+		assert_eq!(h.read(0x67, 1), (proto::ResponseResult::Ok, vec![2]));
+		assert_eq!(h.state.watchdog.update(0, true), WatchdogAction::None);
+		assert_eq!(h.read(0x68, 1), (proto::ResponseResult::Ok, vec![10]));
+		assert_eq!(h.state.watchdog.update(4500, true), WatchdogAction::None);
+		assert_eq!(h.read(0x68, 1), (proto::ResponseResult::Ok, vec![6]));
+		assert_eq!(h.write(0x68, 0), proto::ResponseResult::Ok);
+		assert_eq!(h.state.watchdog.update(4600, true), WatchdogAction::None);
+		assert_eq!(h.read(0x68, 1), (proto::ResponseResult::Ok, vec![10]));
+		// Stopping it
+		assert_eq!(h.write(0x66, 0), proto::ResponseResult::Ok);
+		assert_eq!(h.state.watchdog.update(20_000, true), WatchdogAction::None);
+		assert_eq!(h.read(0x68, 1), (proto::ResponseResult::Ok, vec![0]));
+		assert_eq!(h.read(0x66, 2).0, proto::ResponseResult::BadLength);
+	}
+
+	#[test]
+	fn rise_times() {
+		use crate::risetime::RiseTimer;
+		let mut h = Harness::new();
+		assert_eq!(h.read(0x9B, 6), (proto::ResponseResult::Ok, vec![0xFF; 6]));
+		assert_eq!(h.read(0x9C, 1), (proto::ResponseResult::Ok, vec![0]));
+		// A slow 5.0V rail
+		let mut timer = RiseTimer::new(0);
+		assert_eq!(timer.sample(5, &[1600, 1000], true), None);
+		h.state.rise_times = timer.sample(25, &[1600, 1600], true).unwrap();
+		assert_eq!(
+			h.read(0x9B, 6),
+			(proto::ResponseResult::Ok, vec![5, 0, 25, 0, 5, 0])
+		);
+		assert_eq!(h.read(0x9C, 1), (proto::ResponseResult::Ok, vec![0b010]));
+		assert_eq!(h.write(0x9C, 0), proto::ResponseResult::BadRegister);
+		assert_eq!(h.read(0x9B, 2).0, proto::ResponseResult::BadLength);
+	}
+
+	#[test]
+	fn disk_activity() {
+		let mut h = Harness::new();
+		assert_eq!(h.read(0x2E, 1), (proto::ResponseResult::Ok, vec![0]));
+		assert_eq!(h.write(0x2E, 0x00), proto::ResponseResult::Ok);
+		assert_eq!(h.read(0x2E, 1), (proto::ResponseResult::Ok, vec![1]));
+	}
+
+	#[cfg(not(any(
+		feature = "fan",
+		feature = "encoder",
+		feature = "i2c-target",
+		feature = "ps2-power",
+		feature = "status-led"
+	)))]
+	#[test]
+	fn gpio_registers() {
+		let mut h = Harness::new();
+		assert_eq!(h.read(0xA0, 1), (proto::ResponseResult::Ok, vec![0b11]));
+		assert_eq!(h.write(0xA1, 0xFF), proto::ResponseResult::Ok);
+		assert_eq!(h.read(0xA1, 1), (proto::ResponseResult::Ok, vec![0b11]));
+		assert_eq!(h.write(0xA2, 0b01), proto::ResponseResult::Ok);
+		assert_eq!(h.read(0xA2, 1), (proto::ResponseResult::Ok, vec![0b01]));
+		assert_eq!(
+			h.write(0xA4, expansion::PULL_UP << 2),
+			proto::ResponseResult::Ok
+		);
+		assert_eq!(h.write(0xA4, 0b11), proto::ResponseResult::BadLength);
+		assert_eq!(
+			h.read(0xA4, 1),
+			(proto::ResponseResult::Ok, vec![expansion::PULL_UP << 2])
+		);
+		// Read only
+		h.state.gpio.set_input(0b10);
+		assert_eq!(h.read(0xA3, 1), (proto::ResponseResult::Ok, vec![0b10]));
+		assert_eq!(h.write(0xA3, 0), proto::ResponseResult::BadRegister);
+		assert_eq!(h.write(0xA0, 0), proto::ResponseResult::BadRegister);
+	}
+
+	#[cfg(not(any(
+		feature = "fan",
+		feature = "encoder",
+		feature = "i2c-target",
+		feature = "ps2-power",
+		feature = "status-led"
+	)))]
+	#[test]
+	fn pwm_registers() {
+		let mut h = Harness::new();
+		assert_eq!(
+			h.read(0xA8, 2),
+			(proto::ResponseResult::Ok, vec![0xE8, 0x03])
+		);
+		// 25 kHz
+		assert_eq!(h.write(0xA8, 0xA8), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0xA9, 0x61), proto::ResponseResult::Ok);
+		assert_eq!(h.state.pwm.frequency_hz, 25_000);
+		assert_eq!(h.write(0xAA, 101), proto::ResponseResult::BadLength);
+		assert_eq!(h.write(0xAA, 40), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0xAB, 0xFF), proto::ResponseResult::Ok);
+		assert!(h.state.pwm.is_enabled());
+		assert_eq!(h.read(0xAA, 1), (proto::ResponseResult::Ok, vec![40]));
+		assert_eq!(h.read(0xAB, 1), (proto::ResponseResult::Ok, vec![0x01]));
+	}
+
+	#[cfg(all(feature = "adc", not(feature = "current-sense")))]
+	#[test]
+	fn over_temperature_registers() {
+		let mut h = Harness::new();
+		assert_eq!(h.read(0x73, 1), (proto::ResponseResult::Ok, vec![0]));
+		assert_eq!(h.read(0x74, 1), (proto::ResponseResult::Ok, vec![30]));
+		assert_eq!(h.write(0x73, 128), proto::ResponseResult::BadLength);
+		assert_eq!(h.write(0x73, 70), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0x74, 10), proto::ResponseResult::Ok);
+		assert_eq!(h.state.config.over_temperature_limit_c, 70);
+		assert_eq!(h.read(0x74, 1), (proto::ResponseResult::Ok, vec![10]));
+		h.state.temperature_c = -3;
+		assert_eq!(h.read(0x21, 1), (proto::ResponseResult::Ok, vec![0xFD]));
+	}
+
+	#[test]
+	fn power_restore_register() {
+		let mut h = Harness::new();
+		assert_eq!(h.read(0x2F, 1), (proto::ResponseResult::Ok, vec![0]));
+		// Restore the last state, after 5 seconds
+		assert_eq!(
+			h.write(0x2F, (5 << 2) | power::RESTORE_LAST),
+			proto::ResponseResult::Ok
+		);
+		assert_eq!(h.state.config.power_restore, power::RESTORE_LAST);
+		assert_eq!(h.state.config.power_on_delay_s, 5);
+		assert_eq!(
+			h.read(0x2F, 1),
+			(
+				proto::ResponseResult::Ok,
+				vec![(5 << 2) | power::RESTORE_LAST]
+			)
+		);
+		assert_eq!(h.write(0x2F, 0b11), proto::ResponseResult::BadLength);
+		assert_eq!(h.state.config.power_restore, power::RESTORE_LAST);
+	}
+
+	#[test]
+	fn button_registers() {
+		let mut h = Harness::new();
+		h.state.button_status = 0b0001;
+		assert_eq!(h.read(0x20, 1), (proto::ResponseResult::Ok, vec![0b0001]));
+		assert_eq!(h.write(0x20, 0), proto::ResponseResult::BadRegister);
+		// The power button's defaults
+		assert_eq!(h.read(0xC0, 1), (proto::ResponseResult::Ok, vec![0x21]));
+		// Make GPIO 0 a second power button, and send its long press to the host
+		assert_eq!(h.write(0xC2, 0x41), proto::ResponseResult::Ok);
+		assert_eq!(h.read(0xC2, 1), (proto::ResponseResult::Ok, vec![0x41]));
+		assert_eq!(h.write(0xC3, 0x07), proto::ResponseResult::BadLength);
+		assert_eq!(h.read(0xC3, 1), (proto::ResponseResult::Ok, vec![0x00]));
+		// Tell the host about a tap of the reset button, but reset on a long
+		// press - and keep that policy in flash
+		assert_eq!(h.write(0xC1, 0x34), proto::ResponseResult::Ok);
+		assert_eq!(h.state.config.reset_button, 0x34);
+		assert_eq!(h.write(0xC1, 0x60), proto::ResponseResult::BadLength);
+		assert_eq!(h.state.config.reset_button, 0x34);
+		assert_eq!(
+			h.write(0x0F, CONFIG_COMMAND_DEFAULTS),
+			proto::ResponseResult::Ok
+		);
+		assert_eq!(h.read(0xC1, 1), (proto::ResponseResult::Ok, vec![0x03]));
+		h.state.button_events = 0b0100_0100;
+		assert_eq!(
+			h.read(0xC4, 1),
+			(proto::ResponseResult::Ok, vec![0b0100_0100])
+		);
+		assert_eq!(h.write(0xC4, 0b0000_0100), proto::ResponseResult::Ok);
+		assert_eq!(
+			h.read(0xC4, 1),
+			(proto::ResponseResult::Ok, vec![0b0100_0000])
+		);
+	}
+
+	#[test]
+	fn glitch_filters() {
+		let mut h = Harness::new();
+		assert_eq!(h.read(0xC5, 1), (proto::ResponseResult::Ok, vec![0]));
+		assert_eq!(h.write(0xC5, 10), proto::ResponseResult::Ok);
+		assert_eq!(h.state.config.button_filter, 10);
+		assert_eq!(
+			h.write(0xC5, glitch::BUTTON_FILTER_MAX + 1),
+			proto::ResponseResult::BadLength
+		);
+		assert_eq!(h.write(0xC6, 2), proto::ResponseResult::Ok);
+		assert_eq!(
+			h.write(0xC6, glitch::CS_FILTER_MAX_US + 1),
+			proto::ResponseResult::BadLength
+		);
+		assert_eq!(h.read(0xC6, 1), (proto::ResponseResult::Ok, vec![2]));
+		h.state.glitches.add(glitch::INPUT_RESET, 3);
+		assert_eq!(h.read(0xC7, 2).0, proto::ResponseResult::BadLength);
+		assert_eq!(
+			h.read(0xC7, 6),
+			(proto::ResponseResult::Ok, vec![0, 0, 3, 0, 0, 0])
+		);
+		assert_eq!(h.write(0xC7, 0), proto::ResponseResult::Ok);
+		assert_eq!(h.read(0xC7, 6), (proto::ResponseResult::Ok, vec![0; 6]));
+	}
+
+	#[cfg(feature = "encoder")]
+	#[test]
+	fn encoder_registers() {
+		let mut h = Harness::new();
+		h.state.encoder.update(3, 50);
+		assert_eq!(h.read(0xC8, 2), (proto::ResponseResult::Ok, vec![3, 0]));
+		assert_eq!(h.read(0xC9, 2), (proto::ResponseResult::Ok, vec![60, 0]));
+		assert_eq!(h.read(0xC8, 1).0, proto::ResponseResult::BadLength);
+		assert_eq!(h.write(0xC8, 0), proto::ResponseResult::Ok);
+		h.state.encoder.update(2, 50);
+		assert_eq!(
+			h.read(0xC8, 2),
+			(proto::ResponseResult::Ok, vec![0xFF, 0xFF])
+		);
+		assert_eq!(h.write(0xC9, 0), proto::ResponseResult::BadRegister);
+	}
+
+	#[cfg(feature = "ps2-power")]
+	#[test]
+	fn ps2_power_registers() {
+		let mut h = Harness::new();
+		assert_eq!(h.read(0x49, 1), (proto::ResponseResult::Ok, vec![0x03]));
+		assert_eq!(h.read(0x4A, 1), (proto::ResponseResult::Ok, vec![0x00]));
+		// Turn the mouse off
+		assert_eq!(
+			h.send(&proto::Request::new_clear_bits(false, 0x49, PORT_MOUSE)),
+			proto::ResponseResult::Ok
+		);
+		assert_eq!(h.state.ps2_power.powered(), PORT_KEYBOARD);
+		// Power cycle the keyboard
+		assert_eq!(h.write(0x4A, PORT_KEYBOARD), proto::ResponseResult::Ok);
+		assert_eq!(h.read(0x4A, 1), (proto::ResponseResult::Ok, vec![0x01]));
+		assert_eq!(h.state.ps2_power.powered(), 0);
+		h.state.ps2_power.tick(POWER_CYCLE_OFF_MS);
+		assert_eq!(h.read(0x4A, 1), (proto::ResponseResult::Ok, vec![0x00]));
+		assert_eq!(h.state.ps2_power.powered(), PORT_KEYBOARD);
+		assert_eq!(h.read(0x49, 2).0, proto::ResponseResult::BadLength);
+	}
+
+	#[cfg(feature = "i2c-target")]
+	#[test]
+	fn i2c_target_address_register() {
+		let mut h = Harness::new();
+		assert_eq!(h.read(0x65, 1), (proto::ResponseResult::Ok, vec![0x42]));
+		assert_eq!(h.write(0x65, 0x2A), proto::ResponseResult::Ok);
+		assert_eq!(h.state.config.i2c_address, 0x2A);
+		assert_eq!(h.read(0x65, 1), (proto::ResponseResult::Ok, vec![0x2A]));
+		// Reserved addresses are refused
+		assert_eq!(h.write(0x65, 0x07), proto::ResponseResult::BadLength);
+		assert_eq!(h.write(0x65, 0x78), proto::ResponseResult::BadLength);
+		assert_eq!(h.state.config.i2c_address, 0x2A);
+	}
+
+	#[cfg(feature = "adc")]
+	#[test]
+	fn analog_registers() {
+		let mut h = Harness::new();
+		assert_eq!(
+			h.read(0xB0, 1),
+			(proto::ResponseResult::Ok, vec![analog::ANALOG_AVAILABLE])
+		);
+		assert_eq!(
+			h.read(0xB1, 1),
+			(proto::ResponseResult::Ok, vec![analog::DEFAULT_INTERVAL])
+		);
+		assert_eq!(h.write(0xB1, 50), proto::ResponseResult::Ok);
+		assert_eq!(h.read(0xB1, 1), (proto::ResponseResult::Ok, vec![50]));
+		// Which set both inputs' intervals, but not the temperature's
+		assert_eq!(
+			h.read(0xAC, 3),
+			(proto::ResponseResult::Ok, vec![50, 50, 100])
+		);
+		assert_eq!(h.write(0xAD, 5), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0xAE, 0), proto::ResponseResult::Ok);
+		assert_eq!(h.read(0xAD, 2), (proto::ResponseResult::Ok, vec![5, 0]));
+		assert_eq!(h.read(0xAD, 3).0, proto::ResponseResult::BadLength);
+		assert_eq!(h.read(0xBF, 1), (proto::ResponseResult::Ok, vec![0]));
+		assert_eq!(h.write(0xBF, 0b10_01_00), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0xBF, 0b11), proto::ResponseResult::BadLength);
+		assert_eq!(
+			h.read(0xBF, 1),
+			(proto::ResponseResult::Ok, vec![0b10_01_00])
+		);
+		assert_eq!(h.write(0xB2, 3), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0xB2, 5), proto::ResponseResult::BadLength);
+		assert_eq!(h.read(0xB2, 1), (proto::ResponseResult::Ok, vec![3]));
+		// Nothing sampled yet
+		assert_eq!(h.read(0xB4, 2), (proto::ResponseResult::Ok, vec![0, 0]));
+		let calibration = h.state.config.analog_calibration;
+		h.state
+			.analog
+			.record(0, 1500, 1500, &[Some(1241), Some(4095)], &calibration);
+		assert_eq!(
+			h.read(0xB3, 4),
+			(proto::ResponseResult::Ok, vec![0xE8, 0x03, 0xE4, 0x0C])
+		);
+		assert_eq!(h.read(0xB3, 2).0, proto::ResponseResult::BadLength);
+		assert_eq!(
+			h.read(0xB4, 2),
+			(proto::ResponseResult::Ok, vec![0xE4, 0x0C])
+		);
+		// Read only
+		assert_eq!(h.write(0xB3, 0), proto::ResponseResult::BadRegister);
+		// The history so far, which a write clears
+		let (result, history) = h.read(0xB5, 12);
+		assert_eq!(result, proto::ResponseResult::Ok);
+		assert_eq!(&history[0..6], &[0xE8, 0x03, 0xE8, 0x03, 0xE8, 0x03]);
+		assert_eq!(h.read(0xB5, 6).0, proto::ResponseResult::BadLength);
+		assert_eq!(h.write(0xB5, 0), proto::ResponseResult::Ok);
+		assert_eq!(h.read(0xB5, 12), (proto::ResponseResult::Ok, vec![0; 12]));
+	}
+
+	#[cfg(feature = "adc")]
+	#[test]
+	fn analog_calibration_registers() {
+		let mut h = Harness::new();
+		let unity = [0x00, 0x10, 0x00, 0x00];
+		assert_eq!(
+			h.read(0xB6, 8),
+			(proto::ResponseResult::Ok, [unity, unity].concat())
+		);
+		assert_eq!(h.read(0xBE, 1), (proto::ResponseResult::Ok, vec![0]));
+		// Input 1 by hand: a gain of 3, less 20 mV
+		for (register, byte) in (0xBA..=0xBD).zip([0x00, 0x30, 0xEC, 0xFF]) {
+			assert_eq!(h.write(register, byte), proto::ResponseResult::Ok);
+		}
+		assert_eq!(
+			h.state.config.analog_calibration[1],
+			Calibration {
+				gain: 0x3000,
+				offset_mv: -20
+			}
+		);
+		assert_eq!(
+			h.read(0xBA, 4),
+			(proto::ResponseResult::Ok, vec![0x00, 0x30, 0xEC, 0xFF])
+		);
+		assert_eq!(h.read(0xBA, 5).0, proto::ResponseResult::BadLength);
+		assert_eq!(h.read(0xBE, 1), (proto::ResponseResult::Ok, vec![0x02]));
+		// Nothing to calibrate against yet
+		assert_eq!(
+			h.write(0xBE, ANALOG_CALIBRATE_FACTORY),
+			proto::ResponseResult::BadLength
+		);
+		// 1.65V at both pins
+		let calibration = h.state.config.analog_calibration;
+		h.state
+			.analog
+			.record(0, 1500, 1500, &[Some(2048), Some(2048)], &calibration);
+		assert_eq!(h.state.analog.millivolts(1), 4930);
+		assert_eq!(
+			h.write(0xBE, ANALOG_CALIBRATE_FACTORY),
+			proto::ResponseResult::Ok
+		);
+		assert_eq!(
+			h.read(0xB6, 2),
+			(proto::ResponseResult::Ok, vec![0x00, 0x20])
+		);
+		assert_eq!(h.read(0xBE, 1), (proto::ResponseResult::Ok, vec![0x03]));
+		assert_eq!(
+			h.write(0xBE, ANALOG_CALIBRATE_CLEAR),
+			proto::ResponseResult::Ok
+		);
+		assert_eq!(h.read(0xBE, 1), (proto::ResponseResult::Ok, vec![0]));
+		assert_eq!(h.write(0xBE, 0x03), proto::ResponseResult::BadLength);
+	}
+
+	#[cfg(all(feature = "speaker", not(feature = "current-sense")))]
+	#[test]
+	fn buzzer_queue() {
+		let mut h = Harness::new();
+		assert_eq!(h.write(0x80, 0xB8), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0x81, 0x01), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0x82, 25), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0x83, 5), proto::ResponseResult::Ok);
+		assert_eq!(
+			h.read(0x80, 2),
+			(proto::ResponseResult::Ok, vec![0xB8, 0x01])
+		);
+		for _ in 0..BUZZER_QUEUE_LEN {
+			assert_eq!(
+				h.write(0x84, BUZZER_COMMAND_QUEUE),
+				proto::ResponseResult::Ok
+			);
+		}
+		// The queue is full
+		assert_eq!(
+			h.write(0x84, BUZZER_COMMAND_QUEUE),
+			proto::ResponseResult::BadLength
+		);
+		assert_eq!(
+			h.read(0x84, 1),
+			(proto::ResponseResult::Ok, vec![BUZZER_QUEUE_LEN as u8])
+		);
+		assert_eq!(
+			h.state.buzzer_queue.pop(),
+			Some(Note {
+				frequency_hz: 440,
+				duration: 25,
+				gap: 5,
+			})
+		);
+		assert_eq!(
+			h.write(0x84, BUZZER_COMMAND_FLUSH),
+			proto::ResponseResult::Ok
+		);
+		assert!(h.state.buzzer_queue.is_empty());
+	}
+
+	#[test]
+	fn matches_register_map() {
+		use proto::registers::{Access, Length};
+		let mut h = Harness::new();
+		for register in proto::registers::ALL {
+			if !register.is_available(FEATURE_FLAGS) {
+				assert_eq!(
+					h.read(register.address, 1).0,
+					proto::ResponseResult::BadRegister,
+					"{} shouldn't be in this build",
+					register.name
+				);
+				continue;
+			}
+			let longest = match register.length {
+				Length::Exactly(len) | Length::UpTo(len) | Length::Window(len) => len,
+				Length::Entries(len) => {
+					proto::registers::MAX_READ_LEN - (proto::registers::MAX_READ_LEN % len)
+				}
+			};
+			assert_eq!(
+				h.read(register.address, longest).0,
+				proto::ResponseResult::Ok,
+				"{} should allow a {} byte read",
+				register.name,
+				longest
+			);
+			assert_eq!(
+				h.read(register.address, longest + 1).0,
+				proto::ResponseResult::BadLength,
+				"{} shouldn't allow a {} byte read",
+				register.name,
+				longest + 1
+			);
+			match register.access {
+				Access::ReadOnly => assert_eq!(
+					h.write(register.address, 0),
+					proto::ResponseResult::BadRegister,
+					"{} should be read only",
+					register.name
+				),
+				Access::ReadWrite | Access::Bitmask | Access::WriteOneToClear => assert_ne!(
+					h.write(register.address, 0),
+					proto::ResponseResult::BadRegister,
+					"{} should be writable",
+					register.name
+				),
+				// Some FIFOs can be written, and some can't
+				Access::Fifo => {}
+			}
+			let (bulk_length, bulk_result) = if register.access == Access::Fifo {
+				// At least two reads' worth
+				(longest * 2, proto::ResponseResult::Ok)
+			} else {
+				(1, proto::ResponseResult::BadRegister)
+			};
+			assert_eq!(
+				h.send(&proto::Request::new_bulk_read_start(
+					false,
+					register.address,
+					bulk_length
+				)),
+				bulk_result,
+				"{} bulk read of {} bytes",
+				register.name,
+				bulk_length
+			);
+			let set_bits = h.send(&proto::Request::new_set_bits(false, register.address, 0));
+			if register.access == Access::Bitmask {
+				assert_ne!(
+					set_bits,
+					proto::ResponseResult::BadRegister,
+					"{} should accept Set Bits",
+					register.name
+				);
+			} else {
+				assert_eq!(
+					set_bits,
+					proto::ResponseResult::BadRegister,
+					"{} shouldn't accept Set Bits",
+					register.name
+				);
+			}
+		}
+		// Nothing else answers
+		for address in 0..=0xFF {
+			let listed = proto::registers::find(address)
+				.map(|register| register.is_available(FEATURE_FLAGS))
+				.unwrap_or(false);
+			if !listed {
+				assert_eq!(
+					h.read(address, 1).0,
+					proto::ResponseResult::BadRegister,
+					"0x{:02x} isn't in the register map",
+					address
+				);
+			}
+		}
+	}
+
+	#[test]
+	fn set_and_clear_bits() {
+		let mut h = Harness::new();
+		assert_eq!(h.write(0x26, WAKE_ON_KEYBOARD), proto::ResponseResult::Ok);
+		assert_eq!(
+			h.send(&proto::Request::new_set_bits(false, 0x26, WAKE_ON_UART)),
+			proto::ResponseResult::Ok
+		);
+		assert_eq!(
+			h.read(0x26, 1),
+			(
+				proto::ResponseResult::Ok,
+				vec![WAKE_ON_KEYBOARD | WAKE_ON_UART]
+			)
+		);
+		assert_eq!(
+			h.send(&proto::Request::new_clear_bits(
+				true,
+				0x26,
+				WAKE_ON_KEYBOARD
+			)),
+			proto::ResponseResult::Ok
+		);
+		assert_eq!(
+			h.read(0x26, 1),
+			(proto::ResponseResult::Ok, vec![WAKE_ON_UART])
+		);
+		// Both halves of the interrupt mask
+		assert_eq!(
+			h.send(&proto::Request::new_set_bits(false, 0x15, 0x01)),
+			proto::ResponseResult::Ok
+		);
+		assert_eq!(
+			h.send(&proto::Request::new_set_bits(false, 0x11, 0x80)),
+			proto::ResponseResult::Ok
+		);
+		assert_eq!(h.irq.enabled(), 0x0180);
+		// Not a bitmask
+		assert_eq!(
+			h.send(&proto::Request::new_set_bits(false, 0x25, 0x01)),
+			proto::ResponseResult::BadRegister
+		);
+		assert_eq!(
+			h.send(&proto::Request::new_clear_bits(false, 0x10, 0x01)),
+			proto::ResponseResult::BadRegister
+		);
+	}
+
+	#[test]
+	fn not_ready() {
+		let mut h = Harness::new();
+		h.state.ready = false;
+		// The early registers can be read
+		assert_eq!(h.read(0x0E, 1), (proto::ResponseResult::Ok, vec![0]));
+		assert_eq!(h.read(0x00, 3).0, proto::ResponseResult::Ok);
+		assert_eq!(h.read(0x07, 4).0, proto::ResponseResult::Ok);
+		// But nothing else, and nothing can be written
+		assert_eq!(h.read(0x25, 1).0, proto::ResponseResult::NotReady);
+		assert_eq!(h.read(0xFF, 1).0, proto::ResponseResult::NotReady);
+		assert_eq!(h.write(0x07, 0xFF), proto::ResponseResult::NotReady);
+		assert_eq!(h.write(0x25, 1), proto::ResponseResult::NotReady);
+		assert_eq!(h.state.power_request, None);
+		h.state.ready = true;
+		assert_eq!(
+			h.read(0x0E, 1),
+			(proto::ResponseResult::Ok, vec![BMC_STATUS_READY])
+		);
+		assert_eq!(h.read(0x25, 1).0, proto::ResponseResult::Ok);
+	}
+
+	#[test]
+	fn config_lock() {
+		use crate::lock::{LOCK_COMMAND_LOCK, UNLOCK_KEY_1, UNLOCK_KEY_2};
+		let mut h = Harness::new();
+		assert_eq!(h.read(0x0D, 1), (proto::ResponseResult::Ok, vec![0]));
+		assert_eq!(h.write(0x0D, LOCK_COMMAND_LOCK), proto::ResponseResult::Ok);
+		assert_eq!(h.read(0x0D, 1), (proto::ResponseResult::Ok, vec![1]));
+		// Guarded registers refuse writes, but can still be read
+		assert_eq!(h.write(0x25, 0), proto::ResponseResult::Locked);
+		assert_eq!(h.state.power_request, None);
+		assert_eq!(
+			h.write(0x0F, CONFIG_COMMAND_SAVE),
+			proto::ResponseResult::Locked
+		);
+		assert!(!h.state.save_config);
+		assert_eq!(h.read(0x25, 1).0, proto::ResponseResult::Ok);
+		assert_eq!(
+			h.state.health.as_bytes()[3],
+			proto::ResponseResult::Locked as u8
+		);
+		// Others don't
+		assert_eq!(h.write(0xE0, 0x12), proto::ResponseResult::Ok);
+		assert_eq!(
+			h.send(&proto::Request::new_set_bits(false, 0x26, WAKE_ON_UART)),
+			proto::ResponseResult::Ok
+		);
+		// The keys must be back to back
+		assert_eq!(h.write(0x0D, UNLOCK_KEY_1), proto::ResponseResult::Ok);
+		assert_eq!(h.read(0x0D, 1).0, proto::ResponseResult::Ok);
+		assert_eq!(
+			h.write(0x0D, UNLOCK_KEY_2),
+			proto::ResponseResult::BadLength
+		);
+		assert_eq!(h.write(0x2F, 0), proto::ResponseResult::Locked);
+		assert_eq!(h.write(0x0D, UNLOCK_KEY_1), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0x0D, UNLOCK_KEY_2), proto::ResponseResult::Ok);
+		assert_eq!(h.read(0x0D, 1), (proto::ResponseResult::Ok, vec![0]));
+		assert_eq!(h.write(0x2F, 0), proto::ResponseResult::Ok);
+	}
+
+	#[test]
+	fn wide_read() {
+		let mut h = Harness::new();
+		let build_time_and_features = h.state.build_info[16..24].to_vec();
+		let mut wide_read = |register, width, count| {
+			let req = proto::Request::new_wide_read(false, register, width, count);
+			let rsp = handle_request(
+				&req,
+				&mut h.state,
+				&mut h.event_log,
+				&mut h.irq,
+				&mut h.read_buffer,
+			);
+			(rsp.result, rsp.data.to_vec())
+		};
+		// A run of registers: Firmware Build Time, then Feature Flags
+		let (result, data) = wide_read(0x05, proto::Width::U32, 2);
+		assert_eq!(result, proto::ResponseResult::Ok);
+		assert_eq!(data, build_time_and_features);
+		// One register, as values
+		assert_eq!(wide_read(0x18, proto::Width::U32, 4).1.len(), 16);
+		// Part of a register, a gap in the map, and the reserved width
+		assert_eq!(
+			wide_read(0x18, proto::Width::U32, 2).0,
+			proto::ResponseResult::BadLength
+		);
+		assert_eq!(
+			wide_read(0x68, proto::Width::U8, 2).0,
+			proto::ResponseResult::BadRegister
+		);
+		let mut bytes = [0xCE, 0x05, 0xC2, 0x00];
+		bytes[3] = proto::calculate_crc(&bytes[0..3]);
+		let req = <proto::Request as proto::Receivable>::from_bytes(&bytes).unwrap();
+		assert_eq!(h.send(&req), proto::ResponseResult::BadLength);
+	}
+
+	#[test]
+	fn register_watches() {
+		let mut h = Harness::new();
+		fn sample(h: &mut Harness) -> bool {
+			sample_watches(&mut h.state, &mut h.event_log, &mut h.irq);
+			h.irq.pending() & irq::WATCH_TRIGGERED != 0
+		}
+		// Watch 2: UART RX Count above 32
+		assert_eq!(h.write(0x85, 2), proto::ResponseResult::Ok);
+		for (register, byte) in [(0x86, 0x35), (0x87, 0x40), (0x88, 32), (0x8C, 2)] {
+			assert_eq!(h.write(register, byte), proto::ResponseResult::Ok);
+		}
+		assert_eq!(h.read(0x86, 7).1, [0x35, 0x40, 32, 0, 0, 0, 2]);
+		assert_eq!(h.read(0x8A, 3).1, [0, 0, 2]);
+		for byte in 0..32 {
+			h.state.uart_rx.push(byte);
+		}
+		assert!(!sample(&mut h));
+		h.state.uart_rx.push(32);
+		assert!(sample(&mut h));
+		assert_eq!(h.read(0x8D, 1).1, [0b0100_0100]);
+		// The FIFO is still there for the host to read
+		assert_eq!(h.state.uart_rx.len(), 33);
+		// Cleared whilst still true, so it doesn't trip again
+		assert_eq!(h.write(0x8D, 0xFF), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0x10, 0xFF), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0x14, 0xFF), proto::ResponseResult::Ok);
+		assert!(!sample(&mut h));
+		assert_eq!(h.read(0x8D, 1).1, [0b0000_0100]);
+		// Changing the threshold turns it off
+		assert_eq!(h.write(0x88, 40), proto::ResponseResult::Ok);
+		assert_eq!(h.read(0x8C, 1).1, [0]);
+		assert_eq!(h.read(0x8D, 1).1, [0]);
+		// Only what we can watch can be turned on: a FIFO, a field past the
+		// end of the register, and a comparison we don't know
+		assert_eq!(h.write(0x86, 0x30), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0x8C, 2), proto::ResponseResult::BadLength);
+		assert_eq!(h.write(0x86, 0x35), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0x87, 0x41), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0x8C, 2), proto::ResponseResult::BadLength);
+		assert_eq!(h.write(0x87, 0x00), proto::ResponseResult::Ok);
+		assert_eq!(h.write(0x8C, 5), proto::ResponseResult::BadLength);
+		assert_eq!(h.write(0x8C, 1), proto::ResponseResult::Ok);
+		// The other watches are separate
+		assert_eq!(h.write(0x85, 0), proto::ResponseResult::Ok);
+		assert_eq!(h.read(0x86, 7).1, [0; 7]);
+		assert_eq!(h.write(0x85, 4), proto::ResponseResult::BadLength);
+		assert_eq!(h.read(0x85, 1).1, [0]);
+	}
+
+	#[test]
+	fn bulk_read() {
+		let mut h = Harness::new();
+		for byte in 0..200 {
+			h.state.uart_rx.push(byte);
+		}
+		assert_eq!(
+			h.send(&proto::Request::new_bulk_read_start(false, 0x30, 200)),
+			proto::ResponseResult::Ok
+		);
+		assert!(h.state.uart_rx.is_empty());
+		let mut collected = Vec::new();
+		for offset in (0..200).step_by(READ_BUFFER_LEN) {
+			let length = (200 - offset).min(READ_BUFFER_LEN) as u8;
+			let req = proto::Request::new_bulk_read_continue(false, offset as u8, length);
+			let rsp = handle_request(
+				&req,
+				&mut h.state,
+				&mut h.event_log,
+				&mut h.irq,
+				&mut h.read_buffer,
+			);
+			assert_eq!(rsp.result, proto::ResponseResult::Ok);
+			collected.extend_from_slice(rsp.data);
+		}
+		assert_eq!(collected, (0..200).collect::<Vec<u8>>());
+		// Collecting a piece again gets the same bytes
+		let req = proto::Request::new_bulk_read_continue(true, 60, 2);
+		let rsp = handle_request(
+			&req,
+			&mut h.state,
+			&mut h.event_log,
+			&mut h.irq,
+			&mut h.read_buffer,
+		);
+		assert_eq!(rsp.data, [60, 61]);
+		// Past the end, or more than one read's worth
+		assert_eq!(
+			h.send(&proto::Request::new_bulk_read_continue(false, 190, 11)),
+			proto::ResponseResult::BadLength
+		);
+		assert_eq!(
+			h.send(&proto::Request::new_bulk_read_continue(
+				false,
+				0,
+				READ_BUFFER_LEN as u8 + 1
+			)),
+			proto::ResponseResult::BadLength
+		);
+		// Not a whole number of events, so nothing is taken
+		h.event_log.push(Event::new(0, EventKind::ProtocolError, 0));
+		assert_eq!(
+			h.send(&proto::Request::new_bulk_read_start(false, 0x28, 100)),
+			proto::ResponseResult::BadLength
+		);
+		assert_eq!(h.event_log.len(), 1);
+		// Not a FIFO
+		assert_eq!(
+			h.send(&proto::Request::new_bulk_read_start(false, 0x25, 1)),
+			proto::ResponseResult::BadRegister
+		);
+		// A new start throws away what was left
+		assert_eq!(
+			h.send(&proto::Request::new_bulk_read_start(false, 0x30, 1)),
+			proto::ResponseResult::Ok
+		);
+		assert_eq!(h.state.bulk.len(), 1);
+	}
+
+	#[test]
+	fn bulk_write() {
+		let mut h = Harness::new();
+		let data: Vec<u8> = (0..40).collect();
+		let piece = |offset: u8, length: u8| {
+			let data = &data[usize::from(offset)..usize::from(offset + length)];
+			(
+				proto::Request::new_bulk_write_continue(false, offset, length),
+				data,
+			)
+		};
+		// The config lock doesn't guard the UART
+		assert_eq!(h.write(0x0D, 0x01), proto::ResponseResult::Ok);
+		assert_eq!(
+			h.send(&proto::Request::new_bulk_write_start(false, 0x30, 40)),
+			proto::ResponseResult::Ok
+		);
+		// Nothing is written until it has all arrived
+		let (req, bytes) = piece(0, 20);
+		assert_eq!(h.send_with_payload(&req, bytes), proto::ResponseResult::Ok);
+		assert!(h.state.uart_tx.is_empty());
+		// Sending a piece again is fine, but a gap isn't
+		assert_eq!(h.send_with_payload(&req, bytes), proto::ResponseResult::Ok);
+		let (req, bytes) = piece(21, 19);
+		assert_eq!(
+			h.send_with_payload(&req, bytes),
+			proto::ResponseResult::BadLength
+		);
+		// A payload which doesn't match its request
+		let (req, bytes) = piece(20, 20);
+		assert_eq!(
+			h.send_with_payload(&req, &bytes[1..]),
+			proto::ResponseResult::BadLength
+		);
+		// The FIFO fills up part way, and the rest are kept
+		assert_eq!(
+			h.send_with_payload(&req, bytes),
+			proto::ResponseResult::BadLength
+		);
+		let written: Vec<u8> = core::iter::from_fn(|| h.state.uart_tx.pop()).collect();
+		assert_eq!(written, data[0..UART_TX_FIFO_LEN]);
+		// Sending the last piece again carries on, without writing anything
+		// twice
+		assert_eq!(h.send_with_payload(&req, bytes), proto::ResponseResult::Ok);
+		let written: Vec<u8> = core::iter::from_fn(|| h.state.uart_tx.pop()).collect();
+		assert_eq!(written, data[UART_TX_FIFO_LEN..]);
+		assert_eq!(h.send_with_payload(&req, bytes), proto::ResponseResult::Ok);
+		assert!(h.state.uart_tx.is_empty());
+		// Past the end, or more than one read's worth
+		assert_eq!(
+			h.send(&proto::Request::new_bulk_write_continue(false, 30, 11)),
+			proto::ResponseResult::BadLength
+		);
+		assert_eq!(
+			h.send(&proto::Request::new_bulk_write_start(false, 0x30, 100)),
+			proto::ResponseResult::Ok
+		);
+		assert_eq!(
+			h.send(&proto::Request::new_bulk_write_continue(
+				false,
+				0,
+				READ_BUFFER_LEN as u8 + 1
+			)),
+			proto::ResponseResult::BadLength
+		);
+		// Only a payload for a Bulk Write Continue is accepted
+		let req = proto::Request::new_short_write(false, 0xE0, 1);
+		let rsp = handle_payload(
+			&req,
+			&[1],
+			&mut h.state,
+			&mut h.event_log,
+			&mut h.irq,
+			&mut h.read_buffer,
+		);
+		assert_eq!(rsp.result, proto::ResponseResult::BadRequestType);
+		// A bulk read throws the bulk write away
+		h.state.uart_rx.push(0x55);
+		assert_eq!(
+			h.send(&proto::Request::new_bulk_read_start(false, 0x30, 1)),
+			proto::ResponseResult::Ok
+		);
+		let (req, bytes) = piece(0, 1);
+		assert_eq!(
+			h.send_with_payload(&req, bytes),
+			proto::ResponseResult::BadLength
+		);
+		// Nothing to write, or somewhere which can't be written in bulk
+		assert_eq!(
+			h.send(&proto::Request::new_bulk_write_start(false, 0x30, 0)),
+			proto::ResponseResult::BadLength
+		);
+		assert_eq!(
+			h.send(&proto::Request::new_bulk_write_start(false, 0x28, 1)),
+			proto::ResponseResult::BadRegister
+		);
+		assert_eq!(
+			h.send(&proto::Request::new_bulk_write_start(false, 0xE0, 1)),
+			proto::ResponseResult::BadRegister
+		);
+	}
+}
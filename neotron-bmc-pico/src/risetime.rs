@@ -0,0 +1,184 @@
+//! # Rise Times
+//!
+//! A marginal DC-DC module often still works - it just takes too long to
+//! bring its rail up, or only just gets there. That's easy to miss whilst
+//! assembling a kit, and then shows up as a machine which only boots on the
+//! second try. So each time the DC power comes on, we time how long the
+//! 3.3V and 5.0V rails take to become good (as seen on the MON_3V3 and
+//! MON_5V pins), and how long until `nSYS_RESET` is released, and flag any
+//! which took longer than they should.
+//!
+//! The rails are watched with the ADC, so this only happens in builds with
+//! analog inputs (see [`crate::analog`]).
+
+/// The 3.3V rail
+pub const SIGNAL_RAIL_3V3: usize = 0;
+/// The 5.0V rail
+pub const SIGNAL_RAIL_5V: usize = 1;
+/// The main board reset line
+pub const SIGNAL_RESET: usize = 2;
+
+/// How many signals we time
+pub const SIGNALS: usize = 3;
+
+/// A rail is good once its monitor pin reaches this, in millivolts - 90% of
+/// the 1.65V it reads when the rail is at its nominal voltage
+pub const RAIL_GOOD_MV: u16 = 1485;
+
+/// The longest a rail should take to become good, in milliseconds (as in
+/// the ATX power supply design guide)
+pub const RAIL_RISE_MAX_MS: u16 = 20;
+
+/// The longest the reset line should take to be released, in milliseconds
+pub const RESET_RELEASE_MAX_MS: u16 = 500;
+
+/// How long we keep watching, in milliseconds. Anything which isn't good by
+/// then has failed.
+pub const WINDOW_MS: u32 = 1000;
+
+/// How often the caller should sample the signals, in milliseconds
+pub const SAMPLE_INTERVAL_MS: u32 = 1;
+
+/// The time given for a signal we haven't measured, or which never got
+/// there
+pub const NOT_MEASURED: u16 = 0xFFFF;
+
+/// The limit for each signal
+const LIMITS_MS: [u16; SIGNALS] = [RAIL_RISE_MAX_MS, RAIL_RISE_MAX_MS, RESET_RELEASE_MAX_MS];
+
+/// How long each signal took at the last power-on, and which were out of
+/// spec.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RiseTimes {
+	/// How long each signal took, in milliseconds from the DC power coming
+	/// on (see `SIGNAL_RAIL_3V3`, etc)
+	times_ms: [u16; SIGNALS],
+	/// Bit N is set if signal N was too slow, or never got there
+	faults: u8,
+}
+
+impl RiseTimes {
+	/// How many bytes the Rise Times register holds.
+	pub const SIZE: usize = SIGNALS * 2;
+
+	/// Nothing measured yet.
+	pub const fn new() -> RiseTimes {
+		RiseTimes {
+			times_ms: [NOT_MEASURED; SIGNALS],
+			faults: 0,
+		}
+	}
+
+	/// How long `signal` took, in milliseconds, or `NOT_MEASURED`.
+	pub fn time_ms(&self, signal: usize) -> u16 {
+		self.times_ms[signal]
+	}
+
+	/// Which signals were out of spec, as a bitmask.
+	pub fn faults(&self) -> u8 {
+		self.faults
+	}
+
+	/// Convert to bytes for the Rise Times register - each time as a
+	/// `u16le`, in signal order.
+	pub fn as_bytes(&self) -> [u8; Self::SIZE] {
+		let mut bytes = [0u8; Self::SIZE];
+		for (chunk, time_ms) in bytes.chunks_exact_mut(2).zip(self.times_ms.iter()) {
+			chunk.copy_from_slice(&time_ms.to_le_bytes());
+		}
+		bytes
+	}
+}
+
+impl Default for RiseTimes {
+	fn default() -> Self {
+		RiseTimes::new()
+	}
+}
+
+/// Times the signals after one power-on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RiseTimer {
+	/// When the DC power came on
+	dc_on_ms: u32,
+	/// What we've seen so far
+	times: RiseTimes,
+}
+
+impl RiseTimer {
+	/// Start timing, from the DC power coming on at `dc_on_ms`.
+	pub const fn new(dc_on_ms: u32) -> RiseTimer {
+		RiseTimer {
+			dc_on_ms,
+			times: RiseTimes::new(),
+		}
+	}
+
+	/// Feed in a sample - the voltage on each rail monitor pin, and whether
+	/// the reset line is high. Call this every `SAMPLE_INTERVAL_MS`.
+	///
+	/// Returns the times once every signal is good, or the window is over.
+	pub fn sample(
+		&mut self,
+		now_ms: u32,
+		rails_mv: &[u16; 2],
+		reset_released: bool,
+	) -> Option<RiseTimes> {
+		let elapsed_ms = now_ms.wrapping_sub(self.dc_on_ms);
+		let good = [
+			rails_mv[0] >= RAIL_GOOD_MV,
+			rails_mv[1] >= RAIL_GOOD_MV,
+			reset_released,
+		];
+		let mut done = true;
+		for (signal, is_good) in good.iter().enumerate() {
+			let time_ms = &mut self.times.times_ms[signal];
+			if *time_ms != NOT_MEASURED {
+				continue;
+			}
+			if *is_good {
+				*time_ms = elapsed_ms.min(u32::from(NOT_MEASURED - 1)) as u16;
+				if *time_ms > LIMITS_MS[signal] {
+					self.times.faults |= 1 << signal;
+				}
+			} else if elapsed_ms >= WINDOW_MS {
+				self.times.faults |= 1 << signal;
+			} else {
+				done = false;
+			}
+		}
+		done.then(|| self.times.clone())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn all_good() {
+		let mut timer = RiseTimer::new(1000);
+		assert_eq!(timer.sample(1000, &[0, 0], true), None);
+		assert_eq!(timer.sample(1003, &[1500, 900], true), None);
+		let times = timer.sample(1008, &[1400, 1650], true).unwrap();
+		assert_eq!(times.time_ms(SIGNAL_RAIL_3V3), 3);
+		assert_eq!(times.time_ms(SIGNAL_RAIL_5V), 8);
+		assert_eq!(times.time_ms(SIGNAL_RESET), 0);
+		assert_eq!(times.faults(), 0);
+		assert_eq!(times.as_bytes(), [3, 0, 8, 0, 0, 0]);
+	}
+
+	#[test]
+	fn out_of_spec() {
+		let mut timer = RiseTimer::new(u32::MAX - 10);
+		assert_eq!(timer.sample(5, &[1500, 0], false), None);
+		// The 5.0V rail is slow, and the reset line is never released
+		assert_eq!(timer.sample(40, &[1500, 1500], false), None);
+		let times = timer.sample(989, &[1500, 1500], false).unwrap();
+		assert_eq!(times.time_ms(SIGNAL_RAIL_3V3), 16);
+		assert_eq!(times.time_ms(SIGNAL_RAIL_5V), 51);
+		assert_eq!(times.time_ms(SIGNAL_RESET), NOT_MEASURED);
+		assert_eq!(times.faults(), (1 << SIGNAL_RAIL_5V) | (1 << SIGNAL_RESET));
+		assert_eq!(RiseTimes::new().as_bytes(), [0xFF; RiseTimes::SIZE]);
+	}
+}
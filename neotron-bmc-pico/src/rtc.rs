@@ -0,0 +1,715 @@
+//! # Real-Time Clock
+//!
+//! Every STM32F0 has a calendar RTC in its backup domain, so the host can
+//! keep the date and time without an RTC chip of its own. The backup domain
+//! survives the BMC being reset, and in packages with a VBAT pin wired to a
+//! coin cell it survives the BMC losing power too. The 32-pin packages the
+//! Neotron Pico uses have no VBAT pin, so there the clock is only kept whilst
+//! the BMC has power - which it does whilst the main board is off, from the
+//! standby supply.
+//!
+//! The RTC runs from either the LSI (the internal 40 kHz RC oscillator,
+//! which needs no parts but can be out by as much as 25%) or the LSE (a
+//! 32.768 kHz crystal on OSC32_IN and OSC32_OUT, which the 32-pin packages
+//! don't have either). If the crystal doesn't start, we fall back to the LSI
+//! and say so.
+//!
+//! The host sees the date and time in the same order as a DS1307 - seconds,
+//! minutes, hours, weekday, day, month and year - in binary rather than BCD.
+//! It sets the clock a byte at a time, and the clock is only set when it
+//! writes the year, so the host writes that last. The weekday is worked out
+//! from the date. There is one alarm, which can wake the main board.
+
+use stm32f0xx_hal::pac;
+
+/// Run the RTC from the LSI
+pub const CLOCK_LSI: u8 = 0;
+
+/// Run the RTC from the LSE crystal
+pub const CLOCK_LSE: u8 = 1;
+
+/// Bit in the RTC Status register which is set once the clock has been set,
+/// and cleared when the backup domain loses power (or we change the clock
+/// source)
+pub const STATUS_TIME_VALID: u8 = 1 << 0;
+
+/// Bit in the RTC Status register which is set if the RTC is running from the
+/// LSE crystal
+pub const STATUS_LSE: u8 = 1 << 1;
+
+/// Bit in the RTC Status register which is set if the LSE crystal didn't
+/// start, so we fell back to the LSI
+pub const STATUS_LSE_FAILED: u8 = 1 << 2;
+
+/// Bit in the RTC Status register which is set when the alarm goes off. Write
+/// 1 to clear it.
+pub const STATUS_ALARM: u8 = 1 << 3;
+
+/// How long we give the LSE crystal to start, in milliseconds
+pub const LSE_STARTUP_MS: u32 = 2000;
+
+/// Where the year goes in the RTC Date Time register. Writing it sets the
+/// clock.
+const YEAR_OFFSET: usize = 6;
+
+/// Where the enable goes in the RTC Alarm register
+const ENABLE_OFFSET: usize = Alarm::SIZE - 1;
+
+/// A date and time, from 2000 to 2099.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub struct DateTime {
+	/// 0 to 59
+	pub seconds: u8,
+	/// 0 to 59
+	pub minutes: u8,
+	/// 0 to 23
+	pub hours: u8,
+	/// 1 (Monday) to 7 (Sunday)
+	pub weekday: u8,
+	/// 1 to 31
+	pub day: u8,
+	/// 1 to 12
+	pub month: u8,
+	/// Years since 2000
+	pub year: u8,
+}
+
+impl DateTime {
+	/// How many bytes a date and time takes in the RTC Date Time register.
+	pub const SIZE: usize = 7;
+
+	/// Where the RTC starts when the backup domain is reset - midnight at
+	/// the start of Saturday 1st January 2000.
+	pub const START: DateTime = DateTime {
+		seconds: 0,
+		minutes: 0,
+		hours: 0,
+		weekday: 6,
+		day: 1,
+		month: 1,
+		year: 0,
+	};
+
+	/// Convert to bytes for the RTC Date Time register.
+	pub fn as_bytes(&self) -> [u8; Self::SIZE] {
+		[
+			self.seconds,
+			self.minutes,
+			self.hours,
+			self.weekday,
+			self.day,
+			self.month,
+			self.year,
+		]
+	}
+
+	/// Convert from bytes written to the RTC Date Time register, working out
+	/// the weekday (so the one written is ignored).
+	///
+	/// Returns `None` if it isn't a real date and time, or if the year is
+	/// 2000 - a calendar which says 2000 is one which has never been set.
+	pub fn from_bytes(bytes: &[u8; Self::SIZE]) -> Option<DateTime> {
+		let [seconds, minutes, hours, _, day, month, year] = *bytes;
+		if seconds > 59
+			|| minutes > 59
+			|| hours > 23
+			|| !(1..=99).contains(&year)
+			|| !(1..=12).contains(&month)
+			|| day == 0
+			|| day > days_in_month(year, month)
+		{
+			return None;
+		}
+		Some(DateTime {
+			seconds,
+			minutes,
+			hours,
+			weekday: weekday(year, month, day),
+			day,
+			month,
+			year,
+		})
+	}
+
+	/// Convert to the RTC's time register (`RTC_TR`), in BCD.
+	pub fn time_register(&self) -> u32 {
+		(to_bcd(self.hours) << 16) | (to_bcd(self.minutes) << 8) | to_bcd(self.seconds)
+	}
+
+	/// Convert to the RTC's date register (`RTC_DR`), in BCD.
+	pub fn date_register(&self) -> u32 {
+		(to_bcd(self.year) << 16)
+			| (u32::from(self.weekday) << 13)
+			| (to_bcd(self.month) << 8)
+			| to_bcd(self.day)
+	}
+
+	/// Convert from the RTC's time and date registers.
+	pub fn from_registers(time: u32, date: u32) -> DateTime {
+		DateTime {
+			seconds: from_bcd(time & 0x7F),
+			minutes: from_bcd((time >> 8) & 0x7F),
+			hours: from_bcd((time >> 16) & 0x3F),
+			weekday: ((date >> 13) & 0x7) as u8,
+			day: from_bcd(date & 0x3F),
+			month: from_bcd((date >> 8) & 0x1F),
+			year: from_bcd((date >> 16) & 0xFF),
+		}
+	}
+}
+
+/// Is this year since 2000 a leap year? Every fourth year from 2000 to 2099
+/// is one.
+fn is_leap_year(year: u8) -> bool {
+	(year & 0b11) == 0
+}
+
+/// How many days there are in a month (1 to 12) of a year since 2000.
+fn days_in_month(year: u8, month: u8) -> u8 {
+	match month {
+		2 if is_leap_year(year) => 29,
+		2 => 28,
+		4 | 6 | 9 | 11 => 30,
+		_ => 31,
+	}
+}
+
+/// Which day of the week a date falls on, from 1 (Monday) to 7 (Sunday).
+fn weekday(year: u8, month: u8, day: u8) -> u8 {
+	let mut days = u32::from(day) - 1;
+	for earlier in 0..year {
+		days += if is_leap_year(earlier) { 366 } else { 365 };
+	}
+	for earlier in 1..month {
+		days += u32::from(days_in_month(year, earlier));
+	}
+	// 1st January 2000 was a Saturday
+	((days + 5) % 7 + 1) as u8
+}
+
+/// Convert 0 to 99 to two BCD digits.
+fn to_bcd(value: u8) -> u32 {
+	u32::from(((value / 10) << 4) | (value % 10))
+}
+
+/// Convert two BCD digits to 0 to 99.
+fn from_bcd(bcd: u32) -> u8 {
+	((bcd >> 4) * 10 + (bcd & 0xF)) as u8
+}
+
+/// The RTC's two prescalers (asynchronous and then synchronous, less one
+/// each), which divide its clock down to 1 Hz.
+pub fn prescalers(clock: u8) -> (u32, u32) {
+	if clock == CLOCK_LSE {
+		// 32768 Hz / 128 / 256
+		(127, 255)
+	} else {
+		// 40000 Hz / 100 / 400
+		(99, 399)
+	}
+}
+
+/// When the alarm goes off.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Alarm {
+	/// 0 to 59
+	pub seconds: u8,
+	/// 0 to 59
+	pub minutes: u8,
+	/// 0 to 23
+	pub hours: u8,
+	/// The day of the month, or zero for every day
+	pub day: u8,
+	/// Is the alarm on?
+	pub enabled: bool,
+}
+
+impl Alarm {
+	/// How many bytes the alarm takes in the RTC Alarm register.
+	pub const SIZE: usize = 5;
+
+	/// An alarm which is turned off.
+	pub const OFF: Alarm = Alarm {
+		seconds: 0,
+		minutes: 0,
+		hours: 0,
+		day: 0,
+		enabled: false,
+	};
+
+	/// Convert to bytes for the RTC Alarm register.
+	pub fn as_bytes(&self) -> [u8; Self::SIZE] {
+		[
+			self.seconds,
+			self.minutes,
+			self.hours,
+			self.day,
+			u8::from(self.enabled),
+		]
+	}
+
+	/// This alarm, with one byte of the RTC Alarm register changed.
+	///
+	/// Changing anything but the enable turns the alarm off. Returns `None`
+	/// if the enable isn't 0 or 1, or it turns on an alarm which can never go
+	/// off.
+	pub fn with_byte(&self, offset: usize, byte: u8) -> Option<Alarm> {
+		let mut bytes = self.as_bytes();
+		bytes[offset] = byte;
+		if offset != ENABLE_OFFSET {
+			bytes[ENABLE_OFFSET] = 0;
+		}
+		let alarm = Alarm {
+			seconds: bytes[0],
+			minutes: bytes[1],
+			hours: bytes[2],
+			day: bytes[3],
+			enabled: match bytes[ENABLE_OFFSET] {
+				0 => false,
+				1 => true,
+				_ => return None,
+			},
+		};
+		if alarm.enabled
+			&& (alarm.seconds > 59 || alarm.minutes > 59 || alarm.hours > 23 || alarm.day > 31)
+		{
+			return None;
+		}
+		Some(alarm)
+	}
+
+	/// Is it time for the alarm to go off? It never is when it is off.
+	pub fn matches(&self, now: &DateTime) -> bool {
+		self.enabled
+			&& self.seconds == now.seconds
+			&& self.minutes == now.minutes
+			&& self.hours == now.hours
+			&& (self.day == 0 || self.day == now.day)
+	}
+}
+
+/// What the host sees of the RTC.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RtcState {
+	/// The date and time when we last read the RTC
+	now: DateTime,
+	/// `STATUS_TIME_VALID`, `STATUS_LSE` and `STATUS_LSE_FAILED`, as the
+	/// caller last reported them
+	clock_status: u8,
+	/// Has the alarm gone off since the host cleared it?
+	alarm_fired: bool,
+	/// Did the alarm match the time when we last looked?
+	alarm_matched: bool,
+	/// The alarm
+	alarm: Alarm,
+	/// The bytes the host has written to the RTC Date Time register
+	staged: [u8; DateTime::SIZE],
+	/// Set when the host has set the clock. The caller should clear it and
+	/// set the RTC.
+	set_request: Option<DateTime>,
+}
+
+impl RtcState {
+	/// Create the RTC state, before we have looked at the RTC.
+	pub const fn new() -> RtcState {
+		RtcState {
+			now: DateTime::START,
+			clock_status: 0,
+			alarm_fired: false,
+			alarm_matched: false,
+			alarm: Alarm::OFF,
+			staged: [0u8; DateTime::SIZE],
+			set_request: None,
+		}
+	}
+
+	/// The date and time when we last read the RTC, or when the host last
+	/// set it.
+	pub fn now(&self) -> DateTime {
+		self.now
+	}
+
+	/// The RTC Status register (see `STATUS_TIME_VALID`, etc).
+	pub fn status(&self) -> u8 {
+		if self.alarm_fired {
+			self.clock_status | STATUS_ALARM
+		} else {
+			self.clock_status
+		}
+	}
+
+	/// Clear the bits which are set in a write to the RTC Status register.
+	/// Only the alarm bit can be cleared.
+	pub fn clear(&mut self, bits: u8) {
+		if (bits & STATUS_ALARM) != 0 {
+			self.alarm_fired = false;
+		}
+	}
+
+	/// The alarm.
+	pub fn alarm(&self) -> Alarm {
+		self.alarm
+	}
+
+	/// Change the alarm. It goes off the next time the time matches, even
+	/// if it matches now.
+	pub fn set_alarm(&mut self, alarm: Alarm) {
+		self.alarm = alarm;
+		self.alarm_matched = false;
+	}
+
+	/// Note a byte the host wrote to the RTC Date Time register.
+	///
+	/// Writing the year sets the clock to the bytes written so far. Returns
+	/// `false` (and sets nothing) if they aren't a real date and time.
+	pub fn write(&mut self, offset: usize, byte: u8) -> bool {
+		self.staged[offset] = byte;
+		if offset != YEAR_OFFSET {
+			return true;
+		}
+		match DateTime::from_bytes(&self.staged) {
+			Some(now) => {
+				self.now = now;
+				self.clock_status |= STATUS_TIME_VALID;
+				self.alarm_matched = self.alarm.matches(&now);
+				self.set_request = Some(now);
+				true
+			}
+			None => false,
+		}
+	}
+
+	/// The time the host set, if it set one since we last asked.
+	pub fn take_set_request(&mut self) -> Option<DateTime> {
+		self.set_request.take()
+	}
+
+	/// Note what the RTC says now, and how it is running (see
+	/// `STATUS_TIME_VALID`, etc).
+	///
+	/// Returns `true` if the alarm has just gone off. It only goes off once
+	/// for each match, and never whilst the time isn't valid.
+	pub fn update(&mut self, now: DateTime, clock_status: u8) -> bool {
+		if self.set_request.is_some() {
+			// We haven't set the RTC yet, so it would look like going back
+			return false;
+		}
+		self.now = now;
+		self.clock_status = clock_status & (STATUS_TIME_VALID | STATUS_LSE | STATUS_LSE_FAILED);
+		let matched = (self.clock_status & STATUS_TIME_VALID) != 0 && self.alarm.matches(&now);
+		let fired = matched && !self.alarm_matched;
+		self.alarm_matched = matched;
+		if fired {
+			self.alarm_fired = true;
+		}
+		fired
+	}
+}
+
+impl Default for RtcState {
+	fn default() -> Self {
+		RtcState::new()
+	}
+}
+
+/// `RCC_APB1ENR`: the PWR clock enable
+const RCC_APB1ENR_PWREN: u32 = 1 << 28;
+
+/// `RCC_CSR`: turn on the LSI
+const RCC_CSR_LSION: u32 = 1 << 0;
+
+/// `RCC_CSR`: the LSI is running
+const RCC_CSR_LSIRDY: u32 = 1 << 1;
+
+/// `RCC_BDCR`: turn on the LSE
+const RCC_BDCR_LSEON: u32 = 1 << 0;
+
+/// `RCC_BDCR`: the LSE is running
+const RCC_BDCR_LSERDY: u32 = 1 << 1;
+
+/// `RCC_BDCR`: where the RTC clock selection goes
+const RCC_BDCR_RTCSEL_SHIFT: u32 = 8;
+
+/// `RCC_BDCR`: the RTC clock selection
+const RCC_BDCR_RTCSEL_MASK: u32 = 0b11 << RCC_BDCR_RTCSEL_SHIFT;
+
+/// `RCC_BDCR`: the RTC clock selection for the LSE
+const RTCSEL_LSE: u32 = 0b01;
+
+/// `RCC_BDCR`: the RTC clock selection for the LSI
+const RTCSEL_LSI: u32 = 0b10;
+
+/// `RCC_BDCR`: turn on the RTC
+const RCC_BDCR_RTCEN: u32 = 1 << 15;
+
+/// `RCC_BDCR`: reset the backup domain
+const RCC_BDCR_BDRST: u32 = 1 << 16;
+
+/// `PWR_CR`: allow writes to the backup domain
+const PWR_CR_DBP: u32 = 1 << 8;
+
+/// `RTC_ISR`: the calendar has been set (the year isn't zero)
+const RTC_ISR_INITS: u32 = 1 << 4;
+
+/// `RTC_ISR`: we may change the calendar
+const RTC_ISR_INITF: u32 = 1 << 6;
+
+/// `RTC_ISR`: stop the calendar, so we can change it
+const RTC_ISR_INIT: u32 = 1 << 7;
+
+/// `RTC_CR`: read the calendar directly, not through the shadow registers
+const RTC_CR_BYPSHAD: u32 = 1 << 5;
+
+/// Drives the RTC peripheral.
+pub struct Rtc {
+	/// The RTC peripheral
+	rtc: pac::RTC,
+	/// Did we fall back to the LSI because the LSE didn't start?
+	lse_failed: bool,
+}
+
+impl Rtc {
+	/// Take the RTC, and allow writes to the backup domain.
+	pub fn new(rtc: pac::RTC) -> Rtc {
+		// Safety: we only set the PWR clock enable and the backup domain
+		// write enable, which nothing else clears.
+		let (rcc, pwr) = unsafe { (&*pac::RCC::ptr(), &*pac::PWR::ptr()) };
+		rcc.apb1enr
+			.modify(|r, w| unsafe { w.bits(r.bits() | RCC_APB1ENR_PWREN) });
+		pwr.cr
+			.modify(|r, w| unsafe { w.bits(r.bits() | PWR_CR_DBP) });
+		Rtc {
+			rtc,
+			lse_failed: false,
+		}
+	}
+
+	/// Is the RTC already running from `clock` (because the backup domain
+	/// kept going whilst the BMC was reset)?
+	pub fn is_running_from(&self, clock: u8) -> bool {
+		// Safety: reading the backup domain control register has no side
+		// effects
+		let bdcr = unsafe { &*pac::RCC::ptr() }.bdcr.read().bits();
+		let rtcsel = (bdcr & RCC_BDCR_RTCSEL_MASK) >> RCC_BDCR_RTCSEL_SHIFT;
+		(bdcr & RCC_BDCR_RTCEN) != 0 && rtcsel == rtcsel_for(clock)
+	}
+
+	/// Reset the backup domain, which stops the RTC and forgets the time, and
+	/// start `clock`.
+	///
+	/// The LSI is ready straight away. The LSE can take a couple of seconds
+	/// (see `LSE_STARTUP_MS`), so the caller should wait for
+	/// [`Rtc::lse_ready`] before calling [`Rtc::start`].
+	pub fn reset(&mut self, clock: u8) {
+		// Safety: only the RTC driver touches the backup domain and the LSI
+		// enable
+		let rcc = unsafe { &*pac::RCC::ptr() };
+		rcc.bdcr
+			.modify(|r, w| unsafe { w.bits(r.bits() | RCC_BDCR_BDRST) });
+		rcc.bdcr
+			.modify(|r, w| unsafe { w.bits(r.bits() & !RCC_BDCR_BDRST) });
+		if clock == CLOCK_LSE {
+			rcc.bdcr
+				.modify(|r, w| unsafe { w.bits(r.bits() | RCC_BDCR_LSEON) });
+		} else {
+			rcc.csr
+				.modify(|r, w| unsafe { w.bits(r.bits() | RCC_CSR_LSION) });
+			while (rcc.csr.read().bits() & RCC_CSR_LSIRDY) == 0 {}
+		}
+		self.lse_failed = false;
+	}
+
+	/// Has the LSE crystal started?
+	pub fn lse_ready(&self) -> bool {
+		// Safety: reading the backup domain control register has no side
+		// effects
+		(unsafe { &*pac::RCC::ptr() }.bdcr.read().bits() & RCC_BDCR_LSERDY) != 0
+	}
+
+	/// Start the RTC from `clock`, after [`Rtc::reset`]. If the LSE didn't
+	/// start, we use the LSI instead.
+	pub fn start(&mut self, clock: u8) {
+		let clock = if clock == CLOCK_LSE && !self.lse_ready() {
+			self.reset(CLOCK_LSI);
+			self.lse_failed = true;
+			CLOCK_LSI
+		} else {
+			clock
+		};
+		// Safety: only the RTC driver touches the backup domain
+		let rcc = unsafe { &*pac::RCC::ptr() };
+		rcc.bdcr.modify(|r, w| unsafe {
+			w.bits(
+				(r.bits() & !RCC_BDCR_RTCSEL_MASK)
+					| (rtcsel_for(clock) << RCC_BDCR_RTCSEL_SHIFT)
+					| RCC_BDCR_RTCEN,
+			)
+		});
+		let (prediv_a, prediv_s) = prescalers(clock);
+		self.change(|rtc| {
+			// The synchronous prescaler has to be written first
+			rtc.prer.write(|w| unsafe { w.bits(prediv_s) });
+			rtc.prer
+				.write(|w| unsafe { w.bits((prediv_a << 16) | prediv_s) });
+			rtc.cr
+				.modify(|r, w| unsafe { w.bits(r.bits() | RTC_CR_BYPSHAD) });
+		});
+	}
+
+	/// Set the calendar.
+	pub fn set(&mut self, now: &DateTime) {
+		self.change(|rtc| {
+			rtc.tr.write(|w| unsafe { w.bits(now.time_register()) });
+			rtc.dr.write(|w| unsafe { w.bits(now.date_register()) });
+		});
+	}
+
+	/// Read the calendar, and how the RTC is running (see
+	/// `STATUS_TIME_VALID`, etc).
+	pub fn now(&self) -> (DateTime, u8) {
+		// We bypass the shadow registers, so read the time either side of
+		// the date, in case the date changed in between.
+		let (time, date) = loop {
+			let time = self.rtc.tr.read().bits();
+			let date = self.rtc.dr.read().bits();
+			if self.rtc.tr.read().bits() == time {
+				break (time, date);
+			}
+		};
+		let mut status = 0;
+		if (self.rtc.isr.read().bits() & RTC_ISR_INITS) != 0 {
+			status |= STATUS_TIME_VALID;
+		}
+		if self.lse_failed {
+			status |= STATUS_LSE_FAILED;
+		} else if self.is_running_from(CLOCK_LSE) {
+			status |= STATUS_LSE;
+		}
+		(DateTime::from_registers(time, date), status)
+	}
+
+	/// Stop the calendar, make a change, and start it again.
+	fn change(&mut self, f: impl FnOnce(&pac::RTC)) {
+		// Unlock the RTC registers
+		self.rtc.wpr.write(|w| unsafe { w.bits(0xCA) });
+		self.rtc.wpr.write(|w| unsafe { w.bits(0x53) });
+		self.rtc
+			.isr
+			.modify(|r, w| unsafe { w.bits(r.bits() | RTC_ISR_INIT) });
+		while (self.rtc.isr.read().bits() & RTC_ISR_INITF) == 0 {}
+		f(&self.rtc);
+		self.rtc
+			.isr
+			.modify(|r, w| unsafe { w.bits(r.bits() & !RTC_ISR_INIT) });
+		// Lock them again
+		self.rtc.wpr.write(|w| unsafe { w.bits(0xFF) });
+	}
+}
+
+/// The `RCC_BDCR` clock selection for a clock source.
+fn rtcsel_for(clock: u8) -> u32 {
+	if clock == CLOCK_LSE {
+		RTCSEL_LSE
+	} else {
+		RTCSEL_LSI
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn dates() {
+		// Friday 16th October 2026, 13:45:30
+		let bytes = [30, 45, 13, 0, 16, 10, 26];
+		let now = DateTime::from_bytes(&bytes).unwrap();
+		assert_eq!(now.weekday, 5);
+		assert_eq!(now.as_bytes(), [30, 45, 13, 5, 16, 10, 26]);
+		assert_eq!(now.time_register(), 0x0013_4530);
+		assert_eq!(now.date_register(), 0x0026_B016);
+		assert_eq!(
+			DateTime::from_registers(now.time_register(), now.date_register()),
+			now
+		);
+		// Leap years
+		assert!(DateTime::from_bytes(&[0, 0, 0, 0, 29, 2, 24]).is_some());
+		assert!(DateTime::from_bytes(&[0, 0, 0, 0, 29, 2, 25]).is_none());
+		assert_eq!(weekday(24, 2, 29), 4);
+		assert_eq!(weekday(99, 12, 31), 4);
+		assert_eq!(weekday(0, 1, 1), DateTime::START.weekday);
+		// Not real dates, and 2000 (which means never set)
+		assert!(DateTime::from_bytes(&[60, 0, 0, 0, 1, 1, 26]).is_none());
+		assert!(DateTime::from_bytes(&[0, 0, 24, 0, 1, 1, 26]).is_none());
+		assert!(DateTime::from_bytes(&[0, 0, 0, 0, 31, 4, 26]).is_none());
+		assert!(DateTime::from_bytes(&[0, 0, 0, 0, 1, 13, 26]).is_none());
+		assert!(DateTime::from_bytes(&[0, 0, 0, 0, 1, 1, 0]).is_none());
+		assert!(DateTime::from_bytes(&[0, 0, 0, 0, 1, 1, 100]).is_none());
+		assert_eq!(prescalers(CLOCK_LSE), (127, 255));
+		assert_eq!(prescalers(CLOCK_LSI), (99, 399));
+	}
+
+	#[test]
+	fn setting_the_clock() {
+		let mut rtc = RtcState::new();
+		assert_eq!(rtc.status(), 0);
+		for (offset, &byte) in [0, 30, 7, 0, 2, 3].iter().enumerate() {
+			assert!(rtc.write(offset, byte));
+		}
+		assert_eq!(rtc.take_set_request(), None);
+		// The year sets it
+		assert!(rtc.write(6, 27));
+		assert_eq!(rtc.status(), STATUS_TIME_VALID);
+		assert_eq!(rtc.now().as_bytes(), [0, 30, 7, 2, 2, 3, 27]);
+		// Until the caller has set the RTC, it doesn't go back
+		assert!(!rtc.update(DateTime::START, 0));
+		assert_eq!(rtc.now().hours, 7);
+		assert_eq!(rtc.take_set_request(), Some(rtc.now()));
+		assert_eq!(rtc.take_set_request(), None);
+		// A bad date is refused
+		assert!(rtc.write(4, 30));
+		assert!(rtc.write(5, 2));
+		assert!(!rtc.write(6, 27));
+		assert_eq!(rtc.take_set_request(), None);
+	}
+
+	#[test]
+	fn alarm() {
+		let mut rtc = RtcState::new();
+		// 07:30:00 every day
+		let alarm = Alarm::OFF
+			.with_byte(1, 30)
+			.and_then(|a| a.with_byte(2, 7))
+			.and_then(|a| a.with_byte(4, 1))
+			.unwrap();
+		assert_eq!(alarm.as_bytes(), [0, 30, 7, 0, 1]);
+		// Any other byte turns it off, and it can't be impossible
+		assert!(!alarm.with_byte(3, 5).unwrap().enabled);
+		assert_eq!(alarm.with_byte(4, 2), None);
+		assert_eq!(Alarm::OFF.with_byte(2, 24).unwrap().with_byte(4, 1), None);
+		rtc.set_alarm(alarm);
+		assert_eq!(rtc.alarm(), alarm);
+
+		let mut now = DateTime::from_bytes(&[59, 29, 7, 0, 2, 3, 27]).unwrap();
+		assert!(!rtc.update(now, STATUS_TIME_VALID));
+		now.minutes = 30;
+		now.seconds = 0;
+		assert!(rtc.update(now, STATUS_TIME_VALID | STATUS_LSE));
+		assert_eq!(rtc.status(), STATUS_TIME_VALID | STATUS_LSE | STATUS_ALARM);
+		// Only once, however often we look during that second
+		assert!(!rtc.update(now, STATUS_TIME_VALID | STATUS_LSE));
+		rtc.clear(0xFF);
+		assert_eq!(rtc.status(), STATUS_TIME_VALID | STATUS_LSE);
+		// And never whilst the time isn't valid
+		now.seconds = 1;
+		rtc.update(now, 0);
+		now.seconds = 0;
+		assert!(!rtc.update(now, STATUS_LSE_FAILED));
+		assert_eq!(rtc.status(), STATUS_LSE_FAILED);
+		// On one day of the month
+		rtc.set_alarm(Alarm { day: 3, ..alarm });
+		assert!(!rtc.update(now, STATUS_TIME_VALID));
+		now.day = 3;
+		assert!(rtc.update(now, STATUS_TIME_VALID));
+	}
+}
@@ -0,0 +1,194 @@
+//! # Production Self-Test
+//!
+//! Kit assemblers want a quick check that a freshly built board works before
+//! it ships. When the host asks, we exercise each part of the BMC we can
+//! check without extra test equipment, and report a pass/fail bitmap.
+//!
+//! Some checks can't run in every build (the buzzer pin is the current sense
+//! input in `current-sense` builds, for example), so the report also says
+//! which checks actually ran.
+//!
+//! The host can also run just the protocol loopback (see [`crate::loopback`]),
+//! which checks the register dispatch without touching the hardware.
+
+use crate::config::Config;
+
+/// No SPI timeouts or protocol errors have been seen
+pub const SPI: u8 = 1 << 0;
+/// The ADC measures our 3.3V supply within limits
+pub const ADC: u8 = 1 << 1;
+/// The PS/2 clock and data lines have their pull-up resistors
+pub const PS2: u8 = 1 << 2;
+/// The buzzer pin toggles when a note is played
+pub const BUZZER: u8 = 1 << 3;
+/// The power LED pin reads back what we drive on it
+pub const LED: u8 = 1 << 4;
+/// The config page in flash is blank, or holds a block with a good CRC
+pub const FLASH: u8 = 1 << 5;
+/// The register dispatch answers the conformance exchanges correctly
+pub const PROTOCOL: u8 = 1 << 6;
+
+/// Every check
+pub const ALL: u8 = SPI | ADC | PS2 | BUZZER | LED | FLASH | PROTOCOL;
+
+/// The lowest acceptable 3.3V supply, in millivolts
+pub const VDDA_MIN_MV: u16 = 3000;
+
+/// The highest acceptable 3.3V supply, in millivolts
+pub const VDDA_MAX_MV: u16 = 3600;
+
+/// Write this to the BMC Self-Test register to start a self-test
+pub const COMMAND_RUN: u8 = 1;
+
+/// Write this to the BMC Self-Test register to run only the protocol
+/// loopback
+pub const COMMAND_PROTOCOL: u8 = 2;
+
+/// Which checks ran, and which of those failed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct SelfTestReport {
+	/// One bit per check that ran (see `SPI`, etc)
+	tested: u8,
+	/// One bit per check that failed
+	failed: u8,
+}
+
+impl SelfTestReport {
+	/// How many bytes the BMC Self-Test register holds.
+	pub const SIZE: usize = 2;
+
+	/// Create an empty report, where nothing has been tested.
+	pub const fn new() -> SelfTestReport {
+		SelfTestReport {
+			tested: 0,
+			failed: 0,
+		}
+	}
+
+	/// Record the outcome of the check for `subsystem`.
+	pub fn record(&mut self, subsystem: u8, passed: bool) {
+		self.tested |= subsystem;
+		if passed {
+			self.failed &= !subsystem;
+		} else {
+			self.failed |= subsystem;
+		}
+	}
+
+	/// Which checks ran?
+	pub fn tested(&self) -> u8 {
+		self.tested
+	}
+
+	/// Which checks failed? Zero means everything which ran passed.
+	pub fn failed(&self) -> u8 {
+		self.failed
+	}
+
+	/// Convert to bytes for the BMC Self-Test register.
+	///
+	/// That's the checks which ran, and then the checks which failed.
+	pub fn as_bytes(&self) -> [u8; Self::SIZE] {
+		[self.tested, self.failed]
+	}
+}
+
+/// Watches a pin over a number of samples.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct PinSamples {
+	/// We've seen the pin high
+	seen_high: bool,
+	/// We've seen the pin low
+	seen_low: bool,
+}
+
+impl PinSamples {
+	/// Start watching, having seen nothing.
+	pub const fn new() -> PinSamples {
+		PinSamples {
+			seen_high: false,
+			seen_low: false,
+		}
+	}
+
+	/// Record one reading of the pin.
+	pub fn sample(&mut self, is_high: bool) {
+		if is_high {
+			self.seen_high = true;
+		} else {
+			self.seen_low = true;
+		}
+	}
+
+	/// Have we seen the pin high at least once?
+	pub fn seen_high(&self) -> bool {
+		self.seen_high
+	}
+
+	/// Have we seen the pin both high and low?
+	pub fn toggled(&self) -> bool {
+		self.seen_high && self.seen_low
+	}
+}
+
+/// Is this 3.3V supply voltage, in millivolts, within limits?
+pub fn vdda_ok(mv: u16) -> bool {
+	(VDDA_MIN_MV..=VDDA_MAX_MV).contains(&mv)
+}
+
+/// Check the bytes at the start of the config page.
+///
+/// A blank (erased) page is fine - it just means nothing has been saved yet.
+/// Anything else must be a config block we can read.
+pub fn config_page_ok(bytes: &[u8; Config::SIZE]) -> bool {
+	bytes.iter().all(|&b| b == 0xFF) || Config::from_bytes(bytes).is_some()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::analog::vdda_mv;
+
+	#[test]
+	fn report() {
+		let mut report = SelfTestReport::new();
+		assert_eq!(report.as_bytes(), [0, 0]);
+		report.record(SPI, true);
+		report.record(LED, false);
+		report.record(FLASH, true);
+		assert_eq!(report.tested(), SPI | LED | FLASH);
+		assert_eq!(report.failed(), LED);
+		assert_eq!(report.as_bytes(), [SPI | LED | FLASH, LED]);
+	}
+
+	#[test]
+	fn pin_samples() {
+		let mut pin = PinSamples::new();
+		assert!(!pin.seen_high());
+		pin.sample(false);
+		assert!(!pin.seen_high());
+		assert!(!pin.toggled());
+		pin.sample(true);
+		assert!(pin.seen_high());
+		assert!(pin.toggled());
+	}
+
+	#[test]
+	fn supply_voltage() {
+		// A typical calibration value
+		assert_eq!(vdda_mv(1500, 1500), 3300);
+		assert_eq!(vdda_mv(1500, 1650), 3000);
+		assert!(vdda_ok(3300));
+		assert!(!vdda_ok(vdda_mv(1500, 1700)));
+		assert!(!vdda_ok(vdda_mv(1500, 1300)));
+	}
+
+	#[test]
+	fn config_page() {
+		assert!(config_page_ok(&[0xFF; Config::SIZE]));
+		let mut bytes = Config::DEFAULT.as_bytes();
+		assert!(config_page_ok(&bytes));
+		bytes[4] ^= 0x01;
+		assert!(!config_page_ok(&bytes));
+	}
+}
@@ -3,7 +3,8 @@
 //! Unlike the HAL, this implement 'SPI Peripheral Mode', i.e. for when the
 //! clock signal is an input and not an output.
 
-use stm32f0xx_hal::{pac, prelude::*, rcc::Rcc};
+use neotron_bmc_protocol::{PADDING_BYTE, SYNC_BYTE};
+use stm32f0xx_hal::{pac, rcc::Rcc};
 
 pub struct SpiPeripheral<const RXC: usize, const TXC: usize> {
 	dev: pac::SPI1,
@@ -11,14 +12,36 @@ pub struct SpiPeripheral<const RXC: usize, const TXC: usize> {
 	rx_buffer: [u8; RXC],
 	/// How many bytes have been received?
 	rx_idx: usize,
-	/// A space for data we're about to send
-	tx_buffer: [u8; TXC],
-	/// How many bytes have been played from the TX buffer
+	/// Two spaces for data we're about to send - one being clocked out, and
+	/// one for staging the next response in
+	tx_buffers: [[u8; TXC]; 2],
+	/// Which TX buffer is being clocked out
+	tx_front: usize,
+	/// How many bytes have been played from the front TX buffer
 	tx_idx: usize,
-	/// How many bytes are loaded into the TX buffer
+	/// How many bytes are loaded into the front TX buffer
 	tx_ready: usize,
+	/// How many bytes are staged in the back TX buffer, if any
+	tx_staged: Option<usize>,
+	/// What we send when we have nothing else to send
+	padding: u8,
+	/// Do we send `SYNC_BYTE` before each response?
+	sync: bool,
+	/// Is the next byte out `SYNC_BYTE`, before the response just staged?
+	sync_pending: bool,
+	/// Does the host send a payload once the front TX buffer has gone?
+	payload_pending: bool,
+	/// Is the next byte in the one the host clocked out whilst it read the
+	/// last byte of the response before its payload?
+	rx_skip: bool,
 	/// Has the RX been processed?
 	is_done: bool,
+	/// Is the SPI engine turned on (i.e. is CS low)?
+	is_enabled: bool,
+	/// Have we received any bytes since the watchdog last checked?
+	activity: bool,
+	/// How many transactions has the watchdog aborted?
+	timeouts: u32,
 }
 
 impl<const RXC: usize, const TXC: usize> SpiPeripheral<RXC, TXC> {
@@ -33,7 +56,8 @@ impl<const RXC: usize, const TXC: usize> SpiPeripheral<RXC, TXC> {
 		MISOPIN: stm32f0xx_hal::spi::MisoPin<pac::SPI1>,
 		MOSIPIN: stm32f0xx_hal::spi::MosiPin<pac::SPI1>,
 	{
-		defmt::info!(
+		crate::info!(
+			target: crate::logging::Subsystem::Spi,
 			"pclk = {}, incoming spi_clock = {}",
 			rcc.clocks.pclk().0,
 			speed_hz
@@ -41,11 +65,16 @@ impl<const RXC: usize, const TXC: usize> SpiPeripheral<RXC, TXC> {
 
 		let mode = embedded_hal::spi::MODE_0;
 
-		// Set SPI up in Controller mode. This will cause the HAL to enable the clocks and power to the IP block.
-		// It also checks the pins are OK.
-		let spi_controller = stm32f0xx_hal::spi::Spi::spi1(dev, pins, mode, 8_000_000u32.hz(), rcc);
-		// Now disassemble the driver so we can set it into Controller mode instead
-		let (dev, _pins) = spi_controller.release();
+		// The pin types check the pins are OK. We don't use the HAL's driver
+		// to turn the IP block on, as it would briefly make us the Controller,
+		// driving SCK and COPI whilst the host might be clocking.
+		let _pins = pins;
+		// Safety: we only touch the SPI1 enable and reset bits, which nothing
+		// else modifies.
+		let rcc_regs = unsafe { &*pac::RCC::ptr() };
+		rcc_regs.apb2enr.modify(|_r, w| w.spi1en().enabled());
+		rcc_regs.apb2rstr.modify(|_r, w| w.spi1rst().set_bit());
+		rcc_regs.apb2rstr.modify(|_r, w| w.spi1rst().clear_bit());
 
 		// We are following DM00043574, Section 30.5.1 Configuration of SPI
 
@@ -112,10 +141,20 @@ impl<const RXC: usize, const TXC: usize> SpiPeripheral<RXC, TXC> {
 			dev,
 			rx_buffer: [0u8; RXC],
 			rx_idx: 0,
-			tx_buffer: [0u8; TXC],
+			tx_buffers: [[0u8; TXC]; 2],
+			tx_front: 0,
 			tx_idx: 0,
 			tx_ready: 0,
+			tx_staged: None,
+			padding: PADDING_BYTE,
+			sync: false,
+			sync_pending: false,
+			payload_pending: false,
+			rx_skip: false,
 			is_done: false,
+			is_enabled: false,
+			activity: false,
+			timeouts: 0,
 		};
 
 		// Empty the receive register
@@ -126,19 +165,36 @@ impl<const RXC: usize, const TXC: usize> SpiPeripheral<RXC, TXC> {
 		spi
 	}
 
+	/// Set what we send when we have nothing else to send, and whether we
+	/// send `SYNC_BYTE` before each response.
+	///
+	/// Call this before [`SpiPeripheral::enable`], so a transaction doesn't
+	/// change part-way through.
+	pub fn set_framing(&mut self, padding: u8, sync: bool) {
+		self.padding = padding;
+		self.sync = sync;
+	}
+
 	/// Enable the SPI peripheral (i.e. when CS is low)
 	pub fn enable(&mut self) {
 		self.rx_idx = 0;
 		self.tx_idx = 0;
 		self.tx_ready = 0;
+		self.tx_staged = None;
+		self.sync_pending = false;
+		self.payload_pending = false;
+		self.rx_skip = false;
 		self.is_done = false;
+		self.is_enabled = true;
+		// Give the host a full watchdog period to start clocking
+		self.activity = true;
 		self.dev.cr1.modify(|_r, w| {
 			w.spe().enabled();
 			w
 		});
 		// Load our dummy byte (our TX FIFO will send this then repeat it whilst
 		// it underflows during the receive phase).
-		self.raw_write(0xFF);
+		self.raw_write(self.padding);
 		// Get an IRQ when there's RX data available
 		self.enable_rxne_irq();
 	}
@@ -150,6 +206,63 @@ impl<const RXC: usize, const TXC: usize> SpiPeripheral<RXC, TXC> {
 			w.spe().disabled();
 			w
 		});
+		self.is_enabled = false;
+	}
+
+	/// Finish the frame, when chip select rises (or has risen and fallen
+	/// again since we last looked).
+	///
+	/// Any bytes still in the receive FIFO are taken out first, so they can't
+	/// turn up at the start of the next frame. If we hadn't answered the
+	/// frame yet, the host gave up on it part-way through, and you get the
+	/// bytes it did send. Either way, nothing more will be processed until
+	/// the next chip select.
+	pub fn end_frame(&mut self) -> Option<&[u8]> {
+		while self.has_rx_data() {
+			self.read_isr();
+		}
+		self.disable();
+		if core::mem::replace(&mut self.is_done, true) || self.rx_idx == 0 {
+			None
+		} else {
+			Some(&self.rx_buffer[0..self.rx_idx])
+		}
+	}
+
+	/// Check for a stuck transaction. Call this periodically.
+	///
+	/// If the SPI engine was enabled at the last call, and no bytes have
+	/// arrived since then, we assume the host has gone away with CS held low. We
+	/// abort the transaction, throw away anything half-received, and wait for
+	/// the next chip select.
+	///
+	/// Returns `true` if the transaction was aborted.
+	pub fn watchdog(&mut self) -> bool {
+		let activity = core::mem::replace(&mut self.activity, false);
+		if !self.is_enabled || activity {
+			return false;
+		}
+		self.disable();
+		// Empty the receive register
+		while self.has_rx_data() {
+			let _ = self.raw_read();
+		}
+		self.rx_idx = 0;
+		self.tx_idx = 0;
+		self.tx_ready = 0;
+		self.tx_staged = None;
+		self.sync_pending = false;
+		self.payload_pending = false;
+		self.rx_skip = false;
+		// Don't let anyone process a half-received request
+		self.is_done = true;
+		self.timeouts = self.timeouts.wrapping_add(1);
+		true
+	}
+
+	/// How many transactions has the watchdog aborted?
+	pub fn timeout_count(&self) -> u32 {
+		self.timeouts
 	}
 
 	/// Enable RX Not Empty interrupt
@@ -200,13 +313,25 @@ impl<const RXC: usize, const TXC: usize> SpiPeripheral<RXC, TXC> {
 		self.is_done = true;
 	}
 
+	/// Collect a payload after the response just staged.
+	///
+	/// The host sends it in the same transaction, straight after the last
+	/// byte of the response, so that is where we start receiving again - the
+	/// byte the host clocked out whilst it read that last byte is dropped.
+	/// [`SpiPeripheral::get_received`] gives the payload so far.
+	pub fn expect_payload(&mut self) {
+		self.payload_pending = true;
+	}
+
 	pub fn handle_isr(&mut self) {
 		let irq_status = self.dev.sr.read();
 		if irq_status.rxne().is_not_empty() {
 			self.read_isr();
-		}
-		if irq_status.txe().is_empty() {
-			self.tx_isr();
+			// We send one byte for each one we receive, so a call with
+			// nothing received (e.g. on a chip select change) sends nothing
+			if irq_status.txe().is_empty() {
+				self.tx_isr();
+			}
 		}
 	}
 
@@ -215,6 +340,10 @@ impl<const RXC: usize, const TXC: usize> SpiPeripheral<RXC, TXC> {
 	/// If we read some data, we also load any waiting 'reply byte'.
 	fn read_isr(&mut self) {
 		let cmd = self.raw_read();
+		self.activity = true;
+		if core::mem::replace(&mut self.rx_skip, false) {
+			return;
+		}
 		if self.rx_idx < self.rx_buffer.len() {
 			self.rx_buffer[self.rx_idx] = cmd;
 			self.rx_idx += 1;
@@ -222,49 +351,88 @@ impl<const RXC: usize, const TXC: usize> SpiPeripheral<RXC, TXC> {
 	}
 
 	/// Call this in the TXEIE interrupt. It will load the SPI FIFO with some
-	/// data, either from `tx_buffer` or a padding byte.
+	/// data, either from the front TX buffer, the sync marker, or a padding
+	/// byte.
+	///
+	/// Once the front buffer has all gone, any staged response takes its
+	/// place.
 	fn tx_isr(&mut self) {
-		if (self.tx_idx < self.tx_ready) && (self.tx_idx < self.tx_buffer.len()) {
+		if self.tx_idx >= self.tx_ready {
+			self.swap_tx_buffers();
+		}
+		if core::mem::replace(&mut self.sync_pending, false) {
+			self.raw_write(SYNC_BYTE);
+		} else if self.tx_idx < self.tx_ready {
 			// We have some data yet to send
-			let next_tx = self.tx_buffer[self.tx_idx];
+			let next_tx = self.tx_buffers[self.tx_front][self.tx_idx];
 			self.raw_write(next_tx);
 			self.tx_idx += 1;
+			if self.tx_idx == self.tx_ready && self.tx_staged.is_none() {
+				self.start_payload();
+			}
 		} else {
 			// No data - send padding
-			self.raw_write(0xFF);
+			self.raw_write(self.padding);
+		}
+	}
+
+	/// If a payload follows the response, receive it from the next byte in
+	/// but one.
+	fn start_payload(&mut self) {
+		if core::mem::replace(&mut self.payload_pending, false) {
+			self.rx_idx = 0;
+			self.rx_skip = true;
+			self.is_done = false;
 		}
 	}
 
-	/// Load some data into the TX buffer.
+	/// Make the staged response (if there is one) the one we clock out.
+	fn swap_tx_buffers(&mut self) {
+		if let Some(length) = self.tx_staged.take() {
+			self.tx_front ^= 1;
+			self.tx_ready = length;
+			self.tx_idx = 0;
+			self.sync_pending = self.sync;
+		}
+	}
+
+	/// Stage a response of `length` bytes, which has been put in the back TX
+	/// buffer.
+	///
+	/// It goes out straight away if nothing else is, otherwise as soon as
+	/// the response being clocked out has finished - so a response is never
+	/// changed whilst the host is part-way through reading it.
+	fn stage(&mut self, length: usize) {
+		self.tx_staged = Some(length);
+		if self.tx_idx >= self.tx_ready {
+			self.swap_tx_buffers();
+		}
+	}
+
+	/// Load some data into the back TX buffer, to be sent next.
 	///
 	/// You get an error if you try to load too much.
 	pub fn set_transmit(&mut self, data: &[u8]) -> Result<(), usize> {
-		self.tx_ready = 0;
-		self.tx_idx = 0;
-		if data.len() > TXC {
+		let back = &mut self.tx_buffers[self.tx_front ^ 1];
+		if data.len() > back.len() {
 			// Too much data
 			return Err(TXC);
 		}
-		for (inc, space) in data.iter().zip(self.tx_buffer.iter_mut()) {
-			*space = *inc;
-		}
-		self.tx_ready = data.len();
+		back[0..data.len()].copy_from_slice(data);
+		self.stage(data.len());
 		Ok(())
 	}
 
-	/// Render some message into the TX buffer.
+	/// Render some message into the back TX buffer, to be sent next.
 	///
 	/// You get an error if you try to load too much.
 	pub fn set_transmit_sendable(
 		&mut self,
 		message: &dyn neotron_bmc_protocol::Sendable,
 	) -> Result<(), ()> {
-		self.tx_ready = 0;
-		self.tx_idx = 0;
-
-		match message.render_to_buffer(&mut self.tx_buffer) {
+		match message.render_to_buffer(&mut self.tx_buffers[self.tx_front ^ 1]) {
 			Ok(n) => {
-				self.tx_ready = n;
+				self.stage(n);
 				Ok(())
 			}
 			Err(_) => Err(()),
@@ -0,0 +1,233 @@
+//! # SPI Trace
+//!
+//! When the host says the NBMC "returned garbage once an hour", it helps to
+//! know what the NBMC thought it received, and what it sent back. With the
+//! `spi-trace` feature, we keep the last few SPI transactions - the start of
+//! the request, the start of the response, and when it happened - in a ring
+//! which the host can dump through the SPI Trace register.
+//!
+//! Frames the host gave up on before we answered are recorded too, with no
+//! response. Reading the trace registers, and collecting a bulk read, aren't
+//! recorded, so dumping the trace doesn't wash out what's in it.
+
+use neotron_bmc_protocol as proto;
+
+/// The SPI Trace Count register
+const REGISTER_COUNT: u8 = 0x1E;
+
+/// The SPI Trace register
+const REGISTER_TRACE: u8 = 0x1F;
+
+/// One SPI transaction in the [`SpiTrace`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub struct TraceEntry {
+	/// Milliseconds since the BMC booted, when the request was handled
+	pub timestamp_ms: u32,
+	/// The first four bytes received (the whole of any normal request),
+	/// padded with zeros
+	pub request: [u8; 4],
+	/// How many bytes were received (up to 255)
+	pub received: u8,
+	/// The first two bytes of the response (the result, and the first data
+	/// byte or the CRC), or zeros if we didn't send one
+	pub response: [u8; 2],
+	/// How many bytes the response was, or zero if we didn't send one
+	pub response_len: u8,
+}
+
+impl TraceEntry {
+	/// How many bytes an entry occupies when sent to the host.
+	pub const SIZE: usize = 12;
+
+	/// An empty entry, used to pad out reads.
+	pub const EMPTY: TraceEntry = TraceEntry {
+		timestamp_ms: 0,
+		request: [0; 4],
+		received: 0,
+		response: [0; 2],
+		response_len: 0,
+	};
+
+	/// Record a frame we received, with no response (yet).
+	pub fn new(timestamp_ms: u32, received: &[u8]) -> TraceEntry {
+		let mut request = [0u8; 4];
+		for (dest, src) in request.iter_mut().zip(received.iter()) {
+			*dest = *src;
+		}
+		TraceEntry {
+			timestamp_ms,
+			request,
+			received: received.len().min(usize::from(u8::MAX)) as u8,
+			response: [0; 2],
+			response_len: 0,
+		}
+	}
+
+	/// Record the response we sent.
+	pub fn set_response(&mut self, response: &dyn proto::Sendable) {
+		let mut idx = 0;
+		let start = &mut self.response;
+		response.render_segments(&mut |segment| {
+			for &byte in segment {
+				if let Some(dest) = start.get_mut(idx) {
+					*dest = byte;
+				}
+				idx += 1;
+			}
+		});
+		self.response_len = response.rendered_len().min(usize::from(u8::MAX)) as u8;
+	}
+
+	/// Convert to bytes for transmission.
+	///
+	/// The timestamp is sent first as a `u32le`, then the request bytes, the
+	/// received length, the response bytes and the response length.
+	pub fn as_bytes(&self) -> [u8; Self::SIZE] {
+		let ts = self.timestamp_ms.to_le_bytes();
+		[
+			ts[0],
+			ts[1],
+			ts[2],
+			ts[3],
+			self.request[0],
+			self.request[1],
+			self.request[2],
+			self.request[3],
+			self.received,
+			self.response[0],
+			self.response[1],
+			self.response_len,
+		]
+	}
+}
+
+/// Should this request go in the trace?
+///
+/// Not if it is only reading the trace back, or collecting a bulk read
+/// (the *Bulk Read Start* is recorded).
+pub fn is_traced(req: &proto::Request) -> bool {
+	match req.request_type {
+		proto::RequestType::BulkReadContinue | proto::RequestType::BulkReadContinueAlt => false,
+		proto::RequestType::Read
+		| proto::RequestType::ReadAlt
+		| proto::RequestType::BulkReadStart
+		| proto::RequestType::BulkReadStartAlt
+		| proto::RequestType::WideRead
+		| proto::RequestType::WideReadAlt => {
+			req.register != REGISTER_COUNT && req.register != REGISTER_TRACE
+		}
+		_ => true,
+	}
+}
+
+/// The last `N` SPI transactions.
+///
+/// When the trace is full, the oldest entry is discarded.
+#[derive(Debug)]
+pub struct SpiTrace<const N: usize> {
+	entries: [TraceEntry; N],
+	/// Index of the oldest entry
+	head: usize,
+	/// How many entries are valid
+	len: usize,
+}
+
+impl<const N: usize> SpiTrace<N> {
+	/// Create a new, empty, trace.
+	pub const fn new() -> SpiTrace<N> {
+		SpiTrace {
+			entries: [TraceEntry::EMPTY; N],
+			head: 0,
+			len: 0,
+		}
+	}
+
+	/// Add an entry, discarding the oldest if the trace is full.
+	pub fn push(&mut self, entry: TraceEntry) {
+		let idx = (self.head + self.len) % N;
+		self.entries[idx] = entry;
+		if self.len == N {
+			self.head = (self.head + 1) % N;
+		} else {
+			self.len += 1;
+		}
+	}
+
+	/// Remove the oldest entry.
+	pub fn pop(&mut self) -> Option<TraceEntry> {
+		if self.len == 0 {
+			return None;
+		}
+		let entry = self.entries[self.head];
+		self.head = (self.head + 1) % N;
+		self.len -= 1;
+		Some(entry)
+	}
+
+	/// How many entries are in the trace?
+	pub fn len(&self) -> usize {
+		self.len
+	}
+
+	/// Is the trace empty?
+	pub fn is_empty(&self) -> bool {
+		self.len == 0
+	}
+
+	/// Throw away all the entries.
+	pub fn clear(&mut self) {
+		self.head = 0;
+		self.len = 0;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn entries() {
+		let req = proto::Request::new_read(false, 0x10, 1);
+		let mut entry = TraceEntry::new(0x0102_0304, &req.as_bytes());
+		entry.set_response(&proto::Response::new_ok_with_data(&[0x55]));
+		let crc = req.as_bytes()[3];
+		assert_eq!(
+			entry.as_bytes(),
+			[0x04, 0x03, 0x02, 0x01, 0xC0, 0x10, 0x01, crc, 4, 0xA0, 0x55, 3]
+		);
+		// A frame cut short, which we never answered
+		let entry = TraceEntry::new(0, &[0xC2, 0x11]);
+		assert_eq!(entry.as_bytes(), [0, 0, 0, 0, 0xC2, 0x11, 0, 0, 2, 0, 0, 0]);
+	}
+
+	#[test]
+	fn ring() {
+		let mut trace = SpiTrace::<2>::new();
+		for timestamp_ms in 1..=3 {
+			trace.push(TraceEntry::new(timestamp_ms, &[]));
+		}
+		assert_eq!(trace.len(), 2);
+		assert_eq!(trace.pop().map(|e| e.timestamp_ms), Some(2));
+		assert_eq!(trace.pop().map(|e| e.timestamp_ms), Some(3));
+		assert!(trace.pop().is_none());
+	}
+
+	#[test]
+	fn reading_the_trace_is_not_traced() {
+		assert!(is_traced(&proto::Request::new_read(false, 0x10, 1)));
+		assert!(is_traced(&proto::Request::new_short_write(false, 0x1E, 0)));
+		assert!(!is_traced(&proto::Request::new_read(true, 0x1F, 60)));
+		assert!(!is_traced(&proto::Request::new_bulk_read_start(
+			false, 0x1F, 240
+		)));
+		assert!(!is_traced(&proto::Request::new_wide_read(
+			false,
+			REGISTER_COUNT,
+			proto::Width::U8,
+			1
+		)));
+		assert!(!is_traced(&proto::Request::new_bulk_read_continue(
+			false, 0, 60
+		)));
+	}
+}
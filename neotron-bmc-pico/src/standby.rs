@@ -0,0 +1,111 @@
+//! # Low-power Standby Mode
+//!
+//! Whilst the main board is powered off, the BMC only has to watch the
+//! buttons and blink the power LED, so there's no point running the core from
+//! the 48 MHz PLL. In standby we switch SYSCLK over to the 8 MHz HSI
+//! oscillator and stop the PLL, and the idle task sleeps with `WFI` between
+//! interrupts.
+//!
+//! USART1 is clocked directly from the HSI so that its baud rate is the same
+//! in both modes. SysTick runs from HCLK, so we re-scale its reload value
+//! whenever we change clock speed, to keep the monotonic timer ticking at the
+//! same rate.
+
+use stm32f0xx_hal::pac;
+
+/// The system clock we use when the main board is powered on.
+pub const RUN_CLOCK_HZ: u32 = 48_000_000;
+
+/// The system clock we use when the main board is powered off.
+pub const STANDBY_CLOCK_HZ: u32 = 8_000_000;
+
+/// Tracks whether we are in low-power standby mode.
+pub struct Standby {
+	/// The rate at which the SysTick monotonic timer ticks
+	tick_hz: u32,
+	/// Are we currently running from the HSI?
+	active: bool,
+}
+
+impl Standby {
+	/// Create a new standby controller.
+	///
+	/// Call this after the clocks have been set to `RUN_CLOCK_HZ` and after
+	/// USART1 has been configured for `uart_baud`. It moves USART1 over to
+	/// the HSI clock so the UART keeps working in standby.
+	pub fn new(tick_hz: u32, uart_baud: u32) -> Standby {
+		// Safety: we only touch the USART1 clock select and baud rate
+		// registers, which nothing else modifies after init.
+		let rcc = unsafe { &*pac::RCC::ptr() };
+		let usart1 = unsafe { &*pac::USART1::ptr() };
+		usart1.cr1.modify(|_r, w| w.ue().clear_bit());
+		rcc.cfgr3.modify(|_r, w| w.usart1sw().hsi());
+		usart1
+			.brr
+			.write(|w| unsafe { w.bits(STANDBY_CLOCK_HZ / uart_baud) });
+		usart1.cr1.modify(|_r, w| w.ue().set_bit());
+		Standby {
+			tick_hz,
+			active: false,
+		}
+	}
+
+	/// Are we in standby mode?
+	pub fn is_active(&self) -> bool {
+		self.active
+	}
+
+	/// The current system (and APB timer) clock speed.
+	pub fn clock_hz(&self) -> u32 {
+		if self.active {
+			STANDBY_CLOCK_HZ
+		} else {
+			RUN_CLOCK_HZ
+		}
+	}
+
+	/// Drop down to the HSI and turn off the PLL.
+	pub fn enter(&mut self) {
+		if self.active {
+			return;
+		}
+		// Safety: only the standby controller changes the clock tree after init.
+		let rcc = unsafe { &*pac::RCC::ptr() };
+		// The HSI is always running (it feeds the PLL), so we can switch
+		// straight over to it.
+		rcc.cfgr.modify(|_r, w| w.sw().hsi());
+		while !rcc.cfgr.read().sws().is_hsi() {}
+		rcc.cr.modify(|_r, w| w.pllon().clear_bit());
+		self.set_systick_clock(STANDBY_CLOCK_HZ);
+		self.active = true;
+	}
+
+	/// Restart the PLL and run from it again.
+	///
+	/// The PLL multiplier and source are retained in `RCC_CFGR` from when
+	/// the HAL first configured them, so we just need to turn it back on.
+	pub fn exit(&mut self) {
+		if !self.active {
+			return;
+		}
+		// Safety: only the standby controller changes the clock tree after init.
+		let rcc = unsafe { &*pac::RCC::ptr() };
+		rcc.cr.modify(|_r, w| w.pllon().set_bit());
+		while rcc.cr.read().pllrdy().bit_is_clear() {}
+		rcc.cfgr.modify(|_r, w| w.sw().pll());
+		while !rcc.cfgr.read().sws().is_pll() {}
+		self.set_systick_clock(RUN_CLOCK_HZ);
+		self.active = false;
+	}
+
+	/// Adjust the SysTick reload value for a new HCLK.
+	fn set_systick_clock(&self, clock_hz: u32) {
+		// Safety: the monotonic only reads the reload value when it is
+		// created, so it is safe to change it underneath it.
+		let syst = unsafe { &*cortex_m::peripheral::SYST::PTR };
+		unsafe {
+			syst.rvr.write((clock_hz / self.tick_hz) - 1);
+			syst.cvr.write(0);
+		}
+	}
+}
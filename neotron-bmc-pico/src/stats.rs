@@ -0,0 +1,272 @@
+//! # Lifetime Counters
+//!
+//! Counts of BMC boots, main board power-ons, watchdog resets and fault
+//! shutdowns, which survive the BMC losing power, for tracking how reliable
+//! a board is over its life.
+//!
+//! The counters are kept in their own 1 KiB page of flash (see
+//! [`crate::config`]). The page starts with a header holding a magic
+//! number, a layout version, the totals when the page was last erased, and a
+//! CRC-8. After that comes a log of half-word entries, one per event, which
+//! we append to without erasing the page. When the log is full, we erase the
+//! page and write a new header with the current totals. A page with a bad
+//! header (including a blank one, or one from a firmware with a different
+//! layout) counts as all zeros.
+
+use crate::eventlog::EventKind;
+
+/// How many counters there are.
+pub const COUNTERS: usize = 4;
+
+/// Marks the start of a valid counter page ("NS").
+const MAGIC: [u8; 2] = [0x4E, 0x53];
+
+/// Which version of the counter page layout this firmware writes.
+const LAYOUT_VERSION: u8 = 1;
+
+/// Log entries are this, plus the counter number.
+const ENTRY_BASE: u16 = 0x5300;
+
+/// An unused log entry (erased flash).
+const ENTRY_BLANK: u16 = 0xFFFF;
+
+/// Something we count.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum Counter {
+	/// The BMC started up
+	BmcBoot = 0,
+	/// The main board was powered on
+	PowerOn = 1,
+	/// The BMC was reset by a watchdog
+	WatchdogReset = 2,
+	/// We cut the main board's power because of a fault (over-current or
+	/// over-temperature)
+	FaultShutdown = 3,
+}
+
+impl Counter {
+	/// Which counter an event log entry counts towards, if any.
+	pub fn for_event(kind: EventKind) -> Option<Counter> {
+		match kind {
+			EventKind::PowerOn => Some(Counter::PowerOn),
+			EventKind::OverCurrent | EventKind::OverTemperature => Some(Counter::FaultShutdown),
+			_ => None,
+		}
+	}
+
+	/// The log entry recording one of these.
+	pub fn entry(self) -> u16 {
+		ENTRY_BASE + self as u16
+	}
+}
+
+/// The counter totals.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stats {
+	counts: [u32; COUNTERS],
+}
+
+impl Stats {
+	/// How many bytes the Lifetime Counters register holds.
+	pub const SIZE: usize = COUNTERS * 4;
+
+	/// How many bytes the header at the start of the flash page holds.
+	pub const HEADER_SIZE: usize = 4 + Self::SIZE;
+
+	/// All counters at zero.
+	pub const fn new() -> Stats {
+		Stats {
+			counts: [0; COUNTERS],
+		}
+	}
+
+	/// Get one counter.
+	pub fn count(&self, counter: Counter) -> u32 {
+		self.counts[counter as usize]
+	}
+
+	/// Add one to a counter.
+	pub fn record(&mut self, counter: Counter) {
+		let count = &mut self.counts[counter as usize];
+		*count = count.saturating_add(1);
+	}
+
+	/// Convert to bytes for the Lifetime Counters register - each counter as
+	/// a `u32le`, in [`Counter`] order.
+	pub fn as_bytes(&self) -> [u8; Self::SIZE] {
+		let mut bytes = [0u8; Self::SIZE];
+		for (chunk, count) in bytes.chunks_exact_mut(4).zip(self.counts.iter()) {
+			chunk.copy_from_slice(&count.to_le_bytes());
+		}
+		bytes
+	}
+
+	/// Convert to a header for the flash page.
+	///
+	/// The layout is the magic number, the layout version, the counters (as
+	/// for [`Stats::as_bytes`]), and a CRC-8 of all the preceeding bytes.
+	pub fn header_bytes(&self) -> [u8; Self::HEADER_SIZE] {
+		let mut bytes = [0u8; Self::HEADER_SIZE];
+		bytes[0..2].copy_from_slice(&MAGIC);
+		bytes[2] = LAYOUT_VERSION;
+		bytes[3..3 + Self::SIZE].copy_from_slice(&self.as_bytes());
+		bytes[Self::HEADER_SIZE - 1] =
+			neotron_bmc_protocol::calculate_crc(&bytes[0..Self::HEADER_SIZE - 1]);
+		bytes
+	}
+
+	/// Read the counters from a flash page header.
+	///
+	/// Returns `None` if the magic number, layout version or CRC are wrong.
+	pub fn from_header(bytes: &[u8; Self::HEADER_SIZE]) -> Option<Stats> {
+		if bytes[0..2] != MAGIC || bytes[2] != LAYOUT_VERSION {
+			return None;
+		}
+		if neotron_bmc_protocol::calculate_crc(bytes) != 0 {
+			return None;
+		}
+		let mut stats = Stats::new();
+		for (count, chunk) in stats
+			.counts
+			.iter_mut()
+			.zip(bytes[3..3 + Self::SIZE].chunks_exact(4))
+		{
+			*count = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+		}
+		Some(stats)
+	}
+}
+
+impl Default for Stats {
+	fn default() -> Self {
+		Stats::new()
+	}
+}
+
+/// What we found in the counter page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatsScan {
+	/// The totals, including every log entry
+	pub stats: Stats,
+	/// The index of the first unused log entry, or `None` if the log is full
+	/// or the header is bad (so the page must be erased before we can add to
+	/// it)
+	pub next_free: Option<usize>,
+}
+
+/// Work out the totals from the counter page's header and log entries.
+///
+/// Entries we don't recognise (perhaps from a newer firmware) are skipped.
+pub fn scan<I>(header: &[u8; Stats::HEADER_SIZE], entries: I) -> StatsScan
+where
+	I: IntoIterator<Item = u16>,
+{
+	let mut stats = match Stats::from_header(header) {
+		Some(stats) => stats,
+		None => {
+			return StatsScan {
+				stats: Stats::new(),
+				next_free: None,
+			}
+		}
+	};
+	for (idx, entry) in entries.into_iter().enumerate() {
+		if entry == ENTRY_BLANK {
+			return StatsScan {
+				stats,
+				next_free: Some(idx),
+			};
+		}
+		if let Some(counter) = entry.checked_sub(ENTRY_BASE).and_then(counter_for) {
+			stats.record(counter);
+		}
+	}
+	StatsScan {
+		stats,
+		next_free: None,
+	}
+}
+
+/// Which counter a log entry (less `ENTRY_BASE`) is for.
+fn counter_for(number: u16) -> Option<Counter> {
+	match number {
+		0 => Some(Counter::BmcBoot),
+		1 => Some(Counter::PowerOn),
+		2 => Some(Counter::WatchdogReset),
+		3 => Some(Counter::FaultShutdown),
+		_ => None,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn header_round_trip() {
+		let mut stats = Stats::new();
+		stats.record(Counter::BmcBoot);
+		stats.record(Counter::BmcBoot);
+		stats.record(Counter::FaultShutdown);
+		let header = stats.header_bytes();
+		assert_eq!(Stats::from_header(&header), Some(stats.clone()));
+		assert_eq!(
+			stats.as_bytes(),
+			[2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0]
+		);
+		// A blank page, a corrupt header, or a different layout don't count
+		assert_eq!(Stats::from_header(&[0xFF; Stats::HEADER_SIZE]), None);
+		let mut corrupt = header;
+		corrupt[5] ^= 1;
+		assert_eq!(Stats::from_header(&corrupt), None);
+		let mut newer = header;
+		newer[2] = LAYOUT_VERSION + 1;
+		assert_eq!(Stats::from_header(&newer), None);
+	}
+
+	#[test]
+	fn scan_log() {
+		let mut base = Stats::new();
+		base.record(Counter::PowerOn);
+		let header = base.header_bytes();
+		let entries = [
+			Counter::BmcBoot.entry(),
+			Counter::PowerOn.entry(),
+			0x1234,
+			Counter::WatchdogReset.entry(),
+			ENTRY_BLANK,
+			ENTRY_BLANK,
+		];
+		let found = scan(&header, entries.iter().copied());
+		assert_eq!(found.next_free, Some(4));
+		assert_eq!(found.stats.count(Counter::BmcBoot), 1);
+		assert_eq!(found.stats.count(Counter::PowerOn), 2);
+		assert_eq!(found.stats.count(Counter::WatchdogReset), 1);
+		assert_eq!(found.stats.count(Counter::FaultShutdown), 0);
+		// A full log must be erased before we add to it
+		let full = scan(&header, [Counter::BmcBoot.entry(); 3].iter().copied());
+		assert_eq!(full.next_free, None);
+		assert_eq!(full.stats.count(Counter::BmcBoot), 3);
+		// So must a page with a bad header
+		let blank = scan(
+			&[0xFF; Stats::HEADER_SIZE],
+			[ENTRY_BLANK; 3].iter().copied(),
+		);
+		assert_eq!(blank.next_free, None);
+		assert_eq!(blank.stats, Stats::new());
+	}
+
+	#[test]
+	fn events() {
+		assert_eq!(
+			Counter::for_event(EventKind::PowerOn),
+			Some(Counter::PowerOn)
+		);
+		assert_eq!(
+			Counter::for_event(EventKind::OverCurrent),
+			Some(Counter::FaultShutdown)
+		);
+		assert_eq!(Counter::for_event(EventKind::PowerOff), None);
+	}
+}
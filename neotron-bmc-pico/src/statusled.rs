@@ -0,0 +1,280 @@
+//! # RGB Status LED
+//!
+//! Some cases have a single addressable RGB LED (a WS2812, or *NeoPixel*)
+//! for status. With the `status-led` feature, its data line is driven from
+//! PB6 (one of the I²C pins).
+//!
+//! A WS2812 takes 24 bits (green, red, then blue, each most significant bit
+//! first) at 800 kHz, where a bit is a pulse whose width says whether it is a
+//! 0 or a 1. We generate the pulses with TIM16 in PWM mode, and have the DMA
+//! controller load the next pulse width into the compare register at every
+//! update event, so the CPU only has to build a frame (see [`encode`]).
+//!
+//! The host picks a colour and a pattern. If the BMC cuts the power because
+//! of a fault, the LED blinks a colour for that fault instead, until the
+//! main board is powered on again or the host clears it.
+
+use crate::eventlog::EventKind;
+use crate::power::{led_duty, BREATHE_PERIOD_MS};
+
+/// The LED is dark (the default)
+pub const PATTERN_OFF: u8 = 0;
+/// The LED is lit in the host's colour
+pub const PATTERN_SOLID: u8 = 1;
+/// The LED blinks the host's colour once a second
+pub const PATTERN_BLINK: u8 = 2;
+/// The LED fades the host's colour up and down, like the power LED whilst
+/// off
+pub const PATTERN_BREATHE: u8 = 3;
+
+/// How long one blink takes (lit, then dark)
+pub const BLINK_PERIOD_MS: u32 = 1000;
+
+/// How long one blink takes when showing a fault
+pub const FAULT_BLINK_PERIOD_MS: u32 = 500;
+
+/// The colour shown for each kind of fault, as red, green and blue.
+pub const FAULT_COLOURS: [(EventKind, [u8; 3]); 4] = [
+	(EventKind::OverCurrent, [255, 0, 0]),
+	(EventKind::OverTemperature, [255, 64, 0]),
+	(EventKind::LowVoltage, [255, 160, 0]),
+	(EventKind::NoMainBoard, [0, 0, 255]),
+];
+
+/// The WS2812 bit rate
+pub const BIT_RATE_HZ: u32 = 800_000;
+
+/// How many pulse widths the DMA controller sends for one frame - a low
+/// period to start, the 24 bits, and a zero to leave the line low afterwards.
+pub const FRAME_LEN: usize = 26;
+
+/// The TIM16 settings for one WS2812 bit.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BitTiming {
+	/// The auto-reload value, for one bit period
+	pub reload: u16,
+	/// The pulse width for a 0 bit, in timer ticks
+	pub zero: u16,
+	/// The pulse width for a 1 bit, in timer ticks
+	pub one: u16,
+}
+
+impl BitTiming {
+	/// Work out the timer settings for a timer clocked at `clock_hz`, with no
+	/// prescaler.
+	///
+	/// A 0 bit is high for 0.3 of the period (375 ns), and a 1 bit for 0.6
+	/// (750 ns), which is inside the WS2812's tolerances down to the 8 MHz
+	/// standby clock.
+	pub const fn new(clock_hz: u32) -> BitTiming {
+		let ticks = clock_hz / BIT_RATE_HZ;
+		BitTiming {
+			reload: (ticks - 1) as u16,
+			zero: (ticks * 3 / 10) as u16,
+			one: (ticks * 6 / 10) as u16,
+		}
+	}
+}
+
+/// Build the pulse widths for a frame which sets the LED to `colour` (red,
+/// green and blue).
+pub fn encode(colour: [u8; 3], timing: BitTiming) -> [u16; FRAME_LEN] {
+	let mut frame = [0u16; FRAME_LEN];
+	let [red, green, blue] = colour;
+	let bits = (u32::from(green) << 16) | (u32::from(red) << 8) | u32::from(blue);
+	for (idx, width) in frame[1..FRAME_LEN - 1].iter_mut().enumerate() {
+		*width = if (bits & (1 << (23 - idx))) != 0 {
+			timing.one
+		} else {
+			timing.zero
+		};
+	}
+	frame
+}
+
+/// What the host wants the status LED to show, and any fault the BMC is
+/// showing instead.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatusLed {
+	/// The host's colour, as red, green and blue
+	colour: [u8; 3],
+	/// The host's pattern (see `PATTERN_OFF`, etc)
+	pattern: u8,
+	/// The fault which cut the power, if we're showing one
+	fault: Option<EventKind>,
+}
+
+impl StatusLed {
+	/// Create the state, with the LED dark.
+	pub const fn new() -> StatusLed {
+		StatusLed {
+			colour: [0; 3],
+			pattern: PATTERN_OFF,
+			fault: None,
+		}
+	}
+
+	/// The host's colour, as red, green and blue.
+	pub fn colour(&self) -> [u8; 3] {
+		self.colour
+	}
+
+	/// Set one channel of the host's colour (0 for red, 1 for green, 2 for
+	/// blue).
+	pub fn set_channel(&mut self, channel: usize, value: u8) {
+		self.colour[channel] = value;
+	}
+
+	/// The host's pattern.
+	pub fn pattern(&self) -> u8 {
+		self.pattern
+	}
+
+	/// Choose a pattern. Returns false, and changes nothing, if `pattern`
+	/// isn't one we know.
+	pub fn set_pattern(&mut self, pattern: u8) -> bool {
+		if pattern > PATTERN_BREATHE {
+			return false;
+		}
+		self.pattern = pattern;
+		true
+	}
+
+	/// The fault being shown, if any.
+	pub fn fault(&self) -> Option<EventKind> {
+		self.fault
+	}
+
+	/// Go back to showing the host's colour.
+	pub fn clear_fault(&mut self) {
+		self.fault = None;
+	}
+
+	/// The power manager has logged an event. A fault which cut the power is
+	/// shown until the main board powers on again.
+	pub fn power_event(&mut self, kind: EventKind) {
+		if kind == EventKind::PowerOn {
+			self.fault = None;
+		} else if FAULT_COLOURS.iter().any(|(fault, _)| *fault == kind) {
+			self.fault = Some(kind);
+		}
+	}
+
+	/// What colour should the LED be right now?
+	pub fn current(&self, now_ms: u32) -> [u8; 3] {
+		if let Some(fault) = self.fault {
+			let colour = FAULT_COLOURS
+				.iter()
+				.find(|(kind, _)| *kind == fault)
+				.map(|(_, colour)| *colour)
+				.unwrap_or_default();
+			return if (now_ms % FAULT_BLINK_PERIOD_MS) < FAULT_BLINK_PERIOD_MS / 2 {
+				colour
+			} else {
+				[0; 3]
+			};
+		}
+		match self.pattern {
+			PATTERN_SOLID => self.colour,
+			PATTERN_BLINK => {
+				if (now_ms % BLINK_PERIOD_MS) < BLINK_PERIOD_MS / 2 {
+					self.colour
+				} else {
+					[0; 3]
+				}
+			}
+			PATTERN_BREATHE => {
+				// Up and down again, starting dark
+				let phase = (now_ms % BREATHE_PERIOD_MS) * 200 / BREATHE_PERIOD_MS;
+				let level = if phase < 100 { phase } else { 200 - phase };
+				let mut colour = self.colour;
+				for channel in colour.iter_mut() {
+					*channel = led_duty(level as u8, u16::from(*channel)) as u8;
+				}
+				colour
+			}
+			_ => [0; 3],
+		}
+	}
+}
+
+impl Default for StatusLed {
+	fn default() -> Self {
+		StatusLed::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn encoding() {
+		let timing = BitTiming::new(48_000_000);
+		assert_eq!(
+			timing,
+			BitTiming {
+				reload: 59,
+				zero: 18,
+				one: 36
+			}
+		);
+		assert_eq!(
+			BitTiming::new(8_000_000),
+			BitTiming {
+				reload: 9,
+				zero: 3,
+				one: 6
+			}
+		);
+		// Green goes first
+		let frame = encode([0x00, 0x81, 0x01], timing);
+		assert_eq!(frame[0], 0);
+		assert_eq!(frame[1], 36);
+		assert_eq!(&frame[2..8], &[18; 6]);
+		assert_eq!(frame[8], 36);
+		assert_eq!(&frame[9..24], &[18; 15]);
+		assert_eq!(frame[24], 36);
+		assert_eq!(frame[25], 0);
+	}
+
+	#[test]
+	fn patterns() {
+		let mut led = StatusLed::new();
+		led.set_channel(0, 200);
+		led.set_channel(2, 100);
+		assert_eq!(led.current(0), [0; 3]);
+		assert!(led.set_pattern(PATTERN_SOLID));
+		assert_eq!(led.current(0), [200, 0, 100]);
+		assert!(led.set_pattern(PATTERN_BLINK));
+		assert_eq!(led.current(100), [200, 0, 100]);
+		assert_eq!(led.current(600), [0; 3]);
+		assert!(led.set_pattern(PATTERN_BREATHE));
+		assert_eq!(led.current(0), [0; 3]);
+		assert_eq!(led.current(BREATHE_PERIOD_MS / 2), [200, 0, 100]);
+		assert_eq!(led.current(BREATHE_PERIOD_MS / 4), [50, 0, 25]);
+		assert!(!led.set_pattern(PATTERN_BREATHE + 1));
+		assert_eq!(led.pattern(), PATTERN_BREATHE);
+	}
+
+	#[test]
+	fn faults() {
+		let mut led = StatusLed::new();
+		assert!(led.set_pattern(PATTERN_SOLID));
+		led.set_channel(1, 255);
+		// Not a fault
+		led.power_event(EventKind::PowerOff);
+		assert_eq!(led.current(0), [0, 255, 0]);
+		led.power_event(EventKind::OverTemperature);
+		assert_eq!(led.fault(), Some(EventKind::OverTemperature));
+		assert_eq!(led.current(0), [255, 64, 0]);
+		assert_eq!(led.current(300), [0; 3]);
+		// Powering on again clears it
+		led.power_event(EventKind::PowerOn);
+		assert_eq!(led.current(0), [0, 255, 0]);
+		// So does the host
+		led.power_event(EventKind::OverCurrent);
+		led.clear_fault();
+		assert_eq!(led.fault(), None);
+	}
+}
@@ -0,0 +1,156 @@
+//! # Over-Temperature Shutdown
+//!
+//! If the BMC's temperature sensor goes over the limit in the config, we ask
+//! the host to shut down gracefully (with the Over-Temperature interrupt).
+//! If the main board is still powered on once the shutdown timeout has run
+//! out, we cut the DC power ourselves, and latch the reason in the BMC Health
+//! register.
+//!
+//! Once the host has been asked, it has until the timeout runs out - the
+//! request stands even if things cool down again in the meantime, so an OS
+//! which has started shutting down doesn't get a confusing change of mind.
+
+/// What the caller should do after a temperature reading.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ThermalAction {
+	/// Nothing to do
+	None,
+	/// Ask the host to shut down
+	RequestShutdown,
+	/// The host didn't shut down in time, so cut the DC power
+	CutPower,
+}
+
+/// Where we are in the shutdown sequence.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ThermalState {
+	/// Below the limit (or the main board is off)
+	Normal,
+	/// We have asked the host to shut down
+	ShutdownRequested {
+		/// When we asked
+		since_ms: u32,
+	},
+	/// We have cut the power, and are waiting for the main board to be off
+	Tripped,
+}
+
+/// Watches the temperature, and decides when to shut the main board down.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThermalGuard {
+	state: ThermalState,
+}
+
+impl ThermalGuard {
+	/// Create a new guard, with nothing happening.
+	pub const fn new() -> ThermalGuard {
+		ThermalGuard {
+			state: ThermalState::Normal,
+		}
+	}
+
+	/// Have we asked the host to shut down, and are still waiting for it?
+	pub fn is_shutdown_requested(&self) -> bool {
+		matches!(self.state, ThermalState::ShutdownRequested { .. })
+	}
+
+	/// Check a new temperature reading.
+	///
+	/// `limit_c` is the over-temperature limit in °C (zero disables the
+	/// check) and `timeout_s` is how long, in seconds, the host gets to shut
+	/// down. `powered_on` says whether the main board currently has power.
+	pub fn update(
+		&mut self,
+		now_ms: u32,
+		temperature_c: i8,
+		limit_c: u8,
+		timeout_s: u8,
+		powered_on: bool,
+	) -> ThermalAction {
+		if !powered_on {
+			// Whoever turned it off, we're done
+			self.state = ThermalState::Normal;
+			return ThermalAction::None;
+		}
+		match self.state {
+			ThermalState::Normal => {
+				if limit_c != 0 && i16::from(temperature_c) >= i16::from(limit_c) {
+					self.state = ThermalState::ShutdownRequested { since_ms: now_ms };
+					ThermalAction::RequestShutdown
+				} else {
+					ThermalAction::None
+				}
+			}
+			ThermalState::ShutdownRequested { since_ms } => {
+				if now_ms.wrapping_sub(since_ms) >= u32::from(timeout_s) * 1000 {
+					self.state = ThermalState::Tripped;
+					ThermalAction::CutPower
+				} else {
+					ThermalAction::None
+				}
+			}
+			ThermalState::Tripped => ThermalAction::None,
+		}
+	}
+}
+
+impl Default for ThermalGuard {
+	fn default() -> Self {
+		ThermalGuard::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn shutdown_sequence() {
+		let mut guard = ThermalGuard::new();
+		assert_eq!(guard.update(0, 69, 70, 30, true), ThermalAction::None);
+		assert_eq!(
+			guard.update(1000, 70, 70, 30, true),
+			ThermalAction::RequestShutdown
+		);
+		assert!(guard.is_shutdown_requested());
+		// Cooling down doesn't call it off
+		assert_eq!(guard.update(2000, 40, 70, 30, true), ThermalAction::None);
+		assert_eq!(guard.update(30_999, 75, 70, 30, true), ThermalAction::None);
+		assert_eq!(
+			guard.update(31_000, 75, 70, 30, true),
+			ThermalAction::CutPower
+		);
+		assert!(!guard.is_shutdown_requested());
+		assert_eq!(guard.update(32_000, 75, 70, 30, true), ThermalAction::None);
+		// Once the power is off, we start again
+		assert_eq!(guard.update(33_000, 75, 70, 30, false), ThermalAction::None);
+		assert_eq!(
+			guard.update(34_000, 75, 70, 30, true),
+			ThermalAction::RequestShutdown
+		);
+	}
+
+	#[test]
+	fn host_shuts_down_in_time() {
+		let mut guard = ThermalGuard::new();
+		assert_eq!(
+			guard.update(0, 90, 70, 10, true),
+			ThermalAction::RequestShutdown
+		);
+		assert_eq!(guard.update(5000, 90, 70, 10, false), ThermalAction::None);
+		assert!(!guard.is_shutdown_requested());
+	}
+
+	#[test]
+	fn disabled() {
+		let mut guard = ThermalGuard::new();
+		assert_eq!(guard.update(0, 127, 0, 10, true), ThermalAction::None);
+		// A zero timeout cuts the power at the next reading
+		assert_eq!(guard.update(0, -5, 1, 0, true), ThermalAction::None);
+		assert_eq!(
+			guard.update(0, 1, 1, 0, true),
+			ThermalAction::RequestShutdown
+		);
+		assert_eq!(guard.update(1000, 1, 1, 0, true), ThermalAction::CutPower);
+	}
+}
@@ -0,0 +1,408 @@
+//! # UARTs
+//!
+//! Taking an interrupt for every byte falls over at high baud rates, when the
+//! SPI interrupt is busy. Instead, the DMA controller copies each byte from
+//! USART1 into a circular buffer. We empty that buffer when it is half full,
+//! when it wraps around, and when the line goes idle at the end of a burst,
+//! copying the new bytes into a FIFO which the host reads over SPI.
+//!
+//! Boards with a second UART (see the `uart2` feature) expose it as a
+//! general purpose serial port, with a FIFO in each direction.
+//!
+//! For factory testing, USART1 can also loop its transmitter back to its
+//! receiver and check a pseudo-random pattern comes back intact, without
+//! anything plugged into the UART header.
+
+use core::ops::Range;
+
+/// Follows the DMA controller around a circular buffer of `N` bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DmaRing<const N: usize> {
+	/// Where the bytes we haven't looked at yet start
+	read_pos: usize,
+}
+
+impl<const N: usize> DmaRing<N> {
+	/// Create a new tracker, for a DMA transfer which has just started.
+	pub const fn new() -> DmaRing<N> {
+		DmaRing { read_pos: 0 }
+	}
+
+	/// Work out which bytes have arrived since the last call.
+	///
+	/// `remaining` is the DMA channel's transfer count, which counts down from
+	/// `N` and goes back to `N` when the DMA wraps around to the start of the
+	/// buffer. Returns the positions of the new bytes, oldest first. The
+	/// second range is only non-empty if the DMA has wrapped.
+	///
+	/// If more than `N` bytes arrive between calls, the oldest are overwritten
+	/// before we see them.
+	pub fn advance(&mut self, remaining: usize) -> (Range<usize>, Range<usize>) {
+		let write_pos = N.saturating_sub(remaining) % N;
+		let read_pos = self.read_pos;
+		self.read_pos = write_pos;
+		if write_pos >= read_pos {
+			(read_pos..write_pos, 0..0)
+		} else {
+			(read_pos..N, 0..write_pos)
+		}
+	}
+}
+
+impl<const N: usize> Default for DmaRing<N> {
+	fn default() -> Self {
+		DmaRing::new()
+	}
+}
+
+/// Bytes passing between the host and a UART.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ByteFifo<const N: usize> {
+	/// The bytes, as a ring
+	buffer: [u8; N],
+	/// Where the oldest byte is
+	head: usize,
+	/// How many bytes are waiting
+	len: usize,
+}
+
+impl<const N: usize> ByteFifo<N> {
+	/// Create a new, empty, FIFO.
+	pub const fn new() -> ByteFifo<N> {
+		ByteFifo {
+			buffer: [0; N],
+			head: 0,
+			len: 0,
+		}
+	}
+
+	/// How many more bytes will fit?
+	pub fn space(&self) -> usize {
+		N - self.len
+	}
+
+	/// Add a byte to the FIFO.
+	///
+	/// Returns `false` if the FIFO is full, in which case the byte is dropped.
+	pub fn push(&mut self, byte: u8) -> bool {
+		if self.len == N {
+			return false;
+		}
+		self.buffer[(self.head + self.len) % N] = byte;
+		self.len += 1;
+		true
+	}
+
+	/// Take the oldest byte from the FIFO.
+	pub fn pop(&mut self) -> Option<u8> {
+		if self.len == 0 {
+			return None;
+		}
+		let byte = self.buffer[self.head];
+		self.head = (self.head + 1) % N;
+		self.len -= 1;
+		Some(byte)
+	}
+
+	/// Look at the oldest byte, without taking it from the FIFO.
+	pub fn peek(&self) -> Option<u8> {
+		if self.len == 0 {
+			return None;
+		}
+		Some(self.buffer[self.head])
+	}
+
+	/// How many bytes are waiting?
+	pub fn len(&self) -> usize {
+		self.len
+	}
+
+	/// Is the FIFO empty?
+	pub fn is_empty(&self) -> bool {
+		self.len == 0
+	}
+
+	/// Throw away everything in the FIFO.
+	pub fn clear(&mut self) {
+		self.head = 0;
+		self.len = 0;
+	}
+}
+
+impl<const N: usize> Default for ByteFifo<N> {
+	fn default() -> Self {
+		ByteFifo::new()
+	}
+}
+
+/// Bit in the UART2 Control register which turns the port on
+pub const UART2_ENABLE: u8 = 1 << 0;
+
+/// The baud rate a serial port starts with, in units of 100 bps
+pub const DEFAULT_BAUD_HUNDREDS: u16 = 1152;
+
+/// A general purpose serial port, as the host sees it.
+///
+/// The UART interrupt moves bytes between the FIFOs and the hardware, and
+/// picks up changes to the settings.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SerialPort<const RX: usize, const TX: usize> {
+	/// Bytes received, waiting for the host
+	pub rx: ByteFifo<RX>,
+	/// Bytes from the host, waiting to be sent
+	pub tx: ByteFifo<TX>,
+	/// The baud rate, in units of 100 bps
+	pub baud_hundreds: u16,
+	/// Settings (see `UART2_ENABLE`)
+	pub control: u8,
+}
+
+impl<const RX: usize, const TX: usize> SerialPort<RX, TX> {
+	/// Create a new serial port, which is turned off.
+	pub const fn new() -> SerialPort<RX, TX> {
+		SerialPort {
+			rx: ByteFifo::new(),
+			tx: ByteFifo::new(),
+			baud_hundreds: DEFAULT_BAUD_HUNDREDS,
+			control: 0,
+		}
+	}
+
+	/// Should the port be running?
+	pub fn is_enabled(&self) -> bool {
+		(self.control & UART2_ENABLE) != 0
+	}
+
+	/// The baud rate, in bits per second.
+	///
+	/// Zero is treated as 100 bps.
+	pub fn baud(&self) -> u32 {
+		u32::from(self.baud_hundreds.max(1)) * 100
+	}
+}
+
+impl<const RX: usize, const TX: usize> Default for SerialPort<RX, TX> {
+	fn default() -> Self {
+		SerialPort::new()
+	}
+}
+
+/// The UART self-test sends this many bytes for each block the host asks for
+pub const SELF_TEST_BLOCK_LEN: u16 = 16;
+
+/// Deferred operation result for a UART self-test which got back exactly what
+/// it sent
+pub const SELF_TEST_PASSED: u8 = 0;
+
+/// Deferred operation result for a UART self-test which lost or corrupted
+/// something
+pub const SELF_TEST_FAILED: u8 = 1;
+
+/// Generates the PRBS-9 (x^9 + x^5 + 1) sequence, a byte at a time, LSB
+/// first - the usual pattern for a bit error rate test.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Prbs9 {
+	/// The shift register (never zero)
+	state: u16,
+}
+
+impl Prbs9 {
+	/// Start the sequence from the beginning.
+	pub const fn new() -> Prbs9 {
+		Prbs9 { state: 0x1FF }
+	}
+
+	/// Get the next eight bits of the sequence.
+	pub fn next_byte(&mut self) -> u8 {
+		let mut byte = 0;
+		for bit_number in 0..8 {
+			let bit = ((self.state >> 8) ^ (self.state >> 4)) & 1;
+			self.state = ((self.state << 1) | bit) & 0x1FF;
+			byte |= (bit as u8) << bit_number;
+		}
+		byte
+	}
+}
+
+impl Default for Prbs9 {
+	fn default() -> Self {
+		Prbs9::new()
+	}
+}
+
+/// The outcome of a UART self-test.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct SelfTestResults {
+	/// How many bytes we sent
+	pub sent: u16,
+	/// How many bytes came back
+	pub received: u16,
+	/// How many bits came back wrong
+	pub bit_errors: u16,
+}
+
+impl SelfTestResults {
+	/// How many bytes the UART Self-Test register holds.
+	pub const SIZE: usize = 6;
+
+	/// Did everything come back, intact?
+	pub fn passed(&self) -> bool {
+		self.sent == self.received && self.bit_errors == 0
+	}
+
+	/// Convert to bytes for the UART Self-Test register.
+	///
+	/// That's the bytes sent, the bytes received and then the bit errors, each
+	/// as a `u16le`.
+	pub fn as_bytes(&self) -> [u8; Self::SIZE] {
+		let mut bytes = [0u8; Self::SIZE];
+		bytes[0..2].copy_from_slice(&self.sent.to_le_bytes());
+		bytes[2..4].copy_from_slice(&self.received.to_le_bytes());
+		bytes[4..6].copy_from_slice(&self.bit_errors.to_le_bytes());
+		bytes
+	}
+}
+
+/// A UART self-test in progress.
+///
+/// The same pattern is generated twice - once to send, and once to compare
+/// against what comes back. A lost byte throws the comparison out of step,
+/// so everything after it counts as errors too.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelfTest {
+	/// Makes the bytes we send
+	tx_pattern: Prbs9,
+	/// Makes the bytes we expect back
+	rx_pattern: Prbs9,
+	/// How many bytes to send
+	length: u16,
+	/// The counts so far
+	results: SelfTestResults,
+}
+
+impl SelfTest {
+	/// Start a test which sends `length` bytes.
+	pub const fn new(length: u16) -> SelfTest {
+		SelfTest {
+			tx_pattern: Prbs9::new(),
+			rx_pattern: Prbs9::new(),
+			length,
+			results: SelfTestResults {
+				sent: 0,
+				received: 0,
+				bit_errors: 0,
+			},
+		}
+	}
+
+	/// Get the next byte to send, if there is one.
+	pub fn next_tx(&mut self) -> Option<u8> {
+		if self.results.sent == self.length {
+			return None;
+		}
+		self.results.sent += 1;
+		Some(self.tx_pattern.next_byte())
+	}
+
+	/// Have we sent everything?
+	pub fn is_sent(&self) -> bool {
+		self.results.sent == self.length
+	}
+
+	/// Check a byte which came back.
+	pub fn check_rx(&mut self, byte: u8) {
+		let expected = if self.results.received < self.results.sent {
+			self.rx_pattern.next_byte()
+		} else {
+			// We didn't send this, so every bit of it is wrong
+			!byte
+		};
+		self.results.received = self.results.received.saturating_add(1);
+		let errors = (byte ^ expected).count_ones() as u16;
+		self.results.bit_errors = self.results.bit_errors.saturating_add(errors);
+	}
+
+	/// The counts so far.
+	pub fn results(&self) -> SelfTestResults {
+		self.results
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn ring_wraps() {
+		let mut ring = DmaRing::<8>::new();
+		// Nothing yet
+		assert_eq!(ring.advance(8), (0..0, 0..0));
+		// Three bytes in
+		assert_eq!(ring.advance(5), (0..3, 0..0));
+		// Up to the end of the buffer, where the counter reloads
+		assert_eq!(ring.advance(8), (3..8, 0..0));
+		// Two more, then round past the end
+		assert_eq!(ring.advance(6), (0..2, 0..0));
+		assert_eq!(ring.advance(7), (2..8, 0..1));
+		assert_eq!(ring.advance(7), (1..1, 0..0));
+	}
+
+	#[test]
+	fn prbs9_sequence() {
+		let mut prbs = Prbs9::new();
+		// The sequence repeats every 511 bits, and has 256 ones in that time
+		let bytes: Vec<u8> = (0..511).map(|_| prbs.next_byte()).collect();
+		let ones: u32 = bytes.iter().map(|b| b.count_ones()).sum();
+		assert_eq!(ones, 8 * 256);
+		assert_eq!(prbs.next_byte(), bytes[0]);
+	}
+
+	#[test]
+	fn self_test() {
+		let mut test = SelfTest::new(3);
+		let mut sent = Vec::new();
+		while let Some(byte) = test.next_tx() {
+			sent.push(byte);
+		}
+		assert!(test.is_sent());
+		assert_eq!(sent.len(), 3);
+		test.check_rx(sent[0]);
+		test.check_rx(sent[1] ^ 0x81);
+		assert_eq!(
+			test.results(),
+			SelfTestResults {
+				sent: 3,
+				received: 2,
+				bit_errors: 2
+			}
+		);
+		assert!(!test.results().passed());
+		assert_eq!(test.results().as_bytes(), [3, 0, 2, 0, 2, 0]);
+		// Something we never sent
+		test.check_rx(sent[2]);
+		test.check_rx(0x00);
+		assert_eq!(test.results().bit_errors, 10);
+	}
+
+	#[test]
+	fn fifo_fills_up() {
+		let mut fifo = ByteFifo::<4>::new();
+		assert!(fifo.is_empty());
+		for b in 1..=4 {
+			assert!(fifo.push(b));
+		}
+		assert!(!fifo.push(5));
+		assert_eq!(fifo.len(), 4);
+		assert_eq!(fifo.space(), 0);
+		assert_eq!(fifo.peek(), Some(1));
+		assert_eq!(fifo.pop(), Some(1));
+		assert!(fifo.push(6));
+		let rest: Vec<u8> = core::iter::from_fn(|| fifo.pop()).collect();
+		assert_eq!(rest, vec![2, 3, 4, 6]);
+		assert_eq!(fifo.pop(), None);
+		assert_eq!(fifo.peek(), None);
+		fifo.push(7);
+		fifo.clear();
+		assert!(fifo.is_empty());
+	}
+}
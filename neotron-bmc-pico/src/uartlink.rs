@@ -0,0 +1,364 @@
+//! # Register Access over the UART
+//!
+//! A developer with only a serial cable on the UART header can still reach
+//! the register map - to read registers, power the board on and off, or dump
+//! the event log - even when SPI is unavailable.
+//!
+//! Normally every byte received on the UART goes to the host. Sending a
+//! break (holding the line low for longer than a byte) switches the UART over
+//! to the command channel, and another break switches it back. Whilst the
+//! command channel is open, the host doesn't see anything received on the
+//! UART.
+//!
+//! The *Requests* and *Responses* are the same as over SPI, with each one in
+//! a SLIP frame (RFC 1055). Send one *Request* at a time, and wait for its
+//! *Response* before sending the next. Where a *Request* carries a payload
+//! (and got an `Ok` result), the payload and its CRC go in the next frame.
+
+use neotron_bmc_protocol as proto;
+
+use crate::registers::{PayloadBuffer, PAYLOAD_BUFFER_LEN};
+use crate::uart::ByteFifo;
+
+/// Marks the end (and, on the way out, the start) of a SLIP frame
+pub const SLIP_END: u8 = 0xC0;
+
+/// Marks an escaped byte in a SLIP frame
+pub const SLIP_ESC: u8 = 0xDB;
+
+/// An escaped `SLIP_END`
+pub const SLIP_ESC_END: u8 = 0xDC;
+
+/// An escaped `SLIP_ESC`
+pub const SLIP_ESC_ESC: u8 = 0xDD;
+
+/// How long a frame holding a request is
+const REQUEST_LEN: usize = 4;
+
+/// The longest frame we accept. Only a payload is longer than a request, so
+/// anything longer than the longest payload is a mistake.
+const MAX_FRAME_LEN: usize = PAYLOAD_BUFFER_LEN;
+
+/// How much room we have for SLIP framed responses waiting to go out
+const TX_FIFO_LEN: usize = 128;
+
+/// Collects the bytes of one SLIP frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlipDecoder<const N: usize> {
+	/// The frame so far, with the escapes removed
+	buffer: [u8; N],
+	/// How many bytes of `buffer` are used
+	len: usize,
+	/// Was the last byte `SLIP_ESC`?
+	escaped: bool,
+	/// Did the frame have more bytes than we have room for?
+	overflow: bool,
+}
+
+impl<const N: usize> SlipDecoder<N> {
+	/// Create a decoder, waiting for the start of a frame.
+	pub const fn new() -> SlipDecoder<N> {
+		SlipDecoder {
+			buffer: [0; N],
+			len: 0,
+			escaped: false,
+			overflow: false,
+		}
+	}
+
+	/// Throw away anything received so far.
+	pub fn reset(&mut self) {
+		self.len = 0;
+		self.escaped = false;
+		self.overflow = false;
+	}
+
+	/// Feed in a received byte.
+	///
+	/// Returns the frame once it is complete. Empty frames are skipped, and
+	/// frames which are too long come back as `Err(())`.
+	pub fn push(&mut self, byte: u8) -> Option<Result<&[u8], ()>> {
+		let byte = match (self.escaped, byte) {
+			(_, SLIP_END) => {
+				let (len, overflow) = (self.len, self.overflow);
+				self.reset();
+				return match (len, overflow) {
+					(_, true) => Some(Err(())),
+					(0, false) => None,
+					(len, false) => Some(Ok(&self.buffer[0..len])),
+				};
+			}
+			(false, SLIP_ESC) => {
+				self.escaped = true;
+				return None;
+			}
+			(true, SLIP_ESC_END) => SLIP_END,
+			(true, SLIP_ESC_ESC) => SLIP_ESC,
+			// Not a valid escape, so keep it as it is
+			(_, byte) => byte,
+		};
+		self.escaped = false;
+		if self.len < N {
+			self.buffer[self.len] = byte;
+			self.len += 1;
+		} else {
+			self.overflow = true;
+		}
+		None
+	}
+}
+
+impl<const N: usize> Default for SlipDecoder<N> {
+	fn default() -> Self {
+		SlipDecoder::new()
+	}
+}
+
+/// SLIP encode some bytes, without the `SLIP_END` markers either side.
+pub fn slip_encode(data: &[u8], out: &mut dyn FnMut(u8)) {
+	for &byte in data {
+		match byte {
+			SLIP_END => {
+				out(SLIP_ESC);
+				out(SLIP_ESC_END);
+			}
+			SLIP_ESC => {
+				out(SLIP_ESC);
+				out(SLIP_ESC_ESC);
+			}
+			byte => out(byte),
+		}
+	}
+}
+
+/// The command channel on the UART.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UartLink {
+	/// Is the command channel open (instead of passing bytes to the host)?
+	active: bool,
+	/// Collects the next request
+	decoder: SlipDecoder<MAX_FRAME_LEN>,
+	/// The request whose payload is the next frame, if there is one
+	payload_for: Option<proto::Request>,
+	/// A request waiting to be answered, with its payload if it carries one
+	request: Option<Result<(proto::Request, Option<PayloadBuffer>), proto::Error>>,
+	/// Responses waiting to be sent
+	tx: ByteFifo<TX_FIFO_LEN>,
+}
+
+impl UartLink {
+	/// Create the command channel, closed.
+	pub const fn new() -> UartLink {
+		UartLink {
+			active: false,
+			decoder: SlipDecoder::new(),
+			payload_for: None,
+			request: None,
+			tx: ByteFifo::new(),
+		}
+	}
+
+	/// Is the command channel open?
+	pub fn is_active(&self) -> bool {
+		self.active
+	}
+
+	/// A break arrived, so open the command channel if it was closed, or
+	/// close it if it was open.
+	///
+	/// Returns `true` if the command channel is now open.
+	pub fn toggle(&mut self) -> bool {
+		self.active = !self.active;
+		self.decoder.reset();
+		self.payload_for = None;
+		self.request = None;
+		self.active
+	}
+
+	/// Feed in a byte received whilst the command channel is open.
+	///
+	/// If a request is already waiting to be answered, any more are dropped.
+	pub fn receive(&mut self, byte: u8) {
+		use proto::Receivable;
+		let frame = match self.decoder.push(byte) {
+			None => return,
+			Some(frame) => frame,
+		};
+		let request = match (self.payload_for.take(), frame) {
+			// A payload too long for us is as bad as one which is cut short
+			(Some(req), frame) => match frame.ok().and_then(PayloadBuffer::new) {
+				Some(payload) => Ok((req, Some(payload))),
+				None => Err(proto::Error::BadLength),
+			},
+			(None, Ok(frame)) if frame.len() == REQUEST_LEN => {
+				proto::Request::from_bytes(frame).map(|req| (req, None))
+			}
+			// A short frame is as bad as a long one
+			(None, _) => Err(proto::Error::BadRequestType),
+		};
+		if self.request.is_none() {
+			self.request = Some(request);
+		}
+	}
+
+	/// The request just answered carries a payload, so take the next frame
+	/// as its payload.
+	pub fn expect_payload(&mut self, req: &proto::Request) {
+		self.payload_for = Some(req.clone());
+	}
+
+	/// Take the request waiting to be answered, if there is one.
+	pub fn take_request(
+		&mut self,
+	) -> Option<Result<(proto::Request, Option<PayloadBuffer>), proto::Error>> {
+		self.request.take()
+	}
+
+	/// Queue a response to be sent, in a SLIP frame.
+	///
+	/// Returns `false`, and sends nothing, if there isn't room for it.
+	pub fn send(&mut self, message: &dyn proto::Sendable) -> bool {
+		// Allow for every byte being escaped, plus the markers either side
+		if self.tx.space() < (message.rendered_len() * 2) + 2 {
+			return false;
+		}
+		let tx = &mut self.tx;
+		tx.push(SLIP_END);
+		message.render_segments(&mut |bytes| {
+			slip_encode(bytes, &mut |byte| {
+				tx.push(byte);
+			})
+		});
+		tx.push(SLIP_END);
+		true
+	}
+
+	/// Get the next byte to send, if there is one.
+	pub fn next_tx(&mut self) -> Option<u8> {
+		self.tx.pop()
+	}
+}
+
+impl Default for UartLink {
+	fn default() -> Self {
+		UartLink::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Feed the bytes into the decoder, and return every frame it gives back.
+	fn decode(decoder: &mut SlipDecoder<4>, bytes: &[u8]) -> Vec<Result<Vec<u8>, ()>> {
+		bytes
+			.iter()
+			.filter_map(|&b| decoder.push(b).map(|f| f.map(|f| f.to_vec())))
+			.collect()
+	}
+
+	#[test]
+	fn slip_frames() {
+		let mut decoder = SlipDecoder::<4>::new();
+		// Empty frames are skipped
+		assert_eq!(
+			decode(&mut decoder, &[SLIP_END, 1, 2, SLIP_END, SLIP_END]),
+			vec![Ok(vec![1, 2])]
+		);
+		// Escapes are undone
+		assert_eq!(
+			decode(
+				&mut decoder,
+				&[SLIP_ESC, SLIP_ESC_END, SLIP_ESC, SLIP_ESC_ESC, SLIP_END]
+			),
+			vec![Ok(vec![SLIP_END, SLIP_ESC])]
+		);
+		// Too long
+		assert_eq!(
+			decode(&mut decoder, &[1, 2, 3, 4, 5, SLIP_END, 6, SLIP_END]),
+			vec![Err(()), Ok(vec![6])]
+		);
+		// And back again
+		let mut encoded = Vec::new();
+		slip_encode(&[1, SLIP_END, SLIP_ESC], &mut |b| encoded.push(b));
+		assert_eq!(
+			encoded,
+			vec![1, SLIP_ESC, SLIP_ESC_END, SLIP_ESC, SLIP_ESC_ESC]
+		);
+	}
+
+	#[test]
+	fn requests_and_responses() {
+		let mut link = UartLink::new();
+		assert!(!link.is_active());
+		assert!(link.toggle());
+		let req = proto::Request::new_read(false, 0x10, 1);
+		let mut buffer = [0u8; 4];
+		use proto::Sendable;
+		req.render_to_buffer(&mut buffer).unwrap();
+		// A Read request starts with 0xC0, which needs escaping
+		link.receive(SLIP_END);
+		slip_encode(&buffer, &mut |b| link.receive(b));
+		link.receive(SLIP_END);
+		assert_eq!(link.take_request(), Some(Ok((req, None))));
+		assert_eq!(link.take_request(), None);
+		// A short frame, and a long one
+		for &b in &[0xC2, 0x10, SLIP_END] {
+			link.receive(b);
+		}
+		assert_eq!(link.take_request(), Some(Err(proto::Error::BadRequestType)));
+		slip_encode(&buffer, &mut |b| link.receive(b));
+		for &b in &[0x00, SLIP_END] {
+			link.receive(b);
+		}
+		assert_eq!(link.take_request(), Some(Err(proto::Error::BadRequestType)));
+		// The response is framed, and escaped
+		assert!(link.send(&proto::Response::new_ok_with_data(&[SLIP_END])));
+		let mut sent = Vec::new();
+		while let Some(b) = link.next_tx() {
+			sent.push(b);
+		}
+		assert_eq!(sent[0..4], [SLIP_END, 0xA0, SLIP_ESC, SLIP_ESC_END]);
+		assert_eq!(sent.len(), 6);
+		assert_eq!(sent[5], SLIP_END);
+		// Closing the channel drops anything half received
+		link.receive(0x01);
+		assert!(!link.toggle());
+		assert!(link.toggle());
+		link.receive(SLIP_END);
+		assert_eq!(link.take_request(), None);
+	}
+
+	#[test]
+	fn payloads() {
+		let mut link = UartLink::new();
+		link.toggle();
+		let req = proto::Request::new_bulk_write_continue(false, 0, 2);
+		link.expect_payload(&req);
+		for &b in &[0x41, 0x42, 0x87, SLIP_END] {
+			link.receive(b);
+		}
+		let payload = PayloadBuffer::new(&[0x41, 0x42, 0x87]).unwrap();
+		assert_eq!(link.take_request(), Some(Ok((req.clone(), Some(payload)))));
+		// Only the next frame is a payload, and one which is too long is
+		// refused
+		for &b in &[0x41, 0x42, 0x87, SLIP_END] {
+			link.receive(b);
+		}
+		assert_eq!(link.take_request(), Some(Err(proto::Error::BadRequestType)));
+		link.expect_payload(&req);
+		for _ in 0..=PAYLOAD_BUFFER_LEN {
+			link.receive(0x41);
+		}
+		link.receive(SLIP_END);
+		assert_eq!(link.take_request(), Some(Err(proto::Error::BadLength)));
+		// Closing the channel forgets the payload
+		link.expect_payload(&req);
+		link.toggle();
+		link.toggle();
+		for &b in &[0x41, SLIP_END] {
+			link.receive(b);
+		}
+		assert_eq!(link.take_request(), Some(Err(proto::Error::BadRequestType)));
+	}
+}
@@ -0,0 +1,136 @@
+//! # USB Device
+//!
+//! With the `usb` feature, a board built around an STM32F042 or STM32F072
+//! shows up on a USB host as two things at once:
+//!
+//! * A CDC-ACM serial port, carrying the console. Whilst a terminal has the
+//!   port open (i.e. has raised DTR), the host's UART output goes to the
+//!   serial port instead of the UART, and whatever is typed on it reaches the
+//!   host as if it had arrived on the UART - including the remote console
+//!   escapes, and console takeover.
+//! * A vendor-specific interface with a bulk OUT and a bulk IN endpoint, for
+//!   register access. Each OUT transfer is one *Request*, exactly as it
+//!   would be sent over SPI, and the *Response* comes back as one IN
+//!   transfer. Where a *Request* carries a payload (and got an `Ok`
+//!   result), the payload and its CRC are the next OUT transfer, and get a
+//!   second *Response*.
+//!
+//! So a developer only needs a USB cable, not the FTDI header. The USB pins
+//! are PA11 and PA12, so the UART loses its flow control lines.
+//!
+//! The USB peripheral is clocked from the 48 MHz HSI48 oscillator, trimmed
+//! against the host's Start-of-Frame packets, so it keeps working when
+//! standby turns the PLL off.
+
+use neotron_bmc_protocol as proto;
+use stm32f0xx_hal::pac;
+use usb_device::{class_prelude::*, device::UsbVidPid};
+
+use crate::registers::PayloadBuffer;
+
+/// pid.codes' test VID and PID, which are fine for development.
+pub const VID_PID: UsbVidPid = UsbVidPid(0x1209, 0x0001);
+
+/// Who made us, for the USB string descriptors
+pub const MANUFACTURER: &str = "Neotron";
+
+/// What we are, for the USB string descriptors
+pub const PRODUCT: &str = "Neotron BMC";
+
+/// The largest packet on our bulk endpoints. Every *Request*, payload and
+/// *Response* fits in one.
+pub const MAX_PACKET_LEN: u16 = 64;
+
+/// Our interface class, as the register interface isn't a standard one
+const CLASS_VENDOR: u8 = 0xFF;
+
+/// Start the HSI48 oscillator, feed it to the USB peripheral, and have the
+/// Clock Recovery System trim it against the host's Start-of-Frame packets.
+///
+/// Call this before creating the USB bus.
+pub fn start_clock(crs: &pac::CRS) {
+	// Safety: we only touch the HSI48, CRS and USB clock bits, which nothing
+	// else modifies after init.
+	let rcc = unsafe { &*pac::RCC::ptr() };
+	rcc.cr2.modify(|_r, w| w.hsi48on().set_bit());
+	while rcc.cr2.read().hsi48rdy().bit_is_clear() {}
+	rcc.cfgr3.modify(|_r, w| w.usbsw().hsi48());
+	rcc.apb1enr.modify(|_r, w| w.crsen().set_bit());
+	crs.cfgr.modify(|_r, w| w.syncsrc().usb());
+	crs.cr
+		.modify(|_r, w| w.autotrimen().set_bit().cen().set_bit());
+}
+
+/// The vendor-specific interface, for register access over USB.
+pub struct RegisterClass<'a, B: UsbBus> {
+	/// Our interface number
+	interface: InterfaceNumber,
+	/// Requests arrive here
+	read_ep: EndpointOut<'a, B>,
+	/// Responses go out here
+	write_ep: EndpointIn<'a, B>,
+	/// The request whose payload is the next OUT transfer, if there is one
+	payload_for: Option<proto::Request>,
+}
+
+impl<'a, B: UsbBus> RegisterClass<'a, B> {
+	/// Allocate our interface and endpoints.
+	pub fn new(alloc: &'a UsbBusAllocator<B>) -> RegisterClass<'a, B> {
+		RegisterClass {
+			interface: alloc.interface(),
+			read_ep: alloc.bulk(MAX_PACKET_LEN),
+			write_ep: alloc.bulk(MAX_PACKET_LEN),
+			payload_for: None,
+		}
+	}
+
+	/// Collect the *Request* the USB host sent, if there is one, with its
+	/// payload if it carries one.
+	pub fn take_request(
+		&mut self,
+	) -> Option<Result<(proto::Request, Option<PayloadBuffer>), proto::Error>> {
+		use proto::Receivable;
+		let mut packet = [0u8; MAX_PACKET_LEN as usize];
+		let len = self.read_ep.read(&mut packet).ok()?;
+		Some(match self.payload_for.take() {
+			Some(req) => match PayloadBuffer::new(&packet[..len]) {
+				Some(payload) => Ok((req, Some(payload))),
+				None => Err(proto::Error::BadLength),
+			},
+			None => proto::Request::from_bytes(&packet[..len]).map(|req| (req, None)),
+		})
+	}
+
+	/// The request just answered carries a payload, so take the next OUT
+	/// transfer as its payload.
+	pub fn expect_payload(&mut self, req: &proto::Request) {
+		self.payload_for = Some(req.clone());
+	}
+
+	/// Send a *Response* to the USB host.
+	///
+	/// Returns `false` if the last one hasn't been collected yet, in which
+	/// case this one is dropped.
+	pub fn send_response(&mut self, message: &dyn proto::Sendable) -> bool {
+		let mut packet = [0u8; MAX_PACKET_LEN as usize];
+		let mut len = 0;
+		message.render_segments(&mut |bytes| {
+			let end = (len + bytes.len()).min(packet.len());
+			packet[len..end].copy_from_slice(&bytes[..end - len]);
+			len = end;
+		});
+		self.write_ep.write(&packet[..len]).is_ok()
+	}
+}
+
+impl<B: UsbBus> UsbClass<B> for RegisterClass<'_, B> {
+	fn get_configuration_descriptors(
+		&self,
+		writer: &mut DescriptorWriter,
+	) -> usb_device::Result<()> {
+		writer.interface(self.interface, CLASS_VENDOR, 0, 0)?;
+		writer.endpoint(&self.read_ep)?;
+		writer.endpoint(&self.write_ep)?;
+		Ok(())
+	}
+}
@@ -0,0 +1,354 @@
+//! # Register Watches
+//!
+//! Some things the host only cares about once they cross a line - the 5V rail
+//! dropping below 4.75 V, say, or more than 32 bytes waiting in the UART
+//! FIFO. Rather than have it poll for them, it can set up to [`WATCHES`]
+//! watches, each of which compares a field of some register - a `u8`,
+//! `u16le` or `u32le` at an offset into it - with a threshold. We read the
+//! watched registers every few milliseconds, when we update the interrupts.
+//!
+//! When a watch's comparison becomes true, its *tripped* bit is set, and the
+//! Watch Triggered interrupt is raised. Whilst the tripped bit is set the
+//! watch can't trip again, so a value wobbling about the threshold raises one
+//! interrupt until the host clears the bit. After that, the comparison has to
+//! go false and then true again.
+//!
+//! The configuration is written a byte at a time, so writing any byte but the
+//! comparison turns the watch off - the host writes the comparison last, and
+//! a half-written watch never trips.
+
+/// How many watches there are
+pub const WATCHES: usize = 4;
+
+/// The width of the field, in the top two bits of the field byte
+const FIELD_WIDTH_SHIFT: u32 = 6;
+
+/// The byte offset of the field, in the bottom six bits of the field byte
+const FIELD_OFFSET_MASK: u8 = 0x3F;
+
+/// Where the comparison goes in a watch's configuration bytes
+const COMPARISON_OFFSET: usize = Watch::SIZE - 1;
+
+/// How a watched value is compared with the threshold.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Comparison {
+	/// The watch is turned off
+	Off,
+	/// Trips when the value is below the threshold
+	Below,
+	/// Trips when the value is above the threshold
+	Above,
+	/// Trips when the value equals the threshold
+	Equal,
+	/// Trips when the value doesn't equal the threshold
+	NotEqual,
+}
+
+impl Comparison {
+	/// Decode a comparison from its byte in the Watch Config register.
+	pub fn from_byte(byte: u8) -> Option<Comparison> {
+		match byte {
+			0 => Some(Comparison::Off),
+			1 => Some(Comparison::Below),
+			2 => Some(Comparison::Above),
+			3 => Some(Comparison::Equal),
+			4 => Some(Comparison::NotEqual),
+			_ => None,
+		}
+	}
+
+	/// Encode a comparison as its byte in the Watch Config register.
+	pub fn as_byte(self) -> u8 {
+		match self {
+			Comparison::Off => 0,
+			Comparison::Below => 1,
+			Comparison::Above => 2,
+			Comparison::Equal => 3,
+			Comparison::NotEqual => 4,
+		}
+	}
+
+	/// Is the comparison true for this value? It never is when the watch is
+	/// off.
+	pub fn is_true(self, value: u32, threshold: u32) -> bool {
+		match self {
+			Comparison::Off => false,
+			Comparison::Below => value < threshold,
+			Comparison::Above => value > threshold,
+			Comparison::Equal => value == threshold,
+			Comparison::NotEqual => value != threshold,
+		}
+	}
+}
+
+/// One watch's configuration.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Watch {
+	/// The address of the register to read
+	pub register: u8,
+	/// The field's width (top two bits: 0 is `u8`, 1 is `u16le` and 2 is
+	/// `u32le`) and byte offset (bottom six bits)
+	pub field: u8,
+	/// The value to compare the field with
+	pub threshold: u32,
+	/// How to compare them
+	pub comparison: Comparison,
+}
+
+impl Watch {
+	/// How many bytes a watch takes in the Watch Config register.
+	pub const SIZE: usize = 7;
+
+	/// A watch which is turned off.
+	pub const OFF: Watch = Watch {
+		register: 0,
+		field: 0,
+		threshold: 0,
+		comparison: Comparison::Off,
+	};
+
+	/// Convert to bytes for the Watch Config register.
+	///
+	/// That's the register, the field, the threshold as a `u32le`, and then
+	/// the comparison.
+	pub fn as_bytes(&self) -> [u8; Self::SIZE] {
+		let mut bytes = [0u8; Self::SIZE];
+		bytes[0] = self.register;
+		bytes[1] = self.field;
+		bytes[2..6].copy_from_slice(&self.threshold.to_le_bytes());
+		bytes[COMPARISON_OFFSET] = self.comparison.as_byte();
+		bytes
+	}
+
+	/// This watch, with one byte of its configuration changed.
+	///
+	/// Changing anything but the comparison turns the watch off. Returns
+	/// `None` if the comparison isn't one we know.
+	pub fn with_byte(&self, offset: usize, byte: u8) -> Option<Watch> {
+		let mut bytes = self.as_bytes();
+		bytes[offset] = byte;
+		if offset != COMPARISON_OFFSET {
+			bytes[COMPARISON_OFFSET] = Comparison::Off.as_byte();
+		}
+		Some(Watch {
+			register: bytes[0],
+			field: bytes[1],
+			threshold: u32::from_le_bytes([bytes[2], bytes[3], bytes[4], bytes[5]]),
+			comparison: Comparison::from_byte(bytes[COMPARISON_OFFSET])?,
+		})
+	}
+
+	/// How many bytes the field takes, if its width is one we know.
+	pub fn width(&self) -> Option<usize> {
+		match self.field >> FIELD_WIDTH_SHIFT {
+			0 => Some(1),
+			1 => Some(2),
+			2 => Some(4),
+			_ => None,
+		}
+	}
+
+	/// How far into the register the field starts, in bytes.
+	pub fn offset(&self) -> usize {
+		usize::from(self.field & FIELD_OFFSET_MASK)
+	}
+
+	/// Does the field fit in a register read of `length` bytes?
+	pub fn fits(&self, length: usize) -> bool {
+		self.width()
+			.map(|width| self.offset() + width <= length)
+			.unwrap_or(false)
+	}
+
+	/// Pick the field out of the bytes read from the register.
+	pub fn value(&self, bytes: &[u8]) -> Option<u32> {
+		let width = self.width()?;
+		let field = bytes.get(self.offset()..self.offset() + width)?;
+		let mut value = [0u8; 4];
+		value[0..width].copy_from_slice(field);
+		Some(u32::from_le_bytes(value))
+	}
+}
+
+/// The watches, and which of them have tripped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watches {
+	/// Each watch's configuration
+	watches: [Watch; WATCHES],
+	/// The watch the Watch Config register shows
+	selected: usize,
+	/// Bit N is set if watch N's comparison was true when we last looked
+	met: u8,
+	/// Bit N is set if watch N has tripped since the host cleared it
+	tripped: u8,
+}
+
+impl Watches {
+	/// Create the watches, all turned off.
+	pub const fn new() -> Watches {
+		Watches {
+			watches: [Watch::OFF; WATCHES],
+			selected: 0,
+			met: 0,
+			tripped: 0,
+		}
+	}
+
+	/// Which watch the Watch Config register shows.
+	pub fn selected(&self) -> usize {
+		self.selected
+	}
+
+	/// Choose which watch the Watch Config register shows. Returns `false`
+	/// (and changes nothing) if there's no such watch.
+	pub fn select(&mut self, index: usize) -> bool {
+		if index >= WATCHES {
+			return false;
+		}
+		self.selected = index;
+		true
+	}
+
+	/// A watch's configuration.
+	pub fn get(&self, index: usize) -> Watch {
+		self.watches[index]
+	}
+
+	/// Change a watch's configuration.
+	///
+	/// Its comparison starts off false, so it trips if it is true the next
+	/// time we look.
+	pub fn set(&mut self, index: usize, watch: Watch) {
+		self.watches[index] = watch;
+		self.met &= !(1 << index);
+	}
+
+	/// Note the value a watch's field has now, or `None` if we couldn't read
+	/// it. Returns `true` if the watch has just tripped.
+	pub fn sample(&mut self, index: usize, value: Option<u32>) -> bool {
+		let watch = &self.watches[index];
+		let bit = 1 << index;
+		let met = value
+			.map(|value| watch.comparison.is_true(value, watch.threshold))
+			.unwrap_or(false);
+		let was_met = (self.met & bit) != 0;
+		if met {
+			self.met |= bit;
+		} else {
+			self.met &= !bit;
+		}
+		if met && !was_met && (self.tripped & bit) == 0 {
+			self.tripped |= bit;
+			true
+		} else {
+			false
+		}
+	}
+
+	/// The Watch Status register: which watches' comparisons are true in the
+	/// bottom four bits, and which have tripped in the top four.
+	pub fn status(&self) -> u8 {
+		self.met | (self.tripped << WATCHES)
+	}
+
+	/// Clear the tripped bits which are set in a write to the Watch Status
+	/// register. The bottom four bits are ignored.
+	pub fn clear_tripped(&mut self, bits: u8) {
+		self.tripped &= !(bits >> WATCHES);
+	}
+}
+
+impl Default for Watches {
+	fn default() -> Self {
+		Watches::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn config() {
+		// The 5V rail (the first Analog Input Reading) below 4750 mV
+		let watch = Watch::OFF
+			.with_byte(0, 0xB3)
+			.and_then(|w| w.with_byte(1, 0x40))
+			.and_then(|w| w.with_byte(2, 0x8E))
+			.and_then(|w| w.with_byte(3, 0x12))
+			.and_then(|w| w.with_byte(6, 1))
+			.unwrap();
+		assert_eq!(
+			watch,
+			Watch {
+				register: 0xB3,
+				field: 0x40,
+				threshold: 4750,
+				comparison: Comparison::Below,
+			}
+		);
+		assert_eq!(watch.as_bytes(), [0xB3, 0x40, 0x8E, 0x12, 0, 0, 1]);
+		assert_eq!(watch.width(), Some(2));
+		assert!(watch.fits(4));
+		assert!(!watch.fits(1));
+		assert_eq!(watch.value(&[0x90, 0x12, 0x00, 0x00]), Some(4752));
+		// Any other byte turns it off
+		assert_eq!(watch.with_byte(5, 0).unwrap().comparison, Comparison::Off);
+		assert_eq!(watch.with_byte(6, 5), None);
+		// The second u16 in the register, and a width we don't know
+		let second = Watch {
+			field: 0x42,
+			..watch
+		};
+		assert_eq!(second.value(&[0, 0, 0x34, 0x12]), Some(0x1234));
+		assert!(!second.fits(3));
+		assert_eq!(
+			Watch {
+				field: 0xC0,
+				..watch
+			}
+			.width(),
+			None
+		);
+	}
+
+	#[test]
+	fn tripping() {
+		let mut watches = Watches::new();
+		assert_eq!(watches.get(1), Watch::OFF);
+		assert!(!watches.sample(1, Some(0)));
+		// UART RX Count above 32
+		watches.set(
+			1,
+			Watch {
+				register: 0x35,
+				field: 0x40,
+				threshold: 32,
+				comparison: Comparison::Above,
+			},
+		);
+		assert!(!watches.sample(1, Some(32)));
+		assert_eq!(watches.status(), 0);
+		assert!(watches.sample(1, Some(33)));
+		assert_eq!(watches.status(), 0b0010_0010);
+		// Only once, even if it goes false and true again
+		assert!(!watches.sample(1, Some(40)));
+		assert!(!watches.sample(1, Some(0)));
+		assert!(!watches.sample(1, Some(40)));
+		// Clearing it whilst it's still true doesn't trip it again
+		watches.clear_tripped(0xFF);
+		assert_eq!(watches.status(), 0b0000_0010);
+		assert!(!watches.sample(1, Some(40)));
+		// Until it goes false, and then true
+		assert!(!watches.sample(1, None));
+		assert!(watches.sample(1, Some(40)));
+		// Changing the watch checks it afresh
+		watches.clear_tripped(0xF0);
+		watches.set(1, watches.get(1));
+		assert!(watches.sample(1, Some(40)));
+		// Choosing a watch
+		assert!(watches.select(3));
+		assert!(!watches.select(4));
+		assert_eq!(watches.selected(), 3);
+	}
+}
@@ -0,0 +1,210 @@
+//! # Host Watchdog
+//!
+//! The host's OS can ask us to watch it. Once the Host Watchdog Timeout
+//! register is set, the host has to write to the Host Watchdog Kick register
+//! at least that often, or we reset the main board, as if the reset button
+//! had been pressed.
+//!
+//! A reset loses whatever state the OS had, which makes a hang hard to debug
+//! afterwards. So the host can also set a *pretimeout* - that many seconds
+//! before the reset, we raise the Watchdog Pretimeout interrupt, and the OS
+//! gets one last chance to dump its state (or to kick the watchdog, if it
+//! was only slow).
+//!
+//! The watchdog stops when it fires, and when the main board is powered off,
+//! so the next OS to boot has to start it again. The task which calls
+//! [`HostWatchdog::update`] picks up kicks every `POLL_INTERVAL_MS` or so.
+
+/// How often the caller should call [`HostWatchdog::update`]
+pub const POLL_INTERVAL_MS: u32 = 100;
+
+/// What the caller should do after an update.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WatchdogAction {
+	/// Nothing to do
+	None,
+	/// Raise the Watchdog Pretimeout interrupt
+	Pretimeout,
+	/// The host didn't kick the watchdog in time, so reset the main board
+	Reset,
+}
+
+/// The host's watchdog settings, and how long it has left.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostWatchdog {
+	/// How long the host has between kicks, in seconds (zero when stopped)
+	timeout_s: u8,
+	/// How long before the reset we raise the interrupt, in seconds (zero for
+	/// no interrupt)
+	pretimeout_s: u8,
+	/// The host has started or kicked the watchdog since the last update
+	kicked: bool,
+	/// When the watchdog was last kicked, if it is running
+	kicked_ms: Option<u32>,
+	/// Have we raised the interrupt since the last kick?
+	warned: bool,
+	/// How long was left at the last update, rounded up to whole seconds
+	remaining_s: u8,
+}
+
+impl HostWatchdog {
+	/// Create the watchdog, stopped.
+	pub const fn new() -> HostWatchdog {
+		HostWatchdog {
+			timeout_s: 0,
+			pretimeout_s: 0,
+			kicked: false,
+			kicked_ms: None,
+			warned: false,
+			remaining_s: 0,
+		}
+	}
+
+	/// How long the host has between kicks, in seconds, or zero if the
+	/// watchdog is stopped.
+	pub fn timeout_s(&self) -> u8 {
+		self.timeout_s
+	}
+
+	/// Start the watchdog with a new timeout, or stop it with zero.
+	///
+	/// Returns false, and changes nothing, if the pretimeout wouldn't be
+	/// shorter than the timeout.
+	pub fn set_timeout(&mut self, timeout_s: u8) -> bool {
+		if timeout_s != 0 && self.pretimeout_s >= timeout_s {
+			return false;
+		}
+		self.timeout_s = timeout_s;
+		self.kicked = timeout_s != 0;
+		true
+	}
+
+	/// How long before the reset the interrupt is raised, in seconds.
+	pub fn pretimeout_s(&self) -> u8 {
+		self.pretimeout_s
+	}
+
+	/// Set how long before the reset the interrupt is raised, or turn it off
+	/// with zero.
+	///
+	/// Returns false, and changes nothing, if it wouldn't be shorter than a
+	/// running watchdog's timeout.
+	pub fn set_pretimeout(&mut self, pretimeout_s: u8) -> bool {
+		if self.timeout_s != 0 && pretimeout_s >= self.timeout_s {
+			return false;
+		}
+		self.pretimeout_s = pretimeout_s;
+		true
+	}
+
+	/// The host is still alive. Does nothing if the watchdog is stopped.
+	pub fn kick(&mut self) {
+		if self.timeout_s != 0 {
+			self.kicked = true;
+		}
+	}
+
+	/// How long the host had left at the last update, rounded up to whole
+	/// seconds, or zero if the watchdog is stopped.
+	pub fn remaining_s(&self) -> u8 {
+		self.remaining_s
+	}
+
+	/// Has the pretimeout passed, without a kick since?
+	pub fn in_pretimeout(&self) -> bool {
+		self.warned && self.kicked_ms.is_some()
+	}
+
+	/// Move the watchdog on, and pick up any kick. Call this every
+	/// `POLL_INTERVAL_MS`.
+	pub fn update(&mut self, now_ms: u32, powered_on: bool) -> WatchdogAction {
+		if !powered_on {
+			self.timeout_s = 0;
+		}
+		if self.timeout_s == 0 {
+			self.kicked = false;
+			self.kicked_ms = None;
+			self.remaining_s = 0;
+			return WatchdogAction::None;
+		}
+		if core::mem::replace(&mut self.kicked, false) || self.kicked_ms.is_none() {
+			self.kicked_ms = Some(now_ms);
+			self.warned = false;
+		}
+		let elapsed_ms = now_ms.wrapping_sub(self.kicked_ms.unwrap_or(now_ms));
+		let timeout_ms = u32::from(self.timeout_s) * 1000;
+		if elapsed_ms >= timeout_ms {
+			self.timeout_s = 0;
+			self.kicked_ms = None;
+			self.remaining_s = 0;
+			return WatchdogAction::Reset;
+		}
+		let left_ms = timeout_ms - elapsed_ms;
+		self.remaining_s = left_ms.div_ceil(1000) as u8;
+		if !self.warned && self.pretimeout_s != 0 && left_ms <= u32::from(self.pretimeout_s) * 1000
+		{
+			self.warned = true;
+			return WatchdogAction::Pretimeout;
+		}
+		WatchdogAction::None
+	}
+}
+
+impl Default for HostWatchdog {
+	fn default() -> Self {
+		HostWatchdog::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn pretimeout_then_reset() {
+		let mut watchdog = HostWatchdog::new();
+		assert_eq!(watchdog.update(0, true), WatchdogAction::None);
+		assert!(watchdog.set_pretimeout(2));
+		// The pretimeout must be shorter than the timeout
+		assert!(!watchdog.set_timeout(2));
+		assert!(watchdog.set_timeout(5));
+		assert!(!watchdog.set_pretimeout(5));
+		assert_eq!(watchdog.update(1000, true), WatchdogAction::None);
+		assert_eq!(watchdog.remaining_s(), 5);
+		// Kicked in time
+		watchdog.kick();
+		assert_eq!(watchdog.update(5000, true), WatchdogAction::None);
+		assert_eq!(watchdog.update(7900, true), WatchdogAction::None);
+		assert_eq!(watchdog.remaining_s(), 3);
+		// Two seconds to go
+		assert_eq!(watchdog.update(8000, true), WatchdogAction::Pretimeout);
+		assert_eq!(watchdog.update(8100, true), WatchdogAction::None);
+		assert_eq!(watchdog.update(10_000, true), WatchdogAction::Reset);
+		// It stops once it has fired
+		assert_eq!(watchdog.timeout_s(), 0);
+		assert_eq!(watchdog.update(20_000, true), WatchdogAction::None);
+		watchdog.kick();
+		assert_eq!(watchdog.update(30_000, true), WatchdogAction::None);
+	}
+
+	#[test]
+	fn kick_after_pretimeout() {
+		let mut watchdog = HostWatchdog::new();
+		assert!(watchdog.set_timeout(3));
+		assert!(watchdog.set_pretimeout(1));
+		assert_eq!(watchdog.update(0, true), WatchdogAction::None);
+		assert!(!watchdog.in_pretimeout());
+		assert_eq!(watchdog.update(2000, true), WatchdogAction::Pretimeout);
+		assert!(watchdog.in_pretimeout());
+		// The OS got its chance, and took it
+		watchdog.kick();
+		assert_eq!(watchdog.update(2100, true), WatchdogAction::None);
+		assert!(!watchdog.in_pretimeout());
+		assert_eq!(watchdog.update(4100, true), WatchdogAction::Pretimeout);
+		// Powering off stops it
+		assert_eq!(watchdog.update(4200, false), WatchdogAction::None);
+		assert!(!watchdog.in_pretimeout());
+		assert_eq!(watchdog.timeout_s(), 0);
+		assert_eq!(watchdog.update(9000, true), WatchdogAction::None);
+	}
+}
@@ -0,0 +1,10 @@
+//! Prints the NBMC register map as JSON.
+//!
+//! Run `cargo run --example register_map > registers.json` to update the
+//! copy in this crate after changing the register map.
+
+fn main() {
+	let mut json = String::new();
+	neotron_bmc_protocol::registers::write_json(&mut json).unwrap();
+	print!("{}", json);
+}
@@ -0,0 +1,23 @@
+//! Feeds arbitrary bytes to every decoder in the protocol crate.
+//!
+//! Run with `cargo +nightly fuzz run from_bytes`.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use neotron_bmc_protocol::{BuildInfo, ProtocolVersion, Receivable, Request, Response, Sendable};
+
+fuzz_target!(|data: &[u8]| {
+	if let Ok(req) = Request::from_bytes(data) {
+		// Anything we accept must re-encode to the same bytes
+		assert_eq!(&req.as_bytes()[..], &data[0..4]);
+	}
+	if let Ok(rsp) = Response::from_bytes(data) {
+		let mut buffer = [0u8; 512];
+		if let Ok(len) = rsp.render_to_buffer(&mut buffer) {
+			assert_eq!(&buffer[0..len], data);
+		}
+	}
+	let _ = ProtocolVersion::from_bytes(data);
+	let _ = BuildInfo::from_bytes(data);
+});
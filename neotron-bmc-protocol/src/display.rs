@@ -0,0 +1,295 @@
+//! Human-readable formatting for the protocol types.
+//!
+//! Host-side tools want to print what went over the wire, and to pass
+//! [`Error`] up with `?`. Everything here only needs `core`, so it costs the
+//! firmware nothing unless it is used. With the `std` feature, [`Error`] also
+//! implements `std::error::Error`, so it works with `anyhow` and friends.
+//!
+//! ```
+//! # use neotron_bmc_protocol::{display::HexDump, Request, Response, ResponseResult};
+//! let req = Request::new_read(false, 0x72, 2);
+//! assert_eq!(req.to_string(), "Read 0x72 (Fan Speed), 2 bytes");
+//! let rsp = Response::new_ok_with_data(&[0xDC, 0x05]);
+//! assert_eq!(rsp.to_string(), "OK [DC 05]");
+//! let rsp = Response::new_without_data(ResponseResult::BadLength);
+//! assert_eq!(rsp.to_string(), "Bad Length");
+//! assert_eq!(HexDump(&req.as_bytes()).to_string(), "C0 72 02 0B");
+//! ```
+
+use core::fmt;
+
+use crate::{registers, Error, Request, RequestType, Response, ResponseResult, Width};
+
+/// Prints bytes in hex.
+///
+/// Normally the bytes are printed on one line, separated by spaces. The
+/// alternate form (`{:#}`) prints them like `hexdump -C` does - sixteen to a
+/// line, after the offset and followed by the printable characters - for
+/// longer payloads.
+///
+/// ```
+/// # use neotron_bmc_protocol::display::HexDump;
+/// assert_eq!(format!("{}", HexDump(&[0x00, 0x41, 0xFF])), "00 41 FF");
+/// assert_eq!(
+///     format!("{:#}", HexDump(b"Neotron\x00")),
+///     "0000  4E 65 6F 74 72 6F 6E 00                          |Neotron.|\n"
+/// );
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct HexDump<'a>(pub &'a [u8]);
+
+impl<'a> fmt::Display for HexDump<'a> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		if !f.alternate() {
+			for (idx, byte) in self.0.iter().enumerate() {
+				if idx != 0 {
+					f.write_str(" ")?;
+				}
+				write!(f, "{:02X}", byte)?;
+			}
+			return Ok(());
+		}
+		for (line, chunk) in self.0.chunks(16).enumerate() {
+			write!(f, "{:04X} ", line * 16)?;
+			for idx in 0..16 {
+				match chunk.get(idx) {
+					Some(byte) => write!(f, " {:02X}", byte)?,
+					None => f.write_str("   ")?,
+				}
+			}
+			f.write_str("  |")?;
+			for &byte in chunk {
+				let ch = if byte.is_ascii_graphic() || byte == b' ' {
+					char::from(byte)
+				} else {
+					'.'
+				};
+				write!(f, "{}", ch)?;
+			}
+			f.write_str("|\n")?;
+		}
+		Ok(())
+	}
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			Error::BadCrc => "bad CRC",
+			Error::BadLength => "bad length",
+			Error::BadRequestType => "unknown request type",
+			Error::BufferTooSmall => "buffer too small",
+			Error::BadResponseResult => "unknown response result",
+		})
+	}
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+impl RequestType {
+	/// The name of this kind of [`Request`], without saying whether it is
+	/// the alternate.
+	pub const fn name(&self) -> &'static str {
+		match self {
+			RequestType::Read | RequestType::ReadAlt => "Read",
+			RequestType::ShortWrite | RequestType::ShortWriteAlt => "Short Write",
+			RequestType::LongWrite | RequestType::LongWriteAlt => "Long Write",
+			RequestType::SetBits | RequestType::SetBitsAlt => "Set Bits",
+			RequestType::ClearBits | RequestType::ClearBitsAlt => "Clear Bits",
+			RequestType::BulkReadStart | RequestType::BulkReadStartAlt => "Bulk Read Start",
+			RequestType::BulkReadContinue | RequestType::BulkReadContinueAlt => {
+				"Bulk Read Continue"
+			}
+			RequestType::WideRead | RequestType::WideReadAlt => "Wide Read",
+			RequestType::BulkWriteStart | RequestType::BulkWriteStartAlt => "Bulk Write Start",
+			RequestType::BulkWriteContinue | RequestType::BulkWriteContinueAlt => {
+				"Bulk Write Continue"
+			}
+		}
+	}
+}
+
+impl fmt::Display for RequestType {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		// The alternates are all odd
+		if (*self as u8) & 1 != 0 {
+			write!(f, "{} (alternate)", self.name())
+		} else {
+			f.write_str(self.name())
+		}
+	}
+}
+
+impl fmt::Display for Width {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			Width::U8 => "u8",
+			Width::U16 => "u16",
+			Width::U32 => "u32",
+		})
+	}
+}
+
+impl fmt::Display for ResponseResult {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			ResponseResult::Ok => "OK",
+			ResponseResult::CrcFailure => "CRC Failure",
+			ResponseResult::BadRequestType => "Bad Request Type",
+			ResponseResult::BadRegister => "Bad Register",
+			ResponseResult::BadLength => "Bad Length",
+			ResponseResult::Busy => "Busy",
+			ResponseResult::Locked => "Locked",
+			ResponseResult::NotReady => "Not Ready",
+		})
+	}
+}
+
+/// Prints a register address, and its name if it has one.
+struct RegisterName(u8);
+
+impl fmt::Display for RegisterName {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match registers::find(self.0) {
+			Some(register) => write!(f, "0x{:02X} ({})", self.0, register.name),
+			None => write!(f, "0x{:02X}", self.0),
+		}
+	}
+}
+
+/// Prints a number of bytes, like `1 byte` or `4 bytes`.
+struct ByteCount(u8);
+
+impl fmt::Display for ByteCount {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self.0 {
+			1 => f.write_str("1 byte"),
+			n => write!(f, "{} bytes", n),
+		}
+	}
+}
+
+impl fmt::Display for Request {
+	/// Prints what the [`Request`] asks for, like `Short Write 0x11
+	/// (Interrupt Control) = 0x04`.
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let name = self.request_type.name();
+		let register = RegisterName(self.register);
+		let value = self.length_or_data;
+		match self.request_type {
+			RequestType::ShortWrite | RequestType::ShortWriteAlt => {
+				write!(f, "{} {} = 0x{:02X}", name, register, value)
+			}
+			RequestType::SetBits
+			| RequestType::SetBitsAlt
+			| RequestType::ClearBits
+			| RequestType::ClearBitsAlt => {
+				write!(f, "{} {}, mask 0x{:02X}", name, register, value)
+			}
+			RequestType::BulkReadContinue
+			| RequestType::BulkReadContinueAlt
+			| RequestType::BulkWriteContinue
+			| RequestType::BulkWriteContinueAlt => {
+				// The register field holds the offset
+				write!(
+					f,
+					"{}, offset {}, {}",
+					name,
+					self.register,
+					ByteCount(value)
+				)
+			}
+			RequestType::WideRead | RequestType::WideReadAlt => match self.wide_read_shape() {
+				Some((width, count)) => {
+					write!(f, "{} {}, {} x {}", name, register, count, width)
+				}
+				None => write!(f, "{} {}, shape 0x{:02X}", name, register, value),
+			},
+			_ => write!(f, "{} {}, {}", name, register, ByteCount(value)),
+		}
+	}
+}
+
+impl<'a> fmt::Display for Response<'a> {
+	/// Prints the result, and the data in hex if there is any.
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.result)?;
+		if self.data.is_empty() && self.data_tail.is_empty() {
+			return Ok(());
+		}
+		f.write_str(" [")?;
+		for (idx, byte) in self.data.iter().chain(self.data_tail.iter()).enumerate() {
+			if idx != 0 {
+				f.write_str(" ")?;
+			}
+			write!(f, "{:02X}", byte)?;
+		}
+		f.write_str("]")
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn requests() {
+		assert_eq!(
+			Request::new_short_write(true, 0x11, 0x04).to_string(),
+			"Short Write 0x11 (Interrupt Control) = 0x04"
+		);
+		assert_eq!(
+			Request::new_clear_bits(false, 0x11, 0x04).to_string(),
+			"Clear Bits 0x11 (Interrupt Control), mask 0x04"
+		);
+		assert_eq!(
+			Request::new_bulk_read_continue(false, 60, 40).to_string(),
+			"Bulk Read Continue, offset 60, 40 bytes"
+		);
+		assert_eq!(
+			Request::new_bulk_write_continue(true, 0, 1).to_string(),
+			"Bulk Write Continue, offset 0, 1 byte"
+		);
+		assert_eq!(
+			Request::new_wide_read(false, 0x18, Width::U32, 4).to_string(),
+			"Wide Read 0x18 (Lifetime Counters), 4 x u32"
+		);
+		// Not a register
+		assert_eq!(
+			Request::new_read(false, 0x7F, 1).to_string(),
+			"Read 0x7F, 1 byte"
+		);
+		assert_eq!(RequestType::ReadAlt.to_string(), "Read (alternate)");
+		assert_eq!(RequestType::BulkReadStart.to_string(), "Bulk Read Start");
+	}
+
+	#[test]
+	fn responses() {
+		let data = [0x00, 0x01, 0x02];
+		let split = Response::new_ok_with_segments(&data[0..1], &data[1..]);
+		assert_eq!(split.to_string(), "OK [00 01 02]");
+		assert_eq!(ResponseResult::NotReady.to_string(), "Not Ready");
+	}
+
+	#[test]
+	fn hexdump() {
+		let bytes: Vec<u8> = (0x3E..0x50).collect();
+		assert_eq!(
+			format!("{:#}", HexDump(&bytes)),
+			"0000  3E 3F 40 41 42 43 44 45 46 47 48 49 4A 4B 4C 4D  |>?@ABCDEFGHIJKLM|\n\
+			 0010  4E 4F                                            |NO|\n"
+		);
+		assert_eq!(HexDump(&[]).to_string(), "");
+	}
+
+	#[cfg(feature = "std")]
+	#[test]
+	fn error_trait() {
+		fn decode() -> Result<Request, Box<dyn std::error::Error>> {
+			use crate::Receivable;
+			Ok(Request::from_bytes(&[0xC0, 0x11, 0x03, 0x00])?)
+		}
+		assert_eq!(decode().unwrap_err().to_string(), "bad CRC");
+	}
+}
@@ -1,5 +1,5 @@
 #![doc = include_str!("../README.md")]
-#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
 
 // ============================================================================
 // Modules and Imports
@@ -8,6 +8,24 @@
 use defmt::Format;
 
 mod crc;
+pub mod display;
+pub mod registers;
+pub mod values;
+pub mod vectors;
+
+// ============================================================================
+// Constants
+// ============================================================================
+
+/// The byte the NBMC sends when it has nothing else to send - whilst the
+/// [`Request`] arrives, and during the Turn-Around - unless the *Host* picks
+/// another with the SPI Padding Byte register.
+pub const PADDING_BYTE: u8 = 0xFF;
+
+/// The byte the NBMC sends just before each [`Response`], if the *Host* turns
+/// on the SPI Sync Marker register, so the *Host* can tell exactly where the
+/// [`Response`] starts.
+pub const SYNC_BYTE: u8 = 0x5A;
 
 // ============================================================================
 // Traits
@@ -15,10 +33,48 @@ mod crc;
 
 /// Marks an object as being sendable over a byte-oriented communications link.
 pub trait Sendable {
+	/// How many bytes this object occupies when rendered.
+	fn rendered_len(&self) -> usize;
+
+	/// Convert to bytes for transmission, without copying.
+	///
+	/// Calls `f` with each contiguous block of bytes in turn, in the order
+	/// they should be sent. Useful for feeding a transmit register directly,
+	/// or for payloads which live in two halves of a ring buffer.
+	fn render_segments(&self, f: &mut dyn FnMut(&[u8]));
+
 	/// Convert to bytes for transmission.
 	///
 	/// Copies into the given buffer, giving an error if it isn't large enough.
-	fn render_to_buffer(&self, buffer: &mut [u8]) -> Result<usize, Error>;
+	///
+	/// ```
+	/// # use neotron_bmc_protocol::{Response, ResponseResult, Sendable};
+	/// let mut buffer = [0u8; 5];
+	///
+	/// let req = Response::new_ok_with_data(&[]);
+	/// assert_eq!(req.render_to_buffer(&mut buffer).unwrap(), 2);
+	/// assert_eq!(&buffer[0..=1], [0xA0, 0x69]);
+	///
+	/// let req = Response::new_ok_with_data(&[0x00, 0x01]);
+	/// assert_eq!(req.render_to_buffer(&mut buffer).unwrap(), 4);
+	/// assert_eq!(&buffer[0..=3], [0xA0, 0x00, 0x01, 0x4F]);
+	///
+	/// let req = Response::new_without_data(ResponseResult::BadRequestType);
+	/// assert_eq!(req.render_to_buffer(&mut buffer).unwrap(), 2);
+	/// assert_eq!(&buffer[0..=1], [0xA2, 0x67]);
+	/// ```
+	fn render_to_buffer(&self, buffer: &mut [u8]) -> Result<usize, Error> {
+		let len = self.rendered_len();
+		if buffer.len() < len {
+			return Err(Error::BufferTooSmall);
+		}
+		let mut idx = 0;
+		self.render_segments(&mut |segment| {
+			buffer[idx..idx + segment.len()].copy_from_slice(segment);
+			idx += segment.len();
+		});
+		Ok(len)
+	}
 }
 
 /// Marks an object as being receivable over a byte-oriented communications link.
@@ -35,6 +91,7 @@ pub trait Receivable<'a>: Sized {
 
 /// The ways this API can fail
 #[derive(Debug, Copy, Clone, Format, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Error {
 	BadCrc,
 	BadLength,
@@ -46,6 +103,7 @@ pub enum Error {
 /// The kinds of [`Request`] the *Host* can make to the NBMC
 #[repr(u8)]
 #[derive(Debug, Copy, Clone, Format, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum RequestType {
 	Read = 0xC0,
 	ReadAlt = 0xC1,
@@ -53,11 +111,42 @@ pub enum RequestType {
 	ShortWriteAlt = 0xC3,
 	LongWrite = 0xC4,
 	LongWriteAlt = 0xC5,
+	SetBits = 0xC6,
+	SetBitsAlt = 0xC7,
+	ClearBits = 0xC8,
+	ClearBitsAlt = 0xC9,
+	BulkReadStart = 0xCA,
+	BulkReadStartAlt = 0xCB,
+	BulkReadContinue = 0xCC,
+	BulkReadContinueAlt = 0xCD,
+	WideRead = 0xCE,
+	WideReadAlt = 0xCF,
+	BulkWriteStart = 0xD0,
+	BulkWriteStartAlt = 0xD1,
+	BulkWriteContinue = 0xD2,
+	BulkWriteContinueAlt = 0xD3,
+}
+
+/// How many bytes each value in a *Wide Read* takes up.
+///
+/// It goes in the top two bits of the *Shape* byte of a *Wide Read*
+/// [`Request`], above the number of values.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, Format, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Width {
+	/// 8-bit values
+	U8 = 0,
+	/// 16-bit values, little-endian
+	U16 = 1,
+	/// 32-bit values, little-endian
+	U32 = 2,
 }
 
 /// The NBMC returns this code to indicate whether the previous [`Request`] was
 /// succesful or not.
 #[derive(Debug, Copy, Clone, Format, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ResponseResult {
 	/// The [`Request`] was correctly understood and actioned.
 	Ok = 0xA0,
@@ -78,6 +167,26 @@ pub enum ResponseResult {
 	///
 	/// Did you check the Protocol Version was supported?
 	BadLength = 0xA4,
+	/// The [`Request`] was received correctly, but it started an operation
+	/// which takes longer than one transaction, so there is no result yet.
+	///
+	/// The NBMC gives each such operation a token. Poll the NBMC (or wait for
+	/// its interrupt) to find out when the operation has finished, and what
+	/// its result was.
+	Busy = 0xA5,
+	/// The [`Request`] was received correctly, but it would write to a
+	/// register which can't be changed whilst the configuration is locked.
+	///
+	/// Unlock the configuration (see the Configuration Lock register) and try
+	/// again.
+	Locked = 0xA6,
+	/// The [`Request`] was received correctly, but the NBMC is still starting
+	/// up, so it can't be answered yet.
+	///
+	/// Only reads of the `registers::EARLY` registers are answered whilst the
+	/// NBMC starts up. Poll the BMC Status register until it says the NBMC is
+	/// ready, then try again.
+	NotReady = 0xA7,
 }
 
 // ============================================================================
@@ -86,6 +195,7 @@ pub enum ResponseResult {
 
 /// A *Request* made by the *Host* to the *NBMC*
 #[derive(Debug, Clone, Format, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Request {
 	pub request_type: RequestType,
 	pub register: u8,
@@ -94,9 +204,28 @@ pub struct Request {
 }
 
 /// A *Response* sent by the *NBMC* in reply to a [`Request`] from a *Host*
-#[derive(Debug, Clone, Format, PartialEq, Eq)]
+///
+/// Two responses are equal if they would be sent as the same bytes, however
+/// their payload is split between `data` and `data_tail`.
+#[derive(Debug, Clone, Format, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Response<'a> {
 	pub result: ResponseResult,
+	pub data: &'a [u8],
+	/// More payload, sent straight after `data`. Only used when sending a
+	/// payload which has been split in two (e.g. it wraps around the end of a
+	/// ring buffer). It is always empty in a received [`Response`].
+	pub data_tail: &'a [u8],
+	crc: u8,
+}
+
+/// The bytes the *Host* sends after a *Long Write* or *Bulk Write Continue*
+/// [`Request`] has been answered *OK*, followed by their CRC.
+///
+/// See [`Request::payload_len`] for how many bytes to expect.
+#[derive(Debug, Clone, Format, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Payload<'a> {
 	pub data: &'a [u8],
 	crc: u8,
 }
@@ -104,12 +233,34 @@ pub struct Response<'a> {
 /// Describes the [semantic version](https://semver.org) of this implementation
 /// of the NBMC interface.
 #[derive(Debug, Copy, Clone, Format, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ProtocolVersion {
 	major: u8,
 	minor: u8,
 	patch: u8,
 }
 
+/// Describes the firmware running on the NBMC.
+///
+/// This is transferred as a fixed-size block of [`BuildInfo::SIZE`] bytes,
+/// the last of which is the CRC-8 of all the preceeding bytes.
+#[derive(Debug, Copy, Clone, Format, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BuildInfo {
+	/// The firmware's semantic version, as `[major, minor, patch]`
+	pub firmware_version: [u8; 3],
+	/// The version of this protocol the firmware implements
+	pub protocol_version: ProtocolVersion,
+	/// Information about how the firmware was built (see `BuildInfo::FLAG_DIRTY`, etc)
+	pub build_flags: u8,
+	/// The first eight bytes of the git commit hash the firmware was built from
+	pub git_hash: [u8; 8],
+	/// When the firmware was built, in seconds since the Unix epoch
+	pub build_timestamp: u32,
+	/// Which optional features were compiled in to the firmware
+	pub feature_flags: u32,
+}
+
 // ============================================================================
 // Impls
 // ============================================================================
@@ -125,12 +276,90 @@ impl TryFrom<u8> for RequestType {
 			0xC3 => Ok(RequestType::ShortWriteAlt),
 			0xC4 => Ok(RequestType::LongWrite),
 			0xC5 => Ok(RequestType::LongWriteAlt),
+			0xC6 => Ok(RequestType::SetBits),
+			0xC7 => Ok(RequestType::SetBitsAlt),
+			0xC8 => Ok(RequestType::ClearBits),
+			0xC9 => Ok(RequestType::ClearBitsAlt),
+			0xCA => Ok(RequestType::BulkReadStart),
+			0xCB => Ok(RequestType::BulkReadStartAlt),
+			0xCC => Ok(RequestType::BulkReadContinue),
+			0xCD => Ok(RequestType::BulkReadContinueAlt),
+			0xCE => Ok(RequestType::WideRead),
+			0xCF => Ok(RequestType::WideReadAlt),
+			0xD0 => Ok(RequestType::BulkWriteStart),
+			0xD1 => Ok(RequestType::BulkWriteStartAlt),
+			0xD2 => Ok(RequestType::BulkWriteContinue),
+			0xD3 => Ok(RequestType::BulkWriteContinueAlt),
 			_ => Err(Error::BadRequestType),
 		}
 	}
 }
 
+impl RequestType {
+	/// Does this kind of [`Request`] change the register it names?
+	///
+	/// Only these are refused whilst the configuration is locked. A *Bulk
+	/// Write Continue* names an offset, not a register, so it isn't one - the
+	/// *Bulk Write Start* before it is.
+	///
+	/// ```
+	/// # use neotron_bmc_protocol::RequestType;
+	/// assert!(RequestType::SetBits.is_write());
+	/// assert!(RequestType::BulkWriteStart.is_write());
+	/// assert!(!RequestType::BulkReadStart.is_write());
+	/// assert!(!RequestType::BulkWriteContinue.is_write());
+	/// ```
+	pub const fn is_write(&self) -> bool {
+		matches!(
+			self,
+			RequestType::ShortWrite
+				| RequestType::ShortWriteAlt
+				| RequestType::LongWrite
+				| RequestType::LongWriteAlt
+				| RequestType::SetBits
+				| RequestType::SetBitsAlt
+				| RequestType::ClearBits
+				| RequestType::ClearBitsAlt
+				| RequestType::BulkWriteStart
+				| RequestType::BulkWriteStartAlt
+		)
+	}
+}
+
+impl Width {
+	/// How many bytes a value of this width takes up.
+	///
+	/// ```
+	/// # use neotron_bmc_protocol::Width;
+	/// assert_eq!(Width::U32.bytes(), 4);
+	/// ```
+	pub const fn bytes(self) -> u8 {
+		1 << (self as u8)
+	}
+
+	/// The width of a value which takes up `size` bytes, if a *Wide Read*
+	/// can carry it.
+	///
+	/// ```
+	/// # use neotron_bmc_protocol::Width;
+	/// assert_eq!(Width::for_size(2), Some(Width::U16));
+	/// assert_eq!(Width::for_size(3), None);
+	/// ```
+	pub const fn for_size(size: usize) -> Option<Width> {
+		match size {
+			1 => Some(Width::U8),
+			2 => Some(Width::U16),
+			4 => Some(Width::U32),
+			_ => None,
+		}
+	}
+}
+
 impl Request {
+	/// The bits of a *Wide Read*'s *Shape* byte which hold the number of
+	/// values. The [`Width`] is in the bits above.
+	pub const WIDE_COUNT_MASK: u8 = 0x3F;
+
 	/// Make a new Read Request, requesting the given register and number of
 	/// bytes.
 	///
@@ -196,6 +425,211 @@ impl Request {
 		req
 	}
 
+	/// Make a new Set Bits Request, setting the bits in `mask` in the given
+	/// register and leaving the others alone.
+	///
+	/// Setting `use_alt` to true will use the alternate Request Type. You
+	/// should flip this for every successive call so that duplicate reads can
+	/// be detected.
+	pub fn new_set_bits(use_alt: bool, register: u8, mask: u8) -> Request {
+		let mut req = Request {
+			request_type: if use_alt {
+				RequestType::SetBitsAlt
+			} else {
+				RequestType::SetBits
+			},
+			register,
+			length_or_data: mask,
+			crc: 0x00,
+		};
+		let bytes = req.as_bytes();
+		req.crc = calculate_crc(&bytes[0..=2]);
+		req
+	}
+
+	/// Make a new Clear Bits Request, clearing the bits in `mask` in the given
+	/// register and leaving the others alone.
+	///
+	/// Setting `use_alt` to true will use the alternate Request Type. You
+	/// should flip this for every successive call so that duplicate reads can
+	/// be detected.
+	pub fn new_clear_bits(use_alt: bool, register: u8, mask: u8) -> Request {
+		let mut req = Request {
+			request_type: if use_alt {
+				RequestType::ClearBitsAlt
+			} else {
+				RequestType::ClearBits
+			},
+			register,
+			length_or_data: mask,
+			crc: 0x00,
+		};
+		let bytes = req.as_bytes();
+		req.crc = calculate_crc(&bytes[0..=2]);
+		req
+	}
+
+	/// Make a new Bulk Read Start Request, asking the NBMC to take `length`
+	/// bytes from the given register and hold them for the Host to collect.
+	///
+	/// Setting `use_alt` to true will use the alternate Request Type. You
+	/// should flip this for every successive call so that duplicate reads can
+	/// be detected.
+	pub fn new_bulk_read_start(use_alt: bool, register: u8, length: u8) -> Request {
+		let mut req = Request {
+			request_type: if use_alt {
+				RequestType::BulkReadStartAlt
+			} else {
+				RequestType::BulkReadStart
+			},
+			register,
+			length_or_data: length,
+			crc: 0x00,
+		};
+		let bytes = req.as_bytes();
+		req.crc = calculate_crc(&bytes[0..=2]);
+		req
+	}
+
+	/// Make a new Bulk Read Continue Request, collecting `length` bytes
+	/// from `offset` within the bytes held by the last Bulk Read Start.
+	///
+	/// The offset goes where the Register# usually does.
+	///
+	/// Setting `use_alt` to true will use the alternate Request Type. You
+	/// should flip this for every successive call so that duplicate reads can
+	/// be detected.
+	pub fn new_bulk_read_continue(use_alt: bool, offset: u8, length: u8) -> Request {
+		let mut req = Request {
+			request_type: if use_alt {
+				RequestType::BulkReadContinueAlt
+			} else {
+				RequestType::BulkReadContinue
+			},
+			register: offset,
+			length_or_data: length,
+			crc: 0x00,
+		};
+		let bytes = req.as_bytes();
+		req.crc = calculate_crc(&bytes[0..=2]);
+		req
+	}
+
+	/// Make a new Wide Read Request, reading `count` values of the given
+	/// [`Width`], starting at the given register.
+	///
+	/// The address moves on to the next register once each register's
+	/// values have been read, so a run of registers comes back in one
+	/// *Response*. Only the bottom six bits of `count` are sent.
+	///
+	/// Setting `use_alt` to true will use the alternate Request Type. You
+	/// should flip this for every successive call so that duplicate reads can
+	/// be detected.
+	///
+	/// ```
+	/// # use neotron_bmc_protocol::{Request, Width};
+	/// let req = Request::new_wide_read(false, 0x18, Width::U32, 4);
+	/// assert_eq!(req.as_bytes()[0..3], [0xCE, 0x18, 0x84]);
+	/// assert_eq!(req.wide_read_shape(), Some((Width::U32, 4)));
+	/// ```
+	pub fn new_wide_read(use_alt: bool, register: u8, width: Width, count: u8) -> Request {
+		let mut req = Request {
+			request_type: if use_alt {
+				RequestType::WideReadAlt
+			} else {
+				RequestType::WideRead
+			},
+			register,
+			length_or_data: ((width as u8) << 6) | (count & Self::WIDE_COUNT_MASK),
+			crc: 0x00,
+		};
+		let bytes = req.as_bytes();
+		req.crc = calculate_crc(&bytes[0..=2]);
+		req
+	}
+
+	/// Make a new Bulk Write Start Request, telling the NBMC that `length`
+	/// bytes for the given register will follow, in pieces.
+	///
+	/// Setting `use_alt` to true will use the alternate Request Type. You
+	/// should flip this for every successive call so that duplicate reads can
+	/// be detected.
+	pub fn new_bulk_write_start(use_alt: bool, register: u8, length: u8) -> Request {
+		let mut req = Request {
+			request_type: if use_alt {
+				RequestType::BulkWriteStartAlt
+			} else {
+				RequestType::BulkWriteStart
+			},
+			register,
+			length_or_data: length,
+			crc: 0x00,
+		};
+		let bytes = req.as_bytes();
+		req.crc = calculate_crc(&bytes[0..=2]);
+		req
+	}
+
+	/// Make a new Bulk Write Continue Request, sending `length` bytes to go
+	/// at `offset` within the bytes of the last Bulk Write Start. The bytes
+	/// themselves follow in a [`Payload`].
+	///
+	/// The offset goes where the Register# usually does.
+	///
+	/// Setting `use_alt` to true will use the alternate Request Type. You
+	/// should flip this for every successive call so that duplicate reads can
+	/// be detected.
+	pub fn new_bulk_write_continue(use_alt: bool, offset: u8, length: u8) -> Request {
+		let mut req = Request {
+			request_type: if use_alt {
+				RequestType::BulkWriteContinueAlt
+			} else {
+				RequestType::BulkWriteContinue
+			},
+			register: offset,
+			length_or_data: length,
+			crc: 0x00,
+		};
+		let bytes = req.as_bytes();
+		req.crc = calculate_crc(&bytes[0..=2]);
+		req
+	}
+
+	/// How many bytes the *Host* sends after an *OK* [`Response`] to this
+	/// Request - a [`Payload`] of *Length* bytes, and its CRC - if it is a
+	/// kind which has one.
+	///
+	/// ```
+	/// # use neotron_bmc_protocol::Request;
+	/// assert_eq!(Request::new_bulk_write_continue(false, 0, 60).payload_len(), Some(61));
+	/// assert_eq!(Request::new_long_write(false, 0x10, 5).payload_len(), Some(6));
+	/// assert_eq!(Request::new_bulk_write_start(false, 0x30, 200).payload_len(), None);
+	/// ```
+	pub const fn payload_len(&self) -> Option<usize> {
+		match self.request_type {
+			RequestType::LongWrite
+			| RequestType::LongWriteAlt
+			| RequestType::BulkWriteContinue
+			| RequestType::BulkWriteContinueAlt => Some(self.length_or_data as usize + 1),
+			_ => None,
+		}
+	}
+
+	/// The width and number of values a *Wide Read* asks for, from its
+	/// *Shape* byte.
+	///
+	/// You get `None` if the width bits are the reserved value. This doesn't
+	/// check the Request Type.
+	pub const fn wide_read_shape(&self) -> Option<(Width, u8)> {
+		let width = match self.length_or_data >> 6 {
+			0 => Width::U8,
+			1 => Width::U16,
+			2 => Width::U32,
+			_ => return None,
+		};
+		Some((width, self.length_or_data & Self::WIDE_COUNT_MASK))
+	}
+
 	/// Convert to bytes for transmission.
 	///
 	/// Produces a fixed sized buffer.
@@ -209,18 +643,12 @@ impl Request {
 	}
 }
 impl Sendable for Request {
-	/// Convert to bytes for transmission.
-	///
-	/// Copies into the given buffer, giving an error if it isn't large enough.
-	fn render_to_buffer(&self, buffer: &mut [u8]) -> Result<usize, Error> {
-		let bytes = self.as_bytes();
-		if buffer.len() < bytes.len() {
-			return Err(Error::BufferTooSmall);
-		}
-		for (src, dest) in bytes.iter().zip(buffer.iter_mut()) {
-			*dest = *src;
-		}
-		Ok(bytes.len())
+	fn rendered_len(&self) -> usize {
+		4
+	}
+
+	fn render_segments(&self, f: &mut dyn FnMut(&[u8])) {
+		f(&self.as_bytes());
 	}
 }
 impl<'a> Receivable<'a> for Request {
@@ -262,6 +690,9 @@ impl TryFrom<u8> for ResponseResult {
 			0xA2 => Ok(ResponseResult::BadRequestType),
 			0xA3 => Ok(ResponseResult::BadRegister),
 			0xA4 => Ok(ResponseResult::BadLength),
+			0xA5 => Ok(ResponseResult::Busy),
+			0xA6 => Ok(ResponseResult::Locked),
+			0xA7 => Ok(ResponseResult::NotReady),
 			_ => Err(Error::BadResponseResult),
 		}
 	}
@@ -270,13 +701,32 @@ impl TryFrom<u8> for ResponseResult {
 impl<'a> Response<'a> {
 	/// Make a new OK response, with some optional data
 	pub fn new_ok_with_data(data: &'a [u8]) -> Response<'a> {
+		Self::new_ok_with_segments(data, &[])
+	}
+
+	/// Make a new OK response, where the data is in two pieces.
+	///
+	/// The pieces are sent back-to-back, as if they were one contiguous
+	/// payload, but without being copied together first.
+	///
+	/// ```
+	/// # use neotron_bmc_protocol::{Response, Sendable};
+	/// let ring = [0x02, 0x03, 0x00, 0x01];
+	/// let rsp = Response::new_ok_with_segments(&ring[2..], &ring[0..2]);
+	/// let mut buffer = [0u8; 6];
+	/// assert_eq!(rsp.render_to_buffer(&mut buffer).unwrap(), 6);
+	/// assert_eq!(buffer, [0xA0, 0x00, 0x01, 0x02, 0x03, 0xBB]);
+	/// ```
+	pub fn new_ok_with_segments(data: &'a [u8], data_tail: &'a [u8]) -> Response<'a> {
 		Response {
 			result: ResponseResult::Ok,
 			data,
+			data_tail,
 			crc: {
 				let mut crc = crc::init();
 				crc = crc::update(crc, &[ResponseResult::Ok as u8]);
 				crc = crc::update(crc, data);
+				crc = crc::update(crc, data_tail);
 				crc::finalize(crc)
 			},
 		}
@@ -287,43 +737,53 @@ impl<'a> Response<'a> {
 		Response {
 			result,
 			data: &[],
+			data_tail: &[],
 			crc: calculate_crc(&[result as u8]),
 		}
 	}
-}
 
-impl<'a> Sendable for Response<'a> {
-	/// Convert to bytes for transmission.
+	/// Decode the data in a received *Read Response* as a typed value.
 	///
-	/// Copies into the given buffer, giving an error if it isn't large enough.
+	/// Check `result` first - an error *Response* has no data.
 	///
 	/// ```
-	/// # use neotron_bmc_protocol::{Response, ResponseResult, Sendable};
-	/// let mut buffer = [0u8; 5];
-	///
-	/// let req = Response::new_ok_with_data(&[]);
-	/// assert_eq!(req.render_to_buffer(&mut buffer).unwrap(), 2);
-	/// assert_eq!(&buffer[0..=1], [0xA0, 0x69]);
-	///
-	/// let req = Response::new_ok_with_data(&[0x00, 0x01]);
-	/// assert_eq!(req.render_to_buffer(&mut buffer).unwrap(), 4);
-	/// assert_eq!(&buffer[0..=3], [0xA0, 0x00, 0x01, 0x4F]);
-	///
-	/// let req = Response::new_without_data(ResponseResult::BadRequestType);
-	/// assert_eq!(req.render_to_buffer(&mut buffer).unwrap(), 2);
-	/// assert_eq!(&buffer[0..=1], [0xA2, 0x67]);
+	/// # use neotron_bmc_protocol::{calculate_crc, Receivable, Response};
+	/// let crc = calculate_crc(&[0xA0, 0x34, 0x12]);
+	/// let bytes = [0xA0, 0x34, 0x12, crc];
+	/// let rsp = Response::from_bytes(&bytes).unwrap();
+	/// assert_eq!(rsp.decode::<u16>(), Ok(0x1234));
 	/// ```
-	fn render_to_buffer(&self, buffer: &mut [u8]) -> Result<usize, Error> {
-		let len = 1 + self.data.len() + 1;
-		if buffer.len() < len {
-			return Err(Error::BufferTooSmall);
+	pub fn decode<T: values::RegisterValue>(&self) -> Result<T, Error> {
+		T::decode(self.data)
+	}
+}
+
+impl<'a> PartialEq for Response<'a> {
+	fn eq(&self, other: &Response) -> bool {
+		self.result == other.result
+			&& self.crc == other.crc
+			&& self
+				.data
+				.iter()
+				.chain(self.data_tail)
+				.eq(other.data.iter().chain(other.data_tail))
+	}
+}
+
+impl<'a> Sendable for Response<'a> {
+	fn rendered_len(&self) -> usize {
+		1 + self.data.len() + self.data_tail.len() + 1
+	}
+
+	fn render_segments(&self, f: &mut dyn FnMut(&[u8])) {
+		f(&[self.result as u8]);
+		if !self.data.is_empty() {
+			f(self.data);
 		}
-		buffer[0] = self.result as u8;
-		for (src, dest) in self.data.iter().zip(buffer[1..].iter_mut()) {
-			*dest = *src;
+		if !self.data_tail.is_empty() {
+			f(self.data_tail);
 		}
-		buffer[len - 1] = self.crc;
-		Ok(len)
+		f(&[self.crc]);
 	}
 }
 
@@ -339,6 +799,9 @@ impl<'a> Receivable<'a> for Response<'a> {
 	///
 	/// ```
 	fn from_bytes(data: &'a [u8]) -> Result<Response<'a>, Error> {
+		if data.len() < 2 {
+			return Err(Error::BadLength);
+		}
 		let calc_crc = calculate_crc(&data[0..data.len()]);
 		if calc_crc != 0 {
 			// It's a quirk of CRC-8 that including the CRC always produces a
@@ -348,11 +811,55 @@ impl<'a> Receivable<'a> for Response<'a> {
 		Ok(Response {
 			result: data[0].try_into()?,
 			data: &data[1..=(data.len() - 2)],
+			data_tail: &[],
 			crc: data[data.len() - 1],
 		})
 	}
 }
 
+impl<'a> Payload<'a> {
+	/// Make a new Payload, carrying the given bytes.
+	pub fn new(data: &'a [u8]) -> Payload<'a> {
+		Payload {
+			data,
+			crc: calculate_crc(data),
+		}
+	}
+}
+
+impl<'a> Sendable for Payload<'a> {
+	fn rendered_len(&self) -> usize {
+		self.data.len() + 1
+	}
+
+	fn render_segments(&self, f: &mut dyn FnMut(&[u8])) {
+		f(self.data);
+		f(&[self.crc]);
+	}
+}
+
+impl<'a> Receivable<'a> for Payload<'a> {
+	/// Convert from received bytes - the data, and then the CRC.
+	///
+	/// You get `Err` if the bytes could not be decoded.
+	///
+	/// ```
+	/// # use neotron_bmc_protocol::{Payload, Receivable};
+	/// let bytes = [0x00, 0x01, 0x07];
+	/// let payload = Payload::from_bytes(&bytes).unwrap();
+	/// assert_eq!(payload.data, [0x00, 0x01]);
+	/// ```
+	fn from_bytes(data: &'a [u8]) -> Result<Payload<'a>, Error> {
+		let Some((&crc, bytes)) = data.split_last() else {
+			return Err(Error::BadLength);
+		};
+		if calculate_crc(data) != 0 {
+			return Err(Error::BadCrc);
+		}
+		Ok(Payload { data: bytes, crc })
+	}
+}
+
 impl ProtocolVersion {
 	/// Construct a new [`ProtocolVersion`].
 	///
@@ -411,16 +918,95 @@ impl ProtocolVersion {
 	}
 }
 
-impl Sendable for ProtocolVersion {
-	fn render_to_buffer(&self, buffer: &mut [u8]) -> Result<usize, Error> {
-		let bytes = self.as_bytes();
-		if buffer.len() < bytes.len() {
-			return Err(Error::BufferTooSmall);
+impl BuildInfo {
+	/// The size of an encoded [`BuildInfo`], in bytes
+	pub const SIZE: usize = 32;
+
+	/// The layout version we produce. Future versions may only add fields
+	/// in the reserved area.
+	pub const LAYOUT_VERSION: u8 = 1;
+
+	/// Set in `build_flags` if the firmware was built from a working copy
+	/// with uncommitted changes.
+	pub const FLAG_DIRTY: u8 = 1 << 0;
+
+	/// Set in `build_flags` if the firmware was built with debug assertions.
+	pub const FLAG_DEBUG: u8 = 1 << 1;
+
+	/// Convert to bytes for transmission.
+	///
+	/// ```
+	/// # use neotron_bmc_protocol::{BuildInfo, ProtocolVersion, calculate_crc};
+	/// let info = BuildInfo {
+	///     firmware_version: [0, 4, 0],
+	///     protocol_version: ProtocolVersion::new(1, 0, 0),
+	///     build_flags: 0,
+	///     git_hash: [0xb2, 0x2c, 0x86, 0x80, 0x11, 0x22, 0x33, 0x44],
+	///     build_timestamp: 0x6000_0000,
+	///     feature_flags: 0,
+	/// };
+	/// let bytes = info.as_bytes();
+	/// assert_eq!(&bytes[0..4], &[1, 0, 4, 0]);
+	/// assert_eq!(calculate_crc(&bytes), 0);
+	/// ```
+	pub fn as_bytes(&self) -> [u8; Self::SIZE] {
+		let mut bytes = [0u8; Self::SIZE];
+		bytes[0] = Self::LAYOUT_VERSION;
+		bytes[1..=3].copy_from_slice(&self.firmware_version);
+		bytes[4..=6].copy_from_slice(&self.protocol_version.as_bytes());
+		bytes[7] = self.build_flags;
+		bytes[8..=15].copy_from_slice(&self.git_hash);
+		bytes[16..=19].copy_from_slice(&self.build_timestamp.to_le_bytes());
+		bytes[20..=23].copy_from_slice(&self.feature_flags.to_le_bytes());
+		// Bytes 24..=30 are reserved
+		bytes[Self::SIZE - 1] = calculate_crc(&bytes[0..Self::SIZE - 1]);
+		bytes
+	}
+}
+
+impl Sendable for BuildInfo {
+	fn rendered_len(&self) -> usize {
+		Self::SIZE
+	}
+
+	fn render_segments(&self, f: &mut dyn FnMut(&[u8])) {
+		f(&self.as_bytes());
+	}
+}
+
+impl<'a> Receivable<'a> for BuildInfo {
+	/// Convert from received bytes.
+	///
+	/// You get `Err` if there are not enough bytes, or the CRC is wrong.
+	fn from_bytes(data: &[u8]) -> Result<BuildInfo, Error> {
+		if data.len() < Self::SIZE {
+			return Err(Error::BadLength);
 		}
-		for (src, dest) in bytes.iter().zip(buffer.iter_mut()) {
-			*dest = *src;
+		if calculate_crc(&data[0..Self::SIZE]) != 0 {
+			// It's a quirk of CRC-8 that including the CRC always produces a
+			// result of zero.
+			return Err(Error::BadCrc);
 		}
-		Ok(bytes.len())
+		Ok(BuildInfo {
+			firmware_version: [data[1], data[2], data[3]],
+			protocol_version: ProtocolVersion::new(data[4], data[5], data[6]),
+			build_flags: data[7],
+			git_hash: [
+				data[8], data[9], data[10], data[11], data[12], data[13], data[14], data[15],
+			],
+			build_timestamp: u32::from_le_bytes([data[16], data[17], data[18], data[19]]),
+			feature_flags: u32::from_le_bytes([data[20], data[21], data[22], data[23]]),
+		})
+	}
+}
+
+impl Sendable for ProtocolVersion {
+	fn rendered_len(&self) -> usize {
+		3
+	}
+
+	fn render_segments(&self, f: &mut dyn FnMut(&[u8])) {
+		f(&self.as_bytes());
 	}
 }
 
@@ -454,6 +1040,22 @@ pub fn calculate_crc(data: &[u8]) -> u8 {
 	crc::finalize(crc)
 }
 
+/// Can the *Host* pick this as the padding byte?
+///
+/// It mustn't look like the start of a [`Response`] (a [`ResponseResult`]),
+/// or the [`SYNC_BYTE`].
+///
+/// ```
+/// # use neotron_bmc_protocol::{is_valid_padding, PADDING_BYTE, SYNC_BYTE};
+/// assert!(is_valid_padding(PADDING_BYTE));
+/// assert!(is_valid_padding(0x00));
+/// assert!(!is_valid_padding(0xA0));
+/// assert!(!is_valid_padding(SYNC_BYTE));
+/// ```
+pub fn is_valid_padding(byte: u8) -> bool {
+	byte != SYNC_BYTE && ResponseResult::try_from(byte).is_err()
+}
+
 // ============================================================================
 // Tests
 // ============================================================================
@@ -515,6 +1117,226 @@ mod test {
 		let decoded_req = Request::from_bytes(&bytes).unwrap();
 		assert_eq!(req, decoded_req);
 	}
+
+	#[test]
+	fn set_and_clear_bits_requests() {
+		for (req, request_type) in [
+			(Request::new_set_bits(false, 0x11, 0x04), 0xC6),
+			(Request::new_set_bits(true, 0x11, 0x04), 0xC7),
+			(Request::new_clear_bits(false, 0x11, 0x04), 0xC8),
+			(Request::new_clear_bits(true, 0x11, 0x04), 0xC9),
+		] {
+			let bytes = req.as_bytes();
+			assert_eq!(bytes[0..3], [request_type, 0x11, 0x04]);
+			assert_eq!(calculate_crc(&bytes), 0);
+			let decoded_req = Request::from_bytes(&bytes).unwrap();
+			assert_eq!(req, decoded_req);
+		}
+	}
+
+	#[test]
+	fn wide_read_requests() {
+		for (req, request_type) in [
+			(Request::new_wide_read(false, 0x05, Width::U32, 2), 0xCE),
+			(Request::new_wide_read(true, 0x05, Width::U32, 2), 0xCF),
+		] {
+			let bytes = req.as_bytes();
+			assert_eq!(bytes[0], request_type);
+			assert_eq!(bytes[2], 0x82);
+			assert_eq!(calculate_crc(&bytes), 0);
+			let decoded_req = Request::from_bytes(&bytes).unwrap();
+			assert_eq!(req, decoded_req);
+			assert!(!decoded_req.request_type.is_write());
+		}
+		assert_eq!(
+			Request::new_wide_read(false, 0xE0, Width::U8, 60).wide_read_shape(),
+			Some((Width::U8, 60))
+		);
+		assert_eq!(
+			Request::new_wide_read(false, 0x29, Width::U16, 2).wide_read_shape(),
+			Some((Width::U16, 2))
+		);
+		// The count is cut to six bits
+		assert_eq!(
+			Request::new_wide_read(false, 0xE0, Width::U8, 0x41).wide_read_shape(),
+			Some((Width::U8, 1))
+		);
+		// The top width is reserved
+		assert_eq!(Request::new_read(false, 0x05, 0xC2).wide_read_shape(), None);
+	}
+
+	#[test]
+	fn bulk_read_requests() {
+		for (req, request_type) in [
+			(Request::new_bulk_read_start(false, 0x30, 200), 0xCA),
+			(Request::new_bulk_read_start(true, 0x30, 200), 0xCB),
+			(Request::new_bulk_read_continue(false, 0x3C, 60), 0xCC),
+			(Request::new_bulk_read_continue(true, 0x3C, 60), 0xCD),
+		] {
+			let bytes = req.as_bytes();
+			assert_eq!(bytes[0], request_type);
+			assert_eq!(calculate_crc(&bytes), 0);
+			let decoded_req = Request::from_bytes(&bytes).unwrap();
+			assert_eq!(req, decoded_req);
+		}
+		assert_eq!(
+			Request::new_bulk_read_continue(false, 0x3C, 60).register,
+			0x3C
+		);
+		assert_eq!(
+			Request::from_bytes(&[0xD4, 0x11, 0x04, calculate_crc(&[0xD4, 0x11, 0x04])]),
+			Err(Error::BadRequestType)
+		);
+	}
+
+	#[test]
+	fn bulk_write_requests() {
+		for (req, request_type) in [
+			(Request::new_bulk_write_start(false, 0x30, 200), 0xD0),
+			(Request::new_bulk_write_start(true, 0x30, 200), 0xD1),
+			(Request::new_bulk_write_continue(false, 0x3C, 60), 0xD2),
+			(Request::new_bulk_write_continue(true, 0x3C, 60), 0xD3),
+		] {
+			let bytes = req.as_bytes();
+			assert_eq!(bytes[0], request_type);
+			assert_eq!(calculate_crc(&bytes), 0);
+			let decoded_req = Request::from_bytes(&bytes).unwrap();
+			assert_eq!(req, decoded_req);
+		}
+		assert_eq!(
+			Request::new_bulk_write_continue(false, 0x3C, 60).register,
+			0x3C
+		);
+		assert_eq!(
+			Request::new_bulk_write_continue(true, 0x3C, 60).payload_len(),
+			Some(61)
+		);
+		assert_eq!(Request::new_read(false, 0x30, 60).payload_len(), None);
+	}
+
+	#[test]
+	fn payload_round_trip() {
+		let payload = Payload::new(&[0x00, 0x01, 0x02, 0x03, 0x04]);
+		let mut buffer = [0u8; 6];
+		assert_eq!(payload.render_to_buffer(&mut buffer), Ok(6));
+		assert_eq!(calculate_crc(&buffer), 0);
+		assert_eq!(Payload::from_bytes(&buffer), Ok(payload));
+		// An empty payload is just the CRC
+		assert_eq!(Payload::new(&[]).rendered_len(), 1);
+		assert_eq!(Payload::from_bytes(&[0x00]).unwrap().data, [0u8; 0]);
+		assert_eq!(Payload::from_bytes(&[]), Err(Error::BadLength));
+		buffer[2] ^= 0x10;
+		assert_eq!(Payload::from_bytes(&buffer), Err(Error::BadCrc));
+	}
+
+	#[test]
+	fn build_info_round_trip() {
+		let info = BuildInfo {
+			firmware_version: [0, 4, 0],
+			protocol_version: ProtocolVersion::new(1, 0, 0),
+			build_flags: BuildInfo::FLAG_DIRTY,
+			git_hash: [0x01, 0x23, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF],
+			build_timestamp: 0x1234_5678,
+			feature_flags: 0x8000_0001,
+		};
+		let bytes = info.as_bytes();
+		assert_eq!(
+			bytes[0..24],
+			[
+				0x01, 0x00, 0x04, 0x00, 0x01, 0x00, 0x00, 0x01, 0x01, 0x23, 0x45, 0x67, 0x89, 0xAB,
+				0xCD, 0xEF, 0x78, 0x56, 0x34, 0x12, 0x01, 0x00, 0x00, 0x80
+			]
+		);
+		let decoded_info = BuildInfo::from_bytes(&bytes).unwrap();
+		assert_eq!(info, decoded_info);
+	}
+
+	#[test]
+	fn build_info_bad_crc() {
+		let info = BuildInfo {
+			firmware_version: [0, 4, 0],
+			protocol_version: ProtocolVersion::new(1, 0, 0),
+			build_flags: 0,
+			git_hash: [0u8; 8],
+			build_timestamp: 0,
+			feature_flags: 0,
+		};
+		let mut bytes = info.as_bytes();
+		bytes[16] ^= 0x01;
+		assert_eq!(BuildInfo::from_bytes(&bytes), Err(Error::BadCrc));
+		assert_eq!(BuildInfo::from_bytes(&bytes[0..31]), Err(Error::BadLength));
+	}
+
+	#[test]
+	fn response_segments() {
+		let data = [0x00, 0x01, 0x02, 0x03, 0x04];
+		let split = Response::new_ok_with_segments(&data[0..2], &data[2..]);
+		let whole = Response::new_ok_with_data(&data);
+		let mut split_buffer = [0u8; 7];
+		let mut whole_buffer = [0u8; 7];
+		assert_eq!(split.render_to_buffer(&mut split_buffer), Ok(7));
+		assert_eq!(whole.render_to_buffer(&mut whole_buffer), Ok(7));
+		assert_eq!(split_buffer, whole_buffer);
+		let decoded = Response::from_bytes(&split_buffer).unwrap();
+		assert_eq!(decoded.data, &data);
+		// However the payload is split, it's the same response
+		assert_eq!(split, whole);
+		assert_eq!(decoded, split);
+		assert_ne!(
+			Response::new_ok_with_segments(&data[0..2], &data[3..]),
+			whole
+		);
+		assert_eq!(
+			split.render_to_buffer(&mut split_buffer[0..6]),
+			Err(Error::BufferTooSmall)
+		);
+	}
+
+	#[test]
+	fn busy_response() {
+		let rsp = Response::new_without_data(ResponseResult::Busy);
+		let mut buffer = [0u8; 2];
+		assert_eq!(rsp.render_to_buffer(&mut buffer), Ok(2));
+		assert_eq!(buffer[0], 0xA5);
+		let decoded = Response::from_bytes(&buffer).unwrap();
+		assert_eq!(decoded.result, ResponseResult::Busy);
+		assert_eq!(ResponseResult::try_from(0xA6), Ok(ResponseResult::Locked));
+		assert_eq!(ResponseResult::try_from(0xA7), Ok(ResponseResult::NotReady));
+		assert_eq!(
+			ResponseResult::try_from(0xA8),
+			Err(Error::BadResponseResult)
+		);
+	}
+
+	#[cfg(feature = "serde")]
+	#[test]
+	fn serde_round_trip() {
+		let req = Request::new_short_write(true, 0x11, 0x04);
+		let bytes = postcard::to_stdvec(&req).unwrap();
+		assert_eq!(postcard::from_bytes::<Request>(&bytes), Ok(req));
+		// The CRC is kept as it was, so a bad Request can be replayed
+		let bad = Request::from_bytes(&[0xC0, 0x11, 0x03, 0xC6]).unwrap();
+		let bad = Request { crc: 0x00, ..bad };
+		let bytes = postcard::to_stdvec(&bad).unwrap();
+		let replayed: Request = postcard::from_bytes(&bytes).unwrap();
+		assert_eq!(replayed.as_bytes(), [0xC0, 0x11, 0x03, 0x00]);
+		// Responses borrow their data from the serialised bytes
+		let data = [0x00, 0x01, 0x02];
+		let rsp = Response::new_ok_with_data(&data);
+		let bytes = postcard::to_stdvec(&rsp).unwrap();
+		let decoded: Response = postcard::from_bytes(&bytes).unwrap();
+		assert_eq!(decoded, rsp);
+		let info = BuildInfo {
+			firmware_version: [0, 4, 0],
+			protocol_version: ProtocolVersion::new(1, 0, 0),
+			build_flags: 0,
+			git_hash: [0u8; 8],
+			build_timestamp: 0,
+			feature_flags: registers::FEATURE_FAN,
+		};
+		let bytes = postcard::to_stdvec(&info).unwrap();
+		assert_eq!(postcard::from_bytes::<BuildInfo>(&bytes), Ok(info));
+	}
 }
 
 // ============================================================================
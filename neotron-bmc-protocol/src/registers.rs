@@ -0,0 +1,893 @@
+//! # Register Map
+//!
+//! Every register the NBMC implements - its address, how it can be accessed,
+//! how long a read of it is, and which optional firmware features it needs.
+//! The firmware checks its register handling against this table in its own
+//! tests, so a Host driver which uses it can't drift from the firmware.
+//!
+//! See the NBMC's top-level `README.md` for what each register does.
+//! Registers which the README lists but the firmware doesn't implement yet
+//! aren't in the table.
+
+use defmt::Format;
+
+use crate::{ResponseResult, Width};
+
+use self::Length::{Entries, Exactly, UpTo, Window};
+use self::Requires::{Always, With, WithWithout, Without};
+
+/// Feature flag for main board current sensing (the `current-sense` feature)
+pub const FEATURE_CURRENT_SENSE: u32 = 1 << 0;
+
+/// Feature flag for case fan control (the `fan` feature)
+pub const FEATURE_FAN: u32 = 1 << 1;
+
+/// Feature flag for the second serial port (the `uart2` feature)
+pub const FEATURE_UART2: u32 = 1 << 2;
+
+/// Feature flag for the front panel rotary encoder (the `encoder` feature)
+pub const FEATURE_ENCODER: u32 = 1 << 3;
+
+/// Feature flag for register access over I²C (the `i2c-target` feature)
+pub const FEATURE_I2C_TARGET: u32 = 1 << 4;
+
+/// Feature flag for switching the PS/2 ports' power (the `ps2-power`
+/// feature)
+pub const FEATURE_PS2_POWER: u32 = 1 << 5;
+
+/// Feature flag for the SPI transaction trace (the `spi-trace` feature)
+pub const FEATURE_SPI_TRACE: u32 = 1 << 6;
+
+/// Feature flag for the RGB status LED (the `status-led` feature)
+pub const FEATURE_STATUS_LED: u32 = 1 << 7;
+
+/// Feature flag for the PS/2 keyboard port (the `ps2-keyboard` feature)
+pub const FEATURE_PS2_KEYBOARD: u32 = 1 << 8;
+
+/// Feature flag for the PS/2 mouse port (the `ps2-mouse` feature)
+pub const FEATURE_PS2_MOUSE: u32 = 1 << 9;
+
+/// Feature flag for the buzzer (the `speaker` feature)
+pub const FEATURE_SPEAKER: u32 = 1 << 10;
+
+/// Feature flag for the analog inputs and the temperature sensor (the `adc`
+/// feature)
+pub const FEATURE_ADC: u32 = 1 << 11;
+
+/// Feature flag for the USB console and register interface (the `usb`
+/// feature)
+pub const FEATURE_USB: u32 = 1 << 12;
+
+/// Every feature flag, with the name of the firmware feature which sets it
+pub const FEATURE_NAMES: &[(u32, &str)] = &[
+	(FEATURE_CURRENT_SENSE, "current-sense"),
+	(FEATURE_FAN, "fan"),
+	(FEATURE_UART2, "uart2"),
+	(FEATURE_ENCODER, "encoder"),
+	(FEATURE_I2C_TARGET, "i2c-target"),
+	(FEATURE_PS2_POWER, "ps2-power"),
+	(FEATURE_SPI_TRACE, "spi-trace"),
+	(FEATURE_STATUS_LED, "status-led"),
+	(FEATURE_PS2_KEYBOARD, "ps2-keyboard"),
+	(FEATURE_PS2_MOUSE, "ps2-mouse"),
+	(FEATURE_SPEAKER, "speaker"),
+	(FEATURE_ADC, "adc"),
+	(FEATURE_USB, "usb"),
+];
+
+/// The features which use the spare PB6 and PB7 pins, so the GPIO (well,
+/// PWM) registers aren't there
+const FEATURES_USING_GPIO: u32 =
+	FEATURE_FAN | FEATURE_ENCODER | FEATURE_I2C_TARGET | FEATURE_PS2_POWER | FEATURE_STATUS_LED;
+
+/// The registers which refuse writes whilst the configuration is locked
+/// (see `CONFIG_LOCK`) - the ones which write to flash or the option bytes,
+/// cut or cycle the power, or change how the NBMC watches the Host.
+pub const GUARDED: &[u8] = &[
+	0x0A, 0x0F, 0x19, 0x25, 0x2B, 0x2C, 0x2F, 0x65, 0x66, 0x67, 0x73, 0x74, 0x93, 0xC0, 0xC1, 0xCA,
+	0xCB, 0xCD,
+];
+
+/// The registers which can be read whilst the NBMC is still starting up -
+/// the ones which identify it, BMC Health and BMC Status. Anything else gets
+/// a *Not Ready* result until BMC Status says the NBMC is ready.
+pub const EARLY: &[u8] = &[0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x0E];
+
+/// The FIFO registers which take writes, and so can be written with a *Bulk
+/// Write* (see `allows_bulk_write`).
+pub const BULK_WRITABLE: &[u8] = &[0x30, 0x38];
+
+/// The most bytes a single read can return.
+pub const MAX_READ_LEN: u8 = 60;
+
+/// How a register can be accessed.
+#[derive(Debug, Copy, Clone, Format, PartialEq, Eq)]
+pub enum Access {
+	/// Read only - writes return an error
+	ReadOnly,
+	/// Read and write
+	ReadWrite,
+	/// Read and write a bitmask, where *Set Bits* and *Clear Bits* requests
+	/// can also change some bits without touching the others
+	Bitmask,
+	/// Reads as usual, but writing a 1 bit clears that bit (and a 0 bit is
+	/// ignored)
+	WriteOneToClear,
+	/// A first-in, first-out buffer, where a read takes the oldest bytes out
+	Fifo,
+}
+
+/// How long a read of a register can be.
+#[derive(Debug, Copy, Clone, Format, PartialEq, Eq)]
+pub enum Length {
+	/// Exactly this many bytes
+	Exactly(u8),
+	/// Any number of bytes, up to this many
+	UpTo(u8),
+	/// A whole number of entries, each this many bytes, up to `MAX_READ_LEN`
+	Entries(u8),
+	/// A block of this many bytes, which can be read starting at any address
+	/// within it, up to the end of the block
+	Window(u8),
+}
+
+/// Which optional firmware features a register needs.
+///
+/// The features in a firmware build are in the Firmware Feature Flags
+/// register (see `FIRMWARE_FEATURE_FLAGS`).
+#[derive(Debug, Copy, Clone, Format, PartialEq, Eq)]
+pub enum Requires {
+	/// The register is always there
+	Always,
+	/// The register is only there with all of these features
+	With(u32),
+	/// The register is only there without any of these features
+	Without(u32),
+	/// The register is only there with all of the first features, and none
+	/// of the second
+	WithWithout(u32, u32),
+}
+
+/// Describes one register.
+#[derive(Debug, Copy, Clone, Format, PartialEq, Eq)]
+pub struct Register {
+	/// The register's address
+	pub address: u8,
+	/// The register's name, as in the README
+	pub name: &'static str,
+	/// How the register can be accessed
+	pub access: Access,
+	/// How long a read of the register can be
+	pub length: Length,
+	/// Which features the register needs
+	pub requires: Requires,
+}
+
+impl Register {
+	/// Is this register in a firmware build with these feature flags?
+	///
+	/// ```
+	/// # use neotron_bmc_protocol::registers;
+	/// assert!(registers::FAN_SPEED.is_available(registers::FEATURE_FAN));
+	/// assert!(!registers::FAN_SPEED.is_available(0));
+	/// assert!(registers::BUZZER_QUEUE.is_available(registers::FEATURE_SPEAKER));
+	/// assert!(!registers::BUZZER_QUEUE.is_available(
+	///     registers::FEATURE_SPEAKER | registers::FEATURE_CURRENT_SENSE
+	/// ));
+	/// ```
+	pub const fn is_available(&self, feature_flags: u32) -> bool {
+		match self.requires {
+			Always => true,
+			With(features) => (feature_flags & features) == features,
+			Without(features) => (feature_flags & features) == 0,
+			WithWithout(with, without) => {
+				(feature_flags & with) == with && (feature_flags & without) == 0
+			}
+		}
+	}
+
+	/// Does this register answer at `address`?
+	///
+	/// Only a `Window` covers more than one address.
+	pub const fn contains(&self, address: u8) -> bool {
+		match self.length {
+			Window(len) => address >= self.address && (address - self.address) < len,
+			_ => address == self.address,
+		}
+	}
+
+	/// Can the Host read `length` bytes, starting at `address`?
+	///
+	/// ```
+	/// # use neotron_bmc_protocol::registers;
+	/// assert!(registers::EVENT_LOG.allows_read(0x28, 12));
+	/// assert!(!registers::EVENT_LOG.allows_read(0x28, 13));
+	/// assert!(registers::SCRATCH.allows_read(0xF0, 16));
+	/// assert!(!registers::SCRATCH.allows_read(0xF0, 17));
+	/// ```
+	pub const fn allows_read(&self, address: u8, length: u8) -> bool {
+		if !self.contains(address) {
+			return false;
+		}
+		match self.length {
+			Exactly(len) => length == len,
+			UpTo(len) => length <= len,
+			Entries(len) => length <= MAX_READ_LEN && length.is_multiple_of(len),
+			Window(len) => length <= len - (address - self.address),
+		}
+	}
+
+	/// How many addresses this register answers at.
+	///
+	/// Only a `Window` covers more than one.
+	pub const fn span(&self) -> u8 {
+		match self.length {
+			Window(len) => len,
+			_ => 1,
+		}
+	}
+
+	/// How many bytes a read from `address` to the end of this register
+	/// takes, if the register has a set length.
+	///
+	/// That's the whole register, or the rest of a `Window`.
+	///
+	/// ```
+	/// # use neotron_bmc_protocol::registers;
+	/// assert_eq!(registers::BMC_HEALTH.fixed_len(0x07), Some(4));
+	/// assert_eq!(registers::SCRATCH.fixed_len(0xF0), Some(16));
+	/// assert_eq!(registers::UART_DATA.fixed_len(0x30), None);
+	/// ```
+	pub const fn fixed_len(&self, address: u8) -> Option<u8> {
+		match self.length {
+			Exactly(len) => Some(len),
+			Window(len) => Some(len - (address - self.address)),
+			UpTo(_) | Entries(_) => None,
+		}
+	}
+
+	/// Can the Host take `length` bytes from this register with a *Bulk Read
+	/// Start*?
+	///
+	/// Only a FIFO can be read in bulk. The NBMC takes the bytes out in
+	/// pieces of up to `MAX_READ_LEN`, each of which must be a read the
+	/// register allows.
+	///
+	/// ```
+	/// # use neotron_bmc_protocol::registers;
+	/// assert!(registers::UART_DATA.allows_bulk_read(255));
+	/// assert!(registers::EVENT_LOG.allows_bulk_read(252));
+	/// assert!(!registers::EVENT_LOG.allows_bulk_read(250));
+	/// assert!(!registers::SCRATCH.allows_bulk_read(32));
+	/// ```
+	pub const fn allows_bulk_read(&self, length: u8) -> bool {
+		if !matches!(self.access, Access::Fifo) || length == 0 {
+			return false;
+		}
+		let last = length % MAX_READ_LEN;
+		(length < MAX_READ_LEN || self.allows_read(self.address, MAX_READ_LEN))
+			&& (last == 0 || self.allows_read(self.address, last))
+	}
+
+	/// Can the Host send `length` bytes to this register with a *Bulk Write
+	/// Start*?
+	///
+	/// Only the `BULK_WRITABLE` FIFOs can be written in bulk. The NBMC writes
+	/// the bytes one at a time, as if each had come in a *Short Write*.
+	///
+	/// ```
+	/// # use neotron_bmc_protocol::registers;
+	/// assert!(registers::UART_DATA.allows_bulk_write(255));
+	/// assert!(!registers::UART_DATA.allows_bulk_write(0));
+	/// assert!(!registers::EVENT_LOG.allows_bulk_write(6));
+	/// ```
+	pub const fn allows_bulk_write(&self, length: u8) -> bool {
+		if length == 0 {
+			return false;
+		}
+		let mut idx = 0;
+		while idx < BULK_WRITABLE.len() {
+			if BULK_WRITABLE[idx] == self.address {
+				return true;
+			}
+			idx += 1;
+		}
+		false
+	}
+
+	/// Is this register one of the `GUARDED` ones, which can't be written
+	/// whilst the configuration is locked?
+	///
+	/// ```
+	/// # use neotron_bmc_protocol::registers;
+	/// assert!(registers::POWER_CONTROL.is_guarded());
+	/// assert!(!registers::CONFIG_LOCK.is_guarded());
+	/// ```
+	pub const fn is_guarded(&self) -> bool {
+		let mut idx = 0;
+		while idx < GUARDED.len() {
+			if GUARDED[idx] == self.address {
+				return true;
+			}
+			idx += 1;
+		}
+		false
+	}
+
+	/// Is this register one of the `EARLY` ones, which can be read whilst the
+	/// NBMC is still starting up?
+	///
+	/// ```
+	/// # use neotron_bmc_protocol::registers;
+	/// assert!(registers::BMC_STATUS.is_early());
+	/// assert!(!registers::POWER_CONTROL.is_early());
+	/// ```
+	pub const fn is_early(&self) -> bool {
+		let mut idx = 0;
+		while idx < EARLY.len() {
+			if EARLY[idx] == self.address {
+				return true;
+			}
+			idx += 1;
+		}
+		false
+	}
+
+	/// Does writing to this register return an error?
+	pub const fn is_read_only(&self) -> bool {
+		matches!(self.access, Access::ReadOnly)
+	}
+}
+
+/// Find the register which answers at `address`, if there is one.
+///
+/// ```
+/// # use neotron_bmc_protocol::registers;
+/// assert_eq!(registers::find(0x25), Some(&registers::POWER_CONTROL));
+/// assert_eq!(registers::find(0xE8), Some(&registers::SCRATCH));
+/// assert_eq!(registers::find(0x7F), None);
+/// ```
+pub fn find(address: u8) -> Option<&'static Register> {
+	ALL.iter().find(|register| register.contains(address))
+}
+
+/// Splits a *Wide Read* into the plain reads it is made of.
+///
+/// Made by [`wide_read_pieces`]. Each item is the address and length of one
+/// read, or the result the NBMC gives if the *Wide Read* can't be done, after
+/// which there are no more items.
+#[derive(Debug, Clone)]
+pub struct WideReadPieces {
+	/// The address of the next read
+	address: u16,
+	/// How many bytes are still to be read
+	remaining: u8,
+	/// How many bytes each value takes up
+	width: u8,
+	/// The features of the firmware doing the reading
+	feature_flags: u32,
+	/// Why the *Wide Read* can't be done, if we already know
+	error: Option<ResponseResult>,
+}
+
+impl WideReadPieces {
+	/// Work out the next read.
+	fn piece(&mut self) -> Result<(u8, u8), ResponseResult> {
+		let address = u8::try_from(self.address).map_err(|_| ResponseResult::BadRegister)?;
+		let register = match find(address) {
+			Some(register) if register.is_available(self.feature_flags) => register,
+			_ => return Err(ResponseResult::BadRegister),
+		};
+		// FIFOs (and other registers of no set length) can't be read wide
+		let length = register
+			.fixed_len(address)
+			.ok_or(ResponseResult::BadLength)?;
+		if !length.is_multiple_of(self.width) {
+			return Err(ResponseResult::BadLength);
+		}
+		// Only a window can be read part way
+		let length = length.min(self.remaining);
+		if !register.allows_read(address, length) {
+			return Err(ResponseResult::BadLength);
+		}
+		self.remaining -= length;
+		self.address = u16::from(register.address) + u16::from(register.span());
+		Ok((address, length))
+	}
+}
+
+impl Iterator for WideReadPieces {
+	type Item = Result<(u8, u8), ResponseResult>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if let Some(result) = self.error.take() {
+			self.remaining = 0;
+			return Some(Err(result));
+		}
+		if self.remaining == 0 {
+			return None;
+		}
+		let piece = self.piece();
+		if piece.is_err() {
+			self.remaining = 0;
+		}
+		Some(piece)
+	}
+}
+
+/// Split a *Wide Read* of `count` values of the given width, starting at
+/// `address`, into the plain reads it is made of, for a firmware build with
+/// these feature flags.
+///
+/// Each register is read whole (or to the end, for a `Window`), and must hold
+/// a whole number of values. Then the address moves on to the next register.
+/// It must be one which is there - the run can't skip a gap, or a FIFO. The
+/// values must all fit in one *Read Response*, and there must be at least one.
+///
+/// ```
+/// # use neotron_bmc_protocol::{registers, ResponseResult, Width};
+/// // Firmware Build Time, and then Firmware Feature Flags
+/// let pieces: Vec<_> = registers::wide_read_pieces(0x05, Width::U32, 2, 0).collect();
+/// assert_eq!(pieces, [Ok((0x05, 4)), Ok((0x06, 4))]);
+/// // Protocol Version is three bytes
+/// let pieces: Vec<_> = registers::wide_read_pieces(0x00, Width::U16, 1, 0).collect();
+/// assert_eq!(pieces, [Err(ResponseResult::BadLength)]);
+/// ```
+pub fn wide_read_pieces(
+	address: u8,
+	width: Width,
+	count: u8,
+	feature_flags: u32,
+) -> WideReadPieces {
+	let length = u16::from(width.bytes()) * u16::from(count);
+	let error = if count == 0 || length > u16::from(MAX_READ_LEN) {
+		Some(ResponseResult::BadLength)
+	} else {
+		None
+	};
+	WideReadPieces {
+		address: u16::from(address),
+		remaining: length as u8,
+		width: width.bytes(),
+		feature_flags,
+		error,
+	}
+}
+
+/// Declares a constant for each register, and `ALL`, which holds them all.
+macro_rules! register_map {
+	($($ident:ident = ($address:literal, $name:literal, $access:ident, $length:expr, $requires:expr);)*) => {
+		$(
+			#[doc = $name]
+			pub const $ident: Register = Register {
+				address: $address,
+				name: $name,
+				access: Access::$access,
+				length: $length,
+				requires: $requires,
+			};
+		)*
+
+		/// Every register, in address order.
+		pub const ALL: &[Register] = &[$($ident),*];
+	};
+}
+
+register_map! {
+	PROTOCOL_VERSION = (0x00, "Protocol Version", ReadOnly, Exactly(3), Always);
+	FIRMWARE_VERSION = (0x01, "Firmware Version", ReadOnly, UpTo(32), Always);
+	BUILD_INFO = (0x02, "Build Info", ReadOnly, UpTo(32), Always);
+	FIRMWARE_SEMANTIC_VERSION = (0x03, "Firmware Semantic Version", ReadOnly, Exactly(3), Always);
+	FIRMWARE_GIT_HASH = (0x04, "Firmware Git Hash", ReadOnly, Exactly(8), Always);
+	FIRMWARE_BUILD_TIME = (0x05, "Firmware Build Time", ReadOnly, Exactly(4), Always);
+	FIRMWARE_FEATURE_FLAGS = (0x06, "Firmware Feature Flags", ReadOnly, Exactly(4), Always);
+	BMC_HEALTH = (0x07, "BMC Health", WriteOneToClear, Exactly(4), Always);
+	SPI_MAXIMUM_CLOCK = (0x08, "SPI Maximum Clock", ReadOnly, Exactly(1), Always);
+	SPI_MODES = (0x09, "SPI Modes", ReadOnly, Exactly(1), Always);
+	SPI_CLOCK = (0x0A, "SPI Clock", ReadWrite, Exactly(1), Always);
+	DEFERRED_OPERATION = (0x0B, "Deferred Operation", ReadWrite, Exactly(4), Always);
+	BMC_SELF_TEST = (0x0C, "BMC Self-Test", ReadWrite, Exactly(2), Always);
+	CONFIG_LOCK = (0x0D, "Configuration Lock", ReadWrite, Exactly(1), Always);
+	BMC_STATUS = (0x0E, "BMC Status", ReadOnly, Exactly(1), Always);
+	CONFIG_STORE = (0x0F, "Config Store", ReadWrite, Exactly(1), Always);
+	INTERRUPT_STATUS = (0x10, "Interrupt Status", WriteOneToClear, Exactly(1), Always);
+	INTERRUPT_CONTROL = (0x11, "Interrupt Control", Bitmask, Exactly(1), Always);
+	UART_RX_INTERRUPT_THRESHOLD = (0x12, "UART RX Interrupt Threshold", ReadWrite, Exactly(1), Always);
+	UART_RX_INTERRUPT_TIMEOUT = (0x13, "UART RX Interrupt Timeout", ReadWrite, Exactly(1), Always);
+	INTERRUPT_STATUS_HIGH = (0x14, "Interrupt Status (High)", WriteOneToClear, Exactly(1), Always);
+	INTERRUPT_CONTROL_HIGH = (0x15, "Interrupt Control (High)", Bitmask, Exactly(1), Always);
+	SPI_SERVICE_LATENCY = (0x16, "SPI Service Latency", ReadWrite, Exactly(8), Always);
+	PS2_SERVICE_LATENCY = (0x17, "PS/2 Service Latency", ReadWrite, Exactly(8), Always);
+	LIFETIME_COUNTERS = (0x18, "Lifetime Counters", ReadOnly, Exactly(16), Always);
+	READOUT_PROTECTION = (0x19, "Readout Protection", ReadWrite, Exactly(2), Always);
+	LOG_LEVEL = (0x1A, "Log Level", ReadWrite, Exactly(1), Always);
+	LOG_SUBSYSTEMS = (0x1B, "Log Subsystems", ReadWrite, Exactly(1), Always);
+	SPI_PADDING_BYTE = (0x1C, "SPI Padding Byte", ReadWrite, Exactly(1), Always);
+	SPI_SYNC_MARKER = (0x1D, "SPI Sync Marker", ReadWrite, Exactly(1), Always);
+	SPI_TRACE_COUNT = (0x1E, "SPI Trace Count", ReadWrite, Exactly(1), With(FEATURE_SPI_TRACE));
+	SPI_TRACE = (0x1F, "SPI Trace", Fifo, Entries(12), With(FEATURE_SPI_TRACE));
+	BUTTON_STATUS = (0x20, "Button Status", ReadOnly, Exactly(1), Always);
+	SYSTEM_TEMPERATURE = (0x21, "System Temperature", ReadOnly, Exactly(1), WithWithout(FEATURE_ADC, FEATURE_CURRENT_SENSE));
+	POWER_CONTROL = (0x25, "Power Control", ReadWrite, Exactly(1), Always);
+	WAKE_CONTROL = (0x26, "Wake Control", Bitmask, Exactly(1), Always);
+	EVENT_LOG_COUNT = (0x27, "Event Log Count", ReadWrite, Exactly(1), Always);
+	EVENT_LOG = (0x28, "Event Log", Fifo, Entries(6), Always);
+	MAIN_CURRENT = (0x29, "Main Current", ReadOnly, Exactly(2), With(FEATURE_CURRENT_SENSE));
+	PEAK_MAIN_CURRENT = (0x2A, "Peak Main Current", ReadWrite, Exactly(2), With(FEATURE_CURRENT_SENSE));
+	OVER_CURRENT_LIMIT = (0x2B, "Over-current Limit", ReadWrite, Exactly(1), With(FEATURE_CURRENT_SENSE));
+	CURRENT_SENSE_FULL_SCALE = (0x2C, "Current Sense Full Scale", ReadWrite, Exactly(2), With(FEATURE_CURRENT_SENSE));
+	DISK_ACTIVITY = (0x2E, "Disk Activity", ReadWrite, Exactly(1), Always);
+	POWER_RESTORE = (0x2F, "Power Restore", ReadWrite, Exactly(1), Always);
+	UART_DATA = (0x30, "UART Receive/Transmit Buffer", Fifo, UpTo(MAX_READ_LEN), Always);
+	UART_RX_COUNT = (0x35, "UART RX Count", ReadWrite, Exactly(2), Always);
+	UART_SELF_TEST = (0x36, "UART Self-Test", ReadWrite, Exactly(6), Always);
+	UART_CONSOLE_TAKEOVER = (0x37, "UART Console Takeover", ReadWrite, Exactly(1), With(FEATURE_PS2_KEYBOARD));
+	UART2_DATA = (0x38, "UART2 Receive/Transmit Buffer", Fifo, UpTo(MAX_READ_LEN), With(FEATURE_UART2));
+	UART2_RX_COUNT = (0x39, "UART2 RX Count", ReadWrite, Exactly(2), With(FEATURE_UART2));
+	UART2_TX_SPACE = (0x3A, "UART2 TX Space", ReadOnly, Exactly(1), With(FEATURE_UART2));
+	UART2_BAUD_RATE = (0x3B, "UART2 Baud Rate", ReadWrite, Exactly(2), With(FEATURE_UART2));
+	UART2_CONTROL = (0x3D, "UART2 Control", Bitmask, Exactly(1), With(FEATURE_UART2));
+	UART_REMOTE_CONSOLE = (0x3E, "UART Remote Console", ReadWrite, Exactly(1), Always);
+	UART_TX_SPACE = (0x3F, "UART TX Space", ReadOnly, Exactly(1), Always);
+	KEYBOARD_DATA = (0x40, "PS/2 Keyboard Receive/Transmit Buffer", Fifo, UpTo(MAX_READ_LEN), With(FEATURE_PS2_KEYBOARD));
+	KEYBOARD_CONTROL = (0x41, "PS/2 Keyboard Control", Bitmask, Exactly(1), With(FEATURE_PS2_KEYBOARD));
+	KEYBOARD_KEY_STATE = (0x43, "PS/2 Keyboard Key State", ReadOnly, UpTo(32), With(FEATURE_PS2_KEYBOARD));
+	KEYBOARD_BOOT_KEY = (0x44, "PS/2 Keyboard Boot Key", ReadWrite, Exactly(1), With(FEATURE_PS2_KEYBOARD));
+	BOOT_KEYS = (0x45, "Boot Keys", WriteOneToClear, Exactly(1), With(FEATURE_PS2_KEYBOARD));
+	RESET_CHORD_KEY_1 = (0x46, "Reset Chord Key 1", ReadWrite, Exactly(1), With(FEATURE_PS2_KEYBOARD));
+	RESET_CHORD_KEY_2 = (0x47, "Reset Chord Key 2", ReadWrite, Exactly(1), With(FEATURE_PS2_KEYBOARD));
+	RESET_CHORD_KEY_3 = (0x48, "Reset Chord Key 3", ReadWrite, Exactly(1), With(FEATURE_PS2_KEYBOARD));
+	PS2_PORT_POWER = (0x49, "PS/2 Port Power", Bitmask, Exactly(1), With(FEATURE_PS2_POWER));
+	PS2_POWER_CYCLE = (0x4A, "PS/2 Power Cycle", ReadWrite, Exactly(1), With(FEATURE_PS2_POWER));
+	KEYBOARD_SELF_TEST = (0x4B, "PS/2 Keyboard Self-Test", ReadWrite, Exactly(2), With(FEATURE_PS2_KEYBOARD));
+	KEYBOARD_ID = (0x4C, "PS/2 Keyboard ID", ReadOnly, Exactly(2), With(FEATURE_PS2_KEYBOARD));
+	KEYBOARD_INJECT = (0x4D, "PS/2 Keyboard Inject", ReadWrite, Exactly(1), With(FEATURE_PS2_KEYBOARD));
+	KEYBOARD_REPEAT_RATE = (0x4E, "PS/2 Keyboard Repeat Rate", ReadWrite, Exactly(1), With(FEATURE_PS2_KEYBOARD));
+	KEYBOARD_ASCII = (0x4F, "PS/2 Keyboard ASCII", Fifo, UpTo(MAX_READ_LEN), With(FEATURE_PS2_KEYBOARD));
+	MOUSE_REPORT_COUNT = (0x53, "PS/2 Mouse Report Count", ReadWrite, Exactly(1), With(FEATURE_PS2_MOUSE));
+	MOUSE_REPORTS = (0x54, "PS/2 Mouse Reports", Fifo, Entries(4), With(FEATURE_PS2_MOUSE));
+	MOUSE_MOVEMENT = (0x55, "PS/2 Mouse Movement", ReadOnly, Exactly(6), With(FEATURE_PS2_MOUSE));
+	MOUSE_SAMPLE_RATE = (0x56, "PS/2 Mouse Sample Rate", ReadWrite, Exactly(1), With(FEATURE_PS2_MOUSE));
+	MOUSE_RESOLUTION = (0x57, "PS/2 Mouse Resolution", ReadWrite, Exactly(1), With(FEATURE_PS2_MOUSE));
+	PS2_BIT_TIMING = (0x58, "PS/2 Bit Timing", ReadWrite, Exactly(16), Always);
+	PS2_ERRORS = (0x59, "PS/2 Errors", ReadWrite, Exactly(16), Always);
+	RTC_ALARM = (0x5A, "RTC Alarm", ReadWrite, Window(5), Always);
+	I2C_TARGET_ADDRESS = (0x65, "I²C Target Address", ReadWrite, Exactly(1), With(FEATURE_I2C_TARGET));
+	HOST_WATCHDOG_TIMEOUT = (0x66, "Host Watchdog Timeout", ReadWrite, Exactly(1), Always);
+	HOST_WATCHDOG_PRETIMEOUT = (0x67, "Host Watchdog Pretimeout", ReadWrite, Exactly(1), Always);
+	HOST_WATCHDOG_KICK = (0x68, "Host Watchdog Kick", ReadWrite, Exactly(1), Always);
+	FAN_CONTROL = (0x70, "Fan Control", Bitmask, Exactly(1), With(FEATURE_FAN));
+	FAN_DUTY_CYCLE = (0x71, "Fan Duty Cycle", ReadWrite, Exactly(1), With(FEATURE_FAN));
+	FAN_SPEED = (0x72, "Fan Speed", ReadOnly, Exactly(2), With(FEATURE_FAN));
+	OVER_TEMPERATURE_LIMIT = (0x73, "Over-temperature Limit", ReadWrite, Exactly(1), WithWithout(FEATURE_ADC, FEATURE_CURRENT_SENSE));
+	OVER_TEMPERATURE_TIMEOUT = (0x74, "Over-temperature Timeout", ReadWrite, Exactly(1), WithWithout(FEATURE_ADC, FEATURE_CURRENT_SENSE));
+	RTC_STATUS = (0x75, "RTC Status", WriteOneToClear, Exactly(1), Always);
+	RTC_CLOCK_SOURCE = (0x76, "RTC Clock Source", ReadWrite, Exactly(1), Always);
+	RTC_DATE_TIME = (0x77, "RTC Date Time", ReadWrite, Window(7), Always);
+	BUZZER_NOTE_FREQUENCY = (0x80, "Buzzer Note Frequency", ReadWrite, Exactly(2), WithWithout(FEATURE_SPEAKER, FEATURE_CURRENT_SENSE));
+	BUZZER_NOTE_DURATION = (0x82, "Buzzer Note Duration", ReadWrite, Exactly(1), WithWithout(FEATURE_SPEAKER, FEATURE_CURRENT_SENSE));
+	BUZZER_NOTE_GAP = (0x83, "Buzzer Note Gap", ReadWrite, Exactly(1), WithWithout(FEATURE_SPEAKER, FEATURE_CURRENT_SENSE));
+	BUZZER_QUEUE = (0x84, "Buzzer Queue", ReadWrite, Exactly(1), WithWithout(FEATURE_SPEAKER, FEATURE_CURRENT_SENSE));
+	WATCH_SELECT = (0x85, "Watch Select", ReadWrite, Exactly(1), Always);
+	WATCH_CONFIG = (0x86, "Watch Config", ReadWrite, Window(7), Always);
+	WATCH_STATUS = (0x8D, "Watch Status", WriteOneToClear, Exactly(1), Always);
+	IRQ_LINE_CONTROL = (0x90, "IRQ Line Control", ReadWrite, Exactly(1), Always);
+	IRQ_LINE_TEST = (0x91, "IRQ Line Test", ReadWrite, Exactly(1), Always);
+	REGISTER_CHANGES = (0x92, "Register Changes", WriteOneToClear, Exactly(1), Always);
+	MAIN_BOARD_PRESENCE = (0x93, "Main Board Presence", ReadWrite, Exactly(1), Always);
+	POWER_LED_MODE = (0x94, "Power LED Mode", ReadWrite, Exactly(1), Always);
+	POWER_LED_BRIGHTNESS = (0x95, "Power LED Brightness", ReadWrite, Exactly(1), Always);
+	STATUS_LED_RED = (0x96, "Status LED Red", ReadWrite, Exactly(1), With(FEATURE_STATUS_LED));
+	STATUS_LED_GREEN = (0x97, "Status LED Green", ReadWrite, Exactly(1), With(FEATURE_STATUS_LED));
+	STATUS_LED_BLUE = (0x98, "Status LED Blue", ReadWrite, Exactly(1), With(FEATURE_STATUS_LED));
+	STATUS_LED_PATTERN = (0x99, "Status LED Pattern", ReadWrite, Exactly(1), With(FEATURE_STATUS_LED));
+	STATUS_LED_FAULT = (0x9A, "Status LED Fault", ReadWrite, Exactly(1), With(FEATURE_STATUS_LED));
+	RISE_TIMES = (0x9B, "Rise Times", ReadOnly, Exactly(6), Always);
+	RISE_TIME_FAULTS = (0x9C, "Rise Time Faults", ReadOnly, Exactly(1), Always);
+	INTERRUPT_LEVEL = (0x9D, "Interrupt Level", ReadOnly, Exactly(1), Always);
+	INTERRUPT_LEVEL_HIGH = (0x9E, "Interrupt Level (High)", ReadOnly, Exactly(1), Always);
+	GPIO_AVAILABLE = (0xA0, "GPIO Available", ReadOnly, Exactly(1), Always);
+	GPIO_DIRECTION = (0xA1, "GPIO Direction", Bitmask, Exactly(1), Always);
+	GPIO_OUTPUT = (0xA2, "GPIO Output", Bitmask, Exactly(1), Always);
+	GPIO_INPUT = (0xA3, "GPIO Input", ReadOnly, Exactly(1), Always);
+	GPIO_PULL = (0xA4, "GPIO Pull", Bitmask, Exactly(1), Always);
+	PWM_FREQUENCY = (0xA8, "PWM Frequency", ReadWrite, Exactly(2), Without(FEATURES_USING_GPIO));
+	PWM_DUTY_CYCLE = (0xAA, "PWM Duty Cycle", ReadWrite, Exactly(1), Without(FEATURES_USING_GPIO));
+	PWM_CONTROL = (0xAB, "PWM Control", Bitmask, Exactly(1), Without(FEATURES_USING_GPIO));
+	ADC_CHANNEL_INTERVALS = (0xAC, "ADC Channel Intervals", ReadWrite, Window(3), With(FEATURE_ADC));
+	ANALOG_INPUTS_AVAILABLE = (0xB0, "Analog Inputs Available", ReadOnly, Exactly(1), With(FEATURE_ADC));
+	ANALOG_SAMPLE_INTERVAL = (0xB1, "Analog Sample Interval", ReadWrite, Exactly(1), With(FEATURE_ADC));
+	ANALOG_AVERAGING = (0xB2, "Analog Averaging", ReadWrite, Exactly(1), With(FEATURE_ADC));
+	ANALOG_INPUT_READINGS = (0xB3, "Analog Input Readings", ReadOnly, Exactly(4), With(FEATURE_ADC));
+	ANALOG_REFERENCE = (0xB4, "Analog Reference", ReadOnly, Exactly(2), With(FEATURE_ADC));
+	ANALOG_INPUT_HISTORY = (0xB5, "Analog Input History", ReadWrite, Exactly(12), With(FEATURE_ADC));
+	ANALOG_CALIBRATION = (0xB6, "Analog Calibration", ReadWrite, Window(8), With(FEATURE_ADC));
+	ANALOG_CALIBRATE = (0xBE, "Analog Calibrate", ReadWrite, Exactly(1), With(FEATURE_ADC));
+	ADC_CHANNEL_FILTERS = (0xBF, "ADC Channel Filters", ReadWrite, Exactly(1), With(FEATURE_ADC));
+	POWER_BUTTON_ACTION = (0xC0, "Power Button Action", ReadWrite, Exactly(1), Always);
+	RESET_BUTTON_ACTION = (0xC1, "Reset Button Action", ReadWrite, Exactly(1), Always);
+	GPIO0_BUTTON_ACTION = (0xC2, "GPIO 0 Button Action", ReadWrite, Exactly(1), Always);
+	GPIO1_BUTTON_ACTION = (0xC3, "GPIO 1 Button Action", ReadWrite, Exactly(1), Always);
+	BUTTON_EVENTS = (0xC4, "Button Events", WriteOneToClear, Exactly(1), Always);
+	BUTTON_GLITCH_FILTER = (0xC5, "Button Glitch Filter", ReadWrite, Exactly(1), Always);
+	CS_GLITCH_FILTER = (0xC6, "CS Glitch Filter", ReadWrite, Exactly(1), Always);
+	GLITCH_COUNTERS = (0xC7, "Glitch Counters", ReadWrite, Exactly(6), Always);
+	ENCODER_POSITION = (0xC8, "Encoder Position", ReadWrite, Exactly(2), With(FEATURE_ENCODER));
+	ENCODER_VELOCITY = (0xC9, "Encoder Velocity", ReadOnly, Exactly(2), With(FEATURE_ENCODER));
+	MAIN_BOARD_RESET = (0xCA, "Main Board Reset", ReadWrite, Exactly(1), Always);
+	BOOT_MODE = (0xCB, "Boot Mode", ReadWrite, Exactly(1), Always);
+	NVRAM_PAGE = (0xCC, "NVRAM Page", ReadWrite, Exactly(1), Always);
+	NVRAM_CONTROL = (0xCD, "NVRAM Control", ReadWrite, Exactly(1), Always);
+	NVRAM_WINDOW = (0xD0, "NVRAM Window", ReadWrite, Window(16), Always);
+	SCRATCH = (0xE0, "Scratch Registers", ReadWrite, Window(32), Always);
+}
+
+/// Write the whole register map out as JSON, for Host drivers and tools
+/// which aren't written in Rust.
+///
+/// `examples/register_map.rs` prints this, and `registers.json` in this
+/// crate is a copy of it (which the tests check is up to date).
+///
+/// ```
+/// # use neotron_bmc_protocol::registers;
+/// let mut json = String::new();
+/// registers::write_json(&mut json).unwrap();
+/// assert!(json.contains(r#""name": "Power Control""#));
+/// ```
+pub fn write_json<W>(out: &mut W) -> core::fmt::Result
+where
+	W: core::fmt::Write,
+{
+	writeln!(out, "{{")?;
+	writeln!(out, "  \"version\": \"{}\",", env!("CARGO_PKG_VERSION"))?;
+	writeln!(out, "  \"max_read_len\": {},", MAX_READ_LEN)?;
+	writeln!(out, "  \"features\": [")?;
+	for (idx, (flag, name)) in FEATURE_NAMES.iter().enumerate() {
+		let comma = if idx + 1 == FEATURE_NAMES.len() {
+			""
+		} else {
+			","
+		};
+		writeln!(
+			out,
+			"    {{ \"name\": \"{}\", \"flag\": {} }}{}",
+			name, flag, comma
+		)?;
+	}
+	writeln!(out, "  ],")?;
+	writeln!(out, "  \"registers\": [")?;
+	for (idx, register) in ALL.iter().enumerate() {
+		writeln!(out, "    {{")?;
+		writeln!(out, "      \"address\": {},", register.address)?;
+		write!(out, "      \"name\": ")?;
+		write_json_string(out, register.name)?;
+		writeln!(out, ",")?;
+		let access = match register.access {
+			Access::ReadOnly => "read-only",
+			Access::ReadWrite => "read-write",
+			Access::Bitmask => "bitmask",
+			Access::WriteOneToClear => "write-one-to-clear",
+			Access::Fifo => "fifo",
+		};
+		writeln!(out, "      \"access\": \"{}\",", access)?;
+		let (kind, len) = match register.length {
+			Exactly(len) => ("exactly", len),
+			UpTo(len) => ("up_to", len),
+			Entries(len) => ("entries", len),
+			Window(len) => ("window", len),
+		};
+		writeln!(out, "      \"length\": {{ \"{}\": {} }},", kind, len)?;
+		write!(out, "      \"requires\": ")?;
+		match register.requires {
+			Always => write!(out, "\"always\"")?,
+			With(features) => {
+				write!(out, "{{ ")?;
+				write_json_features(out, "with", features)?;
+				write!(out, " }}")?;
+			}
+			Without(features) => {
+				write!(out, "{{ ")?;
+				write_json_features(out, "without", features)?;
+				write!(out, " }}")?;
+			}
+			WithWithout(with, without) => {
+				write!(out, "{{ ")?;
+				write_json_features(out, "with", with)?;
+				write!(out, ", ")?;
+				write_json_features(out, "without", without)?;
+				write!(out, " }}")?;
+			}
+		}
+		writeln!(out, ",")?;
+		writeln!(out, "      \"guarded\": {},", register.is_guarded())?;
+		writeln!(out, "      \"early\": {},", register.is_early())?;
+		writeln!(
+			out,
+			"      \"bulk_writable\": {}",
+			register.allows_bulk_write(1)
+		)?;
+		let comma = if idx + 1 == ALL.len() { "" } else { "," };
+		writeln!(out, "    }}{}", comma)?;
+	}
+	writeln!(out, "  ]")?;
+	writeln!(out, "}}")
+}
+
+/// Write a string as JSON, with quotes and any escapes it needs.
+fn write_json_string<W>(out: &mut W, value: &str) -> core::fmt::Result
+where
+	W: core::fmt::Write,
+{
+	out.write_char('"')?;
+	for ch in value.chars() {
+		match ch {
+			'"' => out.write_str("\\\"")?,
+			'\\' => out.write_str("\\\\")?,
+			ch if (ch as u32) < 0x20 => write!(out, "\\u{:04x}", ch as u32)?,
+			ch => out.write_char(ch)?,
+		}
+	}
+	out.write_char('"')
+}
+
+/// Write one half of a `Requires` condition as a JSON member, naming the
+/// features.
+fn write_json_features<W>(out: &mut W, condition: &str, features: u32) -> core::fmt::Result
+where
+	W: core::fmt::Write,
+{
+	write!(out, "\"{}\": [", condition)?;
+	let mut first = true;
+	for (_, name) in FEATURE_NAMES
+		.iter()
+		.filter(|(flag, _)| (features & flag) != 0)
+	{
+		if !first {
+			write!(out, ", ")?;
+		}
+		write!(out, "\"{}\"", name)?;
+		first = false;
+	}
+	write!(out, "]")
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn table_is_sorted_and_distinct() {
+		for pair in ALL.windows(2) {
+			assert!(
+				pair[0].address < pair[1].address,
+				"{} is out of order",
+				pair[1].name
+			);
+			assert!(
+				!pair[0].contains(pair[1].address),
+				"{} overlaps {}",
+				pair[0].name,
+				pair[1].name
+			);
+		}
+	}
+
+	#[test]
+	fn read_lengths() {
+		assert!(POWER_CONTROL.allows_read(0x25, 1));
+		assert!(!POWER_CONTROL.allows_read(0x25, 2));
+		assert!(!POWER_CONTROL.allows_read(0x26, 1));
+		assert!(FIRMWARE_VERSION.allows_read(0x01, 0));
+		assert!(!FIRMWARE_VERSION.allows_read(0x01, 33));
+		assert!(MOUSE_REPORTS.allows_read(0x54, MAX_READ_LEN));
+		assert!(!MOUSE_REPORTS.allows_read(0x54, MAX_READ_LEN + 4));
+		assert!(SCRATCH.allows_read(0xFF, 1));
+		assert!(!SCRATCH.allows_read(0xFF, 2));
+		assert!(!SCRATCH.allows_read(0xDF, 1));
+	}
+
+	#[test]
+	fn guarded_registers_exist() {
+		for &address in GUARDED {
+			let register = find(address).unwrap();
+			assert_eq!(register.address, address);
+			assert!(
+				!register.is_read_only(),
+				"{} can't be written",
+				register.name
+			);
+		}
+	}
+
+	#[test]
+	fn flash_writers_are_guarded() {
+		for register in [CONFIG_STORE, READOUT_PROTECTION, BOOT_MODE, NVRAM_CONTROL] {
+			assert!(register.is_guarded(), "{} isn't guarded", register.name);
+		}
+	}
+
+	#[test]
+	fn early_registers_exist() {
+		for &address in EARLY {
+			let register = find(address).unwrap();
+			assert_eq!(register.requires, Always, "{} is optional", register.name);
+		}
+	}
+
+	#[test]
+	fn bulk_writable_registers_are_fifos() {
+		for &address in BULK_WRITABLE {
+			let register = find(address).unwrap();
+			assert_eq!(
+				register.access,
+				Access::Fifo,
+				"{} isn't a FIFO",
+				register.name
+			);
+		}
+	}
+
+	#[test]
+	fn wide_reads() {
+		let pieces = |address, width, count, features| {
+			wide_read_pieces(address, width, count, features).collect::<Vec<_>>()
+		};
+		// A whole register, as values
+		assert_eq!(pieces(0x18, Width::U32, 4, 0), [Ok((0x18, 16))]);
+		// A run of registers
+		assert_eq!(
+			pieces(0x29, Width::U16, 2, FEATURE_CURRENT_SENSE),
+			[Ok((0x29, 2)), Ok((0x2A, 2))]
+		);
+		// Registers which aren't in this build
+		assert_eq!(
+			pieces(0x29, Width::U16, 2, 0),
+			[Err(ResponseResult::BadRegister)]
+		);
+		// Part of a register
+		assert_eq!(
+			pieces(0x18, Width::U32, 1, 0),
+			[Err(ResponseResult::BadLength)]
+		);
+		// Part of a window is fine, and the rest of one moves on past it
+		assert_eq!(pieces(0xE4, Width::U32, 2, 0), [Ok((0xE4, 8))]);
+		assert_eq!(pieces(0xFC, Width::U32, 1, 0), [Ok((0xFC, 4))]);
+		assert_eq!(
+			pieces(0xFC, Width::U32, 2, 0),
+			[Ok((0xFC, 4)), Err(ResponseResult::BadRegister)]
+		);
+		// A gap in the map
+		assert_eq!(
+			pieces(0x68, Width::U8, 2, 0),
+			[Ok((0x68, 1)), Err(ResponseResult::BadRegister)]
+		);
+		// FIFOs have no set length
+		assert_eq!(
+			pieces(0x54, Width::U32, 1, FEATURE_PS2_MOUSE),
+			[Err(ResponseResult::BadLength)]
+		);
+		// At least one value, and no more than fit in one response
+		assert_eq!(
+			pieces(0xE0, Width::U8, 0, 0),
+			[Err(ResponseResult::BadLength)]
+		);
+		assert_eq!(
+			pieces(0xE0, Width::U32, 15, 0),
+			[Ok((0xE0, 32)), Err(ResponseResult::BadRegister)]
+		);
+		assert_eq!(
+			pieces(0xE0, Width::U32, 16, 0),
+			[Err(ResponseResult::BadLength)]
+		);
+	}
+
+	#[test]
+	fn bulk_read_lengths() {
+		assert!(UART_DATA.allows_bulk_read(1));
+		assert!(UART_DATA.allows_bulk_read(MAX_READ_LEN + 1));
+		assert!(!UART_DATA.allows_bulk_read(0));
+		assert!(MOUSE_REPORTS.allows_bulk_read(MAX_READ_LEN * 2 + 4));
+		assert!(!MOUSE_REPORTS.allows_bulk_read(MAX_READ_LEN * 2 + 2));
+		assert!(!BUILD_INFO.allows_bulk_read(32));
+	}
+}
@@ -0,0 +1,196 @@
+//! # Register Values
+//!
+//! Multi-byte registers hold their values little-endian, and some registers
+//! are a set of flags. These helpers turn the bytes of a register into a
+//! typed value, and back again, so neither side has to assemble the bytes by
+//! hand.
+
+use defmt::Format;
+
+use crate::Error;
+
+/// A value held in a register.
+///
+/// ```
+/// # use neotron_bmc_protocol::values::RegisterValue;
+/// let mut buffer = [0u8; 4];
+/// assert_eq!(0x1234u16.encode(&mut buffer), Ok(2));
+/// assert_eq!(&buffer[0..2], [0x34, 0x12]);
+/// assert_eq!(u16::decode(&buffer[0..2]), Ok(0x1234));
+/// assert_eq!(i16::decode(&[0xFE, 0xFF]), Ok(-2));
+/// ```
+pub trait RegisterValue: Sized + Copy {
+	/// How many bytes the value takes up in the register.
+	const SIZE: usize;
+
+	/// Write the value into the start of `buffer`, returning how many bytes
+	/// were used.
+	///
+	/// You get an error if `buffer` is shorter than `SIZE`.
+	fn encode(&self, buffer: &mut [u8]) -> Result<usize, Error>;
+
+	/// Read a value from `data`.
+	///
+	/// You get an error unless `data` is exactly `SIZE` bytes long.
+	fn decode(data: &[u8]) -> Result<Self, Error>;
+
+	/// Replace one byte of the value.
+	///
+	/// Short Writes only carry one byte, so the Host writes a multi-byte
+	/// register a byte at a time - byte 0 (the least significant) to the
+	/// register's address, byte 1 to the next address, and so on. Bytes past
+	/// the end of the value are ignored.
+	///
+	/// ```
+	/// # use neotron_bmc_protocol::values::RegisterValue;
+	/// assert_eq!(0x1234u16.with_byte(1, 0xAB), 0xAB34);
+	/// assert_eq!(0x1234u16.with_byte(2, 0xAB), 0x1234);
+	/// ```
+	fn with_byte(self, index: usize, byte: u8) -> Self {
+		let mut bytes = [0u8; 4];
+		match self.encode(&mut bytes) {
+			Ok(len) if index < len => {
+				bytes[index] = byte;
+				Self::decode(&bytes[0..len]).unwrap_or(self)
+			}
+			_ => self,
+		}
+	}
+}
+
+/// Implements [`RegisterValue`] for integers, stored little-endian.
+macro_rules! impl_register_value {
+	($($t:ty),*) => {
+		$(
+			impl RegisterValue for $t {
+				const SIZE: usize = core::mem::size_of::<$t>();
+
+				fn encode(&self, buffer: &mut [u8]) -> Result<usize, Error> {
+					let buffer = buffer
+						.get_mut(0..Self::SIZE)
+						.ok_or(Error::BufferTooSmall)?;
+					buffer.copy_from_slice(&self.to_le_bytes());
+					Ok(Self::SIZE)
+				}
+
+				fn decode(data: &[u8]) -> Result<Self, Error> {
+					let bytes = data.try_into().map_err(|_| Error::BadLength)?;
+					Ok(<$t>::from_le_bytes(bytes))
+				}
+			}
+		)*
+	};
+}
+
+impl_register_value!(u8, i8, u16, i16, u32, i32);
+
+/// A register which holds a set of flags, one per bit, like Interrupt Status
+/// or the Firmware Feature Flags.
+///
+/// ```
+/// # use neotron_bmc_protocol::values::{Flags, RegisterValue};
+/// let mut flags = Flags::<u8>::decode(&[0b0000_0101]).unwrap();
+/// assert!(flags.contains(0b0000_0001));
+/// assert!(!flags.contains(0b0000_0011));
+/// assert!(flags.intersects(0b0000_0011));
+/// flags.remove(0b0000_0001);
+/// flags.insert(0b1000_0000);
+/// assert_eq!(flags.bits(), 0b1000_0100);
+/// ```
+#[derive(Debug, Copy, Clone, Format, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Flags<T>(pub T);
+
+/// Implements [`Flags`] for an integer type.
+macro_rules! impl_flags {
+	($($t:ty),*) => {
+		$(
+			impl Flags<$t> {
+				/// Get the raw bits.
+				pub const fn bits(&self) -> $t {
+					self.0
+				}
+
+				/// Are all the bits in `mask` set?
+				pub const fn contains(&self, mask: $t) -> bool {
+					(self.0 & mask) == mask
+				}
+
+				/// Are any of the bits in `mask` set?
+				pub const fn intersects(&self, mask: $t) -> bool {
+					(self.0 & mask) != 0
+				}
+
+				/// Set the bits in `mask`.
+				pub fn insert(&mut self, mask: $t) {
+					self.0 |= mask;
+				}
+
+				/// Clear the bits in `mask`.
+				pub fn remove(&mut self, mask: $t) {
+					self.0 &= !mask;
+				}
+			}
+
+			impl RegisterValue for Flags<$t> {
+				const SIZE: usize = <$t as RegisterValue>::SIZE;
+
+				fn encode(&self, buffer: &mut [u8]) -> Result<usize, Error> {
+					self.0.encode(buffer)
+				}
+
+				fn decode(data: &[u8]) -> Result<Self, Error> {
+					<$t>::decode(data).map(Flags)
+				}
+			}
+		)*
+	};
+}
+
+impl_flags!(u8, u16, u32);
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn integers() {
+		let mut buffer = [0u8; 4];
+		assert_eq!(0x1234_5678u32.encode(&mut buffer), Ok(4));
+		assert_eq!(buffer, [0x78, 0x56, 0x34, 0x12]);
+		assert_eq!(u32::decode(&buffer), Ok(0x1234_5678));
+		assert_eq!((-2i32).encode(&mut buffer), Ok(4));
+		assert_eq!(buffer, [0xFE, 0xFF, 0xFF, 0xFF]);
+		assert_eq!(i32::decode(&buffer), Ok(-2));
+		assert_eq!(i8::decode(&[0x80]), Ok(i8::MIN));
+		assert_eq!(u8::decode(&[0x42]), Ok(0x42));
+		// Wrong lengths
+		assert_eq!(u16::decode(&[0x01]), Err(Error::BadLength));
+		assert_eq!(u16::decode(&[0x01, 0x02, 0x03]), Err(Error::BadLength));
+		assert_eq!(0u32.encode(&mut buffer[0..3]), Err(Error::BufferTooSmall));
+	}
+
+	#[test]
+	fn byte_at_a_time() {
+		let value = 0u32.with_byte(0, 0x78).with_byte(1, 0x56);
+		assert_eq!(value.with_byte(2, 0x34).with_byte(3, 0x12), 0x1234_5678);
+		assert_eq!((-1i16).with_byte(1, 0x00), 0x00FF);
+		assert_eq!(0x12u8.with_byte(0, 0x34), 0x34);
+		assert_eq!(0x12u8.with_byte(1, 0x34), 0x12);
+	}
+
+	#[test]
+	fn flags() {
+		let mut buffer = [0u8; 4];
+		let mut flags = Flags::<u32>::default();
+		flags.insert(1 << 16);
+		assert_eq!(flags.encode(&mut buffer), Ok(4));
+		assert_eq!(buffer, [0x00, 0x00, 0x01, 0x00]);
+		assert_eq!(Flags::<u32>::decode(&buffer), Ok(flags));
+		assert!(flags.contains(1 << 16));
+		assert!(!flags.intersects(1));
+		flags.remove(1 << 16);
+		assert_eq!(flags, Flags(0));
+		assert_eq!(Flags::<u16>::decode(&[0x01]), Err(Error::BadLength));
+	}
+}
@@ -0,0 +1,209 @@
+//! # Conformance Vectors
+//!
+//! Golden byte sequences for the SPI protocol, kept in `tests/vectors/` in
+//! this crate. Anything which speaks the protocol can check itself against
+//! them - this crate's own tests check the encoders and decoders, the
+//! firmware's tests feed the exchanges through its request handler, and the
+//! hardware-in-the-loop tests send them to a real NBMC. So a change which
+//! breaks wire compatibility fails somewhere, straight away.
+//!
+//! Each vector file is plain text, one vector per line, with `#` comments.
+//! The top of each file says what its lines mean.
+//!
+//! ```
+//! # use neotron_bmc_protocol::vectors::{self, Vector};
+//! # use neotron_bmc_protocol::{Receivable, Request};
+//! for vector in vectors::parse(vectors::REQUESTS) {
+//!     if let Vector::Request { request, bytes } = vector.unwrap() {
+//!         let mut buffer = [0u8; 4];
+//!         let bytes = bytes.copy_into(&mut buffer).unwrap();
+//!         assert_eq!(Request::from_bytes(bytes), Ok(request));
+//!     }
+//! }
+//! ```
+
+use crate::{Error, Request, ResponseResult};
+
+/// Requests, good and bad
+pub const REQUESTS: &str = include_str!("../tests/vectors/requests.txt");
+
+/// Responses, good and bad
+pub const RESPONSES: &str = include_str!("../tests/vectors/responses.txt");
+
+/// Requests sent to a newly started NBMC, and how it answers them
+pub const EXCHANGES: &str = include_str!("../tests/vectors/exchanges.txt");
+
+/// Some bytes, written in a vector file as space separated hex.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Hex<'a>(&'a str);
+
+/// One line of a vector file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Vector<'a> {
+	/// `request` goes on the wire as `bytes`, and decoding `bytes` gives it
+	/// back.
+	Request { request: Request, bytes: Hex<'a> },
+	/// A Response with `result` and `data` goes on the wire as `bytes`, and
+	/// decoding `bytes` gives it back.
+	Response {
+		result: ResponseResult,
+		data: Hex<'a>,
+		bytes: Hex<'a>,
+	},
+	/// Decoding `bytes` as a Request fails with `error`.
+	BadRequest { error: Error, bytes: Hex<'a> },
+	/// Decoding `bytes` as a Response fails with `error`.
+	BadResponse { error: Error, bytes: Hex<'a> },
+	/// A newly started NBMC answers `request` with `response`.
+	Exchange { request: Hex<'a>, response: Hex<'a> },
+}
+
+/// A line of a vector file which doesn't make sense.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BadLine {
+	/// The line number, counting from one
+	pub line: usize,
+}
+
+impl<'a> Hex<'a> {
+	/// Check `text` is all hex bytes.
+	fn new(text: &'a str) -> Option<Hex<'a>> {
+		let hex = Hex(text);
+		hex.words()
+			.all(|word| word.len() == 2 && u8::from_str_radix(word, 16).is_ok())
+			.then_some(hex)
+	}
+
+	/// The hex bytes, as text.
+	fn words(&self) -> impl Iterator<Item = &'a str> {
+		self.0.split_ascii_whitespace()
+	}
+
+	/// The bytes.
+	pub fn bytes(&self) -> impl Iterator<Item = u8> + 'a {
+		// Checked in `Hex::new`
+		self.words()
+			.map(|word| u8::from_str_radix(word, 16).unwrap_or_default())
+	}
+
+	/// How many bytes there are.
+	pub fn len(&self) -> usize {
+		self.words().count()
+	}
+
+	/// Are there no bytes at all?
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	/// Copy the bytes into the start of `buffer`, and return them.
+	///
+	/// You get an error if `buffer` is too short.
+	pub fn copy_into<'b>(&self, buffer: &'b mut [u8]) -> Result<&'b [u8], Error> {
+		let len = self.len();
+		let buffer = buffer.get_mut(0..len).ok_or(Error::BufferTooSmall)?;
+		for (slot, byte) in buffer.iter_mut().zip(self.bytes()) {
+			*slot = byte;
+		}
+		Ok(buffer)
+	}
+}
+
+/// Go through the vectors in a vector file, skipping the comments and blank
+/// lines.
+pub fn parse(text: &str) -> impl Iterator<Item = Result<Vector<'_>, BadLine>> {
+	text.lines()
+		.enumerate()
+		.map(|(idx, line)| (idx + 1, line.trim()))
+		.filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+		.map(|(line, text)| parse_line(text).ok_or(BadLine { line }))
+}
+
+/// Make sense of one line.
+fn parse_line(text: &str) -> Option<Vector<'_>> {
+	let (left, right) = text.split_once('=')?;
+	let bytes = Hex::new(right)?;
+	let (kind, args) = left.trim().split_once(' ').unwrap_or((left.trim(), ""));
+	let args = args.trim();
+	match kind {
+		"bad-request" => Some(Vector::BadRequest {
+			error: error_named(args)?,
+			bytes,
+		}),
+		"bad-response" => Some(Vector::BadResponse {
+			error: error_named(args)?,
+			bytes,
+		}),
+		"response" => {
+			let (name, data) = args.split_once(' ').unwrap_or((args, ""));
+			Some(Vector::Response {
+				result: result_named(name)?,
+				data: Hex::new(data)?,
+				bytes,
+			})
+		}
+		"exchange" => Some(Vector::Exchange {
+			request: Hex::new(args)?,
+			response: bytes,
+		}),
+		_ => {
+			let (use_alt, args) = args.split_once(' ')?;
+			let use_alt = match use_alt {
+				"0" => false,
+				"1" => true,
+				_ => return None,
+			};
+			let mut args = Hex::new(args)?.bytes();
+			let (register, value) = (args.next()?, args.next()?);
+			if args.next().is_some() {
+				return None;
+			}
+			let request = match kind {
+				"read" => Request::new_read(use_alt, register, value),
+				"short-write" => Request::new_short_write(use_alt, register, value),
+				"long-write" => Request::new_long_write(use_alt, register, value),
+				"set-bits" => Request::new_set_bits(use_alt, register, value),
+				"clear-bits" => Request::new_clear_bits(use_alt, register, value),
+				"bulk-read-start" => Request::new_bulk_read_start(use_alt, register, value),
+				"bulk-read-continue" => Request::new_bulk_read_continue(use_alt, register, value),
+				"wide-read" => {
+					// The value is the Shape byte
+					let (width, count) =
+						Request::new_read(use_alt, register, value).wide_read_shape()?;
+					Request::new_wide_read(use_alt, register, width, count)
+				}
+				"bulk-write-start" => Request::new_bulk_write_start(use_alt, register, value),
+				"bulk-write-continue" => Request::new_bulk_write_continue(use_alt, register, value),
+				_ => return None,
+			};
+			Some(Vector::Request { request, bytes })
+		}
+	}
+}
+
+/// Find an [`Error`] by its name in the code.
+fn error_named(name: &str) -> Option<Error> {
+	match name {
+		"BadCrc" => Some(Error::BadCrc),
+		"BadLength" => Some(Error::BadLength),
+		"BadRequestType" => Some(Error::BadRequestType),
+		"BufferTooSmall" => Some(Error::BufferTooSmall),
+		"BadResponseResult" => Some(Error::BadResponseResult),
+		_ => None,
+	}
+}
+
+/// Find a [`ResponseResult`] by its name in the code.
+fn result_named(name: &str) -> Option<ResponseResult> {
+	match name {
+		"Ok" => Some(ResponseResult::Ok),
+		"CrcFailure" => Some(ResponseResult::CrcFailure),
+		"BadRequestType" => Some(ResponseResult::BadRequestType),
+		"BadRegister" => Some(ResponseResult::BadRegister),
+		"BadLength" => Some(ResponseResult::BadLength),
+		"Busy" => Some(ResponseResult::Busy),
+		"Locked" => Some(ResponseResult::Locked),
+		"NotReady" => Some(ResponseResult::NotReady),
+		_ => None,
+	}
+}
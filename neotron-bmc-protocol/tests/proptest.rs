@@ -0,0 +1,137 @@
+//! Property tests for the protocol decoders.
+//!
+//! The NBMC decodes whatever bytes arrive over SPI, so none of these decoders
+//! may panic, whatever we throw at them.
+
+use neotron_bmc_protocol::{
+	calculate_crc, BuildInfo, Error, Payload, ProtocolVersion, Receivable, Request, RequestType,
+	Response, ResponseResult, Sendable,
+};
+use proptest::prelude::*;
+
+/// All the valid Request Type bytes
+const REQUEST_TYPES: [RequestType; 20] = [
+	RequestType::Read,
+	RequestType::ReadAlt,
+	RequestType::ShortWrite,
+	RequestType::ShortWriteAlt,
+	RequestType::LongWrite,
+	RequestType::LongWriteAlt,
+	RequestType::SetBits,
+	RequestType::SetBitsAlt,
+	RequestType::ClearBits,
+	RequestType::ClearBitsAlt,
+	RequestType::BulkReadStart,
+	RequestType::BulkReadStartAlt,
+	RequestType::BulkReadContinue,
+	RequestType::BulkReadContinueAlt,
+	RequestType::WideRead,
+	RequestType::WideReadAlt,
+	RequestType::BulkWriteStart,
+	RequestType::BulkWriteStartAlt,
+	RequestType::BulkWriteContinue,
+	RequestType::BulkWriteContinueAlt,
+];
+
+proptest! {
+	#[test]
+	fn request_from_arbitrary_bytes(data in proptest::collection::vec(any::<u8>(), 0..64)) {
+		let _ = Request::from_bytes(&data);
+	}
+
+	#[test]
+	fn response_from_arbitrary_bytes(data in proptest::collection::vec(any::<u8>(), 0..300)) {
+		let _ = Response::from_bytes(&data);
+	}
+
+	#[test]
+	fn others_from_arbitrary_bytes(data in proptest::collection::vec(any::<u8>(), 0..64)) {
+		let _ = ProtocolVersion::from_bytes(&data);
+		let _ = BuildInfo::from_bytes(&data);
+		let _ = Payload::from_bytes(&data);
+	}
+
+	#[test]
+	fn truncated_request(data in proptest::collection::vec(any::<u8>(), 0..4)) {
+		prop_assert_eq!(Request::from_bytes(&data), Err(Error::BadLength));
+	}
+
+	#[test]
+	fn request_round_trip(type_idx in 0..REQUEST_TYPES.len(), register: u8, length_or_data: u8) {
+		let request_type = REQUEST_TYPES[type_idx];
+		let use_alt = (request_type as u8 & 1) != 0;
+		let req = match request_type {
+			RequestType::Read | RequestType::ReadAlt => Request::new_read(use_alt, register, length_or_data),
+			RequestType::ShortWrite | RequestType::ShortWriteAlt => Request::new_short_write(use_alt, register, length_or_data),
+			RequestType::LongWrite | RequestType::LongWriteAlt => Request::new_long_write(use_alt, register, length_or_data),
+			RequestType::SetBits | RequestType::SetBitsAlt => Request::new_set_bits(use_alt, register, length_or_data),
+			RequestType::ClearBits | RequestType::ClearBitsAlt => Request::new_clear_bits(use_alt, register, length_or_data),
+			RequestType::BulkReadStart | RequestType::BulkReadStartAlt => Request::new_bulk_read_start(use_alt, register, length_or_data),
+			RequestType::BulkReadContinue | RequestType::BulkReadContinueAlt => Request::new_bulk_read_continue(use_alt, register, length_or_data),
+			RequestType::WideRead | RequestType::WideReadAlt => match Request::new_read(use_alt, register, length_or_data).wide_read_shape() {
+				Some((width, count)) => Request::new_wide_read(use_alt, register, width, count),
+				// The reserved width can't be made
+				None => return Ok(()),
+			},
+			RequestType::BulkWriteStart | RequestType::BulkWriteStartAlt => Request::new_bulk_write_start(use_alt, register, length_or_data),
+			RequestType::BulkWriteContinue | RequestType::BulkWriteContinueAlt => Request::new_bulk_write_continue(use_alt, register, length_or_data),
+		};
+		let bytes = req.as_bytes();
+		let decoded = Request::from_bytes(&bytes).unwrap();
+		prop_assert_eq!(decoded.request_type, request_type);
+		prop_assert_eq!(decoded.register, register);
+		prop_assert_eq!(decoded.length_or_data, length_or_data);
+		prop_assert_eq!(decoded, req);
+	}
+
+	#[test]
+	fn request_bad_crc(type_idx in 0..REQUEST_TYPES.len(), register: u8, length_or_data: u8, bit in 0..32usize) {
+		let req = Request::new_read(false, register, length_or_data);
+		let mut bytes = req.as_bytes();
+		bytes[0] = REQUEST_TYPES[type_idx] as u8;
+		bytes[3] = calculate_crc(&bytes[0..3]);
+		// CRC-8 catches every single-bit error
+		bytes[bit / 8] ^= 1 << (bit % 8);
+		prop_assert_eq!(Request::from_bytes(&bytes), Err(Error::BadCrc));
+	}
+
+	#[test]
+	fn request_bad_type(type_byte: u8, register: u8, length_or_data: u8) {
+		prop_assume!(!REQUEST_TYPES.iter().any(|t| *t as u8 == type_byte));
+		let mut bytes = [type_byte, register, length_or_data, 0];
+		bytes[3] = calculate_crc(&bytes[0..3]);
+		prop_assert_eq!(Request::from_bytes(&bytes), Err(Error::BadRequestType));
+	}
+
+	#[test]
+	fn payload_round_trip(data in proptest::collection::vec(any::<u8>(), 0..=255)) {
+		let payload = Payload::new(&data);
+		let mut buffer = [0u8; 256];
+		let len = payload.render_to_buffer(&mut buffer).unwrap();
+		prop_assert_eq!(len, data.len() + 1);
+		prop_assert_eq!(Payload::from_bytes(&buffer[0..len]), Ok(payload));
+	}
+
+	#[test]
+	fn response_round_trip(data in proptest::collection::vec(any::<u8>(), 0..=255)) {
+		let rsp = Response::new_ok_with_data(&data);
+		let mut buffer = [0u8; 257];
+		let len = rsp.render_to_buffer(&mut buffer).unwrap();
+		prop_assert_eq!(len, data.len() + 2);
+		let decoded = Response::from_bytes(&buffer[0..len]).unwrap();
+		prop_assert_eq!(decoded.result, ResponseResult::Ok);
+		prop_assert_eq!(decoded.data, &data[..]);
+		// One byte short is always too small
+		prop_assert_eq!(rsp.render_to_buffer(&mut buffer[0..len - 1]), Err(Error::BufferTooSmall));
+	}
+}
+
+#[test]
+fn short_responses() {
+	assert_eq!(Response::from_bytes(&[]), Err(Error::BadLength));
+	assert_eq!(Response::from_bytes(&[0x00]), Err(Error::BadLength));
+	assert_eq!(Response::from_bytes(&[0xA0]), Err(Error::BadLength));
+	let rsp = Response::from_bytes(&[0xA0, 0x69]).unwrap();
+	assert_eq!(rsp.result, ResponseResult::Ok);
+	assert!(rsp.data.is_empty());
+}
@@ -0,0 +1,11 @@
+//! Checks the JSON copy of the register map is up to date.
+
+#[test]
+fn json_is_up_to_date() {
+	let mut json = String::new();
+	neotron_bmc_protocol::registers::write_json(&mut json).unwrap();
+	assert!(
+		json == include_str!("../registers.json"),
+		"registers.json is out of date - run `cargo run --example register_map > registers.json`"
+	);
+}
@@ -0,0 +1,124 @@
+//! Checks the encoders and decoders against the conformance vectors in
+//! `tests/vectors/`.
+
+use neotron_bmc_protocol::vectors::{self, Vector};
+use neotron_bmc_protocol::{
+	Error, Receivable, Request, RequestType, Response, ResponseResult, Sendable,
+};
+
+/// Every vector in a file, which must all make sense.
+fn all(text: &str) -> Vec<Vector<'_>> {
+	vectors::parse(text)
+		.map(|vector| vector.unwrap_or_else(|bad| panic!("bad vector on line {}", bad.line)))
+		.collect()
+}
+
+/// The bytes of a vector.
+fn bytes(hex: vectors::Hex<'_>) -> Vec<u8> {
+	hex.bytes().collect()
+}
+
+#[test]
+fn requests() {
+	let mut seen = Vec::new();
+	for vector in all(vectors::REQUESTS) {
+		match vector {
+			Vector::Request {
+				request,
+				bytes: hex,
+			} => {
+				let bytes = bytes(hex);
+				assert_eq!(request.as_bytes().to_vec(), bytes);
+				assert_eq!(Request::from_bytes(&bytes), Ok(request.clone()));
+				seen.push(request.request_type);
+			}
+			Vector::BadRequest { error, bytes: hex } => {
+				assert_eq!(Request::from_bytes(&bytes(hex)), Err(error), "{:?}", hex);
+			}
+			other => panic!("{:?} in the request vectors", other),
+		}
+	}
+	// Every Request Type is covered
+	for byte in 0x00..=0xFF {
+		if let Ok(request_type) = RequestType::try_from(byte) {
+			assert!(seen.contains(&request_type), "{:?} missing", request_type);
+		}
+	}
+}
+
+#[test]
+fn responses() {
+	let mut seen = Vec::new();
+	let mut buffer = [0u8; 64];
+	for vector in all(vectors::RESPONSES) {
+		match vector {
+			Vector::Response {
+				result,
+				data,
+				bytes: hex,
+			} => {
+				let data = bytes(data);
+				let bytes = bytes(hex);
+				let response = if result == ResponseResult::Ok {
+					Response::new_ok_with_data(&data)
+				} else {
+					assert!(data.is_empty());
+					Response::new_without_data(result)
+				};
+				let len = response.render_to_buffer(&mut buffer).unwrap();
+				assert_eq!(&buffer[0..len], &bytes[..]);
+				let decoded = Response::from_bytes(&bytes).unwrap();
+				assert_eq!((decoded.result, decoded.data), (result, &data[..]));
+				seen.push(result);
+			}
+			Vector::BadResponse { error, bytes: hex } => {
+				let decoded = Response::from_bytes(&bytes(hex)).map(|rsp| rsp.result);
+				assert_eq!(decoded, Err(error), "{:?}", hex);
+			}
+			other => panic!("{:?} in the response vectors", other),
+		}
+	}
+	// Every Response Result is covered
+	for byte in 0x00..=0xFF {
+		if let Ok(result) = ResponseResult::try_from(byte) {
+			assert!(seen.contains(&result), "{:?} missing", result);
+		}
+	}
+}
+
+#[test]
+fn exchanges() {
+	let vectors = all(vectors::EXCHANGES);
+	assert!(!vectors.is_empty());
+	for vector in vectors {
+		let Vector::Exchange { request, response } = vector else {
+			panic!("{:?} in the exchange vectors", vector);
+		};
+		let response = bytes(response);
+		let result = Response::from_bytes(&response).unwrap().result;
+		// The NBMC can only refuse a Request it couldn't decode like this
+		let decoded = Request::from_bytes(&bytes(request)).map(|_| ());
+		match result {
+			ResponseResult::CrcFailure => assert_eq!(decoded, Err(Error::BadCrc)),
+			ResponseResult::BadRequestType => assert_eq!(decoded, Err(Error::BadRequestType)),
+			_ => assert_eq!(decoded, Ok(()), "{:?}", request),
+		}
+	}
+}
+
+#[test]
+fn bad_lines() {
+	let text = "# A comment\n\nread 0 00 03 = C0 00 03 84\nread 2 00 03 = C0\nfrob = 00\nresponse Ok = A0 6\n";
+	let lines: Vec<_> = vectors::parse(text)
+		.map(|vector| vector.map(|_| ()))
+		.collect();
+	assert_eq!(
+		lines,
+		vec![
+			Ok(()),
+			Err(vectors::BadLine { line: 4 }),
+			Err(vectors::BadLine { line: 5 }),
+			Err(vectors::BadLine { line: 6 }),
+		]
+	);
+}